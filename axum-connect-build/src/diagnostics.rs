@@ -0,0 +1,429 @@
+use std::{collections::BTreeMap, fmt};
+
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use prost_reflect::{DescriptorPool, DynamicMessage};
+use serde_json::json;
+
+/// Problems the generator can't do anything about: constructs it skips, or code that won't compile.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiagnosticKind {
+    /// A method without a route, eg. a client-streaming one.
+    SkippedMethod,
+    /// An option the generator ignores, eg. a custom method option or an unsupported
+    /// `google.api.http` rule.
+    UnsupportedOption,
+    /// Two proto names that turn into the same Rust item.
+    NameCollision,
+}
+
+impl DiagnosticKind {
+    /// The name in warnings and the diagnostics JSON, eg. `skipped_method`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DiagnosticKind::SkippedMethod => "skipped_method",
+            DiagnosticKind::UnsupportedOption => "unsupported_option",
+            DiagnosticKind::NameCollision => "name_collision",
+        }
+    }
+}
+
+/// A problem found while generating, see [`GeneratedFiles::diagnostics`](crate::GeneratedFiles).
+/// It displays as `[<kind>] <element>: <message>`.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Diagnostic {
+    pub kind: DiagnosticKind,
+    /// The fully-qualified name of the proto element, eg. `hello.HelloWorldService.SayHello`.
+    pub element: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(
+        kind: DiagnosticKind,
+        element: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            kind,
+            element: element.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {}",
+            self.kind.as_str(),
+            self.element,
+            self.message
+        )
+    }
+}
+
+/// The diagnostics of the services in `files_to_generate`, besides those of `google.api.http`
+/// rules, which [`collect_rest_routes`](crate::http::collect_rest_routes) reports.
+pub fn collect(
+    pool: &DescriptorPool,
+    files_to_generate: &[String],
+    service_traits: bool,
+    clients: bool,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    // Rust items of each package module, to the proto names they come from.
+    let mut packages = BTreeMap::<String, BTreeMap<String, Vec<String>>>::new();
+
+    for file in pool.files() {
+        if !files_to_generate.iter().any(|name| name == file.name()) {
+            continue;
+        }
+
+        let items = packages.entry(file.package_name().to_string()).or_default();
+        for message in file.messages() {
+            items
+                .entry(message.name().to_upper_camel_case())
+                .or_default()
+                .push(message.full_name().to_string());
+        }
+        for enumeration in file.enums() {
+            items
+                .entry(enumeration.name().to_upper_camel_case())
+                .or_default()
+                .push(enumeration.full_name().to_string());
+        }
+
+        for service in file.services() {
+            items
+                .entry(service.name().to_upper_camel_case())
+                .or_default()
+                .push(service.full_name().to_string());
+            if service_traits {
+                items
+                    .entry(format!("{}Handlers", service.name().to_upper_camel_case()))
+                    .or_default()
+                    .push(service.full_name().to_string());
+            }
+            if clients {
+                items
+                    .entry(format!("{}Client", service.name().to_upper_camel_case()))
+                    .or_default()
+                    .push(service.full_name().to_string());
+            }
+            unsupported_options(service.full_name(), &service.options(), diagnostics);
+
+            // Items of the service struct, to the methods they come from.
+            let mut methods = BTreeMap::<String, Vec<String>>::new();
+            for method in service.methods() {
+                unsupported_options(method.full_name(), &method.options(), diagnostics);
+
+                if method.is_client_streaming() {
+                    diagnostics.push(Diagnostic::new(
+                        DiagnosticKind::SkippedMethod,
+                        method.full_name(),
+                        match method.is_server_streaming() {
+                            true => "bidi streaming RPCs aren't supported, no route is generated",
+                            false => {
+                                "client streaming RPCs aren't generated yet, mount them with \
+                                 RpcMethod::client_streaming"
+                            }
+                        },
+                    ));
+                    continue;
+                }
+
+                let name = method.name().to_snake_case();
+                let mut generated = vec![
+                    name.clone(),
+                    format!("{}_method", name),
+                    format!("{}_info", name),
+                    format!("{}_PATH", name.to_uppercase()),
+                ];
+                if !method.is_server_streaming() {
+                    generated.push(format!("{}_unary_get", name));
+                }
+                if service_traits {
+                    generated.push(format!("__register_{}", name));
+                }
+                for item in generated {
+                    methods
+                        .entry(item)
+                        .or_default()
+                        .push(method.name().to_string());
+                }
+            }
+
+            if service_traits {
+                for item in ["register_all", "registrars"] {
+                    if let Some(sources) = methods.get(item) {
+                        diagnostics.push(Diagnostic::new(
+                            DiagnosticKind::NameCollision,
+                            service.full_name(),
+                            format!(
+                                "`{}` is generated for `service_traits` and for the method {}",
+                                item,
+                                quoted(sources)
+                            ),
+                        ));
+                    }
+                }
+            }
+            if clients {
+                for item in ["new", "with_headers"] {
+                    if let Some(sources) = methods.get(item) {
+                        diagnostics.push(Diagnostic::new(
+                            DiagnosticKind::NameCollision,
+                            service.full_name(),
+                            format!(
+                                "`{}` is generated for `clients` and for the method {}",
+                                item,
+                                quoted(sources)
+                            ),
+                        ));
+                    }
+                }
+            }
+            for (item, sources) in methods {
+                if sources.len() > 1 {
+                    diagnostics.push(Diagnostic::new(
+                        DiagnosticKind::NameCollision,
+                        service.full_name(),
+                        format!(
+                            "`{}` is generated for each of the methods {}",
+                            item,
+                            quoted(&sources)
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    for (package, items) in packages {
+        for (item, sources) in items {
+            if sources.len() > 1 {
+                diagnostics.push(Diagnostic::new(
+                    DiagnosticKind::NameCollision,
+                    &package,
+                    format!("`{}` is generated for each of {}", item, quoted(&sources)),
+                ));
+            }
+        }
+    }
+}
+
+/// Custom options the generator doesn't read, `google.api.http` aside.
+fn unsupported_options(element: &str, options: &DynamicMessage, diagnostics: &mut Vec<Diagnostic>) {
+    for (extension, _) in options.extensions() {
+        if extension.full_name() == "google.api.http" {
+            continue;
+        }
+        diagnostics.push(Diagnostic::new(
+            DiagnosticKind::UnsupportedOption,
+            element,
+            format!("the option ({}) is ignored", extension.full_name()),
+        ));
+    }
+    for field in options.unknown_fields() {
+        diagnostics.push(Diagnostic::new(
+            DiagnosticKind::UnsupportedOption,
+            element,
+            format!("the unknown option field {} is ignored", field.number()),
+        ));
+    }
+}
+
+fn quoted(names: &[impl AsRef<str>]) -> String {
+    names
+        .iter()
+        .map(|name| format!("`{}`", name.as_ref()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The diagnostics as a JSON array of `{ "kind", "element", "message" }` objects.
+pub fn to_json(diagnostics: &[Diagnostic]) -> String {
+    let diagnostics = diagnostics
+        .iter()
+        .map(|diagnostic| {
+            json!({
+                "kind": diagnostic.kind.as_str(),
+                "element": diagnostic.element,
+                "message": diagnostic.message,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::to_string_pretty(&diagnostics).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_util::generate, AxumConnectGenSettings};
+
+    /// A construct of every kind of diagnostic, besides a service with nothing wrong.
+    const FILES: &[(&str, &str)] = &[(
+        "shop.proto",
+        r#"syntax = "proto3";
+package shop;
+
+import "google/api/annotations.proto";
+import "google/protobuf/descriptor.proto";
+
+extend google.protobuf.MethodOptions {
+  bool audited = 50001;
+}
+
+message Item { string id = 1; }
+message item_ { string id = 1; }
+
+service Orders {
+  rpc Upload(stream Item) returns (Item) {}
+  rpc Chat(stream Item) returns (stream Item) {}
+  rpc Watch(Item) returns (stream Item) {
+    option (google.api.http) = { get: "/v1/items/{id}" };
+  }
+  rpc Get(Item) returns (Item) {
+    option (audited) = true;
+  }
+  rpc GetItem(Item) returns (Item) {}
+  rpc Get_Item(Item) returns (Item) {}
+}
+
+service Clean {
+  rpc Get(Item) returns (Item) {}
+}
+"#,
+    )];
+
+    /// Generated item names shared by methods and the `clients` and `service_traits` items.
+    const RESERVED: &[(&str, &str)] = &[(
+        "reserved.proto",
+        r#"syntax = "proto3";
+package reserved;
+
+message Item { string id = 1; }
+
+service Registry {
+  rpc New(Item) returns (Item) {}
+  rpc RegisterAll(Item) returns (Item) {}
+}
+"#,
+    )];
+
+    const CLEAN: &[(&str, &str)] = &[(
+        "clean.proto",
+        r#"syntax = "proto3";
+package clean;
+
+message Item { string id = 1; }
+
+service Items {
+  rpc Get(Item) returns (Item) {}
+  rpc Watch(Item) returns (stream Item) {}
+}
+"#,
+    )];
+
+    fn diagnostics(files: &[(&str, &str)], settings: &AxumConnectGenSettings) -> Vec<String> {
+        generate(files, settings)
+            .diagnostics
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn reports_each_kind_of_construct_it_cant_generate() {
+        let diagnostics = diagnostics(FILES, &AxumConnectGenSettings::default());
+        assert_eq!(
+            diagnostics[..5],
+            [
+                "[skipped_method] shop.Orders.Chat: bidi streaming RPCs aren't supported, no \
+                 route is generated",
+                "[skipped_method] shop.Orders.Upload: client streaming RPCs aren't generated yet, \
+                 mount them with RpcMethod::client_streaming",
+                "[unsupported_option] shop.Orders.Get: the option (shop.audited) is ignored",
+                "[unsupported_option] shop.Orders.Watch: google.api.http is only supported on \
+                 unary RPCs, skipping",
+                "[name_collision] shop: `Item` is generated for each of `shop.Item`, `shop.item_`",
+            ]
+        );
+        // Every item of the two methods collides, the service without problems has none.
+        assert!(diagnostics[5..].contains(
+            &"[name_collision] shop.Orders: `get_item` is generated for each of the methods \
+              `GetItem`, `Get_Item`"
+                .to_string()
+        ));
+        assert!(diagnostics[5..]
+            .iter()
+            .all(|d| d.contains("`GetItem`, `Get_Item`")));
+        assert!(!diagnostics.iter().any(|d| d.contains("shop.Clean")));
+    }
+
+    #[test]
+    fn reports_methods_named_like_the_items_of_other_options() {
+        assert!(diagnostics(RESERVED, &AxumConnectGenSettings::default()).is_empty());
+
+        let settings = AxumConnectGenSettings {
+            service_traits: true,
+            clients: true,
+            ..AxumConnectGenSettings::default()
+        };
+        assert_eq!(
+            diagnostics(RESERVED, &settings),
+            [
+                "[name_collision] reserved.Registry: `new` is generated for `clients` and for \
+                 the method `New`",
+                "[name_collision] reserved.Registry: `register_all` is generated for \
+                 `service_traits` and for the method `RegisterAll`",
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_the_build_only_when_denied() {
+        let generated = generate(FILES, &AxumConnectGenSettings::default());
+        assert!(generated.check(&AxumConnectGenSettings::default()).is_ok());
+
+        let deny = AxumConnectGenSettings {
+            deny_unsupported: true,
+            ..AxumConnectGenSettings::default()
+        };
+        let error = generated.check(&deny).unwrap_err().to_string();
+        assert!(
+            error.starts_with(&format!(
+                "axum-connect-build found {} unsupported proto construct(s), and \
+                 deny_unsupported is set:\n  [skipped_method] shop.Orders.Chat: ",
+                generated.diagnostics.len()
+            )),
+            "{error}"
+        );
+        for diagnostic in &generated.diagnostics {
+            assert!(error.contains(&format!("\n  {diagnostic}")), "{error}");
+        }
+
+        // Nothing to deny, nothing fails.
+        let clean = generate(CLEAN, &deny);
+        assert!(clean.diagnostics.is_empty());
+        assert!(clean.check(&deny).is_ok());
+    }
+
+    #[test]
+    fn writes_the_diagnostics_as_json() {
+        let generated = generate(FILES, &AxumConnectGenSettings::default());
+        let json: serde_json::Value =
+            serde_json::from_str(&to_json(&generated.diagnostics)).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), generated.diagnostics.len());
+        assert_eq!(
+            json[2],
+            json!({
+                "kind": "unsupported_option",
+                "element": "shop.Orders.Get",
+                "message": "the option (shop.audited) is ignored",
+            })
+        );
+        assert_eq!(to_json(&[]), "[]");
+    }
+}
@@ -0,0 +1,325 @@
+use prost_build::Comments;
+
+/// The doc comment lines for a proto item's comments, leading then trailing ones, sanitized like
+/// prost does for messages and with [`fence_code`] applied. Detached comments are dropped.
+pub fn doc_lines(comments: &Comments) -> Vec<String> {
+    let comments = Comments {
+        leading_detached: vec![],
+        ..comments.clone()
+    };
+    let mut buf = String::new();
+    comments.append_with_indent(0, &mut buf);
+
+    let lines = buf
+        .lines()
+        .filter_map(|line| line.strip_prefix("///"))
+        .map(str::to_string)
+        .collect();
+    fence_code(lines)
+}
+
+/// Applies [`fence_code`] to every doc comment prost generated, ie. on messages, fields and enums.
+pub fn fence_generated_docs(contents: &str) -> String {
+    let mut output = String::with_capacity(contents.len());
+    let mut lines = contents.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        let Some((indent, doc)) = split_doc_line(line) else {
+            output.push_str(line);
+            output.push('\n');
+            continue;
+        };
+
+        // A doc comment is a run of `///` lines at the same indent.
+        let mut block = vec![doc.to_string()];
+        while let Some((_, doc)) = lines
+            .peek()
+            .and_then(|line| split_doc_line(line))
+            .filter(|(next_indent, _)| *next_indent == indent)
+        {
+            block.push(doc.to_string());
+            lines.next();
+        }
+
+        for doc in fence_code(block) {
+            output.push_str(indent);
+            output.push_str("///");
+            output.push_str(&doc);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+fn split_doc_line(line: &str) -> Option<(&str, &str)> {
+    let doc_start = line.len() - line.trim_start().len();
+    let doc = line[doc_start..].strip_prefix("///")?;
+    Some((&line[..doc_start], doc))
+}
+
+/// Rustdoc compiles code blocks in doc comments as Rust doctests, so example code in proto
+/// comments (often in another language, or indented pseudo-code) would break `cargo test`. This
+/// marks Rust fenced blocks as `text`, and fences indented blocks as `text`.
+pub fn fence_code(lines: Vec<String>) -> Vec<String> {
+    // Rustdoc strips the indent shared by every non-blank line before parsing Markdown.
+    let base = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| indent_of(line))
+        .min()
+        .unwrap_or(0);
+
+    let mut output = Vec::with_capacity(lines.len());
+    let mut fence: Option<String> = None;
+    let mut indented_block = false;
+    let mut previous_blank = true;
+
+    for line in lines {
+        let blank = line.trim().is_empty();
+        let indent = indent_of(&line);
+        let trimmed = line.trim_start();
+
+        if let Some(marker) = &fence {
+            if trimmed.starts_with(marker.as_str()) {
+                fence = None;
+            }
+            output.push(line);
+            previous_blank = false;
+            continue;
+        }
+
+        if indented_block {
+            if blank || indent >= base + 4 {
+                output.push(dedent_code(line, base));
+                continue;
+            }
+            close_indented_block(&mut output, base);
+            indented_block = false;
+        }
+
+        if let Some(marker) = fence_marker(trimmed) {
+            let info = trimmed[marker.len()..].trim();
+            if is_rust_info(info) {
+                output.push(format!("{}{}text", " ".repeat(indent), marker));
+            } else {
+                output.push(line.clone());
+            }
+            fence = Some(marker);
+            previous_blank = false;
+            continue;
+        }
+
+        if !blank && previous_blank && indent >= base + 4 {
+            output.push(format!("{}```text", " ".repeat(base)));
+            output.push(dedent_code(line, base));
+            indented_block = true;
+            continue;
+        }
+
+        previous_blank = blank;
+        output.push(line);
+    }
+
+    if indented_block {
+        close_indented_block(&mut output, base);
+    }
+    // An unclosed fence is closed by the end of the comment, which is fine.
+    output
+}
+
+/// Closes a fenced indented block, before the blank lines at its end.
+fn close_indented_block(output: &mut Vec<String>, base: usize) {
+    let trailing_blanks = output
+        .iter()
+        .rev()
+        .take_while(|line| line.trim().is_empty())
+        .count();
+    output.insert(
+        output.len() - trailing_blanks,
+        format!("{}```", " ".repeat(base)),
+    );
+}
+
+/// Drops the 4 spaces of indent that made a line code, which the fence makes redundant.
+fn dedent_code(line: String, base: usize) -> String {
+    match line.get(base..base + 4) {
+        Some("    ") => format!("{}{}", &line[..base], &line[base + 4..]),
+        _ => line,
+    }
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// The fence marker a line opens a code block with, eg. "```" or "~~~~".
+fn fence_marker(trimmed: &str) -> Option<String> {
+    ['`', '~'].into_iter().find_map(|c| {
+        let count = trimmed.chars().take_while(|&next| next == c).count();
+        (count >= 3).then(|| c.to_string().repeat(count))
+    })
+}
+
+/// Whether rustdoc treats a fenced block with this info string as Rust.
+fn is_rust_info(info: &str) -> bool {
+    info.split([',', ' '])
+        .filter(|token| !token.is_empty())
+        .all(|token| {
+            matches!(
+                token,
+                "rust" | "ignore" | "no_run" | "should_panic" | "compile_fail" | "test_harness"
+            ) || token.starts_with("edition")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::{test_util::generate, AxumConnectGenSettings};
+
+    /// Written with `UPDATE_SNAPSHOTS=1 cargo test -p axum-connect-build`; review its diff before
+    /// committing it.
+    const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/snapshots/docs.rs");
+
+    /// Comments on every item codegen documents, leading, trailing and detached.
+    const LIBRARY: &str = r#"syntax = "proto3";
+package library;
+
+// A book on the shelves.
+//
+// Identified by its [isbn], see
+// https://isbn.org.
+message Book {
+  // The ISBN-13, eg. `978-0441013593`.
+  string isbn = 1;
+  string title = 2; // As printed on the cover.
+  Format format = 3;
+}
+
+// How a book is printed.
+enum Format {
+  // Not known.
+  FORMAT_UNSPECIFIED = 0;
+  FORMAT_PAPERBACK = 1; // Soft cover.
+}
+
+message GetBookRequest {
+  string isbn = 1;
+}
+
+// Detached, it's not about anything below.
+
+// Lends and returns books.
+//
+// Example:
+//
+//     client.get_book("978-0441013593")
+service Library {
+  // Finds a book.
+  //
+  // ```rust
+  // let book = library.get_book(isbn).await?;
+  // ```
+  rpc GetBook(GetBookRequest) returns (Book) {
+    option idempotency_level = NO_SIDE_EFFECTS;
+  }
+  // Every book as it's shelved.
+  rpc WatchBooks(GetBookRequest) returns (stream Book); // Until the library closes.
+}
+"#;
+
+    fn library() -> String {
+        let settings = AxumConnectGenSettings {
+            json: false,
+            format: true,
+            ..Default::default()
+        };
+        let generated = generate(&[("library.proto", LIBRARY)], &settings)
+            .files
+            .remove("library.rs")
+            .unwrap();
+        // Without the version stamp, so releases don't change it.
+        generated.split_once('\n').unwrap().1.to_string()
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|line| line.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_the_snapshot() {
+        let generated = library();
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some_and(|update| !update.is_empty()) {
+            std::fs::create_dir_all(Path::new(SNAPSHOT).parent().unwrap()).unwrap();
+            std::fs::write(SNAPSHOT, &generated).unwrap();
+            return;
+        }
+        let snapshot = std::fs::read_to_string(SNAPSHOT).unwrap();
+        assert!(
+            generated == snapshot,
+            "docs.rs changed, write it with UPDATE_SNAPSHOTS=1 if that's deliberate\n{}",
+            generated
+        );
+    }
+
+    #[test]
+    fn documents_every_level() {
+        let code = library();
+        for doc in [
+            "/// A book on the shelves.",
+            "/// Identified by its \\[isbn\\], see\n/// <https://isbn.org.>",
+            "/// The ISBN-13, eg. `978-0441013593`.",
+            "/// As printed on the cover.",
+            "/// How a book is printed.",
+            "/// Soft cover.",
+            "/// Lends and returns books.",
+            "/// Finds a book.",
+            "/// Every book as it's shelved.",
+            "/// Until the library closes.",
+        ] {
+            assert!(code.contains(doc), "no {doc:?} in\n{code}");
+        }
+        assert!(!code.contains("Detached"), "{code}");
+    }
+
+    #[test]
+    fn fences_rust_and_indented_code_as_text() {
+        let docs = fence_code(lines(&[
+            " Example:",
+            "",
+            "     client.get_book(isbn)",
+            "",
+            " ```rust",
+            " let book = get_book(isbn);",
+            " ```",
+        ]));
+        assert_eq!(
+            docs,
+            lines(&[
+                " Example:",
+                "",
+                " ```text",
+                " client.get_book(isbn)",
+                " ```",
+                "",
+                " ```text",
+                " let book = get_book(isbn);",
+                " ```",
+            ])
+        );
+    }
+
+    #[test]
+    fn keeps_other_languages_and_indented_lists() {
+        let docs = lines(&[
+            " ```json",
+            " {\"isbn\": \"978-0441013593\"}",
+            " ```",
+            " Formats:",
+            "     - paperback",
+        ]);
+        assert_eq!(fence_code(docs.clone()), docs);
+    }
+}
@@ -1,126 +1,700 @@
+use std::collections::BTreeMap;
+
 use proc_macro2::TokenStream;
 use prost_build::{Method, Service, ServiceGenerator};
 use quote::{format_ident, quote};
 use syn::parse_str;
 
+use crate::{docs::doc_lines, http::RestRoute};
+
 #[derive(Default)]
-pub struct AxumConnectServiceGenerator {}
+pub struct AxumConnectServiceGenerator {
+    /// Generated services, keyed by package and then fully-qualified service name. They're held
+    /// back until `finalize_package` so that output is sorted regardless of declaration order.
+    services: BTreeMap<String, BTreeMap<String, String>>,
+    /// REST aliases from `google.api.http` options, keyed by fully-qualified method name.
+    rest_routes: BTreeMap<String, Vec<RestRoute>>,
+    /// Also generate a handlers trait per service, see `AxumConnectGenSettings::service_traits`.
+    service_traits: bool,
+    /// Also generate a client per service, see `AxumConnectGenSettings::clients`.
+    clients: bool,
+}
 
 impl AxumConnectServiceGenerator {
-    pub fn new() -> Self {
-        Default::default()
+    pub fn new(
+        rest_routes: BTreeMap<String, Vec<RestRoute>>,
+        service_traits: bool,
+        clients: bool,
+    ) -> Self {
+        Self {
+            rest_routes,
+            service_traits,
+            clients,
+            ..Default::default()
+        }
     }
 
-    fn generate_service(&mut self, service: Service, buf: &mut String) {
+    fn generate_service(&mut self, mut service: Service) -> String {
         // Service struct
         let service_name = format_ident!("{}", service.name);
-        let methods =
-            service.methods.into_iter().filter_map(|m| {
-                // Don't currently support client streaming. Will-do soon.
-                if m.client_streaming {
-                    return None;
+        let service_docs = doc_lines(&service.comments);
+        service
+            .methods
+            .sort_by(|a, b| a.proto_name.cmp(&b.proto_name));
+        let path_root = format!("{}.{}", service.package, service.proto_name);
+        // Don't currently support client streaming. Will-do soon.
+        let methods = service
+            .methods
+            .into_iter()
+            .filter(|m| !m.client_streaming)
+            .collect::<Vec<_>>();
+
+        let path_consts = methods
+            .iter()
+            .map(|m| path_const_ident(&m.name))
+            .collect::<Vec<_>>();
+        let paths = methods
+            .iter()
+            .map(|m| format!("/{}/{}", path_root, m.proto_name))
+            .collect::<Vec<_>>();
+        // The method's comments, then its path.
+        let path_docs = methods
+            .iter()
+            .zip(&paths)
+            .map(|(m, path)| {
+                let mut docs = doc_lines(&m.comments);
+                if !docs.is_empty() {
+                    docs.push(String::new());
                 }
+                docs.push(format!(" `{}`", path));
+                docs
+            })
+            .collect::<Vec<_>>();
+        let (handlers_trait, handlers_registration) = match self.service_traits {
+            true => generate_handlers_trait(&service_name, &methods, &path_consts),
+            false => (quote! {}, quote! {}),
+        };
+        let client = match self.clients {
+            true => generate_client(&service_name, &methods, &path_consts),
+            false => quote! {},
+        };
+        let methods = methods
+            .into_iter()
+            .map(|m| self.generate_service_method(m, &path_root))
+            .collect::<Vec<_>>();
 
-                Some(self.generate_service_method(
-                    m,
-                    &format!("{}.{}", service.package, service.proto_name),
-                ))
-            });
+        quote! {
+            #(#[doc = #service_docs])*
+            pub struct #service_name;
 
-        buf.push_str(
-            quote! {
-                pub struct #service_name;
+            #[allow(dead_code)]
+            impl #service_name {
+                #(
+                    #(#[doc = #path_docs])*
+                    pub const #path_consts: &'static str = #paths;
+                )*
 
-                #[allow(dead_code)]
-                impl #service_name {
-                    #(#methods)*
-                }
+                /// The path of every RPC of the service.
+                pub const PATHS: &'static [&'static str] = &[#(Self::#path_consts),*];
+
+                #(#methods)*
+
+                #handlers_registration
             }
-            .to_string()
-            .as_str(),
-        );
+
+            #handlers_trait
+
+            #client
+        }
+        .to_string()
     }
 
     fn generate_service_method(&mut self, method: Method, path_root: &str) -> TokenStream {
         let method_name = format_ident!("{}", method.name);
+        let path_const = path_const_ident(&method.name);
         let method_name_unary_get = format_ident!("{}_unary_get", method.name);
+        let method_name_method = format_ident!("{}_method", method.name);
+        let method_name_info = format_ident!("{}_info", method.name);
         let input_type: syn::Type = parse_str(&method.input_type).unwrap();
         let output_type: syn::Type = parse_str(&method.output_type).unwrap();
-        let path = format!("/{}/{}", path_root, method.proto_name);
+        let method_proto_name = &method.proto_name;
+        let method_docs = doc_lines(&method.comments);
+        let idempotency_level = method.options.idempotency_level.unwrap_or_default();
+        // Connect only allows GET for `NO_SIDE_EFFECTS` methods.
+        let no_side_effects = idempotency_level == 1;
+        let kind = if method.server_streaming {
+            quote! { axum_connect::router::RpcMethodKind::ServerStreaming }
+        } else {
+            quote! { axum_connect::router::RpcMethodKind::Unary }
+        };
+        let (input_name, output_name) = (&method.input_type, &method.output_type);
+        let info = quote! {
+            axum_connect::router::RpcMethodInfo::from_rpc_path(
+                Self::#path_const,
+                #kind,
+                axum_connect::router::RpcIdempotencyLevel::from_proto(#idempotency_level),
+            )
+            .with_message_types(#input_name, #output_name)
+        };
 
         if method.server_streaming {
             quote! {
+                #(#[doc = #method_docs])*
                 pub fn #method_name<T, H, S>(
                     handler: H
-                ) -> impl FnOnce(axum::Router<S>) -> axum_connect::router::RpcRouter<S>
+                ) -> impl FnOnce(axum_connect::router::RpcRouter<S>) -> axum_connect::router::RpcRouter<S>
                 where
                     H: axum_connect::handler::RpcHandlerStream<#input_type, #output_type, T, S>,
                     T: 'static,
                     S: Clone + Send + Sync + 'static,
                 {
-                    move |router: axum::Router<S>| {
-                        router.route(
-                            #path,
-                            axum::routing::post(|
+                    move |router: axum_connect::router::RpcRouter<S>| {
+                        router.rpc_method(Self::#method_name_method(handler))
+                    }
+                }
+
+                /// The RPC on its own, eg. to turn into a `tower::Service`.
+                pub fn #method_name_method<T, H, S>(handler: H) -> axum_connect::router::RpcMethod<S>
+                where
+                    H: axum_connect::handler::RpcHandlerStream<#input_type, #output_type, T, S>,
+                    T: 'static,
+                    S: Clone + Send + Sync + 'static,
+                {
+                    axum_connect::router::RpcMethod::new(
+                        #info,
+                        axum::routing::post(|
+                            axum::extract::State(state): axum::extract::State<S>,
+                            request: axum::http::Request<axum::body::Body>
+                        | async move {
+                            handler.call(request, state).await
+                        }),
+                    )
+                }
+
+                /// The RPC's info without a handler, eg. to check its idempotency level before
+                /// calling it.
+                pub fn #method_name_info() -> axum_connect::router::RpcMethodInfo {
+                    #info
+                }
+            }
+        } else {
+            let (post_info, method_router) = if no_side_effects {
+                (
+                    quote! {
+                        #info.with_http_methods(vec![axum::http::Method::POST, axum::http::Method::GET])
+                    },
+                    quote! {
+                        axum::routing::post({
+                            let handler = handler.clone();
+                            |
                                 axum::extract::State(state): axum::extract::State<S>,
                                 request: axum::http::Request<axum::body::Body>
                             | async move {
                                 handler.call(request, state).await
-                            }),
-                        )
+                            }
+                        })
+                        .get(|
+                            axum::extract::State(state): axum::extract::State<S>,
+                            request: axum::http::Request<axum::body::Body>
+                        | async move {
+                            handler.call(request, state).await
+                        })
+                    },
+                )
+            } else {
+                (
+                    info.clone(),
+                    quote! {
+                        axum::routing::post(|
+                            axum::extract::State(state): axum::extract::State<S>,
+                            request: axum::http::Request<axum::body::Body>
+                        | async move {
+                            handler.call(request, state).await
+                        })
+                    },
+                )
+            };
+
+            let rest_routes = self
+                .rest_routes
+                .get(&format!("{}.{}", path_root, method_proto_name))
+                .map(|routes| {
+                    routes
+                        .iter()
+                        .map(|route| generate_rest_route(route, &info))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+
+            // `NO_SIDE_EFFECTS` methods are mounted for GET already, mounting it again would panic
+            // with overlapping routes.
+            let unary_get = if no_side_effects {
+                let note = format!(
+                    "`{}` mounts the RPC for GET already, this does nothing after it",
+                    method.name
+                );
+                quote! {
+                    /// Mounts the RPC for GET requests, unless it's mounted for them already. This
+                    /// is a `NO_SIDE_EFFECTS` method, which are mounted for GET as well as POST.
+                    #[deprecated(note = #note)]
+                    pub fn #method_name_unary_get<T, H, S>(
+                        handler: H
+                    ) -> impl FnOnce(axum_connect::router::RpcRouter<S>) -> axum_connect::router::RpcRouter<S>
+                    where
+                        H: axum_connect::handler::RpcHandlerUnary<#input_type, #output_type, T, S>,
+                        T: 'static,
+                        S: Clone + Send + Sync + 'static,
+                    {
+                        move |router: axum_connect::router::RpcRouter<S>| {
+                            let mounted = router.paths().iter().any(|info| {
+                                info.path == Self::#path_const
+                                    && info.http_methods.contains(&axum::http::Method::GET)
+                            });
+                            match mounted {
+                                true => router,
+                                false => router.rpc_method(axum_connect::router::RpcMethod::new(
+                                    #info.with_http_methods(vec![axum::http::Method::GET]),
+                                    axum::routing::get(|
+                                        axum::extract::State(state): axum::extract::State<S>,
+                                        request: axum::http::Request<axum::body::Body>
+                                    | async move {
+                                        handler.call(request, state).await
+                                    }),
+                                )),
+                            }
+                        }
                     }
                 }
-            }
-        } else {
+            } else {
+                quote! {
+                    /// Mounts the RPC for GET requests, regardless of its idempotency level.
+                    pub fn #method_name_unary_get<T, H, S>(
+                        handler: H
+                    ) -> impl FnOnce(axum_connect::router::RpcRouter<S>) -> axum_connect::router::RpcRouter<S>
+                    where
+                        H: axum_connect::handler::RpcHandlerUnary<#input_type, #output_type, T, S>,
+                        T: 'static,
+                        S: Clone + Send + Sync + 'static,
+                    {
+                        move |router: axum_connect::router::RpcRouter<S>| {
+                            router.rpc_method(axum_connect::router::RpcMethod::new(
+                                #info.with_http_methods(vec![axum::http::Method::GET]),
+                                axum::routing::get(|
+                                    axum::extract::State(state): axum::extract::State<S>,
+                                    request: axum::http::Request<axum::body::Body>
+                                | async move {
+                                    handler.call(request, state).await
+                                }),
+                            ))
+                        }
+                    }
+                }
+            };
+
             quote! {
+                #(#[doc = #method_docs])*
                 pub fn #method_name<T, H, S>(
                     handler: H
-                ) -> impl FnOnce(axum::Router<S>) -> axum_connect::router::RpcRouter<S>
+                ) -> impl FnOnce(axum_connect::router::RpcRouter<S>) -> axum_connect::router::RpcRouter<S>
                 where
                     H: axum_connect::handler::RpcHandlerUnary<#input_type, #output_type, T, S>,
                     T: 'static,
                     S: Clone + Send + Sync + 'static,
                 {
-                    move |router: axum::Router<S>| {
-                        router.route(
-                            #path,
-                            axum::routing::post(|
-                                axum::extract::State(state): axum::extract::State<S>,
-                                request: axum::http::Request<axum::body::Body>
-                            | async move {
-                                handler.call(request, state).await
-                            }),
-                        )
+                    move |router: axum_connect::router::RpcRouter<S>| {
+                        // REST aliases go first, they need to clone the handler before it's moved.
+                        router
+                        #(#rest_routes)*
+                        .rpc_method(Self::#method_name_method(handler))
                     }
                 }
 
-                pub fn #method_name_unary_get<T, H, S>(
-                    handler: H
-                ) -> impl FnOnce(axum::Router<S>) -> axum_connect::router::RpcRouter<S>
+                /// The RPC on its own, eg. to turn into a `tower::Service`.
+                pub fn #method_name_method<T, H, S>(handler: H) -> axum_connect::router::RpcMethod<S>
                 where
                     H: axum_connect::handler::RpcHandlerUnary<#input_type, #output_type, T, S>,
                     T: 'static,
                     S: Clone + Send + Sync + 'static,
                 {
-                    move |router: axum::Router<S>| {
-                        router.route(
-                            #path,
-                            axum::routing::get(|
-                                axum::extract::State(state): axum::extract::State<S>,
-                                request: axum::http::Request<axum::body::Body>
-                            | async move {
-                                handler.call(request, state).await
-                            }),
-                        )
-                    }
+                    axum_connect::router::RpcMethod::new(#post_info, #method_router)
+                }
+
+                /// The RPC's info without a handler, eg. to check its idempotency level before
+                /// calling it.
+                pub fn #method_name_info() -> axum_connect::router::RpcMethodInfo {
+                    #post_info
                 }
+
+                #unary_get
             }
         }
     }
 }
 
+/// The `<Service>Handlers` trait, with a method per RPC, and the `register_all` and `registrars`
+/// of the service struct mounting its implementations.
+fn generate_handlers_trait(
+    service_name: &syn::Ident,
+    methods: &[Method],
+    path_consts: &[syn::Ident],
+) -> (TokenStream, TokenStream) {
+    let trait_name = format_ident!("{}Handlers", service_name);
+    let trait_doc = format!(
+        " Handlers for every RPC of [`{}`], mounted with [`{}::register_all`]. They take an \
+         `RpcRequest` and answer with an `RpcResponse`, for the metadata around the messages. Each \
+         call gets its own clone, streaming RPCs take it by value so their stream can own what it \
+         needs.",
+        service_name, service_name
+    );
+
+    let trait_methods = methods.iter().map(|m| {
+        let method_name = format_ident!("{}", m.name);
+        let input_type: syn::Type = parse_str(&m.input_type).unwrap();
+        let output_type: syn::Type = parse_str(&m.output_type).unwrap();
+        let docs = doc_lines(&m.comments);
+        // Streaming methods take a clone of the handlers, the stream can't borrow from them.
+        let (receiver, output) = match m.server_streaming {
+            true => (
+                quote! { self },
+                quote! {
+                    impl axum_connect::futures::Stream<
+                        Item = axum_connect::response::RpcResult<#output_type>
+                    > + Send + 'static
+                },
+            ),
+            false => (quote! { &self }, quote! { #output_type }),
+        };
+        quote! {
+            #(#[doc = #docs])*
+            fn #method_name(
+                #receiver,
+                request: axum_connect::request::RpcRequest<#input_type>,
+            ) -> impl std::future::Future<
+                Output = axum_connect::response::RpcResult<
+                    axum_connect::response::RpcResponse<#output>
+                >
+            > + Send;
+        }
+    });
+
+    let registrars = methods.iter().zip(path_consts).map(|(m, path_const)| {
+        let method_name = format_ident!("{}", m.name);
+        let register = format_ident!("__register_{}", m.name);
+        let proto_name = &m.proto_name;
+        let registrar = quote! {
+            axum_connect::router::RpcRegistrar {
+                method: #proto_name,
+                path: Self::#path_const,
+                register: Self::#register::<H, S>,
+            }
+        };
+        let input_type: syn::Type = parse_str(&m.input_type).unwrap();
+        let respond = match m.server_streaming {
+            true => quote! {
+                match handlers.#method_name(request).await {
+                    Ok(response) => axum_connect::futures::StreamExt::left_stream(
+                        call.respond(response),
+                    ),
+                    Err(error) => axum_connect::futures::StreamExt::right_stream(
+                        axum_connect::futures::stream::once(std::future::ready(Err(error))),
+                    ),
+                }
+            },
+            false => quote! {
+                handlers
+                    .#method_name(request)
+                    .await
+                    .map(|response| call.respond(response))
+            },
+        };
+        let register_fn = quote! {
+            #[doc(hidden)]
+            pub fn #register<H, S>(
+                router: axum_connect::router::RpcRouter<S>,
+                handlers: H,
+            ) -> axum_connect::router::RpcRouter<S>
+            where
+                H: #trait_name,
+                S: Clone + Send + Sync + 'static,
+            {
+                Self::#method_name(
+                    move |mut call: axum_connect::request::RpcCallParts, request: #input_type| {
+                        async move {
+                            let request = call.request(request);
+                            #respond
+                        }
+                    },
+                )(router)
+            }
+        };
+        (registrar, register_fn)
+    });
+    let (registrars, register_fns): (Vec<_>, Vec<_>) = registrars.unzip();
+
+    let handlers_trait = quote! {
+        #[doc = #trait_doc]
+        pub trait #trait_name: Clone + Send + Sync + 'static {
+            #(#trait_methods)*
+        }
+    };
+    let registration = quote! {
+        /// Mounts every RPC of the service, handled by `handlers`.
+        pub fn register_all<H, S>(
+            router: axum_connect::router::RpcRouter<S>,
+            handlers: H,
+        ) -> axum_connect::router::RpcRouter<S>
+        where
+            H: #trait_name,
+            S: Clone + Send + Sync + 'static,
+        {
+            Self::registrars::<H, S>()
+                .iter()
+                .fold(router, |router, registrar| (registrar.register)(router, handlers.clone()))
+        }
+
+        /// A registrar per RPC of the service, in the order of `PATHS`, eg. to mount some of them
+        /// by name.
+        pub fn registrars<H, S>() -> &'static [axum_connect::router::RpcRegistrar<S, H>]
+        where
+            H: #trait_name,
+            S: Clone + Send + Sync + 'static,
+        {
+            &[#(#registrars),*]
+        }
+
+        #(#register_fns)*
+    };
+    (handlers_trait, registration)
+}
+
+/// The `<Service>Client`, with a method per RPC calling it through an `RpcClient`.
+fn generate_client(
+    service_name: &syn::Ident,
+    methods: &[Method],
+    path_consts: &[syn::Ident],
+) -> TokenStream {
+    let client_name = format_ident!("{}Client", service_name);
+    let client_doc = format!(
+        " A client of every RPC of [`{}`], over an `axum_connect::client::RpcClient`, which has \
+         the base URL, metadata, timeout and interceptors of the calls.",
+        service_name
+    );
+
+    let client_methods = methods.iter().zip(path_consts).map(|(m, path_const)| {
+        let method_name = format_ident!("{}", m.name);
+        let input_type: syn::Type = parse_str(&m.input_type).unwrap();
+        let output_type: syn::Type = parse_str(&m.output_type).unwrap();
+        let docs = doc_lines(&m.comments);
+        let (call, output) = match m.server_streaming {
+            true => (
+                quote! { server_stream },
+                quote! { axum_connect::client::RpcResponseStream<#output_type> },
+            ),
+            false => (quote! { unary }, quote! { #output_type }),
+        };
+        quote! {
+            #(#[doc = #docs])*
+            pub async fn #method_name(
+                &self,
+                request: #input_type,
+            ) -> axum_connect::response::RpcResult<#output> {
+                self.client.#call(#service_name::#path_const, request).await
+            }
+        }
+    });
+
+    quote! {
+        #[doc = #client_doc]
+        #[derive(Clone, Debug)]
+        pub struct #client_name {
+            client: axum_connect::client::RpcClient,
+        }
+
+        #[allow(dead_code)]
+        impl #client_name {
+            pub fn new(client: axum_connect::client::RpcClient) -> Self {
+                Self { client }
+            }
+
+            /// The client with `headers` on its calls, eg. those of an
+            /// `axum_connect::client::ClientContext`, see `RpcClient::with_headers`.
+            pub fn with_headers(&self, headers: axum::http::HeaderMap) -> Self {
+                Self { client: self.client.with_headers(headers) }
+            }
+
+            #(#client_methods)*
+        }
+    }
+}
+
+/// `SAY_HELLO_PATH` for the `say_hello` method.
+fn path_const_ident(method_name: &str) -> syn::Ident {
+    format_ident!("{}_PATH", method_name.to_uppercase())
+}
+
+/// Generates the `.rest_route(...)` call for a single `google.api.http` binding. It rewrites the
+/// request into a Connect JSON one and hands it to the same handler, along with the RPC's `info`.
+fn generate_rest_route(route: &RestRoute, info: &TokenStream) -> TokenStream {
+    let path = &route.path;
+    let verb = format_ident!("{}", route.verb);
+    let path_fields = route
+        .path_fields
+        .iter()
+        .map(|(capture, field)| quote! { (#capture, #field) });
+    let body = match &route.body {
+        Some(body) => quote! { Some(#body) },
+        None => quote! { None },
+    };
+    let bool_fields = &route.bool_fields;
+    let repeated_fields = &route.repeated_fields;
+
+    quote! {
+        .rest_route(#path, {
+            let handler = handler.clone();
+            axum::routing::#verb(|
+                axum::extract::State(state): axum::extract::State<S>,
+                request: axum::http::Request<axum::body::Body>
+            | async move {
+                const BINDING: axum_connect::rest::RestBinding = axum_connect::rest::RestBinding {
+                    path_fields: &[#(#path_fields),*],
+                    body: #body,
+                    bool_fields: &[#(#bool_fields),*],
+                    repeated_fields: &[#(#repeated_fields),*],
+                };
+
+                match axum_connect::rest::rest_into_connect_request(request, &BINDING).await {
+                    Ok(request) => handler.call(request, state).await,
+                    Err(response) => response,
+                }
+            })
+            .layer(axum::Extension(#info))
+        })
+    }
+}
+
 impl ServiceGenerator for AxumConnectServiceGenerator {
     fn generate(&mut self, service: Service, buf: &mut String) {
-        self.generate_service(service, buf);
+        // The code goes in with `finalize_package`, sorted by name. Prost drops packages whose
+        // buffer is still empty by then, eg. of a file with only services, so mark it used.
+        if buf.is_empty() {
+            buf.push('\n');
+        }
+        let package = service.package.clone();
+        let fq_name = format!("{}.{}", service.package, service.proto_name);
+        let code = self.generate_service(service);
+
+        self.services
+            .entry(package)
+            .or_default()
+            .insert(fq_name, code);
+    }
+
+    fn finalize_package(&mut self, package: &str, buf: &mut String) {
+        for code in self
+            .services
+            .remove(package)
+            .unwrap_or_default()
+            .into_values()
+        {
+            buf.push_str(&code);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{generate, squashed},
+        AxumConnectGenSettings,
+    };
+
+    const USERS: &str = r#"syntax = "proto3";
+package users;
+
+message GetUserRequest { string id = 1; }
+message User { string id = 1; }
+
+service Users {
+  rpc GetUser(GetUserRequest) returns (User) {
+    option idempotency_level = NO_SIDE_EFFECTS;
+  }
+  rpc DeleteUser(GetUserRequest) returns (User) {
+    option idempotency_level = IDEMPOTENT;
+  }
+  rpc Watch(GetUserRequest) returns (stream User) {}
+}
+"#;
+
+    fn users() -> String {
+        let generated = generate(
+            &[("users.proto", USERS)],
+            &AxumConnectGenSettings::default(),
+        );
+        squashed(&generated, "users")
+    }
+
+    /// The code of the generated function `name`, up to the next one.
+    fn function(code: &str, name: &str) -> String {
+        let start = code.find(&format!("pubfn{name}<")).unwrap();
+        let end = code[start + 1..]
+            .find("pubfn")
+            .map_or(code.len(), |end| start + 1 + end);
+        code[start..end].to_string()
+    }
+
+    #[test]
+    fn mounts_get_for_no_side_effects_methods() {
+        let code = users();
+        let get_user = function(&code, "get_user_method");
+        assert!(get_user.contains(".get(|"), "{get_user}");
+        assert!(get_user.contains("vec![axum::http::Method::POST,axum::http::Method::GET]"));
+
+        let delete_user = function(&code, "delete_user_method");
+        assert!(!delete_user.contains(".get(|"), "{delete_user}");
+        assert!(!delete_user.contains("Method::GET"));
+        assert!(!function(&code, "watch_method").contains("Method::GET"));
+    }
+
+    #[test]
+    fn unary_get_of_no_side_effects_methods_is_deprecated() {
+        let code = users();
+        let before_get_user = &code[..code.find("pubfnget_user_unary_get<").unwrap()];
+        assert!(before_get_user.ends_with(
+            "#[deprecated(note=\"`get_user`mountstheRPCforGETalready,thisdoesnothingafterit\")]"
+        ));
+        // It only mounts GET if it isn't mounted already.
+        assert!(function(&code, "get_user_unary_get").contains("matchmounted{true=>router,"));
+
+        let delete_user = function(&code, "delete_user_unary_get");
+        assert!(!delete_user.contains("mounted"), "{delete_user}");
+        let before_delete_user = &code[..code.find("pubfndelete_user_unary_get<").unwrap()];
+        assert!(!before_delete_user.ends_with(")]"));
+        assert!(!code.contains("pubfnwatch_unary_get"));
+    }
+
+    #[test]
+    fn generates_a_client_per_service_when_asked() {
+        let settings = AxumConnectGenSettings {
+            clients: true,
+            ..Default::default()
+        };
+        let code = squashed(&generate(&[("users.proto", USERS)], &settings), "users");
+        assert!(
+            code.contains("pubstructUsersClient{client:axum_connect::client::RpcClient,}"),
+            "{code}"
+        );
+        // prost-build formats the code, eg. with trailing commas in long lists.
+        assert!(code.contains(
+            "pubasyncfnget_user(&self,request:GetUserRequest,)\
+             ->axum_connect::response::RpcResult<User>{\
+             self.client.unary(Users::GET_USER_PATH,request).await}"
+        ));
+        assert!(code.contains(
+            "pubasyncfnwatch(&self,request:GetUserRequest,)\
+             ->axum_connect::response::RpcResult<axum_connect::client::RpcResponseStream<User>,>{\
+             self.client.server_stream(Users::WATCH_PATH,request).await}"
+        ));
+
+        assert!(!users().contains("UsersClient"));
     }
 }
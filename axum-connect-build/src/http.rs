@@ -0,0 +1,222 @@
+use std::collections::BTreeMap;
+
+use prost_reflect::{DescriptorPool, DynamicMessage, Kind, MessageDescriptor, Value};
+
+use crate::diagnostics::{Diagnostic, DiagnosticKind};
+
+/// A single REST alias for a unary RPC, parsed from a `google.api.http` rule (or one of its
+/// `additional_bindings`).
+pub struct RestRoute {
+    /// The axum routing function to use, eg. `get`.
+    pub verb: &'static str,
+    /// The axum path, with template variables renamed to `{p0}`, `{p1}`... because field paths
+    /// can contain dots.
+    pub path: String,
+    /// (axum capture name, request field path) pairs.
+    pub path_fields: Vec<(String, String)>,
+    /// `None` if there's no body, `Some("*")` or `Some("field.path")` otherwise.
+    pub body: Option<String>,
+    /// Request fields (by path) that need a JSON bool rather than a string.
+    pub bool_fields: Vec<String>,
+    /// Request fields (by path) that are repeated.
+    pub repeated_fields: Vec<String>,
+}
+
+/// Collects REST routes for every method with a `google.api.http` option, keyed by the
+/// fully-qualified method name (eg. `hello.HelloWorldService.SayHello`). Unsupported rules are
+/// skipped with an `UnsupportedOption` diagnostic.
+pub fn collect_rest_routes(
+    pool: &DescriptorPool,
+    diagnostics: &mut Vec<Diagnostic>,
+) -> BTreeMap<String, Vec<RestRoute>> {
+    let mut routes = BTreeMap::new();
+
+    // The extension only exists if some proto imported `google/api/annotations.proto`.
+    let Some(http) = pool.get_extension_by_name("google.api.http") else {
+        return routes;
+    };
+
+    for service in pool.services() {
+        for method in service.methods() {
+            let options = method.options();
+            if !options.has_extension(&http) {
+                continue;
+            }
+
+            if method.is_client_streaming() || method.is_server_streaming() {
+                diagnostics.push(Diagnostic::new(
+                    DiagnosticKind::UnsupportedOption,
+                    method.full_name(),
+                    "google.api.http is only supported on unary RPCs, skipping",
+                ));
+                continue;
+            }
+
+            let Some(rule) = options.get_extension(&http).as_message().cloned() else {
+                continue;
+            };
+
+            let mut rules = vec![rule.clone()];
+            if let Some(Value::List(additional)) =
+                rule.get_field_by_name("additional_bindings").as_deref()
+            {
+                rules.extend(additional.iter().filter_map(|v| v.as_message().cloned()));
+            }
+
+            let method_routes = rules
+                .iter()
+                .filter_map(|rule| match parse_rule(rule, &method.input()) {
+                    Ok(route) => Some(route),
+                    Err(reason) => {
+                        diagnostics.push(Diagnostic::new(
+                            DiagnosticKind::UnsupportedOption,
+                            method.full_name(),
+                            format!("unsupported google.api.http rule ({}), skipping", reason),
+                        ));
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            if !method_routes.is_empty() {
+                routes.insert(method.full_name().to_string(), method_routes);
+            }
+        }
+    }
+
+    routes
+}
+
+fn parse_rule(rule: &DynamicMessage, input: &MessageDescriptor) -> Result<RestRoute, String> {
+    if rule.has_field_by_name("custom") {
+        return Err("custom verbs are not supported".to_string());
+    }
+
+    let get_str = |name: &str| {
+        rule.get_field_by_name(name)
+            .and_then(|v| v.as_str().map(str::to_string))
+            .unwrap_or_default()
+    };
+
+    if !get_str("response_body").is_empty() {
+        return Err("response_body is not supported".to_string());
+    }
+
+    let (verb, template) = ["get", "put", "post", "delete", "patch"]
+        .into_iter()
+        .find(|verb| rule.has_field_by_name(verb))
+        .map(|verb| (verb, get_str(verb)))
+        .ok_or_else(|| "missing HTTP method".to_string())?;
+
+    let (path, path_fields) = parse_template(&template)?;
+    let body = Some(get_str("body")).filter(|b| !b.is_empty());
+
+    let mut bool_fields = vec![];
+    let mut repeated_fields = vec![];
+    collect_field_hints(
+        input,
+        "",
+        &mut vec![],
+        &mut bool_fields,
+        &mut repeated_fields,
+    );
+
+    Ok(RestRoute {
+        verb,
+        path,
+        path_fields,
+        body,
+        bool_fields,
+        repeated_fields,
+    })
+}
+
+/// Turns a path template like `/v1/{parent=shelves/*}/books/{book.id}` into an axum path.
+///
+/// Supports whole-segment variables as `{field}`, `{field=*}` and (as the last segment)
+/// `{field=**}`. Anything else is rejected.
+fn parse_template(template: &str) -> Result<(String, Vec<(String, String)>), String> {
+    if !template.starts_with('/') {
+        return Err(format!("path template must start with '/': {}", template));
+    }
+
+    if template
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .contains(':')
+    {
+        return Err("custom verbs are not supported".to_string());
+    }
+
+    let segments = template[1..].split('/').collect::<Vec<_>>();
+    let mut path = String::new();
+    let mut fields = vec![];
+
+    for (i, segment) in segments.iter().enumerate() {
+        path.push('/');
+
+        let Some(variable) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) else {
+            if segment.contains(['{', '}', '*']) {
+                return Err(format!("unsupported path segment: {}", segment));
+            }
+            path.push_str(segment);
+            continue;
+        };
+
+        let capture = format!("p{}", fields.len());
+        let (field, pattern) = variable.split_once('=').unwrap_or((variable, "*"));
+
+        match pattern {
+            "*" => path.push_str(&format!("{{{}}}", capture)),
+            "**" if i == segments.len() - 1 => path.push_str(&format!("{{*{}}}", capture)),
+            _ => return Err(format!("unsupported variable pattern: {}", variable)),
+        }
+
+        fields.push((capture, field.to_string()));
+    }
+
+    Ok((path, fields))
+}
+
+fn collect_field_hints(
+    message: &MessageDescriptor,
+    prefix: &str,
+    stack: &mut Vec<String>,
+    bool_fields: &mut Vec<String>,
+    repeated_fields: &mut Vec<String>,
+) {
+    // Guard against recursive messages.
+    if stack.iter().any(|name| name == message.full_name()) {
+        return;
+    }
+    stack.push(message.full_name().to_string());
+
+    for field in message.fields() {
+        let path = format!("{}{}", prefix, field.name());
+
+        if field.is_list() {
+            repeated_fields.push(path.clone());
+        }
+
+        match field.kind() {
+            Kind::Bool if !field.is_map() => bool_fields.push(path),
+            Kind::Message(nested)
+                if !field.is_list()
+                    && !field.is_map()
+                    && !nested.full_name().starts_with("google.protobuf.") =>
+            {
+                collect_field_hints(
+                    &nested,
+                    &format!("{}.", path),
+                    stack,
+                    bool_fields,
+                    repeated_fields,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    stack.pop();
+}
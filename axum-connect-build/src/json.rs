@@ -0,0 +1,137 @@
+use std::collections::BTreeMap;
+
+use prost_reflect::{DescriptorPool, Kind};
+
+/// Rewrites the pbjson `Serialize` impls so `google.protobuf.Timestamp` fields go through
+/// `axum_connect::json::Timestamp`, which emits the Z-normalized form the protobuf JSON mapping
+/// calls for. Everything else pbjson already gets right (unset optionals are omitted, 64-bit ints
+/// and their wrappers are strings, durations are `"1.5s"`).
+pub fn normalize_timestamps(
+    pool: &DescriptorPool,
+    files_to_generate: &[String],
+    files: &mut BTreeMap<String, String>,
+) {
+    for message in pool.all_messages() {
+        let file = message.parent_file();
+        if !files_to_generate.iter().any(|name| name == file.name()) {
+            continue;
+        }
+
+        let Some(contents) = files.get_mut(&format!("{}.rs", file.package_name())) else {
+            continue;
+        };
+
+        let replacements = message
+            .fields()
+            .filter_map(|field| {
+                // Repeated fields and maps are serialized whole, through a wrapper of their own.
+                let (kind, wrapper) = match field.kind() {
+                    Kind::Message(entry) if field.is_map() => (
+                        entry.map_entry_value_field().kind(),
+                        Some("axum_connect::json::TimestampMap"),
+                    ),
+                    kind if field.is_list() => (kind, Some("axum_connect::json::Timestamps")),
+                    kind => (kind, None),
+                };
+                matches!(kind, Kind::Message(m) if m.full_name() == "google.protobuf.Timestamp")
+                    .then(|| (field.json_name().to_string(), wrapper))
+            })
+            .collect::<Vec<_>>();
+
+        if replacements.is_empty() {
+            continue;
+        }
+
+        // Only touch this message's `Serialize` impl, other messages can reuse field names.
+        let start_marker = format!("serialize_struct(\"{}\", len)?;", message.full_name());
+        let Some(start) = contents.find(&start_marker) else {
+            continue;
+        };
+        let end = contents[start..]
+            .find("struct_ser.end()")
+            .map(|end| start + end)
+            .unwrap_or(contents.len());
+
+        let mut body = contents[start..end].to_string();
+        for (json_name, wrapper) in replacements {
+            let prefix = format!("struct_ser.serialize_field(\"{}\", ", json_name);
+            body = match wrapper {
+                // `&self.field)?;`, where `field` is however prost named it.
+                Some(wrapper) => wrap_value(&body, &format!("{}&self.", prefix), wrapper),
+                // Singular and oneof fields are both serialized from a `v` binding.
+                None => body.replace(
+                    &format!("{}v)?;", prefix),
+                    &format!("{}&axum_connect::json::Timestamp(v))?;", prefix),
+                ),
+            };
+        }
+        contents.replace_range(start..end, &body);
+    }
+}
+
+/// Wraps the `&self.field` argument following every `prefix` (which ends in `&self.`) in `wrapper`.
+fn wrap_value(body: &str, prefix: &str, wrapper: &str) -> String {
+    let mut out = String::new();
+    let mut rest = body;
+
+    while let Some(index) = rest.find(prefix) {
+        let value_start = index + prefix.len() - "&self.".len();
+        out.push_str(&rest[..value_start]);
+        rest = &rest[value_start..];
+
+        let value_end = rest.find(")?;").unwrap_or(rest.len());
+        out.push_str(&format!("&{}({})", wrapper, &rest[..value_end]));
+        rest = &rest[value_end..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+/// Rewrites the pbjson `Deserialize` impls of enums to decode unknown names and numbers as the
+/// zero value, proto3's open enum behavior, instead of failing. Every enum field goes through
+/// these impls, so this covers nested messages, repeated fields and maps alike.
+pub fn open_enums(files: &mut BTreeMap<String, String>) {
+    for contents in files.values_mut() {
+        *contents = contents.replace(
+            "_ => Err(serde::de::Error::unknown_variant(value, FIELDS)),",
+            "_ => Ok(Default::default()),",
+        );
+        *contents = open_enum_numbers(contents);
+    }
+}
+
+/// Replaces the `visit_i64`/`visit_u64` bodies, which fail with `invalid_value` on numbers that
+/// aren't a variant.
+fn open_enum_numbers(contents: &str) -> String {
+    const START: &str = "i32::try_from(v)";
+    const ERROR: &str = "serde::de::Error::invalid_value(serde::de::Unexpected::";
+    const END: &str = "})";
+
+    let mut out = String::new();
+    let mut rest = contents;
+
+    while let Some(index) = rest.find(START) {
+        out.push_str(&rest[..index]);
+        rest = &rest[index..];
+
+        // Only the enum visitors have an `invalid_value` error right after the conversion.
+        let Some(end) = rest
+            .find(ERROR)
+            .filter(|error| !rest[START.len()..*error].contains(START))
+            .and_then(|error| rest[error..].find(END).map(|end| error + end + END.len()))
+        else {
+            out.push_str(START);
+            rest = &rest[START.len()..];
+            continue;
+        };
+
+        out.push_str(
+            "Ok(i32::try_from(v).ok().and_then(|x| x.try_into().ok()).unwrap_or_default())",
+        );
+        rest = &rest[end..];
+    }
+
+    out.push_str(rest);
+    out
+}
@@ -1,15 +1,27 @@
 use std::{
-    cell::RefCell,
+    collections::BTreeMap,
     env,
-    io::{BufWriter, Write},
-    ops::Deref,
     path::{Path, PathBuf},
-    rc::Rc,
 };
 
 use gen::AxumConnectServiceGenerator;
+use prost_build::Module;
+use prost_reflect::DescriptorPool;
 
+pub use diagnostics::{Diagnostic, DiagnosticKind};
+
+mod diagnostics;
+mod docs;
 mod gen;
+mod http;
+mod json;
+mod manifest;
+mod oneof;
+mod openapi;
+mod pagination;
+mod reflect;
+#[cfg(test)]
+mod test_util;
 
 #[derive(Clone, Debug)]
 pub struct AxumConnectGenSettings {
@@ -17,6 +29,66 @@ pub struct AxumConnectGenSettings {
     pub inputs: Vec<PathBuf>,
     pub protoc_args: Vec<String>,
     pub protoc_version: Option<String>,
+    /// If set, also emit a file with this name that includes every generated file in nested
+    /// `pub mod`s matching the proto packages.
+    pub include_file: Option<String>,
+    /// Generate pbjson Serde impls so messages can be sent as JSON. Defaults to `true`. Proto-only
+    /// services can turn this off to save on compile time, but must also disable the `json`
+    /// feature of `axum-connect`; JSON requests are then rejected with `Unimplemented`.
+    pub json: bool,
+    /// If set, also emit an OpenAPI v3 document describing every service at this path. Relative
+    /// paths are resolved against `OUT_DIR` (or the plugin output directory).
+    pub openapi: Option<String>,
+    /// If set, also emit a JSON manifest of every RPC (path, streaming kind, idempotency and
+    /// accepted HTTP methods) at this path, eg. for configuring an API gateway. Same schema as
+    /// `RpcRouter::manifest_json` at runtime.
+    pub routes_manifest: Option<String>,
+    /// RPCs the `routes_manifest` lists with `auth_required: false`, those the server mounts with
+    /// `RouteOptions::public()`: either a method, as `package.Service/Method`, or every method of a
+    /// service, as `package.Service`. Every other RPC is listed as requiring authorization.
+    pub public_rpcs: Vec<String>,
+    /// Write the generated files here instead of `OUT_DIR`, eg. to check them in. Relative paths
+    /// are resolved against the crate's manifest directory.
+    pub out_dir: Option<PathBuf>,
+    /// If set, the package files go in a `{gen_mod_name}/` directory next to a `{gen_mod_name}.rs`
+    /// file that includes them, nested in `pub mod`s matching the proto packages. Declare it with
+    /// `mod {gen_mod_name};` when generating into `src/`.
+    pub gen_mod_name: Option<String>,
+    /// Embed each package's descriptors and implement `prost_reflect::ReflectMessage` for its
+    /// messages, which `axum_connect::field_mask` needs. Requires the `field-mask` feature of
+    /// `axum-connect`. Defaults to `false`.
+    pub field_masks: bool,
+    /// Decode unknown enum names and numbers in JSON requests as the zero value, like proto3's
+    /// open enums do in binary, so clients may send values the server doesn't know yet. With the
+    /// default `false` they're rejected with `InvalidArgument`, naming the field and value.
+    pub open_enums: bool,
+    /// Implement `axum_connect::pagination::HasPageToken` for messages with a `string page_token`
+    /// field, so `Page` can decode their tokens. Requires the `pagination` feature of
+    /// `axum-connect`. Defaults to `false`.
+    pub page_tokens: bool,
+    /// Add accessors to messages with a oneof: for a oneof `payload` with a field `text`,
+    /// `payload_as_text()`, which returns the field or fails with `InvalidArgument` naming it, and
+    /// `payload_kind_name()`, the name of the field that's set, for errors and logs. Defaults to
+    /// `false`.
+    pub oneof_helpers: bool,
+    /// Also generate a `<Service>Handlers` trait per service, with a method per RPC taking an
+    /// `axum_connect::request::RpcRequest` and returning an `RpcResponse`, and on the
+    /// service struct a `register_all(router, handlers)` mounting every RPC with an
+    /// implementation of it, and `registrars()`, an `axum_connect::router::RpcRegistrar` per RPC
+    /// to mount them from a table, eg. all but some by name. Defaults to `false`.
+    pub service_traits: bool,
+    /// Also generate a `<Service>Client` per service, with a method per RPC calling it through an
+    /// `axum_connect::client::RpcClient`, which has the base URL, metadata, timeout and
+    /// interceptors of the calls. Defaults to `false`.
+    pub clients: bool,
+    /// Run the generated Rust files through `prettyplease`. Defaults to `false`.
+    pub format: bool,
+    /// Don't rewrite output files whose contents didn't change, so editors and incremental builds
+    /// watching them don't churn. Defaults to `false`.
+    pub skip_if_unchanged: bool,
+    /// Fail the build on any diagnostic, eg. a skipped client-streaming method or an ignored
+    /// custom option, rather than only warning about them. Defaults to `false`.
+    pub deny_unsupported: bool,
 }
 
 impl Default for AxumConnectGenSettings {
@@ -26,6 +98,22 @@ impl Default for AxumConnectGenSettings {
             inputs: Default::default(),
             protoc_args: Default::default(),
             protoc_version: Some("31.1".to_string()),
+            include_file: None,
+            json: true,
+            openapi: None,
+            routes_manifest: None,
+            public_rpcs: vec![],
+            out_dir: None,
+            gen_mod_name: None,
+            field_masks: false,
+            open_enums: false,
+            page_tokens: false,
+            oneof_helpers: false,
+            service_traits: false,
+            clients: false,
+            format: false,
+            skip_if_unchanged: false,
+            deny_unsupported: false,
         }
     }
 }
@@ -70,58 +158,454 @@ pub fn axum_connect_codegen(settings: AxumConnectGenSettings) -> anyhow::Result<
         println!("cargo:rerun-if-changed={}", input.display());
     }
 
-    let descriptor_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("proto_descriptor.bin");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let descriptor_path = out_dir.join("proto_descriptor.bin");
 
     let mut conf = prost_build::Config::new();
-
-    // Standard prost configuration
-    conf.compile_well_known_types();
     conf.file_descriptor_set_path(&descriptor_path);
-    conf.extern_path(".google.protobuf", "::axum_connect::pbjson_types");
-    conf.service_generator(Box::new(AxumConnectServiceGenerator::new()));
 
     // Arg configuration
-    for arg in settings.protoc_args {
+    for arg in &settings.protoc_args {
         conf.protoc_arg(arg);
     }
 
-    // File configuration
-    conf.compile_protos(&settings.inputs, &settings.includes)
-        .unwrap();
+    // Run protoc, then generate everything it gave back. That includes imports, same as
+    // `compile_protos` would. The descriptor set is re-read raw from disk because the decoded
+    // version drops custom options like `google.api.http`.
+    let descriptor_set = conf.load_fds(&settings.inputs, &settings.includes)?;
+    let files_to_generate = descriptor_set
+        .file
+        .iter()
+        .map(|file| file.name().to_string())
+        .collect::<Vec<_>>();
+
+    let generated = generate_files(
+        &std::fs::read(&descriptor_path)?,
+        &files_to_generate,
+        &settings,
+    )?;
+
+    for diagnostic in &generated.diagnostics {
+        println!("cargo:warning={}", diagnostic);
+    }
+    // Always written, so CI can read them from a clean build too, `[]` if there's none.
+    std::fs::write(
+        out_dir.join("axum_connect_diagnostics.json"),
+        diagnostics::to_json(&generated.diagnostics),
+    )?;
+    generated.check(&settings)?;
+
+    let out_dir = match &settings.out_dir {
+        Some(dir) => Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join(dir),
+        None => out_dir,
+    };
+    write_files(&generated.files, &out_dir, settings.skip_if_unchanged)?;
+
+    Ok(())
+}
+
+/// Writes `files` under `dir`, creating their directories, and returns the names of those it
+/// wrote: with `skip_if_unchanged`, files already there with the same contents are left alone.
+fn write_files(
+    files: &BTreeMap<String, String>,
+    dir: &Path,
+    skip_if_unchanged: bool,
+) -> std::io::Result<Vec<String>> {
+    let mut written = vec![];
+    for (name, contents) in files {
+        let path = dir.join(name);
+        if skip_if_unchanged
+            && std::fs::read(&path).is_ok_and(|existing| existing == contents.as_bytes())
+        {
+            continue;
+        }
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, contents)?;
+        written.push(name.clone());
+    }
+    Ok(written)
+}
+
+/// The output of [`generate_files`].
+pub struct GeneratedFiles {
+    /// Output file name to file contents.
+    pub files: BTreeMap<String, String>,
+    /// Constructs the generator skipped or can't compile, like client-streaming methods or
+    /// unsupported `google.api.http` rules, sorted by kind and element.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl GeneratedFiles {
+    /// Fails listing every diagnostic if there are any and `settings.deny_unsupported` is set.
+    pub fn check(&self, settings: &AxumConnectGenSettings) -> anyhow::Result<()> {
+        if !settings.deny_unsupported || self.diagnostics.is_empty() {
+            return Ok(());
+        }
+        let diagnostics = self
+            .diagnostics
+            .iter()
+            .map(|diagnostic| format!("\n  {}", diagnostic))
+            .collect::<String>();
+        anyhow::bail!(
+            "axum-connect-build found {} unsupported proto construct(s), and deny_unsupported is set:{}",
+            self.diagnostics.len(),
+            diagnostics
+        )
+    }
+}
+
+/// Generates the Prost types, pbjson Serde impls and axum-connect service handlers for
+/// `files_to_generate` (proto file names, as protoc reports them). `descriptor_set` is an encoded
+/// `FileDescriptorSet` that must also contain every file they import.
+///
+/// Output is deterministic: files are keyed by name and services/methods are emitted sorted by
+/// fully-qualified name. This is shared by the build script flow above and the
+/// `protoc-gen-axum-connect` plugin; only codegen related fields of `settings` are used.
+pub fn generate_files(
+    descriptor_set: &[u8],
+    files_to_generate: &[String],
+    settings: &AxumConnectGenSettings,
+) -> anyhow::Result<GeneratedFiles> {
+    let pool = DescriptorPool::decode(descriptor_set)?;
+    let descriptors = pool.file_descriptor_protos().collect::<Vec<_>>();
+
+    let mut diagnostics = vec![];
+    let rest_routes = http::collect_rest_routes(&pool, &mut diagnostics);
+    diagnostics::collect(
+        &pool,
+        files_to_generate,
+        settings.service_traits,
+        settings.clients,
+        &mut diagnostics,
+    );
+    diagnostics.sort();
+    diagnostics.dedup();
+
+    let mut conf = prost_build::Config::new();
+
+    // Standard prost configuration
+    conf.compile_well_known_types();
+    conf.extern_path(".google.protobuf", "::axum_connect::pbjson_types");
+    // `prost::Name` impls, for packing messages in `Any`s with `axum_connect::any`.
+    conf.enable_type_names();
+    conf.type_name_domain(["."], "type.googleapis.com");
+    conf.service_generator(Box::new(AxumConnectServiceGenerator::new(
+        rest_routes,
+        settings.service_traits,
+        settings.clients,
+    )));
+
+    let requests = descriptors
+        .iter()
+        .filter(|file| files_to_generate.iter().any(|name| name == file.name()))
+        .map(|file| {
+            (
+                Module::from_protobuf_package_name(file.package()),
+                (*file).clone(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // Only generate Serde impls for the packages we're generating Prost types for.
+    let prefixes = requests
+        .iter()
+        .map(|(_, file)| format!(".{}", file.package()))
+        .collect::<Vec<_>>();
+
+    let mut files = conf
+        .generate(requests)?
+        .into_iter()
+        .map(|(module, contents)| {
+            let contents = docs::fence_generated_docs(&contents);
+            (module.to_file_name_or("_"), contents)
+        })
+        .collect::<BTreeMap<_, _>>();
 
     // Use pbjson to generate the Serde impls, and inline them with the Prost files.
-    let descriptor_set = std::fs::read(descriptor_path)?;
-    let mut output: PathBuf = PathBuf::from(env::var("OUT_DIR").unwrap());
-    output.push("FILENAME");
+    if settings.json {
+        let mut builder = pbjson_build::Builder::new();
+        for file in &descriptors {
+            builder.register_file_descriptor((*file).clone());
+        }
 
-    // TODO: This is a nasty hack. Get rid of it. Idk how without dumping Prost and pbjson though.
-    let files = Rc::new(RefCell::new(vec![]));
+        let writers = builder
+            .extern_path(".google.protobuf", "::axum_connect::pbjson_types")
+            .generate(&prefixes, |_| Ok(Vec::<u8>::new()))?;
 
-    let files_c = files.clone();
-    let writers = pbjson_build::Builder::new()
-        .register_descriptors(&descriptor_set)?
-        .extern_path(".google.protobuf", "::axum_connect::pbjson_types")
-        .generate(&["."], move |package| {
-            output.set_file_name(format!("{}.rs", package));
-            files_c.deref().borrow_mut().push(output.clone());
+        for (package, writer) in writers {
+            files
+                .entry(format!("{}.rs", package))
+                .or_default()
+                .push_str(&String::from_utf8(writer)?);
+        }
 
-            let file = std::fs::OpenOptions::new().append(true).create(true).open(&output)?;
+        json::normalize_timestamps(&pool, files_to_generate, &mut files);
+        if settings.open_enums {
+            json::open_enums(&mut files);
+        }
+    }
 
-            Ok(BufWriter::new(file))
-        })?;
+    // Replace a few namespaces with re-exported ones, so users don't need matching versions of
+    // Prost, pbjson and Serde in their own crate.
+    for contents in files.values_mut() {
+        *contents = contents
+            .replace("pbjson::", "axum_connect::pbjson::")
+            .replace("prost::", "axum_connect::prost::")
+            .replace("serde::", "axum_connect::serde::");
+    }
 
-    for (_, mut writer) in writers {
-        writer.flush()?;
+    if settings.field_masks {
+        reflect::embed_descriptors(&pool, files_to_generate, &mut files);
     }
 
-    // Now second part of the nasty hack, replace a few namespaces with re-exported ones.
-    for file in files.take().into_iter() {
-        let contents = std::fs::read_to_string(&file)?;
-        let contents = contents.replace("pbjson::", "axum_connect::pbjson::");
-        let contents = contents.replace("prost::", "axum_connect::prost::");
-        let contents = contents.replace("serde::", "axum_connect::serde::");
-        std::fs::write(&file, contents)?;
+    if settings.page_tokens {
+        pagination::page_tokens(&pool, files_to_generate, &mut files);
     }
 
-    Ok(())
+    if settings.oneof_helpers {
+        oneof::oneof_helpers(&pool, files_to_generate, &mut files);
+    }
+
+    if settings.format {
+        for contents in files.values_mut() {
+            *contents = prettyplease::unparse(&syn::parse_file(contents)?);
+        }
+    }
+
+    if let Some(gen_mod_name) = &settings.gen_mod_name {
+        files = files
+            .into_iter()
+            .map(|(name, contents)| (format!("{}/{}", gen_mod_name, name), contents))
+            .collect();
+    }
+
+    if let Some(include_file) = &settings.include_file {
+        let contents = generate_include_file(files.keys());
+        files.insert(include_file.clone(), contents);
+    }
+
+    if let Some(gen_mod_name) = &settings.gen_mod_name {
+        let prefix = format!("{}/", gen_mod_name);
+        let contents = generate_include_file(files.keys().filter(|name| name.starts_with(&prefix)));
+        files.insert(format!("{}.rs", gen_mod_name), contents);
+    }
+
+    // Stamp every file with the generator version, so stale checked-in code is easy to spot.
+    let header = format!(
+        "// @generated by axum-connect-build v{}. DO NOT EDIT.\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    for contents in files.values_mut() {
+        contents.insert_str(0, &header);
+    }
+
+    // Added after stamping, JSON doesn't have comments.
+    if let Some(openapi) = &settings.openapi {
+        files.insert(
+            openapi.clone(),
+            openapi::generate_openapi(&pool, files_to_generate),
+        );
+    }
+
+    if let Some(routes_manifest) = &settings.routes_manifest {
+        files.insert(
+            routes_manifest.clone(),
+            manifest::generate_manifest(&pool, files_to_generate, &settings.public_rpcs),
+        );
+    }
+
+    Ok(GeneratedFiles { files, diagnostics })
+}
+
+/// Builds a single file that `include!`s every generated file, nested in `pub mod`s matching the
+/// proto packages.
+fn generate_include_file<'a>(file_names: impl Iterator<Item = &'a String>) -> String {
+    let mut contents = String::new();
+    let mut stack: Vec<&str> = vec![];
+
+    for file_name in file_names {
+        // Files under a `gen_mod_name` directory are still nested by package alone.
+        let package = file_name
+            .rsplit('/')
+            .next()
+            .unwrap_or(file_name)
+            .trim_end_matches(".rs");
+        let parts = package.split('.').collect::<Vec<_>>();
+
+        let common = stack.iter().zip(&parts).take_while(|(a, b)| a == b).count();
+
+        while stack.len() > common {
+            stack.pop();
+            contents.push_str(&format!("{}}}\n", "    ".repeat(stack.len())));
+        }
+
+        for part in &parts[common..] {
+            contents.push_str(&format!(
+                "{}pub mod {} {{\n",
+                "    ".repeat(stack.len()),
+                part
+            ));
+            stack.push(part);
+        }
+
+        contents.push_str(&format!(
+            "{}include!(\"{}\");\n",
+            "    ".repeat(stack.len()),
+            file_name
+        ));
+    }
+
+    while !stack.is_empty() {
+        stack.pop();
+        contents.push_str(&format!("{}}}\n", "    ".repeat(stack.len())));
+    }
+
+    contents
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::generate;
+
+    /// Services and methods out of order, in two packages, one of them with only a service.
+    const FILES: &[(&str, &str)] = &[
+        (
+            "zoo.proto",
+            r#"syntax = "proto3";
+package zoo;
+
+message Animal { string name = 1; optional int64 legs = 2; }
+
+service Zebras {
+  rpc Watch(Animal) returns (stream Animal) {}
+  rpc Feed(Animal) returns (Animal) {}
+}
+
+service Aardvarks {
+  rpc Find(Animal) returns (Animal) {
+    option idempotency_level = NO_SIDE_EFFECTS;
+  }
+  rpc Count(Animal) returns (Animal) {}
+}
+"#,
+        ),
+        (
+            "farm.proto",
+            r#"syntax = "proto3";
+package farm;
+
+import "zoo.proto";
+
+service Barn {
+  rpc Adopt(zoo.Animal) returns (zoo.Animal) {}
+}
+"#,
+        ),
+    ];
+
+    fn settings() -> AxumConnectGenSettings {
+        AxumConnectGenSettings {
+            include_file: Some("mod.rs".to_string()),
+            openapi: Some("openapi.json".to_string()),
+            routes_manifest: Some("routes.json".to_string()),
+            field_masks: true,
+            page_tokens: true,
+            oneof_helpers: true,
+            service_traits: true,
+            clients: true,
+            ..AxumConnectGenSettings::default()
+        }
+    }
+
+    #[test]
+    fn regenerates_byte_identical_files() {
+        let first = generate(FILES, &settings()).files;
+        for _ in 0..3 {
+            assert_eq!(generate(FILES, &settings()).files, first);
+        }
+        // Whatever order protoc is asked for the files in.
+        let mut reversed = FILES.to_vec();
+        reversed.reverse();
+        assert_eq!(generate(&reversed, &settings()).files, first);
+        assert_eq!(
+            first.keys().collect::<Vec<_>>(),
+            ["farm.rs", "mod.rs", "openapi.json", "routes.json", "zoo.rs"]
+        );
+        assert!(first["farm.rs"].contains("pub struct Barn;"));
+    }
+
+    #[test]
+    fn sorts_services_and_methods_by_name() {
+        let zoo = &generate(FILES, &settings()).files["zoo.rs"];
+        let at = |item: &str| zoo.find(item).unwrap_or_else(|| panic!("no {item}"));
+        assert!(at("pub struct Aardvarks;") < at("pub struct Zebras;"));
+        assert!(at("pub const COUNT_PATH") < at("pub const FIND_PATH"));
+        assert!(at("pub const FEED_PATH") < at("pub const WATCH_PATH"));
+    }
+
+    #[test]
+    fn skips_the_serde_impls_in_proto_only_mode() {
+        let with_json = &generate(FILES, &AxumConnectGenSettings::default()).files["zoo.rs"];
+        assert!(with_json.contains("impl axum_connect::serde::Serialize for Animal"));
+        assert!(with_json.contains("impl<'de> axum_connect::serde::Deserialize<'de> for Animal"));
+
+        let settings = AxumConnectGenSettings {
+            json: false,
+            ..AxumConnectGenSettings::default()
+        };
+        let proto_only = &generate(FILES, &settings).files["zoo.rs"];
+        assert!(!proto_only.contains("serde"), "{proto_only}");
+        assert!(!proto_only.contains("pbjson"), "{proto_only}");
+        // The services are the same either way.
+        assert!(proto_only.contains("pub struct Zebras;"));
+        assert!(proto_only.contains("pub fn feed<T, H, S>("));
+    }
+
+    #[test]
+    fn stamps_the_generator_version() {
+        let generated = generate(FILES, &settings()).files;
+        let header = format!(
+            "// @generated by axum-connect-build v{}. DO NOT EDIT.\n",
+            env!("CARGO_PKG_VERSION")
+        );
+        for name in ["farm.rs", "mod.rs", "zoo.rs"] {
+            assert!(generated[name].starts_with(&header), "{name}");
+        }
+        assert!(generated["openapi.json"].starts_with('{'));
+    }
+
+    #[test]
+    fn writes_a_module_directory_and_skips_unchanged_files() {
+        let settings = AxumConnectGenSettings {
+            gen_mod_name: Some("gen".to_string()),
+            format: true,
+            ..AxumConnectGenSettings::default()
+        };
+        let mut files = generate(FILES, &settings).files;
+        let dir = tempfile::tempdir().unwrap();
+
+        let written = write_files(&files, dir.path(), true).unwrap();
+        assert_eq!(written, ["gen.rs", "gen/farm.rs", "gen/zoo.rs"]);
+        let module = std::fs::read_to_string(dir.path().join("gen.rs")).unwrap();
+        assert!(module.contains("pub mod zoo {\n    include!(\"gen/zoo.rs\");\n}"));
+        // Formatted already, so formatting it again changes nothing.
+        let zoo = std::fs::read_to_string(dir.path().join("gen/zoo.rs")).unwrap();
+        let body = zoo.split_once('\n').unwrap().1;
+        assert_eq!(prettyplease::unparse(&syn::parse_file(body).unwrap()), body);
+
+        assert!(write_files(&files, dir.path(), true).unwrap().is_empty());
+        files
+            .get_mut("gen/zoo.rs")
+            .unwrap()
+            .push_str("// changed\n");
+        assert_eq!(
+            write_files(&files, dir.path(), true).unwrap(),
+            ["gen/zoo.rs"]
+        );
+        assert_eq!(write_files(&files, dir.path(), false).unwrap().len(), 3);
+    }
 }
@@ -0,0 +1,158 @@
+use prost_reflect::DescriptorPool;
+use serde_json::json;
+
+/// Generates the `routes.json` manifest for every service in `files_to_generate`.
+///
+/// The schema matches `RpcRouter::manifest_json` in `axum-connect`, so the build-time file and the
+/// runtime endpoint can be used interchangeably. Routes are sorted by path. Every route has
+/// `auth_required` set unless it's in `public_rpcs`, by its path without the leading `/` or by its
+/// service's full name.
+pub fn generate_manifest(
+    pool: &DescriptorPool,
+    files_to_generate: &[String],
+    public_rpcs: &[String],
+) -> String {
+    let mut routes = vec![];
+
+    for file in pool
+        .files()
+        .filter(|file| files_to_generate.iter().any(|name| name == file.name()))
+    {
+        for service in file.services() {
+            for method in service.methods() {
+                let kind = match (method.is_client_streaming(), method.is_server_streaming()) {
+                    (false, false) => "unary",
+                    (true, false) => "client_streaming",
+                    (false, true) => "server_streaming",
+                    (true, true) => "bidi_streaming",
+                };
+
+                let idempotency_level = method
+                    .method_descriptor_proto()
+                    .options
+                    .as_ref()
+                    .map(|options| options.idempotency_level.unwrap_or_default())
+                    .unwrap_or_default();
+
+                // Generated code also mounts GET for `NO_SIDE_EFFECTS` unary methods.
+                let http_methods = if idempotency_level == 1 && kind == "unary" {
+                    vec!["POST", "GET"]
+                } else {
+                    vec!["POST"]
+                };
+
+                let rpc = format!("{}/{}", service.full_name(), method.name());
+                let public = public_rpcs
+                    .iter()
+                    .any(|public| *public == rpc || public == service.full_name());
+
+                routes.push(json!({
+                    "path": format!("/{rpc}"),
+                    "service": service.full_name(),
+                    "method": method.name(),
+                    "kind": kind,
+                    "idempotent": idempotency_level != 0,
+                    "idempotency_level": match idempotency_level {
+                        1 => "no_side_effects",
+                        2 => "idempotent",
+                        _ => "idempotency_unknown",
+                    },
+                    "http_methods": http_methods,
+                    "auth_required": !public,
+                }));
+            }
+        }
+    }
+
+    routes.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+
+    let manifest = json!({ "version": 1, "routes": routes });
+    serde_json::to_string_pretty(&manifest).unwrap() + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use crate::{test_util::generate, AxumConnectGenSettings};
+
+    const SHOP: &str = r#"syntax = "proto3";
+package shop;
+
+message Empty {}
+
+service Catalog {
+  rpc List(Empty) returns (Empty) {
+    option idempotency_level = NO_SIDE_EFFECTS;
+  }
+  rpc Watch(Empty) returns (stream Empty) {}
+}
+
+service Orders {
+  rpc Place(Empty) returns (Empty) {
+    option idempotency_level = IDEMPOTENT;
+  }
+  rpc Status(Empty) returns (Empty) {}
+}
+"#;
+
+    fn manifest(public_rpcs: &[&str]) -> Value {
+        let settings = AxumConnectGenSettings {
+            routes_manifest: Some("routes.json".to_string()),
+            public_rpcs: public_rpcs.iter().map(|rpc| rpc.to_string()).collect(),
+            ..Default::default()
+        };
+        let generated = generate(&[("shop.proto", SHOP)], &settings);
+        serde_json::from_str(&generated.files["routes.json"]).unwrap()
+    }
+
+    fn auth_required(manifest: &Value) -> Vec<(&str, bool)> {
+        manifest["routes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|route| {
+                (
+                    route["path"].as_str().unwrap(),
+                    route["auth_required"].as_bool().unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists_every_rpc_sorted_by_path() {
+        let manifest = manifest(&[]);
+        assert_eq!(manifest["version"], 1);
+        assert_eq!(
+            manifest["routes"][0],
+            json!({
+                "path": "/shop.Catalog/List",
+                "service": "shop.Catalog",
+                "method": "List",
+                "kind": "unary",
+                "idempotent": true,
+                "idempotency_level": "no_side_effects",
+                "http_methods": ["POST", "GET"],
+                "auth_required": true,
+            })
+        );
+        assert_eq!(manifest["routes"][1]["kind"], "server_streaming");
+        assert_eq!(manifest["routes"][1]["http_methods"], json!(["POST"]));
+        assert_eq!(manifest["routes"][2]["idempotency_level"], "idempotent");
+        assert_eq!(manifest["routes"][3]["idempotent"], false);
+    }
+
+    #[test]
+    fn requires_auth_unless_public() {
+        assert_eq!(
+            auth_required(&manifest(&["shop.Catalog", "shop.Orders/Status"])),
+            [
+                ("/shop.Catalog/List", false),
+                ("/shop.Catalog/Watch", false),
+                ("/shop.Orders/Place", true),
+                ("/shop.Orders/Status", false),
+            ]
+        );
+    }
+}
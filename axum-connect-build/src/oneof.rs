@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use proc_macro2::TokenStream;
+use prost_reflect::{DescriptorPool, FieldDescriptor, Kind, MessageDescriptor, OneofDescriptor};
+use quote::{format_ident, quote};
+
+use crate::reflect::{rust_ident, rust_type_path};
+
+/// Adds accessors to every message with a oneof, appended to its package file: for a oneof
+/// `payload` with a field `text`, `payload_as_text(&self) -> Result<&String, RpcError>`, failing
+/// with `InvalidArgument` naming the expected field unless that's the one set, and
+/// `payload_kind_name(&self) -> Option<&'static str>`, the name of the field that is.
+pub fn oneof_helpers(
+    pool: &DescriptorPool,
+    files_to_generate: &[String],
+    files: &mut BTreeMap<String, String>,
+) {
+    for file in pool.files() {
+        if !files_to_generate.iter().any(|name| name == file.name()) {
+            continue;
+        }
+        let package = file.package_name();
+        let file_name = match package {
+            "" => "_.rs".to_string(),
+            package => format!("{}.rs", package),
+        };
+        let Some(contents) = files.get_mut(&file_name) else {
+            continue;
+        };
+
+        let mut messages = file.messages().collect::<Vec<_>>();
+        let mut impls = vec![];
+        while let Some(message) = messages.pop() {
+            messages.extend(message.child_messages());
+            let methods = message
+                .oneofs()
+                .filter(|oneof| !is_synthetic(oneof))
+                .map(|oneof| oneof_methods(package, &message, &oneof))
+                .collect::<Vec<_>>();
+            if methods.is_empty() {
+                continue;
+            }
+
+            let rust_path = rust_type_path(package, message.full_name());
+            impls.push(quote! {
+                impl #rust_path {
+                    #(#methods)*
+                }
+            });
+        }
+
+        if !impls.is_empty() {
+            contents.push_str(&quote! { #(#impls)* }.to_string());
+            contents.push('\n');
+        }
+    }
+}
+
+/// The oneofs prost doesn't generate an enum for, those of proto3 `optional` fields.
+fn is_synthetic(oneof: &OneofDescriptor) -> bool {
+    oneof
+        .fields()
+        .all(|field| field.field_descriptor_proto().proto3_optional())
+}
+
+fn oneof_methods(
+    package: &str,
+    message: &MessageDescriptor,
+    oneof: &OneofDescriptor,
+) -> TokenStream {
+    let oneof_name = oneof.name();
+    let field = rust_ident(&oneof_name.to_snake_case());
+    let enum_path = oneof_enum_path(package, message, oneof);
+    let kind_name = format_ident!("{}_kind_name", oneof_name.to_snake_case());
+
+    let fields = oneof.fields().collect::<Vec<_>>();
+    let variants = fields
+        .iter()
+        .map(|field| rust_ident(&field.name().to_upper_camel_case()))
+        .collect::<Vec<_>>();
+    let names = fields.iter().map(|field| field.name()).collect::<Vec<_>>();
+
+    let accessors = fields
+        .iter()
+        .zip(&variants)
+        .map(|(variant_field, variant)| {
+            let name = variant_field.name();
+            let method =
+                format_ident!("{}_as_{}", oneof_name.to_snake_case(), name.to_snake_case());
+            let rust_type = field_type(package, variant_field);
+            let doc = format!(
+                " The `{}` of the `{}` oneof, `InvalidArgument` if another field or none is set.",
+                name, oneof_name
+            );
+            let wrong = format!("Expected `{}` in `{}`, got `{{}}`", name, oneof_name);
+            let unset = format!("Expected `{}` in `{}`, but it isn't set", name, oneof_name);
+            // A oneof with a single field has no other to be set.
+            let other = (fields.len() > 1).then(|| {
+                quote! { Some(_) => format!(#wrong, self.#kind_name().unwrap_or_default()), }
+            });
+            quote! {
+                #[doc = #doc]
+                pub fn #method(&self) -> Result<&#rust_type, axum_connect::error::RpcError> {
+                    let message = match &self.#field {
+                        Some(#enum_path::#variant(value)) => {
+                            // Recursive messages are boxed, this derefs those to the field type too.
+                            let value: &#rust_type = value;
+                            return Ok(value);
+                        }
+                        #other
+                        None => #unset.to_string(),
+                    };
+                    Err(axum_connect::error::RpcError::new(
+                        axum_connect::error::RpcErrorCode::InvalidArgument,
+                        message,
+                    ))
+                }
+            }
+        });
+
+    let doc = format!(
+        " The name of the field set in the `{}` oneof, `None` if none is.",
+        oneof_name
+    );
+    quote! {
+        #(#accessors)*
+
+        #[doc = #doc]
+        pub fn #kind_name(&self) -> Option<&'static str> {
+            match &self.#field {
+                #(Some(#enum_path::#variants(_)) => Some(#names),)*
+                None => None,
+            }
+        }
+    }
+}
+
+/// The path of the enum prost generates for `oneof`, in the module named after its message.
+fn oneof_enum_path(
+    package: &str,
+    message: &MessageDescriptor,
+    oneof: &OneofDescriptor,
+) -> TokenStream {
+    let full_name = message.full_name();
+    let relative = match package {
+        "" => full_name,
+        package => &full_name[package.len() + 1..],
+    };
+    let modules = relative
+        .split('.')
+        .map(|segment| rust_ident(&segment.to_snake_case()));
+    let name = rust_ident(&oneof.name().to_upper_camel_case());
+    quote! { #(#modules::)* #name }
+}
+
+/// The Rust type of a oneof field, relative to the package module, the way prost maps it.
+fn field_type(package: &str, field: &FieldDescriptor) -> TokenStream {
+    match field.kind() {
+        Kind::Double => quote! { f64 },
+        Kind::Float => quote! { f32 },
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => quote! { i32 },
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => quote! { i64 },
+        Kind::Uint32 | Kind::Fixed32 => quote! { u32 },
+        Kind::Uint64 | Kind::Fixed64 => quote! { u64 },
+        Kind::Bool => quote! { bool },
+        Kind::String => quote! { ::std::string::String },
+        Kind::Bytes => quote! { ::std::vec::Vec<u8> },
+        // Open enums, as their number.
+        Kind::Enum(_) => quote! { i32 },
+        Kind::Message(target) => message_path(package, &target),
+    }
+}
+
+/// The path of `target` from the module of `package`, through `super` for other packages.
+fn message_path(package: &str, target: &MessageDescriptor) -> TokenStream {
+    let target_package = target.package_name();
+    if target_package == "google.protobuf" {
+        let name = rust_type_path(target_package, target.full_name());
+        return quote! { ::axum_connect::pbjson_types::#name };
+    }
+
+    let name = rust_type_path(target_package, target.full_name());
+    if target_package == package {
+        return name;
+    }
+    let supers = match package {
+        "" => 0,
+        package => package.split('.').count(),
+    };
+    let supers = (0..supers).map(|_| quote! { super:: });
+    let modules = target_package
+        .split('.')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| rust_ident(&segment.to_snake_case()));
+    quote! { #(#supers)* #(#modules::)* #name }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_util::{generate, squashed},
+        AxumConnectGenSettings,
+    };
+
+    /// A oneof of three fields, and a proto3 `optional` field, whose synthetic oneof gets none.
+    const FILES: &[(&str, &str)] = &[(
+        "shapes.proto",
+        r#"syntax = "proto3";
+package shapes;
+
+message Shape {
+  oneof kind {
+    Circle circle = 1;
+    string label = 2;
+    int64 sides = 3;
+  }
+  optional string name = 4;
+}
+
+message Circle { double radius = 1; }
+"#,
+    )];
+
+    #[test]
+    fn emits_the_accessors_only_when_enabled() {
+        let lean = squashed(
+            &generate(FILES, &AxumConnectGenSettings::default()),
+            "shapes",
+        );
+        assert!(!lean.contains("kind_as_"), "{lean}");
+        assert!(!lean.contains("kind_name"), "{lean}");
+
+        let settings = AxumConnectGenSettings {
+            oneof_helpers: true,
+            ..AxumConnectGenSettings::default()
+        };
+        let helpers = squashed(&generate(FILES, &settings), "shapes");
+        for accessor in [
+            "pubfnkind_as_circle(&self)->Result<&Circle,axum_connect::error::RpcError>",
+            "pubfnkind_as_label(&self)->Result<&::std::string::String,",
+            "pubfnkind_as_sides(&self)->Result<&i64,",
+            "pubfnkind_kind_name(&self)->Option<&'staticstr>",
+        ] {
+            assert!(helpers.contains(accessor), "no {accessor} in {helpers}");
+        }
+        assert!(!helpers.contains("name_as_"), "{helpers}");
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+
+use prost_reflect::{DescriptorPool, FieldDescriptor, Kind, MessageDescriptor};
+use serde_json::{json, Map, Value};
+
+const ERROR_SCHEMA: &str = "connect.error";
+
+/// Generates an OpenAPI v3 document for every service in `files_to_generate`.
+///
+/// Unary RPCs are documented as `POST /{service}/{method}` with JSON request/response schemas.
+/// Streaming RPCs don't get schemas yet, they are only listed with an `x-connect-streaming` vendor
+/// extension. Output is deterministic, everything is keyed by fully-qualified name.
+pub fn generate_openapi(pool: &DescriptorPool, files_to_generate: &[String]) -> String {
+    let mut paths = BTreeMap::new();
+    let mut schemas = BTreeMap::new();
+    let mut packages = vec![];
+
+    for file in pool
+        .files()
+        .filter(|file| files_to_generate.iter().any(|name| name == file.name()))
+    {
+        if file.services().len() > 0 && !packages.contains(&file.package_name().to_string()) {
+            packages.push(file.package_name().to_string());
+        }
+
+        for service in file.services() {
+            for method in service.methods() {
+                let path = format!("/{}/{}", service.full_name(), method.name());
+                let mut operation = json!({
+                    "operationId": method.full_name(),
+                    "tags": [service.full_name()],
+                });
+
+                let streaming = match (method.is_client_streaming(), method.is_server_streaming()) {
+                    (false, false) => None,
+                    (true, false) => Some("client_streaming"),
+                    (false, true) => Some("server_streaming"),
+                    (true, true) => Some("bidi_streaming"),
+                };
+
+                if let Some(streaming) = streaming {
+                    operation["x-connect-streaming"] = json!(streaming);
+                } else {
+                    add_message_schema(&method.input(), &mut schemas);
+                    add_message_schema(&method.output(), &mut schemas);
+
+                    operation["requestBody"] = json!({
+                        "required": true,
+                        "content": {
+                            "application/json": { "schema": schema_ref(method.input().full_name()) }
+                        }
+                    });
+                    operation["responses"] = json!({
+                        "200": {
+                            "description": "Success",
+                            "content": {
+                                "application/json": {
+                                    "schema": schema_ref(method.output().full_name())
+                                }
+                            }
+                        },
+                        "default": {
+                            "description": "Connect error",
+                            "content": {
+                                "application/json": { "schema": schema_ref(ERROR_SCHEMA) }
+                            }
+                        }
+                    });
+                }
+
+                paths.insert(path, json!({ "post": operation }));
+            }
+        }
+    }
+
+    schemas.insert(ERROR_SCHEMA.to_string(), error_schema());
+    packages.sort();
+
+    let document = json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": packages.join(", "),
+            "version": "1",
+        },
+        "paths": paths,
+        "components": { "schemas": schemas },
+    });
+
+    serde_json::to_string_pretty(&document).unwrap() + "\n"
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Adds a schema for `message` and everything it references (well-known types are inlined).
+fn add_message_schema(message: &MessageDescriptor, schemas: &mut BTreeMap<String, Value>) {
+    if schemas.contains_key(message.full_name()) {
+        return;
+    }
+
+    // Insert a placeholder first so recursive messages terminate.
+    schemas.insert(message.full_name().to_string(), Value::Null);
+
+    let mut properties = Map::new();
+    for field in message.fields() {
+        properties.insert(field.json_name().to_string(), field_schema(&field, schemas));
+    }
+
+    schemas.insert(
+        message.full_name().to_string(),
+        json!({ "type": "object", "properties": properties }),
+    );
+}
+
+fn field_schema(field: &FieldDescriptor, schemas: &mut BTreeMap<String, Value>) -> Value {
+    if field.is_map() {
+        let Kind::Message(entry) = field.kind() else {
+            unreachable!("map fields are always messages");
+        };
+        let value = entry.map_entry_value_field();
+
+        return json!({
+            "type": "object",
+            "additionalProperties": kind_schema(&value.kind(), schemas),
+        });
+    }
+
+    let schema = kind_schema(&field.kind(), schemas);
+    if field.is_list() {
+        json!({ "type": "array", "items": schema })
+    } else {
+        schema
+    }
+}
+
+/// Maps a proto type to a schema, following the protobuf JSON mapping.
+fn kind_schema(kind: &Kind, schemas: &mut BTreeMap<String, Value>) -> Value {
+    match kind {
+        Kind::Double => json!({ "type": "number", "format": "double" }),
+        Kind::Float => json!({ "type": "number", "format": "float" }),
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => {
+            json!({ "type": "integer", "format": "int32" })
+        }
+        Kind::Uint32 | Kind::Fixed32 => json!({ "type": "integer", "format": "int64" }),
+        // 64-bit integers are strings in JSON.
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => {
+            json!({ "type": "string", "format": "int64" })
+        }
+        Kind::Uint64 | Kind::Fixed64 => json!({ "type": "string", "format": "uint64" }),
+        Kind::Bool => json!({ "type": "boolean" }),
+        Kind::String => json!({ "type": "string" }),
+        Kind::Bytes => json!({ "type": "string", "format": "byte" }),
+        Kind::Enum(e) => json!({
+            "type": "string",
+            "enum": e.values().map(|v| v.name().to_string()).collect::<Vec<_>>(),
+        }),
+        Kind::Message(m) => match well_known_schema(m.full_name()) {
+            Some(schema) => schema,
+            None => {
+                add_message_schema(m, schemas);
+                schema_ref(m.full_name())
+            }
+        },
+    }
+}
+
+fn well_known_schema(name: &str) -> Option<Value> {
+    Some(match name {
+        "google.protobuf.Timestamp" => json!({ "type": "string", "format": "date-time" }),
+        "google.protobuf.Duration" => {
+            json!({ "type": "string", "pattern": "^-?[0-9]+(\\.[0-9]+)?s$" })
+        }
+        "google.protobuf.FieldMask" => json!({ "type": "string" }),
+        "google.protobuf.Empty" => json!({ "type": "object" }),
+        "google.protobuf.Struct" => json!({ "type": "object", "additionalProperties": {} }),
+        "google.protobuf.Value" => json!({}),
+        "google.protobuf.ListValue" => json!({ "type": "array", "items": {} }),
+        "google.protobuf.Any" => json!({
+            "type": "object",
+            "properties": { "@type": { "type": "string" } },
+            "additionalProperties": {},
+        }),
+        "google.protobuf.DoubleValue" => json!({ "type": "number", "format": "double" }),
+        "google.protobuf.FloatValue" => json!({ "type": "number", "format": "float" }),
+        "google.protobuf.Int32Value" => json!({ "type": "integer", "format": "int32" }),
+        "google.protobuf.UInt32Value" => json!({ "type": "integer", "format": "int64" }),
+        "google.protobuf.Int64Value" => json!({ "type": "string", "format": "int64" }),
+        "google.protobuf.UInt64Value" => json!({ "type": "string", "format": "uint64" }),
+        "google.protobuf.BoolValue" => json!({ "type": "boolean" }),
+        "google.protobuf.StringValue" => json!({ "type": "string" }),
+        "google.protobuf.BytesValue" => json!({ "type": "string", "format": "byte" }),
+        _ => return None,
+    })
+}
+
+/// https://connectrpc.com/docs/protocol/#error-end-stream
+fn error_schema() -> Value {
+    json!({
+        "type": "object",
+        "properties": {
+            "code": {
+                "type": "string",
+                "enum": [
+                    "canceled", "unknown", "invalid_argument", "deadline_exceeded", "not_found",
+                    "already_exists", "permission_denied", "resource_exhausted",
+                    "failed_precondition", "aborted", "out_of_range", "unimplemented", "internal",
+                    "unavailable", "data_loss", "unauthenticated",
+                ],
+            },
+            "message": { "type": "string" },
+            "details": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "type": { "type": "string" },
+                        "value": { "type": "string", "format": "byte" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{test_util::generate, AxumConnectGenSettings};
+
+    /// Written with `UPDATE_SNAPSHOTS=1 cargo test -p axum-connect-build`; review its diff before
+    /// committing it.
+    const SNAPSHOT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/snapshots/openapi.json");
+
+    const STORE: &str = r#"syntax = "proto3";
+package store.v1;
+
+import "google/protobuf/duration.proto";
+import "google/protobuf/struct.proto";
+import "google/protobuf/timestamp.proto";
+import "google/protobuf/wrappers.proto";
+
+enum Size {
+  SIZE_UNSPECIFIED = 0;
+  SIZE_SMALL = 1;
+  SIZE_LARGE = 2;
+}
+
+message Item {
+  message Price {
+    int64 cents = 1;
+    string currency = 2;
+  }
+
+  string id = 1;
+  Size size = 2;
+  Price price = 3;
+  repeated string tags = 4;
+  map<string, int32> stock = 5;
+  bytes image = 6;
+  optional double weight = 7;
+  google.protobuf.Timestamp created = 8;
+  google.protobuf.Duration shelf_life = 9;
+  google.protobuf.StringValue note = 10;
+  google.protobuf.Struct extra = 11;
+  repeated Item related = 12;
+}
+
+message GetItemRequest {
+  string id = 1;
+}
+
+message WatchItemsRequest {
+  repeated string ids = 1;
+}
+
+service ItemService {
+  rpc GetItem(GetItemRequest) returns (Item) {
+    option idempotency_level = NO_SIDE_EFFECTS;
+  }
+  rpc WatchItems(WatchItemsRequest) returns (stream Item);
+}
+"#;
+
+    fn openapi() -> String {
+        let settings = AxumConnectGenSettings {
+            openapi: Some("openapi.json".to_string()),
+            ..Default::default()
+        };
+        generate(&[("store/v1/store.proto", STORE)], &settings)
+            .files
+            .remove("openapi.json")
+            .unwrap()
+    }
+
+    #[test]
+    fn matches_the_snapshot() {
+        let generated = openapi();
+        if std::env::var_os("UPDATE_SNAPSHOTS").is_some_and(|update| !update.is_empty()) {
+            std::fs::create_dir_all(Path::new(SNAPSHOT).parent().unwrap()).unwrap();
+            std::fs::write(SNAPSHOT, &generated).unwrap();
+            return;
+        }
+        let snapshot = std::fs::read_to_string(SNAPSHOT).unwrap();
+        assert!(
+            generated == snapshot,
+            "openapi.json changed, write it with UPDATE_SNAPSHOTS=1 if that's deliberate\n{}",
+            generated
+        );
+    }
+
+    #[test]
+    fn generates_the_same_document_every_time() {
+        assert_eq!(openapi(), openapi());
+    }
+}
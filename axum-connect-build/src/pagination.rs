@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+use prost_reflect::{Cardinality, DescriptorPool, Kind};
+use quote::quote;
+
+use crate::reflect::rust_type_path;
+
+/// Implements `axum_connect::pagination::HasPageToken` for every message with a singular
+/// `string page_token` field, appended to its package file.
+pub fn page_tokens(
+    pool: &DescriptorPool,
+    files_to_generate: &[String],
+    files: &mut BTreeMap<String, String>,
+) {
+    for file in pool.files() {
+        if !files_to_generate.iter().any(|name| name == file.name()) {
+            continue;
+        }
+        let package = file.package_name();
+        let file_name = match package {
+            "" => "_.rs".to_string(),
+            package => format!("{}.rs", package),
+        };
+        let Some(contents) = files.get_mut(&file_name) else {
+            continue;
+        };
+
+        let mut messages = file.messages().collect::<Vec<_>>();
+        let mut impls = vec![];
+        while let Some(message) = messages.pop() {
+            messages.extend(message.child_messages());
+            let Some(field) = message.get_field_by_name("page_token") else {
+                continue;
+            };
+            // Fields of a (real) oneof are generated as an enum variant.
+            let proto3_optional = field.field_descriptor_proto().proto3_optional();
+            let in_oneof = field.containing_oneof().is_some() && !proto3_optional;
+            if field.kind() != Kind::String || field.is_list() || in_oneof {
+                continue;
+            }
+
+            let rust_path = rust_type_path(package, message.full_name());
+            let optional =
+                field.supports_presence() && field.cardinality() != Cardinality::Required;
+            let token = match optional {
+                true => quote! { self.page_token.as_deref().unwrap_or_default() },
+                false => quote! { &self.page_token },
+            };
+            impls.push(quote! {
+                impl axum_connect::pagination::HasPageToken for #rust_path {
+                    fn page_token(&self) -> &str {
+                        #token
+                    }
+                }
+            });
+        }
+
+        if !impls.is_empty() {
+            contents.push_str(&quote! { #(#impls)* }.to_string());
+            contents.push('\n');
+        }
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+
+use heck::{ToSnakeCase, ToUpperCamelCase};
+use proc_macro2::{Literal, TokenStream};
+use prost::Message;
+use prost_reflect::{prost_types::FileDescriptorSet, DescriptorPool, FileDescriptor};
+use quote::{format_ident, quote};
+
+/// Appends the package's descriptors (with those of its imports) to every generated package file,
+/// and implements `prost_reflect::ReflectMessage` for its messages, for `axum_connect::field_mask`.
+pub fn embed_descriptors(
+    pool: &DescriptorPool,
+    files_to_generate: &[String],
+    files: &mut BTreeMap<String, String>,
+) {
+    let mut packages = BTreeMap::<String, Vec<FileDescriptor>>::new();
+    for file in pool.files() {
+        // Well-known types come from `pbjson_types`, which can't be implemented on here.
+        let is_external = file.package_name() == "google.protobuf";
+        if !is_external && files_to_generate.iter().any(|name| name == file.name()) {
+            packages
+                .entry(file.package_name().to_string())
+                .or_default()
+                .push(file);
+        }
+    }
+
+    for (package, package_files) in packages {
+        let file_name = match package.as_str() {
+            "" => "_.rs".to_string(),
+            package => format!("{}.rs", package),
+        };
+        let Some(contents) = files.get_mut(&file_name) else {
+            continue;
+        };
+
+        // Imports first, the pool is built in order.
+        let mut ordered = vec![];
+        for file in &package_files {
+            push_with_dependencies(file, &mut ordered);
+        }
+        let set = FileDescriptorSet {
+            file: ordered
+                .iter()
+                .map(|file| file.file_descriptor_proto().clone())
+                .collect(),
+        };
+        let set = Literal::byte_string(&set.encode_to_vec());
+
+        let impls = package_files
+            .iter()
+            .flat_map(|file| file.messages())
+            .flat_map(|message| {
+                let mut all = vec![];
+                let mut stack = vec![message];
+                while let Some(message) = stack.pop() {
+                    stack.extend(message.child_messages());
+                    if !message.is_map_entry() {
+                        all.push(message);
+                    }
+                }
+                all
+            })
+            .map(|message| {
+                let full_name = message.full_name();
+                let rust_path = rust_type_path(&package, full_name);
+                quote! {
+                    impl axum_connect::prost_reflect::ReflectMessage for #rust_path {
+                        fn descriptor(&self) -> axum_connect::prost_reflect::MessageDescriptor {
+                            DESCRIPTOR_POOL
+                                .get_message_by_name(#full_name)
+                                .expect("the message is in its package's descriptor pool")
+                        }
+                    }
+                }
+            });
+
+        let tokens = quote! {
+            /// An encoded `FileDescriptorSet` of this package and its imports.
+            pub const FILE_DESCRIPTOR_SET: &[u8] = #set;
+
+            /// The descriptors of this package and its imports.
+            pub static DESCRIPTOR_POOL: ::std::sync::LazyLock<axum_connect::prost_reflect::DescriptorPool> =
+                ::std::sync::LazyLock::new(|| {
+                    axum_connect::prost_reflect::DescriptorPool::decode(FILE_DESCRIPTOR_SET)
+                        .expect("the embedded descriptors are valid")
+                });
+
+            #(#impls)*
+        };
+        contents.push_str(&tokens.to_string());
+        contents.push('\n');
+    }
+}
+
+fn push_with_dependencies(file: &FileDescriptor, ordered: &mut Vec<FileDescriptor>) {
+    if ordered.contains(file) {
+        return;
+    }
+    for dependency in file.dependencies() {
+        push_with_dependencies(&dependency, ordered);
+    }
+    ordered.push(file.clone());
+}
+
+/// The path of a message's type relative to its package module, the way prost names it: nested
+/// messages live in a module named after their parent.
+pub(crate) fn rust_type_path(package: &str, full_name: &str) -> TokenStream {
+    let relative = match package {
+        "" => full_name,
+        package => &full_name[package.len() + 1..],
+    };
+
+    let mut segments = relative.split('.').collect::<Vec<_>>();
+    let name = segments.pop().unwrap_or_default();
+    let modules = segments
+        .into_iter()
+        .map(|segment| rust_ident(&segment.to_snake_case()));
+    let name = rust_ident(&name.to_upper_camel_case());
+    quote! { #(#modules::)* #name }
+}
+
+/// Matches prost's identifier sanitizing.
+pub(crate) fn rust_ident(ident: &str) -> syn::Ident {
+    match ident {
+        "_" | "super" | "self" | "Self" | "extern" | "crate" => format_ident!("{}_", ident),
+        s if s.starts_with(|c: char| c.is_numeric()) => format_ident!("_{}", ident),
+        s => syn::parse_str::<syn::Ident>(s).unwrap_or_else(|_| format_ident!("r#{}", s)),
+    }
+}
@@ -0,0 +1,88 @@
+//! Fixture protos for the unit tests, compiled with the `protoc` on the `PATH` (or `PROTOC`).
+
+use std::fs;
+
+use crate::{generate_files, AxumConnectGenSettings, GeneratedFiles};
+
+/// Just enough of `google/api` for `google.api.http` options.
+const GOOGLE_API: &[(&str, &str)] = &[
+    (
+        "google/api/http.proto",
+        r#"syntax = "proto3";
+package google.api;
+
+message HttpRule {
+  string selector = 1;
+  oneof pattern {
+    string get = 2;
+    string put = 3;
+    string post = 4;
+    string delete = 5;
+    string patch = 6;
+    CustomHttpPattern custom = 8;
+  }
+  string body = 7;
+  string response_body = 12;
+  repeated HttpRule additional_bindings = 11;
+}
+
+message CustomHttpPattern {
+  string kind = 1;
+  string path = 2;
+}
+"#,
+    ),
+    (
+        "google/api/annotations.proto",
+        r#"syntax = "proto3";
+package google.api;
+
+import "google/api/http.proto";
+import "google/protobuf/descriptor.proto";
+
+extend google.protobuf.MethodOptions {
+  HttpRule http = 72295728;
+}
+"#,
+    ),
+];
+
+/// The encoded `FileDescriptorSet` of `files` (name and contents), imports included.
+pub(crate) fn descriptor_set(files: &[(&str, &str)]) -> Vec<u8> {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, contents) in GOOGLE_API.iter().chain(files) {
+        let path = dir.path().join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    let descriptor_path = dir.path().join("descriptor.bin");
+    let inputs = files
+        .iter()
+        .map(|(name, _)| dir.path().join(name))
+        .collect::<Vec<_>>();
+    let mut conf = prost_build::Config::new();
+    conf.file_descriptor_set_path(&descriptor_path);
+    conf.load_fds(&inputs, &[dir.path()]).unwrap();
+    fs::read(descriptor_path).unwrap()
+}
+
+/// Generates `files` with `settings`.
+pub(crate) fn generate(
+    files: &[(&str, &str)],
+    settings: &AxumConnectGenSettings,
+) -> GeneratedFiles {
+    let names = files
+        .iter()
+        .map(|(name, _)| name.to_string())
+        .collect::<Vec<_>>();
+    generate_files(&descriptor_set(files), &names, settings).unwrap()
+}
+
+/// The generated code of `package`, squashed into one line without whitespace, so tests can look
+/// for generated items regardless of formatting.
+pub(crate) fn squashed(generated: &GeneratedFiles, package: &str) -> String {
+    generated.files[&format!("{package}.rs")]
+        .split_whitespace()
+        .collect()
+}
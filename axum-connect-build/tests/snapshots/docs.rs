@@ -0,0 +1,262 @@
+/// A book on the shelves.
+///
+/// Identified by its \[isbn\], see
+/// <https://isbn.org.>
+#[derive(Clone, PartialEq, ::axum_connect::prost::Message)]
+pub struct Book {
+    /// The ISBN-13, eg. `978-0441013593`.
+    #[prost(string, tag = "1")]
+    pub isbn: ::axum_connect::prost::alloc::string::String,
+    /// As printed on the cover.
+    #[prost(string, tag = "2")]
+    pub title: ::axum_connect::prost::alloc::string::String,
+    #[prost(enumeration = "Format", tag = "3")]
+    pub format: i32,
+}
+impl ::axum_connect::prost::Name for Book {
+    const NAME: &'static str = "Book";
+    const PACKAGE: &'static str = "library";
+    fn full_name() -> ::axum_connect::prost::alloc::string::String {
+        "library.Book".into()
+    }
+    fn type_url() -> ::axum_connect::prost::alloc::string::String {
+        "type.googleapis.com/library.Book".into()
+    }
+}
+#[derive(Clone, PartialEq, ::axum_connect::prost::Message)]
+pub struct GetBookRequest {
+    #[prost(string, tag = "1")]
+    pub isbn: ::axum_connect::prost::alloc::string::String,
+}
+impl ::axum_connect::prost::Name for GetBookRequest {
+    const NAME: &'static str = "GetBookRequest";
+    const PACKAGE: &'static str = "library";
+    fn full_name() -> ::axum_connect::prost::alloc::string::String {
+        "library.GetBookRequest".into()
+    }
+    fn type_url() -> ::axum_connect::prost::alloc::string::String {
+        "type.googleapis.com/library.GetBookRequest".into()
+    }
+}
+/// How a book is printed.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    ::axum_connect::prost::Enumeration
+)]
+#[repr(i32)]
+pub enum Format {
+    /// Not known.
+    Unspecified = 0,
+    /// Soft cover.
+    Paperback = 1,
+}
+impl Format {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::Unspecified => "FORMAT_UNSPECIFIED",
+            Self::Paperback => "FORMAT_PAPERBACK",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "FORMAT_UNSPECIFIED" => Some(Self::Unspecified),
+            "FORMAT_PAPERBACK" => Some(Self::Paperback),
+            _ => None,
+        }
+    }
+}
+/// Lends and returns books.
+///
+/// Example:
+///
+/// ```text
+///  client.get_book("978-0441013593")
+/// ```
+pub struct Library;
+#[allow(dead_code)]
+impl Library {
+    /// Finds a book.
+    ///
+    /// ```text
+    /// let book = library.get_book(isbn).await?;
+    /// ```
+    ///
+    /// `/library.Library/GetBook`
+    pub const GET_BOOK_PATH: &'static str = "/library.Library/GetBook";
+    /// Every book as it's shelved.
+    ///
+    /// Until the library closes.
+    ///
+    /// `/library.Library/WatchBooks`
+    pub const WATCH_BOOKS_PATH: &'static str = "/library.Library/WatchBooks";
+    /// The path of every RPC of the service.
+    pub const PATHS: &'static [&'static str] = &[
+        Self::GET_BOOK_PATH,
+        Self::WATCH_BOOKS_PATH,
+    ];
+    /// Finds a book.
+    ///
+    /// ```text
+    /// let book = library.get_book(isbn).await?;
+    /// ```
+    pub fn get_book<T, H, S>(
+        handler: H,
+    ) -> impl FnOnce(
+        axum_connect::router::RpcRouter<S>,
+    ) -> axum_connect::router::RpcRouter<S>
+    where
+        H: axum_connect::handler::RpcHandlerUnary<GetBookRequest, Book, T, S>,
+        T: 'static,
+        S: Clone + Send + Sync + 'static,
+    {
+        move |router: axum_connect::router::RpcRouter<S>| {
+            router.rpc_method(Self::get_book_method(handler))
+        }
+    }
+    /// The RPC on its own, eg. to turn into a `tower::Service`.
+    pub fn get_book_method<T, H, S>(handler: H) -> axum_connect::router::RpcMethod<S>
+    where
+        H: axum_connect::handler::RpcHandlerUnary<GetBookRequest, Book, T, S>,
+        T: 'static,
+        S: Clone + Send + Sync + 'static,
+    {
+        axum_connect::router::RpcMethod::new(
+            axum_connect::router::RpcMethodInfo::from_rpc_path(
+                    Self::GET_BOOK_PATH,
+                    axum_connect::router::RpcMethodKind::Unary,
+                    axum_connect::router::RpcIdempotencyLevel::from_proto(1i32),
+                )
+                .with_message_types("GetBookRequest", "Book")
+                .with_http_methods(
+                    vec![axum::http::Method::POST, axum::http::Method::GET],
+                ),
+            axum::routing::post({
+                    let handler = handler.clone();
+                    |
+                        axum::extract::State(state): axum::extract::State<S>,
+                        request: axum::http::Request<axum::body::Body>|
+                    async move { handler.call(request, state).await }
+                })
+                .get(|
+                    axum::extract::State(state): axum::extract::State<S>,
+                    request: axum::http::Request<axum::body::Body>|
+                async move { handler.call(request, state).await }),
+        )
+    }
+    /// The RPC's info without a handler, eg. to check its idempotency level before
+    /// calling it.
+    pub fn get_book_info() -> axum_connect::router::RpcMethodInfo {
+        axum_connect::router::RpcMethodInfo::from_rpc_path(
+                Self::GET_BOOK_PATH,
+                axum_connect::router::RpcMethodKind::Unary,
+                axum_connect::router::RpcIdempotencyLevel::from_proto(1i32),
+            )
+            .with_message_types("GetBookRequest", "Book")
+            .with_http_methods(vec![axum::http::Method::POST, axum::http::Method::GET])
+    }
+    /// Mounts the RPC for GET requests, unless it's mounted for them already. This
+    /// is a `NO_SIDE_EFFECTS` method, which are mounted for GET as well as POST.
+    #[deprecated(
+        note = "`get_book` mounts the RPC for GET already, this does nothing after it"
+    )]
+    pub fn get_book_unary_get<T, H, S>(
+        handler: H,
+    ) -> impl FnOnce(
+        axum_connect::router::RpcRouter<S>,
+    ) -> axum_connect::router::RpcRouter<S>
+    where
+        H: axum_connect::handler::RpcHandlerUnary<GetBookRequest, Book, T, S>,
+        T: 'static,
+        S: Clone + Send + Sync + 'static,
+    {
+        move |router: axum_connect::router::RpcRouter<S>| {
+            let mounted = router
+                .paths()
+                .iter()
+                .any(|info| {
+                    info.path == Self::GET_BOOK_PATH
+                        && info.http_methods.contains(&axum::http::Method::GET)
+                });
+            match mounted {
+                true => router,
+                false => {
+                    router
+                        .rpc_method(
+                            axum_connect::router::RpcMethod::new(
+                                axum_connect::router::RpcMethodInfo::from_rpc_path(
+                                        Self::GET_BOOK_PATH,
+                                        axum_connect::router::RpcMethodKind::Unary,
+                                        axum_connect::router::RpcIdempotencyLevel::from_proto(1i32),
+                                    )
+                                    .with_message_types("GetBookRequest", "Book")
+                                    .with_http_methods(vec![axum::http::Method::GET]),
+                                axum::routing::get(|
+                                    axum::extract::State(state): axum::extract::State<S>,
+                                    request: axum::http::Request<axum::body::Body>|
+                                async move { handler.call(request, state).await }),
+                            ),
+                        )
+                }
+            }
+        }
+    }
+    /// Every book as it's shelved.
+    ///
+    /// Until the library closes.
+    pub fn watch_books<T, H, S>(
+        handler: H,
+    ) -> impl FnOnce(
+        axum_connect::router::RpcRouter<S>,
+    ) -> axum_connect::router::RpcRouter<S>
+    where
+        H: axum_connect::handler::RpcHandlerStream<GetBookRequest, Book, T, S>,
+        T: 'static,
+        S: Clone + Send + Sync + 'static,
+    {
+        move |router: axum_connect::router::RpcRouter<S>| {
+            router.rpc_method(Self::watch_books_method(handler))
+        }
+    }
+    /// The RPC on its own, eg. to turn into a `tower::Service`.
+    pub fn watch_books_method<T, H, S>(handler: H) -> axum_connect::router::RpcMethod<S>
+    where
+        H: axum_connect::handler::RpcHandlerStream<GetBookRequest, Book, T, S>,
+        T: 'static,
+        S: Clone + Send + Sync + 'static,
+    {
+        axum_connect::router::RpcMethod::new(
+            axum_connect::router::RpcMethodInfo::from_rpc_path(
+                    Self::WATCH_BOOKS_PATH,
+                    axum_connect::router::RpcMethodKind::ServerStreaming,
+                    axum_connect::router::RpcIdempotencyLevel::from_proto(0i32),
+                )
+                .with_message_types("GetBookRequest", "Book"),
+            axum::routing::post(|
+                axum::extract::State(state): axum::extract::State<S>,
+                request: axum::http::Request<axum::body::Body>|
+            async move { handler.call(request, state).await }),
+        )
+    }
+    /// The RPC's info without a handler, eg. to check its idempotency level before
+    /// calling it.
+    pub fn watch_books_info() -> axum_connect::router::RpcMethodInfo {
+        axum_connect::router::RpcMethodInfo::from_rpc_path(
+                Self::WATCH_BOOKS_PATH,
+                axum_connect::router::RpcMethodKind::ServerStreaming,
+                axum_connect::router::RpcIdempotencyLevel::from_proto(0i32),
+            )
+            .with_message_types("GetBookRequest", "Book")
+    }
+}
@@ -1,7 +1,28 @@
+use std::path::PathBuf;
+
 use axum_connect_build::{axum_connect_codegen, AxumConnectGenSettings};
 
 fn main() {
-    let settings = AxumConnectGenSettings::from_directory_recursive("proto")
+    let mut settings = AxumConnectGenSettings::from_directory_recursive("proto")
         .expect("failed to glob proto files");
+    // `HelloWorldServiceClient`, which `tests/client.rs` calls the example with.
+    settings.clients = true;
+    // `Shape::kind_as_circle` and friends, which `tests/oneof.rs` calls.
+    settings.oneof_helpers = true;
+    // `HelloWorldServiceHandlers` and `registrars()`, which `tests/registrars.rs` mounts.
+    settings.service_traits = true;
     axum_connect_codegen(settings).unwrap();
+
+    // `enums.proto` again with open enums, for `tests/enums.rs` to compare. The first run fetched
+    // protoc already.
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    axum_connect_codegen(AxumConnectGenSettings {
+        includes: vec!["proto".into()],
+        inputs: vec!["proto/enums.proto".into()],
+        protoc_version: None,
+        open_enums: true,
+        out_dir: Some(out_dir.join("open")),
+        ..Default::default()
+    })
+    .unwrap();
 }
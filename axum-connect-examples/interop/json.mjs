@@ -0,0 +1,60 @@
+// Writes the JSON fixtures `cargo test -p axum-connect-example --test json_mapping` round-trips,
+// as protobuf-es serializes each message:
+//
+//   npm install && npm run generate && npm run json
+//
+// Review the diff of `json/` before checking it in, the Rust side must read and write the same
+// JSON from then on.
+
+import { mkdir, writeFile } from "node:fs/promises";
+import { create, toJsonString } from "@bufbuild/protobuf";
+import { DurationSchema, TimestampSchema } from "@bufbuild/protobuf/wkt";
+import { ProfileSchema } from "./gen/mapping_pb.js";
+
+const json = new URL("./json/", import.meta.url);
+
+// 2024-01-02T03:04:05Z
+const seconds = 1704164645n;
+
+const cases = [
+  ["empty", {}],
+  // Set optionals and wrappers are written even when they hold the default value.
+  [
+    "defaults",
+    { nickname: "", limit: 0n, note: "", expiry: { case: "neverExpires", value: false } },
+  ],
+  [
+    "full",
+    {
+      id: "u1",
+      nickname: "Ferris",
+      balance: -9007199254740993n,
+      visits: 18446744073709551615n,
+      age: 30,
+      limit: 9007199254740993n,
+      note: "hi",
+      created: create(TimestampSchema, { seconds, nanos: 500_000_000 }),
+      session: create(DurationSchema, { seconds: 1n, nanos: 500_000_000 }),
+      logins: [
+        create(TimestampSchema, { seconds }),
+        create(TimestampSchema, { seconds, nanos: 123_000 }),
+      ],
+      expiry: {
+        case: "expiresAt",
+        value: create(TimestampSchema, { seconds, nanos: 123_456_789 }),
+      },
+      lastSeen: {
+        web: create(TimestampSchema, { seconds }),
+        app: create(TimestampSchema, { seconds, nanos: 120_000_000 }),
+      },
+    },
+  ],
+];
+
+await mkdir(json, { recursive: true });
+for (const [name, init] of cases) {
+  const message = create(ProfileSchema, init);
+  const text = toJsonString(ProfileSchema, message, { prettySpaces: 2 });
+  await writeFile(new URL(`${name}.json`, json), text + "\n");
+  console.log(`wrote ${name}`);
+}
@@ -0,0 +1,79 @@
+// Records the fixtures `cargo test -p axum-connect-example --test interop` replays, from a
+// connect-es client talking to the example server:
+//
+//   cargo run -p axum-connect-example   # in another terminal
+//   npm install && npm run generate && npm run record
+//
+// Every call goes through a `fetch` that writes the request connect-es made, as raw HTTP/1.1
+// bytes, to `fixtures/<name>.request`, and the response the server sent back to
+// `fixtures/<name>.response`. Review the responses before checking them in, they're what the
+// server is expected to send from then on.
+
+import { mkdir, writeFile } from "node:fs/promises";
+import { createClient } from "@connectrpc/connect";
+import { createConnectTransport } from "@connectrpc/connect-web";
+import { HelloWorldService } from "./gen/hello_pb.js";
+
+const baseUrl = process.env.BASE_URL ?? "http://localhost:3030";
+const fixtures = new URL("./fixtures/", import.meta.url);
+
+const cases = [
+  ["unary_json", {}, (client) => client.sayHello({ name: "Ferris" })],
+  ["unary_proto", { useBinaryFormat: true }, (client) => client.sayHello({ name: "Ferris" })],
+  ["get_json", { useHttpGet: true }, (client) => client.sayHello({ name: "Ferris" })],
+  ["stream_json", {}, (client) => drain(client.sayHelloStream({ name: "Ferris" }))],
+  [
+    "stream_proto",
+    { useBinaryFormat: true },
+    (client) => drain(client.sayHelloStream({ name: "Ferris" })),
+  ],
+];
+
+// The fixture the current call is recorded as.
+let fixture;
+
+async function recordingFetch(input, init) {
+  const request = new Request(input, init);
+  const url = new URL(request.url);
+  const body = Buffer.from(await request.clone().arrayBuffer());
+  const head = [`${request.method} ${url.pathname}${url.search} HTTP/1.1`, `host: ${url.host}`];
+  request.headers.forEach((value, name) => head.push(`${name}: ${value}`));
+  if (request.method !== "GET") {
+    head.push(`content-length: ${body.length}`);
+  }
+  await save(`${fixture}.request`, head, body);
+
+  const response = await fetch(request);
+  const responseBody = Buffer.from(await response.clone().arrayBuffer());
+  const responseHead = [`HTTP/1.1 ${response.status} ${response.statusText}`];
+  // fetch has already de-chunked and decompressed the body.
+  const framing = ["content-length", "transfer-encoding", "content-encoding"];
+  response.headers.forEach((value, name) => {
+    if (!framing.includes(name)) {
+      responseHead.push(`${name}: ${value}`);
+    }
+  });
+  responseHead.push(`content-length: ${responseBody.length}`);
+  await save(`${fixture}.response`, responseHead, responseBody);
+
+  return response;
+}
+
+async function save(name, head, body) {
+  const bytes = Buffer.concat([Buffer.from(head.join("\r\n") + "\r\n\r\n"), body]);
+  await writeFile(new URL(name, fixtures), bytes);
+}
+
+async function drain(stream) {
+  for await (const _ of stream) {
+    // The responses are recorded by `recordingFetch`.
+  }
+}
+
+await mkdir(fixtures, { recursive: true });
+for (const [name, options, call] of cases) {
+  fixture = name;
+  const transport = createConnectTransport({ baseUrl, fetch: recordingFetch, ...options });
+  await call(createClient(HelloWorldService, transport));
+  console.log(`recorded ${name}`);
+}
@@ -0,0 +1,104 @@
+//! The example service, shared by the server in `main.rs` and the interop and golden tests in
+//! `tests/`.
+
+use async_stream::stream;
+use axum_connect::{futures::Stream, prelude::*};
+use axum_extra::extract::Host;
+use error::Error;
+use proto::hello::*;
+use tower_http::services::ServeDir;
+
+// Take a peak at error.rs to see how errors work in axum-connect.
+pub mod error;
+
+pub mod proto {
+    // Include the generated code in a `proto` module.
+    //
+    // Note: I'm not super happy with this pattern. I hope to add support to `protoc-gen-prost` in
+    // the near-ish future instead see:
+    // https://github.com/neoeinstein/protoc-gen-prost/issues/82#issuecomment-1877107220 That will
+    // better align with Buf.build's philosophy. This is how it works for now though.
+    pub mod hello {
+        include!(concat!(env!("OUT_DIR"), "/hello.rs"));
+    }
+
+    // Messages without a service, for the JSON mapping tests.
+    pub mod mapping {
+        include!(concat!(env!("OUT_DIR"), "/mapping.rs"));
+    }
+
+    // The same enums decoded strictly and as proto3's open enums, for the enum decoding tests.
+    pub mod enums {
+        include!(concat!(env!("OUT_DIR"), "/enums.rs"));
+    }
+
+    pub mod open_enums {
+        include!(concat!(env!("OUT_DIR"), "/open/enums.rs"));
+    }
+
+    // A message with a oneof, generated with its `oneof_helpers` accessors.
+    pub mod shapes {
+        include!(concat!(env!("OUT_DIR"), "/shapes.rs"));
+    }
+}
+
+/// The example router, as `main` serves it.
+pub fn app() -> RpcRouter {
+    // Build our application with a route. Note the `rpc` method which was added by `axum-connect`.
+    // It expect a service method handler, wrapped in it's respective type. The handler (below) is
+    // just a normal Rust function. Just like Axum, it also supports extractors!
+    RpcRouter::new()
+        // A standard unary Connect-Web request handler. `SayHello` is marked `NO_SIDE_EFFECTS`, so
+        // it's mounted for GET requests too, which have well-defined semantics for caching.
+        .rpc(HelloWorldService::say_hello(say_hello_unary))
+        // A server-streaming request handler. Very useful when you need them!
+        .rpc(HelloWorldService::say_hello_stream(stream_three_reponses))
+        // Anything that isn't an RPC (or another route) is served from the `static` directory,
+        // handy for a single-page app shipped in the same binary. Non-RPC routes can be added
+        // with `rest_route`.
+        .fallback_service(ServeDir::new("static"))
+}
+
+/// The bread-and-butter of Connect-Web, a Unary request handler.
+///
+/// Just to demo error handling, I've chose to return a `Result` here. If your method is
+/// infallible, you could just as easily return a `HellResponse` directly. The error type I'm using
+/// is defined in `error.rs` and is worth taking a quick peak at.
+///
+/// Like Axum, both the request AND response types just need to implement RpcFromRequestParts` and
+/// `RpcIntoResponse` respectively. This allows for a ton of flexibility in what your handlers
+/// actually accept/return. This is a concept very core to Axum, so I won't go too deep into the
+/// ideology here.
+async fn say_hello_unary(Host(host): Host, request: HelloRequest) -> Result<HelloResponse, Error> {
+    Ok(HelloResponse {
+        message: format!(
+            "Hello {}! You're addressing the hostname: {}.",
+            request.name.unwrap_or_else(|| "unnamed".to_string()),
+            host
+        ),
+    })
+}
+
+/// This is a server-streaming request handler. Much more rare to see one in the wild, but they
+/// sure are useful when you need them! axum-connect has only partial support for everything
+/// connect-web defines in server-streaming requests. For example, it doesn't define a way to
+/// return trailers. I've never once actually needed them, so it feels weird to muddy the API just
+/// to support such a niche use. Trailers are IMO the worst single decision gRPC made, locking them
+/// into HTTP/2 forever. I'm not a fan -.-
+///
+/// You can however return a stream of anything that converts `RpcIntoResponse`, just like the
+/// unary handlers. Again, very flexible. In this case I'm using the amazing `async-stream` crate
+/// to make the code nice and readable.
+async fn stream_three_reponses(
+    Host(host): Host,
+    request: HelloRequest,
+) -> impl Stream<Item = HelloResponse> {
+    stream! {
+        yield HelloResponse { message: "Hello".to_string() };
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        yield HelloResponse { message: request.name().to_string() };
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        yield HelloResponse { message: format!("You're addressing the hostname: {}", host) };
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
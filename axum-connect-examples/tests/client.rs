@@ -0,0 +1,66 @@
+//! The generated `HelloWorldServiceClient`, calling the example in-process.
+
+use axum::http::{header, request, HeaderValue};
+use axum_connect::{
+    client::{ClientInterceptor, RpcClient, TowerClient},
+    prelude::*,
+};
+
+use axum_connect_example::{app, proto::hello::*};
+
+/// Addresses the calls to a virtual host, which the example's handlers greet with.
+struct VirtualHost(&'static str);
+
+impl ClientInterceptor for VirtualHost {
+    fn before(&self, request: &mut request::Parts) -> RpcResult<()> {
+        let host = HeaderValue::from_static(self.0);
+        request.headers.insert(header::HOST, host);
+        Ok(())
+    }
+}
+
+fn client() -> HelloWorldServiceClient {
+    let http = TowerClient::new(app().into_router());
+    HelloWorldServiceClient::new(RpcClient::builder("http://example.com", http).build())
+}
+
+#[tokio::test]
+async fn calls_unary_rpcs() {
+    let request = HelloRequest {
+        name: Some("Alec".to_string()),
+    };
+    let response = client().say_hello(request).await.unwrap();
+    assert_eq!(
+        response.message,
+        "Hello Alec! You're addressing the hostname: example.com."
+    );
+}
+
+#[tokio::test]
+async fn runs_the_interceptors() {
+    let http = TowerClient::new(app().into_router());
+    let client = HelloWorldServiceClient::new(
+        RpcClient::builder("http://example.com", http)
+            .interceptor(VirtualHost("staging.example.com"))
+            .build(),
+    );
+
+    let request = HelloRequest {
+        name: Some("Alec".to_string()),
+    };
+    let response = client.say_hello(request).await.unwrap();
+    assert_eq!(
+        response.message,
+        "Hello Alec! You're addressing the hostname: staging.example.com."
+    );
+}
+
+#[tokio::test]
+async fn calls_server_streaming_rpcs() {
+    let request = HelloRequest {
+        name: Some("Alec".to_string()),
+    };
+    let stream = client().say_hello_stream(request).await.unwrap();
+    let first = stream.into_first().await.unwrap().unwrap();
+    assert_eq!(first.message, "Hello");
+}
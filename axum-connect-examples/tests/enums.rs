@@ -0,0 +1,87 @@
+//! Unknown enum values in JSON requests, rejected by default and decoded as the zero value with
+//! the `open_enums` codegen option, at every depth of a message.
+
+use axum_connect::{handler::RpcJsonDecode, prelude::*};
+
+use axum_connect_example::proto::{enums, open_enums};
+
+/// An `Account` with `value` as its status, its settings' default status and in its history.
+fn accounts(value: &str) -> [String; 3] {
+    [
+        format!(r#"{{"status": {value}}}"#),
+        format!(r#"{{"settings": {{"defaultStatus": {value}}}}}"#),
+        format!(r#"{{"history": ["STATUS_ACTIVE", {value}]}}"#),
+    ]
+}
+
+#[test]
+fn decodes_known_values_either_way() {
+    let json = r#"{"status": "STATUS_ACTIVE", "history": [2]}"#;
+
+    let strict = enums::Account::rpc_json_decode(json.as_bytes()).unwrap();
+    assert_eq!(strict.status(), enums::Status::Active);
+    assert_eq!(strict.history, [enums::Status::Suspended as i32]);
+
+    let open = open_enums::Account::rpc_json_decode(json.as_bytes()).unwrap();
+    assert_eq!(open.status(), open_enums::Status::Active);
+    assert_eq!(open.history, [open_enums::Status::Suspended as i32]);
+}
+
+#[test]
+fn rejects_unknown_names_naming_the_field() {
+    let errors = accounts(r#""STATUS_CLOSED""#).map(|json| {
+        let error = enums::Account::rpc_json_decode(json.as_bytes()).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        error.message
+    });
+
+    assert!(errors[0]
+        .starts_with("Failed to decode JSON protobuf. status: unknown variant `STATUS_CLOSED`"));
+    assert!(errors[1].starts_with(
+        "Failed to decode JSON protobuf. settings.defaultStatus: unknown variant `STATUS_CLOSED`"
+    ));
+    assert!(errors[2].starts_with(
+        "Failed to decode JSON protobuf. history[1]: unknown variant `STATUS_CLOSED`"
+    ));
+}
+
+#[test]
+fn rejects_unknown_numbers_naming_the_field() {
+    let errors = accounts("7").map(|json| {
+        let error = enums::Account::rpc_json_decode(json.as_bytes()).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        error.message
+    });
+
+    assert!(
+        errors[0].starts_with("Failed to decode JSON protobuf. status: invalid value: integer `7`"),
+        "{}",
+        errors[0]
+    );
+    assert!(errors[1].starts_with(
+        "Failed to decode JSON protobuf. settings.defaultStatus: invalid value: integer `7`"
+    ));
+    assert!(errors[2]
+        .starts_with("Failed to decode JSON protobuf. history[1]: invalid value: integer `7`"));
+}
+
+#[test]
+fn decodes_unknown_names_and_numbers_as_the_zero_value_when_open() {
+    let unspecified = open_enums::Status::Unspecified;
+    for value in [r#""STATUS_CLOSED""#, "7"] {
+        let [status, settings, history] = accounts(value)
+            .map(|json| open_enums::Account::rpc_json_decode(json.as_bytes()).unwrap());
+
+        assert_eq!(status.status(), unspecified, "{value}");
+        assert_eq!(
+            settings.settings.unwrap().default_status(),
+            unspecified,
+            "{value}"
+        );
+        assert_eq!(
+            history.history,
+            [open_enums::Status::Active as i32, unspecified as i32],
+            "{value}"
+        );
+    }
+}
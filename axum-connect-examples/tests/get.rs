@@ -0,0 +1,82 @@
+//! GET for `NO_SIDE_EFFECTS` methods, as codegen mounts it.
+
+use axum::{
+    body::Body,
+    http::{Method, Request},
+};
+use axum_connect::{prelude::*, testing::TestClient};
+use base64::{engine::general_purpose::URL_SAFE, Engine};
+use prost::Message;
+
+use axum_connect_example::proto::hello::*;
+
+async fn say_hello(request: HelloRequest) -> RpcResult<HelloResponse> {
+    Ok(HelloResponse {
+        message: format!("Hello {}!", request.name()),
+    })
+}
+
+fn get(name: &str) -> Request<Body> {
+    let message = HelloRequest {
+        name: Some(name.to_string()),
+    };
+    let uri = format!(
+        "{}?connect=v1&encoding=proto&base64=1&message={}",
+        HelloWorldService::SAY_HELLO_PATH,
+        URL_SAFE.encode(message.encode_to_vec())
+    );
+    Request::get(uri).body(Body::empty()).unwrap()
+}
+
+fn post(name: &str) -> Request<Body> {
+    let message = HelloRequest {
+        name: Some(name.to_string()),
+    };
+    Request::post(HelloWorldService::SAY_HELLO_PATH)
+        .header("content-type", "application/proto")
+        .body(Body::from(message.encode_to_vec()))
+        .unwrap()
+}
+
+async fn greeting(client: &TestClient, request: Request<Body>) -> Result<String, RpcError> {
+    let response = client.send(request).await;
+    match response.error() {
+        Some(error) => Err(error),
+        None => Ok(HelloResponse::decode(response.body).unwrap().message),
+    }
+}
+
+#[tokio::test]
+async fn mounts_get_for_no_side_effects_methods() {
+    let app = RpcRouter::new().rpc(HelloWorldService::say_hello(say_hello));
+    let info = &app.paths()[0];
+    assert_eq!(info.http_methods, [Method::POST, Method::GET]);
+
+    let client = TestClient::new(app.into_router());
+    assert_eq!(greeting(&client, get("Ada")).await.unwrap(), "Hello Ada!");
+    assert_eq!(greeting(&client, post("Ada")).await.unwrap(), "Hello Ada!");
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn unary_get_after_the_method_does_nothing() {
+    // Used to panic with overlapping GET routes.
+    let app = RpcRouter::new()
+        .rpc(HelloWorldService::say_hello(say_hello))
+        .rpc(HelloWorldService::say_hello_unary_get(say_hello));
+    assert_eq!(app.paths().len(), 1);
+
+    let client = TestClient::new(app.into_router());
+    assert_eq!(greeting(&client, get("Ada")).await.unwrap(), "Hello Ada!");
+    assert_eq!(greeting(&client, post("Ada")).await.unwrap(), "Hello Ada!");
+}
+
+#[tokio::test]
+#[allow(deprecated)]
+async fn unary_get_on_its_own_mounts_only_get() {
+    let app = RpcRouter::new().rpc(HelloWorldService::say_hello_unary_get(say_hello));
+    let client = TestClient::new(app.into_router());
+    assert_eq!(greeting(&client, get("Ada")).await.unwrap(), "Hello Ada!");
+    let error = greeting(&client, post("Ada")).await.unwrap_err();
+    assert_eq!(error.code, RpcErrorCode::Unimplemented);
+}
@@ -0,0 +1,202 @@
+//! Pins the wire format of the example router's responses, so a refactor of the response path
+//! can't change what deployed clients receive without it showing up.
+//!
+//! $ cargo test -p axum-connect-example --test golden
+//! $ UPDATE_GOLDEN=1 cargo test -p axum-connect-example --test golden
+//!
+//! Each transcript in `golden` is a `<name>.request` with the raw HTTP/1.1 request bytes, and a
+//! `<name>.response` with the response it got when it was recorded, normalized. The second
+//! command records them all again, after a deliberate wire change or for a new `.request`; review
+//! the diff of the `.response` files before committing it. Every protocol feature should add its
+//! own transcripts.
+//!
+//! Unlike the interop fixtures, bodies are compared byte for byte, frame boundaries included,
+//! and so are the status line and every recorded header. Only what legitimately changes from run
+//! to run is normalized: the `date`, the `x-request-id` the server generates (one sent by the
+//! request is kept), and the framing headers of HTTP/1.1 itself, which are dropped. Header order
+//! isn't compared. Gzipped bodies are pinned gunzipped, without their length: the compressed
+//! bytes depend on the deflate backend the features of the build select.
+
+use std::{io::Read, net::SocketAddr, path::Path};
+
+use anyhow::{Context, Result};
+use tokio::net::TcpListener;
+
+use transcript::HttpMessage;
+
+mod transcript;
+
+const GOLDEN: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/golden");
+
+/// Set to record the responses again instead of comparing them.
+const UPDATE: &str = "UPDATE_GOLDEN";
+
+/// How HTTP/1.1 framed the response, dropped.
+const FRAMING_HEADERS: &[&str] = &["transfer-encoding", "connection", "keep-alive"];
+
+/// Headers whose values change from run to run, and what they're replaced with.
+const NORMALIZED_HEADERS: &[(&str, &str)] = &[("date", "<date>"), ("x-request-id", "<request-id>")];
+
+#[tokio::test]
+async fn matches_the_golden_transcripts() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // With the recommended layers, for request ids and gzip.
+    let router = axum_connect_example::app()
+        .layer(axum_connect::layers::recommended())
+        .into_router();
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let names = transcript::request_names(GOLDEN).unwrap_or_else(|error| {
+        panic!("failed to list the transcripts in {}: {:#}", GOLDEN, error)
+    });
+    assert!(!names.is_empty(), "no transcripts in {}", GOLDEN);
+    let update = std::env::var_os(UPDATE).is_some_and(|update| !update.is_empty());
+
+    let mut failures = vec![];
+    for name in &names {
+        let result = match update {
+            true => record(addr, name).await.map(|()| None),
+            false => replay(addr, name).await,
+        };
+        match result {
+            Ok(None) => {}
+            Ok(Some(diff)) => failures.push(format!("{}\n{}", name, diff)),
+            Err(error) => failures.push(format!("{}: {:#}", name, error)),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} transcripts failed:\n{}\nif the changes are deliberate, record them with {}=1",
+        failures.len(),
+        names.len(),
+        failures.join("\n"),
+        UPDATE
+    );
+}
+
+/// Sends the transcript's request, and writes the normalized response.
+async fn record(addr: SocketAddr, name: &str) -> Result<()> {
+    let request = transcript::read_file(GOLDEN, name, "request")?;
+    let response = normalize(&request, transcript::exchange(addr, &request).await?)?;
+
+    let path = Path::new(GOLDEN).join(format!("{}.response", name));
+    std::fs::write(&path, render(&response))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+/// Sends the transcript's request, `None` if the response matches.
+async fn replay(addr: SocketAddr, name: &str) -> Result<Option<String>> {
+    let request = transcript::read_file(GOLDEN, name, "request")?;
+    let expected = transcript::read_file(GOLDEN, name, "response")?;
+    let expected =
+        HttpMessage::parse(&expected, true)?.context("the response transcript is truncated")?;
+
+    let actual = normalize(&request, transcript::exchange(addr, &request).await?)?;
+    Ok(diff(&expected, &actual)
+        .map(|diff| format!("  {}\n{}", transcript::request_line(&request), diff)))
+}
+
+/// `response` without the parts that change from run to run, its headers sorted.
+fn normalize(request: &[u8], mut response: HttpMessage) -> Result<HttpMessage> {
+    let request = HttpMessage::parse(request, true)?.context("the request is truncated")?;
+
+    response
+        .headers
+        .retain(|(name, _)| !FRAMING_HEADERS.contains(&name.as_str()));
+    if response.header("content-encoding") == Some("gzip") {
+        let mut body = vec![];
+        flate2::read::GzDecoder::new(response.body.as_slice())
+            .read_to_end(&mut body)
+            .context("the gzipped body is malformed")?;
+        response.body = body;
+        response
+            .headers
+            .retain(|(name, _)| name != "content-length");
+    }
+    for (name, value) in &mut response.headers {
+        let Some((_, placeholder)) = NORMALIZED_HEADERS
+            .iter()
+            .find(|(normalized, _)| normalized == name)
+        else {
+            continue;
+        };
+        // Echoed from the request, so it's part of what's pinned.
+        if request.header(name) != Some(value.as_str()) {
+            *value = placeholder.to_string();
+        }
+    }
+    response.headers.sort();
+    Ok(response)
+}
+
+/// The transcript of a normalized response. Its body is the rest of the file, framing headers
+/// aside.
+fn render(response: &HttpMessage) -> Vec<u8> {
+    let mut out = format!("{}\r\n", response.start_line);
+    for (name, value) in &response.headers {
+        out.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    out.push_str("\r\n");
+
+    let mut out = out.into_bytes();
+    out.extend_from_slice(&response.body);
+    out
+}
+
+/// The differences in status, headers and body, `None` if there are none.
+fn diff(expected: &HttpMessage, actual: &HttpMessage) -> Option<String> {
+    let mut out = String::new();
+
+    if expected.start_line != actual.start_line {
+        out.push_str(&format!(
+            "  status: expected {}, got {}\n",
+            expected.start_line, actual.start_line
+        ));
+    }
+
+    // Both are normalized, so sorted.
+    let expected_headers = header_lines(expected);
+    let actual_headers = header_lines(actual);
+    if expected_headers != actual_headers {
+        out.push_str("  headers:\n");
+        for header in expected_headers
+            .iter()
+            .filter(|h| !actual_headers.contains(h))
+        {
+            out.push_str(&format!("    - {}\n", header));
+        }
+        for header in actual_headers
+            .iter()
+            .filter(|h| !expected_headers.contains(h))
+        {
+            out.push_str(&format!("    + {}\n", header));
+        }
+    }
+
+    if expected.body != actual.body {
+        out.push_str("  body:\n");
+        let expected_body = expected.decoded_body();
+        let actual_body = actual.decoded_body();
+        match expected_body == actual_body {
+            // Eg. the same JSON, formatted differently.
+            true => transcript::diff_lines(
+                &mut out,
+                &[transcript::hex(&expected.body)],
+                &[transcript::hex(&actual.body)],
+            ),
+            false => transcript::diff_lines(&mut out, &expected_body, &actual_body),
+        }
+    }
+
+    (!out.is_empty()).then_some(out)
+}
+
+fn header_lines(message: &HttpMessage) -> Vec<String> {
+    message
+        .headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect()
+}
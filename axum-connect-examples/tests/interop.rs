@@ -0,0 +1,150 @@
+//! Replays requests recorded from a connect-es client against the example router, and compares
+//! the responses with the ones recorded alongside them.
+//!
+//! $ cargo test -p axum-connect-example --test interop
+//!
+//! Each fixture in `interop/fixtures` is a `<name>.request` with the raw HTTP/1.1 request bytes,
+//! and a `<name>.response` with the response expected back. `interop/record.mjs` regenerates them.
+//!
+//! Headers that change from run to run or with the transport (`date`, `content-length`, ...) are
+//! ignored, as are the headers a response fixture lists in its `x-interop-ignore` header. Bodies
+//! are compared decoded: JSON regardless of formatting and key order, binary messages as their
+//! JSON mapping, and streams frame by frame. The test fails with a diff of both for each fixture
+//! that doesn't match.
+
+use std::net::SocketAddr;
+
+use anyhow::{Context, Result};
+use tokio::net::TcpListener;
+use tower_http::cors::CorsLayer;
+
+use transcript::HttpMessage;
+
+mod transcript;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/interop/fixtures");
+
+const IGNORED_HEADERS: &[&str] = &[
+    "date",
+    "content-length",
+    "transfer-encoding",
+    "connection",
+    "keep-alive",
+];
+
+/// Lists more headers to ignore, comma separated, in a response fixture.
+const IGNORE_HEADER: &str = "x-interop-ignore";
+
+#[tokio::test]
+async fn replays_the_connect_es_fixtures() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    // Served like `main` serves it.
+    let router = axum_connect_example::app()
+        .into_router()
+        .layer(CorsLayer::very_permissive());
+    tokio::spawn(async move { axum::serve(listener, router).await });
+
+    let names = transcript::request_names(FIXTURES)
+        .unwrap_or_else(|error| panic!("failed to list the fixtures in {}: {:#}", FIXTURES, error));
+    assert!(!names.is_empty(), "no fixtures in {}", FIXTURES);
+
+    let mut failures = vec![];
+    for name in &names {
+        match replay(addr, name).await {
+            Ok(None) => {}
+            Ok(Some(diff)) => failures.push(format!("{}\n{}", name, diff)),
+            Err(error) => failures.push(format!("{}: {:#}", name, error)),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} fixtures failed:\n{}",
+        failures.len(),
+        names.len(),
+        failures.join("\n")
+    );
+}
+
+/// Sends the fixture's request as it was recorded, `None` if the response matches.
+async fn replay(addr: SocketAddr, name: &str) -> Result<Option<String>> {
+    let request = transcript::read_file(FIXTURES, name, "request")?;
+    let expected = transcript::read_file(FIXTURES, name, "response")?;
+    let expected =
+        HttpMessage::parse(&expected, true)?.context("the response fixture is truncated")?;
+
+    let actual = transcript::exchange(addr, &request).await?;
+    Ok(diff(&expected, &actual)
+        .map(|diff| format!("  {}\n{}", transcript::request_line(&request), diff)))
+}
+
+/// The headers to compare, as `name: value` lines.
+fn compared_headers(message: &HttpMessage, ignored: &[String]) -> Vec<String> {
+    let mut headers = message
+        .headers
+        .iter()
+        .filter(|(name, _)| {
+            name != IGNORE_HEADER
+                && !IGNORED_HEADERS.contains(&name.as_str())
+                && !ignored.contains(name)
+        })
+        .map(|(name, value)| format!("{}: {}", name, value))
+        .collect::<Vec<_>>();
+    headers.sort();
+    headers
+}
+
+/// The differences in status, headers and decoded body, `None` if there are none.
+fn diff(expected: &HttpMessage, actual: &HttpMessage) -> Option<String> {
+    let mut out = String::new();
+
+    if expected.status() != actual.status() {
+        out.push_str(&format!(
+            "  status: expected {}, got {}\n",
+            expected.start_line, actual.start_line
+        ));
+    }
+
+    let ignored = expected
+        .header(IGNORE_HEADER)
+        .map(|names| {
+            names
+                .split(',')
+                .map(|name| name.trim().to_ascii_lowercase())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    let expected_headers = compared_headers(expected, &ignored);
+    let mut unexpected_headers = compared_headers(actual, &ignored);
+    let mut missing_headers = vec![];
+    for header in expected_headers {
+        match unexpected_headers
+            .iter()
+            .position(|actual| *actual == header)
+        {
+            Some(index) => {
+                unexpected_headers.remove(index);
+            }
+            None => missing_headers.push(header),
+        }
+    }
+    if !missing_headers.is_empty() || !unexpected_headers.is_empty() {
+        out.push_str("  headers:\n");
+        for header in missing_headers {
+            out.push_str(&format!("    - {}\n", header));
+        }
+        for header in unexpected_headers {
+            out.push_str(&format!("    + {}\n", header));
+        }
+    }
+
+    let expected_body = expected.decoded_body();
+    let actual_body = actual.decoded_body();
+    if expected_body != actual_body {
+        out.push_str("  body:\n");
+        transcript::diff_lines(&mut out, &expected_body, &actual_body);
+    }
+
+    (!out.is_empty()).then_some(out)
+}
@@ -0,0 +1,87 @@
+//! Round-trips the JSON protobuf-es writes for `mapping.Profile` through the generated Serde
+//! impls, so the fields connect-es clients are strictest about (unset optionals, 64-bit ints and
+//! their wrappers, timestamps, repeated and in maps too, and durations) keep the protobuf JSON
+//! mapping.
+//!
+//! $ cargo test -p axum-connect-example --test json_mapping
+//!
+//! `interop/json.mjs` writes the fixtures in `interop/json`. They're compared as JSON values, so
+//! formatting and key order don't matter.
+
+use axum_connect::pbjson_types::{Duration, Int64Value, StringValue, Timestamp};
+use axum_connect_example::proto::mapping::{profile::Expiry, Profile};
+use serde_json::Value;
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/interop/json");
+
+/// 2024-01-02T03:04:05Z
+const SECONDS: i64 = 1704164645;
+
+fn fixture(name: &str) -> Value {
+    let path = format!("{}/{}.json", FIXTURES, name);
+    let contents =
+        std::fs::read_to_string(&path).unwrap_or_else(|error| panic!("{}: {}", path, error));
+    serde_json::from_str(&contents).unwrap()
+}
+
+/// The fixture read as a `Profile`, after checking that it writes back to the same JSON.
+fn round_trip(name: &str) -> Profile {
+    let json = fixture(name);
+    let profile: Profile = serde_json::from_value(json.clone()).unwrap();
+    assert_eq!(serde_json::to_value(&profile).unwrap(), json, "{}", name);
+    profile
+}
+
+fn timestamp(nanos: i32) -> Timestamp {
+    Timestamp {
+        seconds: SECONDS,
+        nanos,
+    }
+}
+
+#[test]
+fn omits_unset_fields() {
+    assert_eq!(round_trip("empty"), Profile::default());
+}
+
+#[test]
+fn writes_set_optionals_and_wrappers_holding_defaults() {
+    let profile = round_trip("defaults");
+    assert_eq!(profile.nickname.as_deref(), Some(""));
+    assert_eq!(profile.limit, Some(Int64Value { value: 0 }));
+    assert_eq!(profile.note, Some(StringValue::default()));
+    assert_eq!(profile.expiry, Some(Expiry::NeverExpires(false)));
+}
+
+#[test]
+fn maps_every_field_like_protobuf_es() {
+    let profile = round_trip("full");
+    assert_eq!(
+        profile,
+        Profile {
+            id: "u1".to_string(),
+            nickname: Some("Ferris".to_string()),
+            balance: -9007199254740993,
+            visits: u64::MAX,
+            age: 30,
+            limit: Some(Int64Value {
+                value: 9007199254740993,
+            }),
+            note: Some(StringValue {
+                value: "hi".to_string(),
+            }),
+            created: Some(timestamp(500_000_000)),
+            session: Some(Duration {
+                seconds: 1,
+                nanos: 500_000_000,
+            }),
+            logins: vec![timestamp(0), timestamp(123_000)],
+            expiry: Some(Expiry::ExpiresAt(timestamp(123_456_789))),
+            last_seen: [
+                ("web".to_string(), timestamp(0)),
+                ("app".to_string(), timestamp(120_000_000)),
+            ]
+            .into(),
+        }
+    );
+}
@@ -0,0 +1,54 @@
+//! The `oneof_helpers` accessors of a three-field oneof: the field that's set, another one, and
+//! none at all.
+
+use axum_connect::prelude::*;
+
+use axum_connect_example::proto::shapes::{shape::Kind, Circle, Shape};
+
+fn shape(kind: Option<Kind>) -> Shape {
+    Shape { kind }
+}
+
+#[test]
+fn returns_the_field_that_is_set() {
+    let circle = shape(Some(Kind::Circle(Circle { radius: 2.0 })));
+    assert_eq!(circle.kind_as_circle().unwrap().radius, 2.0);
+    assert_eq!(circle.kind_kind_name(), Some("circle"));
+
+    let label = shape(Some(Kind::Label("square".to_string())));
+    assert_eq!(label.kind_as_label().unwrap(), "square");
+    assert_eq!(label.kind_kind_name(), Some("label"));
+
+    let sides = shape(Some(Kind::Sides(4)));
+    assert_eq!(*sides.kind_as_sides().unwrap(), 4);
+    assert_eq!(sides.kind_kind_name(), Some("sides"));
+}
+
+#[test]
+fn names_both_fields_when_another_is_set() {
+    let label = shape(Some(Kind::Label("square".to_string())));
+
+    let error = label.kind_as_circle().unwrap_err();
+    assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+    assert_eq!(error.message, "Expected `circle` in `kind`, got `label`");
+
+    let error = label.kind_as_sides().unwrap_err();
+    assert_eq!(error.message, "Expected `sides` in `kind`, got `label`");
+}
+
+#[test]
+fn fails_every_accessor_when_unset() {
+    let unset = shape(None);
+    assert_eq!(unset.kind_kind_name(), None);
+    for error in [
+        unset.kind_as_circle().unwrap_err(),
+        unset.kind_as_label().unwrap_err(),
+        unset.kind_as_sides().unwrap_err(),
+    ] {
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+    }
+    assert_eq!(
+        unset.kind_as_label().unwrap_err().message,
+        "Expected `label` in `kind`, but it isn't set"
+    );
+}
@@ -0,0 +1,96 @@
+//! The generated path constants, against the routes the router actually serves.
+
+use axum::{body::Body, http::Request};
+use axum_connect::{
+    futures::{stream, Stream},
+    prelude::*,
+    testing::TestClient,
+};
+use prost::Message;
+
+use axum_connect_example::proto::hello::*;
+
+async fn say_hello(request: HelloRequest) -> RpcResult<HelloResponse> {
+    Ok(HelloResponse {
+        message: format!("Hello {}!", request.name()),
+    })
+}
+
+async fn say_hello_stream(request: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+    stream::iter([say_hello(request).await])
+}
+
+fn app() -> RpcRouter {
+    RpcRouter::new()
+        .rpc(HelloWorldService::say_hello(say_hello))
+        .rpc(HelloWorldService::say_hello_stream(say_hello_stream))
+}
+
+#[test]
+fn paths_are_the_connect_paths_of_the_service() {
+    assert_eq!(
+        HelloWorldService::SAY_HELLO_PATH,
+        "/hello.HelloWorldService/SayHello"
+    );
+    assert_eq!(
+        HelloWorldService::SAY_HELLO_STREAM_PATH,
+        "/hello.HelloWorldService/SayHelloStream"
+    );
+    assert_eq!(
+        HelloWorldService::PATHS,
+        [
+            HelloWorldService::SAY_HELLO_PATH,
+            HelloWorldService::SAY_HELLO_STREAM_PATH
+        ]
+    );
+}
+
+#[test]
+fn mounts_every_rpc_at_its_path_constant() {
+    let mounted = app()
+        .paths()
+        .into_iter()
+        .map(|info| info.path)
+        .collect::<Vec<_>>();
+    assert_eq!(mounted, HelloWorldService::PATHS);
+}
+
+#[tokio::test]
+async fn serves_calls_at_the_path_constants() {
+    let client = TestClient::new(app().into_router());
+    let message = HelloRequest {
+        name: Some("Ada".to_string()),
+    };
+
+    let response = client
+        .send(
+            Request::post(HelloWorldService::SAY_HELLO_PATH)
+                .header("content-type", "application/proto")
+                .body(Body::from(message.encode_to_vec()))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.error(), None);
+    assert_eq!(
+        HelloResponse::decode(response.body).unwrap().message,
+        "Hello Ada!"
+    );
+
+    let mut frame = vec![0];
+    frame.extend((message.encoded_len() as u32).to_be_bytes());
+    frame.extend(message.encode_to_vec());
+    let response = client
+        .send(
+            Request::post(HelloWorldService::SAY_HELLO_STREAM_PATH)
+                .header("content-type", "application/connect+proto")
+                .body(Body::from(frame))
+                .unwrap(),
+        )
+        .await;
+    let (frames, error) = response.frames();
+    assert_eq!(error, None);
+    assert_eq!(
+        HelloResponse::decode(frames[0].as_slice()).unwrap().message,
+        "Hello Ada!"
+    );
+}
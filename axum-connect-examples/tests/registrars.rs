@@ -0,0 +1,120 @@
+//! Mounting the example service from its `service_traits` table, whole or without a method.
+
+use axum::{body::Body, http::Request};
+use axum_connect::{
+    futures::{stream, Stream},
+    prelude::*,
+    request::RpcRequest,
+    response::RpcResponse,
+    testing::{TestClient, TestResponse},
+};
+use prost::Message;
+
+use axum_connect_example::proto::hello::*;
+
+#[derive(Clone)]
+struct Greeter {
+    greeting: &'static str,
+}
+
+impl Greeter {
+    fn greet(&self, request: &HelloRequest) -> HelloResponse {
+        HelloResponse {
+            message: format!("{} {}!", self.greeting, request.name()),
+        }
+    }
+}
+
+impl HelloWorldServiceHandlers for Greeter {
+    async fn say_hello(
+        &self,
+        request: RpcRequest<HelloRequest>,
+    ) -> RpcResult<RpcResponse<HelloResponse>> {
+        Ok(RpcResponse::new(self.greet(request.get_ref())))
+    }
+
+    async fn say_hello_stream(
+        self,
+        request: RpcRequest<HelloRequest>,
+    ) -> RpcResult<RpcResponse<impl Stream<Item = RpcResult<HelloResponse>> + Send + 'static>> {
+        let response = self.greet(request.get_ref());
+        Ok(RpcResponse::new(stream::iter([Ok(response)])))
+    }
+}
+
+/// Some state of the app, which the table is generic over.
+#[derive(Clone)]
+struct AppState;
+
+fn greeter() -> Greeter {
+    Greeter { greeting: "Hi" }
+}
+
+fn mounted(router: &RpcRouter<AppState>) -> Vec<String> {
+    router.paths().into_iter().map(|info| info.path).collect()
+}
+
+async fn say_hello(router: RpcRouter<AppState>) -> TestResponse {
+    let client = TestClient::new(router.with_state(AppState).into_router());
+    let request = HelloRequest {
+        name: Some("Ada".to_string()),
+    };
+    client
+        .send(
+            Request::post(HelloWorldService::SAY_HELLO_PATH)
+                .header("content-type", "application/proto")
+                .body(Body::from(request.encode_to_vec()))
+                .unwrap(),
+        )
+        .await
+}
+
+#[test]
+fn lists_a_registrar_per_method_in_the_order_of_the_paths() {
+    let registrars = HelloWorldService::registrars::<Greeter, AppState>();
+    assert_eq!(registrars.len(), HelloWorldService::PATHS.len());
+    assert_eq!(
+        registrars
+            .iter()
+            .map(|registrar| registrar.method)
+            .collect::<Vec<_>>(),
+        ["SayHello", "SayHelloStream"]
+    );
+    assert_eq!(
+        registrars
+            .iter()
+            .map(|registrar| registrar.path)
+            .collect::<Vec<_>>(),
+        HelloWorldService::PATHS
+    );
+}
+
+#[tokio::test]
+async fn mounts_every_method_from_the_table() {
+    let router = HelloWorldService::register_all(RpcRouter::new(), greeter());
+    assert_eq!(mounted(&router), HelloWorldService::PATHS);
+
+    let response = say_hello(router).await;
+    assert_eq!(response.error(), None);
+    assert_eq!(
+        HelloResponse::decode(response.body).unwrap().message,
+        "Hi Ada!"
+    );
+}
+
+#[tokio::test]
+async fn leaves_out_methods_excluded_by_name() {
+    let router = HelloWorldService::registrars::<Greeter, AppState>()
+        .iter()
+        .filter(|registrar| registrar.method != "SayHelloStream")
+        .fold(RpcRouter::new(), |router, registrar| {
+            (registrar.register)(router, greeter())
+        });
+    assert_eq!(mounted(&router), [HelloWorldService::SAY_HELLO_PATH]);
+
+    let response = say_hello(router).await;
+    assert_eq!(
+        HelloResponse::decode(response.body).unwrap().message,
+        "Hi Ada!"
+    );
+}
@@ -0,0 +1,262 @@
+//! Raw HTTP/1.1 transcripts, shared by the interop fixtures (`tests/interop.rs`) and the golden
+//! transcripts (`tests/golden.rs`): sending a recorded request over TCP, parsing the response, and
+//! decoding Connect bodies to print them.
+
+// Each test uses its own part of it.
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, path::Path, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use axum_connect::prost::Message as _;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use axum_connect_example::proto::hello::HelloResponse;
+
+/// The names of the `<name>.request` files in `dir`, sorted.
+pub fn request_names(dir: &str) -> Result<Vec<String>> {
+    let mut names = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "request")
+        {
+            if let Some(stem) = path.file_stem() {
+                names.push(stem.to_string_lossy().into_owned());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+pub fn read_file(dir: &str, name: &str, extension: &str) -> Result<Vec<u8>> {
+    let path = Path::new(dir).join(format!("{}.{}", name, extension));
+    std::fs::read(&path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+/// Sends `request` as it is on a new connection, and reads the response back.
+pub async fn exchange(addr: SocketAddr, request: &[u8]) -> Result<HttpMessage> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(request).await?;
+
+    let mut received = vec![];
+    loop {
+        if let Some(message) = HttpMessage::parse(&received, false)? {
+            return Ok(message);
+        }
+        let mut chunk = [0; 8192];
+        let read = tokio::time::timeout(Duration::from_secs(10), stream.read(&mut chunk))
+            .await
+            .context("timed out waiting for the response")??;
+        if read == 0 {
+            return HttpMessage::parse(&received, true)?
+                .context("the connection closed before the response was complete");
+        }
+        received.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// The first line of `request`, to print with its failures.
+pub fn request_line(request: &[u8]) -> String {
+    String::from_utf8_lossy(request.split(|b| *b == b'\n').next().unwrap_or(&[]))
+        .trim_end()
+        .to_string()
+}
+
+/// An HTTP/1.1 message, with the body de-chunked.
+pub struct HttpMessage {
+    pub start_line: String,
+    /// Names are lowercase.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpMessage {
+    /// Parses the message at the start of `bytes`, `None` if it's incomplete. Without a
+    /// `content-length` or chunked encoding, the body is the rest of `bytes`, once at `eof`.
+    pub fn parse(bytes: &[u8], eof: bool) -> Result<Option<Self>> {
+        let incomplete = || match eof {
+            true => bail!("the message is truncated"),
+            false => Ok(None),
+        };
+
+        let Some(head_end) = find(bytes, b"\r\n\r\n") else {
+            return incomplete();
+        };
+        let head = std::str::from_utf8(&bytes[..head_end]).context("the head isn't UTF-8")?;
+        let mut lines = head.split("\r\n");
+        let start_line = lines.next().unwrap_or_default().to_string();
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line
+                    .split_once(':')
+                    .with_context(|| format!("malformed header line {:?}", line))?;
+                Ok((name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut message = Self {
+            start_line,
+            headers,
+            body: vec![],
+        };
+        let rest = &bytes[head_end + 4..];
+        let chunked = message
+            .header("transfer-encoding")
+            .is_some_and(|encoding| encoding.eq_ignore_ascii_case("chunked"));
+
+        if chunked {
+            match dechunk(rest)? {
+                Some(body) => message.body = body,
+                None => return incomplete(),
+            }
+        } else if let Some(length) = message.header("content-length") {
+            let length = length
+                .parse::<usize>()
+                .with_context(|| format!("invalid content-length {:?}", length))?;
+            if rest.len() < length {
+                return incomplete();
+            }
+            message.body = rest[..length].to_vec();
+        } else if eof {
+            message.body = rest.to_vec();
+        } else {
+            return Ok(None);
+        }
+
+        Ok(Some(message))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn status(&self) -> &str {
+        self.start_line.split(' ').nth(1).unwrap_or_default()
+    }
+
+    /// The body as lines to compare and print, decoded by its content type. Compressed bodies
+    /// are printed as hex.
+    pub fn decoded_body(&self) -> Vec<String> {
+        if self.header("content-encoding").is_some() {
+            return vec![format!("compressed {}", hex(&self.body))];
+        }
+        let content_type = self.header("content-type").unwrap_or_default();
+        let content_type = content_type.split(';').next().unwrap_or_default().trim();
+        match content_type {
+            "application/json" => vec![json(&self.body)],
+            "application/proto" => vec![proto(&self.body)],
+            "application/connect+json" => frames(&self.body, false),
+            "application/connect+proto" => frames(&self.body, true),
+            _ if self.body.is_empty() => vec![],
+            _ => vec![String::from_utf8_lossy(&self.body).into_owned()],
+        }
+    }
+}
+
+/// The body of a chunked message, `None` if it's incomplete.
+fn dechunk(mut bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut body = vec![];
+    loop {
+        let Some(line_end) = find(bytes, b"\r\n") else {
+            return Ok(None);
+        };
+        let line = std::str::from_utf8(&bytes[..line_end]).context("invalid chunk size")?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .with_context(|| format!("invalid chunk size {:?}", size))?;
+        bytes = &bytes[line_end + 2..];
+
+        if size == 0 {
+            // The last chunk is followed by trailers, if any, and an empty line.
+            let complete = bytes.starts_with(b"\r\n") || find(bytes, b"\r\n\r\n").is_some();
+            return Ok(complete.then_some(body));
+        }
+        if bytes.len() < size + 2 {
+            return Ok(None);
+        }
+        body.extend_from_slice(&bytes[..size]);
+        bytes = &bytes[size + 2..];
+    }
+}
+
+/// Connect stream frames, one line each with their flags. End-of-stream frames are always JSON.
+fn frames(mut body: &[u8], binary: bool) -> Vec<String> {
+    let mut lines = vec![];
+    while !body.is_empty() {
+        if body.len() < 5 {
+            lines.push(format!("truncated frame {}", hex(body)));
+            break;
+        }
+        let flags = body[0];
+        let size = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        let Some(payload) = body.get(5..5 + size) else {
+            lines.push(format!("truncated frame {}", hex(body)));
+            break;
+        };
+
+        let payload = if flags & 0b01 != 0 {
+            format!("compressed {}", hex(payload))
+        } else if flags & 0b10 != 0 || !binary {
+            json(payload)
+        } else {
+            proto(payload)
+        };
+        lines.push(format!("frame {:#04x} {}", flags, payload));
+        body = &body[5 + size..];
+    }
+    lines
+}
+
+/// JSON with the formatting and key order normalized, the text as it is if it isn't JSON.
+fn json(bytes: &[u8]) -> String {
+    match serde_json::from_slice::<serde_json::Value>(bytes) {
+        Ok(value) => value.to_string(),
+        Err(_) => String::from_utf8_lossy(bytes).into_owned(),
+    }
+}
+
+/// Binary messages as their JSON mapping. Both RPCs of the example respond with `HelloResponse`.
+fn proto(bytes: &[u8]) -> String {
+    match HelloResponse::decode(bytes) {
+        Ok(message) => format!("proto {}", serde_json::to_string(&message).unwrap()),
+        Err(_) => format!("invalid proto {}", hex(bytes)),
+    }
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Prints expected and actual lines side by side, `-` and `+` where they differ.
+pub fn diff_lines(out: &mut String, expected: &[String], actual: &[String]) {
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(expected), Some(actual)) if expected == actual => {
+                out.push_str(&format!("      {}\n", expected));
+            }
+            (expected, actual) => {
+                if let Some(expected) = expected {
+                    out.push_str(&format!("    - {}\n", expected));
+                }
+                if let Some(actual) = actual {
+                    out.push_str(&format!("    + {}\n", actual));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,376 @@
+//! Macros for `axum-connect`, re-exported from it with the `macros` feature.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote, quote_spanned, ToTokens};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input,
+    punctuated::Punctuated,
+    spanned::Spanned,
+    FnArg, GenericArgument, Ident, ItemFn, PathArguments, ReturnType, Token, Type, TypeParamBound,
+};
+
+/// Checks the signature of an RPC handler, turning the trait bound errors of a handler that
+/// doesn't fit (pages of them, pointing at the `rpc(...)` call) into one error per problem,
+/// pointing at the parameter or return type at fault:
+///
+/// ```text
+/// error[E0277]: the 2nd parameter `Db` does not implement `RpcFromRequestParts` for `HelloResponse` responses
+/// ```
+///
+/// The last parameter is the request message, the others are extractors. The response message is
+/// taken from the return type (`HelloResponse`, `Result<HelloResponse, E>`, or the items of an
+/// `impl Stream`), and the router state from a `State<T>` parameter, `()` without one. Either can
+/// be named instead, eg. `#[rpc_debug_handler(state = AppState, response = HelloResponse)]`.
+/// Handlers of streaming RPCs that take no request message need `without_message`.
+///
+/// It only adds checks, the function is left as it is. Like `axum::debug_handler`, it's meant for
+/// debugging, and only works on free `async fn`s without generics.
+#[proc_macro_attribute]
+pub fn rpc_debug_handler(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as Args);
+    let function = parse_macro_input!(item as ItemFn);
+    let checks = checks(&args, &function).unwrap_or_else(syn::Error::into_compile_error);
+
+    quote! {
+        #function
+        #checks
+    }
+    .into()
+}
+
+#[derive(Default)]
+struct Args {
+    state: Option<Type>,
+    response: Option<Type>,
+    without_message: bool,
+}
+
+impl Parse for Args {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut args = Args::default();
+        for arg in Punctuated::<Arg, Token![,]>::parse_terminated(input)? {
+            match arg {
+                Arg::State(ty) => args.state = Some(ty),
+                Arg::Response(ty) => args.response = Some(ty),
+                Arg::WithoutMessage => args.without_message = true,
+            }
+        }
+        Ok(args)
+    }
+}
+
+enum Arg {
+    State(Type),
+    Response(Type),
+    WithoutMessage,
+}
+
+impl Parse for Arg {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let name: Ident = input.parse()?;
+        match name.to_string().as_str() {
+            "without_message" => Ok(Arg::WithoutMessage),
+            "state" | "response" => {
+                input.parse::<Token![=]>()?;
+                let ty = input.parse()?;
+                match name == "state" {
+                    true => Ok(Arg::State(ty)),
+                    false => Ok(Arg::Response(ty)),
+                }
+            }
+            _ => Err(syn::Error::new(
+                name.span(),
+                "expected `state = <type>`, `response = <type>` or `without_message`",
+            )),
+        }
+    }
+}
+
+fn checks(args: &Args, function: &ItemFn) -> syn::Result<TokenStream2> {
+    let sig = &function.sig;
+    let name = &sig.ident;
+    if sig.asyncness.is_none() {
+        return Err(syn::Error::new(
+            sig.fn_token.span(),
+            "`rpc_debug_handler` only works on `async fn`s",
+        ));
+    }
+    if !sig.generics.params.is_empty() {
+        return Err(syn::Error::new(
+            sig.generics.span(),
+            "`rpc_debug_handler` doesn't work on generic handlers",
+        ));
+    }
+
+    let mut params = vec![];
+    for input in &sig.inputs {
+        match input {
+            FnArg::Typed(pat) => params.push((*pat.ty).clone()),
+            FnArg::Receiver(receiver) => {
+                return Err(syn::Error::new(
+                    receiver.span(),
+                    "`rpc_debug_handler` only works on free functions",
+                ))
+            }
+        }
+    }
+
+    let (extractors, message) =
+        match args.without_message {
+            true => (&params[..], None),
+            false => match params.split_last() {
+                Some((message, extractors)) => (extractors, Some(message)),
+                None => return Err(syn::Error::new(
+                    sig.paren_token.span.join(),
+                    "an RPC handler takes the request message as its last parameter, handlers of \
+                     streaming RPCs without one need `#[rpc_debug_handler(without_message)]`",
+                )),
+            },
+        };
+
+    let output = match &sig.output {
+        ReturnType::Default => syn::parse_quote!(()),
+        ReturnType::Type(_, ty) => (**ty).clone(),
+    };
+    let stream_item = stream_item(&output);
+    let into = stream_item.clone().unwrap_or_else(|| output.clone());
+    let response = match &args.response {
+        Some(response) => response.clone(),
+        None => response_message(&into).ok_or_else(|| {
+            syn::Error::new(
+                into.span(),
+                format!(
+                    "can't tell the response message from `{}`, name it with \
+                     `#[rpc_debug_handler(response = <message>)]`",
+                    tokens(&into)
+                ),
+            )
+        })?,
+    };
+    let state = args
+        .state
+        .clone()
+        .or_else(|| extractors.iter().find_map(state_type))
+        .unwrap_or_else(|| syn::parse_quote!(()));
+
+    let response_name = tokens(&response);
+    let state_name = tokens(&state);
+    let mut checks = vec![];
+
+    for (index, extractor) in extractors.iter().enumerate() {
+        let check = format_ident!("Parameter{}", index + 1);
+        let message = format!(
+            "the {} parameter `{{Self}}` does not implement `RpcFromRequestParts` for `{}` \
+             responses",
+            ordinal(index + 1),
+            response_name
+        );
+        let note = format!(
+            "extractors implement `RpcFromRequestParts<M, S>` for the response message `M` and \
+             the router state `S`, here `{}`",
+            state_name
+        );
+        checks.push(quote_spanned! {extractor.span()=>
+            #[diagnostic::on_unimplemented(message = #message, label = "not an RPC extractor", note = #note)]
+            trait #check {}
+            impl<T> #check for T
+            where
+                T: ::axum_connect::parts::RpcFromRequestParts<#response, #state>,
+            {}
+            fn assert<T: #check>() {}
+            assert::<#extractor>();
+        });
+    }
+
+    if let Some(message) = message {
+        let text = format!(
+            "the request message `{{Self}}` must be a prost `Message` with a `Default`{}",
+            JSON_NOTE
+        );
+        checks.push(quote_spanned! {message.span()=>
+            #[diagnostic::on_unimplemented(message = #text, label = "not a request message")]
+            trait RequestMessage {}
+            impl<T> RequestMessage for T
+            where
+                T: ::axum_connect::prost::Message
+                    + ::std::default::Default
+                    + ::axum_connect::handler::RpcJsonDecode
+                    + ::std::marker::Send
+                    + 'static,
+            {}
+            fn assert<T: RequestMessage>() {}
+            assert::<#message>();
+        });
+    }
+
+    let text = format!(
+        "the response message `{{Self}}` must be a prost `Message`{}",
+        JSON_NOTE
+    );
+    checks.push(quote_spanned! {response.span()=>
+        #[diagnostic::on_unimplemented(message = #text, label = "not a response message")]
+        trait ResponseMessage {}
+        impl<T> ResponseMessage for T
+        where
+            T: ::axum_connect::prost::Message
+                + ::axum_connect::handler::RpcJsonEncode
+                + ::std::marker::Send
+                + 'static,
+        {}
+        fn assert<T: ResponseMessage>() {}
+        assert::<#response>();
+    });
+
+    let subject = match stream_item {
+        Some(_) => "the stream's items",
+        None => "the return type",
+    };
+    let text = format!(
+        "{} `{{Self}}` is not `RpcIntoResponse` for message `{}`",
+        subject, response_name
+    );
+    checks.push(quote_spanned! {into.span()=>
+        #[diagnostic::on_unimplemented(
+            message = #text,
+            label = "not an RPC response",
+            note = "return the message, a `Result` of it and an `RpcIntoError`, or an `RpcError`"
+        )]
+        trait IntoResponse {}
+        impl<T> IntoResponse for T where T: ::axum_connect::response::RpcIntoResponse<#response> {}
+        fn assert<T: IntoResponse>() {}
+        assert::<#into>();
+    });
+
+    // The future (and stream) are only named by calling the handler, which never happens.
+    let calls = (0..params.len()).map(|_| quote!(::std::todo!()));
+    let text = format!("the future returned by `{}` is not `Send`", name);
+    let mut send = quote_spanned! {name.span()=>
+        #[diagnostic::on_unimplemented(
+            message = #text,
+            label = "not `Send`",
+            note = "a value that isn't `Send` (eg. an `Rc` or a `std::sync::MutexGuard`) is probably held across an `.await`"
+        )]
+        trait SendFuture {}
+        impl<T: ::std::marker::Send> SendFuture for T {}
+        fn assert<T: SendFuture>(_: &T) {}
+        let future = #name(#(#calls),*);
+        assert(&future);
+    };
+    if stream_item.is_some() {
+        let text = format!(
+            "the stream returned by `{}` must be `Send` and `'static`",
+            name
+        );
+        send.extend(quote_spanned! {output.span()=>
+            #[diagnostic::on_unimplemented(message = #text, label = "not a `Send + 'static` stream")]
+            trait SendStream {}
+            impl<T: ::std::marker::Send + 'static> SendStream for T {}
+            fn assert_stream<T: SendStream>(_: T) {}
+            async fn output<F: ::std::future::Future>(future: F) -> F::Output {
+                future.await
+            }
+            let _ = async move { assert_stream(output(future).await) };
+        });
+    }
+
+    let checks = checks.into_iter().map(|check| quote!({ #check }));
+    Ok(quote! {
+        #[allow(warnings, clippy::all)]
+        const _: () = {
+            fn checks() {
+                #(#checks)*
+                #send
+            }
+        };
+    })
+}
+
+const JSON_NOTE: &str = " that can be decoded from and encoded as JSON (with the `json` \
+                         feature, that's the pbjson generated serde impls)";
+
+/// The item type of an `impl Stream<Item = T>`, `None` for anything else.
+fn stream_item(ty: &Type) -> Option<Type> {
+    let Type::ImplTrait(impl_trait) = ty else {
+        return None;
+    };
+    impl_trait.bounds.iter().find_map(|bound| {
+        let TypeParamBound::Trait(bound) = bound else {
+            return None;
+        };
+        let segment = bound.path.segments.last()?;
+        if segment.ident != "Stream" {
+            return None;
+        }
+        let PathArguments::AngleBracketed(args) = &segment.arguments else {
+            return None;
+        };
+        args.args.iter().find_map(|arg| match arg {
+            GenericArgument::AssocType(assoc) if assoc.ident == "Item" => Some(assoc.ty.clone()),
+            _ => None,
+        })
+    })
+}
+
+/// The message a handler output responds with: `T` of a `Result<T, _>` or `RpcResult<T>`, the
+/// type itself otherwise. `None` for errors, which are responses for any message.
+fn response_message(ty: &Type) -> Option<Type> {
+    let Type::Path(path) = ty else {
+        return Some(ty.clone());
+    };
+    let segment = path.path.segments.last()?;
+    match segment.ident.to_string().as_str() {
+        "RpcError" | "RpcErrorCode" => None,
+        "Result" | "RpcResult" => match &segment.arguments {
+            PathArguments::AngleBracketed(args) => match args.args.first() {
+                Some(GenericArgument::Type(ty)) => Some(ty.clone()),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => Some(ty.clone()),
+    }
+}
+
+/// `T` of a `State<T>` parameter.
+fn state_type(ty: &Type) -> Option<Type> {
+    let Type::Path(path) = ty else {
+        return None;
+    };
+    let segment = path.path.segments.last()?;
+    if segment.ident != "State" {
+        return None;
+    }
+    match &segment.arguments {
+        PathArguments::AngleBracketed(args) => match args.args.first() {
+            Some(GenericArgument::Type(ty)) => Some(ty.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn ordinal(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// A type as written, without the spaces `quote` puts between tokens.
+fn tokens(ty: &Type) -> String {
+    ty.to_token_stream()
+        .to_string()
+        .replace(" < ", "<")
+        .replace("< ", "<")
+        .replace(" >", ">")
+        .replace(" , ", ", ")
+        .replace(" :: ", "::")
+        .replace(":: ", "::")
+        .replace("& ", "&")
+}
@@ -0,0 +1,9 @@
+//! The errors `rpc_debug_handler` gives for handlers that don't fit, pinned in `tests/ui/*.stderr`.
+//! Rerun with `TRYBUILD=overwrite` to record them after changing the diagnostics.
+
+#[test]
+fn ui() {
+    let t = trybuild::TestCases::new();
+    t.pass("tests/ui/pass_*.rs");
+    t.compile_fail("tests/ui/fail_*.rs");
+}
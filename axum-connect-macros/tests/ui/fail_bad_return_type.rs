@@ -0,0 +1,10 @@
+use axum_connect::rpc_debug_handler;
+
+include!("messages.rs");
+
+#[rpc_debug_handler(response = HelloResponse)]
+async fn say_hello(request: HelloRequest) -> String {
+    format!("Hello {}!", request.name)
+}
+
+fn main() {}
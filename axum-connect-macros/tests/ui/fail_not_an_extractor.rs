@@ -0,0 +1,15 @@
+use axum_connect::{prelude::*, rpc_debug_handler};
+
+include!("messages.rs");
+
+struct Db;
+
+#[rpc_debug_handler]
+async fn say_hello(db: Db, request: HelloRequest) -> RpcResult<HelloResponse> {
+    let Db = db;
+    Ok(HelloResponse {
+        message: format!("Hello {}!", request.name),
+    })
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use std::rc::Rc;
+
+use axum_connect::{prelude::*, rpc_debug_handler};
+
+include!("messages.rs");
+
+async fn lookup() {}
+
+#[rpc_debug_handler]
+async fn say_hello(request: HelloRequest) -> RpcResult<HelloResponse> {
+    let name = Rc::new(request.name);
+    lookup().await;
+    Ok(HelloResponse {
+        message: format!("Hello {}!", name),
+    })
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+// The messages of the UI tests, as generated code would define them.
+
+#[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HelloRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct HelloResponse {
+    #[prost(string, tag = "1")]
+    pub message: String,
+}
@@ -0,0 +1,33 @@
+use axum::extract::State;
+use axum_connect::{
+    futures::{stream, Stream},
+    prelude::*,
+    rpc_debug_handler,
+};
+
+include!("messages.rs");
+
+#[derive(Clone)]
+struct AppState {
+    greeting: String,
+}
+
+#[rpc_debug_handler]
+async fn say_hello(
+    State(state): State<AppState>,
+    RpcMetadata(metadata): RpcMetadata,
+    request: HelloRequest,
+) -> RpcResult<HelloResponse> {
+    Ok(HelloResponse {
+        message: format!("{} {}, {} headers!", state.greeting, request.name, metadata.len()),
+    })
+}
+
+#[rpc_debug_handler]
+async fn say_hello_stream(request: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+    stream::iter([Ok(HelloResponse {
+        message: format!("Hello {}!", request.name),
+    })])
+}
+
+fn main() {}
@@ -0,0 +1,79 @@
+//! Decoding requests through the router, with the `Content-Type` values clients send as they are
+//! and ones that need the slow path of the parser (parameters and uppercase letters).
+
+use std::hint::black_box;
+
+use axum::{
+    body::{self, Body},
+    http::{header, Request},
+    Router,
+};
+use axum_connect::{
+    prelude::*,
+    router::{RpcIdempotencyLevel, RpcMethod, RpcMethodInfo, RpcMethodKind},
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use prost::Message;
+use tower::ServiceExt;
+
+#[derive(Clone, PartialEq, Message, serde::Serialize, serde::Deserialize)]
+struct Blob {
+    #[prost(bytes = "vec", tag = "1")]
+    data: Vec<u8>,
+}
+
+const UNARY_PATH: &str = "/bench.BlobService/Put";
+
+fn router() -> Router {
+    let unary = RpcMethodInfo::from_rpc_path(
+        UNARY_PATH,
+        RpcMethodKind::Unary,
+        RpcIdempotencyLevel::IdempotencyUnknown,
+    );
+    RpcRouter::new()
+        .rpc_method(RpcMethod::unary(unary, |blob: Blob| async move {
+            RpcResult::Ok(blob)
+        }))
+        .into_router()
+}
+
+fn request(content_type: &str, body: Vec<u8>) -> Request<Body> {
+    Request::post(UNARY_PATH)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn call(router: Router, request: Request<Body>) -> usize {
+    let response = router.oneshot(request).await.unwrap();
+    body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .len()
+}
+
+fn decode(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let router = router();
+    let blob = Blob { data: vec![7; 64] }.encode_to_vec();
+    let mut group = c.benchmark_group("decode");
+    for (name, content_type) in [
+        ("exact", "application/proto"),
+        ("parameters", "application/proto; charset=utf-8"),
+        ("uppercase", "Application/Proto"),
+    ] {
+        group.bench_function(format!("unary_proto_{name}"), |b| {
+            b.iter_batched(
+                || request(content_type, blob.clone()),
+                |request| black_box(runtime.block_on(call(router.clone(), request))),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
@@ -0,0 +1,104 @@
+//! Encoding responses through the router, the path the buffer pool is for. With the
+//! `debug-metrics` feature the pool's counters are printed after the runs. Stream frames of
+//! 64 KiB and up are sent as separate prefix and payload chunks, the 128 KiB and 1 MiB runs.
+
+use std::hint::black_box;
+
+use axum::{
+    body::{self, Body},
+    http::{header, Request},
+    Router,
+};
+use axum_connect::{
+    prelude::*,
+    router::{RpcIdempotencyLevel, RpcMethod, RpcMethodInfo, RpcMethodKind},
+};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use futures::stream;
+use prost::Message;
+use tower::ServiceExt;
+
+#[derive(Clone, PartialEq, Message, serde::Serialize, serde::Deserialize)]
+struct Blob {
+    #[prost(bytes = "vec", tag = "1")]
+    data: Vec<u8>,
+}
+
+const UNARY_PATH: &str = "/bench.BlobService/Get";
+const STREAM_PATH: &str = "/bench.BlobService/Tail";
+const FRAMES: usize = 16;
+
+fn router(size: usize) -> Router {
+    let blob = Blob {
+        data: vec![7; size],
+    };
+    let unary = RpcMethodInfo::from_rpc_path(
+        UNARY_PATH,
+        RpcMethodKind::Unary,
+        RpcIdempotencyLevel::IdempotencyUnknown,
+    );
+    let streaming = RpcMethodInfo::from_rpc_path(
+        STREAM_PATH,
+        RpcMethodKind::ServerStreaming,
+        RpcIdempotencyLevel::IdempotencyUnknown,
+    );
+    let stream_blob = blob.clone();
+    RpcRouter::new()
+        .rpc_method(RpcMethod::unary(unary, move |_: Blob| {
+            let blob = blob.clone();
+            async move { RpcResult::Ok(blob) }
+        }))
+        .rpc_method(RpcMethod::server_streaming(streaming, move |_: Blob| {
+            let blob = stream_blob.clone();
+            async move { stream::iter((0..FRAMES).map(move |_| RpcResult::Ok(blob.clone()))) }
+        }))
+        .into_router()
+}
+
+fn request(path: &str, content_type: &str, body: Vec<u8>) -> Request<Body> {
+    Request::post(path)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+async fn call(router: Router, request: Request<Body>) -> usize {
+    let response = router.oneshot(request).await.unwrap();
+    body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .unwrap()
+        .len()
+}
+
+fn encode(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("encode");
+    for size in [256, 4096, 128 * 1024, 1024 * 1024] {
+        let router = router(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_function(format!("unary_proto_{size}"), |b| {
+            b.iter_batched(
+                || request(UNARY_PATH, "application/proto", vec![]),
+                |request| black_box(runtime.block_on(call(router.clone(), request))),
+                BatchSize::SmallInput,
+            )
+        });
+        group.throughput(Throughput::Bytes((size * FRAMES) as u64));
+        group.bench_function(format!("stream_proto_{size}"), |b| {
+            b.iter_batched(
+                || request(STREAM_PATH, "application/connect+proto", vec![0; 5]),
+                |request| black_box(runtime.block_on(call(router.clone(), request))),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+
+    #[cfg(feature = "debug-metrics")]
+    println!("{:?}", axum_connect::buffer_pool_stats());
+}
+
+criterion_group!(benches, encode);
+criterion_main!(benches);
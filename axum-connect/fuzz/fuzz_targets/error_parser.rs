@@ -0,0 +1,20 @@
+#![no_main]
+
+use axum_connect::codec::{encode_unary_error, parse_end_stream, parse_unary_error};
+use libfuzzer_sys::fuzz_target;
+
+// Parsing never panics, and whatever parses survives an encode/parse round trip.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(error) = parse_unary_error(data) {
+        let reparsed = parse_unary_error(&encode_unary_error(&error)).expect("round trip");
+        assert_eq!(reparsed.code, error.code);
+        assert_eq!(reparsed.message, error.message);
+        assert_eq!(reparsed.details.len(), error.details.len());
+    }
+
+    if let Ok(end) = parse_end_stream(data) {
+        let encoded = end.encode();
+        let reparsed = parse_end_stream(&encoded[5..]).expect("round trip");
+        assert_eq!(reparsed.metadata, end.metadata);
+    }
+});
@@ -0,0 +1,52 @@
+#![no_main]
+
+use axum_connect::codec::{decode_envelope, FrameDecoder};
+use axum_connect::prost::bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+// The first byte picks how the rest is chunked, frames must come out the same either way.
+fuzz_target!(|data: &[u8]| {
+    let Some((&chunk, data)) = data.split_first() else {
+        return;
+    };
+    let _ = decode_envelope(data);
+
+    let mut whole = FrameDecoder::new().max_frame_bytes(1 << 20);
+    whole.push(data);
+    let mut expected = vec![];
+    while let Ok(Some(frame)) = whole.next_frame() {
+        expected.push(frame);
+    }
+
+    let mut chunked = FrameDecoder::new().max_frame_bytes(1 << 20);
+    let mut frames = vec![];
+    for bytes in data.chunks(chunk.max(1) as usize) {
+        chunked.push(bytes);
+        while let Ok(Some(frame)) = chunked.next_frame() {
+            frames.push(frame);
+        }
+    }
+    assert_eq!(frames, expected);
+    let _ = chunked.finish();
+
+    // Passed through, the bytes must come out unchanged up to the same cut either way.
+    let passed_whole = pass(data, data.len().max(1));
+    let passed_chunked = pass(data, chunk.max(1) as usize);
+    assert_eq!(passed_chunked, passed_whole);
+    assert!(data.starts_with(&passed_whole));
+});
+
+fn pass(data: &[u8], chunk: usize) -> Vec<u8> {
+    let mut decoder = FrameDecoder::new().max_frame_bytes(1 << 20);
+    let mut passed = vec![];
+    for bytes in data.chunks(chunk) {
+        match decoder.pass(Bytes::copy_from_slice(bytes)) {
+            Ok(bytes) => passed.extend_from_slice(&bytes),
+            Err((bytes, _)) => {
+                passed.extend_from_slice(&bytes);
+                return passed;
+            }
+        }
+    }
+    passed
+}
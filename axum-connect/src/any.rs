@@ -0,0 +1,203 @@
+//! Packing messages into `google.protobuf.Any`s and back, see [`pack`] and [`unpack`].
+//!
+//! Generated messages implement `prost::Name`, which gives their type URL, eg.
+//! `type.googleapis.com/acme.user.v1.UserCreated`. The well-known types from `pbjson_types` don't.
+
+use prost::{Message, Name};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+use crate::error::{RpcError, RpcErrorCode};
+
+pub use pbjson_types::Any;
+
+const TYPE_KEY: &str = "@type";
+
+/// Wraps `message` in an `Any` with its type URL.
+pub fn pack<M: Message + Name>(message: &M) -> Any {
+    Any {
+        type_url: M::type_url(),
+        value: message.encode_to_vec().into(),
+    }
+}
+
+/// Decodes the `M` in `any`, failing with `InvalidArgument` if it holds another type or can't be
+/// decoded. Only the type name at the end of the URL is compared, so any domain matches.
+///
+/// ```
+/// # use axum_connect::any::{pack, unpack};
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct UserCreated { #[prost(string, tag = "1")] id: String }
+/// # impl prost::Name for UserCreated {
+/// #     const NAME: &'static str = "UserCreated";
+/// #     const PACKAGE: &'static str = "acme.user.v1";
+/// #     fn type_url() -> String { format!("type.googleapis.com/{}", Self::full_name()) }
+/// # }
+/// let any = pack(&UserCreated { id: "1".to_string() });
+/// assert_eq!(any.type_url, "type.googleapis.com/acme.user.v1.UserCreated");
+/// assert!(matches!(unpack::<UserCreated>(&any), Ok(created) if created.id == "1"));
+/// ```
+pub fn unpack<M: Message + Name + Default>(any: &Any) -> Result<M, RpcError> {
+    check_type::<M>(&any.type_url)?;
+    M::decode(any.value.as_ref()).map_err(|e| {
+        RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!("Invalid `{}` in Any: {}", M::full_name(), e),
+        )
+    })
+}
+
+/// Whether `any` holds an `M`.
+pub fn is<M: Name>(any: &Any) -> bool {
+    type_name(&any.type_url) == M::full_name()
+}
+
+/// The fully-qualified message name of a type URL, the part after the last `/`.
+pub fn type_name(type_url: &str) -> &str {
+    type_url.rsplit('/').next().unwrap_or(type_url)
+}
+
+/// The protobuf JSON form of the `M` in `any`: its fields with an `@type` key holding the type
+/// URL.
+///
+/// pbjson's own impls for `Any` serialize the raw `typeUrl` and base64 `value` instead, which
+/// other Connect clients don't understand.
+pub fn to_json<M>(any: &Any) -> Result<Value, RpcError>
+where
+    M: Message + Name + Default + Serialize,
+{
+    let message = unpack::<M>(any)?;
+    let mut object = match serde_json::to_value(&message) {
+        Ok(Value::Object(object)) => object,
+        _ => {
+            return Err(RpcError::new(
+                RpcErrorCode::Internal,
+                format!("`{}` didn't serialize to a JSON object", M::full_name()),
+            ))
+        }
+    };
+    object.insert(TYPE_KEY.to_string(), Value::String(any.type_url.clone()));
+    Ok(object.into())
+}
+
+/// Parses the protobuf JSON form of an `Any` holding an `M`, the reverse of [`to_json`]. Fails
+/// with `InvalidArgument` if `@type` is missing or names another type, or the fields don't parse.
+pub fn from_json<M>(json: &Value) -> Result<Any, RpcError>
+where
+    M: Message + Name + DeserializeOwned,
+{
+    let invalid = |message: String| RpcError::new(RpcErrorCode::InvalidArgument, message);
+
+    let Value::Object(object) = json else {
+        return Err(invalid("An Any must be a JSON object".to_string()));
+    };
+    let Some(Value::String(type_url)) = object.get(TYPE_KEY) else {
+        return Err(invalid(format!("An Any needs a `{}` string", TYPE_KEY)));
+    };
+    check_type::<M>(type_url)?;
+
+    let mut fields = object.clone();
+    fields.remove(TYPE_KEY);
+    let message = serde_json::from_value::<M>(fields.into())
+        .map_err(|e| invalid(format!("Invalid `{}` in Any: {}", M::full_name(), e)))?;
+    Ok(Any {
+        type_url: type_url.clone(),
+        value: message.encode_to_vec().into(),
+    })
+}
+
+fn check_type<M: Name>(type_url: &str) -> Result<(), RpcError> {
+    match type_name(type_url) == M::full_name() {
+        true => Ok(()),
+        false => Err(RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!("Expected a `{}` in Any, got `{}`", M::full_name(), type_url),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::test_util::{hello, HelloRequest, HelloResponse};
+
+    #[test]
+    fn packs_and_unpacks_messages() {
+        let any = pack(&hello("Ada"));
+
+        assert_eq!(any.type_url, "type.googleapis.com/hello.HelloRequest");
+        assert_eq!(any.value.as_ref(), hello("Ada").encode_to_vec());
+        assert!(is::<HelloRequest>(&any));
+        assert_eq!(unpack::<HelloRequest>(&any), Ok(hello("Ada")));
+    }
+
+    #[test]
+    fn unpacks_type_urls_of_any_domain() {
+        let any = Any {
+            type_url: "example.com/types/hello.HelloRequest".to_string(),
+            value: hello("Ada").encode_to_vec().into(),
+        };
+        assert_eq!(unpack::<HelloRequest>(&any), Ok(hello("Ada")));
+    }
+
+    #[test]
+    fn rejects_other_types_naming_both() {
+        let any = pack(&hello("Ada"));
+
+        assert!(!is::<HelloResponse>(&any));
+        assert_eq!(
+            unpack::<HelloResponse>(&any),
+            Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                "Expected a `hello.HelloResponse` in Any, got `type.googleapis.com/hello.HelloRequest`"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn rejects_undecodable_values() {
+        let any = Any {
+            type_url: HelloRequest::type_url(),
+            value: vec![0x0a, 0x05, b'A'].into(),
+        };
+        let error = unpack::<HelloRequest>(&any).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert!(
+            error
+                .message
+                .starts_with("Invalid `hello.HelloRequest` in Any: "),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn round_trips_the_json_form_with_an_at_type_key() {
+        let any = pack(&hello("Ada"));
+
+        let json = to_json::<HelloRequest>(&any).unwrap();
+        assert_eq!(
+            json,
+            json!({"@type": "type.googleapis.com/hello.HelloRequest", "name": "Ada"})
+        );
+        assert_eq!(from_json::<HelloRequest>(&json), Ok(any));
+    }
+
+    #[test]
+    fn rejects_json_without_the_right_at_type() {
+        let error = |json| from_json::<HelloRequest>(&json).unwrap_err().message;
+
+        assert_eq!(error(json!("Ada")), "An Any must be a JSON object");
+        assert_eq!(
+            error(json!({"name": "Ada"})),
+            "An Any needs a `@type` string"
+        );
+        assert_eq!(
+            error(json!({"@type": "type.googleapis.com/hello.HelloResponse", "name": "Ada"})),
+            "Expected a `hello.HelloRequest` in Any, got `type.googleapis.com/hello.HelloResponse`"
+        );
+    }
+}
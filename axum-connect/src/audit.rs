@@ -0,0 +1,353 @@
+//! Recording the requests and responses of selected RPCs to an audit store, see [`RpcAudit`].
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use axum::http::request;
+use serde_json::Value;
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::{
+    error::RpcError,
+    handler::RpcJsonEncode,
+    response::{RpcPayload, RpcResult},
+    router::{RouteOptions, RpcMethodInfo},
+};
+
+/// An audit store for the calls of the routes mounted with [`RouteOptions::audited`]. Requests and
+/// responses are recorded as the JSON the client would see with the JSON codec, whichever codec
+/// it used. Errors are those sent to the client, after
+/// [`redact_internal_errors`](crate::config::RpcConfig::redact_internal_errors), but with their
+/// [internal message](RpcError::with_internal_message).
+///
+/// ```
+/// # use axum_connect::{audit::RpcAudit, prelude::*, router::RpcMethodInfo};
+/// # use serde_json::Value;
+/// struct AuditLog;
+///
+/// impl RpcAudit for AuditLog {
+///     async fn record(
+///         &self,
+///         method: &RpcMethodInfo,
+///         request: Value,
+///         response: Result<Value, &RpcError>,
+///     ) {
+///         println!("{} {} -> {:?}", method.path, request, response);
+///     }
+/// }
+/// ```
+pub trait RpcAudit: Send + Sync + 'static {
+    fn record(
+        &self,
+        method: &RpcMethodInfo,
+        request: Value,
+        response: Result<Value, &RpcError>,
+    ) -> impl Future<Output = ()> + Send;
+}
+
+type RecordFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// [`RpcAudit`] made object safe, so the worker can be spawned without its type.
+trait DynAudit: Send + Sync {
+    fn record<'a>(
+        &'a self,
+        method: &'a RpcMethodInfo,
+        request: Value,
+        response: Result<Value, &'a RpcError>,
+    ) -> RecordFuture<'a>;
+}
+
+impl<A: RpcAudit> DynAudit for A {
+    fn record<'a>(
+        &'a self,
+        method: &'a RpcMethodInfo,
+        request: Value,
+        response: Result<Value, &'a RpcError>,
+    ) -> RecordFuture<'a> {
+        Box::pin(RpcAudit::record(self, method, request, response))
+    }
+}
+
+struct AuditRecord {
+    method: RpcMethodInfo,
+    request: Value,
+    response: Result<Value, RpcError>,
+}
+
+/// Records calls with an [`RpcAudit`] off the critical path: calls are queued, and a task of its
+/// own records them one at a time, so a slow store never holds up a response. When the queue is
+/// full, records are dropped rather than waited for; [`dropped`](RpcAuditor::dropped) counts
+/// them, and they're logged as `warn` level `tracing` events on the `axum_connect::audit` target.
+///
+/// Mount it on an [`RpcRouter`](crate::router::RpcRouter) with
+/// [`audit`](crate::router::RpcRouter::audit). Only unary RPCs with typed handlers are
+/// recorded. Clones share the queue.
+#[derive(Clone)]
+pub struct RpcAuditor {
+    sender: mpsc::Sender<AuditRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl RpcAuditor {
+    /// Starts the task recording with `audit`, which must be done within a Tokio runtime. It ends
+    /// once the `RpcAuditor` and its clones are gone, after recording what's queued.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is 0.
+    pub fn new(audit: impl RpcAudit, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<AuditRecord>(capacity);
+        let audit: Box<dyn DynAudit> = Box::new(audit);
+        tokio::spawn(async move {
+            while let Some(record) = receiver.recv().await {
+                match record.response {
+                    Ok(response) => {
+                        audit
+                            .record(&record.method, record.request, Ok(response))
+                            .await
+                    }
+                    Err(error) => {
+                        audit
+                            .record(&record.method, record.request, Err(&error))
+                            .await
+                    }
+                }
+            }
+        });
+
+        Self {
+            sender,
+            dropped: Default::default(),
+        }
+    }
+
+    /// The records dropped so far because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn submit(&self, record: AuditRecord) {
+        let path = record.method.path.clone();
+        if let Err(TrySendError::Full(_) | TrySendError::Closed(_)) = self.sender.try_send(record) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            // Every drop is counted, but only the 1st, 2nd, 4th, 8th... are logged.
+            if dropped.is_power_of_two() {
+                tracing::warn!(
+                    target: "axum_connect::audit",
+                    procedure = %path,
+                    dropped,
+                    "the audit queue is full, dropped the record of {}",
+                    path
+                );
+            }
+        }
+    }
+}
+
+impl fmt::Debug for RpcAuditor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcAuditor")
+            .field("dropped", &self.dropped())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The audit of a call to an audited route, from its request until its response.
+pub(crate) struct AuditCall {
+    auditor: RpcAuditor,
+    method: RpcMethodInfo,
+    request: Value,
+}
+
+impl AuditCall {
+    /// `None` unless the route is audited and the router has an [`RpcAuditor`].
+    pub fn start(parts: &request::Parts) -> Option<Self> {
+        if !parts
+            .extensions
+            .get::<RouteOptions>()
+            .is_some_and(|options| options.audited)
+        {
+            return None;
+        }
+        let auditor = parts.extensions.get::<RpcAuditor>()?.clone();
+        let method = parts.extensions.get::<RpcMethodInfo>()?.clone();
+        Some(Self {
+            auditor,
+            method,
+            request: Value::Null,
+        })
+    }
+
+    /// Records the request, before the handler takes it.
+    pub fn request<M: RpcJsonEncode>(&mut self, request: &M) {
+        self.request = to_value(request);
+    }
+
+    pub fn finish<M: RpcJsonEncode>(self, response: &RpcResult<RpcPayload<M>>) {
+        let response = match response {
+            Ok(payload) => Ok(payload.json_value()),
+            Err(error) => Err(error.clone()),
+        };
+        self.auditor.submit(AuditRecord {
+            method: self.method,
+            request: self.request,
+            response,
+        });
+    }
+}
+
+/// The JSON of `message`, `null` if it can't be encoded, eg. without the `json` feature.
+pub(crate) fn to_value<M: RpcJsonEncode>(message: &M) -> Value {
+    message
+        .rpc_json_encode()
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .unwrap_or(Value::Null)
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use serde_json::json;
+    use tokio::sync::{mpsc::UnboundedSender, Semaphore};
+
+    use super::*;
+    use crate::{
+        error::RpcErrorCode,
+        router::RpcRouter,
+        test_util::{
+            client, hello, proto_request, say_hello, unary, HelloRequest, HelloResponse, SAY_HELLO,
+        },
+    };
+
+    const FAIL: &str = "/hello.HelloWorldService/Fail";
+
+    type Recorded = (String, Value, Result<Value, RpcError>);
+
+    struct Recording {
+        records: UnboundedSender<Recorded>,
+        // Held back until the test adds permits, `None` to record right away.
+        gate: Option<Arc<Semaphore>>,
+    }
+
+    impl RpcAudit for Recording {
+        async fn record(
+            &self,
+            method: &RpcMethodInfo,
+            request: Value,
+            response: Result<Value, &RpcError>,
+        ) {
+            if let Some(gate) = &self.gate {
+                gate.acquire().await.unwrap().forget();
+            }
+            let record = (method.path.clone(), request, response.map_err(Clone::clone));
+            self.records.send(record).unwrap();
+        }
+    }
+
+    async fn fail(_: HelloRequest) -> RpcResult<HelloResponse> {
+        Err(
+            RpcError::new(RpcErrorCode::NotFound, "No such greeting".into())
+                .with_internal_message("greetings table is empty"),
+        )
+    }
+
+    fn audited_router(auditor: RpcAuditor) -> RpcRouter {
+        RpcRouter::new()
+            .rpc_with_options(RouteOptions::new().audited(), |router| {
+                router
+                    .rpc_method(unary(SAY_HELLO, say_hello))
+                    .rpc_method(unary(FAIL, fail))
+            })
+            .rpc_method(unary("/hello.HelloWorldService/Unaudited", say_hello))
+            .audit(auditor)
+    }
+
+    #[tokio::test]
+    async fn records_audited_calls_as_json_whatever_their_codec() {
+        let (records, mut recorded) = tokio::sync::mpsc::unbounded_channel();
+        let auditor = RpcAuditor::new(
+            Recording {
+                records,
+                gate: None,
+            },
+            8,
+        );
+        let client = client(audited_router(auditor));
+
+        client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        client.send(proto_request(FAIL, &hello("Bob"))).await;
+
+        let (path, request, response) = recorded.recv().await.unwrap();
+        assert_eq!(path, SAY_HELLO);
+        assert_eq!(request, json!({ "name": "Ada" }));
+        assert_eq!(response.unwrap(), json!({ "message": "Hello Ada!" }));
+
+        let (path, request, response) = recorded.recv().await.unwrap();
+        assert_eq!(path, FAIL);
+        assert_eq!(request, json!({ "name": "Bob" }));
+        let error = response.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+        assert_eq!(error.internal_message(), Some("greetings table is empty"));
+    }
+
+    #[tokio::test]
+    async fn skips_routes_that_are_not_audited() {
+        let (records, mut recorded) = tokio::sync::mpsc::unbounded_channel();
+        let auditor = RpcAuditor::new(
+            Recording {
+                records,
+                gate: None,
+            },
+            8,
+        );
+        let client = client(audited_router(auditor));
+
+        client
+            .send(proto_request(
+                "/hello.HelloWorldService/Unaudited",
+                &hello("Ada"),
+            ))
+            .await;
+        client.send(proto_request(SAY_HELLO, &hello("Bob"))).await;
+
+        let (path, request, _) = recorded.recv().await.unwrap();
+        assert_eq!(path, SAY_HELLO);
+        assert_eq!(request, json!({ "name": "Bob" }));
+    }
+
+    #[tokio::test]
+    async fn drops_records_once_the_queue_is_full_without_holding_up_calls() {
+        let (records, mut recorded) = tokio::sync::mpsc::unbounded_channel();
+        let gate = Arc::new(Semaphore::new(0));
+        let auditor = RpcAuditor::new(
+            Recording {
+                records,
+                gate: Some(gate.clone()),
+            },
+            1,
+        );
+        let client = client(audited_router(auditor.clone()));
+
+        // The first record is taken by the stalled worker and the second queued, so the other
+        // three are dropped, while every call is answered.
+        for name in ["a", "b", "c", "d", "e"] {
+            let response = client.send(proto_request(SAY_HELLO, &hello(name))).await;
+            assert!(response.error().is_none());
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(auditor.dropped(), 3);
+
+        gate.add_permits(2);
+        for name in ["a", "b"] {
+            let (_, request, _) = recorded.recv().await.unwrap();
+            assert_eq!(request, json!({ "name": name }));
+        }
+    }
+}
@@ -0,0 +1,313 @@
+//! Centralized per-RPC authorization, see [`RpcAuthorize`].
+
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use axum::http::request;
+
+use crate::{
+    error::RpcError,
+    router::{RouteOptions, RpcMethodInfo},
+};
+
+/// Who a request was authorized for, returned by [`RpcAuthorize`] and available to handlers
+/// through the [`Auth`](crate::parts::Auth) extractor.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AuthContext {
+    pub user_id: String,
+    pub scopes: Vec<String>,
+}
+
+impl AuthContext {
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+}
+
+/// Decides whether a request may call an RPC. Registered with
+/// [`RpcRouter::authorize`](crate::router::RpcRouter::authorize), it runs before any extractor of
+/// every route not mounted as [`RouteOptions::public`].
+///
+/// Return `Unauthenticated` if the caller couldn't be identified and `PermissionDenied` if they
+/// may not call `method`; the error is sent as-is.
+///
+/// ```
+/// # use axum::http::request;
+/// # use axum_connect::{auth::{AuthContext, RpcAuthorize}, prelude::*, router::RpcMethodInfo};
+/// struct ApiKeys;
+///
+/// impl RpcAuthorize for ApiKeys {
+///     async fn authorize(
+///         &self,
+///         method: &RpcMethodInfo,
+///         parts: &request::Parts,
+///     ) -> Result<AuthContext, RpcError> {
+///         match parts.headers.get("x-api-key") {
+///             Some(key) if key == "secret" => Ok(AuthContext::default()),
+///             Some(_) => Err(RpcError::new(
+///                 RpcErrorCode::PermissionDenied,
+///                 format!("Not allowed to call {}", method.path),
+///             )),
+///             None => Err(RpcError::new(
+///                 RpcErrorCode::Unauthenticated,
+///                 "Missing x-api-key".to_string(),
+///             )),
+///         }
+///     }
+/// }
+/// ```
+pub trait RpcAuthorize: Send + Sync + 'static {
+    fn authorize(
+        &self,
+        method: &RpcMethodInfo,
+        parts: &request::Parts,
+    ) -> impl Future<Output = Result<AuthContext, RpcError>> + Send;
+}
+
+type AuthorizeFuture<'a> = Pin<Box<dyn Future<Output = Result<AuthContext, RpcError>> + Send + 'a>>;
+
+/// [`RpcAuthorize`] made object safe, so it can be kept in an extension.
+trait DynAuthorize: Send + Sync {
+    fn authorize<'a>(
+        &'a self,
+        method: &'a RpcMethodInfo,
+        parts: &'a request::Parts,
+    ) -> AuthorizeFuture<'a>;
+}
+
+impl<A: RpcAuthorize> DynAuthorize for A {
+    fn authorize<'a>(
+        &'a self,
+        method: &'a RpcMethodInfo,
+        parts: &'a request::Parts,
+    ) -> AuthorizeFuture<'a> {
+        Box::pin(RpcAuthorize::authorize(self, method, parts))
+    }
+}
+
+/// The authorizer registered on a router, as a request extension.
+#[derive(Clone)]
+pub(crate) struct Authorizer(Arc<dyn DynAuthorize>);
+
+impl Authorizer {
+    pub fn new(authorizer: impl RpcAuthorize) -> Self {
+        Self(Arc::new(authorizer))
+    }
+}
+
+/// Runs the registered authorizer, if any, and leaves its [`AuthContext`] in the extensions.
+pub(crate) async fn authorize(parts: &mut request::Parts) -> Result<(), RpcError> {
+    let Some(Authorizer(authorizer)) = parts.extensions.get::<Authorizer>().cloned() else {
+        return Ok(());
+    };
+
+    if parts
+        .extensions
+        .get::<RouteOptions>()
+        .is_some_and(|options| options.public)
+    {
+        return Ok(());
+    }
+
+    let method = parts
+        .extensions
+        .get::<RpcMethodInfo>()
+        .cloned()
+        .unwrap_or_else(|| RpcMethodInfo::from_path(parts.uri.path()));
+
+    let context = authorizer.authorize(&method, parts).await?;
+    parts.extensions.insert(context);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        error::RpcErrorCode,
+        parts::Auth,
+        prelude::*,
+        test_util::{
+            client, hello, message, proto_request, say_hello, unary, HelloRequest, HelloResponse,
+            SAY_HELLO,
+        },
+    };
+
+    const WHOAMI: &str = "/hello.HelloWorldService/WhoAmI";
+    const LOGIN: &str = "/hello.AuthService/Login";
+
+    struct ApiKeys;
+
+    impl RpcAuthorize for ApiKeys {
+        async fn authorize(
+            &self,
+            method: &RpcMethodInfo,
+            parts: &request::Parts,
+        ) -> Result<AuthContext, RpcError> {
+            match parts.headers.get("x-api-key") {
+                Some(key) if key == "ada" => Ok(AuthContext {
+                    user_id: "ada".to_string(),
+                    scopes: vec!["users.read".to_string()],
+                }),
+                Some(_) => Err(RpcError::new(
+                    RpcErrorCode::PermissionDenied,
+                    format!("Not allowed to call {}", method.path),
+                )),
+                None => Err(RpcError::new(
+                    RpcErrorCode::Unauthenticated,
+                    "Missing x-api-key".to_string(),
+                )),
+            }
+        }
+    }
+
+    async fn whoami(Auth(auth): Auth, _: HelloRequest) -> RpcResult<HelloResponse> {
+        assert!(auth.has_scope("users.read"));
+        Ok(HelloResponse {
+            message: auth.user_id,
+        })
+    }
+
+    async fn call(router: RpcRouter, path: &str, key: Option<&'static str>) -> RpcResult<String> {
+        let mut request = proto_request(path, &hello("Ada"));
+        if let Some(key) = key {
+            request
+                .headers_mut()
+                .insert("x-api-key", HeaderValue::from_static(key));
+        }
+        let response = client(router).send(request).await;
+        match response.error() {
+            Some(error) => Err(error),
+            None => Ok(message::<HelloResponse>(&response).message),
+        }
+    }
+
+    fn code(result: RpcResult<String>) -> RpcErrorCode {
+        result.unwrap_err().code
+    }
+
+    fn authorized() -> RpcRouter {
+        RpcRouter::new()
+            .rpc_method(unary(WHOAMI, whoami))
+            .rpc_with_options(RouteOptions::public(), |router: RpcRouter| {
+                router.rpc_method(unary(LOGIN, say_hello))
+            })
+            .authorize(ApiKeys)
+    }
+
+    #[tokio::test]
+    async fn allows_authorized_calls() {
+        let response = call(authorized(), WHOAMI, Some("ada")).await;
+        assert_eq!(response.unwrap(), "ada");
+    }
+
+    #[tokio::test]
+    async fn denies_unauthorized_calls() {
+        let error = call(authorized(), WHOAMI, Some("mallory")).await;
+        assert_eq!(code(error), RpcErrorCode::PermissionDenied);
+        let error = call(authorized(), WHOAMI, None).await;
+        assert_eq!(code(error), RpcErrorCode::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn public_routes_skip_the_authorizer() {
+        let response = call(authorized(), LOGIN, None).await;
+        assert_eq!(response.unwrap(), "Hello Ada!");
+    }
+
+    #[tokio::test]
+    async fn covers_routes_mounted_after_it() {
+        let router = || {
+            RpcRouter::new()
+                .authorize(ApiKeys)
+                .rpc_method(unary(SAY_HELLO, say_hello))
+        };
+        let error = call(router(), SAY_HELLO, None).await;
+        assert_eq!(code(error), RpcErrorCode::Unauthenticated);
+        let response = call(router(), SAY_HELLO, Some("ada")).await;
+        assert_eq!(response.unwrap(), "Hello Ada!");
+    }
+
+    #[tokio::test]
+    async fn covers_merged_routes() {
+        let router = || {
+            RpcRouter::new()
+                .authorize(ApiKeys)
+                .merge(RpcRouter::new().rpc_method(unary(SAY_HELLO, say_hello)))
+        };
+        let error = call(router(), SAY_HELLO, None).await;
+        assert_eq!(code(error), RpcErrorCode::Unauthenticated);
+    }
+
+    #[tokio::test]
+    async fn merged_routers_keep_their_authorizer() {
+        struct DenyAll;
+
+        impl RpcAuthorize for DenyAll {
+            async fn authorize(
+                &self,
+                _: &RpcMethodInfo,
+                _: &request::Parts,
+            ) -> Result<AuthContext, RpcError> {
+                Err(RpcError::new(
+                    RpcErrorCode::PermissionDenied,
+                    "Denied".to_string(),
+                ))
+            }
+        }
+
+        let router = || {
+            let admin = RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, say_hello))
+                .authorize(DenyAll);
+            RpcRouter::new()
+                .rpc_method(unary(WHOAMI, whoami))
+                .merge(admin)
+                .authorize(ApiKeys)
+        };
+        let error = call(router(), SAY_HELLO, Some("ada")).await;
+        assert_eq!(code(error), RpcErrorCode::PermissionDenied);
+        let response = call(router(), WHOAMI, Some("ada")).await;
+        assert_eq!(response.unwrap(), "ada");
+    }
+
+    #[tokio::test]
+    async fn auth_fails_without_an_authorizer() {
+        let router = RpcRouter::new().rpc_method(unary(WHOAMI, whoami));
+        let error = call(router, WHOAMI, Some("ada")).await;
+        assert_eq!(code(error), RpcErrorCode::Unauthenticated);
+    }
+
+    #[test]
+    fn the_manifest_marks_what_requires_auth() {
+        let auth_required = |router: RpcRouter| {
+            let manifest: serde_json::Value =
+                serde_json::from_str(&router.manifest_json()).unwrap();
+            manifest["routes"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|route| {
+                    (
+                        route["path"].as_str().unwrap().to_string(),
+                        route["auth_required"].as_bool().unwrap(),
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let router = authorized().rpc_method(unary(SAY_HELLO, say_hello));
+        assert_eq!(
+            auth_required(router),
+            [
+                (LOGIN.to_string(), false),
+                (SAY_HELLO.to_string(), true),
+                (WHOAMI.to_string(), true),
+            ]
+        );
+
+        let router = RpcRouter::new().rpc_method(unary(SAY_HELLO, say_hello));
+        assert_eq!(auth_required(router), [(SAY_HELLO.to_string(), false)]);
+    }
+}
@@ -0,0 +1,298 @@
+//! Per-item errors of batch RPCs, see [`AggregatedError`].
+
+use std::collections::HashMap;
+
+use crate::{
+    error::{BadRequest, ErrorInfo, FieldViolation, RpcError, RpcErrorDetail},
+    response::RpcResult,
+};
+
+/// When [`AggregatedError::resolve`] fails the whole call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BatchPolicy {
+    /// Fail if any item failed.
+    #[default]
+    FailOnAny,
+    /// Fail only if every item failed, otherwise respond with the partial results.
+    FailOnAll,
+    /// Always respond with the partial results, even if every item failed.
+    Partial,
+}
+
+/// The results of a batch's items, keyed by their index in the request.
+///
+/// ```
+/// # use axum_connect::{batch::{AggregatedError, BatchPolicy}, prelude::*};
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct User {}
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct BatchCreateUsersResponse { #[prost(message, repeated, tag = "1")] users: Vec<User> }
+/// # async fn create_user(user: User) -> RpcResult<User> { Ok(user) }
+/// async fn batch_create_users(users: Vec<User>) -> RpcResult<BatchCreateUsersResponse> {
+///     let mut results = vec![];
+///     for user in users {
+///         results.push(create_user(user).await);
+///     }
+///
+///     AggregatedError::collect(results)
+///         .field_prefix("users")
+///         .resolve(BatchPolicy::FailOnAll, |created, _failed| BatchCreateUsersResponse {
+///             users: created.into_iter().map(|(_, user)| user).collect(),
+///         })
+/// }
+/// ```
+#[derive(Clone)]
+pub struct AggregatedError<T> {
+    succeeded: Vec<(usize, T)>,
+    failed: Vec<(usize, RpcError)>,
+    field_prefix: String,
+}
+
+impl<T> AggregatedError<T> {
+    /// Splits the results of a batch in request order into its successes and failures.
+    pub fn collect(results: impl IntoIterator<Item = Result<T, RpcError>>) -> Self {
+        let mut succeeded = vec![];
+        let mut failed = vec![];
+        for (index, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(value) => succeeded.push((index, value)),
+                Err(error) => failed.push((index, error)),
+            }
+        }
+
+        Self {
+            succeeded,
+            failed,
+            field_prefix: "requests".to_string(),
+        }
+    }
+
+    /// The request field holding the items, for the `BadRequest` violations of
+    /// [`into_error`](Self::into_error). Defaults to `requests`, failed items are `requests[3]`.
+    pub fn field_prefix(mut self, field_prefix: &str) -> Self {
+        self.field_prefix = field_prefix.to_string();
+        self
+    }
+
+    pub fn succeeded(&self) -> &[(usize, T)] {
+        &self.succeeded
+    }
+
+    pub fn failed(&self) -> &[(usize, RpcError)] {
+        &self.failed
+    }
+
+    /// Fails with [`into_error`](Self::into_error) if `policy` says so, otherwise responds with
+    /// what `partial` makes of the successes and failures.
+    pub fn resolve<R>(
+        self,
+        policy: BatchPolicy,
+        partial: impl FnOnce(Vec<(usize, T)>, Vec<(usize, RpcError)>) -> R,
+    ) -> RpcResult<R> {
+        let fail = match policy {
+            BatchPolicy::FailOnAny => !self.failed.is_empty(),
+            BatchPolicy::FailOnAll => !self.failed.is_empty() && self.succeeded.is_empty(),
+            BatchPolicy::Partial => false,
+        };
+
+        if fail {
+            return Err(self.into_error().expect("an item failed"));
+        }
+        Ok(partial(self.succeeded, self.failed))
+    }
+
+    /// A single error for the failed items, with the code and message of the first one. `None` if
+    /// no item failed.
+    ///
+    /// Its details hold a `google.rpc.BadRequest` with a violation per failed item (`requests[3]`
+    /// and its message), and a `google.rpc.ErrorInfo` per failed item whose reason is the item's
+    /// application code, or else its Connect code (eg. `NOT_FOUND`), with `index` and `code`
+    /// metadata.
+    pub fn into_error(self) -> Option<RpcError> {
+        let total = self.succeeded.len() + self.failed.len();
+        let (first_index, first) = self.failed.first()?;
+
+        let mut error = RpcError::new(
+            first.code.clone(),
+            format!(
+                "{} of {} items failed, the first at index {}: {}",
+                self.failed.len(),
+                total,
+                first_index,
+                first.message
+            ),
+        );
+
+        let bad_request = BadRequest {
+            field_violations: self
+                .failed
+                .iter()
+                .map(|(index, item)| FieldViolation {
+                    field: format!("{}[{}]", self.field_prefix, index),
+                    description: item.message.clone(),
+                })
+                .collect(),
+        };
+        error
+            .details
+            .push(RpcErrorDetail::new("google.rpc.BadRequest", &bad_request));
+
+        for (index, item) in &self.failed {
+            let info = ErrorInfo {
+                reason: item
+                    .app_code()
+                    .unwrap_or_else(|| item.code.to_string().to_uppercase()),
+                domain: String::new(),
+                metadata: HashMap::from([
+                    ("index".to_string(), index.to_string()),
+                    ("code".to_string(), item.code.to_string()),
+                ]),
+            };
+            error
+                .details
+                .push(RpcErrorDetail::new("google.rpc.ErrorInfo", &info));
+        }
+
+        Some(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::RpcErrorCode;
+
+    fn taken(index: usize) -> RpcError {
+        RpcError::new(
+            RpcErrorCode::AlreadyExists,
+            format!("user{} is taken", index),
+        )
+    }
+
+    /// The results of creating users `0..items`, failing those in `failing`.
+    fn batch(items: usize, failing: &[usize]) -> AggregatedError<String> {
+        AggregatedError::collect((0..items).map(|index| match failing.contains(&index) {
+            true => Err(taken(index)),
+            false => Ok(format!("user{}", index)),
+        }))
+        .field_prefix("users")
+    }
+
+    /// The created users and failed indexes, as a batch response would list them.
+    fn resolve(batch: AggregatedError<String>, policy: BatchPolicy) -> RpcResult<String> {
+        batch.resolve(policy, |created, failed| {
+            let created = created
+                .into_iter()
+                .map(|(_, user)| user)
+                .collect::<Vec<_>>();
+            let failed = failed.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+            format!("created {:?}, failed {:?}", created, failed)
+        })
+    }
+
+    #[test]
+    fn responds_when_every_item_succeeds() {
+        for policy in [
+            BatchPolicy::FailOnAny,
+            BatchPolicy::FailOnAll,
+            BatchPolicy::Partial,
+        ] {
+            assert_eq!(
+                resolve(batch(2, &[]), policy),
+                Ok(r#"created ["user0", "user1"], failed []"#.to_string())
+            );
+        }
+        assert!(batch(2, &[]).into_error().is_none());
+    }
+
+    #[test]
+    fn fails_or_responds_by_policy_when_some_items_fail() {
+        let error = resolve(batch(3, &[1]), BatchPolicy::FailOnAny).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::AlreadyExists);
+        assert_eq!(
+            error.message,
+            "1 of 3 items failed, the first at index 1: user1 is taken"
+        );
+
+        let partial = Ok(r#"created ["user0", "user2"], failed [1]"#.to_string());
+        assert_eq!(resolve(batch(3, &[1]), BatchPolicy::FailOnAll), partial);
+        assert_eq!(resolve(batch(3, &[1]), BatchPolicy::Partial), partial);
+    }
+
+    #[test]
+    fn fails_by_policy_when_every_item_fails() {
+        for policy in [BatchPolicy::FailOnAny, BatchPolicy::FailOnAll] {
+            let error = resolve(batch(2, &[0, 1]), policy).unwrap_err();
+            assert_eq!(
+                error.message,
+                "2 of 2 items failed, the first at index 0: user0 is taken"
+            );
+        }
+        assert_eq!(
+            resolve(batch(2, &[0, 1]), BatchPolicy::Partial),
+            Ok("created [], failed [0, 1]".to_string())
+        );
+    }
+
+    #[test]
+    fn details_a_violation_and_an_error_info_per_failed_item() {
+        let mut items = (0..3)
+            .map(|index| Ok(format!("user{}", index)))
+            .collect::<Vec<_>>();
+        items[0] = Err(taken(0));
+        items[2] = Err(
+            RpcError::new(RpcErrorCode::ResourceExhausted, "Too many users".into())
+                .with_app_code("USER_QUOTA"),
+        );
+        let error = AggregatedError::collect(items)
+            .field_prefix("users")
+            .into_error()
+            .unwrap();
+
+        let json = serde_json::to_value(&error).unwrap();
+        let types = json["details"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|detail| detail["type"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                "google.rpc.BadRequest",
+                "google.rpc.ErrorInfo",
+                "google.rpc.ErrorInfo"
+            ]
+        );
+        assert_eq!(
+            json["details"][0]["value"],
+            "ChoKCHVzZXJzWzBdEg51c2VyMCBpcyB0YWtlbgoaCgh1c2Vyc1syXRIOVG9vIG1hbnkgdXNlcnM"
+        );
+
+        let bad_request = error.details[0].decode::<BadRequest>().unwrap();
+        assert_eq!(
+            bad_request.field_violations,
+            [
+                FieldViolation {
+                    field: "users[0]".to_string(),
+                    description: "user0 is taken".to_string(),
+                },
+                FieldViolation {
+                    field: "users[2]".to_string(),
+                    description: "Too many users".to_string(),
+                },
+            ]
+        );
+        // The metadata is a map, so its encoding has no set order.
+        let infos = error.details[1..]
+            .iter()
+            .map(|detail| detail.decode::<ErrorInfo>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(infos[0].reason, "ALREADY_EXISTS");
+        assert_eq!(infos[0].metadata["index"], "0");
+        assert_eq!(infos[0].metadata["code"], "already_exists");
+        assert_eq!(infos[1].reason, "USER_QUOTA");
+        assert_eq!(infos[1].metadata["index"], "2");
+        assert_eq!(infos[1].metadata["code"], "resource_exhausted");
+    }
+}
@@ -0,0 +1,724 @@
+//! Failing fast while a route's calls keep failing, eg. because a dependency is down, see
+//! [`CircuitBreaker`].
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+
+use crate::{
+    error::{RpcError, RpcErrorCode},
+    logging::RpcCallStats,
+    ratelimit::retry_later,
+    router::{RouteOptions, RpcMethodInfo},
+};
+
+/// How many buckets the sliding window is counted in.
+const BUCKETS: u32 = 10;
+
+/// A circuit breaker for the routes of [`RouteOptions::circuit_breaker`], each with a circuit of
+/// its own (the POST and GET routes of a method share one).
+///
+/// A circuit starts closed, counting the results of the last [`window`](Self::window) of calls.
+/// Once at least [`min_calls`](Self::min_calls) of them are in and the share that failed reaches
+/// [`failure_rate`](Self::failure_rate), it opens: calls fail with `Unavailable` without reaching
+/// the handler, with a `google.rpc.RetryInfo` detail and a `Retry-After` header saying when to
+/// try again. After [`open_for`](Self::open_for) it's half-open and lets
+/// [`probes`](Self::probes) calls through; if they all succeed it closes again, if one fails it
+/// opens for another `open_for`.
+///
+/// Calls fail when they end with one of the [`failure_codes`](Self::failure_codes), streams when
+/// they end. Other errors, eg. `InvalidArgument`, mean the handler is working, they count as
+/// successes. State changes are logged as `warn` level `tracing` events on the
+/// `axum_connect::breaker` target.
+///
+/// Clones share the circuits, so a clone kept aside is the admin handle: [`state`](Self::state)
+/// and [`circuits`](Self::circuits) for dashboards and metrics (see
+/// `PrometheusMetrics::circuit_breaker`), [`reset`](Self::reset) to close a circuit by hand.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use axum_connect::{breaker::CircuitBreaker, prelude::*, router::RouteOptions};
+/// # fn routes(app: RpcRouter) -> RpcRouter {
+/// let breaker = CircuitBreaker::new()
+///     .failure_rate(0.5)
+///     .open_for(Duration::from_secs(5));
+/// app.rpc_with_options(RouteOptions::new().circuit_breaker(breaker.clone()), |r| r)
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    window: Duration,
+    min_calls: u64,
+    failure_rate: f64,
+    open_for: Duration,
+    probes: u32,
+    failure_codes: Vec<RpcErrorCode>,
+    clock: Arc<dyn Fn() -> Instant + Send + Sync>,
+    /// By RPC path.
+    circuits: Arc<Mutex<BTreeMap<String, Circuit>>>,
+}
+
+/// The state of a route's circuit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Calls go through.
+    Closed,
+    /// Calls fail fast with `Unavailable`.
+    Open,
+    /// Probe calls go through to find out if the route has recovered.
+    HalfOpen,
+}
+
+impl CircuitState {
+    /// The name in logs and metrics, eg. `half_open`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::Open => "open",
+            CircuitState::HalfOpen => "half_open",
+        }
+    }
+}
+
+impl fmt::Display for CircuitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A route's circuit as seen by [`CircuitBreaker::circuits`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CircuitStatus {
+    pub path: String,
+    pub service: String,
+    pub method: String,
+    pub state: CircuitState,
+    /// Calls in the window, and how many of them failed.
+    pub calls: u64,
+    pub failures: u64,
+    /// Calls failed fast since the breaker was created.
+    pub rejected: u64,
+}
+
+struct Circuit {
+    info: RpcMethodInfo,
+    state: State,
+    /// Oldest first.
+    buckets: VecDeque<Bucket>,
+    rejected: u64,
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { in_flight: u32, succeeded: u32 },
+}
+
+struct Bucket {
+    start: Instant,
+    calls: u64,
+    failures: u64,
+}
+
+impl Circuit {
+    fn new(info: RpcMethodInfo) -> Self {
+        Self {
+            info,
+            state: State::Closed,
+            buckets: VecDeque::new(),
+            rejected: 0,
+        }
+    }
+
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.state {
+            State::Closed => CircuitState::Closed,
+            State::Open { until } if now < until => CircuitState::Open,
+            State::Open { .. } | State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    fn calls(&self) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .fold((0, 0), |(calls, failures), bucket| {
+                (calls + bucket.calls, failures + bucket.failures)
+            })
+    }
+}
+
+impl CircuitBreaker {
+    /// Opens at a failure rate of 50% over the last 30 seconds, of at least 20 calls, for 10
+    /// seconds, then closes after 3 successful probes.
+    pub fn new() -> Self {
+        Self {
+            window: Duration::from_secs(30),
+            min_calls: 20,
+            failure_rate: 0.5,
+            open_for: Duration::from_secs(10),
+            probes: 3,
+            failure_codes: vec![
+                RpcErrorCode::Unavailable,
+                RpcErrorCode::DeadlineExceeded,
+                RpcErrorCode::Internal,
+                RpcErrorCode::Unknown,
+            ],
+            clock: Arc::new(Instant::now),
+            circuits: Default::default(),
+        }
+    }
+
+    /// How far back the calls a closed circuit counts go. They're counted in 10 buckets, so
+    /// results leave the window a tenth of it at a time.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window.max(Duration::from_millis(BUCKETS.into()));
+        self
+    }
+
+    /// How many calls the window needs before the circuit can open, so a couple of failures
+    /// on a quiet route don't open it.
+    pub fn min_calls(mut self, min_calls: u64) -> Self {
+        self.min_calls = min_calls.max(1);
+        self
+    }
+
+    /// The share of failed calls in the window that opens the circuit, from 0 to 1.
+    pub fn failure_rate(mut self, failure_rate: f64) -> Self {
+        self.failure_rate = failure_rate.clamp(0.0, 1.0);
+        self
+    }
+
+    /// How long an open circuit fails calls before letting probes through.
+    pub fn open_for(mut self, open_for: Duration) -> Self {
+        self.open_for = open_for;
+        self
+    }
+
+    /// How many probe calls of a half-open circuit must succeed for it to close. At most that
+    /// many are in flight at once, the calls beyond them fail fast.
+    pub fn probes(mut self, probes: u32) -> Self {
+        self.probes = probes.max(1);
+        self
+    }
+
+    /// The codes of the calls that count as failures, `Unavailable`, `DeadlineExceeded`,
+    /// `Internal` and `Unknown` by default.
+    pub fn failure_codes(mut self, failure_codes: impl IntoIterator<Item = RpcErrorCode>) -> Self {
+        self.failure_codes = failure_codes.into_iter().collect();
+        self
+    }
+
+    /// Where the breaker reads the time, [`Instant::now`] by default, eg. a clock a test
+    /// advances by hand.
+    pub fn clock(mut self, clock: impl Fn() -> Instant + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// The state of the circuit of the RPC at `path`, `None` if it hasn't been called yet.
+    pub fn state(&self, path: &str) -> Option<CircuitState> {
+        let now = (self.clock)();
+        let circuits = self.circuits.lock().unwrap();
+        circuits.get(path).map(|circuit| circuit.state(now))
+    }
+
+    /// Every circuit, by path.
+    pub fn circuits(&self) -> Vec<CircuitStatus> {
+        let now = (self.clock)();
+        let mut circuits = self.circuits.lock().unwrap();
+        circuits
+            .iter_mut()
+            .map(|(path, circuit)| {
+                self.expire(circuit, now);
+                let (calls, failures) = circuit.calls();
+                CircuitStatus {
+                    path: path.clone(),
+                    service: circuit.info.service.clone(),
+                    method: circuit.info.method.clone(),
+                    state: circuit.state(now),
+                    calls,
+                    failures,
+                    rejected: circuit.rejected,
+                }
+            })
+            .collect()
+    }
+
+    /// Closes the circuit of the RPC at `path` and forgets its calls, eg. once the dependency is
+    /// known to be back. Probes in flight are let finish without counting.
+    pub fn reset(&self, path: &str) {
+        if let Some(circuit) = self.circuits.lock().unwrap().get_mut(path) {
+            self.close(circuit, "reset");
+        }
+    }
+
+    /// [`reset`](Self::reset)s every circuit.
+    pub fn reset_all(&self) {
+        for circuit in self.circuits.lock().unwrap().values_mut() {
+            self.close(circuit, "reset");
+        }
+    }
+
+    /// Lets the call through, or says how long until the circuit lets it.
+    fn admit(&self, info: &RpcMethodInfo) -> Result<Call, Duration> {
+        let now = (self.clock)();
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(info.path.clone())
+            .or_insert_with(|| Circuit::new(info.clone()));
+
+        if let State::Open { until } = circuit.state {
+            if now < until {
+                circuit.rejected += 1;
+                return Err(until - now);
+            }
+            self.transition(circuit, CircuitState::HalfOpen, "open_for elapsed");
+            circuit.state = State::HalfOpen {
+                in_flight: 0,
+                succeeded: 0,
+            };
+        }
+
+        let probe = match &mut circuit.state {
+            State::HalfOpen {
+                in_flight,
+                succeeded,
+            } => {
+                if *in_flight + *succeeded >= self.probes {
+                    circuit.rejected += 1;
+                    return Err(self.open_for);
+                }
+                *in_flight += 1;
+                true
+            }
+            _ => false,
+        };
+        Ok(Call {
+            breaker: self.clone(),
+            path: info.path.clone(),
+            probe,
+            stats: None,
+            ended: false,
+        })
+    }
+
+    /// Counts the result of a call let through.
+    fn record(&self, path: &str, probe: bool, failed: bool) {
+        let now = (self.clock)();
+        let mut circuits = self.circuits.lock().unwrap();
+        let Some(circuit) = circuits.get_mut(path) else {
+            return;
+        };
+
+        match circuit.state {
+            State::HalfOpen {
+                in_flight,
+                succeeded,
+            } if probe => {
+                if failed {
+                    self.open(circuit, now, "a probe failed");
+                } else if succeeded + 1 >= self.probes {
+                    self.close(circuit, "the probes succeeded");
+                } else {
+                    circuit.state = State::HalfOpen {
+                        in_flight: in_flight - 1,
+                        succeeded: succeeded + 1,
+                    };
+                }
+            }
+            // Calls let through before the circuit opened, or before a reset.
+            State::Open { .. } | State::HalfOpen { .. } => {}
+            State::Closed => {
+                self.expire(circuit, now);
+                let bucket_length = self.window / BUCKETS;
+                match circuit.buckets.back_mut() {
+                    Some(bucket) if now < bucket.start + bucket_length => {
+                        bucket.calls += 1;
+                        bucket.failures += u64::from(failed);
+                    }
+                    _ => circuit.buckets.push_back(Bucket {
+                        start: now,
+                        calls: 1,
+                        failures: u64::from(failed),
+                    }),
+                }
+
+                let (calls, failures) = circuit.calls();
+                if failed
+                    && calls >= self.min_calls
+                    && failures as f64 >= self.failure_rate * calls as f64
+                {
+                    self.open(circuit, now, "the failure rate was reached");
+                }
+            }
+        }
+    }
+
+    /// Gives up the probe slot of a call that ended without a result, eg. dropped when the client
+    /// went away.
+    fn abandon(&self, path: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(State::HalfOpen { in_flight, .. }) =
+            circuits.get_mut(path).map(|circuit| &mut circuit.state)
+        {
+            *in_flight = in_flight.saturating_sub(1);
+        }
+    }
+
+    /// Drops the buckets that have left the window.
+    fn expire(&self, circuit: &mut Circuit, now: Instant) {
+        while circuit
+            .buckets
+            .front()
+            .is_some_and(|bucket| bucket.start + self.window <= now)
+        {
+            circuit.buckets.pop_front();
+        }
+    }
+
+    fn open(&self, circuit: &mut Circuit, now: Instant, reason: &str) {
+        self.transition(circuit, CircuitState::Open, reason);
+        circuit.state = State::Open {
+            until: now + self.open_for,
+        };
+        circuit.buckets.clear();
+    }
+
+    fn close(&self, circuit: &mut Circuit, reason: &str) {
+        if !matches!(circuit.state, State::Closed) {
+            self.transition(circuit, CircuitState::Closed, reason);
+        }
+        circuit.state = State::Closed;
+        circuit.buckets.clear();
+    }
+
+    fn transition(&self, circuit: &Circuit, to: CircuitState, reason: &str) {
+        let (calls, failures) = circuit.calls();
+        tracing::warn!(
+            target: "axum_connect::breaker",
+            procedure = circuit.info.path,
+            state = to.as_str(),
+            calls,
+            failures,
+            "circuit breaker {}: {}",
+            to,
+            reason
+        );
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("window", &self.window)
+            .field("min_calls", &self.min_calls)
+            .field("failure_rate", &self.failure_rate)
+            .field("open_for", &self.open_for)
+            .field("probes", &self.probes)
+            .field("failure_codes", &self.failure_codes)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Breakers are only equal to their own clones, which share the circuits.
+impl PartialEq for CircuitBreaker {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.circuits, &other.circuits)
+    }
+}
+
+impl Eq for CircuitBreaker {}
+
+/// A call let through, counted once its response has been sent. Calls without a result, dropped
+/// before the handler answered or before their stream ended, aren't counted.
+struct Call {
+    breaker: CircuitBreaker,
+    path: String,
+    probe: bool,
+    stats: Option<Arc<RpcCallStats>>,
+    ended: bool,
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let code = self.stats.as_ref().and_then(|stats| stats.code());
+        if self.stats.is_some() && (self.ended || code.is_some()) {
+            let failed = code.is_some_and(|code| self.breaker.failure_codes.contains(&code));
+            self.breaker.record(&self.path, self.probe, failed);
+        } else if self.probe {
+            self.breaker.abandon(&self.path);
+        }
+    }
+}
+
+/// The `route_layers` middleware applying the route's [`CircuitBreaker`], if it has one.
+pub(crate) async fn circuit_breaker(request: Request, next: Next) -> Response {
+    let Some(breaker) = request
+        .extensions()
+        .get::<RouteOptions>()
+        .and_then(|options| options.circuit_breaker.clone())
+    else {
+        return next.run(request).await;
+    };
+
+    let info = request
+        .extensions()
+        .get::<RpcMethodInfo>()
+        .cloned()
+        .unwrap_or_else(|| RpcMethodInfo::from_path(request.uri().path()));
+    let mut call = match breaker.admit(&info) {
+        Ok(call) => call,
+        Err(retry_after) => {
+            let (mut parts, _) = request.into_parts();
+            let error = RpcError::new(
+                RpcErrorCode::Unavailable,
+                format!(
+                    "{} is failing, calls are rejected until it recovers",
+                    info.path
+                ),
+            );
+            return retry_later(&mut parts, error, retry_after);
+        }
+    };
+
+    let response = next.run(request).await;
+    call.stats = response.extensions().get::<Arc<RpcCallStats>>().cloned();
+    // Streams are counted once they end, the error can come at the end of the body.
+    if call.stats.as_ref().is_some_and(|stats| stats.streaming()) {
+        return response.map(|inner| Body::new(CallBody { inner, call }));
+    }
+    call.ended = true;
+    response
+}
+
+/// A response stream, its [`Call`] counted when it's dropped after the end.
+struct CallBody {
+    inner: Body,
+    call: Call,
+}
+
+impl http_body::Body for CallBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let frame = Pin::new(&mut self.inner).poll_frame(cx);
+        if matches!(frame, Poll::Ready(None)) || self.inner.is_end_stream() {
+            self.call.ended = true;
+        }
+        frame
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::http::header;
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        test_util::{client, hello, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO},
+        testing::{TestClient, TestResponse},
+    };
+
+    /// An RPC failing with `failure` while it's set, counting the calls reaching it.
+    #[derive(Clone, Default)]
+    struct Backend {
+        failure: Arc<Mutex<Option<RpcErrorCode>>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl Backend {
+        fn fail_with(&self, code: Option<RpcErrorCode>) {
+            *self.failure.lock().unwrap() = code;
+        }
+
+        fn calls(&self) -> usize {
+            self.calls.load(Ordering::SeqCst)
+        }
+    }
+
+    /// Opens after 2 calls of which half failed, for 10 seconds, on tokio's paused clock.
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new()
+            .min_calls(2)
+            .failure_rate(0.5)
+            .open_for(Duration::from_secs(10))
+            .probes(1)
+            .clock(|| tokio::time::Instant::now().into_std())
+    }
+
+    fn serve(breaker: &CircuitBreaker, backend: &Backend) -> TestClient {
+        let backend = backend.clone();
+        let handler = move |_: HelloRequest| {
+            let backend = backend.clone();
+            async move {
+                backend.calls.fetch_add(1, Ordering::SeqCst);
+                let failure = backend.failure.lock().unwrap().clone();
+                match failure {
+                    Some(code) => Err(RpcError::new(code, "the database is down".to_string())),
+                    None => Ok(HelloResponse {
+                        message: "Hello!".to_string(),
+                    }),
+                }
+            }
+        };
+        let options = RouteOptions::new().circuit_breaker(breaker.clone());
+        client(RpcRouter::new().rpc_with_options(options, |router| {
+            router.rpc_method(unary(SAY_HELLO, handler))
+        }))
+    }
+
+    async fn call(client: &TestClient) -> TestResponse {
+        client.send(proto_request(SAY_HELLO, &hello("Ada"))).await
+    }
+
+    fn code(response: &TestResponse) -> Option<RpcErrorCode> {
+        response.error().map(|error| error.code)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn opens_then_half_opens_then_closes_on_a_successful_probe() {
+        let (breaker, backend) = (breaker(), Backend::default());
+        let client = serve(&breaker, &backend);
+
+        assert_eq!(code(&call(&client).await), None);
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Closed));
+
+        backend.fail_with(Some(RpcErrorCode::Unavailable));
+        assert_eq!(code(&call(&client).await), Some(RpcErrorCode::Unavailable));
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Open));
+        assert_eq!(backend.calls(), 2);
+
+        // Failed fast, without reaching the handler.
+        tokio::time::advance(Duration::from_secs(4)).await;
+        let response = call(&client).await;
+        assert_eq!(code(&response), Some(RpcErrorCode::Unavailable));
+        assert_eq!(response.headers[header::RETRY_AFTER], "6");
+        let error = response.error().unwrap();
+        assert_eq!(error.details[0].proto_type, "google.rpc.RetryInfo");
+        assert_eq!(backend.calls(), 2);
+
+        tokio::time::advance(Duration::from_secs(6)).await;
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::HalfOpen));
+
+        backend.fail_with(None);
+        assert_eq!(code(&call(&client).await), None);
+        assert_eq!(backend.calls(), 3);
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Closed));
+        assert_eq!(code(&call(&client).await), None);
+
+        let status = &breaker.circuits()[0];
+        assert_eq!(
+            (status.state, status.calls, status.failures, status.rejected),
+            (CircuitState::Closed, 1, 0, 1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reopens_when_the_probe_fails() {
+        let (breaker, backend) = (breaker(), Backend::default());
+        let client = serve(&breaker, &backend);
+
+        backend.fail_with(Some(RpcErrorCode::Internal));
+        call(&client).await;
+        call(&client).await;
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Open));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert_eq!(code(&call(&client).await), Some(RpcErrorCode::Internal));
+        assert_eq!(backend.calls(), 3);
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Open));
+
+        // Open for another 10 seconds from the failed probe.
+        tokio::time::advance(Duration::from_secs(9)).await;
+        let response = call(&client).await;
+        assert_eq!(code(&response), Some(RpcErrorCode::Unavailable));
+        assert_eq!(response.headers[header::RETRY_AFTER], "1");
+        assert_eq!(backend.calls(), 3);
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+        backend.fail_with(None);
+        assert_eq!(code(&call(&client).await), None);
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Closed));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_counts_the_failure_codes() {
+        let (breaker, backend) = (breaker(), Backend::default());
+        let client = serve(&breaker, &backend);
+
+        for failure in [
+            RpcErrorCode::InvalidArgument,
+            RpcErrorCode::NotFound,
+            RpcErrorCode::PermissionDenied,
+            RpcErrorCode::ResourceExhausted,
+        ] {
+            backend.fail_with(Some(failure.clone()));
+            for _ in 0..3 {
+                assert_eq!(code(&call(&client).await), Some(failure.clone()));
+            }
+        }
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Closed));
+        assert_eq!(breaker.circuits()[0].failures, 0);
+
+        // 12 successes in the window, so 12 more failures open it.
+        backend.fail_with(Some(RpcErrorCode::DeadlineExceeded));
+        for _ in 0..11 {
+            call(&client).await;
+        }
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Closed));
+        call(&client).await;
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Open));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_the_configured_failure_codes() {
+        let breaker = breaker().failure_codes([RpcErrorCode::ResourceExhausted]);
+        let backend = Backend::default();
+        let client = serve(&breaker, &backend);
+
+        backend.fail_with(Some(RpcErrorCode::Unavailable));
+        call(&client).await;
+        call(&client).await;
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Closed));
+
+        backend.fail_with(Some(RpcErrorCode::ResourceExhausted));
+        call(&client).await;
+        call(&client).await;
+        assert_eq!(breaker.state(SAY_HELLO), Some(CircuitState::Open));
+    }
+}
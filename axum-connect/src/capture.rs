@@ -0,0 +1,290 @@
+//! Capturing example calls of every RPC for documentation, see [`RpcCapture`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, io,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use axum::http::request;
+use prost::Message;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    audit::to_value,
+    handler::RpcJsonEncode,
+    hooks::ResponseHooks,
+    response::{RpcPayload, RpcResult},
+};
+
+/// A request and the response it got, as the JSON a client would see with the JSON codec.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CapturedExample {
+    pub request: Value,
+    pub response: Value,
+}
+
+/// Keeps the first successful calls of every RPC, request and response, as examples for
+/// documentation, eg. from a staging deployment or an end-to-end test run. Meant for development,
+/// not production traffic.
+///
+/// Mount it on an [`RpcRouter`](crate::router::RpcRouter) with
+/// [`capture`](crate::router::RpcRouter::capture). Responses are captured as they're sent, after
+/// the [`ResponseHooks`]; the request gets the hooks registered for its type too, on the captured
+/// copy only, so the same hooks that scrub sensitive fields from responses scrub them from the
+/// examples. Only unary RPCs with typed handlers are captured.
+///
+/// The store is bounded: past `per_method` examples of an RPC, its calls only read an atomic
+/// counter. Clones share the store.
+///
+/// ```
+/// # use axum_connect::{capture::RpcCapture, prelude::*};
+/// let capture = RpcCapture::new(3);
+/// let app: RpcRouter = RpcRouter::new()
+///     // .rpc(...)
+///     .capture(capture.clone());
+///
+/// // Once the server is done, eg. after `serve` returns.
+/// # let dir = std::env::temp_dir().join("axum-connect-capture-doctest");
+/// capture.dump(&dir).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct RpcCapture {
+    per_method: usize,
+    methods: Arc<RwLock<HashMap<String, Arc<MethodExamples>>>>,
+}
+
+/// The examples of one RPC.
+#[derive(Default)]
+struct MethodExamples {
+    /// Captured examples and calls on their way to be, never more than `per_method`.
+    taken: AtomicUsize,
+    examples: Mutex<Vec<CapturedExample>>,
+}
+
+impl RpcCapture {
+    /// Keeps up to `per_method` examples of each RPC.
+    pub fn new(per_method: usize) -> Self {
+        Self {
+            per_method,
+            methods: Default::default(),
+        }
+    }
+
+    /// The examples captured so far, by RPC path, eg. `/hello.HelloWorldService/SayHello`.
+    pub fn examples(&self) -> BTreeMap<String, Vec<CapturedExample>> {
+        self.methods
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(path, method)| (path.clone(), method.examples.lock().unwrap().clone()))
+            .filter(|(_, examples)| !examples.is_empty())
+            .collect()
+    }
+
+    /// Writes the examples to `dir`, created if it doesn't exist, as a JSON array per RPC named
+    /// after it, eg. `hello.HelloWorldService.SayHello.json`.
+    pub fn dump(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        for (path, examples) in self.examples() {
+            let name = path.trim_start_matches('/').replace('/', ".");
+            let json = serde_json::to_vec_pretty(&examples).map_err(io::Error::other)?;
+            std::fs::write(dir.join(format!("{}.json", name)), json)?;
+        }
+        Ok(())
+    }
+
+    /// Takes a slot for an example of `path`, `None` if it has all it keeps.
+    fn reserve(&self, path: &str) -> Option<Arc<MethodExamples>> {
+        let method = self.methods.read().unwrap().get(path).cloned();
+        let method = match method {
+            Some(method) => method,
+            None => self
+                .methods
+                .write()
+                .unwrap()
+                .entry(path.to_string())
+                .or_default()
+                .clone(),
+        };
+        method
+            .taken
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |taken| {
+                (taken < self.per_method).then_some(taken + 1)
+            })
+            .ok()?;
+        Some(method)
+    }
+}
+
+impl fmt::Debug for RpcCapture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcCapture")
+            .field("per_method", &self.per_method)
+            .field("methods", &self.methods.read().unwrap().len())
+            .finish()
+    }
+}
+
+/// The capture of a call to a route of a router with an [`RpcCapture`], from its request until
+/// its response. Its slot is given back unless the call succeeds.
+pub(crate) struct CaptureCall {
+    method: Arc<MethodExamples>,
+    /// The request, binary encoded, so it can be copied for the hooks after the handler took it.
+    request: Vec<u8>,
+    captured: bool,
+}
+
+impl CaptureCall {
+    /// `None` unless the router has an [`RpcCapture`] and the RPC has room for another example.
+    pub fn start(parts: &request::Parts) -> Option<Self> {
+        let capture = parts.extensions.get::<RpcCapture>()?;
+        let method = capture.reserve(parts.uri.path())?;
+        Some(Self {
+            method,
+            request: vec![],
+            captured: false,
+        })
+    }
+
+    /// Records the request, before the handler takes it.
+    pub fn request<M: Message>(&mut self, request: &M) {
+        self.request = request.encode_to_vec();
+    }
+
+    pub fn finish<Req, Res>(mut self, hooks: &ResponseHooks, response: &RpcResult<RpcPayload<Res>>)
+    where
+        Req: Message + RpcJsonEncode + Default + 'static,
+        Res: RpcJsonEncode,
+    {
+        let Ok(payload) = response else {
+            return;
+        };
+        let Ok(mut request) = Req::decode(self.request.as_slice()) else {
+            return;
+        };
+        hooks.apply(&mut request);
+
+        self.method.examples.lock().unwrap().push(CapturedExample {
+            request: to_value(&request),
+            response: payload.json_value(),
+        });
+        self.captured = true;
+    }
+}
+
+impl Drop for CaptureCall {
+    fn drop(&mut self) {
+        if !self.captured {
+            self.method.taken.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        error::{RpcError, RpcErrorCode},
+        router::RpcRouter,
+        test_util::{
+            client, hello, message, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO,
+        },
+    };
+
+    const SAY_GOODBYE: &str = "/hello.HelloWorldService/SayGoodbye";
+
+    /// Greets `name`, unless it's `boom`.
+    async fn greet(request: HelloRequest) -> RpcResult<HelloResponse> {
+        match request.name.as_str() {
+            "boom" => Err(RpcError::new(RpcErrorCode::Internal, "Boom".to_string())),
+            name => Ok(HelloResponse {
+                message: format!("Hello {name}!"),
+            }),
+        }
+    }
+
+    async fn part(request: HelloRequest) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse {
+            message: format!("Goodbye {}!", request.name),
+        })
+    }
+
+    /// Both RPCs captured in `capture`, with the names scrubbed from requests and responses.
+    fn captured(capture: &RpcCapture) -> RpcRouter {
+        let hooks = ResponseHooks::new()
+            .on_response::<HelloRequest>(|request| request.name = "<name>".to_string())
+            .on_response::<HelloResponse>(|response| {
+                response.message = response.message.split(' ').next().unwrap().to_string();
+            });
+        RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, greet))
+            .rpc_method(unary(SAY_GOODBYE, part))
+            .response_hooks(hooks)
+            .capture(capture.clone())
+    }
+
+    fn example(response: &str) -> CapturedExample {
+        CapturedExample {
+            request: json!({ "name": "<name>" }),
+            response: json!({ "message": response }),
+        }
+    }
+
+    #[tokio::test]
+    async fn captures_the_first_successful_calls_of_each_rpc_redacted() {
+        let capture = RpcCapture::new(2);
+        let client = client(captured(&capture));
+
+        // Failed calls give their slot back.
+        let response = client.send(proto_request(SAY_HELLO, &hello("boom"))).await;
+        assert!(response.error().is_some());
+        for name in ["Ada", "Alan", "Grace"] {
+            let response = client.send(proto_request(SAY_HELLO, &hello(name))).await;
+            // The client gets the response the hooks made too.
+            assert_eq!(message::<HelloResponse>(&response).message, "Hello");
+        }
+        let response = client.send(proto_request(SAY_GOODBYE, &hello("Ada"))).await;
+        assert_eq!(response.error(), None);
+
+        let examples = capture.examples();
+        assert_eq!(
+            examples.keys().collect::<Vec<_>>(),
+            [SAY_GOODBYE, SAY_HELLO]
+        );
+        assert_eq!(examples[SAY_HELLO], [example("Hello"), example("Hello")]);
+        assert_eq!(examples[SAY_GOODBYE], [example("Goodbye")]);
+    }
+
+    #[tokio::test]
+    async fn dumps_a_file_per_rpc() {
+        let capture = RpcCapture::new(1);
+        let client = client(captured(&capture));
+        for path in [SAY_HELLO, SAY_GOODBYE] {
+            client.send(proto_request(path, &hello("Ada"))).await;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        capture.dump(dir.path()).unwrap();
+        let read = |name: &str| {
+            let json = std::fs::read(dir.path().join(name)).unwrap();
+            serde_json::from_slice::<Value>(&json).unwrap()
+        };
+        assert_eq!(
+            read("hello.HelloWorldService.SayHello.json"),
+            json!([{ "request": { "name": "<name>" }, "response": { "message": "Hello" } }])
+        );
+        assert_eq!(
+            read("hello.HelloWorldService.SayGoodbye.json"),
+            json!([{ "request": { "name": "<name>" }, "response": { "message": "Goodbye" } }])
+        );
+    }
+}
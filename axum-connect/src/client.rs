@@ -0,0 +1,1438 @@
+//! Calling Connect services: [`RpcClient`], what generated `<Service>Client`s call through, over
+//! any [`HttpClient`], passing a handler's deadline and identity on, see [`ClientContext`], and
+//! reading the server streams services respond with, see [`RpcResponseStream`].
+
+use std::{
+    fmt,
+    future::Future,
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    http::{
+        self, header, request, response, HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+        Uri,
+    },
+    BoxError,
+};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{future::BoxFuture, stream::BoxStream, Stream, StreamExt, TryStreamExt};
+use http_body::Body;
+use prost::Message;
+use tokio::time::Sleep;
+use tower::{Service, ServiceExt};
+
+use crate::{
+    codec::{self, ContentType, FrameDecoder, RpcJsonDecode, RpcJsonEncode},
+    error::{RpcError, RpcErrorCode},
+    parts::{RpcDeadline, RpcFromRequestParts},
+    response::RpcResult,
+};
+
+#[cfg(feature = "wasm-client")]
+mod fetch;
+
+#[cfg(feature = "wasm-client")]
+pub use fetch::FetchClient;
+
+const TIMEOUT_HEADER: HeaderName = HeaderName::from_static("connect-timeout-ms");
+
+/// The headers passed on as-is: the request id and the W3C trace context.
+const PROPAGATED_HEADERS: [HeaderName; 3] = [
+    HeaderName::from_static("x-request-id"),
+    HeaderName::from_static("traceparent"),
+    HeaderName::from_static("tracestate"),
+];
+
+const PROTOCOL_VERSION_HEADER: HeaderName = HeaderName::from_static("connect-protocol-version");
+
+/// The spec allows at most 10 digits.
+const MAX_TIMEOUT_MILLIS: u128 = 9_999_999_999;
+
+/// The headers for a downstream Connect call made while handling this one: `connect-timeout-ms`
+/// with the time left until the call's deadline, less a safety margin for the network hop, and the
+/// call's `x-request-id`, `traceparent` and `tracestate`. Put them on the request of whatever HTTP
+/// client you use, so nested calls share one budget; each hop takes its margin out of it.
+///
+/// As an extractor it picks up the deadline and headers of the call:
+///
+/// ```
+/// # use axum_connect::{client::ClientContext, prelude::*};
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct GetProfileRequest {}
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct GetProfileResponse {}
+/// async fn get_profile(
+///     context: ClientContext,
+///     request: GetProfileRequest,
+/// ) -> RpcResult<GetProfileResponse> {
+///     // Fails with `DeadlineExceeded` if there's no time left to make the call.
+///     let headers = context.headers()?;
+///     // http.post(url).headers(headers).body(...)
+///     Ok(GetProfileResponse {})
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientContext {
+    deadline: RpcDeadline,
+    safety_margin: Duration,
+    headers: HeaderMap,
+}
+
+impl ClientContext {
+    /// The margin taken off the remaining time by default.
+    pub const DEFAULT_SAFETY_MARGIN: Duration = Duration::from_millis(10);
+
+    /// The context of a call with `deadline` and no headers to pass on.
+    pub fn from_deadline(deadline: RpcDeadline) -> Self {
+        Self {
+            deadline,
+            safety_margin: Self::DEFAULT_SAFETY_MARGIN,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// The context of the call `parts` is the request of.
+    pub fn from_parts(parts: &request::Parts) -> Self {
+        let deadline = parts.extensions.get().copied().unwrap_or_default();
+        let mut context = Self::from_deadline(deadline);
+        for name in PROPAGATED_HEADERS {
+            if let Some(value) = parts.headers.get(&name) {
+                context.headers.insert(name, value.clone());
+            }
+        }
+        context
+    }
+
+    pub fn safety_margin(mut self, safety_margin: Duration) -> Self {
+        self.safety_margin = safety_margin;
+        self
+    }
+
+    /// The timeout of a downstream call started now, `None` if this call has no deadline.
+    pub fn timeout(&self) -> RpcResult<Option<Duration>> {
+        self.timeout_at(Instant::now())
+    }
+
+    /// Like [`timeout`](Self::timeout), for a call started at `now`.
+    pub fn timeout_at(&self, now: Instant) -> RpcResult<Option<Duration>> {
+        let Some(deadline) = self.deadline.0 else {
+            return Ok(None);
+        };
+
+        // Timeouts are whole milliseconds, and a timeout of 0 is already exceeded.
+        let timeout = deadline.saturating_duration_since(now + self.safety_margin);
+        if timeout.as_millis() == 0 {
+            return Err(RpcError::new(
+                RpcErrorCode::DeadlineExceeded,
+                "Not enough time left before the deadline to make the call".to_string(),
+            ));
+        }
+        Ok(Some(timeout))
+    }
+
+    /// The headers of a downstream call started now.
+    pub fn headers(&self) -> RpcResult<HeaderMap> {
+        self.headers_at(Instant::now())
+    }
+
+    /// Like [`headers`](Self::headers), for a call started at `now`.
+    pub fn headers_at(&self, now: Instant) -> RpcResult<HeaderMap> {
+        let mut headers = self.headers.clone();
+        if let Some(timeout) = self.timeout_at(now)? {
+            let millis = timeout.as_millis().min(MAX_TIMEOUT_MILLIS);
+            headers.insert(TIMEOUT_HEADER, HeaderValue::from(millis as u64));
+        }
+        Ok(headers)
+    }
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for ClientContext
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::from_parts(parts))
+    }
+}
+
+/// The body of a response an [`HttpClient`] received, its chunks as they arrive.
+pub type ClientBody = BoxStream<'static, Result<Bytes, BoxError>>;
+
+/// The transport of an [`RpcClient`]: sends a request with its whole body and returns the response
+/// as soon as its head arrives, with the body still streaming in. It's object safe and doesn't
+/// care what runs its futures, so the same client works over hyper, in-process (see
+/// [`TowerClient`]) or a browser's `fetch` (see `FetchClient`, with the `wasm-client` feature).
+///
+/// Failing to send the request, or to receive the head of the response, fails the call with
+/// `Unavailable`.
+pub trait HttpClient: Send + Sync + 'static {
+    fn send(
+        &self,
+        request: http::Request<Bytes>,
+    ) -> BoxFuture<'static, Result<http::Response<ClientBody>, BoxError>>;
+}
+
+impl<T: HttpClient + ?Sized> HttpClient for Arc<T> {
+    fn send(
+        &self,
+        request: http::Request<Bytes>,
+    ) -> BoxFuture<'static, Result<http::Response<ClientBody>, BoxError>> {
+        (**self).send(request)
+    }
+}
+
+/// An [`HttpClient`] calling a tower `Service`, eg. an `axum::Router` in-process for tests, or a
+/// hyper-util client over the network.
+///
+/// ```
+/// # use axum_connect::{client::{RpcClient, TowerClient}, prelude::*};
+/// let router = RpcRouter::new().into_router();
+/// let client = RpcClient::builder("http://localhost", TowerClient::new(router)).build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct TowerClient<S>(S);
+
+impl<S> TowerClient<S> {
+    pub fn new(service: S) -> Self {
+        Self(service)
+    }
+}
+
+impl<S, B> HttpClient for TowerClient<S>
+where
+    S: Service<http::Request<axum::body::Body>, Response = http::Response<B>>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send + 'static,
+    S::Error: Into<BoxError>,
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    fn send(
+        &self,
+        request: http::Request<Bytes>,
+    ) -> BoxFuture<'static, Result<http::Response<ClientBody>, BoxError>> {
+        let service = self.0.clone();
+        Box::pin(async move {
+            let response = service
+                .oneshot(request.map(axum::body::Body::from))
+                .await
+                .map_err(Into::into)?;
+            Ok(response.map(body_chunks))
+        })
+    }
+}
+
+/// The data of `body`, without its HTTP trailers: Connect's are in the body.
+fn body_chunks<B>(body: B) -> ClientBody
+where
+    B: Body + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    let mut body = Box::pin(body);
+    futures::stream::poll_fn(move |cx| loop {
+        match ready!(body.as_mut().poll_frame(cx)) {
+            None => return Poll::Ready(None),
+            Some(Err(error)) => return Poll::Ready(Some(Err(error.into()))),
+            Some(Ok(frame)) => {
+                if let Ok(mut data) = frame.into_data() {
+                    return Poll::Ready(Some(Ok(data.copy_to_bytes(data.remaining()))));
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Runs around every call of an [`RpcClient`], unary and streaming, eg. to attach an auth token,
+/// log latency or point calls at another host.
+///
+/// Interceptors run `before` in the order they were added, and `after` in the reverse order. The
+/// response's extensions are the request's, so `before` can leave something there for `after`,
+/// eg. when the call started.
+///
+/// ```
+/// # use std::time::Instant;
+/// # use axum::http::{request, response, HeaderValue};
+/// # use axum_connect::{client::ClientInterceptor, prelude::*};
+/// struct Latency;
+///
+/// impl ClientInterceptor for Latency {
+///     fn before(&self, request: &mut request::Parts) -> RpcResult<()> {
+///         request.extensions.insert(Instant::now());
+///         request.headers.insert("authorization", HeaderValue::from_static("Bearer hunter2"));
+///         Ok(())
+///     }
+///
+///     fn after(&self, response: &mut response::Parts) -> RpcResult<()> {
+///         if let Some(started) = response.extensions.get::<Instant>() {
+///             println!("{} in {:?}", response.status, started.elapsed());
+///         }
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait ClientInterceptor: Send + Sync + 'static {
+    /// Runs before the request is sent. An error fails the call with it, and the request isn't
+    /// sent.
+    fn before(&self, request: &mut request::Parts) -> RpcResult<()> {
+        let _ = request;
+        Ok(())
+    }
+
+    /// Runs once the head of the response arrived, before its body is read. An error fails the
+    /// call with it.
+    fn after(&self, response: &mut response::Parts) -> RpcResult<()> {
+        let _ = response;
+        Ok(())
+    }
+}
+
+/// What every clone of an [`RpcClient`] shares.
+struct ClientConfig {
+    /// Without a trailing `/`.
+    base_url: String,
+    http: Box<dyn HttpClient>,
+    binary: bool,
+    interceptors: Vec<Box<dyn ClientInterceptor>>,
+}
+
+/// A Connect client of the RPCs under a base URL, which generated `<Service>Client`s (see the
+/// `clients` setting of codegen) wrap with a method per RPC. It's cheap to clone, clones share
+/// their transport and interceptors.
+///
+/// Calls are `POST`s of binary protobuf (JSON with [`json`](RpcClientBuilder::json)). A non-`200`
+/// unary response fails with its error, see [`RpcError::from_response`], and server streams come
+/// back as an [`RpcResponseStream`].
+///
+/// ```
+/// # use std::time::Duration;
+/// # use axum::http::{HeaderName, HeaderValue};
+/// # use axum_connect::{client::{RpcClient, TowerClient}, prelude::*};
+/// # let http = TowerClient::new(RpcRouter::new().into_router());
+/// let client = RpcClient::builder("https://api.example.com", http)
+///     .metadata(HeaderName::from_static("x-tenant"), HeaderValue::from_static("acme"))
+///     .timeout(Duration::from_secs(5))
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct RpcClient {
+    config: Arc<ClientConfig>,
+    /// The default metadata and timeout, with those of [`with_headers`](Self::with_headers).
+    headers: HeaderMap,
+}
+
+impl RpcClient {
+    /// A client of the RPCs under `base_url`, eg. `https://api.example.com`, sending its requests
+    /// with `http`.
+    pub fn builder(base_url: impl Into<String>, http: impl HttpClient) -> RpcClientBuilder {
+        RpcClientBuilder {
+            base_url: base_url.into(),
+            http: Box::new(http),
+            metadata: HeaderMap::new(),
+            timeout: None,
+            binary: true,
+            interceptors: vec![],
+        }
+    }
+
+    /// The client with `headers` on its calls, replacing the metadata of the same names, eg. those
+    /// of a [`ClientContext`], whose `connect-timeout-ms` replaces the default timeout.
+    pub fn with_headers(&self, headers: HeaderMap) -> Self {
+        let mut client = self.clone();
+        client.headers.extend(headers);
+        client
+    }
+
+    /// Calls the unary RPC at `path`, eg. `/hello.HelloWorldService/SayHello`.
+    pub async fn unary<Req, Res>(&self, path: &str, message: Req) -> RpcResult<Res>
+    where
+        Req: Message + RpcJsonEncode,
+        Res: Message + RpcJsonDecode + Default + 'static,
+    {
+        let content_type = match self.config.binary {
+            true => ContentType::Proto,
+            false => ContentType::Json,
+        };
+        let mut body = vec![];
+        codec::encode_unary_response(&message, self.config.binary, &mut body)?;
+
+        let (parts, mut body) = self.call(path, content_type, body).await?;
+        let mut bytes = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.map_err(|error| {
+                RpcError::new(
+                    RpcErrorCode::Unknown,
+                    format!("Failed to read the response: {}", error),
+                )
+            })?;
+            bytes.extend_from_slice(&chunk);
+        }
+
+        if parts.status != StatusCode::OK {
+            return Err(RpcError::from_response(&http::Response::from_parts(
+                parts, bytes,
+            )));
+        }
+        let binary = match parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| ContentType::parse(value.as_bytes()))
+        {
+            Some(content_type) if !content_type.is_streaming() => content_type.is_binary(),
+            _ => {
+                return Err(RpcError::new(
+                    RpcErrorCode::Internal,
+                    "The response's Content-Type isn't a unary Connect response's".to_string(),
+                )
+                .with_metadata(parts.headers))
+            }
+        };
+        codec::decode_unary_response(&bytes, binary)
+    }
+
+    /// Calls the server streaming RPC at `path`, its messages come as the response streams in.
+    pub async fn server_stream<Req, Res>(
+        &self,
+        path: &str,
+        message: Req,
+    ) -> RpcResult<RpcResponseStream<Res>>
+    where
+        Req: Message + RpcJsonEncode,
+        Res: Message + RpcJsonDecode + Default + 'static,
+    {
+        let content_type = match self.config.binary {
+            true => ContentType::ConnectProto,
+            false => ContentType::ConnectJson,
+        };
+        let mut body = vec![];
+        codec::encode_stream_response(&message, self.config.binary, &mut body)?;
+
+        let (parts, body) = self.call(path, content_type, body).await?;
+        RpcResponseStream::from_parts(parts, body)
+    }
+
+    /// Sends the request through the interceptors and the transport, returning the response's
+    /// head once the interceptors are done with it.
+    async fn call(
+        &self,
+        path: &str,
+        content_type: ContentType,
+        body: Vec<u8>,
+    ) -> RpcResult<(response::Parts, ClientBody)> {
+        let url = format!("{}{}", self.config.base_url, path);
+        let uri = url.parse::<Uri>().map_err(|error| {
+            RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!("Invalid URL {:?}: {}", url, error),
+            )
+        })?;
+        let (mut parts, ()) = http::Request::new(()).into_parts();
+        parts.method = Method::POST;
+        parts.uri = uri;
+        parts.headers = self.headers.clone();
+        parts.headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(content_type.as_str()),
+        );
+        parts
+            .headers
+            .insert(PROTOCOL_VERSION_HEADER, HeaderValue::from_static("1"));
+
+        for interceptor in &self.config.interceptors {
+            interceptor.before(&mut parts)?;
+        }
+        let extensions = std::mem::take(&mut parts.extensions);
+        let response = self
+            .config
+            .http
+            .send(http::Request::from_parts(parts, Bytes::from(body)))
+            .await
+            .map_err(|error| {
+                RpcError::new(
+                    RpcErrorCode::Unavailable,
+                    format!("Failed to send the request: {}", error),
+                )
+            })?;
+
+        let (mut parts, body) = response.into_parts();
+        parts.extensions = extensions;
+        for interceptor in self.config.interceptors.iter().rev() {
+            interceptor.after(&mut parts)?;
+        }
+        Ok((parts, body))
+    }
+}
+
+impl fmt::Debug for RpcClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcClient")
+            .field("base_url", &self.config.base_url)
+            .field("binary", &self.config.binary)
+            .field("headers", &self.headers)
+            .field("interceptors", &self.config.interceptors.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Configures an [`RpcClient`], see [`RpcClient::builder`].
+pub struct RpcClientBuilder {
+    base_url: String,
+    http: Box<dyn HttpClient>,
+    metadata: HeaderMap,
+    timeout: Option<Duration>,
+    binary: bool,
+    interceptors: Vec<Box<dyn ClientInterceptor>>,
+}
+
+impl RpcClientBuilder {
+    /// Sends `value` as the metadata `key` with every call, next to any other values of it.
+    pub fn metadata(mut self, key: HeaderName, value: HeaderValue) -> Self {
+        self.metadata.append(key, value);
+        self
+    }
+
+    /// The timeout of every call, sent as `connect-timeout-ms` for the server to enforce, so it
+    /// doesn't need a timer on the client's side.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Sends and receives JSON rather than binary protobuf, which needs the `json` feature.
+    pub fn json(mut self) -> Self {
+        self.binary = false;
+        self
+    }
+
+    /// Runs `interceptor` around every call, after those added before it, see
+    /// [`ClientInterceptor`].
+    pub fn interceptor(mut self, interceptor: impl ClientInterceptor) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    pub fn build(self) -> RpcClient {
+        let mut headers = self.metadata;
+        if let Some(timeout) = self.timeout {
+            let millis = timeout.as_millis().clamp(1, MAX_TIMEOUT_MILLIS);
+            headers.insert(TIMEOUT_HEADER, HeaderValue::from(millis as u64));
+        }
+        RpcClient {
+            config: Arc::new(ClientConfig {
+                base_url: self.base_url.trim_end_matches('/').to_string(),
+                http: self.http,
+                binary: self.binary,
+                interceptors: self.interceptors,
+            }),
+            headers,
+        }
+    }
+}
+
+impl fmt::Debug for RpcClientBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcClientBuilder")
+            .field("base_url", &self.base_url)
+            .field("metadata", &self.metadata)
+            .field("timeout", &self.timeout)
+            .field("binary", &self.binary)
+            .field("interceptors", &self.interceptors.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The messages of a server stream response, what [`RpcClient::server_stream`] returns. For calls
+/// made with another HTTP client, wrap its response with [`from_response`](Self::from_response),
+/// or its body with [`new`](Self::new).
+///
+/// It's a `Stream` of `RpcResult<M>`, which ends with the end-of-stream frame: an error sent
+/// there is the last item, with the trailers as its metadata, and the trailers stay readable
+/// with [`trailers`](Self::trailers). A body that ends without that frame fails with `Internal`.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use axum_connect::{client::RpcResponseStream, pbjson_types::Empty, prelude::*};
+/// # type Event = Empty;
+/// async fn tail(response: axum::http::Response<axum::body::Body>) -> RpcResult<Vec<Event>> {
+///     let mut events = RpcResponseStream::<Event>::from_response(response)?
+///         .message_timeout(Duration::from_secs(30))
+///         .timeout(Duration::from_secs(300));
+///     let all = events.collect_all(1000).await?;
+///     println!("trailers: {:?}", events.trailers());
+///     Ok(all)
+/// }
+/// ```
+pub struct RpcResponseStream<M> {
+    /// `None` once the stream has ended.
+    body: Option<BoxStream<'static, Result<Bytes, BoxError>>>,
+    decoder: FrameDecoder,
+    binary: bool,
+    /// Gunzip compressed messages, see [`gzip`](Self::gzip).
+    #[cfg(feature = "stream-compression")]
+    gzip: bool,
+    trailers: Option<HeaderMap>,
+    message_timeout: Option<Duration>,
+    /// The wait for the next message, started when it's polled for.
+    message_sleep: Option<Pin<Box<Sleep>>>,
+    deadline: Option<(Duration, Pin<Box<Sleep>>)>,
+    message: PhantomData<fn() -> M>,
+}
+
+impl<M> RpcResponseStream<M> {
+    /// The stream of a response `body` in the binary (`application/connect+proto`) or JSON
+    /// (`application/connect+json`) codec.
+    pub fn new<S, E>(body: S, binary: bool) -> Self
+    where
+        S: Stream<Item = Result<Bytes, E>> + Send + 'static,
+        E: Into<BoxError> + 'static,
+    {
+        Self {
+            body: Some(body.map_err(Into::into).boxed()),
+            decoder: FrameDecoder::new(),
+            binary,
+            #[cfg(feature = "stream-compression")]
+            gzip: false,
+            trailers: None,
+            message_timeout: None,
+            message_sleep: None,
+            deadline: None,
+            message: PhantomData,
+        }
+    }
+
+    /// The stream of a server stream `response`, in the codec of its `Content-Type`. Connect
+    /// streams always have a 200 status, errors come at their end: any other status fails with
+    /// its code (see [`RpcErrorCode::from_http_status`]) and so does a content type that isn't
+    /// a Connect stream's, with `Internal`. The error's metadata is the response's headers.
+    pub fn from_response<B>(response: http::Response<B>) -> RpcResult<Self>
+    where
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<BoxError>,
+    {
+        let (parts, body) = response.into_parts();
+        Self::from_parts(parts, body_chunks(body))
+    }
+
+    fn from_parts(parts: response::Parts, body: ClientBody) -> RpcResult<Self> {
+        if parts.status != StatusCode::OK {
+            return Err(RpcError::new(
+                RpcErrorCode::from_http_status(parts.status),
+                format!("HTTP status {}", parts.status),
+            )
+            .with_metadata(parts.headers));
+        }
+
+        let content_type = parts.headers.get(header::CONTENT_TYPE);
+        let Some(content_type) = content_type
+            .and_then(|value| ContentType::parse(value.as_bytes()))
+            .filter(|content_type| content_type.is_streaming())
+        else {
+            let content_type = content_type.and_then(|value| value.to_str().ok());
+            return Err(RpcError::new(
+                RpcErrorCode::Internal,
+                format!(
+                    "The response's Content-Type {:?} isn't a Connect stream's",
+                    content_type.unwrap_or_default()
+                ),
+            )
+            .with_metadata(parts.headers));
+        };
+
+        let stream = Self::new(body, content_type.is_binary());
+        #[cfg(feature = "stream-compression")]
+        let stream = match parts.headers.get("connect-content-encoding") {
+            Some(encoding) if encoding.as_bytes().eq_ignore_ascii_case(b"gzip") => stream.gzip(),
+            _ => stream,
+        };
+        Ok(stream)
+    }
+
+    /// Accepts gzipped messages, for a response with `connect-content-encoding: gzip`, see
+    /// `RouteOptions::stream_compression`. [`from_response`](Self::from_response) checks the
+    /// header itself.
+    #[cfg(feature = "stream-compression")]
+    pub fn gzip(mut self) -> Self {
+        self.gzip = true;
+        self
+    }
+
+    /// Fails with `DeadlineExceeded` when a message (or the end of the stream) takes longer than
+    /// `timeout` to arrive, counted from when it's polled for. Like [`timeout`](Self::timeout),
+    /// it needs a tokio runtime with its timer, so it's not for the browser.
+    pub fn message_timeout(mut self, timeout: Duration) -> Self {
+        self.message_timeout = Some(timeout);
+        self.message_sleep = None;
+        self
+    }
+
+    /// Fails with `DeadlineExceeded` when the whole stream takes longer than `timeout`, counted
+    /// from now.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.deadline = Some((timeout, Box::pin(tokio::time::sleep(timeout))));
+        self
+    }
+
+    /// The trailing metadata, once the end-of-stream frame has been read.
+    pub fn trailers(&self) -> Option<&HeaderMap> {
+        self.trailers.as_ref()
+    }
+
+    /// Ends the stream, dropping the body, which cancels the call.
+    fn cancel(&mut self) {
+        self.body = None;
+        self.message_sleep = None;
+        self.deadline = None;
+    }
+
+    /// Ends the stream with `item`.
+    fn end(&mut self, item: Option<RpcResult<M>>) -> Poll<Option<RpcResult<M>>> {
+        self.cancel();
+        Poll::Ready(item)
+    }
+}
+
+impl<M> RpcResponseStream<M>
+where
+    M: Message + RpcJsonDecode + Default + 'static,
+{
+    /// All the messages, failing with `ResourceExhausted` as soon as there are more than
+    /// `limit`, or with the stream's error.
+    pub async fn collect_all(&mut self, limit: usize) -> RpcResult<Vec<M>> {
+        let mut messages = vec![];
+        while let Some(message) = self.next().await {
+            let message = message?;
+            if messages.len() == limit {
+                self.cancel();
+                return Err(RpcError::new(
+                    RpcErrorCode::ResourceExhausted,
+                    format!("The stream has more than {} messages", limit),
+                ));
+            }
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    /// The first message, `None` if the stream ended without one. The rest of the call is
+    /// cancelled.
+    pub async fn into_first(mut self) -> RpcResult<Option<M>> {
+        self.next().await.transpose()
+    }
+
+    /// Runs `f` on the messages, at most `limit` at a time (`None` for no limit), like
+    /// [`TryStreamExt::try_for_each_concurrent`]. Stops at the first error, of `f` or the stream.
+    pub async fn try_for_each_concurrent<F, Fut>(
+        &mut self,
+        limit: impl Into<Option<usize>>,
+        f: F,
+    ) -> RpcResult<()>
+    where
+        F: FnMut(M) -> Fut,
+        Fut: Future<Output = RpcResult<()>>,
+    {
+        let result = TryStreamExt::try_for_each_concurrent(&mut *self, limit, f).await;
+        if result.is_err() {
+            self.cancel();
+        }
+        result
+    }
+
+    fn decode(&mut self, frame: codec::Frame) -> Poll<Option<RpcResult<M>>> {
+        if frame.is_end_stream() {
+            return match codec::parse_end_stream(&frame.payload) {
+                Ok(end) => {
+                    self.trailers = Some(end.metadata);
+                    self.end(end.error.map(Err))
+                }
+                Err(error) => self.end(Some(Err(RpcError::new(
+                    RpcErrorCode::Internal,
+                    format!("Invalid end-of-stream frame: {}", error),
+                )))),
+            };
+        }
+        // Without a `connect-content-encoding`, servers don't compress.
+        let payload = match frame.is_compressed() {
+            false => frame.payload,
+            #[cfg(feature = "stream-compression")]
+            true if self.gzip => match codec::gunzip(&frame.payload) {
+                Ok(payload) => payload,
+                Err(error) => return self.end(Some(Err(error))),
+            },
+            true => {
+                return self.end(Some(Err(RpcError::new(
+                    RpcErrorCode::Internal,
+                    "The server sent a compressed message".to_string(),
+                ))))
+            }
+        };
+
+        self.message_sleep = None;
+        match codec::decode_unary_response(&payload, self.binary) {
+            Ok(message) => Poll::Ready(Some(Ok(message))),
+            Err(error) => self.end(Some(Err(error))),
+        }
+    }
+}
+
+impl<M> Stream for RpcResponseStream<M>
+where
+    M: Message + RpcJsonDecode + Default + 'static,
+{
+    type Item = RpcResult<M>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            let Some(body) = &mut this.body else {
+                return Poll::Ready(None);
+            };
+
+            match this.decoder.next_frame() {
+                Ok(Some(frame)) => return this.decode(frame),
+                Ok(None) => {}
+                Err(error) => return this.end(Some(Err(error))),
+            }
+
+            match body.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(bytes))) => this.decoder.push(&bytes),
+                Poll::Ready(Some(Err(error))) => {
+                    return this.end(Some(Err(RpcError::new(
+                        RpcErrorCode::Unknown,
+                        format!("Failed to read the response: {}", error),
+                    ))));
+                }
+                Poll::Ready(None) => {
+                    let error = match std::mem::take(&mut this.decoder).finish() {
+                        Ok(()) => RpcError::new(
+                            RpcErrorCode::Internal,
+                            "The stream ended without an end-of-stream frame".to_string(),
+                        ),
+                        Err(error) => error,
+                    };
+                    return this.end(Some(Err(error)));
+                }
+                Poll::Pending => break,
+            }
+        }
+
+        if let Some((timeout, deadline)) = &mut this.deadline {
+            if deadline.as_mut().poll(cx).is_ready() {
+                let message = format!("The stream didn't end within {:?}", timeout);
+                return this.end(Some(Err(RpcError::new(
+                    RpcErrorCode::DeadlineExceeded,
+                    message,
+                ))));
+            }
+        }
+        if let Some(timeout) = this.message_timeout {
+            let sleep = this
+                .message_sleep
+                .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+            if sleep.as_mut().poll(cx).is_ready() {
+                return this.end(Some(Err(RpcError::new(
+                    RpcErrorCode::DeadlineExceeded,
+                    format!("No message within {:?}", timeout),
+                ))));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+impl<M> fmt::Debug for RpcResponseStream<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcResponseStream")
+            .field("binary", &self.binary)
+            .field("ended", &self.body.is_none())
+            .field("trailers", &self.trailers)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    use axum::extract::State;
+    use futures::stream;
+
+    use super::*;
+    use crate::{
+        parts::{RpcMetadata, RpcTrailers},
+        router::RpcRouter,
+        test_util::{
+            hello, hello_router, say_hello, server_stream, unary, HelloRequest, HelloResponse,
+            SAY_HELLO, SAY_HELLO_STREAM,
+        },
+    };
+
+    type Events = Arc<Mutex<Vec<String>>>;
+
+    /// Records the calls it sees, as `<name> before <path>` and `<name> after <status>`.
+    struct Recorder {
+        name: &'static str,
+        events: Events,
+    }
+
+    impl ClientInterceptor for Recorder {
+        fn before(&self, request: &mut request::Parts) -> RpcResult<()> {
+            let event = format!("{} before {}", self.name, request.uri.path());
+            self.events.lock().unwrap().push(event);
+            Ok(())
+        }
+
+        fn after(&self, response: &mut response::Parts) -> RpcResult<()> {
+            let event = format!("{} after {}", self.name, response.status);
+            self.events.lock().unwrap().push(event);
+            Ok(())
+        }
+    }
+
+    fn builder(router: RpcRouter) -> RpcClientBuilder {
+        RpcClient::builder("http://localhost/", TowerClient::new(router.into_router()))
+    }
+
+    /// A client of `router` with an `outer` recorder and an `inner` one.
+    fn recorded(router: RpcRouter) -> (RpcClient, Events) {
+        let events = Events::default();
+        let client = builder(router)
+            .interceptor(Recorder {
+                name: "outer",
+                events: Arc::clone(&events),
+            })
+            .interceptor(Recorder {
+                name: "inner",
+                events: Arc::clone(&events),
+            })
+            .build();
+        (client, events)
+    }
+
+    fn seen(events: &Events) -> Vec<String> {
+        events.lock().unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn intercepts_unary_calls() {
+        let (client, events) = recorded(hello_router());
+
+        let response: HelloResponse = client.unary(SAY_HELLO, hello("Alec")).await.unwrap();
+        assert_eq!(response.message, "Hello Alec!");
+        assert_eq!(
+            seen(&events),
+            [
+                format!("outer before {SAY_HELLO}"),
+                format!("inner before {SAY_HELLO}"),
+                "inner after 200 OK".to_string(),
+                "outer after 200 OK".to_string(),
+            ]
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn reads_empty_json_bodies_as_empty_messages() {
+        use crate::{config::RpcConfig, router::RpcMethod, test_util::unary_info};
+
+        const FORGET: &str = "/hello.HelloWorldService/Forget";
+        async fn forget(_: HelloRequest) -> RpcResult<pbjson_types::Empty> {
+            Ok(pbjson_types::Empty {})
+        }
+        let router = |empty_response_body| {
+            RpcRouter::new()
+                .rpc_method(RpcMethod::unary(unary_info(FORGET), forget))
+                .with_config(RpcConfig::default().empty_response_body(empty_response_body))
+        };
+        for empty_response_body in [false, true] {
+            for client in [
+                builder(router(empty_response_body)).build(),
+                builder(router(empty_response_body)).json().build(),
+            ] {
+                let response = client.unary(FORGET, hello("Ada")).await;
+                assert_eq!(response, Ok(pbjson_types::Empty {}));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn intercepts_streams() {
+        let (client, events) = recorded(hello_router());
+
+        let mut stream = client
+            .server_stream::<_, HelloResponse>(SAY_HELLO_STREAM, hello("Alec"))
+            .await
+            .unwrap();
+        let messages = stream.collect_all(10).await.unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0].message, "Hello Alec #1!");
+        assert_eq!(
+            seen(&events),
+            [
+                format!("outer before {SAY_HELLO_STREAM}"),
+                format!("inner before {SAY_HELLO_STREAM}"),
+                "inner after 200 OK".to_string(),
+                "outer after 200 OK".to_string(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn intercepts_errors() {
+        async fn not_found(_request: HelloRequest) -> RpcResult<HelloResponse> {
+            Err(RpcError::new(
+                RpcErrorCode::NotFound,
+                "No such greeting".to_string(),
+            ))
+        }
+        let (client, events) = recorded(RpcRouter::new().rpc_method(unary(SAY_HELLO, not_found)));
+
+        let error = client
+            .unary::<_, HelloResponse>(SAY_HELLO, hello("Alec"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+        assert_eq!(error.message, "No such greeting");
+        assert_eq!(seen(&events)[3], "outer after 404 Not Found");
+    }
+
+    #[tokio::test]
+    async fn interceptor_errors_skip_the_transport() {
+        struct Deny;
+
+        impl ClientInterceptor for Deny {
+            fn before(&self, _request: &mut request::Parts) -> RpcResult<()> {
+                Err(RpcError::new(
+                    RpcErrorCode::Unauthenticated,
+                    "No token".to_string(),
+                ))
+            }
+        }
+
+        #[derive(Default)]
+        struct Counting(AtomicUsize);
+
+        impl HttpClient for Counting {
+            fn send(
+                &self,
+                _request: http::Request<Bytes>,
+            ) -> BoxFuture<'static, Result<http::Response<ClientBody>, BoxError>> {
+                self.0.fetch_add(1, Ordering::Relaxed);
+                Box::pin(async { Err("unreachable".into()) })
+            }
+        }
+
+        let http = Arc::new(Counting::default());
+        let client = RpcClient::builder("http://localhost", Arc::clone(&http))
+            .interceptor(Deny)
+            .build();
+
+        let error = client
+            .unary::<_, HelloResponse>(SAY_HELLO, hello("Alec"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::Unauthenticated);
+        let error = client
+            .server_stream::<_, HelloResponse>(SAY_HELLO_STREAM, hello("Alec"))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::Unauthenticated);
+        assert_eq!(http.0.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn builds_unary_requests() {
+        /// Keeps the request it was sent, and responds with an empty message.
+        #[derive(Default)]
+        struct Capture(Mutex<Option<http::Request<Bytes>>>);
+
+        impl HttpClient for Capture {
+            fn send(
+                &self,
+                request: http::Request<Bytes>,
+            ) -> BoxFuture<'static, Result<http::Response<ClientBody>, BoxError>> {
+                *self.0.lock().unwrap() = Some(request);
+                let response = http::Response::builder()
+                    .header(header::CONTENT_TYPE, "application/proto")
+                    .body(futures::stream::empty().boxed())
+                    .unwrap();
+                Box::pin(async { Ok(response) })
+            }
+        }
+
+        let http = Arc::new(Capture::default());
+        let client = RpcClient::builder("https://api.example.com/", Arc::clone(&http))
+            .metadata(
+                HeaderName::from_static("x-tenant"),
+                HeaderValue::from_static("acme"),
+            )
+            .timeout(Duration::from_millis(1500))
+            .build();
+        let response: HelloResponse = client.unary(SAY_HELLO, hello("Alec")).await.unwrap();
+        assert_eq!(response, HelloResponse::default());
+
+        let request = http.0.lock().unwrap().take().unwrap();
+        assert_eq!(request.method(), Method::POST);
+        assert_eq!(
+            request.uri(),
+            "https://api.example.com/hello.HelloWorldService/SayHello"
+        );
+        let headers = request
+            .headers()
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            headers,
+            [
+                ("x-tenant", "acme"),
+                ("connect-timeout-ms", "1500"),
+                ("content-type", "application/proto"),
+                ("connect-protocol-version", "1"),
+            ]
+        );
+        assert_eq!(request.body().as_ref(), hello("Alec").encode_to_vec());
+    }
+
+    #[tokio::test]
+    async fn sends_the_default_metadata_and_timeout() {
+        async fn echo(metadata: RpcMetadata, _request: HelloRequest) -> RpcResult<HelloResponse> {
+            let get = |key| metadata.get(key).unwrap_or_default().to_string();
+            Ok(HelloResponse {
+                message: format!("{} {}", get("x-tenant"), get("connect-timeout-ms")),
+            })
+        }
+        let client = builder(RpcRouter::new().rpc_method(unary(SAY_HELLO, echo)))
+            .metadata(
+                HeaderName::from_static("x-tenant"),
+                HeaderValue::from_static("acme"),
+            )
+            .timeout(Duration::from_secs(5))
+            .build();
+
+        let response: HelloResponse = client.unary(SAY_HELLO, hello("Alec")).await.unwrap();
+        assert_eq!(response.message, "acme 5000");
+
+        // A handler's context replaces the default timeout with the time it has left.
+        let context = ClientContext::from_deadline(RpcDeadline(Some(
+            Instant::now() + Duration::from_secs(1),
+        )));
+        let response: HelloResponse = client
+            .with_headers(context.headers().unwrap())
+            .unary(SAY_HELLO, hello("Alec"))
+            .await
+            .unwrap();
+        let timeout = response.message.strip_prefix("acme ").unwrap();
+        assert!(timeout.parse::<u64>().unwrap() < 1000, "{timeout}");
+    }
+
+    #[tokio::test]
+    async fn interceptors_can_point_calls_elsewhere() {
+        struct Staging;
+
+        impl ClientInterceptor for Staging {
+            fn before(&self, request: &mut request::Parts) -> RpcResult<()> {
+                let path = request.uri.path().replace("hello.", "staging.hello.");
+                request.uri = path.parse().unwrap();
+                Ok(())
+            }
+        }
+
+        let staging = "/staging.hello.HelloWorldService/SayHello";
+        async fn staging_hello(request: HelloRequest) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: format!("Staging {}", request.name),
+            })
+        }
+        let client = builder(RpcRouter::new().rpc_method(unary(staging, staging_hello)))
+            .interceptor(Staging)
+            .build();
+
+        let response: HelloResponse = client.unary(SAY_HELLO, hello("Alec")).await.unwrap();
+        assert_eq!(response.message, "Staging Alec");
+    }
+
+    /// The parts of a request with `headers`.
+    fn parts(headers: &[(&'static str, &'static str)]) -> request::Parts {
+        let mut request = http::Request::builder();
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(()).unwrap().into_parts().0
+    }
+
+    fn timeout_header(headers: &HeaderMap) -> u64 {
+        headers["connect-timeout-ms"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn shrinks_the_timeout_across_nested_calls() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        // The front handler has 800 ms, and calls the next service 50 ms in.
+        let front = ClientContext::from_deadline(RpcDeadline(Some(at(800))));
+        let timeout = timeout_header(&front.headers_at(at(50)).unwrap());
+        assert_eq!(timeout, 740);
+
+        // Which gets the call at 60 ms, and calls the last one 100 ms later.
+        let middle = ClientContext::from_deadline(RpcDeadline(Some(
+            at(60) + Duration::from_millis(timeout),
+        )));
+        let timeout = timeout_header(&middle.headers_at(at(160)).unwrap());
+        assert_eq!(timeout, 630);
+
+        let last = ClientContext::from_deadline(RpcDeadline(Some(
+            at(170) + Duration::from_millis(timeout),
+        )))
+        .safety_margin(Duration::from_millis(100));
+        assert_eq!(timeout_header(&last.headers_at(at(500)).unwrap()), 200);
+    }
+
+    #[test]
+    fn refuses_calls_without_time_left() {
+        let start = Instant::now();
+        let context = ClientContext::from_deadline(RpcDeadline(Some(start)));
+        for now in [start, start + Duration::from_secs(1)] {
+            let error = context.headers_at(now).unwrap_err();
+            assert_eq!(error.code, RpcErrorCode::DeadlineExceeded);
+        }
+
+        // Inside the safety margin is too late already.
+        let context =
+            ClientContext::from_deadline(RpcDeadline(Some(start + Duration::from_millis(15))));
+        assert_eq!(
+            context.timeout_at(start + Duration::from_millis(5)),
+            Err(RpcError::new(
+                RpcErrorCode::DeadlineExceeded,
+                "Not enough time left before the deadline to make the call".to_string()
+            ))
+        );
+        assert_eq!(
+            context.timeout_at(start),
+            Ok(Some(Duration::from_millis(5)))
+        );
+    }
+
+    #[test]
+    fn passes_on_the_request_id_and_trace_context() {
+        let context = ClientContext::from_parts(&parts(&[
+            ("x-request-id", "req-1"),
+            (
+                "traceparent",
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            ),
+            ("tracestate", "vendor=1"),
+            ("authorization", "Bearer secret"),
+            ("connect-timeout-ms", "500"),
+        ]));
+        // No deadline in the extensions, so no timeout either.
+        let headers = context.headers().unwrap();
+        let names = headers.keys().map(|name| name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["x-request-id", "traceparent", "tracestate"]);
+        assert_eq!(headers["x-request-id"], "req-1");
+
+        let headers = ClientContext::from_parts(&parts(&[])).headers().unwrap();
+        assert!(headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn propagates_the_deadline_through_a_handler() {
+        async fn backend(
+            metadata: RpcMetadata,
+            _request: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            let get = |key| metadata.get(key).unwrap_or_default().to_string();
+            Ok(HelloResponse {
+                message: format!("{} {}", get("connect-timeout-ms"), get("x-request-id")),
+            })
+        }
+
+        async fn front(
+            State(backend): State<RpcClient>,
+            context: ClientContext,
+            request: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            backend
+                .with_headers(context.headers()?)
+                .unary(SAY_HELLO, request)
+                .await
+        }
+
+        let backend = builder(RpcRouter::new().rpc_method(unary(SAY_HELLO, backend))).build();
+        let front = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, front))
+            .with_state(backend);
+        let client = builder(front)
+            .metadata(
+                HeaderName::from_static("x-request-id"),
+                HeaderValue::from_static("req-1"),
+            )
+            .timeout(Duration::from_millis(800))
+            .build();
+
+        let response: HelloResponse = client.unary(SAY_HELLO, hello("Alec")).await.unwrap();
+        let (timeout, request_id) = response.message.split_once(' ').unwrap();
+        let timeout = timeout.parse::<u64>().unwrap();
+        assert!((1..=790).contains(&timeout), "{timeout}");
+        assert_eq!(request_id, "req-1");
+    }
+
+    /// Greets `count` times, counting the greetings in the `x-greetings` trailer, then fails if
+    /// the name is `fail`.
+    async fn counted_greetings(
+        trailers: RpcTrailers,
+        request: HelloRequest,
+    ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+        let end = (request.name == "fail").then(|| {
+            Err(RpcError::new(
+                RpcErrorCode::Aborted,
+                "Out of greetings".to_string(),
+            ))
+        });
+        let greetings = (1..=2).map(move |n| {
+            trailers.insert("x-greetings", HeaderValue::from(n));
+            Ok(HelloResponse {
+                message: format!("Hello {} #{n}!", request.name),
+            })
+        });
+        stream::iter(greetings.chain(end))
+    }
+
+    #[tokio::test]
+    async fn reads_the_trailers_once_the_stream_ends() {
+        let router =
+            || RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, counted_greetings));
+
+        let client = builder(router()).build();
+        let mut stream = client
+            .server_stream::<_, HelloResponse>(SAY_HELLO_STREAM, hello("Alec"))
+            .await
+            .unwrap();
+        assert_eq!(stream.trailers(), None);
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.message, "Hello Alec #1!");
+        assert_eq!(stream.trailers(), None);
+        assert_eq!(stream.collect_all(10).await.unwrap().len(), 1);
+        assert_eq!(stream.trailers().unwrap()["x-greetings"], "2");
+
+        // The error of the end-of-stream frame is the last item, with the trailers as metadata.
+        let client = builder(router()).build();
+        let mut stream = client
+            .server_stream::<_, HelloResponse>(SAY_HELLO_STREAM, hello("fail"))
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.next().await.unwrap().is_ok());
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::Aborted);
+        assert_eq!(error.message, "Out of greetings");
+        assert_eq!(error.metadata()["x-greetings"], "2");
+        assert_eq!(stream.trailers().unwrap()["x-greetings"], "2");
+        assert!(stream.next().await.is_none());
+    }
+
+    /// Greets once, then never again.
+    async fn stalled_greetings(
+        request: HelloRequest,
+    ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+        stream::once(say_hello(request)).chain(stream::pending())
+    }
+
+    #[tokio::test]
+    async fn times_out_waiting_for_the_next_message() {
+        let client = builder(
+            RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, stalled_greetings)),
+        )
+        .build();
+
+        let mut stream = client
+            .server_stream::<_, HelloResponse>(SAY_HELLO_STREAM, hello("Alec"))
+            .await
+            .unwrap()
+            .message_timeout(Duration::from_millis(50));
+        let first = stream.next().await.unwrap().unwrap();
+        assert_eq!(first.message, "Hello Alec!");
+        let started = Instant::now();
+        let error = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::DeadlineExceeded);
+        assert_eq!(error.message, "No message within 50ms");
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert!(stream.next().await.is_none());
+        assert_eq!(stream.trailers(), None);
+    }
+
+    #[tokio::test]
+    async fn times_out_the_whole_stream() {
+        /// A greeting every 20ms, forever.
+        async fn ticking_greetings(
+            request: HelloRequest,
+        ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::repeat(request.name).then(|name| async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                Ok(HelloResponse { message: name })
+            })
+        }
+
+        let client = builder(
+            RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, ticking_greetings)),
+        )
+        .build();
+        let mut stream = client
+            .server_stream::<_, HelloResponse>(SAY_HELLO_STREAM, hello("Alec"))
+            .await
+            .unwrap()
+            .message_timeout(Duration::from_secs(1))
+            .timeout(Duration::from_millis(100));
+        let error = stream.collect_all(1000).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::DeadlineExceeded);
+        assert_eq!(error.message, "The stream didn't end within 100ms");
+    }
+
+    #[tokio::test]
+    async fn takes_the_first_message_or_stops_at_the_limit() {
+        let client = builder(hello_router()).build();
+        let stream = || client.server_stream::<_, HelloResponse>(SAY_HELLO_STREAM, hello("Alec"));
+
+        let first = stream().await.unwrap().into_first().await.unwrap();
+        assert_eq!(first.unwrap().message, "Hello Alec #1!");
+
+        let error = stream().await.unwrap().collect_all(2).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+
+        let seen = AtomicUsize::new(0);
+        stream()
+            .await
+            .unwrap()
+            .try_for_each_concurrent(2, |_| {
+                seen.fetch_add(1, Ordering::Relaxed);
+                async { Ok(()) }
+            })
+            .await
+            .unwrap();
+        assert_eq!(seen.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,126 @@
+//! [`FetchClient`], sending the calls of an [`RpcClient`](super::RpcClient) with the browser's
+//! `fetch`.
+
+use axum::{
+    http::{self, HeaderName, HeaderValue},
+    BoxError,
+};
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream, FutureExt, StreamExt};
+use js_sys::{Array, Promise, Uint8Array};
+use send_wrapper::SendWrapper;
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Headers, Request, RequestInit, Response, Window, WorkerGlobalScope};
+
+use super::{ClientBody, HttpClient};
+
+/// An [`HttpClient`] over the browser's `fetch`, for clients compiled to `wasm32-unknown-unknown`,
+/// eg. in a Leptos or Yew frontend, in a page or a worker. Server streams are read off the
+/// response's `ReadableStream` as it arrives.
+///
+/// ```no_run
+/// # use axum_connect::client::{FetchClient, RpcClient};
+/// let client = RpcClient::builder("https://api.example.com", FetchClient::new()).build();
+/// ```
+///
+/// The futures and streams of `fetch` aren't `Send`, which is fine in the browser, where wasm
+/// runs on a single thread: they're wrapped to be `Send`, and would panic if polled from
+/// another thread.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FetchClient {
+    _private: (),
+}
+
+impl FetchClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl HttpClient for FetchClient {
+    fn send(
+        &self,
+        request: http::Request<Bytes>,
+    ) -> BoxFuture<'static, Result<http::Response<ClientBody>, BoxError>> {
+        SendWrapper::new(fetch(request)).boxed()
+    }
+}
+
+async fn fetch(request: http::Request<Bytes>) -> Result<http::Response<ClientBody>, BoxError> {
+    let (parts, body) = request.into_parts();
+    let headers = Headers::new().map_err(js_error)?;
+    for (name, value) in &parts.headers {
+        headers
+            .append(name.as_str(), value.to_str()?)
+            .map_err(js_error)?;
+    }
+    let init = RequestInit::new();
+    init.set_method(parts.method.as_str());
+    init.set_headers(&headers);
+    init.set_body(&Uint8Array::from(body.as_ref()));
+    let request =
+        Request::new_with_str_and_init(&parts.uri.to_string(), &init).map_err(js_error)?;
+
+    let response: Response = JsFuture::from(global_fetch(&request))
+        .await
+        .map_err(js_error)?
+        .dyn_into()
+        .map_err(js_error)?;
+
+    let mut head = http::Response::builder().status(response.status());
+    if let Some(entries) = js_sys::try_iter(response.headers().as_ref()).map_err(js_error)? {
+        for entry in entries {
+            let entry = Array::from(&entry.map_err(js_error)?);
+            let (Some(name), Some(value)) = (entry.get(0).as_string(), entry.get(1).as_string())
+            else {
+                continue;
+            };
+            head = head.header(HeaderName::try_from(name)?, HeaderValue::try_from(value)?);
+        }
+    }
+
+    let body = match response.body() {
+        Some(body) => {
+            let chunks = wasm_streams::ReadableStream::from_raw(body.unchecked_into())
+                .into_stream()
+                .map(|chunk| match chunk {
+                    Ok(chunk) => Ok(Bytes::from(Uint8Array::new(&chunk).to_vec())),
+                    Err(error) => Err(js_error(error)),
+                });
+            SendWrapper::new(chunks).boxed()
+        }
+        None => stream::empty().boxed(),
+    };
+    Ok(head.body(body)?)
+}
+
+/// `fetch` of the page, or of the worker without one.
+fn global_fetch(request: &Request) -> Promise {
+    let global = js_sys::global();
+    match global.dyn_ref::<Window>() {
+        Some(window) => window.fetch_with_request(request),
+        None => global
+            .unchecked_into::<WorkerGlobalScope>()
+            .fetch_with_request(request),
+    }
+}
+
+/// JS errors aren't `Send`, their description is.
+fn js_error(error: JsValue) -> BoxError {
+    match error.dyn_ref::<js_sys::Error>() {
+        Some(error) => String::from(error.message()).into(),
+        None => format!("{:?}", error).into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_an_http_client() {
+        fn http_client(_: Box<dyn HttpClient>) {}
+        http_client(Box::new(FetchClient::new()));
+    }
+}
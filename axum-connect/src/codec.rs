@@ -0,0 +1,1062 @@
+//! The Connect wire format without the HTTP around it: content types, envelopes, and the message
+//! and error encodings, as functions over byte slices. The handlers are built on these, and
+//! clients, proxies or test harnesses speaking Connect can use them too.
+//!
+//! This API is semi-stable. It follows the protocol, so it only changes with it, and breaking
+//! changes come with a minor version (and a changelog entry) rather than a major one.
+//!
+//! https://connectrpc.com/docs/protocol/
+
+use std::any::TypeId;
+
+use axum::http::{header, Extensions, HeaderMap, HeaderName, HeaderValue};
+use bytes::Bytes;
+use prost::Message;
+use serde::{de::Error as _, Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::config::RpcConfig;
+use crate::error::{RpcError, RpcErrorCode};
+use crate::response::RpcResult;
+
+pub use crate::handler::{RpcJsonDecode, RpcJsonEncode};
+pub use crate::response::EndStreamResponse;
+
+/// The envelope flag of a compressed message.
+pub const COMPRESSED_FLAG: u8 = 0x01;
+
+/// Gzips the payload of an envelope, which then has the [`COMPRESSED_FLAG`], in a stream with a
+/// `connect-content-encoding` of `gzip`.
+#[cfg(feature = "stream-compression")]
+pub fn gzip(payload: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(
+        Vec::with_capacity(payload.len() / 2),
+        flate2::Compression::default(),
+    );
+    // Writing to a `Vec` doesn't fail.
+    encoder.write_all(payload).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// The payload of an envelope compressed with [`gzip`]. Fails with `Internal` if it isn't gzip,
+/// servers don't send them.
+#[cfg(feature = "stream-compression")]
+pub fn gunzip(payload: &[u8]) -> RpcResult<Vec<u8>> {
+    use std::io::Read;
+
+    let mut decompressed = vec![];
+    flate2::read::GzDecoder::new(payload)
+        .read_to_end(&mut decompressed)
+        .map_err(|error| {
+            RpcError::new(
+                RpcErrorCode::Internal,
+                format!("Invalid gzip envelope: {}", error),
+            )
+        })?;
+    Ok(decompressed)
+}
+
+/// The Connect content types, the codec and framing of a request body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    Json,
+    Proto,
+    ConnectJson,
+    ConnectProto,
+}
+
+/// The parsed `Content-Type` of a request, cached in its extensions by [`ContentType::cached`].
+#[derive(Clone, Copy)]
+struct RequestContentType(Option<ContentType>);
+
+impl ContentType {
+    const ALL: [ContentType; 4] = [
+        ContentType::Json,
+        ContentType::Proto,
+        ContentType::ConnectJson,
+        ContentType::ConnectProto,
+    ];
+
+    /// Parses a `Content-Type` header value, `None` if it isn't a Connect content type.
+    pub fn parse(value: &[u8]) -> Option<Self> {
+        // Clients almost always send these exactly, so a byte comparison does.
+        match value {
+            b"application/json" => return Some(ContentType::Json),
+            b"application/proto" => return Some(ContentType::Proto),
+            b"application/connect+json" => return Some(ContentType::ConnectJson),
+            b"application/connect+proto" => return Some(ContentType::ConnectProto),
+            _ => {}
+        }
+
+        // Parameters (eg. `; charset=utf-8`) or uppercase letters.
+        let media_type = std::str::from_utf8(value).ok()?.split(';').next()?.trim();
+        Self::ALL
+            .into_iter()
+            .find(|content_type| media_type.eq_ignore_ascii_case(content_type.as_str()))
+    }
+
+    /// The request's content type, parsed the first time it's asked for.
+    pub(crate) fn cached(extensions: &mut Extensions, headers: &HeaderMap) -> Option<Self> {
+        if let Some(RequestContentType(content_type)) = extensions.get() {
+            return *content_type;
+        }
+
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| Self::parse(value.as_bytes()));
+        extensions.insert(RequestContentType(content_type));
+        content_type
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ContentType::Json => "application/json",
+            ContentType::Proto => "application/proto",
+            ContentType::ConnectJson => "application/connect+json",
+            ContentType::ConnectProto => "application/connect+proto",
+        }
+    }
+
+    pub fn is_streaming(self) -> bool {
+        matches!(self, ContentType::ConnectJson | ContentType::ConnectProto)
+    }
+
+    pub fn is_binary(self) -> bool {
+        matches!(self, ContentType::Proto | ContentType::ConnectProto)
+    }
+}
+
+/// A single envelope of a stream: its flags and payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub flags: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    /// Whether this is the end-of-stream frame, whose payload is an [`EndStreamResponse`].
+    pub fn is_end_stream(&self) -> bool {
+        self.flags & EndStreamResponse::FLAG != 0
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags & COMPRESSED_FLAG != 0
+    }
+}
+
+/// Splits a stream body into its envelopes (flags u8, big-endian length u32, payload), however
+/// the bytes are chunked. Push bytes as they arrive and take frames until there's none left.
+///
+/// ```
+/// # use axum_connect::codec::FrameDecoder;
+/// let mut decoder = FrameDecoder::new();
+/// decoder.push(&[0, 0, 0, 0, 2, b'{']);
+/// assert!(matches!(decoder.next_frame(), Ok(None)));
+///
+/// decoder.push(b"}");
+/// assert!(matches!(decoder.next_frame(), Ok(Some(frame)) if frame.payload == b"{}"));
+/// assert!(decoder.finish().is_ok());
+/// ```
+///
+/// For passing a body on as it arrives, [`pass`](FrameDecoder::pass) checks the envelopes
+/// without buffering their payloads instead.
+#[derive(Clone, Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+    /// Where the next frame starts in `buffer`.
+    offset: usize,
+    max_frame_bytes: Option<usize>,
+    max_frames: Option<usize>,
+    max_total_bytes: Option<usize>,
+    /// Frames taken so far.
+    frames: usize,
+    /// Bytes pushed so far.
+    total_bytes: usize,
+    /// Payload bytes of the envelope being passed that are still to come.
+    passing: usize,
+    /// Whether the end-of-stream frame has been passed.
+    passed_end: bool,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Frames announcing a larger payload fail with `ResourceExhausted`, before it's buffered.
+    pub fn max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.max_frame_bytes = Some(max_frame_bytes);
+        self
+    }
+
+    /// Streams of more frames fail with `ResourceExhausted` at the first one over the limit.
+    pub fn max_frames(mut self, max_frames: usize) -> Self {
+        self.max_frames = Some(max_frames);
+        self
+    }
+
+    /// Streams of more bytes, envelope prefixes included, fail with `ResourceExhausted` once
+    /// they're pushed, before the frames in them are taken.
+    pub fn max_total_bytes(mut self, max_total_bytes: usize) -> Self {
+        self.max_total_bytes = Some(max_total_bytes);
+        self
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.total_bytes += bytes.len();
+        // Drop the frames already taken, rather than growing forever on long streams.
+        if self.offset > 0 {
+            self.buffer.drain(..self.offset);
+            self.offset = 0;
+        }
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// The next complete frame, `None` if more bytes are needed for it.
+    pub fn next_frame(&mut self) -> RpcResult<Option<Frame>> {
+        if let Some(max) = self.max_total_bytes.filter(|max| self.total_bytes > *max) {
+            return Err(RpcError::new(
+                RpcErrorCode::ResourceExhausted,
+                format!("Stream is larger than the {} byte limit", max),
+            ));
+        }
+        let bytes = &self.buffer[self.offset..];
+        if bytes.len() < 5 {
+            return Ok(None);
+        }
+        if let Some(max) = self.max_frames.filter(|max| self.frames >= *max) {
+            return Err(RpcError::new(
+                RpcErrorCode::ResourceExhausted,
+                format!("Stream has more than the {} frame limit", max),
+            ));
+        }
+
+        let size = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+        if let Some(max) = self.max_frame_bytes.filter(|max| size > *max) {
+            return Err(RpcError::new(
+                RpcErrorCode::ResourceExhausted,
+                format!(
+                    "Envelope of {} bytes is larger than the {} byte limit",
+                    size, max
+                ),
+            ));
+        }
+        if bytes.len() - 5 < size {
+            return Ok(None);
+        }
+
+        let frame = Frame {
+            flags: bytes[0],
+            payload: bytes[5..5 + size].to_vec(),
+        };
+        self.offset += 5 + size;
+        self.frames += 1;
+        Ok(Some(frame))
+    }
+
+    /// Checks the envelopes in `bytes`, the next chunk of the stream, without keeping their
+    /// payloads, and returns what can be passed on: `bytes`, less a partial envelope prefix at its
+    /// end, which goes in front of the next chunk once it can be checked. Not to be mixed with
+    /// [`push`](FrameDecoder::push) on the same decoder.
+    ///
+    /// Envelopes with unknown flags fail with `InvalidArgument`, and so do bytes after the
+    /// end-of-stream frame; larger than `max_frame_bytes` fails with `ResourceExhausted`. The error
+    /// comes with the bytes in front of the envelope at fault, the ones still good to pass on.
+    ///
+    /// ```
+    /// # use axum_connect::codec::FrameDecoder;
+    /// # use bytes::Bytes;
+    /// let mut decoder = FrameDecoder::new();
+    /// let passed = decoder.pass(Bytes::from_static(&[0, 0, 0, 0, 1, b'a', 0x80, 0, 0]));
+    /// assert_eq!(passed.unwrap(), &[0, 0, 0, 0, 1, b'a'][..]);
+    ///
+    /// let (passed, error) = decoder.pass(Bytes::from_static(&[0, 0])).unwrap_err();
+    /// assert!(passed.is_empty());
+    /// assert_eq!(error.message, "Unknown envelope flags 0x80");
+    /// ```
+    pub fn pass(&mut self, bytes: Bytes) -> Result<Bytes, (Bytes, RpcError)> {
+        let bytes = match self.buffer.is_empty() {
+            true => bytes,
+            false => {
+                let mut held = std::mem::take(&mut self.buffer);
+                held.extend_from_slice(&bytes);
+                Bytes::from(held)
+            }
+        };
+
+        let mut at = 0;
+        loop {
+            let passing = self.passing.min(bytes.len() - at);
+            self.passing -= passing;
+            at += passing;
+            if at == bytes.len() {
+                return Ok(bytes);
+            }
+            if self.passed_end {
+                return Err((
+                    bytes.slice(..at),
+                    RpcError::new(
+                        RpcErrorCode::InvalidArgument,
+                        "Bytes after the end-of-stream frame".to_string(),
+                    ),
+                ));
+            }
+            if bytes.len() - at < 5 {
+                self.buffer.extend_from_slice(&bytes[at..]);
+                return Ok(bytes.slice(..at));
+            }
+
+            let flags = bytes[at];
+            let size =
+                u32::from_be_bytes([bytes[at + 1], bytes[at + 2], bytes[at + 3], bytes[at + 4]])
+                    as usize;
+            if flags & !(COMPRESSED_FLAG | EndStreamResponse::FLAG) != 0 {
+                return Err((
+                    bytes.slice(..at),
+                    RpcError::new(
+                        RpcErrorCode::InvalidArgument,
+                        format!("Unknown envelope flags {:#04x}", flags),
+                    ),
+                ));
+            }
+            if let Some(max) = self.max_frame_bytes.filter(|max| size > *max) {
+                return Err((
+                    bytes.slice(..at),
+                    RpcError::new(
+                        RpcErrorCode::ResourceExhausted,
+                        format!(
+                            "Envelope of {} bytes is larger than the {} byte limit",
+                            size, max
+                        ),
+                    ),
+                ));
+            }
+            self.passed_end = flags & EndStreamResponse::FLAG != 0;
+            self.passing = size;
+            at += 5;
+        }
+    }
+
+    /// Whether the end-of-stream frame has been [`pass`](FrameDecoder::pass)ed.
+    pub fn passed_end_stream(&self) -> bool {
+        self.passed_end
+    }
+
+    /// Fails with `InvalidArgument` if the stream ended in the middle of a frame.
+    pub fn finish(self) -> RpcResult<()> {
+        if self.passing > 0 {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!("Truncated envelope, {} payload bytes missing", self.passing),
+            ));
+        }
+        let rest = &self.buffer[self.offset..];
+        match rest.len() {
+            0 => Ok(()),
+            1..5 => Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "Truncated envelope, expected at least 5 bytes but got {}",
+                    rest.len()
+                ),
+            )),
+            _ => Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "Truncated envelope, expected {} payload bytes but got {}",
+                    u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]),
+                    rest.len() - 5
+                ),
+            )),
+        }
+    }
+}
+
+/// Parses a single streaming request envelope and returns the payload. Bytes after it are
+/// ignored, a server-streaming request only has the one.
+///
+/// https://connectrpc.com/docs/protocol/#streaming-request
+pub fn decode_envelope(bytes: &[u8]) -> RpcResult<&[u8]> {
+    if bytes.len() < 5 {
+        return Err(RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!(
+                "Truncated envelope, expected at least 5 bytes but got {}",
+                bytes.len()
+            ),
+        ));
+    }
+
+    let flags = bytes[0];
+    if flags & COMPRESSED_FLAG != 0 {
+        return Err(RpcError::new(
+            RpcErrorCode::Unimplemented,
+            "Compressed request envelopes are not supported".to_string(),
+        ));
+    }
+
+    let size = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]) as usize;
+    let payload = &bytes[5..];
+    if payload.len() < size {
+        return Err(RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!(
+                "Truncated envelope, expected {} payload bytes but got {}",
+                size,
+                payload.len()
+            ),
+        ));
+    }
+
+    Ok(&payload[..size])
+}
+
+/// Appends `payload` to `buffer` in an envelope with `flags`.
+pub fn encode_envelope(flags: u8, payload: &[u8], buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&envelope_prefix(flags, payload.len()));
+    buffer.extend_from_slice(payload);
+}
+
+/// The 5 bytes in front of a payload of `size` bytes, for writing the two separately.
+pub fn envelope_prefix(flags: u8, size: usize) -> [u8; 5] {
+    let size = (size as u32).to_be_bytes();
+    [flags, size[0], size[1], size[2], size[3]]
+}
+
+/// Decodes the body of a unary request, binary protobuf or JSON. Failures are `InvalidArgument`s
+/// saying what's wrong, including a body in the other codec than the content type says (with
+/// [`RpcConfig::detect_codec_mismatch`]).
+pub fn decode_unary_request<M>(bytes: &[u8], binary: bool, config: &RpcConfig) -> RpcResult<M>
+where
+    M: Message + RpcJsonDecode + Default,
+{
+    decode_message(bytes, binary, false, config)
+}
+
+/// Decodes the body of a server-streaming request, the message in its single envelope.
+pub fn decode_stream_request<M>(bytes: &[u8], binary: bool, config: &RpcConfig) -> RpcResult<M>
+where
+    M: Message + RpcJsonDecode + Default,
+{
+    decode_message(decode_envelope(bytes)?, binary, true, config)
+}
+
+/// Decodes the body of a unary request sent with a streaming Content-Type, see
+/// [`RpcConfig::adapt_streaming_unary`]. Like [`decode_stream_request`], except that more than one
+/// message envelope fails with `InvalidArgument`. An end-of-stream envelope is ignored.
+pub fn decode_single_frame_request<M>(
+    bytes: &[u8],
+    binary: bool,
+    config: &RpcConfig,
+) -> RpcResult<M>
+where
+    M: Message + RpcJsonDecode + Default,
+{
+    let mut decoder = FrameDecoder::new();
+    decoder.push(bytes);
+    let mut messages = vec![];
+    while let Some(frame) = decoder.next_frame()? {
+        if !frame.is_end_stream() {
+            messages.push(frame);
+        }
+    }
+    decoder.finish()?;
+
+    let message = match messages.len() {
+        1 => messages.remove(0),
+        count => {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "This method is unary, it takes a single request message, not {}",
+                    count
+                ),
+            ))
+        }
+    };
+    if message.is_compressed() {
+        return Err(RpcError::new(
+            RpcErrorCode::Unimplemented,
+            "Compressed request envelopes are not supported".to_string(),
+        ));
+    }
+    decode_message(&message.payload, binary, true, config)
+}
+
+/// Decodes a frame of a client-streaming request, one of its messages. Clients end their stream by
+/// closing the body, so an end-of-stream frame fails with `InvalidArgument`.
+pub fn decode_request_frame<M>(frame: &Frame, binary: bool, config: &RpcConfig) -> RpcResult<M>
+where
+    M: Message + RpcJsonDecode + Default,
+{
+    if frame.is_end_stream() {
+        return Err(RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            "Request streams end with the body, not an end-of-stream envelope".to_string(),
+        ));
+    }
+    if frame.is_compressed() {
+        return Err(RpcError::new(
+            RpcErrorCode::Unimplemented,
+            "Compressed request envelopes are not supported".to_string(),
+        ));
+    }
+    decode_message(&frame.payload, binary, true, config)
+}
+
+/// The still-encoded message of a unary GET request, from its `message` and `base64` query
+/// parameters.
+///
+/// https://connectrpc.com/docs/protocol/#unary-get-request
+pub fn decode_query_message(message: &str, base64: bool) -> RpcResult<Vec<u8>> {
+    if !base64 {
+        return Ok(message.as_bytes().to_vec());
+    }
+
+    use base64::{engine::general_purpose, Engine as _};
+    general_purpose::URL_SAFE.decode(message).map_err(|err| {
+        RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!("Wrong query.message, {}", err),
+        )
+    })
+}
+
+/// Appends the body of a unary response to `buffer`.
+///
+/// Unary bodies and stream envelopes both go through here, so JSON streams get the same pbjson
+/// Serialize impls as unary responses (64-bit integers as strings, enums by name). Error details
+/// are always binary protobuf, base64 encoded, so they can't lose precision either.
+pub fn encode_unary_response<M: RpcJsonEncode + Message>(
+    message: &M,
+    binary: bool,
+    buffer: &mut Vec<u8>,
+) -> RpcResult<()> {
+    if binary {
+        message.encode(buffer).map_err(|error| {
+            RpcError::new(
+                RpcErrorCode::Internal,
+                format!("Failed to serialize response: {error}"),
+            )
+        })
+    } else {
+        message.rpc_json_encode_into(buffer)
+    }
+}
+
+/// Decodes the body of a successful unary response, the reverse of [`encode_unary_response`]. An
+/// empty JSON body is a `google.protobuf.Empty`, what servers with
+/// [`RpcConfig::empty_response_body`] send (in binary, an empty body is any empty message).
+pub fn decode_unary_response<M>(bytes: &[u8], binary: bool) -> RpcResult<M>
+where
+    M: Message + RpcJsonDecode + Default + 'static,
+{
+    if binary {
+        decode_binary_message(bytes)
+    } else if bytes.is_empty() && TypeId::of::<M>() == TypeId::of::<pbjson_types::Empty>() {
+        Ok(M::default())
+    } else {
+        M::rpc_json_decode(bytes)
+    }
+}
+
+/// Appends a message of a response stream to `buffer`, in its envelope.
+pub fn encode_stream_response<M: RpcJsonEncode + Message>(
+    message: &M,
+    binary: bool,
+    buffer: &mut Vec<u8>,
+) -> RpcResult<()> {
+    let start = buffer.len();
+    buffer.extend_from_slice(&[0, 0, 0, 0, 0]);
+    encode_unary_response(message, binary, buffer)?;
+
+    let size = ((buffer.len() - start - 5) as u32).to_be_bytes();
+    buffer[start + 1..start + 5].copy_from_slice(&size);
+    Ok(())
+}
+
+/// The body of a unary error response. Errors in unary calls are ALWAYS encoded as JSON, their
+/// metadata goes in the headers.
+///
+/// https://connectrpc.com/docs/protocol/#unary-response
+pub fn encode_unary_error(error: &RpcError) -> Vec<u8> {
+    serde_json::to_vec(error).unwrap()
+}
+
+/// `json` with the keys of every object sorted, map entries included, so the same message always
+/// encodes to the same bytes; see [`RpcConfig::stable_json_field_order`]. Anything that isn't JSON
+/// is returned as it is.
+pub fn sort_json_keys(json: &[u8]) -> Vec<u8> {
+    match serde_json::from_slice::<Value>(json) {
+        Ok(value) => serde_json::to_vec(&SortedKeys(&value)).unwrap(),
+        Err(_) => json.to_vec(),
+    }
+}
+
+/// Serializes objects with their keys sorted, even with serde_json's `preserve_order` feature on.
+struct SortedKeys<'a>(&'a Value);
+
+impl Serialize for SortedKeys<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0 {
+            Value::Array(values) => serializer.collect_seq(values.iter().map(SortedKeys)),
+            Value::Object(object) => {
+                let mut entries = object.iter().collect::<Vec<_>>();
+                entries.sort_unstable_by_key(|(key, _)| *key);
+                serializer.collect_map(
+                    entries
+                        .into_iter()
+                        .map(|(key, value)| (key, SortedKeys(value))),
+                )
+            }
+            value => value.serialize(serializer),
+        }
+    }
+}
+
+/// Parses the body of a unary error response, the reverse of [`encode_unary_error`]. Unknown codes
+/// are read as `Unknown`.
+pub fn parse_unary_error(bytes: &[u8]) -> Result<RpcError, serde_json::Error> {
+    serde_json::from_slice(bytes)
+}
+
+/// Parses the payload of an end-of-stream frame, the reverse of [`EndStreamResponse::encode`]
+/// without the envelope. The error, if any, gets the trailing metadata too.
+pub fn parse_end_stream(payload: &[u8]) -> Result<EndStreamResponse, serde_json::Error> {
+    #[derive(Deserialize)]
+    struct Wire {
+        #[serde(default)]
+        error: Option<RpcError>,
+        #[serde(default)]
+        metadata: std::collections::BTreeMap<String, Vec<String>>,
+    }
+
+    let wire: Wire = serde_json::from_slice(payload)?;
+    let mut metadata = HeaderMap::new();
+    for (key, values) in wire.metadata {
+        let key = HeaderName::try_from(key).map_err(serde_json::Error::custom)?;
+        for value in values {
+            let value = HeaderValue::try_from(value).map_err(serde_json::Error::custom)?;
+            metadata.append(key.clone(), value);
+        }
+    }
+
+    let error = wire
+        .error
+        .map(|error| error.with_metadata(metadata.clone()));
+    Ok(EndStreamResponse { error, metadata })
+}
+
+fn decode_message<M>(
+    bytes: &[u8],
+    binary: bool,
+    streaming: bool,
+    config: &RpcConfig,
+) -> RpcResult<M>
+where
+    M: Message + RpcJsonDecode + Default,
+{
+    let (proto, json) = if streaming {
+        (ContentType::ConnectProto, ContentType::ConnectJson)
+    } else {
+        (ContentType::Proto, ContentType::Json)
+    };
+
+    if binary {
+        if config.detect_codec_mismatch && looks_like_json(bytes) {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "The body is JSON, but the Content-Type is {}. Send JSON as {}",
+                    proto.as_str(),
+                    json.as_str()
+                ),
+            ));
+        }
+
+        decode_binary_message(bytes)
+    } else {
+        M::rpc_json_decode(bytes).map_err(|mut error| {
+            // Not when the JSON codec is disabled, that error says what to do already.
+            if error.code == RpcErrorCode::InvalidArgument && std::str::from_utf8(bytes).is_err() {
+                error.message = format!(
+                    "The body isn't valid UTF-8, so it can't be JSON. Send binary protobuf as {}",
+                    proto.as_str()
+                );
+            }
+            error
+        })
+    }
+}
+
+/// Decodes a single binary protobuf message.
+///
+/// Decode failures are always the client's fault, so they map to `InvalidArgument`. Prost's error
+/// already names the message/field stack; we add the byte offset decoding stopped at. The raw body
+/// bytes are never echoed back.
+pub(crate) fn decode_binary_message<M: Message + Default>(bytes: &[u8]) -> RpcResult<M> {
+    let mut buf = bytes;
+
+    M::decode(&mut buf).map_err(|e| {
+        RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!(
+                "Failed to decode binary protobuf at byte offset {}. {}",
+                bytes.len() - buf.len(),
+                e
+            ),
+        )
+    })
+}
+
+/// Whether a body sent as binary protobuf is actually JSON. Binary messages can start with `{`,
+/// `[` or whitespace too, so it only counts if the whole body parses as JSON.
+fn looks_like_json(bytes: &[u8]) -> bool {
+    let first = bytes.iter().find(|b| !b.is_ascii_whitespace());
+    matches!(first, Some(b'{' | b'['))
+        && serde_json::from_slice::<serde::de::IgnoredAny>(bytes).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{HelloRequest, HelloResponse};
+
+    /// A length-delimited `name` whose length varint never ends.
+    const TRUNCATED_VARINT: &[u8] = &[0x0a, 0xff, 0xff];
+    /// `name` (a string) as a varint.
+    const WRONG_WIRE_TYPE: &[u8] = &[0x08, 0x96, 0x01];
+
+    fn hello_request(name: &str) -> HelloRequest {
+        HelloRequest {
+            name: name.to_string(),
+        }
+    }
+
+    fn unary_error(body: &[u8]) -> RpcError {
+        decode_unary_request::<HelloRequest>(body, true, &RpcConfig::default()).unwrap_err()
+    }
+
+    #[test]
+    fn truncated_varints_are_invalid_arguments() {
+        let error = unary_error(TRUNCATED_VARINT);
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert!(
+            error
+                .message
+                .starts_with("Failed to decode binary protobuf at byte offset"),
+            "{}",
+            error.message
+        );
+        assert!(
+            error.message.contains("HelloRequest.name"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn wrong_wire_types_are_invalid_arguments() {
+        let error = unary_error(WRONG_WIRE_TYPE);
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert!(
+            error.message.contains("invalid wire type"),
+            "{}",
+            error.message
+        );
+        assert!(
+            error.message.contains("HelloRequest.name"),
+            "{}",
+            error.message
+        );
+        // The body isn't echoed back, in any form.
+        assert!(!error.message.contains("150"), "{}", error.message);
+    }
+
+    #[test]
+    fn corrupt_frames_are_invalid_arguments() {
+        let mut body = vec![];
+        encode_envelope(0, WRONG_WIRE_TYPE, &mut body);
+        let error =
+            decode_stream_request::<HelloRequest>(&body, true, &RpcConfig::default()).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert!(
+            error.message.contains("invalid wire type"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn decodes_unary_and_stream_requests() {
+        let config = RpcConfig::default();
+        let bytes = hello_request("Ada").encode_to_vec();
+        let request: HelloRequest = decode_unary_request(&bytes, true, &config).unwrap();
+        assert_eq!(request, hello_request("Ada"));
+
+        let mut body = vec![];
+        encode_envelope(0, &bytes, &mut body);
+        let request: HelloRequest = decode_stream_request(&body, true, &config).unwrap();
+        assert_eq!(request, hello_request("Ada"));
+    }
+
+    #[test]
+    fn rejects_json_sent_as_binary() {
+        let error = unary_error(br#"{"name": "Ada"}"#);
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(
+            error.message,
+            "The body is JSON, but the Content-Type is application/proto. Send JSON as application/json"
+        );
+
+        let mut body = vec![];
+        encode_envelope(0, br#"{"name": "Ada"}"#, &mut body);
+        let error =
+            decode_stream_request::<HelloRequest>(&body, true, &RpcConfig::default()).unwrap_err();
+        assert_eq!(
+            error.message,
+            "The body is JSON, but the Content-Type is application/connect+proto. Send JSON as application/connect+json"
+        );
+    }
+
+    #[test]
+    fn single_frame_requests_take_exactly_one_message() {
+        let config = RpcConfig::default();
+        let mut body = vec![];
+        encode_envelope(0, &hello_request("Ada").encode_to_vec(), &mut body);
+        body.extend(EndStreamResponse::default().encode());
+        let request: HelloRequest = decode_single_frame_request(&body, true, &config).unwrap();
+        assert_eq!(request, hello_request("Ada"));
+
+        encode_envelope(0, &hello_request("Bob").encode_to_vec(), &mut body);
+        let error = decode_single_frame_request::<HelloRequest>(&body, true, &config).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(
+            error.message,
+            "This method is unary, it takes a single request message, not 2"
+        );
+    }
+
+    #[test]
+    fn parses_content_types() {
+        assert_eq!(
+            ContentType::parse(b"application/proto"),
+            Some(ContentType::Proto)
+        );
+        assert_eq!(
+            ContentType::parse(b"Application/JSON; charset=utf-8"),
+            Some(ContentType::Json)
+        );
+        assert_eq!(
+            ContentType::parse(b"application/connect+proto ;foo=bar"),
+            Some(ContentType::ConnectProto)
+        );
+        assert_eq!(ContentType::parse(b"application/grpc"), None);
+        assert_eq!(ContentType::parse(&[0xff, 0xfe]), None);
+
+        assert!(ContentType::ConnectJson.is_streaming());
+        assert!(!ContentType::ConnectJson.is_binary());
+        assert!(ContentType::Proto.is_binary());
+        assert!(!ContentType::Proto.is_streaming());
+    }
+
+    #[test]
+    fn frames_are_the_same_however_the_bytes_are_chunked() {
+        let mut body = vec![];
+        encode_envelope(0, b"first", &mut body);
+        encode_envelope(0, b"", &mut body);
+        encode_envelope(EndStreamResponse::FLAG, b"{}", &mut body);
+
+        let mut whole = FrameDecoder::new();
+        whole.push(&body);
+        let mut expected = vec![];
+        while let Some(frame) = whole.next_frame().unwrap() {
+            expected.push(frame);
+        }
+        whole.finish().unwrap();
+        assert_eq!(expected.len(), 3);
+        assert!(expected[2].is_end_stream());
+
+        let mut chunked = FrameDecoder::new();
+        let mut frames = vec![];
+        for byte in &body {
+            chunked.push(&[*byte]);
+            while let Some(frame) = chunked.next_frame().unwrap() {
+                frames.push(frame);
+            }
+        }
+        chunked.finish().unwrap();
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn streams_ending_mid_frame_are_truncated() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[0, 0, 0]);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        assert_eq!(
+            decoder.finish().unwrap_err().message,
+            "Truncated envelope, expected at least 5 bytes but got 3"
+        );
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&[0, 0, 0, 0, 4, b'a']);
+        assert_eq!(decoder.next_frame().unwrap(), None);
+        assert_eq!(
+            decoder.finish().unwrap_err().message,
+            "Truncated envelope, expected 4 payload bytes but got 1"
+        );
+    }
+
+    #[test]
+    fn frame_decoder_limits_are_resource_exhausted() {
+        let mut body = vec![];
+        encode_envelope(0, b"abc", &mut body);
+        encode_envelope(0, b"abc", &mut body);
+
+        let mut decoder = FrameDecoder::new().max_frame_bytes(2);
+        decoder.push(&body);
+        let error = decoder.next_frame().unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+        assert_eq!(
+            error.message,
+            "Envelope of 3 bytes is larger than the 2 byte limit"
+        );
+
+        let mut decoder = FrameDecoder::new().max_frames(1);
+        decoder.push(&body);
+        assert!(decoder.next_frame().unwrap().is_some());
+        assert_eq!(
+            decoder.next_frame().unwrap_err().message,
+            "Stream has more than the 1 frame limit"
+        );
+
+        let mut decoder = FrameDecoder::new().max_total_bytes(10);
+        decoder.push(&body);
+        assert_eq!(
+            decoder.next_frame().unwrap_err().message,
+            "Stream is larger than the 10 byte limit"
+        );
+    }
+
+    #[test]
+    fn envelopes_round_trip() {
+        let mut body = vec![];
+        encode_envelope(0, b"payload", &mut body);
+        assert_eq!(&body[..5], envelope_prefix(0, 7));
+        // Bytes after the envelope are ignored.
+        body.extend_from_slice(b"rest");
+        assert_eq!(decode_envelope(&body).unwrap(), b"payload");
+
+        assert_eq!(
+            decode_envelope(&[0, 0, 0]).unwrap_err().message,
+            "Truncated envelope, expected at least 5 bytes but got 3"
+        );
+        assert_eq!(
+            decode_envelope(&[0, 0, 0, 0, 2, b'a']).unwrap_err().message,
+            "Truncated envelope, expected 2 payload bytes but got 1"
+        );
+        let error = decode_envelope(&[1, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::Unimplemented);
+    }
+
+    #[test]
+    fn decodes_query_messages() {
+        assert_eq!(
+            decode_query_message(r#"{"name":"Ada"}"#, false).unwrap(),
+            br#"{"name":"Ada"}"#
+        );
+        let bytes = hello_request("Ada").encode_to_vec();
+        use base64::{engine::general_purpose, Engine as _};
+        let encoded = general_purpose::URL_SAFE.encode(&bytes);
+        assert_eq!(decode_query_message(&encoded, true).unwrap(), bytes);
+
+        let error = decode_query_message("not base64!", true).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert!(
+            error.message.starts_with("Wrong query.message, "),
+            "{}",
+            error.message
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        let response = HelloResponse {
+            message: "Hello Ada!".to_string(),
+        };
+        let mut bytes = vec![];
+        encode_unary_response(&response, true, &mut bytes).unwrap();
+        assert_eq!(
+            decode_unary_response::<HelloResponse>(&bytes, true).unwrap(),
+            response
+        );
+
+        let mut body = vec![];
+        encode_stream_response(&response, true, &mut body).unwrap();
+        assert_eq!(decode_envelope(&body).unwrap(), bytes);
+    }
+
+    #[test]
+    fn errors_round_trip() {
+        let error = RpcError::new(RpcErrorCode::NotFound, "No such user".to_string());
+        let parsed = parse_unary_error(&encode_unary_error(&error)).unwrap();
+        assert_eq!(parsed, error);
+
+        let mut metadata = HeaderMap::new();
+        metadata.append("x-retry", HeaderValue::from_static("1"));
+        metadata.append("x-retry", HeaderValue::from_static("2"));
+        let end = EndStreamResponse::error(error.clone().with_metadata(metadata.clone()));
+        let parsed = parse_end_stream(&end.encode()[5..]).unwrap();
+        assert_eq!(parsed.metadata, metadata);
+        assert_eq!(parsed.error, Some(error.with_metadata(metadata)));
+
+        let parsed = parse_end_stream(b"{}").unwrap();
+        assert!(parsed.error.is_none() && parsed.metadata.is_empty());
+    }
+
+    #[cfg(not(feature = "json"))]
+    #[tokio::test]
+    async fn rejects_json_without_the_json_feature() {
+        use crate::test_util::{client, hello, hello_router, message, proto_request, SAY_HELLO};
+
+        let client = client(hello_router());
+        let response = client
+            .post(SAY_HELLO, "application/json", b"{}".to_vec())
+            .await;
+        let error = response.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::Unimplemented);
+        assert_eq!(
+            error.message,
+            "The JSON codec is disabled on this server, use application/proto instead"
+        );
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+    }
+
+    #[test]
+    fn reports_the_offset_decoding_stopped_at() {
+        // A valid `name`, then the truncated one.
+        let mut bytes = vec![0x0a, 0x01, b'a'];
+        bytes.extend_from_slice(TRUNCATED_VARINT);
+        let error = decode_binary_message::<HelloRequest>(&bytes).unwrap_err();
+        assert!(
+            error
+                .message
+                .starts_with("Failed to decode binary protobuf at byte offset "),
+            "{}",
+            error.message
+        );
+        let offset: usize = error.message
+            ["Failed to decode binary protobuf at byte offset ".len()..]
+            .split('.')
+            .next()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((3..=bytes.len()).contains(&offset), "{}", error.message);
+    }
+}
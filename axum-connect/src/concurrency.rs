@@ -0,0 +1,408 @@
+//! Limits on in-flight calls, per route (see [`RouteOptions::concurrency_limit`]) and per
+//! connection (see [`RpcConfig::max_calls_per_connection`]).
+//!
+//! [`RpcConfig::max_calls_per_connection`]: crate::config::RpcConfig::max_calls_per_connection
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use axum::http::{request, HeaderMap, HeaderValue};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    config::RpcConfig,
+    error::{RpcError, RpcErrorCode},
+    parts::peer_address,
+    router::{RouteOptions, RpcMethodInfo},
+};
+
+/// The calls in flight on each connection, by peer address, of the routes a config with
+/// [`RpcConfig::max_calls_per_connection`] was applied to. Each config counts its own calls, so
+/// routers with different limits in one process don't use up each other's.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionCalls {
+    calls: Arc<Mutex<BTreeMap<SocketAddr, usize>>>,
+    /// Whether a call without a peer address was warned about.
+    warned: Arc<AtomicBool>,
+}
+
+/// The semaphores of a group of routes mounted with a concurrency limit, one per RPC path so the
+/// POST and GET routes of a method share theirs.
+#[derive(Clone)]
+pub(crate) struct ConcurrencyLimits {
+    limit: usize,
+    queue: usize,
+    routes: Arc<Mutex<HashMap<String, Arc<RouteLimit>>>>,
+}
+
+struct RouteLimit {
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+}
+
+impl ConcurrencyLimits {
+    /// The limits for `options`, `None` if it has no concurrency limit.
+    pub fn new(options: &RouteOptions) -> Option<Self> {
+        Some(Self {
+            limit: options.concurrency_limit?,
+            queue: options.concurrency_queue,
+            routes: Default::default(),
+        })
+    }
+
+    fn route(&self, path: &str) -> Arc<RouteLimit> {
+        let mut routes = self.routes.lock().unwrap();
+        if let Some(route) = routes.get(path) {
+            return route.clone();
+        }
+
+        let route = Arc::new(RouteLimit {
+            semaphore: Arc::new(Semaphore::new(self.limit)),
+            queued: AtomicUsize::new(0),
+        });
+        routes.insert(path.to_string(), route.clone());
+        route
+    }
+}
+
+/// A call's slots on its connection and route, held until it's dropped.
+pub(crate) struct Permit {
+    _connection: Option<ConnectionSlot>,
+    _route: Option<OwnedSemaphorePermit>,
+}
+
+/// Takes the call's slots, the connection's first. A call waiting for its route holds its
+/// connection's slot, it's in flight as far as the client is concerned.
+pub(crate) async fn acquire(parts: &request::Parts) -> Result<Permit, RpcError> {
+    let connection = ConnectionSlot::acquire(parts)?;
+    Ok(Permit {
+        _connection: connection,
+        _route: acquire_route(parts).await?,
+    })
+}
+
+/// A call in flight on a connection, counted out when it's dropped.
+struct ConnectionSlot {
+    calls: ConnectionCalls,
+    peer: SocketAddr,
+}
+
+impl ConnectionSlot {
+    /// `None` if connections aren't limited or the peer is unknown. The limit can't be applied
+    /// without the peer address, the first call without one logs a `warn` level `tracing` event
+    /// on the `axum_connect::concurrency` target saying so.
+    fn acquire(parts: &request::Parts) -> Result<Option<Self>, RpcError> {
+        let Some(max) = RpcConfig::from_parts(parts).max_calls_per_connection else {
+            return Ok(None);
+        };
+        // Only missing if the config was put in the extensions by hand, rather than `with_config`.
+        static UNTRACKED: AtomicBool = AtomicBool::new(false);
+        let Some(table) = parts.extensions.get::<ConnectionCalls>() else {
+            warn_unlimited(
+                &UNTRACKED,
+                "the config wasn't applied with `RpcRouter::with_config`",
+            );
+            return Ok(None);
+        };
+        let Some(peer) = peer_address(parts) else {
+            warn_unlimited(
+                &table.warned,
+                "the router isn't served with `into_make_service_with_connect_info::<SocketAddr>()`",
+            );
+            return Ok(None);
+        };
+
+        let mut connections = table.calls.lock().unwrap();
+        let calls = connections.entry(peer).or_default();
+        if *calls >= max {
+            let mut metadata = HeaderMap::new();
+            metadata.insert("x-max-calls-per-connection", HeaderValue::from(max));
+            return Err(RpcError::new(
+                RpcErrorCode::ResourceExhausted,
+                format!(
+                    "Too many concurrent calls on this connection, at most {} are allowed. \
+                     Wait for some to finish or open another connection",
+                    max
+                ),
+            )
+            .with_metadata(metadata));
+        }
+        *calls += 1;
+        Ok(Some(Self {
+            calls: table.clone(),
+            peer,
+        }))
+    }
+}
+
+/// Logs that `max_calls_per_connection` isn't applied, unless `warned` already did.
+fn warn_unlimited(warned: &AtomicBool, reason: &str) {
+    if !warned.swap(true, Ordering::Relaxed) {
+        tracing::warn!(
+            target: "axum_connect::concurrency",
+            "max_calls_per_connection is set but calls aren't limited: {}",
+            reason
+        );
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.calls.calls.lock().unwrap();
+        if let Some(calls) = connections.get_mut(&self.peer) {
+            *calls -= 1;
+            if *calls == 0 {
+                connections.remove(&self.peer);
+            }
+        }
+    }
+}
+
+/// Takes a slot on the call's route. `None` if the route isn't limited.
+async fn acquire_route(parts: &request::Parts) -> Result<Option<OwnedSemaphorePermit>, RpcError> {
+    let Some(limits) = parts.extensions.get::<ConcurrencyLimits>() else {
+        return Ok(None);
+    };
+
+    let path = parts
+        .extensions
+        .get::<RpcMethodInfo>()
+        .map(|info| info.path.as_str())
+        .unwrap_or_else(|| parts.uri.path());
+    let route = limits.route(path);
+
+    if let Ok(permit) = route.semaphore.clone().try_acquire_owned() {
+        return Ok(Some(permit));
+    }
+
+    // Counted out again when the call stops waiting, even if it's dropped while queued.
+    let queued = Queued::enter(&route.queued);
+    if queued.position >= limits.queue {
+        return Err(RpcError::new(
+            RpcErrorCode::ResourceExhausted,
+            format!(
+                "Too many concurrent calls to {}, at most {} are allowed",
+                path, limits.limit
+            ),
+        ));
+    }
+
+    // The semaphore is never closed.
+    let permit = route.semaphore.clone().acquire_owned().await.unwrap();
+    drop(queued);
+    Ok(Some(permit))
+}
+
+/// A call waiting for a slot.
+struct Queued<'a> {
+    queued: &'a AtomicUsize,
+    /// How many calls were waiting before this one.
+    position: usize,
+}
+
+impl<'a> Queued<'a> {
+    fn enter(queued: &'a AtomicUsize) -> Self {
+        let position = queued.fetch_add(1, Ordering::Relaxed);
+        Self { queued, position }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::extract::ConnectInfo;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        router::RpcMethod,
+        test_util::{client, hello, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO},
+        testing::{TestClient, TestResponse},
+    };
+
+    /// Holds the calls of the method from [`gated`] until they're let through.
+    struct Gate {
+        entered: mpsc::UnboundedReceiver<()>,
+        open: Arc<Semaphore>,
+    }
+
+    impl Gate {
+        /// Waits for the next call to reach the handler.
+        async fn entered(&mut self) {
+            self.entered.recv().await.unwrap();
+        }
+
+        fn let_through(&self, calls: usize) {
+            self.open.add_permits(calls);
+        }
+    }
+
+    /// SAY_HELLO, waiting in its handler until the [`Gate`] lets it through.
+    fn gated() -> (RpcMethod, Gate) {
+        let (entered, receiver) = mpsc::unbounded_channel();
+        let open = Arc::new(Semaphore::new(0));
+        let gate = Gate {
+            entered: receiver,
+            open: open.clone(),
+        };
+        let handler = move |_: HelloRequest| {
+            let (entered, open) = (entered.clone(), open.clone());
+            async move {
+                entered.send(()).unwrap();
+                open.acquire().await.unwrap().forget();
+                Ok::<_, RpcError>(HelloResponse {
+                    message: "Hello!".to_string(),
+                })
+            }
+        };
+        (unary(SAY_HELLO, handler), gate)
+    }
+
+    /// Calls SAY_HELLO from `peer`, or from an unknown peer.
+    async fn call(client: &TestClient, peer: Option<SocketAddr>) -> TestResponse {
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        if let Some(peer) = peer {
+            request.extensions_mut().insert(ConnectInfo(peer));
+        }
+        client.send(request).await
+    }
+
+    /// Starts a call from `peer` and waits for it to reach the handler.
+    async fn start(
+        client: &TestClient,
+        gate: &mut Gate,
+        peer: Option<SocketAddr>,
+    ) -> tokio::task::JoinHandle<TestResponse> {
+        let client = client.clone();
+        let call = tokio::spawn(async move { call(&client, peer).await });
+        gate.entered().await;
+        call
+    }
+
+    fn peer(port: u16) -> Option<SocketAddr> {
+        Some(SocketAddr::from(([127, 0, 0, 1], port)))
+    }
+
+    #[tokio::test]
+    async fn limits_the_calls_in_flight_on_each_connection() {
+        let (method, mut gate) = gated();
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(method)
+                .with_config(RpcConfig::new().max_calls_per_connection(2)),
+        );
+
+        let first = start(&client, &mut gate, peer(1000)).await;
+        let second = start(&client, &mut gate, peer(1000)).await;
+
+        let response = call(&client, peer(1000)).await;
+        let error = response.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+        assert_eq!(response.headers["x-max-calls-per-connection"], "2");
+
+        // Other connections have their own count.
+        let other = start(&client, &mut gate, peer(1001)).await;
+
+        gate.let_through(3);
+        for call in [first, second, other] {
+            assert!(call.await.unwrap().error().is_none());
+        }
+
+        // The finished calls gave their slots back.
+        gate.let_through(1);
+        assert!(call(&client, peer(1000)).await.error().is_none());
+    }
+
+    #[tokio::test]
+    async fn doesnt_limit_calls_without_a_peer_address() {
+        let (method, mut gate) = gated();
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(method)
+                .with_config(RpcConfig::new().max_calls_per_connection(1)),
+        );
+
+        let first = start(&client, &mut gate, None).await;
+        let second = start(&client, &mut gate, None).await;
+
+        gate.let_through(2);
+        for call in [first, second] {
+            assert!(call.await.unwrap().error().is_none());
+        }
+    }
+
+    /// SAY_HELLO in a group of routes with `options`.
+    fn limited(options: RouteOptions) -> (TestClient, Gate) {
+        let (method, gate) = gated();
+        let router = RpcRouter::new().rpc_with_options(options, |router| router.rpc_method(method));
+        (client(router), gate)
+    }
+
+    /// Lets the spawned calls run until they're all waiting, on tokio's paused clock.
+    async fn settle() {
+        tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+    }
+
+    #[tokio::test]
+    async fn limits_the_calls_in_flight_on_each_route() {
+        let (client, mut gate) = limited(RouteOptions::new().concurrency_limit(2));
+
+        let first = start(&client, &mut gate, None).await;
+        let second = start(&client, &mut gate, None).await;
+
+        let error = call(&client, None).await.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+        assert_eq!(
+            error.message,
+            "Too many concurrent calls to /hello.HelloWorldService/SayHello, at most 2 are allowed"
+        );
+
+        // A finished call frees its slot.
+        gate.let_through(1);
+        assert!(first.await.unwrap().error().is_none());
+        let third = start(&client, &mut gate, None).await;
+
+        gate.let_through(2);
+        for call in [second, third] {
+            assert!(call.await.unwrap().error().is_none());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn queues_calls_over_the_limit() {
+        let options = RouteOptions::new()
+            .concurrency_limit(1)
+            .concurrency_queue(1);
+        let (client, mut gate) = limited(options);
+
+        let first = start(&client, &mut gate, None).await;
+        let queued = tokio::spawn({
+            let client = client.clone();
+            async move { call(&client, None).await }
+        });
+        settle().await;
+        assert!(gate.entered.try_recv().is_err());
+
+        let error = call(&client, None).await.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+
+        // The queued call takes the slot of the first.
+        gate.let_through(1);
+        assert!(first.await.unwrap().error().is_none());
+        gate.entered().await;
+        gate.let_through(1);
+        assert!(queued.await.unwrap().error().is_none());
+    }
+}
@@ -0,0 +1,900 @@
+//! Protocol settings shared by every RPC on a router.
+
+use std::{
+    sync::{Arc, LazyLock},
+    time::Duration,
+};
+
+use axum::http::{header, request, HeaderMap, HeaderName, HeaderValue};
+
+use crate::error::{RpcError, RpcErrorCode, RpcIntoError};
+
+/// Transport headers of responses outside the `connect-` and `grpc-` prefixes, see
+/// [`is_reserved_response_header`].
+const RESERVED_RESPONSE_HEADERS: [HeaderName; 9] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_ENCODING,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+    header::CONNECTION,
+    header::TE,
+    header::TRAILER,
+    header::UPGRADE,
+    HeaderName::from_static("keep-alive"),
+];
+
+/// The default [`RpcConfig::max_get_url_bytes`].
+pub const DEFAULT_MAX_GET_URL_BYTES: usize = 8 * 1024;
+
+/// Settings for how RPCs are handled, applied with
+/// [`RpcRouter::with_config`](crate::router::RpcRouter::with_config).
+///
+/// The defaults match the behavior without a config, so applying `RpcConfig::default()` changes
+/// nothing.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use axum_connect::config::RpcConfig;
+/// let config = RpcConfig::new()
+///     .max_request_bytes(4 * 1024 * 1024)
+///     .require_protocol_version(true)
+///     .stream_idle_timeout(Duration::from_secs(30));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcConfig {
+    /// Requests with a larger body are rejected with `ResourceExhausted`. Unlimited by default.
+    pub max_request_bytes: Option<usize>,
+    /// Reject requests without a `connect-protocol-version` header (or `connect=v1` query
+    /// parameter for GET requests). Off by default, only a wrong version is rejected.
+    pub require_protocol_version: bool,
+    /// Replace the message and details of `Internal` and `Unknown` errors returned by handlers
+    /// with a generic message, so implementation details don't leak to clients. The original
+    /// message is kept as the error's
+    /// [`internal_message`](crate::error::RpcError::with_internal_message), unless it has one
+    /// already, so it still shows up in the logs. Off by default.
+    pub redact_internal_errors: bool,
+    /// Default for [`RpcRouter::stream_idle_timeout`](crate::router::RpcRouter::stream_idle_timeout),
+    /// which overrides it per route. Unlimited by default.
+    pub stream_idle_timeout: Option<Duration>,
+    /// Append the RPC path and the extractor's type name to extractor rejections, and send the
+    /// type name as `x-rejected-extractor` metadata. Helps debugging handlers with many
+    /// extractors, but leaks type names, so off by default.
+    pub rejection_context: bool,
+    /// Reject bodies sent as binary protobuf that are actually JSON, which prost would otherwise
+    /// decode into nonsense, with an error pointing at the Content-Type. On by default.
+    pub detect_codec_mismatch: bool,
+    /// Reject `connect-timeout-ms` values that aren't a number with `InvalidArgument`, instead of
+    /// ignoring them. Off by default. Timeouts over 10 digits are rejected either way, and a
+    /// timeout of 0 fails with `DeadlineExceeded` before the handler is called.
+    pub strict_timeouts: bool,
+    /// Headers added to every RPC response, success, error or stream, eg. a uniform `Server`
+    /// header. Headers the handler sets win, and reserved ones (see
+    /// [`is_reserved_response_header`]) are never added this way. None by default.
+    pub default_response_headers: HeaderMap,
+    /// Reject GET requests whose path and query are longer, with `InvalidArgument`, before the
+    /// message in the query is decoded. 8 KiB by default, what most proxies allow.
+    pub max_get_url_bytes: Option<usize>,
+    /// Reject requests whose metadata, the names and values of their headers, adds up to more
+    /// bytes, with `ResourceExhausted`. Checked before authorization and the extractors; transport
+    /// headers don't count, see
+    /// [`RpcMetadata::is_reserved`](crate::parts::RpcMetadata::is_reserved). Unlimited by default.
+    pub max_metadata_bytes: Option<usize>,
+    /// Like `max_metadata_bytes`, for each metadata value on its own. Unlimited by default.
+    pub max_metadata_value_bytes: Option<usize>,
+    /// Reject calls past this many in flight on one connection, with `ResourceExhausted`, so a
+    /// single client can't use up what global limits leave for everyone. Streams count until they
+    /// end. Connections are told apart by the peer address, so the router must be served with
+    /// `into_make_service_with_connect_info::<SocketAddr>()` (or have a `MockConnectInfo` layer):
+    /// calls without one aren't limited, and the first of them logs a `warn` level `tracing`
+    /// event on the `axum_connect::concurrency` target. Each
+    /// [`with_config`](crate::router::RpcRouter::with_config)
+    /// counts the calls of its own routes, so routers with different limits served together don't
+    /// share their counts. Unlimited by default.
+    pub max_calls_per_connection: Option<usize>,
+    /// Fail calls with `Internal` when a reserved header (see [`is_reserved_response_header`]),
+    /// eg. `content-length`, is set by error metadata, [`RpcTrailers`] or
+    /// `default_response_headers`, instead of dropping it with a `warn` level `tracing` event on
+    /// the `axum_connect::headers` target. Those would corrupt the response. Off by default, meant
+    /// for development, eg. `.strict_reserved_headers(cfg!(debug_assertions))`.
+    ///
+    /// [`RpcTrailers`]: crate::parts::RpcTrailers
+    pub strict_reserved_headers: bool,
+    /// Serve unary methods called with a streaming Content-Type (`application/connect+json` or
+    /// `application/connect+proto`), as misconfigured clients do, instead of rejecting them. The
+    /// request must be a single enveloped message, more fail with `InvalidArgument`, and the
+    /// response is a stream of the one message, which is what those clients read. Every such call
+    /// is logged as a `warn` level `tracing` event on the `axum_connect::codec` target, with the
+    /// client's `user-agent`. Off by default, meant for migrating the clients.
+    pub adapt_streaming_unary: bool,
+    /// Send unary `google.protobuf.Empty` responses as an empty body in JSON too, instead of `{}`,
+    /// for gateways that want no payload at all (binary ones are empty either way). Still `200`,
+    /// with the JSON content type. Off by default.
+    pub empty_response_body: bool,
+    /// Reject HTTP/1.1 calls without a `Host` header with `InvalidArgument`, like HTTP/1.0 ones
+    /// always are. Off by default, requests made in-process (eg. with tower's `oneshot`) don't
+    /// have one.
+    pub require_host: bool,
+    /// Sort the keys of the JSON the handlers send, at every level and map entries included, so the
+    /// same response is always the same bytes: unary responses and errors, and each message and
+    /// end-of-stream frame of streams. Diffs of recorded responses (eg. contract tests) stay quiet
+    /// then, prost's `HashMap` fields otherwise come out in a different order every run. Off by
+    /// default, each body is parsed and written again.
+    pub stable_json_field_order: bool,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            max_request_bytes: None,
+            require_protocol_version: false,
+            redact_internal_errors: false,
+            stream_idle_timeout: None,
+            rejection_context: false,
+            detect_codec_mismatch: true,
+            strict_timeouts: false,
+            default_response_headers: HeaderMap::new(),
+            max_get_url_bytes: Some(DEFAULT_MAX_GET_URL_BYTES),
+            max_metadata_bytes: None,
+            max_metadata_value_bytes: None,
+            max_calls_per_connection: None,
+            strict_reserved_headers: false,
+            adapt_streaming_unary: false,
+            empty_response_body: false,
+            require_host: false,
+            stable_json_field_order: false,
+        }
+    }
+}
+
+impl RpcConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn max_request_bytes(mut self, max_request_bytes: usize) -> Self {
+        self.max_request_bytes = Some(max_request_bytes);
+        self
+    }
+
+    pub fn require_protocol_version(mut self, require_protocol_version: bool) -> Self {
+        self.require_protocol_version = require_protocol_version;
+        self
+    }
+
+    pub fn redact_internal_errors(mut self, redact_internal_errors: bool) -> Self {
+        self.redact_internal_errors = redact_internal_errors;
+        self
+    }
+
+    pub fn stream_idle_timeout(mut self, stream_idle_timeout: Duration) -> Self {
+        self.stream_idle_timeout = Some(stream_idle_timeout);
+        self
+    }
+
+    pub fn rejection_context(mut self, rejection_context: bool) -> Self {
+        self.rejection_context = rejection_context;
+        self
+    }
+
+    pub fn detect_codec_mismatch(mut self, detect_codec_mismatch: bool) -> Self {
+        self.detect_codec_mismatch = detect_codec_mismatch;
+        self
+    }
+
+    pub fn strict_timeouts(mut self, strict_timeouts: bool) -> Self {
+        self.strict_timeouts = strict_timeouts;
+        self
+    }
+
+    pub fn default_response_headers(mut self, default_response_headers: HeaderMap) -> Self {
+        self.default_response_headers = default_response_headers;
+        self
+    }
+
+    pub fn max_get_url_bytes(mut self, max_get_url_bytes: usize) -> Self {
+        self.max_get_url_bytes = Some(max_get_url_bytes);
+        self
+    }
+
+    pub fn max_metadata_bytes(mut self, max_metadata_bytes: usize) -> Self {
+        self.max_metadata_bytes = Some(max_metadata_bytes);
+        self
+    }
+
+    pub fn max_metadata_value_bytes(mut self, max_metadata_value_bytes: usize) -> Self {
+        self.max_metadata_value_bytes = Some(max_metadata_value_bytes);
+        self
+    }
+
+    pub fn max_calls_per_connection(mut self, max_calls_per_connection: usize) -> Self {
+        self.max_calls_per_connection = Some(max_calls_per_connection);
+        self
+    }
+
+    pub fn strict_reserved_headers(mut self, strict_reserved_headers: bool) -> Self {
+        self.strict_reserved_headers = strict_reserved_headers;
+        self
+    }
+
+    pub fn adapt_streaming_unary(mut self, adapt_streaming_unary: bool) -> Self {
+        self.adapt_streaming_unary = adapt_streaming_unary;
+        self
+    }
+
+    pub fn empty_response_body(mut self, empty_response_body: bool) -> Self {
+        self.empty_response_body = empty_response_body;
+        self
+    }
+
+    pub fn require_host(mut self, require_host: bool) -> Self {
+        self.require_host = require_host;
+        self
+    }
+
+    pub fn stable_json_field_order(mut self, stable_json_field_order: bool) -> Self {
+        self.stable_json_field_order = stable_json_field_order;
+        self
+    }
+
+    /// The config for a request, or the default one if none was applied.
+    pub(crate) fn from_parts(parts: &request::Parts) -> Arc<RpcConfig> {
+        static DEFAULT: LazyLock<Arc<RpcConfig>> = LazyLock::new(Default::default);
+
+        parts
+            .extensions
+            .get::<Arc<RpcConfig>>()
+            .unwrap_or(&DEFAULT)
+            .clone()
+    }
+
+    /// Applies `rejection_context` to an error returned by the extractor `E`.
+    pub(crate) fn rejection<E>(&self, error: impl RpcIntoError, path: &str) -> RpcError {
+        let mut error = error.rpc_into_error();
+        if !self.rejection_context {
+            return error;
+        }
+
+        let extractor = std::any::type_name::<E>();
+        error.message = format!(
+            "{} (rejected by `{}` in {})",
+            error.message, extractor, path
+        );
+        if let Ok(value) = HeaderValue::from_str(extractor) {
+            error.metadata_mut().append("x-rejected-extractor", value);
+        }
+        error
+    }
+
+    /// Adds the `default_response_headers` the response doesn't have yet. Fails with a reserved one
+    /// under `strict_reserved_headers`, they're skipped otherwise.
+    pub(crate) fn apply_default_response_headers(
+        &self,
+        headers: &mut HeaderMap,
+    ) -> Result<(), RpcError> {
+        if let Some(name) = self.reserved_default_header() {
+            if self.strict_reserved_headers {
+                return Err(reserved_header_error("default_response_headers", name));
+            }
+        }
+        for name in self.default_response_headers.keys() {
+            if headers.contains_key(name) || is_reserved_response_header(name) {
+                continue;
+            }
+            for value in self.default_response_headers.get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        Ok(())
+    }
+
+    /// The first of the `default_response_headers` that's reserved.
+    pub(crate) fn reserved_default_header(&self) -> Option<&HeaderName> {
+        self.default_response_headers
+            .keys()
+            .find(|name| is_reserved_response_header(name))
+    }
+
+    /// Applies `redact_internal_errors` to an error returned by a handler.
+    pub(crate) fn redact(&self, mut error: RpcError) -> RpcError {
+        match error.code {
+            RpcErrorCode::Internal | RpcErrorCode::Unknown if self.redact_internal_errors => {
+                let metadata = std::mem::take(error.metadata_mut());
+                let internal_message = error
+                    .internal_message()
+                    .unwrap_or(&error.message)
+                    .to_string();
+                RpcError::new(error.code, "Internal error".to_string())
+                    .with_metadata(metadata)
+                    .with_internal_message(internal_message)
+            }
+            _ => error,
+        }
+    }
+}
+
+/// Whether `name` is a header of the transport, owned by the router and the server, which
+/// handlers mustn't set as error metadata, trailers or default headers: `content-type`,
+/// `content-length`, `content-encoding`, the hop-by-hop headers and everything under `connect-`
+/// and `grpc-`.
+pub fn is_reserved_response_header(name: &HeaderName) -> bool {
+    RESERVED_RESPONSE_HEADERS.contains(name)
+        || name.as_str().starts_with("connect-")
+        || name.as_str().starts_with("grpc-")
+}
+
+/// Removes the reserved headers `source` set from `headers`, logging each, or under `strict` fails
+/// with `Internal` instead.
+pub(crate) fn strip_reserved_headers(
+    headers: &mut HeaderMap,
+    strict: bool,
+    source: &str,
+) -> Result<(), RpcError> {
+    let reserved = headers
+        .keys()
+        .filter(|name| is_reserved_response_header(name))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in reserved {
+        if strict {
+            return Err(reserved_header_error(source, &name));
+        }
+        tracing::warn!(
+            target: "axum_connect::headers",
+            header = %name,
+            "dropped the reserved header `{}` set in {}",
+            name,
+            source
+        );
+        headers.remove(&name);
+    }
+    Ok(())
+}
+
+fn reserved_header_error(source: &str, name: &HeaderName) -> RpcError {
+    RpcError::new(
+        RpcErrorCode::Internal,
+        format!(
+            "The reserved header `{}` was set in {}, see RpcConfig::strict_reserved_headers",
+            name, source
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use axum::{body::Body, extract::ConnectInfo, http::Request};
+    use futures::{stream, Stream};
+    use tokio::sync::{mpsc, Semaphore};
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        test_util::{
+            client, hello, hello_router, message, proto_request, say_hello, say_hello_stream,
+            server_stream, stream_request, traced, unary, HelloRequest, HelloResponse, SAY_HELLO,
+            SAY_HELLO_STREAM,
+        },
+    };
+
+    async fn greet(request: HelloRequest) -> RpcResult<HelloResponse> {
+        match request.name.as_str() {
+            "boom" => Err(RpcError::new(
+                RpcErrorCode::Internal,
+                "db password is hunter2".to_string(),
+            )),
+            name => Ok(HelloResponse {
+                message: format!("Hello {name}!"),
+            }),
+        }
+    }
+
+    fn config() -> RpcConfig {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-served-by", HeaderValue::from_static("a"));
+        RpcConfig::new()
+            .max_request_bytes(16)
+            .require_protocol_version(true)
+            .redact_internal_errors(true)
+            .default_response_headers(headers)
+    }
+
+    /// A call of `name` with a protocol version unless `versionless`.
+    fn call(name: &str, versionless: bool) -> Request<Body> {
+        let mut request = proto_request(SAY_HELLO, &hello(name));
+        if !versionless {
+            request
+                .headers_mut()
+                .insert("connect-protocol-version", HeaderValue::from_static("1"));
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn applies_every_option_at_once() {
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, greet))
+                .with_config(config()),
+        );
+
+        let response = client.send(call("Ada", false)).await;
+        assert!(response.status.is_success());
+        assert_eq!(response.headers["x-served-by"], "a");
+
+        let error = client.send(call("Ada", true)).await.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+
+        let error = client
+            .send(call(
+                "Hubert Blaine Wolfeschlegelsteinhausenbergerdorff",
+                false,
+            ))
+            .await
+            .error()
+            .unwrap();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+
+        let error = client.send(call("boom", false)).await.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::Internal);
+        assert_eq!(error.message, "Internal error");
+    }
+
+    #[tokio::test]
+    async fn the_default_config_changes_nothing() {
+        let without = client(hello_router()).send(call("Ada", true)).await;
+        let with = client(hello_router().with_config(RpcConfig::default()))
+            .send(call("Ada", true))
+            .await;
+        assert_eq!(with.status, without.status);
+        assert_eq!(with.headers, without.headers);
+        assert_eq!(with.body, without.body);
+    }
+
+    #[test]
+    fn debug_lists_the_effective_values() {
+        let debug = format!("{:?}", config());
+        for field in [
+            "max_request_bytes: Some(16)",
+            "require_protocol_version: true",
+            "redact_internal_errors: true",
+            "stream_idle_timeout: None",
+            "max_get_url_bytes: Some(8192)",
+            r#""x-served-by": "a""#,
+        ] {
+            assert!(debug.contains(field), "{field} isn't in {debug}");
+        }
+    }
+
+    /// The `x-user-id` of a request.
+    struct UserId;
+
+    impl<M: prost::Message, S: Send + Sync> RpcFromRequestParts<M, S> for UserId {
+        type Rejection = RpcError;
+
+        async fn rpc_from_request_parts(
+            parts: &mut request::Parts,
+            _state: &S,
+        ) -> Result<Self, RpcError> {
+            match parts.headers.contains_key("x-user-id") {
+                true => Ok(UserId),
+                false => Err(RpcError::new(
+                    RpcErrorCode::Unauthenticated,
+                    "Missing x-user-id header".to_string(),
+                )),
+            }
+        }
+    }
+
+    async fn greet_user(
+        _: RpcMetadata,
+        _: UserId,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        greet(request).await
+    }
+
+    async fn rejected(config: RpcConfig) -> crate::testing::TestResponse {
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, greet_user))
+            .with_config(config);
+        client(router)
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await
+    }
+
+    #[tokio::test]
+    async fn names_the_rejecting_extractor_when_enabled() {
+        let response = rejected(RpcConfig::default().rejection_context(true)).await;
+
+        assert_eq!(
+            response.error().unwrap().message,
+            "Missing x-user-id header (rejected by `axum_connect::config::tests::UserId` in \
+             /hello.HelloWorldService/SayHello)"
+        );
+        assert_eq!(
+            response.headers["x-rejected-extractor"],
+            "axum_connect::config::tests::UserId"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_rejections_as_they_are_by_default() {
+        let response = rejected(RpcConfig::default()).await;
+
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::Unauthenticated,
+                "Missing x-user-id header".to_string()
+            ))
+        );
+        assert!(!response.headers.contains_key("x-rejected-extractor"));
+    }
+
+    /// A uniform `Server` header and a security header, plus a `content-type` that mustn't apply.
+    fn with_default_headers(router: RpcRouter) -> crate::testing::TestClient {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SERVER, HeaderValue::from_static("acme"));
+        headers.insert(
+            "x-content-type-options",
+            HeaderValue::from_static("nosniff"),
+        );
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+        client(router.with_config(RpcConfig::default().default_response_headers(headers)))
+    }
+
+    #[tokio::test]
+    async fn adds_default_headers_to_errors_and_streams() {
+        let client = with_default_headers(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, greet))
+                .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream)),
+        );
+
+        let error = client.send(proto_request(SAY_HELLO, &hello("boom"))).await;
+        let stream = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        assert_eq!(error.error().unwrap().code, RpcErrorCode::Internal);
+        assert_eq!(error.headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(
+            stream.headers[header::CONTENT_TYPE],
+            "application/connect+proto"
+        );
+        for response in [error, stream] {
+            assert_eq!(response.headers[header::SERVER], "acme");
+            assert_eq!(response.headers["x-content-type-options"], "nosniff");
+        }
+    }
+
+    #[tokio::test]
+    async fn lets_handlers_override_default_headers() {
+        async fn branded(
+            headers: RpcResponseHeaders,
+            request: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            headers.insert(header::SERVER, HeaderValue::from_static("acme-billing"));
+            greet(request).await
+        }
+        let client = with_default_headers(RpcRouter::new().rpc_method(unary(SAY_HELLO, branded)));
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        assert_eq!(
+            response
+                .headers
+                .get_all(header::SERVER)
+                .iter()
+                .collect::<Vec<_>>(),
+            ["acme-billing"]
+        );
+        assert_eq!(response.headers["x-content-type-options"], "nosniff");
+        assert_eq!(response.headers[header::CONTENT_TYPE], "application/proto");
+    }
+
+    const LEAKED: &str = "connect-content-encoding";
+
+    /// Sets [`LEAKED`] at the injection point named by the request, eg. `trailers`.
+    async fn leaky(
+        headers: RpcResponseHeaders,
+        trailers: RpcTrailers,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        let value = HeaderValue::from_static("br");
+        match request.name.as_str() {
+            "response headers" => headers.insert(LEAKED, value),
+            "trailers" => trailers.insert(LEAKED, value),
+            "error metadata" => {
+                let mut error = RpcError::new(RpcErrorCode::Unavailable, "Try later".to_string());
+                error.metadata_mut().insert(LEAKED, value);
+                return Err(error);
+            }
+            _ => {}
+        }
+        say_hello(request).await
+    }
+
+    /// Like [`leaky`], for streams. A stream's error fails it after its first message.
+    async fn leaky_stream(
+        headers: RpcResponseHeaders,
+        trailers: RpcTrailers,
+        request: HelloRequest,
+    ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+        let first = say_hello(request.clone()).await;
+        let value = HeaderValue::from_static("br");
+        let end = match request.name.as_str() {
+            "response headers" => {
+                headers.insert(LEAKED, value);
+                None
+            }
+            "trailers" => {
+                trailers.insert(LEAKED, value);
+                None
+            }
+            "error metadata" => {
+                let mut error = RpcError::new(RpcErrorCode::Unavailable, "Try later".to_string());
+                error.metadata_mut().insert(LEAKED, value);
+                Some(Err(error))
+            }
+            _ => None,
+        };
+        stream::iter(std::iter::once(first).chain(end))
+    }
+
+    fn leaky_router(config: RpcConfig) -> crate::testing::TestClient {
+        client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, leaky))
+                .rpc_method(server_stream(SAY_HELLO_STREAM, leaky_stream))
+                .with_config(config),
+        )
+    }
+
+    /// The metadata of a stream's end-of-stream message.
+    fn end_metadata(response: &crate::testing::TestResponse) -> HeaderMap {
+        let mut decoder = crate::codec::FrameDecoder::new();
+        decoder.push(&response.body);
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            if frame.is_end_stream() {
+                return crate::codec::parse_end_stream(&frame.payload)
+                    .unwrap()
+                    .metadata;
+            }
+        }
+        panic!("the stream has no end-of-stream frame");
+    }
+
+    fn reserved_error(source: &str) -> RpcError {
+        RpcError::new(
+            RpcErrorCode::Internal,
+            format!(
+                "The reserved header `{LEAKED}` was set in {source}, see \
+                 RpcConfig::strict_reserved_headers"
+            ),
+        )
+    }
+
+    #[tokio::test]
+    async fn drops_reserved_headers_with_a_warning() {
+        let client = leaky_router(RpcConfig::default());
+
+        for source in ["response headers", "trailers", "error metadata"] {
+            let (recorder, _guard) = traced();
+            let unary = client.send(proto_request(SAY_HELLO, &hello(source))).await;
+            let stream = client
+                .send(stream_request(SAY_HELLO_STREAM, &hello(source)))
+                .await;
+
+            for response in [&unary, &stream] {
+                assert!(!response.headers.contains_key(LEAKED), "{source}");
+                assert!(
+                    !response
+                        .headers
+                        .contains_key(format!("trailer-{LEAKED}").as_str()),
+                    "{source}"
+                );
+            }
+            assert!(!end_metadata(&stream).contains_key(LEAKED), "{source}");
+            let (frames, error) = stream.frames();
+            assert_eq!(frames.len(), 1, "{source}");
+            match source {
+                "error metadata" => {
+                    assert_eq!(unary.error().unwrap().code, RpcErrorCode::Unavailable);
+                    assert_eq!(error.unwrap().code, RpcErrorCode::Unavailable);
+                }
+                _ => {
+                    assert_eq!(
+                        message::<HelloResponse>(&unary).message,
+                        format!("Hello {source}!")
+                    );
+                    assert_eq!(error, None, "{source}");
+                }
+            }
+
+            let events = recorder.events("axum_connect::headers");
+            assert_eq!(events.len(), 2, "{source}");
+            for event in events {
+                assert_eq!(event.field("header"), Some(LEAKED));
+                assert_eq!(
+                    event.field("message"),
+                    Some(
+                        format!("dropped the reserved header `{LEAKED}` set in {source}").as_str()
+                    )
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_calls_setting_reserved_headers_when_strict() {
+        let client = leaky_router(RpcConfig::default().strict_reserved_headers(true));
+
+        for source in ["response headers", "trailers", "error metadata"] {
+            let (recorder, _guard) = traced();
+            let unary = client.send(proto_request(SAY_HELLO, &hello(source))).await;
+            let stream = client
+                .send(stream_request(SAY_HELLO_STREAM, &hello(source)))
+                .await;
+
+            assert_eq!(unary.error(), Some(reserved_error(source)));
+            assert!(!unary.headers.contains_key(LEAKED));
+            assert_eq!(stream.frames().1, Some(reserved_error(source)));
+            assert!(!stream.headers.contains_key(LEAKED));
+            assert!(recorder.events("axum_connect::headers").is_empty());
+        }
+
+        // Calls that don't set any aren't affected.
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+    }
+
+    fn with_reserved_default(strict: bool) -> RpcConfig {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::SERVER, HeaderValue::from_static("acme"));
+        headers.insert(LEAKED, HeaderValue::from_static("br"));
+        RpcConfig::default()
+            .default_response_headers(headers)
+            .strict_reserved_headers(strict)
+    }
+
+    #[tokio::test]
+    async fn warns_of_reserved_default_headers_once_and_skips_them() {
+        let (recorder, _guard) = traced();
+        let client = leaky_router(with_reserved_default(false));
+        let events = recorder.events("axum_connect::headers");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].field("header"), Some(LEAKED));
+
+        let unary = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        let stream = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+        for response in [unary, stream] {
+            assert_eq!(response.error(), None);
+            assert_eq!(response.headers[header::SERVER], "acme");
+            assert!(!response.headers.contains_key(LEAKED));
+        }
+        assert_eq!(recorder.events("axum_connect::headers").len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_calls_with_reserved_default_headers_when_strict() {
+        let client = leaky_router(with_reserved_default(true));
+
+        let unary = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        let stream = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        assert_eq!(
+            unary.error(),
+            Some(reserved_error("default_response_headers"))
+        );
+        assert_eq!(
+            stream.frames().1,
+            Some(reserved_error("default_response_headers"))
+        );
+        for response in [unary, stream] {
+            assert!(!response.headers.contains_key(LEAKED));
+        }
+    }
+
+    const ADMIN_SAY_HELLO: &str = "/admin.AdminService/SayHello";
+    const ADMIN_HOLD: &str = "/admin.AdminService/Hold";
+
+    /// A call of `name` at `path` from `port`, with a protocol version.
+    fn call_from(path: &str, name: &str, port: u16) -> Request<Body> {
+        let mut request = proto_request(path, &hello(name));
+        request
+            .headers_mut()
+            .insert("connect-protocol-version", HeaderValue::from_static("1"));
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], port))));
+        request
+    }
+
+    #[tokio::test]
+    async fn keeps_the_configs_of_routers_served_together_apart() {
+        // A call to ADMIN_HOLD waits in its handler until a permit is added.
+        let (entered, mut held) = mpsc::unbounded_channel();
+        let open = Arc::new(Semaphore::new(0));
+        let hold = {
+            let open = open.clone();
+            move |request: HelloRequest| {
+                let (entered, open) = (entered.clone(), open.clone());
+                async move {
+                    entered.send(()).unwrap();
+                    open.acquire().await.unwrap().forget();
+                    greet(request).await
+                }
+            }
+        };
+
+        let admin = RpcRouter::new()
+            .rpc_method(unary(ADMIN_SAY_HELLO, greet))
+            .rpc_method(unary(ADMIN_HOLD, hold))
+            .with_config(config().max_calls_per_connection(1));
+        let mut headers = HeaderMap::new();
+        headers.insert("x-served-by", HeaderValue::from_static("b"));
+        let public = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, greet))
+            .with_config(
+                RpcConfig::new()
+                    .default_response_headers(headers)
+                    .max_calls_per_connection(1),
+            );
+        let client = client(admin.merge(public));
+
+        // Both routers' calls at once, on the same runtime.
+        let long = "Hubert Blaine Wolfeschlegelsteinhausenbergerdorff";
+        let (admin_hello, public_hello, admin_long, public_long, admin_boom, public_boom) = tokio::join!(
+            client.send(call_from(ADMIN_SAY_HELLO, "Ada", 1000)),
+            client.send(call_from(SAY_HELLO, "Ada", 1000)),
+            client.send(call_from(ADMIN_SAY_HELLO, long, 1000)),
+            client.send(call_from(SAY_HELLO, long, 1000)),
+            client.send(call_from(ADMIN_SAY_HELLO, "boom", 1000)),
+            client.send(call_from(SAY_HELLO, "boom", 1000)),
+        );
+        assert_eq!(admin_hello.headers["x-served-by"], "a");
+        assert_eq!(public_hello.headers["x-served-by"], "b");
+        assert_eq!(
+            admin_long.error().unwrap().code,
+            RpcErrorCode::ResourceExhausted
+        );
+        assert_eq!(public_long.error(), None);
+        assert_eq!(admin_boom.error().unwrap().message, "Internal error");
+        assert_eq!(
+            public_boom.error().unwrap().message,
+            "db password is hunter2"
+        );
+
+        let mut versionless = proto_request(SAY_HELLO, &hello("Ada"));
+        versionless
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1000))));
+        assert_eq!(client.send(versionless).await.error(), None);
+
+        // The admin call in flight uses up the connection's admin slot, not its public one.
+        let admin_call = tokio::spawn({
+            let client = client.clone();
+            async move { client.send(call_from(ADMIN_HOLD, "Ada", 1000)).await }
+        });
+        held.recv().await.unwrap();
+        let error = client
+            .send(call_from(ADMIN_SAY_HELLO, "Ada", 1000))
+            .await
+            .error()
+            .unwrap();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+        let response = client.send(call_from(SAY_HELLO, "Ada", 1000)).await;
+        assert_eq!(response.error(), None);
+
+        open.add_permits(1);
+        assert_eq!(admin_call.await.unwrap().error(), None);
+    }
+}
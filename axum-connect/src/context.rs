@@ -0,0 +1,451 @@
+//! Values middleware derives for a request and hands on to its handler, eg. a parsed device or a
+//! looked up tenant, see [`RpcContextMap`].
+
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::HashMap,
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use axum::http::{self, Extensions, Request};
+use prost::Message;
+use tower::{Layer, Service};
+
+use crate::{
+    error::{RpcError, RpcErrorCode},
+    parts::RpcFromRequestParts,
+};
+
+/// What each [`provider`] declared it provides, to name it when a value is missing.
+static PROVIDERS: Mutex<Vec<(ContextKey, &'static str)>> = Mutex::new(Vec::new());
+
+/// The default namespace, for values of types of their own that nothing else inserts. Values
+/// of types anyone could insert, eg. a `String` or `Uuid`, belong in a namespace of the crate
+/// inserting them, see [`RpcContextMap`].
+#[derive(Clone, Copy, Debug)]
+pub struct Shared;
+
+/// A value's type and the namespace it was inserted in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct ContextKey {
+    namespace: TypeId,
+    value: TypeId,
+}
+
+impl ContextKey {
+    fn of<N: 'static, T: 'static>() -> Self {
+        Self {
+            namespace: TypeId::of::<N>(),
+            value: TypeId::of::<T>(),
+        }
+    }
+}
+
+/// The typed values of a request, kept in its extensions, one map per request. Middleware inserts
+/// into it and handlers take the values with [`FromContext`].
+///
+/// Each value is keyed by its type and a namespace type, so two crates inserting a `String` (or
+/// anything else off the shelf) don't overwrite each other. A crate declares its namespace as an
+/// empty type of its own:
+///
+/// ```
+/// # use axum::{extract::Request, middleware::Next, response::Response};
+/// # use axum_connect::context::RpcContextMap;
+/// pub struct Devices;
+///
+/// #[derive(Clone)]
+/// pub struct Device(pub String);
+///
+/// async fn parse_device(mut request: Request, next: Next) -> Response {
+///     let device = request
+///         .headers()
+///         .get("user-agent")
+///         .and_then(|agent| agent.to_str().ok())
+///         .unwrap_or_default()
+///         .to_string();
+///     RpcContextMap::of(request.extensions_mut()).insert::<Devices, _>(Device(device));
+///     next.run(request).await
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct RpcContextMap {
+    values: HashMap<ContextKey, Arc<dyn Any + Send + Sync>>,
+    /// The [`provider`]s the request went through, in order.
+    ran: Vec<&'static str>,
+}
+
+impl RpcContextMap {
+    /// The map of the request of `extensions`, inserted if it has none yet.
+    pub fn of(extensions: &mut Extensions) -> &mut Self {
+        if extensions.get::<Self>().is_none() {
+            extensions.insert(Self::default());
+        }
+        extensions.get_mut::<Self>().unwrap()
+    }
+
+    /// Sets the `T` of namespace `N`, replacing what it was set to.
+    pub fn insert<N: 'static, T: Send + Sync + 'static>(&mut self, value: T) {
+        self.values
+            .insert(ContextKey::of::<N, T>(), Arc::new(value));
+    }
+
+    pub fn get<N: 'static, T: 'static>(&self) -> Option<&T> {
+        self.values
+            .get(&ContextKey::of::<N, T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn contains<N: 'static, T: 'static>(&self) -> bool {
+        self.values.contains_key(&ContextKey::of::<N, T>())
+    }
+
+    /// Why the `T` of namespace `N` isn't there, naming the provider that should have set it.
+    fn missing<N: 'static, T: 'static>(&self) -> String {
+        let key = ContextKey::of::<N, T>();
+        let providers = PROVIDERS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(provided, _)| *provided == key)
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>();
+        let value = format!(
+            "No {} in the request context (namespace {})",
+            type_name::<T>(),
+            type_name::<N>()
+        );
+        match providers.iter().find(|name| self.ran.contains(name)) {
+            Some(name) => format!("{}, the `{}` provider ran but didn't set it", value, name),
+            None if providers.is_empty() => {
+                format!("{}, and no provider declares it", value)
+            }
+            None => format!(
+                "{}, it's provided by {}, which isn't layered on this route",
+                value,
+                providers
+                    .iter()
+                    .map(|name| format!("`{}`", name))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ),
+        }
+    }
+}
+
+impl fmt::Debug for RpcContextMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcContextMap")
+            .field("values", &self.values.len())
+            .field("ran", &self.ran)
+            .finish()
+    }
+}
+
+/// The `T` of namespace `N` in the request's [`RpcContextMap`], a clone of it. Fails with
+/// `Internal` if it isn't there, with a message naming the type and the [`provider`] declared to
+/// provide it, and whether that one ran.
+///
+/// ```
+/// # use axum_connect::context::FromContext;
+/// # pub struct Devices;
+/// # #[derive(Clone)]
+/// # pub struct Device(pub String);
+/// # struct RegisterRequest;
+/// # struct RegisterResponse;
+/// async fn register(
+///     FromContext { value: device, .. }: FromContext<Device, Devices>,
+///     request: RegisterRequest,
+/// ) -> RegisterResponse {
+///     # let _ = device.0;
+///     # RegisterResponse
+/// }
+/// ```
+pub struct FromContext<T, N = Shared> {
+    pub value: T,
+    namespace: PhantomData<fn() -> N>,
+}
+
+impl<T: fmt::Debug, N> fmt::Debug for FromContext<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FromContext")
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+impl<M, S, T, N> RpcFromRequestParts<M, S> for FromContext<T, N>
+where
+    M: Message,
+    S: Send + Sync,
+    T: Clone + Send + Sync + 'static,
+    N: 'static,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let map = RpcContextMap::of(&mut parts.extensions);
+        match map.get::<N, T>() {
+            Some(value) => Ok(Self {
+                value: value.clone(),
+                namespace: PhantomData,
+            }),
+            None => Err(RpcError::new(RpcErrorCode::Internal, map.missing::<N, T>())),
+        }
+    }
+}
+
+/// Declares that the middleware `layer` provides context values, for the message of a
+/// [`FromContext`] that's missing one. The returned layer is `layer` itself otherwise, named
+/// `name` in those messages:
+///
+/// ```
+/// # use axum::middleware;
+/// # use axum_connect::{context, prelude::*};
+/// # pub struct Devices;
+/// # #[derive(Clone)]
+/// # pub struct Device(pub String);
+/// # async fn parse_device(request: axum::extract::Request, next: middleware::Next) -> axum::response::Response {
+/// #     next.run(request).await
+/// # }
+/// # fn routes(app: RpcRouter) -> RpcRouter {
+/// app.layer(
+///     context::provider("parse_device", middleware::from_fn(parse_device))
+///         .provides::<Devices, Device>(),
+/// )
+/// # }
+/// ```
+pub fn provider<L>(name: &'static str, layer: L) -> ContextProvider<L> {
+    ContextProvider { name, layer }
+}
+
+/// The layer returned by [`provider`].
+#[derive(Clone, Debug)]
+pub struct ContextProvider<L> {
+    name: &'static str,
+    layer: L,
+}
+
+impl<L> ContextProvider<L> {
+    /// Declares that it sets the `T` of namespace `N`. Declarations are process-wide, so a
+    /// handler missing a value can name the provider even on routes it isn't layered on.
+    pub fn provides<N: 'static, T: 'static>(self) -> Self {
+        let provided = (ContextKey::of::<N, T>(), self.name);
+        let mut providers = PROVIDERS.lock().unwrap();
+        if !providers.contains(&provided) {
+            providers.push(provided);
+        }
+        self
+    }
+}
+
+impl<S, L: Layer<S>> Layer<S> for ContextProvider<L> {
+    type Service = ContextProviderService<L::Service>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ContextProviderService {
+            name: self.name,
+            inner: self.layer.layer(inner),
+        }
+    }
+}
+
+/// The service of a [`ContextProvider`].
+#[derive(Clone, Debug)]
+pub struct ContextProviderService<S> {
+    name: &'static str,
+    inner: S,
+}
+
+impl<S, B> Service<Request<B>> for ContextProviderService<S>
+where
+    S: Service<Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut request: Request<B>) -> Self::Future {
+        RpcContextMap::of(request.extensions_mut())
+            .ran
+            .push(self.name);
+        self.inner.call(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        extract::Request,
+        middleware::{from_fn, Next},
+        response::Response,
+    };
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        test_util::{
+            client, hello, message, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO,
+        },
+    };
+
+    struct Devices;
+
+    struct Billing;
+
+    #[derive(Clone, Debug)]
+    struct Device(String);
+
+    async fn parse_device(mut request: Request, next: Next) -> Response {
+        let map = RpcContextMap::of(request.extensions_mut());
+        map.insert::<Devices, _>(Device("phone".to_string()));
+        map.insert::<Devices, _>("from devices".to_string());
+        map.insert::<Billing, _>("from billing".to_string());
+        next.run(request).await
+    }
+
+    async fn register(
+        FromContext { value: device, .. }: FromContext<Device, Devices>,
+        _: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse { message: device.0 })
+    }
+
+    fn device_router(provider: bool) -> RpcRouter {
+        let router = RpcRouter::new().rpc_method(unary(SAY_HELLO, register));
+        match provider {
+            true => router.layer(
+                super::provider("parse_device", from_fn(parse_device))
+                    .provides::<Devices, Device>(),
+            ),
+            false => router,
+        }
+    }
+
+    #[tokio::test]
+    async fn hands_provided_values_to_handlers() {
+        let response = client(device_router(true))
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "phone");
+    }
+
+    #[tokio::test]
+    async fn names_the_provider_of_missing_values() {
+        // Declares `parse_device`, though it isn't layered on the route called.
+        device_router(true);
+
+        let error = client(device_router(false))
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await
+            .error()
+            .unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::Internal);
+        assert_eq!(
+            error.message,
+            "No axum_connect::context::tests::Device in the request context (namespace \
+             axum_connect::context::tests::Devices), it's provided by `parse_device`, which isn't \
+             layered on this route"
+        );
+    }
+
+    #[tokio::test]
+    async fn tells_unset_values_from_undeclared_ones() {
+        #[derive(Clone)]
+        struct Unset;
+        async fn unset(_: FromContext<Unset>, _: HelloRequest) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse::default())
+        }
+        #[derive(Clone)]
+        struct Undeclared;
+        async fn undeclared(
+            _: FromContext<Undeclared>,
+            _: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse::default())
+        }
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, unset))
+                .rpc_method(unary("/hello.HelloWorldService/Undeclared", undeclared))
+                .layer(
+                    super::provider("parse_device", from_fn(parse_device))
+                        .provides::<Shared, Unset>(),
+                ),
+        );
+
+        let error = client
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await
+            .error()
+            .unwrap();
+        assert!(
+            error
+                .message
+                .ends_with("the `parse_device` provider ran but didn't set it"),
+            "{}",
+            error.message
+        );
+
+        let error = client
+            .send(proto_request(
+                "/hello.HelloWorldService/Undeclared",
+                &hello("Ada"),
+            ))
+            .await
+            .error()
+            .unwrap();
+        assert!(
+            error.message.ends_with("and no provider declares it"),
+            "{}",
+            error.message
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_values_of_namespaces_apart() {
+        async fn both(
+            FromContext { value: devices, .. }: FromContext<String, Devices>,
+            FromContext { value: billing, .. }: FromContext<String, Billing>,
+            _: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: format!("{devices}, {billing}"),
+            })
+        }
+        async fn shared(_: FromContext<String>, _: HelloRequest) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse::default())
+        }
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, both))
+                .rpc_method(unary("/hello.HelloWorldService/Shared", shared))
+                .layer(from_fn(parse_device)),
+        );
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            "from devices, from billing"
+        );
+
+        let response = client
+            .send(proto_request(
+                "/hello.HelloWorldService/Shared",
+                &hello("Ada"),
+            ))
+            .await;
+        assert_eq!(response.error().unwrap().code, RpcErrorCode::Internal);
+    }
+}
@@ -0,0 +1,214 @@
+//! Enforcing the `connect-timeout-ms` header, surfaced to handlers as [`RpcDeadline`].
+
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use axum::http::request;
+use futures::{future::Either, Stream, StreamExt};
+
+use crate::{
+    config::RpcConfig,
+    error::{RpcError, RpcErrorCode},
+    parts::RpcDeadline,
+    response::RpcResult,
+};
+
+const TIMEOUT_HEADER: &str = "connect-timeout-ms";
+
+/// The spec allows at most 10 digits.
+const MAX_TIMEOUT_DIGITS: usize = 10;
+
+/// Parses the request's timeout and stores the deadline for [`RpcDeadline`]. A timeout of 0 fails
+/// with `DeadlineExceeded` so the handler is never called, one over 10 digits with
+/// `InvalidArgument`. Other invalid values are ignored, or rejected with `strict_timeouts`.
+pub(crate) fn start(
+    parts: &mut request::Parts,
+    config: &RpcConfig,
+) -> Result<Option<Instant>, RpcError> {
+    let deadline = parse(parts, config)?.map(|timeout| Instant::now() + timeout);
+    parts.extensions.insert(RpcDeadline(deadline));
+    Ok(deadline)
+}
+
+fn parse(parts: &request::Parts, config: &RpcConfig) -> Result<Option<Duration>, RpcError> {
+    let Some(value) = parts.headers.get(TIMEOUT_HEADER) else {
+        return Ok(None);
+    };
+
+    let value = value.as_bytes();
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return match config.strict_timeouts {
+            true => Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "{} must be a number of milliseconds, got `{}`",
+                    TIMEOUT_HEADER,
+                    String::from_utf8_lossy(value)
+                ),
+            )),
+            false => Ok(None),
+        };
+    }
+
+    if value.len() > MAX_TIMEOUT_DIGITS {
+        return Err(RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!(
+                "{} can have at most {} digits, got {}",
+                TIMEOUT_HEADER,
+                MAX_TIMEOUT_DIGITS,
+                value.len()
+            ),
+        ));
+    }
+
+    // At most 10 ASCII digits, so this always fits.
+    let millis = std::str::from_utf8(value).unwrap().parse::<u64>().unwrap();
+    if millis == 0 {
+        return Err(RpcError::new(
+            RpcErrorCode::DeadlineExceeded,
+            format!("{} is 0, the deadline already passed", TIMEOUT_HEADER),
+        ));
+    }
+
+    Ok(Some(Duration::from_millis(millis)))
+}
+
+fn exceeded() -> RpcError {
+    RpcError::new(
+        RpcErrorCode::DeadlineExceeded,
+        "The call's deadline passed".to_string(),
+    )
+}
+
+/// Runs `future` until the deadline, failing with `DeadlineExceeded` after it.
+pub(crate) async fn run<F: Future>(deadline: Option<Instant>, future: F) -> RpcResult<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), future)
+            .await
+            .map_err(|_| exceeded()),
+        None => Ok(future.await),
+    }
+}
+
+/// Ends a response stream with `DeadlineExceeded` once the deadline passes, dropping the rest.
+pub(crate) fn limit_stream<S, T>(
+    deadline: Option<Instant>,
+    stream: S,
+) -> impl Stream<Item = RpcResult<T>> + Send
+where
+    S: Stream<Item = RpcResult<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let Some(deadline) = deadline else {
+        return Either::Left(stream);
+    };
+
+    let expired = Box::pin(tokio::time::sleep_until(deadline.into()));
+    let state = Some((stream.boxed(), expired));
+    Either::Right(futures::stream::unfold(state, |state| async move {
+        let (mut stream, expired) = state?;
+        match futures::future::select(stream.next(), expired).await {
+            Either::Left((Some(item), expired)) => Some((item, Some((stream, expired)))),
+            Either::Left((None, _)) => None,
+            Either::Right(_) => Some((Err(exceeded()), None)),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::Request;
+
+    use super::*;
+    use crate::{
+        router::RpcRouter,
+        test_util::{client, hello, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO},
+    };
+
+    fn timeout(value: &str, strict: bool) -> Result<Option<Duration>, RpcErrorCode> {
+        let (parts, ()) = Request::post(SAY_HELLO)
+            .header(TIMEOUT_HEADER, value)
+            .body(())
+            .unwrap()
+            .into_parts();
+        let config = RpcConfig::default().strict_timeouts(strict);
+        parse(&parts, &config).map_err(|error| error.code)
+    }
+
+    #[test]
+    fn parses_valid_timeouts() {
+        for strict in [false, true] {
+            assert_eq!(
+                timeout("1500", strict),
+                Ok(Some(Duration::from_millis(1500)))
+            );
+            assert_eq!(timeout("0001", strict), Ok(Some(Duration::from_millis(1))));
+            assert_eq!(
+                timeout("9999999999", strict),
+                Ok(Some(Duration::from_millis(9_999_999_999)))
+            );
+        }
+    }
+
+    #[test]
+    fn fails_zero_timeouts_with_deadline_exceeded() {
+        for strict in [false, true] {
+            assert_eq!(timeout("0", strict), Err(RpcErrorCode::DeadlineExceeded));
+            assert_eq!(timeout("0000", strict), Err(RpcErrorCode::DeadlineExceeded));
+        }
+    }
+
+    #[test]
+    fn fails_timeouts_over_10_digits() {
+        for strict in [false, true] {
+            assert_eq!(
+                timeout("10000000000", strict),
+                Err(RpcErrorCode::InvalidArgument)
+            );
+            assert_eq!(
+                timeout("00000000001", strict),
+                Err(RpcErrorCode::InvalidArgument)
+            );
+        }
+    }
+
+    #[test]
+    fn ignores_non_numeric_timeouts_unless_strict() {
+        for value in ["", "soon", "-5", "1.5", "+10", " 10", "10ms"] {
+            assert_eq!(timeout(value, false), Ok(None), "{value:?}");
+            assert_eq!(
+                timeout(value, true),
+                Err(RpcErrorCode::InvalidArgument),
+                "{value:?}"
+            );
+        }
+    }
+
+    async fn slow_hello(request: HelloRequest) -> RpcResult<HelloResponse> {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        Ok(HelloResponse {
+            message: format!("Hello {}!", request.name),
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_calls_past_their_deadline() {
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, slow_hello)));
+        let call = |timeout: &str| {
+            let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+            request
+                .headers_mut()
+                .insert(TIMEOUT_HEADER, timeout.parse().unwrap());
+            client.send(request)
+        };
+
+        let error = call("100").await.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::DeadlineExceeded);
+        assert_eq!(call("3000").await.error(), None);
+        let error = call("0").await.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::DeadlineExceeded);
+    }
+}
@@ -0,0 +1,142 @@
+//! The handler of [`RpcRouter::mount_debug_routes`](crate::router::RpcRouter::mount_debug_routes),
+//! a JSON table of the mounted RPCs for development.
+
+use std::sync::{Arc, OnceLock};
+
+use axum::{
+    extract::Request,
+    http::{header, request, StatusCode},
+    response::IntoResponse,
+    routing::{get, MethodRouter},
+};
+
+use crate::parts::peer_address;
+
+/// An allowlist for [`mount_debug_routes`](crate::router::RpcRouter::mount_debug_routes) letting
+/// through requests from a loopback address. Needs the server's
+/// `into_make_service_with_connect_info::<SocketAddr>()` (or a `MockConnectInfo` layer), otherwise
+/// nothing is let through.
+pub fn localhost(parts: &request::Parts) -> bool {
+    peer_address(parts).is_some_and(|address| address.ip().to_canonical().is_loopback())
+}
+
+pub(crate) fn method_router<S>(
+    table: Arc<OnceLock<String>>,
+    allow: impl Fn(&request::Parts) -> bool + Send + Sync + 'static,
+) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let allow = Arc::new(allow);
+    get(move |request: Request| async move {
+        let (parts, _) = request.into_parts();
+        match table.get().filter(|_| allow(&parts)) {
+            Some(table) => {
+                ([(header::CONTENT_TYPE, "application/json")], table.clone()).into_response()
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{body::Body, extract::connect_info::MockConnectInfo, Router};
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        config::RpcConfig,
+        router::{
+            RouteOptions, RpcIdempotencyLevel, RpcMethod, RpcMethodInfo, RpcMethodKind, RpcRouter,
+        },
+        test_util::{client_of, hello_router, say_hello},
+        testing::TestResponse,
+    };
+
+    async fn get_table(router: Router) -> TestResponse {
+        let request = Request::get("/debug/rpc").body(Body::empty()).unwrap();
+        client_of(router).send(request).await
+    }
+
+    /// [`hello_router`] with its debug routes, let through by `allow`, and an RPC over GET with
+    /// options mounted after them.
+    fn debugged(allow: impl Fn(&request::Parts) -> bool + Send + Sync + 'static) -> Router {
+        let info = RpcMethodInfo::new(
+            "admin.AdminService",
+            "Status",
+            RpcMethodKind::Unary,
+            RpcIdempotencyLevel::NoSideEffects,
+        )
+        .with_http_methods(vec![axum::http::Method::POST, axum::http::Method::GET]);
+        hello_router()
+            .mount_debug_routes("/debug/rpc", allow)
+            .rpc_with_options(
+                RouteOptions::new().concurrency_limit(4),
+                |router: RpcRouter| router.rpc_method(RpcMethod::unary(info, say_hello)),
+            )
+            .with_config(RpcConfig::default().max_request_bytes(1024))
+            .into_router()
+    }
+
+    #[tokio::test]
+    async fn describes_every_mounted_rpc() {
+        let response = get_table(debugged(|_| true)).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type(), Some("application/json"));
+
+        let table: Value = serde_json::from_slice(&response.body).unwrap();
+        let routes = table["routes"].as_array().unwrap();
+        let paths = routes
+            .iter()
+            .map(|route| route["path"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                "/admin.AdminService/Status",
+                "/hello.HelloWorldService/SayHello",
+                "/hello.HelloWorldService/SayHelloStream",
+            ]
+        );
+
+        let status = &routes[0];
+        assert_eq!(status["kind"], "unary");
+        assert_eq!(status["idempotency_level"], "no_side_effects");
+        assert_eq!(status["idempotent"], true);
+        assert_eq!(status["http_methods"], serde_json::json!(["POST", "GET"]));
+        assert_eq!(status["features"]["get"], true);
+        assert_eq!(status["options"]["concurrency_limit"], 4);
+        assert_eq!(status["config"]["max_request_bytes"], 1024);
+        assert_eq!(status["authorization"], "none");
+
+        let stream = &routes[2];
+        assert_eq!(stream["kind"], "server_streaming");
+        assert_eq!(stream["features"]["get"], false);
+        assert_eq!(stream["options"]["concurrency_limit"], Value::Null);
+    }
+
+    #[tokio::test]
+    async fn answers_requests_it_doesnt_allow_with_a_404() {
+        let loopback = |ip: [u8; 4]| MockConnectInfo(SocketAddr::from((ip, 4000)));
+
+        let response = get_table(debugged(localhost).layer(loopback([127, 0, 0, 1]))).await;
+        assert_eq!(response.status, StatusCode::OK);
+
+        let response = get_table(debugged(localhost).layer(loopback([10, 0, 0, 1]))).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert!(response.body.is_empty());
+
+        // Without the peer address, nothing is let through.
+        let response = get_table(debugged(localhost)).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn is_never_mounted_implicitly() {
+        let response = get_table(hello_router().into_router()).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,263 @@
+//! Server-side defaults for request fields, see [`RouteOptions::request_defaults`].
+
+use std::{any::TypeId, fmt, ops::Range, sync::Arc};
+
+use axum::http::request;
+use prost::{
+    bytes::Buf,
+    encoding::{decode_key, skip_field, DecodeContext},
+    Message,
+};
+
+use crate::{
+    error::{RpcError, RpcErrorCode},
+    response::RpcResult,
+    router::RouteOptions,
+};
+
+/// A template of [`RouteOptions::request_defaults`]. Templates are equal if they're of the same
+/// message type and encode the same.
+#[derive(Clone)]
+pub struct RequestDefaults(Arc<Template>);
+
+struct Template {
+    type_id: TypeId,
+    type_name: &'static str,
+    encoded: Vec<u8>,
+    /// The number of each field in `encoded`, with the bytes it's encoded in.
+    fields: Vec<(u32, Range<usize>)>,
+}
+
+impl RequestDefaults {
+    pub fn new<M: Message + 'static>(template: &M) -> Self {
+        let encoded = template.encode_to_vec();
+        Self(Arc::new(Template {
+            type_id: TypeId::of::<M>(),
+            type_name: std::any::type_name::<M>(),
+            fields: fields(&encoded),
+            encoded,
+        }))
+    }
+
+    /// The name of the template's message type, eg. `my_crate::proto::ListUsersRequest`.
+    pub fn type_name(&self) -> &'static str {
+        self.0.type_name
+    }
+
+    pub(crate) fn is_for<M: 'static>(&self) -> bool {
+        self.0.type_id == TypeId::of::<M>()
+    }
+
+    /// `message` with the template's fields where it has none.
+    ///
+    /// Works on the encodings: the template's fields whose numbers don't appear in the message's
+    /// are put in front of it, then it's decoded again. Fields without presence are only encoded
+    /// when they aren't the default, those with presence (`optional` and message fields) whenever
+    /// they're set, so either way only unset fields are filled. Set message fields are kept as
+    /// they are, not merged with the template's. A oneof case the message sets comes after the
+    /// template's, so it wins.
+    pub(crate) fn apply<M: Message + Default>(&self, message: M) -> RpcResult<M> {
+        let encoded = message.encode_to_vec();
+        let present = fields(&encoded);
+
+        let mut merged = Vec::with_capacity(self.0.encoded.len() + encoded.len());
+        for (number, range) in &self.0.fields {
+            if !present.iter().any(|(present, _)| present == number) {
+                merged.extend_from_slice(&self.0.encoded[range.clone()]);
+            }
+        }
+        merged.extend_from_slice(&encoded);
+
+        M::decode(merged.as_slice()).map_err(|error| {
+            RpcError::new(
+                RpcErrorCode::Internal,
+                format!("Failed to apply the request defaults: {}", error),
+            )
+        })
+    }
+}
+
+impl fmt::Debug for RequestDefaults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RequestDefaults")
+            .field(&self.0.type_name)
+            .finish()
+    }
+}
+
+impl PartialEq for RequestDefaults {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.type_id == other.0.type_id && self.0.encoded == other.0.encoded
+    }
+}
+
+impl Eq for RequestDefaults {}
+
+/// Fills the unset fields of `message` from the route's defaults for its type, if it has any.
+pub(crate) fn apply<M: Message + Default + 'static>(
+    parts: &request::Parts,
+    message: M,
+) -> RpcResult<M> {
+    match for_message::<M>(parts) {
+        Some(defaults) => defaults.apply(message),
+        None => Ok(message),
+    }
+}
+
+/// The route's defaults for messages of type `M`, eg. to fill each message of a request stream.
+pub(crate) fn for_message<M: 'static>(parts: &request::Parts) -> Option<RequestDefaults> {
+    parts.extensions.get::<RouteOptions>().and_then(|options| {
+        options
+            .request_defaults
+            .iter()
+            .find(|defaults| defaults.is_for::<M>())
+            .cloned()
+    })
+}
+
+/// The fields of an encoded message, in order. Both encodings come from prost, so they're valid.
+fn fields(encoded: &[u8]) -> Vec<(u32, Range<usize>)> {
+    let mut fields = vec![];
+    let mut buf = encoded;
+    while buf.has_remaining() {
+        let start = encoded.len() - buf.remaining();
+        let Ok((number, wire_type)) = decode_key(&mut buf) else {
+            break;
+        };
+        if skip_field(wire_type, number, &mut buf, DecodeContext::default()).is_err() {
+            break;
+        }
+        fields.push((number, start..encoded.len() - buf.remaining()));
+    }
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        router::RpcRouter,
+        test_util::{
+            client, message, proto_request, say_hello, unary, HelloRequest, HelloResponse,
+            SAY_HELLO,
+        },
+    };
+
+    #[derive(Clone, PartialEq, Message)]
+    struct ListUsers {
+        #[prost(int32, tag = "1")]
+        page_size: i32,
+        #[prost(string, optional, tag = "2")]
+        filter: Option<String>,
+        #[prost(string, repeated, tag = "3")]
+        roles: Vec<String>,
+        #[prost(message, optional, tag = "4")]
+        order: Option<Order>,
+    }
+
+    #[derive(Clone, PartialEq, Message)]
+    struct Order {
+        #[prost(string, tag = "1")]
+        field: String,
+        #[prost(bool, tag = "2")]
+        descending: bool,
+    }
+
+    fn template() -> RequestDefaults {
+        RequestDefaults::new(&ListUsers {
+            page_size: 50,
+            filter: Some("active".to_string()),
+            roles: vec!["member".to_string()],
+            order: Some(Order {
+                field: "name".to_string(),
+                descending: true,
+            }),
+        })
+    }
+
+    #[test]
+    fn fills_unset_fields() {
+        let filled = template().apply(ListUsers::default()).unwrap();
+
+        assert_eq!(filled.page_size, 50);
+        assert_eq!(filled.filter.as_deref(), Some("active"));
+        assert_eq!(filled.roles, ["member"]);
+        assert_eq!(filled.order.unwrap().field, "name");
+    }
+
+    #[test]
+    fn keeps_set_fields() {
+        let request = ListUsers {
+            page_size: 10,
+            filter: Some("admins".to_string()),
+            roles: vec!["admin".to_string(), "owner".to_string()],
+            order: None,
+        };
+
+        let filled = template().apply(request).unwrap();
+
+        assert_eq!(filled.page_size, 10);
+        assert_eq!(filled.filter.as_deref(), Some("admins"));
+        assert_eq!(filled.roles, ["admin", "owner"]);
+    }
+
+    #[test]
+    fn keeps_fields_explicitly_set_to_their_default_if_they_have_presence() {
+        let request = ListUsers {
+            filter: Some(String::new()),
+            ..Default::default()
+        };
+
+        let filled = template().apply(request).unwrap();
+
+        assert_eq!(filled.filter.as_deref(), Some(""));
+        // Without presence a zero is the same as unset.
+        assert_eq!(filled.page_size, 50);
+    }
+
+    #[test]
+    fn doesnt_merge_set_message_fields() {
+        let request = ListUsers {
+            order: Some(Order {
+                field: "email".to_string(),
+                descending: false,
+            }),
+            ..Default::default()
+        };
+
+        let order = template().apply(request).unwrap().order.unwrap();
+
+        assert_eq!(order.field, "email");
+        assert!(!order.descending);
+    }
+
+    #[tokio::test]
+    async fn only_fills_requests_of_the_templates_type() {
+        let options = RouteOptions::new()
+            .request_defaults(HelloRequest {
+                name: "stranger".to_string(),
+            })
+            .request_defaults(ListUsers::default());
+        let client = client(RpcRouter::new().rpc_with_options(options, |router| {
+            router.rpc_method(unary(SAY_HELLO, say_hello))
+        }));
+
+        let unnamed = client
+            .send(proto_request(SAY_HELLO, &HelloRequest::default()))
+            .await;
+        let named = client
+            .send(proto_request(
+                SAY_HELLO,
+                &HelloRequest {
+                    name: "Ada".to_string(),
+                },
+            ))
+            .await;
+
+        assert_eq!(
+            message::<HelloResponse>(&unnamed).message,
+            "Hello stranger!"
+        );
+        assert_eq!(message::<HelloResponse>(&named).message, "Hello Ada!");
+    }
+}
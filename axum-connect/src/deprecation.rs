@@ -0,0 +1,377 @@
+//! Sunsetting RPCs gradually, see [`RouteOptions::deprecated`](crate::router::RouteOptions).
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    codec::ContentType,
+    error::{RpcError, RpcErrorCode},
+    handler::codec::ResponseEncoder,
+    logging::RpcCallStats,
+    router::RouteOptions,
+};
+
+/// How often each route logs that a deprecated RPC was called.
+const WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// A deprecated RPC, still served until its sunset. Every response gets a `Deprecation` header
+/// (the date it was deprecated, RFC 9745) and a `Sunset` header (RFC 8594), calls are logged as a
+/// `warn` level `tracing` event on the `axum_connect::deprecation` target with the caller's
+/// user-agent, at most once a minute per route, and [`RpcLog::deprecated`] is set for metrics.
+///
+/// After the sunset, [`fail_after_sunset`](Deprecation::fail_after_sunset) fails a share of the
+/// calls, to flush out the clients left.
+///
+/// [`RpcLog::deprecated`]: crate::logging::RpcLog::deprecated
+#[derive(Clone)]
+pub struct Deprecation {
+    message: String,
+    since: SystemTime,
+    sunset: SystemTime,
+    fail_after_sunset: u8,
+    /// The state of the generator picking the calls that fail.
+    rng: Arc<AtomicU64>,
+    /// By route, when the last warning was logged and how many calls haven't been logged since.
+    warnings: Arc<Mutex<HashMap<String, (Instant, u64)>>>,
+}
+
+impl Deprecation {
+    /// `message` says what to use instead, it's logged and sent with the errors of
+    /// [`fail_after_sunset`](Deprecation::fail_after_sunset). The RPC counts as deprecated since
+    /// now, see [`since`](Deprecation::since).
+    pub fn new(message: &str, sunset: SystemTime) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        Self {
+            message: message.to_string(),
+            since: SystemTime::now(),
+            sunset,
+            fail_after_sunset: 0,
+            rng: Arc::new(AtomicU64::new(seed)),
+            warnings: Default::default(),
+        }
+    }
+
+    /// When the RPC was deprecated, for the `Deprecation` header.
+    pub fn since(mut self, since: SystemTime) -> Self {
+        self.since = since;
+        self
+    }
+
+    /// Fails `percent` of the calls after the sunset with `FailedPrecondition`, picked at random.
+    /// None by default, 100 fails them all.
+    pub fn fail_after_sunset(mut self, percent: u8) -> Self {
+        self.fail_after_sunset = percent.min(100);
+        self
+    }
+
+    /// Seeds the generator picking the calls [`fail_after_sunset`](Deprecation::fail_after_sunset)
+    /// fails, so which ones do is the same from run to run.
+    pub fn seed(self, seed: u64) -> Self {
+        self.rng.store(seed, Ordering::Relaxed);
+        self
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn sunset(&self) -> SystemTime {
+        self.sunset
+    }
+
+    /// Whether this call is one of those failed after the sunset.
+    fn fails(&self, now: SystemTime) -> bool {
+        if now < self.sunset || self.fail_after_sunset == 0 {
+            return false;
+        }
+        // splitmix64
+        let mut z = self
+            .rng
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        z % 100 < u64::from(self.fail_after_sunset)
+    }
+
+    /// Logs the call, unless the route already did within [`WARNING_INTERVAL`].
+    fn warn(&self, path: &str, user_agent: &str) {
+        let suppressed = {
+            let mut warnings = self.warnings.lock().unwrap();
+            match warnings.get_mut(path) {
+                Some((last, suppressed)) if last.elapsed() < WARNING_INTERVAL => {
+                    *suppressed += 1;
+                    return;
+                }
+                Some((last, suppressed)) => {
+                    *last = Instant::now();
+                    std::mem::take(suppressed)
+                }
+                None => {
+                    warnings.insert(path.to_string(), (Instant::now(), 0));
+                    0
+                }
+            }
+        };
+
+        tracing::warn!(
+            target: "axum_connect::deprecation",
+            procedure = path,
+            user_agent,
+            sunset = %http_date(self.sunset),
+            // Calls since the last warning that weren't logged.
+            suppressed,
+            "deprecated rpc called: {}",
+            self.message
+        );
+    }
+}
+
+impl fmt::Debug for Deprecation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Deprecation")
+            .field("message", &self.message)
+            .field("since", &self.since)
+            .field("sunset", &self.sunset)
+            .field("fail_after_sunset", &self.fail_after_sunset)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Deprecations are equal if they're configured the same.
+impl PartialEq for Deprecation {
+    fn eq(&self, other: &Self) -> bool {
+        self.message == other.message
+            && self.since == other.since
+            && self.sunset == other.sunset
+            && self.fail_after_sunset == other.fail_after_sunset
+    }
+}
+
+impl Eq for Deprecation {}
+
+/// The `route_layers` middleware applying the route's [`Deprecation`], if it has one.
+pub(crate) async fn deprecation(request: Request, next: Next) -> Response {
+    let Some(deprecation) = request
+        .extensions()
+        .get::<RouteOptions>()
+        .and_then(|options| options.deprecation.clone())
+    else {
+        return next.run(request).await;
+    };
+
+    let user_agent = request
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("unknown");
+    deprecation.warn(request.uri().path(), user_agent);
+
+    let mut response = if deprecation.fails(SystemTime::now()) {
+        let content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+            .filter(|_| request.method() != Method::GET);
+        let streaming = content_type.is_some_and(|content_type| content_type.is_streaming());
+        let binary = content_type.is_some_and(|content_type| content_type.is_binary());
+        let error = RpcError::new(
+            RpcErrorCode::FailedPrecondition,
+            format!(
+                "{} was sunset on {}: {}",
+                request.uri().path(),
+                http_date(deprecation.sunset),
+                deprecation.message
+            ),
+        );
+        ResponseEncoder::error(error, streaming, binary).encode_response()
+    } else {
+        next.run(request).await
+    };
+
+    let since = deprecation
+        .since
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let headers = response.headers_mut();
+    if let Ok(since) = HeaderValue::try_from(format!("@{}", since)) {
+        headers.insert(DEPRECATION, since);
+    }
+    if let Ok(sunset) = HeaderValue::from_str(&http_date(deprecation.sunset)) {
+        headers.insert(SUNSET, sunset);
+    }
+    if let Some(stats) = response.extensions().get::<Arc<RpcCallStats>>() {
+        stats.set_deprecated();
+    }
+    response
+}
+
+/// `time` as an HTTP date, eg. `Wed, 01 Jul 2026 00:00:00 GMT`.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, seconds) = (seconds / 86_400, seconds % 86_400);
+
+    // Days since the epoch to a civil date, from http://howardhinnant.github.io/date_algorithms.html.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 2
+    } else {
+        shifted_month - 10
+    };
+    let year = era * 400 + year_of_era + u64::from(month < 2);
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[((days - 719_468) % 7) as usize],
+        day,
+        MONTHS[month as usize],
+        year,
+        seconds / 3_600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        router::RpcRouter,
+        test_util::{
+            client, hello, message, proto_request, say_hello, say_hello_stream, server_stream,
+            stream_request, unary, HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+        testing::TestClient,
+    };
+
+    const SUNSET_DATE: &str = "Wed, 01 Jul 2026 00:00:00 GMT";
+
+    fn sunset() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_782_864_000)
+    }
+
+    fn deprecated(deprecation: Deprecation) -> TestClient {
+        let options = RouteOptions::new().deprecation(deprecation);
+        client(RpcRouter::new().rpc_with_options(options, |router| {
+            router
+                .rpc_method(unary(SAY_HELLO, say_hello))
+                .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+        }))
+    }
+
+    fn before_the_sunset() -> Deprecation {
+        let sunset = UNIX_EPOCH + Duration::from_secs(4_102_444_800);
+        Deprecation::new("Use SayHelloV2", sunset)
+            .fail_after_sunset(100)
+            .since(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+    }
+
+    #[test]
+    fn formats_http_dates() {
+        assert_eq!(http_date(UNIX_EPOCH), "Thu, 01 Jan 1970 00:00:00 GMT");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400 + 45_296);
+        assert_eq!(http_date(leap_day), "Tue, 29 Feb 2000 12:34:56 GMT");
+        let sunset = UNIX_EPOCH + Duration::from_secs(1_782_864_000);
+        assert_eq!(http_date(sunset), "Wed, 01 Jul 2026 00:00:00 GMT");
+    }
+
+    #[tokio::test]
+    async fn serves_deprecated_rpcs_with_the_deprecation_headers() {
+        let client = deprecated(before_the_sunset());
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        assert_eq!(response.headers[DEPRECATION], "@1700000000");
+        assert_eq!(response.headers[SUNSET], "Fri, 01 Jan 2100 00:00:00 GMT");
+    }
+
+    #[tokio::test]
+    async fn keeps_serving_after_the_sunset_unless_told_to_fail() {
+        let client = deprecated(Deprecation::new("Use SayHelloV2", sunset()));
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        assert_eq!(response.headers[SUNSET], SUNSET_DATE);
+    }
+
+    #[tokio::test]
+    async fn fails_calls_after_the_sunset() {
+        let client =
+            deprecated(Deprecation::new("Use SayHelloV2", sunset()).fail_after_sunset(100));
+
+        let unary = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        let stream = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        let error = unary.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::FailedPrecondition);
+        assert_eq!(
+            error.message,
+            format!("{SAY_HELLO} was sunset on {SUNSET_DATE}: Use SayHelloV2")
+        );
+        assert_eq!(unary.headers[SUNSET], SUNSET_DATE);
+        assert_eq!(stream.content_type(), Some("application/connect+proto"));
+        let (frames, error) = stream.frames();
+        assert!(frames.is_empty());
+        assert_eq!(error.unwrap().code, RpcErrorCode::FailedPrecondition);
+    }
+
+    #[tokio::test]
+    async fn fails_a_share_of_the_calls_after_the_sunset() {
+        let deprecation = Deprecation::new("Use SayHelloV2", sunset())
+            .fail_after_sunset(50)
+            .seed(7);
+        let client = deprecated(deprecation);
+
+        let mut failed = 0;
+        for _ in 0..100 {
+            let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+            failed += usize::from(response.error().is_some());
+        }
+
+        assert!((30..=70).contains(&failed), "{failed} of 100 calls failed");
+    }
+}
@@ -0,0 +1,375 @@
+//! Draining a server for rolling deploys, see [`DrainController`].
+
+use std::{
+    fmt,
+    future::Future,
+    pin::{pin, Pin},
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::{get, MethodRouter},
+};
+use http_body::{Frame, SizeHint};
+use tokio::{sync::watch, time::Instant};
+
+use crate::{
+    codec::ContentType,
+    error::{RpcError, RpcErrorCode},
+    handler::codec::ResponseEncoder,
+};
+
+/// Drains the RPCs of a router before it's shut down, so a rolling deploy drops no calls: once
+/// [`start_drain`](DrainController::start_drain) is called,
+///
+/// - the [`readiness`](DrainController::readiness) route answers `503`, so the load balancer
+///   stops sending traffic,
+/// - the ping service and new calls fail with `Unavailable`, which clients retry elsewhere,
+/// - calls in flight run to completion, but open response streams end with `Unavailable` after
+///   the [grace period](DrainController::grace_period).
+///
+/// Mount it on an [`RpcRouter`](crate::router::RpcRouter) with
+/// [`drain`](crate::router::RpcRouter::drain), it counts the calls of its RPC routes until their
+/// response has been sent. Drain first, then stop accepting connections, eg. in the graceful
+/// shutdown signal of `axum_connect::serve`:
+///
+/// ```no_run
+/// # use axum_connect::{drain::DrainController, prelude::*};
+/// # use std::time::Duration;
+/// # async fn run(app: RpcRouter) {
+/// # async fn sigterm() {}
+/// let drain = DrainController::new().grace_period(Duration::from_secs(10));
+/// let app = app
+///     .drain(drain.clone())
+///     .rest_route("/readyz", drain.readiness());
+/// // Serve `app`, and once the process is told to stop:
+/// sigterm().await;
+/// drain.start_drain().await;
+/// // Shut the server down.
+/// # }
+/// ```
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct DrainController {
+    grace_period: Duration,
+    state: Arc<watch::Sender<DrainState>>,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct DrainState {
+    /// When the drain started, `None` while serving.
+    started: Option<Instant>,
+    in_flight: usize,
+}
+
+impl DrainController {
+    /// Serving, with no grace period: response streams end as soon as the drain starts.
+    pub fn new() -> Self {
+        Self {
+            grace_period: Duration::ZERO,
+            state: Arc::new(watch::Sender::new(DrainState::default())),
+        }
+    }
+
+    /// How long open response streams keep going once the drain starts, eg. for the load balancer
+    /// to notice the failing readiness first.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    /// Starts draining, right away rather than when the future is polled. The future resolves
+    /// once no call is in flight anymore, which may be never for a stream that's never polled.
+    /// Calling it again doesn't restart the grace period.
+    pub fn start_drain(&self) -> impl Future<Output = ()> + Send + 'static {
+        self.state.send_if_modified(|state| {
+            let starts = state.started.is_none();
+            if starts {
+                state.started = Some(Instant::now());
+            }
+            starts
+        });
+
+        let mut state = self.state.subscribe();
+        async move {
+            // The sender lives as long as the controller, and the controller as long as its
+            // calls: if it's gone, so are they.
+            let _ = state.wait_for(|state| state.in_flight == 0).await;
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.state.borrow().started.is_some()
+    }
+
+    /// The calls in flight, until their response has been sent.
+    pub fn in_flight(&self) -> usize {
+        self.state.borrow().in_flight
+    }
+
+    /// A readiness probe: `200 ok` while serving, `503 draining` once the drain starts.
+    pub fn readiness<S>(&self) -> MethodRouter<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let drain = self.clone();
+        get(move || async move {
+            let (status, body) = match drain.is_draining() {
+                true => (StatusCode::SERVICE_UNAVAILABLE, "draining"),
+                false => (StatusCode::OK, "ok"),
+            };
+            (status, [(header::CONTENT_TYPE, "text/plain")], body).into_response()
+        })
+    }
+
+    /// The error of calls and pings arriving during the drain.
+    pub(crate) fn unavailable() -> RpcError {
+        RpcError::new(
+            RpcErrorCode::Unavailable,
+            "The server is draining".to_string(),
+        )
+    }
+
+    /// Resolves once the drain has started and its grace period ran out.
+    pub(crate) async fn streams_ended(&self) {
+        let mut state = self.state.subscribe();
+        let started = match state.wait_for(|state| state.started.is_some()).await {
+            Ok(state) => state.started,
+            Err(_) => None,
+        };
+        match started {
+            Some(started) => tokio::time::sleep_until(started + self.grace_period).await,
+            None => std::future::pending().await,
+        }
+    }
+
+    /// Counts a call in, unless the drain started.
+    fn enter(&self) -> Option<InFlight> {
+        let entered = self.state.send_if_modified(|state| {
+            if state.started.is_some() {
+                return false;
+            }
+            state.in_flight += 1;
+            true
+        });
+        entered.then(|| InFlight(self.clone()))
+    }
+}
+
+impl Default for DrainController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for DrainController {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = *self.state.borrow();
+        f.debug_struct("DrainController")
+            .field("grace_period", &self.grace_period)
+            .field("draining", &state.started.is_some())
+            .field("in_flight", &state.in_flight)
+            .finish()
+    }
+}
+
+/// A call in flight, counted out when it's dropped.
+struct InFlight(DrainController);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.state.send_modify(|state| state.in_flight -= 1);
+    }
+}
+
+/// The `route_layers` middleware counting the calls of a [`DrainController`], and failing them
+/// once it drains.
+pub(crate) async fn track(request: Request, next: Next) -> Response {
+    let Some(drain) = request.extensions().get::<DrainController>().cloned() else {
+        return next.run(request).await;
+    };
+
+    let Some(in_flight) = drain.enter() else {
+        let content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+            .filter(|_| request.method() != Method::GET);
+        let streaming = content_type.is_some_and(|content_type| content_type.is_streaming());
+        let binary = content_type.is_some_and(|content_type| content_type.is_binary());
+        return ResponseEncoder::error(DrainController::unavailable(), streaming, binary)
+            .encode_response();
+    };
+
+    next.run(request).await.map(|body| {
+        Body::new(InFlightBody {
+            inner: body,
+            _in_flight: in_flight,
+        })
+    })
+}
+
+/// A response body, the call is in flight until it's sent (or dropped).
+struct InFlightBody {
+    inner: Body,
+    _in_flight: InFlight,
+}
+
+impl HttpBody for InFlightBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        pin!(&mut self.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use futures::{stream, FutureExt, Stream, StreamExt};
+    use tokio::sync::{mpsc, Semaphore};
+
+    use super::*;
+    use crate::{
+        response::RpcResult,
+        router::RpcRouter,
+        test_util::{
+            client, hello, message, proto_request, say_hello, server_stream, stream_request, unary,
+            HelloRequest, HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+        testing::TestClient,
+    };
+
+    fn drained(drain: &DrainController, router: RpcRouter) -> TestClient {
+        client(
+            router
+                .drain(drain.clone())
+                .rest_route("/readyz", drain.readiness()),
+        )
+    }
+
+    #[tokio::test]
+    async fn fails_readiness_and_new_calls_once_draining() {
+        let drain = DrainController::new();
+        let client = drained(
+            &drain,
+            RpcRouter::new().rpc_method(unary(SAY_HELLO, say_hello)),
+        );
+        let readyz = || Request::get("/readyz").body(Body::empty()).unwrap();
+
+        let ready = client.send(readyz()).await;
+        let served = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        drain.start_drain().await;
+        let draining = client.send(readyz()).await;
+        let refused = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(
+            (ready.status, &ready.body[..]),
+            (StatusCode::OK, &b"ok"[..])
+        );
+        assert_eq!(message::<HelloResponse>(&served).message, "Hello Ada!");
+        assert_eq!(
+            (draining.status, &draining.body[..]),
+            (StatusCode::SERVICE_UNAVAILABLE, &b"draining"[..])
+        );
+        let error = refused.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::Unavailable);
+        assert_eq!(error.message, "The server is draining");
+        assert!(drain.is_draining());
+    }
+
+    #[tokio::test]
+    async fn finishes_the_calls_in_flight() {
+        let (entered, mut entered_rx) = mpsc::unbounded_channel();
+        let open = Arc::new(Semaphore::new(0));
+        let held = {
+            let open = open.clone();
+            move |request: HelloRequest| {
+                let (entered, open) = (entered.clone(), open.clone());
+                async move {
+                    entered.send(()).unwrap();
+                    let _permit = open.acquire().await.unwrap();
+                    say_hello(request).await
+                }
+            }
+        };
+        let drain = DrainController::new();
+        let client = drained(&drain, RpcRouter::new().rpc_method(unary(SAY_HELLO, held)));
+
+        let in_flight = tokio::spawn({
+            let client = client.clone();
+            async move { client.send(proto_request(SAY_HELLO, &hello("Ada"))).await }
+        });
+        entered_rx.recv().await.unwrap();
+        let drained = Arc::new(AtomicBool::new(false));
+        let drained_all = drain.start_drain().map({
+            let drained = drained.clone();
+            move |_| drained.store(true, Ordering::SeqCst)
+        });
+        let drained_all = tokio::spawn(drained_all);
+        tokio::task::yield_now().await;
+
+        assert_eq!(drain.in_flight(), 1);
+        assert!(!drained.load(Ordering::SeqCst));
+        open.add_permits(1);
+        let response = in_flight.await.unwrap();
+        drained_all.await.unwrap();
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        assert_eq!(drain.in_flight(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ends_open_streams_after_the_grace_period() {
+        async fn endless(_: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            let first = HelloResponse {
+                message: "first".to_string(),
+            };
+            stream::iter([Ok(first)]).chain(stream::pending())
+        }
+        let drain = DrainController::new().grace_period(Duration::from_secs(10));
+        let client = drained(
+            &drain,
+            RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, endless)),
+        );
+
+        let stream = tokio::spawn({
+            let client = client.clone();
+            async move {
+                client
+                    .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let start = Instant::now();
+        drain.start_drain().await;
+
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+        let (frames, error) = stream.await.unwrap().frames();
+        assert_eq!(frames.len(), 1);
+        let error = error.unwrap();
+        assert_eq!(error.code, RpcErrorCode::Unavailable);
+        assert_eq!(error.message, "The server is shutting down");
+    }
+}
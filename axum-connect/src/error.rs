@@ -1,14 +1,51 @@
-use axum::http::StatusCode;
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use axum::{
+    extract::rejection,
+    http::{self, HeaderMap, HeaderValue, StatusCode},
+};
+use pbjson_types::Any;
 use prost::Message;
-use serde::Serialize;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{prelude::RpcResult, response::RpcIntoResponse};
+use crate::{codec, prelude::RpcResult, response::RpcIntoResponse};
 
-#[derive(Clone, Serialize)]
+/// Serializes to (and deserializes from) the Connect error JSON, the same impls the unary error
+/// body and [`from_response`](RpcError::from_response) go through, so the representation can be
+/// stored and read back as-is, eg. records of failed calls. The metadata isn't part of it.
+/// Details with binary values round-trip as their base64:
+///
+/// ```
+/// # use axum_connect::error::{ErrorInfo, RpcError, RpcErrorCode};
+/// let error = RpcError::new(RpcErrorCode::ResourceExhausted, "Quota exceeded".to_string())
+///     .with_app_code("QUOTA_SOFT_LIMIT");
+///
+/// let json = serde_json::to_string(&error).unwrap();
+/// assert_eq!(
+///     json,
+///     r#"{"code":"resource_exhausted","message":"Quota exceeded","details":[{"type":"google.rpc.ErrorInfo","value":"ChBRVU9UQV9TT0ZUX0xJTUlU"}]}"#
+/// );
+///
+/// let parsed: RpcError = serde_json::from_str(&json).unwrap();
+/// assert_eq!(parsed.code, error.code);
+/// assert_eq!(parsed.details, error.details);
+/// assert_eq!(parsed.app_code().as_deref(), Some("QUOTA_SOFT_LIMIT"));
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RpcError {
     pub code: RpcErrorCode,
+    // Both are optional on the wire, connect-go omits them when empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub details: Vec<RpcErrorDetail>,
+    /// Boxed to keep `RpcResult`s small, see [`metadata`](RpcError::metadata).
+    #[serde(skip)]
+    metadata: Box<HeaderMap>,
+    /// What happened, for the server's own logs only: it's never sent to the client. See
+    /// [`with_internal_message`](RpcError::with_internal_message).
+    #[serde(skip)]
+    internal_message: Option<String>,
 }
 
 pub trait RpcIntoError {
@@ -27,25 +64,229 @@ impl RpcError {
             code,
             message,
             details: vec![],
+            metadata: Default::default(),
+            internal_message: None,
+        }
+    }
+
+    pub fn with_metadata(mut self, metadata: HeaderMap) -> Self {
+        self.metadata = Box::new(metadata);
+        self
+    }
+
+    /// Sent as response headers for unary RPCs, and as the end-of-stream metadata for streaming
+    /// ones. Duplicate keys are sent as-is.
+    pub fn metadata(&self) -> &HeaderMap {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut HeaderMap {
+        &mut self.metadata
+    }
+
+    /// Keeps `message` for the server's logs, alongside the one sent to the client, eg. the
+    /// upstream failure behind a "Payment declined". It shows up in [`RpcLog`], the
+    /// [`RpcCallStats`] and the spans of [`RpcTraceLayer`], and in the `Debug` output, but never in
+    /// the response or the `Display` output.
+    ///
+    /// ```
+    /// # use axum_connect::error::{RpcError, RpcErrorCode};
+    /// let error = RpcError::new(RpcErrorCode::FailedPrecondition, "Payment declined".to_string())
+    ///     .with_internal_message("stripe error 402: card_declined");
+    ///
+    /// assert_eq!(error.to_string(), "failed_precondition: Payment declined");
+    /// assert!(!serde_json::to_string(&error).unwrap().contains("stripe"));
+    /// assert!(format!("{:?}", error).contains("stripe"));
+    /// ```
+    ///
+    /// [`RpcLog`]: crate::logging::RpcLog
+    /// [`RpcCallStats`]: crate::logging::RpcCallStats
+    /// [`RpcTraceLayer`]: crate::trace::RpcTraceLayer
+    pub fn with_internal_message(mut self, message: impl Into<String>) -> Self {
+        self.internal_message = Some(message.into());
+        self
+    }
+
+    pub fn internal_message(&self) -> Option<&str> {
+        self.internal_message.as_deref()
+    }
+
+    /// Attaches an application-specific error code (eg. `QUOTA_SOFT_LIMIT`), for taxonomies finer
+    /// than the Connect codes. It's sent as the `reason` of a `google.rpc.ErrorInfo` detail, and in
+    /// the `x-app-error-code` metadata.
+    pub fn with_app_code(self, app_code: &str) -> Self {
+        self.with_error_info(ErrorInfo {
+            reason: app_code.to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Like [`with_app_code`](Self::with_app_code), with the `ErrorInfo`'s domain and metadata too.
+    pub fn with_error_info(mut self, info: ErrorInfo) -> Self {
+        if let Ok(value) = HeaderValue::from_str(&info.reason) {
+            self.metadata.insert(APP_ERROR_CODE_HEADER, value);
+        }
+        self.details
+            .push(RpcErrorDetail::new("google.rpc.ErrorInfo", &info));
+        self
+    }
+
+    /// The error of a unary error response, with its headers as the metadata. A body that isn't a
+    /// Connect error (eg. a proxy's HTML page) gets the code of the status, see
+    /// [`RpcErrorCode::from_http_status`].
+    pub fn from_response<B: AsRef<[u8]>>(response: &http::Response<B>) -> Self {
+        let status = response.status();
+        let error = match codec::parse_unary_error(response.body().as_ref()) {
+            Ok(error) => error,
+            Err(_) => RpcError::new(
+                RpcErrorCode::from_http_status(status),
+                format!("HTTP status {}", status),
+            ),
+        };
+        error.with_metadata(response.headers().clone())
+    }
+
+    /// The application-specific error code, from the first `google.rpc.ErrorInfo` detail or else
+    /// the `x-app-error-code` metadata.
+    pub fn app_code(&self) -> Option<String> {
+        let reason = self
+            .details
+            .iter()
+            .filter(|detail| detail.proto_type == "google.rpc.ErrorInfo")
+            .find_map(|detail| detail.decode::<ErrorInfo>())
+            .map(|info| info.reason)
+            .filter(|reason| !reason.is_empty());
+
+        reason.or_else(|| {
+            let value = self.metadata.get(APP_ERROR_CODE_HEADER)?.to_str().ok()?;
+            Some(value.to_string())
+        })
+    }
+}
+
+/// The code and the message sent to the client, without the internal message.
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.message.as_str() {
+            "" => write!(f, "{}", self.code),
+            message => write!(f, "{}: {}", self.code, message),
         }
     }
 }
 
+const APP_ERROR_CODE_HEADER: &str = "x-app-error-code";
+
+/// `google.rpc.ErrorInfo`, the structured cause of an error.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ErrorInfo {
+    /// The application-specific error code, eg. `QUOTA_SOFT_LIMIT`.
+    #[prost(string, tag = "1")]
+    pub reason: String,
+    /// The service or product the reason belongs to, eg. `billing.example.com`.
+    #[prost(string, tag = "2")]
+    pub domain: String,
+    #[prost(map = "string, string", tag = "3")]
+    pub metadata: HashMap<String, String>,
+}
+
+/// `google.rpc.BadRequest`, the fields of a request that were invalid.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct BadRequest {
+    #[prost(message, repeated, tag = "1")]
+    pub field_violations: Vec<FieldViolation>,
+}
+
+/// `google.rpc.BadRequest.FieldViolation`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct FieldViolation {
+    /// The path of the field, eg. `users[3].email`.
+    #[prost(string, tag = "1")]
+    pub field: String,
+    #[prost(string, tag = "2")]
+    pub description: String,
+}
+
 impl<C, M> RpcIntoError for (C, M)
 where
     C: Into<RpcErrorCode>,
     M: Into<String>,
 {
     fn rpc_into_error(self) -> RpcError {
-        RpcError {
-            code: self.0.into(),
-            message: self.1.into(),
-            details: vec![],
-        }
+        RpcError::new(self.0.into(), self.1.into())
     }
 }
 
-#[derive(Clone, Serialize)]
+/// The rejections of axum's extractors, for custom extractors built on them, by their status:
+/// a body over the limit is `ResourceExhausted`, other client errors (a malformed query, invalid
+/// JSON) are `InvalidArgument`, and server errors (a missing extension, say) are `Internal`. The
+/// message is axum's.
+macro_rules! impl_rejections {
+    ($($(#[$meta:meta])* $rejection:ty,)*) => {
+        $(
+            $(#[$meta])*
+            impl RpcIntoError for $rejection {
+                fn rpc_into_error(self) -> RpcError {
+                    rejection_error(self.status(), self.body_text())
+                }
+            }
+
+            $(#[$meta])*
+            impl From<$rejection> for RpcError {
+                fn from(rejection: $rejection) -> Self {
+                    rejection.rpc_into_error()
+                }
+            }
+        )*
+    };
+}
+
+impl_rejections! {
+    rejection::BytesRejection,
+    rejection::FailedToBufferBody,
+    rejection::LengthLimitError,
+    rejection::UnknownBodyError,
+    rejection::StringRejection,
+    rejection::InvalidUtf8,
+    rejection::JsonRejection,
+    rejection::JsonDataError,
+    rejection::JsonSyntaxError,
+    rejection::MissingJsonContentType,
+    rejection::ExtensionRejection,
+    rejection::MissingExtension,
+    rejection::PathRejection,
+    rejection::RawPathParamsRejection,
+    rejection::FailedToDeserializePathParams,
+    rejection::InvalidUtf8InPathParam,
+    rejection::MissingPathParams,
+    rejection::QueryRejection,
+    rejection::FailedToDeserializeQueryString,
+    rejection::FormRejection,
+    rejection::RawFormRejection,
+    rejection::InvalidFormContentType,
+    rejection::FailedToDeserializeForm,
+    rejection::FailedToDeserializeFormBody,
+    rejection::MatchedPathRejection,
+    rejection::MatchedPathMissing,
+    rejection::NestedPathRejection,
+    axum::extract::multipart::MultipartRejection,
+    #[cfg(feature = "axum-extra")]
+    axum_extra::extract::rejection::HostRejection,
+    #[cfg(feature = "axum-extra")]
+    axum_extra::extract::rejection::FailedToResolveHost,
+}
+
+fn rejection_error(status: StatusCode, message: String) -> RpcError {
+    let code = match status {
+        StatusCode::PAYLOAD_TOO_LARGE => RpcErrorCode::ResourceExhausted,
+        status if status.is_client_error() => RpcErrorCode::InvalidArgument,
+        _ => RpcErrorCode::Internal,
+    };
+    RpcError::new(code, message)
+}
+
+/// A detail of an [`RpcError`], serialized as on the wire: the message's type name and its
+/// encoding in base64, without padding. Padded values are read too.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct RpcErrorDetail {
     #[serde(rename = "type")]
     pub proto_type: String,
@@ -53,8 +294,60 @@ pub struct RpcErrorDetail {
     pub proto_b62_value: String,
 }
 
-#[derive(Clone, Serialize)]
-#[serde(rename_all = "snake_case")]
+impl RpcErrorDetail {
+    /// A detail holding `message`, whose fully-qualified protobuf name is `proto_type` (eg.
+    /// `google.rpc.RetryInfo`).
+    pub fn new<M: Message>(proto_type: &str, message: &M) -> Self {
+        use base64::{engine::general_purpose, Engine as _};
+
+        Self {
+            proto_type: proto_type.to_string(),
+            proto_b62_value: general_purpose::STANDARD_NO_PAD.encode(message.encode_to_vec()),
+        }
+    }
+
+    /// A detail holding the message packed in `any`, eg. by [`any::pack`](crate::any::pack).
+    pub fn from_any(any: &Any) -> Self {
+        use base64::{engine::general_purpose, Engine as _};
+
+        Self {
+            proto_type: crate::any::type_name(&any.type_url).to_string(),
+            proto_b62_value: general_purpose::STANDARD_NO_PAD.encode(&any.value),
+        }
+    }
+
+    /// The detail as an `Any`, eg. for [`any::unpack`](crate::any::unpack). `None` if it's
+    /// malformed.
+    pub fn to_any(&self) -> Option<Any> {
+        Some(Any {
+            type_url: format!("type.googleapis.com/{}", self.proto_type),
+            value: self.bytes()?.into(),
+        })
+    }
+
+    /// Decodes the detail as `M`, `None` if it's malformed. Doesn't check `proto_type`.
+    pub fn decode<M: Message + Default>(&self) -> Option<M> {
+        M::decode(self.bytes()?.as_slice()).ok()
+    }
+
+    fn bytes(&self) -> Option<Vec<u8>> {
+        use base64::{
+            engine::{general_purpose, DecodePaddingMode, GeneralPurpose},
+            Engine as _,
+        };
+
+        // Connect sends it without padding, but some implementations pad it anyway.
+        const ENGINE: GeneralPurpose = GeneralPurpose::new(
+            &base64::alphabet::STANDARD,
+            general_purpose::NO_PAD.with_decode_padding_mode(DecodePaddingMode::Indifferent),
+        );
+
+        ENGINE.decode(&self.proto_b62_value).ok()
+    }
+}
+
+/// Serialized as its [`Display`](fmt::Display) string.
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum RpcErrorCode {
     Canceled,
     Unknown,
@@ -74,6 +367,71 @@ pub enum RpcErrorCode {
     Unauthenticated,
 }
 
+/// Prints the code as it appears on the wire, eg. `invalid_argument`.
+impl fmt::Display for RpcErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RpcErrorCode::Canceled => "canceled",
+            RpcErrorCode::Unknown => "unknown",
+            RpcErrorCode::InvalidArgument => "invalid_argument",
+            RpcErrorCode::DeadlineExceeded => "deadline_exceeded",
+            RpcErrorCode::NotFound => "not_found",
+            RpcErrorCode::AlreadyExists => "already_exists",
+            RpcErrorCode::PermissionDenied => "permission_denied",
+            RpcErrorCode::ResourceExhausted => "resource_exhausted",
+            RpcErrorCode::FailedPrecondition => "failed_precondition",
+            RpcErrorCode::Aborted => "aborted",
+            RpcErrorCode::OutOfRange => "out_of_range",
+            RpcErrorCode::Unimplemented => "unimplemented",
+            RpcErrorCode::Internal => "internal",
+            RpcErrorCode::Unavailable => "unavailable",
+            RpcErrorCode::DataLoss => "data_loss",
+            RpcErrorCode::Unauthenticated => "unauthenticated",
+        })
+    }
+}
+
+/// Parses a code as it appears on the wire.
+impl FromStr for RpcErrorCode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "canceled" => RpcErrorCode::Canceled,
+            "unknown" => RpcErrorCode::Unknown,
+            "invalid_argument" => RpcErrorCode::InvalidArgument,
+            "deadline_exceeded" => RpcErrorCode::DeadlineExceeded,
+            "not_found" => RpcErrorCode::NotFound,
+            "already_exists" => RpcErrorCode::AlreadyExists,
+            "permission_denied" => RpcErrorCode::PermissionDenied,
+            "resource_exhausted" => RpcErrorCode::ResourceExhausted,
+            "failed_precondition" => RpcErrorCode::FailedPrecondition,
+            "aborted" => RpcErrorCode::Aborted,
+            "out_of_range" => RpcErrorCode::OutOfRange,
+            "unimplemented" => RpcErrorCode::Unimplemented,
+            "internal" => RpcErrorCode::Internal,
+            "unavailable" => RpcErrorCode::Unavailable,
+            "data_loss" => RpcErrorCode::DataLoss,
+            "unauthenticated" => RpcErrorCode::Unauthenticated,
+            _ => return Err(()),
+        })
+    }
+}
+
+impl Serialize for RpcErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Unrecognized codes are read as `Unknown`, as the spec asks clients to.
+impl<'de> Deserialize<'de> for RpcErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = String::deserialize(deserializer)?;
+        Ok(code.parse().unwrap_or(RpcErrorCode::Unknown))
+    }
+}
+
 impl From<RpcErrorCode> for StatusCode {
     fn from(val: RpcErrorCode) -> Self {
         match val {
@@ -98,6 +456,49 @@ impl From<RpcErrorCode> for StatusCode {
     }
 }
 
+impl RpcErrorCode {
+    /// The code of an HTTP response without a Connect error in its body, eg. a 503 from a load
+    /// balancer, by the spec's table: 401, 403 and 404 are `unauthenticated`, `permission_denied`
+    /// and `unimplemented`, 429, 502, 503 and 504 are `unavailable`, 400 is `internal`, and every
+    /// other status (408 included) is `unknown`.
+    ///
+    /// ```
+    /// # use axum::http::StatusCode;
+    /// # use axum_connect::error::RpcErrorCode;
+    /// for (status, code) in [
+    ///     (400, RpcErrorCode::Internal),
+    ///     (401, RpcErrorCode::Unauthenticated),
+    ///     (403, RpcErrorCode::PermissionDenied),
+    ///     (404, RpcErrorCode::Unimplemented),
+    ///     (429, RpcErrorCode::Unavailable),
+    ///     (502, RpcErrorCode::Unavailable),
+    ///     (503, RpcErrorCode::Unavailable),
+    ///     (504, RpcErrorCode::Unavailable),
+    ///     (408, RpcErrorCode::Unknown),
+    ///     (500, RpcErrorCode::Unknown),
+    ///     (200, RpcErrorCode::Unknown),
+    /// ] {
+    ///     let status = StatusCode::from_u16(status).unwrap();
+    ///     assert_eq!(RpcErrorCode::from_http_status(status), code);
+    /// }
+    /// ```
+    ///
+    /// https://connectrpc.com/docs/protocol/#http-to-error-code
+    pub fn from_http_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => RpcErrorCode::Internal,
+            StatusCode::UNAUTHORIZED => RpcErrorCode::Unauthenticated,
+            StatusCode::FORBIDDEN => RpcErrorCode::PermissionDenied,
+            StatusCode::NOT_FOUND => RpcErrorCode::Unimplemented,
+            StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT => RpcErrorCode::Unavailable,
+            _ => RpcErrorCode::Unknown,
+        }
+    }
+}
+
 impl<T> RpcIntoResponse<T> for RpcErrorCode
 where
     T: Message,
@@ -124,3 +525,430 @@ where
 //         (status_code, json).into_response()
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{stream, Stream};
+
+    use super::*;
+    use crate::{
+        logging::RpcLogLayer,
+        router::RpcRouter,
+        test_util::{
+            client, hello, proto_request, server_stream, stream_request, unary, HelloRequest,
+            HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+    };
+
+    const CODES: [RpcErrorCode; 16] = [
+        RpcErrorCode::Canceled,
+        RpcErrorCode::Unknown,
+        RpcErrorCode::InvalidArgument,
+        RpcErrorCode::DeadlineExceeded,
+        RpcErrorCode::NotFound,
+        RpcErrorCode::AlreadyExists,
+        RpcErrorCode::PermissionDenied,
+        RpcErrorCode::ResourceExhausted,
+        RpcErrorCode::FailedPrecondition,
+        RpcErrorCode::Aborted,
+        RpcErrorCode::OutOfRange,
+        RpcErrorCode::Unimplemented,
+        RpcErrorCode::Internal,
+        RpcErrorCode::Unavailable,
+        RpcErrorCode::DataLoss,
+        RpcErrorCode::Unauthenticated,
+    ];
+
+    fn response(body: &str) -> http::Response<&[u8]> {
+        http::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header("x-region", "eu")
+            .body(body.as_bytes())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn sends_minimal_errors_as_their_code_alone() {
+        async fn unavailable(_: HelloRequest) -> RpcResult<HelloResponse> {
+            Err(RpcError::new(RpcErrorCode::Unavailable, String::new()))
+        }
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, unavailable)));
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(response.body, r#"{"code":"unavailable"}"#.as_bytes());
+    }
+
+    #[test]
+    fn reads_minimal_errors_back() {
+        let error = RpcError::from_response(&response(r#"{"code":"unavailable"}"#));
+
+        assert_eq!(error.code, RpcErrorCode::Unavailable);
+        assert_eq!(error.message, "");
+        assert_eq!(error.details, []);
+        assert_eq!(error.metadata()["x-region"], "eu");
+    }
+
+    #[test]
+    fn round_trips_maximal_errors() {
+        let json = r#"{"code":"resource_exhausted","message":"Quota exceeded","details":[{"type":"google.rpc.ErrorInfo","value":"ChBRVU9UQV9TT0ZUX0xJTUlU"},{"type":"hello.HelloRequest","value":"CgNBZGE"}]}"#;
+        let mut error = RpcError::new(RpcErrorCode::ResourceExhausted, "Quota exceeded".into())
+            .with_app_code("QUOTA_SOFT_LIMIT");
+        error
+            .details
+            .push(RpcErrorDetail::new("hello.HelloRequest", &hello("Ada")));
+
+        assert_eq!(serde_json::to_string(&error).unwrap(), json);
+
+        let parsed = RpcError::from_response(&response(json));
+        assert_eq!(parsed.code, error.code);
+        assert_eq!(parsed.message, error.message);
+        assert_eq!(parsed.details, error.details);
+        assert_eq!(parsed.app_code().as_deref(), Some("QUOTA_SOFT_LIMIT"));
+    }
+
+    #[test]
+    fn writes_every_code_in_lowercase_snake_case() {
+        for code in CODES {
+            let wire = code.to_string();
+            assert!(
+                wire.chars().all(|c| c.is_ascii_lowercase() || c == '_'),
+                "{wire}"
+            );
+            assert_eq!(serde_json::to_string(&code).unwrap(), format!("\"{wire}\""));
+            assert_eq!(wire.parse(), Ok(code));
+        }
+    }
+
+    #[test]
+    fn round_trips_through_serde_json_with_binary_details() {
+        let blob = Any {
+            type_url: "type.googleapis.com/acme.Blob".to_string(),
+            value: vec![0x00, 0xff, 0x80, 0x0a, 0xfe].into(),
+        };
+        let mut error = RpcError::new(RpcErrorCode::DataLoss, "Corrupt page".into())
+            .with_app_code("PAGE_CHECKSUM")
+            .with_metadata(HeaderMap::from_iter([(
+                http::header::HeaderName::from_static("x-region"),
+                HeaderValue::from_static("eu"),
+            )]))
+            .with_internal_message("page 7 of shard 3");
+        error.details.push(RpcErrorDetail::from_any(&blob));
+
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "code": "data_loss",
+                "message": "Corrupt page",
+                "details": [
+                    {"type": "google.rpc.ErrorInfo", "value": "Cg1QQUdFX0NIRUNLU1VN"},
+                    {"type": "acme.Blob", "value": "AP+ACv4"},
+                ],
+            })
+        );
+
+        // The same as the wire JSON `from_response` reads, minus what's never sent.
+        let parsed: RpcError = serde_json::from_value(json.clone()).unwrap();
+        let read = RpcError::from_response(&response(&json.to_string()));
+        for parsed in [&parsed, &read] {
+            assert_eq!(parsed.code, error.code);
+            assert_eq!(parsed.message, error.message);
+            assert_eq!(parsed.details, error.details);
+            assert_eq!(parsed.details[1].to_any(), Some(blob.clone()));
+            assert_eq!(parsed.app_code().as_deref(), Some("PAGE_CHECKSUM"));
+            assert_eq!(parsed.internal_message(), None);
+        }
+        assert!(parsed.metadata().is_empty());
+    }
+
+    #[test]
+    fn deserializes_every_code_and_padded_details() {
+        for code in CODES {
+            let json = format!(r#"{{"code":"{code}"}}"#);
+            let parsed: RpcError = serde_json::from_str(&json).unwrap();
+            assert_eq!(parsed, RpcError::new(code, String::new()));
+            assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        }
+
+        let parsed: RpcError = serde_json::from_str(
+            r#"{"code":"data_loss","details":[{"type":"acme.Blob","value":"AP+ACv4="}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            parsed.details[0].to_any().unwrap().value,
+            [0x00, 0xff, 0x80, 0x0a, 0xfe].as_slice()
+        );
+
+        let parsed: RpcError = serde_json::from_str(r#"{"code":"teapot"}"#).unwrap();
+        assert_eq!(parsed.code, RpcErrorCode::Unknown);
+    }
+
+    fn declined() -> RpcError {
+        RpcError::new(RpcErrorCode::FailedPrecondition, "Payment declined".into())
+            .with_internal_message("stripe error 402: card_declined")
+    }
+
+    #[tokio::test]
+    async fn logs_internal_messages_without_sending_them() {
+        async fn pay(_: HelloRequest) -> RpcResult<HelloResponse> {
+            Err(declined())
+        }
+        async fn pay_stream(_: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([Err(declined())])
+        }
+        let logged = Arc::new(Mutex::new(vec![]));
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, pay))
+            .rpc_method(server_stream(SAY_HELLO_STREAM, pay_stream))
+            .layer(RpcLogLayer::new().on_complete({
+                let logged = logged.clone();
+                move |log| logged.lock().unwrap().push(log.internal_message.clone())
+            }));
+        let client = client(router);
+
+        let unary = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        let streamed = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        for response in [unary, streamed] {
+            let body = String::from_utf8_lossy(&response.body);
+            assert!(body.contains("Payment declined"), "{body}");
+            assert!(!body.contains("stripe"), "{body}");
+        }
+        let internal = Some("stripe error 402: card_declined".to_string());
+        assert_eq!(*logged.lock().unwrap(), [internal.clone(), internal]);
+    }
+
+    fn over_quota() -> RpcError {
+        RpcError::new(RpcErrorCode::ResourceExhausted, "Quota exceeded".into())
+            .with_app_code("QUOTA_SOFT_LIMIT")
+    }
+
+    #[tokio::test]
+    async fn sends_app_codes_of_unary_errors() {
+        async fn over(_: HelloRequest) -> RpcResult<HelloResponse> {
+            Err(over_quota())
+        }
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, over)));
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(response.headers[APP_ERROR_CODE_HEADER], "QUOTA_SOFT_LIMIT");
+        assert_eq!(
+            response.body,
+            r#"{"code":"resource_exhausted","message":"Quota exceeded","details":[{"type":"google.rpc.ErrorInfo","value":"ChBRVU9UQV9TT0ZUX0xJTUlU"}]}"#.as_bytes()
+        );
+        let error = response.error().unwrap();
+        assert_eq!(error.app_code().as_deref(), Some("QUOTA_SOFT_LIMIT"));
+    }
+
+    #[tokio::test]
+    async fn sends_app_codes_of_stream_errors() {
+        async fn over(_: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([Err(over_quota())])
+        }
+        let client = client(RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, over)));
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        let mut decoder = crate::codec::FrameDecoder::new();
+        decoder.push(&response.body);
+        let end = decoder.next_frame().unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(end.payload).unwrap(),
+            r#"{"error":{"code":"resource_exhausted","message":"Quota exceeded","details":[{"type":"google.rpc.ErrorInfo","value":"ChBRVU9UQV9TT0ZUX0xJTUlU"}]},"metadata":{"x-app-error-code":["QUOTA_SOFT_LIMIT"]}}"#
+        );
+        let (_, error) = response.frames();
+        assert_eq!(
+            error.unwrap().app_code().as_deref(),
+            Some("QUOTA_SOFT_LIMIT")
+        );
+    }
+
+    /// The spec's HTTP to error code table.
+    const HTTP_CODES: [(u16, RpcErrorCode); 8] = [
+        (400, RpcErrorCode::Internal),
+        (401, RpcErrorCode::Unauthenticated),
+        (403, RpcErrorCode::PermissionDenied),
+        (404, RpcErrorCode::Unimplemented),
+        (429, RpcErrorCode::Unavailable),
+        (502, RpcErrorCode::Unavailable),
+        (503, RpcErrorCode::Unavailable),
+        (504, RpcErrorCode::Unavailable),
+    ];
+
+    #[test]
+    fn maps_http_statuses_to_codes() {
+        for status in 100..600 {
+            let Ok(status) = StatusCode::from_u16(status) else {
+                continue;
+            };
+            let expected = HTTP_CODES
+                .iter()
+                .find(|(code, _)| *code == status.as_u16())
+                .map_or(RpcErrorCode::Unknown, |(_, code)| code.clone());
+            assert_eq!(RpcErrorCode::from_http_status(status), expected, "{status}");
+        }
+    }
+
+    #[test]
+    fn reads_errors_of_bare_statuses_from_their_status() {
+        let error = RpcError::from_response(&response("<html>Service Unavailable</html>"));
+        assert_eq!(
+            error,
+            RpcError::new(
+                RpcErrorCode::Unavailable,
+                "HTTP status 503 Service Unavailable".to_string()
+            )
+            .with_metadata(error.metadata().clone())
+        );
+        assert_eq!(error.metadata()["x-region"], "eu");
+
+        for (status, code) in HTTP_CODES {
+            let response = http::Response::builder()
+                .status(status)
+                .body(Vec::new())
+                .unwrap();
+            assert_eq!(RpcError::from_response(&response).code, code, "{status}");
+        }
+        let response = http::Response::builder()
+            .status(StatusCode::REQUEST_TIMEOUT)
+            .body("")
+            .unwrap();
+        assert_eq!(
+            RpcError::from_response(&response).code,
+            RpcErrorCode::Unknown
+        );
+    }
+
+    /// The error an axum extractor's rejection becomes.
+    async fn rejected<E, Via>(request: http::Request<axum::body::Body>) -> RpcError
+    where
+        E: axum::extract::FromRequest<(), Via> + fmt::Debug,
+        E::Rejection: RpcIntoError,
+    {
+        E::from_request(request, &())
+            .await
+            .unwrap_err()
+            .rpc_into_error()
+    }
+
+    fn get(uri: &str) -> http::Request<axum::body::Body> {
+        http::Request::get(uri)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    }
+
+    #[derive(Clone, Debug, Deserialize)]
+    struct Page {
+        page: u32,
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_requests_with_invalid_argument() {
+        use axum::{body::Body, extract::Query, Json};
+
+        let error = rejected::<Query<Page>, _>(get("/?page=first")).await;
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert!(
+            error
+                .message
+                .starts_with("Failed to deserialize query string"),
+            "{}",
+            error.message
+        );
+
+        let invalid_utf8 = http::Request::post("/")
+            .body(Body::from(vec![0xff, 0xfe]))
+            .unwrap();
+        let error = rejected::<String, _>(invalid_utf8).await;
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+
+        let error = rejected::<Json<Page>, _>(get("/")).await;
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(
+            error.message,
+            "Expected request with `Content-Type: application/json`"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_over_the_limit_with_resource_exhausted() {
+        use axum::{body::Body, extract::DefaultBodyLimit, routing::post, Router};
+        use tower::ServiceExt;
+
+        // Body limits come from a layer, so this goes through a router.
+        async fn limited(body: Result<axum::body::Bytes, rejection::BytesRejection>) -> String {
+            match body {
+                Ok(_) => "ok".to_string(),
+                Err(rejection) => rejection.rpc_into_error().code.to_string(),
+            }
+        }
+        let router = Router::new()
+            .route("/", post(limited))
+            .layer(DefaultBodyLimit::max(4));
+        let response = router
+            .oneshot(
+                http::Request::post("/")
+                    .body(Body::from("too long"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "resource_exhausted");
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_server_setup_with_internal() {
+        use axum::extract::{Extension, MatchedPath, Path};
+
+        let error = rejected::<Extension<Page>, _>(get("/")).await;
+        assert_eq!(error.code, RpcErrorCode::Internal);
+        assert!(
+            error.message.starts_with("Missing request extension"),
+            "{}",
+            error.message
+        );
+
+        let error = rejected::<MatchedPath, _>(get("/")).await;
+        assert_eq!(error.code, RpcErrorCode::Internal);
+
+        let error = rejected::<Path<u32>, _>(get("/")).await;
+        assert_eq!(error.code, RpcErrorCode::Internal);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_queries_of_rpcs_with_invalid_argument() {
+        use axum::extract::Query;
+
+        async fn paged(
+            Query(page): Query<Page>,
+            request: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: format!("{} page {}", request.name, page.page),
+            })
+        }
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, paged)));
+
+        let request = proto_request(&format!("{SAY_HELLO}?page=2"), &hello("Ada"));
+        let response = client.send(request).await;
+        assert_eq!(
+            HelloResponse::decode(response.body).unwrap().message,
+            "Ada page 2"
+        );
+
+        let request = proto_request(&format!("{SAY_HELLO}?page=first"), &hello("Ada"));
+        let error = client.send(request).await.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+    }
+}
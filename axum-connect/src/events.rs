@@ -0,0 +1,275 @@
+//! A live feed of RPC lifecycle events, eg. for an admin dashboard, see [`RpcEventBus`].
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use axum::{body::Body, http::Request};
+use tokio::sync::broadcast;
+
+use crate::{error::RpcErrorCode, router::RpcMethodInfo};
+
+/// The RPC an [`RpcEvent`] is about, shared by all of its events.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RpcEventCall {
+    /// Unique per bus, to tie the events of one call together.
+    pub id: u64,
+    pub method: RpcMethodInfo,
+    /// The `x-request-id` header, if the request had one.
+    pub request_id: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub enum RpcEvent {
+    /// The request reached its route.
+    Started {
+        call: Arc<RpcEventCall>,
+        at: SystemTime,
+    },
+    /// The first bytes of the response body were sent.
+    FirstResponseFrame {
+        call: Arc<RpcEventCall>,
+        at: SystemTime,
+    },
+    /// The response body was sent in full, or dropped because the client went away.
+    Completed {
+        call: Arc<RpcEventCall>,
+        at: SystemTime,
+        /// The Connect error code, or `None` if the RPC succeeded.
+        code: Option<RpcErrorCode>,
+    },
+}
+
+impl RpcEvent {
+    pub fn call(&self) -> &RpcEventCall {
+        match self {
+            RpcEvent::Started { call, .. }
+            | RpcEvent::FirstResponseFrame { call, .. }
+            | RpcEvent::Completed { call, .. } => call,
+        }
+    }
+
+    pub fn at(&self) -> SystemTime {
+        match self {
+            RpcEvent::Started { at, .. }
+            | RpcEvent::FirstResponseFrame { at, .. }
+            | RpcEvent::Completed { at, .. } => *at,
+        }
+    }
+}
+
+/// Publishes an [`RpcEvent`] for every step of every call to the routes it's registered on with
+/// [`RpcRouter::event_bus`](crate::router::RpcRouter::event_bus).
+///
+/// Events go out on a `tokio::sync::broadcast` channel, so publishing never waits: a subscriber
+/// that falls more than `capacity` events behind misses the oldest ones (its `recv` returns
+/// `Lagged`), and without subscribers events are dropped.
+///
+/// ```
+/// # use axum_connect::{events::RpcEventBus, prelude::*};
+/// let bus = RpcEventBus::new(1024);
+/// let mut events = bus.subscribe();
+/// let app = RpcRouter::<()>::new().event_bus(bus);
+/// ```
+#[derive(Clone, Debug)]
+pub struct RpcEventBus {
+    sender: broadcast::Sender<RpcEvent>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl RpcEventBus {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            sender: broadcast::channel(capacity).0,
+            next_id: Default::default(),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RpcEvent> {
+        self.sender.subscribe()
+    }
+
+    fn publish(&self, event: RpcEvent) {
+        // Only fails if there are no subscribers.
+        let _ = self.sender.send(event);
+    }
+}
+
+/// The events of one call, published by the route's stats layer.
+pub(crate) struct CallEvents {
+    bus: RpcEventBus,
+    call: Arc<RpcEventCall>,
+    first_frame_sent: AtomicBool,
+}
+
+impl CallEvents {
+    /// Publishes `Started`.
+    pub fn start(bus: &RpcEventBus, req: &Request<Body>) -> Self {
+        let method = req
+            .extensions()
+            .get::<RpcMethodInfo>()
+            .cloned()
+            .unwrap_or_else(|| RpcMethodInfo::from_path(req.uri().path()));
+        let request_id = req
+            .headers()
+            .get("x-request-id")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+
+        let call = Arc::new(RpcEventCall {
+            id: bus.next_id.fetch_add(1, Ordering::Relaxed),
+            method,
+            request_id,
+        });
+        bus.publish(RpcEvent::Started {
+            call: call.clone(),
+            at: SystemTime::now(),
+        });
+
+        Self {
+            bus: bus.clone(),
+            call,
+            first_frame_sent: AtomicBool::new(false),
+        }
+    }
+
+    /// Publishes `FirstResponseFrame`, the first time it's called.
+    pub fn frame(&self) {
+        if !self.first_frame_sent.swap(true, Ordering::Relaxed) {
+            self.bus.publish(RpcEvent::FirstResponseFrame {
+                call: self.call.clone(),
+                at: SystemTime::now(),
+            });
+        }
+    }
+
+    /// Publishes `Completed`.
+    pub fn complete(&self, code: Option<RpcErrorCode>) {
+        self.bus.publish(RpcEvent::Completed {
+            call: self.call.clone(),
+            at: SystemTime::now(),
+            code,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use futures::stream;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::{
+        error::RpcError,
+        prelude::*,
+        test_util::{
+            client, hello, hello_router, proto_request, server_stream, stream_request,
+            HelloRequest, HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+    };
+
+    /// The events received so far, as their kind, call id and code.
+    fn received(
+        events: &mut broadcast::Receiver<RpcEvent>,
+    ) -> Vec<(&'static str, u64, Option<RpcErrorCode>)> {
+        let mut received = vec![];
+        while let Ok(event) = events.try_recv() {
+            let id = event.call().id;
+            received.push(match event {
+                RpcEvent::Started { .. } => ("started", id, None),
+                RpcEvent::FirstResponseFrame { .. } => ("first frame", id, None),
+                RpcEvent::Completed { code, .. } => ("completed", id, code),
+            });
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn publishes_the_steps_of_a_unary_call_in_order() {
+        let bus = RpcEventBus::new(16);
+        let mut events = bus.subscribe();
+        let client = client(hello_router().event_bus(bus));
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("x-request-id", HeaderValue::from_static("req-1"));
+
+        client.send(request).await;
+        client.send(proto_request(SAY_HELLO, &hello("Bob"))).await;
+
+        let first = events.try_recv().unwrap();
+        assert_eq!(first.call().method.path, SAY_HELLO);
+        assert_eq!(first.call().request_id.as_deref(), Some("req-1"));
+        assert_eq!(
+            received(&mut events),
+            [
+                ("first frame", 0, None),
+                ("completed", 0, None),
+                ("started", 1, None),
+                ("first frame", 1, None),
+                ("completed", 1, None),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn publishes_one_first_frame_and_the_code_of_a_stream() {
+        async fn failing(_: HelloRequest) -> impl futures::Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([
+                Ok(HelloResponse::default()),
+                Err(RpcError::new(RpcErrorCode::Aborted, "Gave up".to_string())),
+            ])
+        }
+        let bus = RpcEventBus::new(16);
+        let mut events = bus.subscribe();
+        let router = hello_router()
+            .rpc_method(server_stream("/hello.HelloWorldService/Failing", failing))
+            .event_bus(bus);
+        let client = client(router);
+
+        client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+        client
+            .send(stream_request(
+                "/hello.HelloWorldService/Failing",
+                &hello("Ada"),
+            ))
+            .await;
+
+        assert_eq!(
+            received(&mut events),
+            [
+                ("started", 0, None),
+                ("first frame", 0, None),
+                ("completed", 0, None),
+                ("started", 1, None),
+                ("first frame", 1, None),
+                ("completed", 1, Some(RpcErrorCode::Aborted)),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn drops_events_for_subscribers_that_fall_behind() {
+        let bus = RpcEventBus::new(2);
+        let mut events = bus.subscribe();
+        let client = client(hello_router().event_bus(bus));
+
+        for name in ["Ada", "Bob"] {
+            let response = client.send(proto_request(SAY_HELLO, &hello(name))).await;
+            assert!(response.error().is_none());
+        }
+
+        assert_eq!(events.try_recv().unwrap_err(), TryRecvError::Lagged(4));
+        assert_eq!(
+            received(&mut events),
+            [("first frame", 1, None), ("completed", 1, None)]
+        );
+    }
+}
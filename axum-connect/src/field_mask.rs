@@ -0,0 +1,469 @@
+//! Applying and checking `google.protobuf.FieldMask`s, eg. a request's `read_mask`.
+//!
+//! Messages need descriptors for this, generate them with `field_masks: true` in codegen.
+
+use std::collections::BTreeMap;
+
+use prost_reflect::{DynamicMessage, Kind, MapKey, MessageDescriptor, ReflectMessage, Value};
+
+use crate::error::{RpcError, RpcErrorCode};
+
+pub use pbjson_types::FieldMask;
+
+/// Clears every field of `message` the mask doesn't select. An empty mask selects everything.
+///
+/// Paths follow AIP-161: `a.b` selects `b` in the message field `a`, or in every element when `a`
+/// is repeated. In a map, the segment after the field is a key, `labels.env` keeps only the `env`
+/// entry. A trailing `*` selects everything below it. Paths that don't name a field select nothing,
+/// check them first with [`validate_field_mask`].
+///
+/// ```
+/// # use axum_connect::{field_mask::{apply_field_mask, validate_field_mask, FieldMask}, prelude::*};
+/// # use axum_connect::prost_reflect::prost_types::Duration as Book;
+/// fn read_book(mut book: Book, read_mask: &FieldMask) -> RpcResult<Book> {
+///     validate_field_mask::<Book>(read_mask)?;
+///     apply_field_mask(&mut book, read_mask);
+///     Ok(book)
+/// }
+/// ```
+pub fn apply_field_mask<M: ReflectMessage + Default>(message: &mut M, mask: &FieldMask) {
+    if mask.paths.is_empty() {
+        return;
+    }
+
+    let Node::Fields(fields) = Node::from_mask(mask) else {
+        return;
+    };
+
+    let mut dynamic = message.transcode_to_dynamic();
+    retain(&mut dynamic, &fields);
+    *message = dynamic
+        .transcode_to()
+        .expect("the message has the same descriptor");
+}
+
+/// Checks that every path of the mask names a field of `M`, failing with `InvalidArgument`
+/// naming the first one that doesn't.
+pub fn validate_field_mask<M: ReflectMessage + Default>(mask: &FieldMask) -> Result<(), RpcError> {
+    let descriptor = M::default().descriptor();
+    for path in &mask.paths {
+        validate_path(&descriptor, path).map_err(|reason| {
+            RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!("Invalid field mask path `{}`: {}", path, reason),
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// What a mask selects in a message, or a map's entries.
+enum Node {
+    All,
+    Fields(BTreeMap<String, Node>),
+}
+
+impl Node {
+    fn from_mask(mask: &FieldMask) -> Self {
+        let mut root = Node::Fields(BTreeMap::new());
+        for path in &mask.paths {
+            // `a.*` selects as much as `a`.
+            let mut segments = path.split('.').collect::<Vec<_>>();
+            while segments.last() == Some(&"*") {
+                segments.pop();
+            }
+
+            let mut node = &mut root;
+            for segment in segments {
+                let Node::Fields(fields) = node else {
+                    break;
+                };
+                node = fields
+                    .entry(segment.to_string())
+                    .or_insert_with(|| Node::Fields(BTreeMap::new()));
+            }
+            *node = Node::All;
+        }
+        root
+    }
+}
+
+fn retain(message: &mut DynamicMessage, fields: &BTreeMap<String, Node>) {
+    for field in message.descriptor().fields() {
+        let selected = match fields.get(field.name()) {
+            None => None,
+            Some(Node::All) => continue,
+            Some(Node::Fields(selected)) => Some(selected),
+        };
+        // Nothing below a scalar can be selected.
+        let has_fields = field.is_map() || matches!(field.kind(), Kind::Message(_));
+        let Some(selected) = selected.filter(|_| has_fields && message.has_field(&field)) else {
+            message.clear_field(&field);
+            continue;
+        };
+
+        match message.get_field_mut(&field) {
+            Value::Message(value) => retain(value, selected),
+            Value::List(values) => {
+                for value in values {
+                    if let Value::Message(value) = value {
+                        retain(value, selected);
+                    }
+                }
+            }
+            Value::Map(entries) => {
+                entries.retain(|key, value| match selected.get(&map_key_string(key)) {
+                    None => false,
+                    Some(Node::All) => true,
+                    Some(Node::Fields(selected)) => {
+                        if let Value::Message(value) = value {
+                            retain(value, selected);
+                        }
+                        true
+                    }
+                })
+            }
+            _ => {}
+        }
+    }
+}
+
+fn map_key_string(key: &MapKey) -> String {
+    match key {
+        MapKey::Bool(value) => value.to_string(),
+        MapKey::I32(value) => value.to_string(),
+        MapKey::I64(value) => value.to_string(),
+        MapKey::U32(value) => value.to_string(),
+        MapKey::U64(value) => value.to_string(),
+        MapKey::String(value) => value.clone(),
+    }
+}
+
+fn validate_path(descriptor: &MessageDescriptor, path: &str) -> Result<(), String> {
+    let mut message = descriptor.clone();
+    let mut segments = path.split('.').peekable();
+
+    while let Some(segment) = segments.next() {
+        if segment == "*" {
+            return match segments.peek() {
+                None => Ok(()),
+                Some(_) => Err("`*` can only be the last segment".to_string()),
+            };
+        }
+        if segment.is_empty() {
+            return Err("empty segment".to_string());
+        }
+
+        let Some(field) = message.get_field_by_name(segment) else {
+            return Err(format!(
+                "`{}` isn't a field of `{}`",
+                segment,
+                message.full_name()
+            ));
+        };
+
+        let kind = if field.is_map() {
+            let Kind::Message(entry) = field.kind() else {
+                unreachable!("map fields are messages");
+            };
+            match segments.next() {
+                None => return Ok(()),
+                Some("*") if segments.peek().is_none() => return Ok(()),
+                Some(key) => check_map_key(&entry.map_entry_key_field().kind(), key)?,
+            }
+            entry.map_entry_value_field().kind()
+        } else {
+            field.kind()
+        };
+
+        match (kind, segments.peek()) {
+            (_, None) => return Ok(()),
+            (Kind::Message(value), Some(_)) => message = value,
+            (_, Some(_)) => return Err(format!("`{}` has no fields", segment)),
+        }
+    }
+
+    Err("empty path".to_string())
+}
+
+fn check_map_key(kind: &Kind, key: &str) -> Result<(), String> {
+    let valid = match kind {
+        Kind::Bool => key.parse::<bool>().is_ok(),
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => key.parse::<i32>().is_ok(),
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => key.parse::<i64>().is_ok(),
+        Kind::Uint32 | Kind::Fixed32 => key.parse::<u32>().is_ok(),
+        Kind::Uint64 | Kind::Fixed64 => key.parse::<u64>().is_ok(),
+        _ => true,
+    };
+    match valid {
+        true => Ok(()),
+        false => Err(format!("`{}` isn't a valid map key", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::LazyLock};
+
+    use prost_reflect::{
+        prost_types::{
+            field_descriptor_proto::{Label, Type},
+            DescriptorProto, FieldDescriptorProto, FileDescriptorProto, MessageOptions,
+        },
+        DescriptorPool,
+    };
+
+    use super::*;
+
+    /// The descriptors of `fixture.Book`, its `Author` and its `Chapter`s.
+    static POOL: LazyLock<DescriptorPool> = LazyLock::new(|| {
+        fn field(name: &str, number: i32, kind: Type, label: Label) -> FieldDescriptorProto {
+            FieldDescriptorProto {
+                name: Some(name.to_string()),
+                number: Some(number),
+                r#type: Some(kind as i32),
+                label: Some(label as i32),
+                json_name: Some(name.to_string()),
+                ..Default::default()
+            }
+        }
+        fn message_field(
+            name: &str,
+            number: i32,
+            type_name: &str,
+            label: Label,
+        ) -> FieldDescriptorProto {
+            FieldDescriptorProto {
+                type_name: Some(type_name.to_string()),
+                ..field(name, number, Type::Message, label)
+            }
+        }
+        fn message(name: &str, fields: Vec<FieldDescriptorProto>) -> DescriptorProto {
+            DescriptorProto {
+                name: Some(name.to_string()),
+                field: fields,
+                ..Default::default()
+            }
+        }
+
+        let labels_entry = DescriptorProto {
+            options: Some(MessageOptions {
+                map_entry: Some(true),
+                ..Default::default()
+            }),
+            ..message(
+                "LabelsEntry",
+                vec![
+                    field("key", 1, Type::String, Label::Optional),
+                    field("value", 2, Type::String, Label::Optional),
+                ],
+            )
+        };
+        let book = DescriptorProto {
+            nested_type: vec![labels_entry],
+            ..message(
+                "Book",
+                vec![
+                    field("title", 1, Type::String, Label::Optional),
+                    message_field("author", 2, ".fixture.Author", Label::Optional),
+                    message_field("chapters", 3, ".fixture.Chapter", Label::Repeated),
+                    message_field("labels", 4, ".fixture.Book.LabelsEntry", Label::Repeated),
+                ],
+            )
+        };
+        let file = FileDescriptorProto {
+            name: Some("fixture.proto".to_string()),
+            package: Some("fixture".to_string()),
+            syntax: Some("proto3".to_string()),
+            message_type: vec![
+                book,
+                message(
+                    "Author",
+                    vec![
+                        field("name", 1, Type::String, Label::Optional),
+                        field("email", 2, Type::String, Label::Optional),
+                    ],
+                ),
+                message(
+                    "Chapter",
+                    vec![
+                        field("title", 1, Type::String, Label::Optional),
+                        field("pages", 2, Type::Int32, Label::Optional),
+                    ],
+                ),
+            ],
+            ..Default::default()
+        };
+        DescriptorPool::from_file_descriptor_set(prost_reflect::prost_types::FileDescriptorSet {
+            file: vec![file],
+        })
+        .unwrap()
+    });
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Book {
+        #[prost(string, tag = "1")]
+        title: String,
+        #[prost(message, optional, tag = "2")]
+        author: Option<Author>,
+        #[prost(message, repeated, tag = "3")]
+        chapters: Vec<Chapter>,
+        #[prost(map = "string, string", tag = "4")]
+        labels: HashMap<String, String>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Author {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(string, tag = "2")]
+        email: String,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct Chapter {
+        #[prost(string, tag = "1")]
+        title: String,
+        #[prost(int32, tag = "2")]
+        pages: i32,
+    }
+
+    impl ReflectMessage for Book {
+        fn descriptor(&self) -> MessageDescriptor {
+            POOL.get_message_by_name("fixture.Book").unwrap()
+        }
+    }
+
+    fn book() -> Book {
+        Book {
+            title: "Dune".to_string(),
+            author: Some(Author {
+                name: "Frank Herbert".to_string(),
+                email: "frank@example.com".to_string(),
+            }),
+            chapters: vec![
+                Chapter {
+                    title: "Arrakis".to_string(),
+                    pages: 40,
+                },
+                Chapter {
+                    title: "Muad'Dib".to_string(),
+                    pages: 35,
+                },
+            ],
+            labels: HashMap::from([
+                ("genre".to_string(), "sci-fi".to_string()),
+                ("shelf".to_string(), "B2".to_string()),
+            ]),
+        }
+    }
+
+    fn mask(paths: &[&str]) -> FieldMask {
+        FieldMask {
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+        }
+    }
+
+    fn masked(paths: &[&str]) -> Book {
+        let mut book = book();
+        apply_field_mask(&mut book, &mask(paths));
+        book
+    }
+
+    #[test]
+    fn keeps_everything_for_an_empty_mask() {
+        assert_eq!(masked(&[]), book());
+    }
+
+    #[test]
+    fn keeps_only_the_selected_fields() {
+        assert_eq!(
+            masked(&["title"]),
+            Book {
+                title: "Dune".to_string(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            masked(&["author", "title"]),
+            Book {
+                chapters: vec![],
+                labels: HashMap::new(),
+                ..book()
+            }
+        );
+    }
+
+    #[test]
+    fn selects_nested_fields() {
+        assert_eq!(
+            masked(&["author.name"]).author,
+            Some(Author {
+                name: "Frank Herbert".to_string(),
+                email: String::new(),
+            })
+        );
+        assert_eq!(masked(&["author.*"]).author, book().author);
+    }
+
+    #[test]
+    fn selects_fields_of_every_repeated_element() {
+        let chapters = masked(&["chapters.title"]).chapters;
+        assert_eq!(
+            chapters
+                .iter()
+                .map(|chapter| (chapter.title.as_str(), chapter.pages))
+                .collect::<Vec<_>>(),
+            [("Arrakis", 0), ("Muad'Dib", 0)]
+        );
+        assert_eq!(masked(&["chapters"]).chapters, book().chapters);
+    }
+
+    #[test]
+    fn selects_map_entries_by_key() {
+        assert_eq!(
+            masked(&["labels.genre"]).labels,
+            HashMap::from([("genre".to_string(), "sci-fi".to_string())])
+        );
+        assert_eq!(masked(&["labels"]).labels, book().labels);
+    }
+
+    #[test]
+    fn accepts_paths_naming_fields() {
+        let valid = mask(&[
+            "title",
+            "author.email",
+            "chapters.pages",
+            "labels.genre",
+            "author.*",
+            "*",
+        ]);
+        assert_eq!(validate_field_mask::<Book>(&valid), Ok(()));
+    }
+
+    #[test]
+    fn rejects_the_first_bad_path() {
+        for (path, message) in [
+            (
+                "author.phone",
+                "Invalid field mask path `author.phone`: `phone` isn't a field of `fixture.Author`",
+            ),
+            (
+                "title.length",
+                "Invalid field mask path `title.length`: `title` has no fields",
+            ),
+            (
+                "chapters..title",
+                "Invalid field mask path `chapters..title`: empty segment",
+            ),
+            (
+                "*.title",
+                "Invalid field mask path `*.title`: `*` can only be the last segment",
+            ),
+        ] {
+            let error = validate_field_mask::<Book>(&mask(&["title", path])).unwrap_err();
+            assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+            assert_eq!(error.message, message);
+        }
+    }
+}
@@ -1,25 +1,144 @@
+use std::any::TypeId;
 use std::convert::Infallible;
+use std::iter::{self, Chain, Once};
+use std::option;
 use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use axum::body::{self, Body};
-use axum::http::{header, request, Request, StatusCode};
+use axum::body::{Body, BodyDataStream, Bytes, HttpBody};
+use axum::http::{header, request, HeaderMap, HeaderName, HeaderValue, StatusCode, Version};
 use axum::response::{IntoResponse, Response};
-use futures::{Stream, StreamExt};
+use bytes::BytesMut;
+use futures::{stream, Stream, StreamExt};
 use prost::Message;
-use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+use crate::codec::{self, decode_binary_message, ContentType, FrameDecoder};
+use crate::config::{strip_reserved_headers, RpcConfig};
+use crate::defaults::{self, RequestDefaults};
 use crate::error::{RpcError, RpcErrorCode, RpcIntoError};
-use crate::response::{RpcIntoResponse, RpcResult};
+use crate::logging::RpcCallStats;
+use crate::parts::{RpcMetadata, RpcResponseHeaders, RpcTrailers};
+use crate::pool;
+use crate::request::RpcRequestStream;
+use crate::response::{EndStreamResponse, RpcPayload, RpcResult};
+use crate::router::{
+    CompressionMode, RouteOptions, StreamIdleTimeout, DEFAULT_MAX_STREAM_FRAMES,
+    DEFAULT_MAX_STREAM_TOTAL_BYTES,
+};
+use crate::verify::verify_body;
 
 pub(crate) struct ReqResInto {
     pub binary: bool,
+    pub framing: Framing,
+}
+
+/// How a request is framed, and so its response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Unary,
+    Stream,
+    /// A unary method called with a streaming Content-Type, under
+    /// [`RpcConfig::adapt_streaming_unary`]: one enveloped message each way.
+    AdaptedUnary,
+}
+
+impl Framing {
+    pub fn is_streaming(self) -> bool {
+        self != Framing::Unary
+    }
+}
+
+/// JSON decoding of request messages.
+///
+/// With the `json` feature (on by default) this is implemented for every `DeserializeOwned` type,
+/// which is what the pbjson generated impls give you. Without it, it's implemented for every type
+/// and always fails with `Unimplemented`, so services generated with `json: false` still compile.
+pub trait RpcJsonDecode: Sized {
+    fn rpc_json_decode(bytes: &[u8]) -> RpcResult<Self>;
+}
+
+/// JSON encoding of response messages, see [`RpcJsonDecode`].
+pub trait RpcJsonEncode {
+    fn rpc_json_encode(&self) -> RpcResult<Vec<u8>>;
+
+    /// Appends the encoding to `buffer`, which lets responses reuse pooled buffers.
+    fn rpc_json_encode_into(&self, buffer: &mut Vec<u8>) -> RpcResult<()> {
+        buffer.extend(self.rpc_json_encode()?);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "json")]
+impl<M: serde::de::DeserializeOwned> RpcJsonDecode for M {
+    fn rpc_json_decode(bytes: &[u8]) -> RpcResult<Self> {
+        // The path names the offending field, eg. of an unknown enum value in a nested message.
+        let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+        let message = serde_path_to_error::deserialize(&mut deserializer).map_err(|e| {
+            let message = match e.path().to_string().as_str() {
+                "." => format!("Failed to decode JSON protobuf. {}", e.inner()),
+                path => format!("Failed to decode JSON protobuf. {}: {}", path, e.inner()),
+            };
+            RpcError::new(RpcErrorCode::InvalidArgument, message)
+        })?;
+        deserializer.end().map_err(|e| {
+            RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!("Failed to decode JSON protobuf. {}", e),
+            )
+        })?;
+        Ok(message)
+    }
+}
+
+#[cfg(feature = "json")]
+impl<M: Serialize> RpcJsonEncode for M {
+    fn rpc_json_encode(&self) -> RpcResult<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.rpc_json_encode_into(&mut buffer)?;
+        Ok(buffer)
+    }
+
+    fn rpc_json_encode_into(&self, buffer: &mut Vec<u8>) -> RpcResult<()> {
+        serde_json::to_writer(buffer, self).map_err(|error| {
+            RpcError::new(
+                RpcErrorCode::Internal,
+                format!("Failed to serialize response: {error}"),
+            )
+        })
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<M> RpcJsonDecode for M {
+    fn rpc_json_decode(_bytes: &[u8]) -> RpcResult<Self> {
+        Err(json_disabled())
+    }
+}
+
+#[cfg(not(feature = "json"))]
+impl<M> RpcJsonEncode for M {
+    fn rpc_json_encode(&self) -> RpcResult<Vec<u8>> {
+        Err(json_disabled())
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn json_disabled() -> RpcError {
+    RpcError::new(
+        RpcErrorCode::Unimplemented,
+        "The JSON codec is disabled on this server, use application/proto instead".to_string(),
+    )
 }
 
 type ResponseStream<M> = Pin<Box<dyn Stream<Item = RpcResult<M>> + Send>>;
 
 enum ResponseContent<M> {
-    UnarySuccess(M),
+    /// An encoded unary response. Messages are encoded up front, so one that fails to encode is an
+    /// `UnaryError` with the status and JSON content type of one, never a body in another codec.
+    UnarySuccess(Bytes),
     UnaryError(RpcError),
     StreamingSuccess(ResponseStream<M>),
     StreamingError(RpcError),
@@ -27,38 +146,127 @@ enum ResponseContent<M> {
 
 pub(crate) struct ResponseEncoder<M> {
     binary: bool,
+    /// How many request messages were decoded before this response, for `RpcLogLayer`.
+    request_messages: u64,
     content: ResponseContent<M>,
+    headers: Option<RpcResponseHeaders>,
+    trailers: Option<RpcTrailers>,
+    /// Fail with `Internal` instead of dropping reserved headers from error metadata and trailers.
+    strict_reserved_headers: bool,
+    /// Sort the keys of the JSON sent, see [`RpcConfig::stable_json_field_order`].
+    stable_json_field_order: bool,
+    /// Gzip stream messages of at least this many bytes, see `RouteOptions::stream_compression`.
+    stream_compression: Option<usize>,
 }
 
 impl ResponseEncoder<()> {
     pub fn error(error: impl RpcIntoError, streaming: bool, binary: bool) -> Self {
         Self {
             binary,
+            request_messages: 0,
             content: if streaming {
                 ResponseContent::StreamingError(error.rpc_into_error())
             } else {
                 ResponseContent::UnaryError(error.rpc_into_error())
             },
+            headers: None,
+            trailers: None,
+            strict_reserved_headers: false,
+            stable_json_field_order: false,
+            stream_compression: None,
         }
     }
 }
 
-impl<M: Message + Serialize + 'static> ResponseEncoder<M> {
-    pub fn unary(response: impl RpcIntoResponse<M>, binary: bool) -> Self {
+impl ResponseEncoder<()> {
+    pub fn unary_raw(response: RpcResult<Vec<u8>>, binary: bool) -> Self {
+        Self {
+            binary,
+            request_messages: 1,
+            content: match response {
+                Ok(bytes) => ResponseContent::UnarySuccess(bytes.into()),
+                Err(error) => ResponseContent::UnaryError(error),
+            },
+            headers: None,
+            trailers: None,
+            strict_reserved_headers: false,
+            stable_json_field_order: false,
+            stream_compression: None,
+        }
+    }
+}
+
+impl<M: Message + RpcJsonEncode + 'static> ResponseEncoder<M> {
+    pub fn unary(response: RpcResult<RpcPayload<M>>, binary: bool) -> Self {
         Self {
             binary,
-            content: match response.rpc_into_response() {
-                Ok(message) => ResponseContent::UnarySuccess(message),
+            request_messages: 1,
+            content: match response.and_then(|payload| {
+                payload.encode(binary, |message| encode_unary_message(message, binary))
+            }) {
+                Ok(bytes) => ResponseContent::UnarySuccess(bytes),
                 Err(error) => ResponseContent::UnaryError(error),
             },
+            headers: None,
+            trailers: None,
+            strict_reserved_headers: false,
+            stable_json_field_order: false,
+            stream_compression: None,
         }
     }
 
     pub fn stream(stream: ResponseStream<M>, binary: bool) -> Self {
         Self {
             binary,
+            request_messages: 1,
             content: ResponseContent::StreamingSuccess(stream),
+            headers: None,
+            trailers: None,
+            strict_reserved_headers: false,
+            stable_json_field_order: false,
+            stream_compression: None,
+        }
+    }
+
+    /// Sends a `google.protobuf.Empty` unary response as an empty body, see
+    /// [`RpcConfig::empty_response_body`].
+    pub fn empty_response_body(mut self, empty_response_body: bool) -> Self {
+        if empty_response_body && TypeId::of::<M>() == TypeId::of::<pbjson_types::Empty>() {
+            if let ResponseContent::UnarySuccess(bytes) = &mut self.content {
+                *bytes = Bytes::new();
+            }
         }
+        self
+    }
+
+    /// Sends what the handler set in `headers` with the response headers.
+    pub fn headers(mut self, headers: RpcResponseHeaders) -> Self {
+        self.headers = Some(headers);
+        self
+    }
+
+    /// Sends what the handler set in `trailers` at the end of the response.
+    pub fn trailers(mut self, trailers: RpcTrailers) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+
+    /// See [`RpcConfig::strict_reserved_headers`](crate::config::RpcConfig::strict_reserved_headers).
+    pub fn strict_reserved_headers(mut self, strict_reserved_headers: bool) -> Self {
+        self.strict_reserved_headers = strict_reserved_headers;
+        self
+    }
+
+    /// See [`RpcConfig::stable_json_field_order`].
+    pub fn stable_json_field_order(mut self, stable_json_field_order: bool) -> Self {
+        self.stable_json_field_order = stable_json_field_order;
+        self
+    }
+
+    /// Gzips the messages of a stream of at least `threshold` bytes, see [`stream_compression`].
+    pub fn stream_compression(mut self, threshold: Option<usize>) -> Self {
+        self.stream_compression = threshold;
+        self
     }
 
     pub fn status_code(&self) -> StatusCode {
@@ -77,110 +285,499 @@ impl<M: Message + Serialize + 'static> ResponseEncoder<M> {
     pub fn content_type(&self) -> &'static str {
         use ResponseContent::*;
 
-        match (&self.content, self.binary) {
+        let content_type = match (&self.content, self.binary) {
             // Streaming
-            (StreamingSuccess(_) | StreamingError(_), false) => "application/connect+json",
-            (StreamingSuccess(_) | StreamingError(_), true) => "application/connect+proto",
+            (StreamingSuccess(_) | StreamingError(_), false) => ContentType::ConnectJson,
+            (StreamingSuccess(_) | StreamingError(_), true) => ContentType::ConnectProto,
 
             // Errors in unary calls are ALWAYS encoded as JSONs
             // https://connectrpc.com/docs/protocol/#unary-response
-            (UnaryError(_), _) => "application/json",
+            (UnaryError(_), _) => ContentType::Json,
 
             // Unary successful
-            (UnarySuccess(_), false) => "application/json",
-            (UnarySuccess(_), true) => "application/proto",
-        }
+            (UnarySuccess(_), false) => ContentType::Json,
+            (UnarySuccess(_), true) => ContentType::Proto,
+        };
+
+        content_type.as_str()
     }
 
-    fn encode_body(self) -> Body {
+    fn encode_body(self, stats: &Arc<RpcCallStats>) -> Body {
         use ResponseContent::*;
 
+        let sorted = self.stable_json_field_order;
         match self.content {
             // Error
-            UnaryError(error) => Body::from(encode_unary_error(error)),
-            StreamingError(error) => Body::from(encode_streaming_error(error)),
+            UnaryError(error) => {
+                stats.set_error(&error);
+                let body = encode_unary_error(error);
+                match sorted {
+                    true => Body::from(codec::sort_json_keys(&body)),
+                    false => Body::from(body),
+                }
+            }
+            // A body of unknown length, streaming responses never have a `content-length`.
+            StreamingError(error) => {
+                stats.set_error(&error);
+                let frame = Bytes::from(encode_end_of_stream(
+                    Some(error),
+                    self.trailers,
+                    self.strict_reserved_headers,
+                    sorted,
+                    stats,
+                ));
+                Body::from_stream(futures::stream::once(
+                    async move { Ok::<_, Infallible>(frame) },
+                ))
+            }
 
-            // Unary
-            UnarySuccess(message) => Body::from(if self.binary {
-                encode_unary_message_binary(message)
-            } else {
-                encode_unary_message_json(message).unwrap_or_else(encode_unary_error)
-            }),
+            // Unary, an empty body (see `empty_response_body`) isn't JSON and stays as it is.
+            UnarySuccess(bytes) if sorted && !self.binary && !bytes.is_empty() => {
+                Body::from(codec::sort_json_keys(&bytes))
+            }
+            UnarySuccess(bytes) => Body::from(bytes),
 
             // Streaming
-            StreamingSuccess(stream) => Body::from_stream(encode_stream(stream, self.binary)),
+            StreamingSuccess(stream) => Body::from_stream(encode_stream(
+                stream,
+                EnvelopeEncoder::new(self.binary, sorted, self.stream_compression),
+                self.trailers,
+                self.strict_reserved_headers,
+                sorted,
+                stats.clone(),
+            )),
         }
     }
 
-    pub fn encode_response(self) -> Response {
+    pub fn encode_response(mut self) -> Response {
+        use ResponseContent::*;
+
+        // Unary responses send trailers as headers with a `trailer-` prefix.
+        // https://connectrpc.com/docs/protocol/#unary-response
+        let mut unary_trailers = match (&self.content, &self.trailers) {
+            (UnarySuccess(_) | UnaryError(_), Some(trailers)) => trailers.take(),
+            _ => HeaderMap::new(),
+        };
+        // Reserved headers in the error metadata would corrupt the response, they're dropped (or
+        // fail the call) like those among the trailers.
+        let strict = self.strict_reserved_headers;
+        let checked = match &mut self.content {
+            UnaryError(error) => {
+                strip_reserved_headers(error.metadata_mut(), strict, "error metadata")
+            }
+            _ => Ok(()),
+        }
+        .and_then(|()| strip_reserved_headers(&mut unary_trailers, strict, "trailers"));
+        let mut headers = self
+            .headers
+            .as_ref()
+            .map(RpcResponseHeaders::take)
+            .unwrap_or_default();
+        let checked =
+            checked.and_then(|()| strip_reserved_headers(&mut headers, strict, "response headers"));
+        if let Err(error) = checked {
+            self.content = match self.content {
+                StreamingSuccess(_) | StreamingError(_) => StreamingError(error),
+                _ => UnaryError(error),
+            };
+            unary_trailers.clear();
+            headers.clear();
+        }
+
         let code = self.status_code();
-        let headers = [(header::CONTENT_TYPE, self.content_type())];
-        let body = self.encode_body();
-        (code, headers, body).into_response()
+        let content_type = [(header::CONTENT_TYPE, self.content_type())];
+        let compressed =
+            matches!(self.content, StreamingSuccess(_)) && self.stream_compression.is_some();
+
+        // Picked up by `RpcLogLayer`, which can't otherwise see the Connect error code.
+        let stats = Arc::new(RpcCallStats {
+            streaming: matches!(self.content, StreamingSuccess(_) | StreamingError(_)),
+            request_messages: self.request_messages,
+            ..Default::default()
+        });
+        if matches!(self.content, UnarySuccess(_)) {
+            stats.response_messages.store(1, Ordering::Relaxed);
+        }
+
+        // Unary error metadata goes in the headers, streaming error metadata in the end-of-stream.
+        let error_metadata = match &self.content {
+            UnaryError(error) => error.metadata().clone(),
+            _ => HeaderMap::new(),
+        };
+
+        let body = self.encode_body(&stats);
+        // Set here rather than left to the server, so it's there for middleware too.
+        let content_length = body.size_hint().exact();
+        let mut response = (code, content_type, body).into_response();
+        if let Some(content_length) = content_length {
+            response
+                .headers_mut()
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(content_length));
+        }
+        for (key, value) in headers.iter().chain(error_metadata.iter()) {
+            response.headers_mut().append(key, value.clone());
+        }
+        for (key, value) in unary_trailers.iter() {
+            if let Ok(key) = HeaderName::try_from(format!("trailer-{}", key)) {
+                response.headers_mut().append(key, value.clone());
+            }
+        }
+        if compressed {
+            response.headers_mut().insert(
+                HeaderName::from_static("connect-content-encoding"),
+                HeaderValue::from_static("gzip"),
+            );
+        }
+        response.extensions_mut().insert(stats);
+        response
     }
 }
 
 fn encode_unary_error(error: RpcError) -> Vec<u8> {
-    // Errors in unary calls are ALWAYS encoded as JSONs
-    //
-    // https://connectrpc.com/docs/protocol/#unary-response
-    serde_json::to_vec(&error).unwrap()
+    codec::encode_unary_error(&error)
 }
 
-fn encode_streaming_error(error: RpcError) -> Vec<u8> {
+/// The end-of-stream frame, with the handler's trailers after the error's metadata. Reserved
+/// headers among them are dropped, or with `strict` make it an `Internal` error. With `sorted`,
+/// the keys of its JSON are sorted.
+fn encode_end_of_stream(
+    error: Option<RpcError>,
+    trailers: Option<RpcTrailers>,
+    strict: bool,
+    sorted: bool,
+    stats: &RpcCallStats,
+) -> Vec<u8> {
     // Streaming errors are wrapped in an { "error": ... }
     // while unary errors are just plain JSON encoded.
     //
     // https://connectrpc.com/docs/protocol/#error-end-stream
-    #[derive(Serialize)]
-    struct EndOfStream {
-        error: RpcError,
+    let mut end = match error {
+        Some(error) => EndStreamResponse::error(error),
+        None => EndStreamResponse::default(),
+    };
+    let mut trailers = trailers.map(|trailers| trailers.take()).unwrap_or_default();
+    let checked = strip_reserved_headers(&mut end.metadata, strict, "error metadata")
+        .and_then(|()| strip_reserved_headers(&mut trailers, strict, "trailers"));
+    match checked {
+        Ok(()) => {
+            for (key, value) in trailers.iter() {
+                end.metadata.append(key, value.clone());
+            }
+        }
+        Err(error) => {
+            stats.set_error(&error);
+            end = EndStreamResponse::error(error);
+        }
     }
+    let mut frame = end.encode();
+    if sorted {
+        sort_frame_keys(&mut frame);
+    }
+    frame
+}
 
-    let message = EndOfStream { error };
-
-    let mut result = vec![0x2, 0, 0, 0, 0];
-    serde_json::to_writer(&mut result, &message).unwrap();
+/// Sorts the keys of the JSON payload of an enveloped `frame`, see
+/// [`RpcConfig::stable_json_field_order`].
+fn sort_frame_keys(frame: &mut Vec<u8>) {
+    let payload = codec::sort_json_keys(&frame[5..]);
+    let prefix = codec::envelope_prefix(frame[0], payload.len());
+    frame.clear();
+    frame.extend_from_slice(&prefix);
+    frame.extend_from_slice(&payload);
+}
 
-    let size = ((result.len() - 5) as u32).to_be_bytes();
-    result[1..5].copy_from_slice(&size);
-    result
+fn encode_unary_message<M: RpcJsonEncode + Message>(message: M, binary: bool) -> RpcResult<Bytes> {
+    let mut buffer = pool::take();
+    codec::encode_unary_response(&message, binary, &mut buffer)?;
+    Ok(pool::into_bytes(buffer))
 }
 
-fn encode_unary_message_binary<M: Message>(message: M) -> Vec<u8> {
-    message.encode_to_vec()
+/// Binary messages this large are sent as two chunks, the envelope prefix and the payload encoded
+/// into a buffer of its own, which hyper writes out with vectored IO instead of copying them
+/// together. Smaller frames stay one chunk, a tiny one of its own doesn't pay for itself (and is a
+/// DATA frame of its own in HTTP/2). Payloads this large wouldn't be pooled anyway.
+const VECTORED_FRAME_BYTES: usize = pool::MAX_POOLED_CAPACITY;
+
+/// Room for the prefixes of this many large frames is allocated at once.
+const PREFIXES_PER_ALLOCATION: usize = 64;
+
+/// The chunks of one frame, see [`VECTORED_FRAME_BYTES`].
+type FrameChunks = Chain<Once<Bytes>, option::IntoIter<Bytes>>;
+
+/// Envelope-encodes the messages of a response stream.
+struct EnvelopeEncoder {
+    binary: bool,
+    /// Sort the keys of JSON messages, see [`RpcConfig::stable_json_field_order`].
+    sorted: bool,
+    /// Gzip messages of at least this many bytes.
+    #[cfg_attr(not(feature = "stream-compression"), allow(dead_code))]
+    compression: Option<usize>,
+    /// The prefixes of large frames are split off this, so they don't need an allocation each.
+    prefixes: BytesMut,
 }
 
-fn encode_unary_message_json<M: Serialize>(message: M) -> RpcResult<Vec<u8>> {
-    match serde_json::to_vec(&message) {
-        Ok(message) => Ok(message),
-        Err(error) => Err(RpcError::new(
-            RpcErrorCode::Internal,
-            format!("Failed to serialize response: {error}"),
-        )),
+impl EnvelopeEncoder {
+    fn new(binary: bool, sorted: bool, compression: Option<usize>) -> Self {
+        Self {
+            binary,
+            sorted,
+            compression,
+            prefixes: BytesMut::new(),
+        }
+    }
+
+    /// Gzips the payload of the enveloped `frame` if it's at least the threshold, and counts it in
+    /// `stats`.
+    fn compress_frame(&self, frame: Vec<u8>, stats: &RpcCallStats) -> Vec<u8> {
+        #[cfg(feature = "stream-compression")]
+        if self
+            .compression
+            .is_some_and(|threshold| frame.len() - 5 >= threshold)
+        {
+            let payload = codec::gzip(&frame[5..]);
+            let mut frame = frame;
+            frame.clear();
+            codec::encode_envelope(codec::COMPRESSED_FLAG, &payload, &mut frame);
+            stats.compressed_frames.fetch_add(1, Ordering::Relaxed);
+            return frame;
+        }
+        stats.uncompressed_frames.fetch_add(1, Ordering::Relaxed);
+        frame
+    }
+
+    /// Like [`compress_frame`](Self::compress_frame), for a `payload` without its envelope. Returns
+    /// the flags of the envelope too.
+    fn compress_payload(&self, payload: Vec<u8>, stats: &RpcCallStats) -> (u8, Vec<u8>) {
+        #[cfg(feature = "stream-compression")]
+        if self
+            .compression
+            .is_some_and(|threshold| payload.len() >= threshold)
+        {
+            stats.compressed_frames.fetch_add(1, Ordering::Relaxed);
+            return (codec::COMPRESSED_FLAG, codec::gzip(&payload));
+        }
+        stats.uncompressed_frames.fetch_add(1, Ordering::Relaxed);
+        (0, payload)
+    }
+
+    fn encode<M: RpcJsonEncode + Message>(
+        &mut self,
+        message: &M,
+        stats: &RpcCallStats,
+    ) -> RpcResult<FrameChunks> {
+        let size = match self.binary {
+            true => message.encoded_len(),
+            false => 0,
+        };
+
+        if size < VECTORED_FRAME_BYTES {
+            let mut buffer = pool::take();
+            buffer.reserve(5 + size);
+            codec::encode_stream_response(message, self.binary, &mut buffer)?;
+            if self.sorted && !self.binary {
+                sort_frame_keys(&mut buffer);
+            }
+            let buffer = self.compress_frame(buffer, stats);
+            return Ok(iter::once(pool::into_bytes(buffer)).chain(None));
+        }
+
+        let mut payload = Vec::with_capacity(size);
+        codec::encode_unary_response(message, true, &mut payload)?;
+        let (flags, payload) = self.compress_payload(payload, stats);
+        if self.prefixes.capacity() < 5 {
+            // Reuses the allocation once the prefixes split off it are dropped.
+            self.prefixes.reserve(5 * PREFIXES_PER_ALLOCATION);
+        }
+        self.prefixes
+            .extend_from_slice(&codec::envelope_prefix(flags, payload.len()));
+        let prefix = self.prefixes.split().freeze();
+        Ok(iter::once(prefix).chain(Some(Bytes::from(payload))))
     }
 }
 
-fn encode_envelope<M: Serialize + Message>(message: M, binary: bool) -> RpcResult<Vec<u8>> {
-    let mut result = vec![0, 0, 0, 0, 0];
+/// The threshold of the route's `RouteOptions::stream_compression`, if it has one and the client
+/// sent `gzip` in its `connect-accept-encoding`.
+#[cfg(feature = "stream-compression")]
+pub(crate) fn stream_compression(parts: &request::Parts) -> Option<usize> {
+    let threshold = parts.extensions.get::<RouteOptions>()?.stream_compression?;
+    accepts_gzip(&parts.headers, "connect-accept-encoding").then_some(threshold)
+}
+
+#[cfg(not(feature = "stream-compression"))]
+pub(crate) fn stream_compression(_parts: &request::Parts) -> Option<usize> {
+    None
+}
+
+/// Whether the `accept` headers list `gzip`, without a `q=0`.
+fn accepts_gzip(headers: &HeaderMap, accept: &str) -> bool {
+    headers
+        .get_all(accept)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .any(|encoding| {
+            let mut params = encoding.split(';').map(str::trim);
+            params
+                .next()
+                .is_some_and(|name| name.eq_ignore_ascii_case("gzip"))
+                && !params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                })
+        })
+}
+
+/// The compression of a call's response, as negotiated and as its handler overrode it through
+/// [`RpcCompressionInfo`](crate::parts::RpcCompressionInfo), which shares it through the request
+/// extensions.
+#[derive(Clone, Debug)]
+pub(crate) struct ResponseCompression(Arc<Mutex<Negotiated>>);
+
+#[derive(Debug)]
+struct Negotiated {
+    request_encoding: Option<String>,
+    /// Whether the response can be gzipped at all: the client accepts it, and the server does it.
+    gzip: bool,
+    /// The threshold of the route's `RouteOptions::stream_compression` for this client.
+    threshold: Option<usize>,
+    encoding: Option<&'static str>,
+    overridden: bool,
+}
+
+/// Set on unary responses whose handler overrode their compression, for the compression of
+/// `layers::recommended`.
+#[cfg(feature = "tower-http")]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ForcedEncoding(pub Option<&'static str>);
+
+impl ResponseCompression {
+    /// Negotiates from the request headers. Streaming responses gzip their messages over
+    /// `threshold`, unary ones are gzipped by `layers::recommended`, if it's there.
+    pub fn new(parts: &request::Parts, streaming: bool, threshold: Option<usize>) -> Self {
+        let request_header = match streaming {
+            true => "connect-content-encoding",
+            false => header::CONTENT_ENCODING.as_str(),
+        };
+        let request_encoding = parts
+            .headers
+            .get(request_header)
+            .and_then(|encoding| encoding.to_str().ok())
+            .filter(|encoding| !encoding.eq_ignore_ascii_case("identity"))
+            .map(str::to_string);
+
+        let (gzip, encoding) = match streaming {
+            true => (
+                cfg!(feature = "stream-compression")
+                    && accepts_gzip(&parts.headers, "connect-accept-encoding"),
+                threshold.map(|_| "gzip"),
+            ),
+            false => {
+                #[cfg(feature = "tower-http")]
+                let gzip = parts.extensions.get::<crate::layers::UnaryGzip>().is_some()
+                    && accepts_gzip(&parts.headers, header::ACCEPT_ENCODING.as_str());
+                #[cfg(not(feature = "tower-http"))]
+                let gzip = false;
+                (gzip, gzip.then_some("gzip"))
+            }
+        };
+
+        let mut negotiated = Negotiated {
+            request_encoding,
+            gzip,
+            threshold,
+            encoding,
+            overridden: false,
+        };
+        let mode = parts
+            .extensions
+            .get::<RouteOptions>()
+            .map(|options| options.compression)
+            .unwrap_or_default();
+        match mode {
+            CompressionMode::Auto => {}
+            CompressionMode::Never => {
+                negotiated.gzip = false;
+                negotiated.encoding = None;
+                negotiated.overridden = true;
+            }
+            CompressionMode::Force(encoding) => {
+                let gzip = encoding.eq_ignore_ascii_case("gzip") && negotiated.gzip;
+                negotiated.encoding = gzip.then_some("gzip");
+                negotiated.overridden = true;
+            }
+        }
+
+        Self(Arc::new(Mutex::new(negotiated)))
+    }
+
+    /// The request's encoding and the response's.
+    pub fn encodings(&self) -> (Option<String>, Option<&'static str>) {
+        let negotiated = self.0.lock().unwrap();
+        (negotiated.request_encoding.clone(), negotiated.encoding)
+    }
+
+    /// Sends the response with `encoding` if it can be, and returns what it's sent with.
+    pub fn set(&self, encoding: Option<&str>) -> Option<&'static str> {
+        let mut negotiated = self.0.lock().unwrap();
+        let requested = match encoding {
+            None => Some(None),
+            Some(encoding) if encoding.eq_ignore_ascii_case("identity") => Some(None),
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") && negotiated.gzip => {
+                Some(Some("gzip"))
+            }
+            Some(_) => None,
+        };
+        match requested {
+            Some(requested) => {
+                negotiated.encoding = requested;
+                negotiated.overridden = true;
+            }
+            None => tracing::warn!(
+                target: "axum_connect::codec",
+                requested = encoding,
+                sent = negotiated.encoding.unwrap_or("identity"),
+                "the handler asked for a response encoding the client doesn't accept, or the \
+                 server can't compress with"
+            ),
+        }
+        negotiated.encoding
+    }
 
-    if binary {
-        if let Err(error) = message.encode(&mut result) {
-            return Err(RpcError::new(RpcErrorCode::Internal, error.to_string()));
+    /// The threshold stream messages are gzipped over: every message if the handler asked for
+    /// gzip.
+    pub fn stream_threshold(&self) -> Option<usize> {
+        let negotiated = self.0.lock().unwrap();
+        match negotiated.overridden {
+            true => negotiated.encoding.map(|_| 0),
+            false => negotiated.threshold,
         }
-    } else if let Err(error) = serde_json::to_writer(&mut result, &message) {
-        return Err(RpcError::new(RpcErrorCode::Internal, error.to_string()));
     }
 
-    let size = ((result.len() - 5) as u32).to_be_bytes();
-    result[1..5].copy_from_slice(&size);
-    Ok(result)
+    /// Marks a unary `response` with the handler's override, for `layers::recommended`, which
+    /// leaves stream responses alone either way.
+    #[cfg_attr(not(feature = "tower-http"), allow(unused_variables))]
+    pub fn mark(&self, response: &mut Response) {
+        #[cfg(feature = "tower-http")]
+        let negotiated = self.0.lock().unwrap();
+        #[cfg(feature = "tower-http")]
+        if negotiated.overridden {
+            response
+                .extensions_mut()
+                .insert(ForcedEncoding(negotiated.encoding));
+        }
+    }
 }
 
-fn encode_stream<M: Serialize + Message + 'static>(
+fn encode_stream<M: RpcJsonEncode + Message + 'static>(
     stream: ResponseStream<M>,
-    binary: bool,
-) -> impl Stream<Item = Result<Vec<u8>, Infallible>> {
+    encoder: EnvelopeEncoder,
+    trailers: Option<RpcTrailers>,
+    strict_reserved_headers: bool,
+    stable_json_field_order: bool,
+    stats: Arc<RpcCallStats>,
+) -> impl Stream<Item = Result<Bytes, Infallible>> {
     // This was born in hell and in hell it shall stay.
     // For mortals, it simply ensures that all messages
     // inside the stream are passed along envelope-encodeed
@@ -189,40 +786,80 @@ fn encode_stream<M: Serialize + Message + 'static>(
     // At this this stage the only errors can come from within
     // the stream and this thing handles that case by simply
     // encoding the error end terminating the stream.
-    futures::stream::unfold(Some(stream), move |stream| async move {
-        match stream {
-            None => {
-                // We are past the last message, returning None
-                // ends the stream without any more messages.
-                None
-            }
-            Some(mut stream) => match stream.next().await {
-                Some(Ok(message)) => {
-                    // This is a normal message, we need to envelope-encode it.
-                    // If an error occurs, we encode it instead and terminate
-                    // the stream.
-                    match encode_envelope(message, binary) {
-                        Ok(message) => Some((Ok(message), Some(stream))),
-                        Err(error) => Some((Ok(encode_streaming_error(error)), None)),
-                    }
-                }
-                Some(Err(error)) => {
-                    // An error in the stream. Send it as the last
-                    // message and terminate the stream.
-                    Some((Ok(encode_streaming_error(error)), None))
-                }
+    let last = |bytes: Vec<u8>| iter::once(Bytes::from(bytes)).chain(None);
+    futures::stream::unfold(Some((stream, encoder)), move |state| {
+        let stats = stats.clone();
+        let trailers = trailers.clone();
+        async move {
+            match state {
                 None => {
-                    // Stream was read all the way through without errors,
-                    // send the last message.
-                    //
-                    // Final streaming message ALWAYS has to contain at least
-                    // an empty object and is ALWAYS encoded as JSON.
-                    // https://connectrpc.com/docs/protocol/#error-end-stream
-                    Some((Ok(vec![0x2, 0, 0, 0, 2, b'{', b'}']), None))
+                    // We are past the last message, returning None
+                    // ends the stream without any more messages.
+                    None
                 }
-            },
+                Some((mut stream, mut encoder)) => match stream.next().await {
+                    Some(Ok(message)) => {
+                        // This is a normal message, we need to envelope-encode it.
+                        // If an error occurs, we encode it instead and terminate
+                        // the stream.
+                        match encoder.encode(&message, &stats) {
+                            Ok(chunks) => {
+                                stats.response_messages.fetch_add(1, Ordering::Relaxed);
+                                Some((chunks, Some((stream, encoder))))
+                            }
+                            Err(error) => {
+                                stats.set_error(&error);
+                                Some((
+                                    last(encode_end_of_stream(
+                                        Some(error),
+                                        trailers,
+                                        strict_reserved_headers,
+                                        stable_json_field_order,
+                                        &stats,
+                                    )),
+                                    None,
+                                ))
+                            }
+                        }
+                    }
+                    Some(Err(error)) => {
+                        // An error in the stream. Send it as the last
+                        // message and terminate the stream.
+                        stats.set_error(&error);
+                        Some((
+                            last(encode_end_of_stream(
+                                Some(error),
+                                trailers,
+                                strict_reserved_headers,
+                                stable_json_field_order,
+                                &stats,
+                            )),
+                            None,
+                        ))
+                    }
+                    None => {
+                        // Stream was read all the way through without errors,
+                        // send the last message.
+                        //
+                        // Final streaming message ALWAYS has to contain at least
+                        // an empty object and is ALWAYS encoded as JSON.
+                        // https://connectrpc.com/docs/protocol/#error-end-stream
+                        Some((
+                            last(encode_end_of_stream(
+                                None,
+                                trailers,
+                                strict_reserved_headers,
+                                stable_json_field_order,
+                                &stats,
+                            )),
+                            None,
+                        ))
+                    }
+                },
+            }
         }
     })
+    .flat_map(|chunks| futures::stream::iter(chunks.map(Ok)))
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -235,6 +872,25 @@ pub(crate) struct UnaryGetQuery {
 }
 
 pub(crate) fn decode_check_query(parts: &request::Parts) -> Result<ReqResInto, Response> {
+    // Before anything is parsed, so oversized URLs cost no decoding.
+    let url_bytes = parts
+        .uri
+        .path_and_query()
+        .map_or(0, |url| url.as_str().len());
+    if let Some(max) = RpcConfig::from_parts(parts).max_get_url_bytes {
+        if url_bytes > max {
+            let error = RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "The URL is {} bytes, over the limit of {} for GET requests, send the request \
+                     with POST instead",
+                    url_bytes, max
+                ),
+            );
+            return Err(ResponseEncoder::error(error, false, false).encode_response());
+        }
+    }
+
     let query_str = match parts.uri.query() {
         Some(x) => x,
         None => {
@@ -255,6 +911,15 @@ pub(crate) fn decode_check_query(parts: &request::Parts) -> Result<ReqResInto, R
         }
     };
 
+    if RpcConfig::from_parts(parts).require_protocol_version && query.connect.is_none() {
+        let error = RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            "Missing connect query parameter".to_string(),
+        );
+
+        return Err(ResponseEncoder::error(error, false, false).encode_response());
+    }
+
     let binary = match query.encoding.as_str() {
         "json" => false,
         "proto" => true,
@@ -268,15 +933,31 @@ pub(crate) fn decode_check_query(parts: &request::Parts) -> Result<ReqResInto, R
         }
     };
 
-    Ok(ReqResInto { binary })
+    check_metadata(parts)
+        .map_err(|error| ResponseEncoder::error(error, false, binary).encode_response())?;
+
+    Ok(ReqResInto {
+        binary,
+        framing: Framing::Unary,
+    })
 }
 
 pub(crate) fn decode_check_headers(
     parts: &mut request::Parts,
     for_streaming: bool,
 ) -> Result<ReqResInto, Response> {
-    // Check the version header, if specified.
-    if let Some(version) = parts.headers.get("connect-protocol-version") {
+    // Check the version header, if specified (or required).
+    let version = parts.headers.get("connect-protocol-version");
+    if version.is_none() && RpcConfig::from_parts(parts).require_protocol_version {
+        let error = RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            "Missing connect-protocol-version header".to_string(),
+        );
+
+        return Err(ResponseEncoder::error(error, for_streaming, true).encode_response());
+    }
+
+    if let Some(version) = version {
         let version = version.to_str().unwrap_or_default();
         if version != "1" {
             let error = RpcError::new(
@@ -291,42 +972,167 @@ pub(crate) fn decode_check_headers(
     // Decode the content type (binary or JSON).
     // TODO: I'm not sure if this is correct. The Spec doesn't say what content type will be set for
     //       server-streaming responses.
-    let binary = match parts.headers.get("content-type") {
-        Some(content_type) => match (
-            content_type
-                .to_str()
-                .unwrap_or_default()
-                .to_lowercase()
-                .split(';')
-                .next()
-                .unwrap_or_default()
-                .trim(),
-            for_streaming,
-        ) {
-            ("application/json", false) => false,
-            ("application/proto", false) => true,
-            ("application/connect+json", true) => false,
-            ("application/connect+proto", true) => true,
-            (s, _) => {
-                let error = RpcError::new(
-                    RpcErrorCode::InvalidArgument,
-                    format!("Wrong or unknown Content-Type: {}", s),
-                );
+    let binary = match ContentType::cached(&mut parts.extensions, &parts.headers) {
+        Some(content_type) if content_type.is_streaming() == for_streaming => {
+            content_type.is_binary()
+        }
+        // A client stub out of date with the proto. The error is encoded the way the client
+        // expects, so it can read it instead of choking on the other framing.
+        Some(content_type) if for_streaming => {
+            let error = RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "This method is streaming, its Content-Type must be \
+                     application/connect+json or application/connect+proto, not {}",
+                    content_type.as_str()
+                ),
+            );
 
-                return Err(ResponseEncoder::error(error, true, true).encode_response());
-            }
-        },
-        None => {
+            return Err(ResponseEncoder::error(error, false, false).encode_response());
+        }
+        Some(content_type) => {
             let error = RpcError::new(
                 RpcErrorCode::InvalidArgument,
-                "Missing Content-Type header".to_string(),
+                format!(
+                    "This method is unary, its Content-Type must be application/json or \
+                     application/proto, not {}",
+                    content_type.as_str()
+                ),
             );
 
+            let binary = content_type.is_binary();
+            return Err(ResponseEncoder::error(error, true, binary).encode_response());
+        }
+        None => {
+            if let Some(response) = grpc_rejection(parts) {
+                return Err(response);
+            }
+
+            let error = match parts.headers.get(header::CONTENT_TYPE) {
+                Some(content_type) => RpcError::new(
+                    RpcErrorCode::InvalidArgument,
+                    format!(
+                        "Wrong or unknown Content-Type: {}",
+                        content_type
+                            .to_str()
+                            .unwrap_or_default()
+                            .to_lowercase()
+                            .split(';')
+                            .next()
+                            .unwrap_or_default()
+                            .trim()
+                    ),
+                ),
+                None => RpcError::new(
+                    RpcErrorCode::InvalidArgument,
+                    "Missing Content-Type header".to_string(),
+                ),
+            };
+
             return Err(ResponseEncoder::error(error, true, true).encode_response());
         }
     };
 
-    Ok(ReqResInto { binary })
+    check_metadata(parts)
+        .map_err(|error| ResponseEncoder::error(error, for_streaming, binary).encode_response())?;
+
+    let framing = match for_streaming {
+        true => Framing::Stream,
+        false => Framing::Unary,
+    };
+    Ok(ReqResInto { binary, framing })
+}
+
+/// [`decode_check_headers`] for a unary method, which takes a streaming Content-Type under
+/// [`RpcConfig::adapt_streaming_unary`].
+pub(crate) fn decode_check_unary_headers(
+    parts: &mut request::Parts,
+) -> Result<ReqResInto, Response> {
+    let streaming = ContentType::cached(&mut parts.extensions, &parts.headers)
+        .is_some_and(|content_type| content_type.is_streaming());
+    if !streaming || !RpcConfig::from_parts(parts).adapt_streaming_unary {
+        return decode_check_headers(parts, false);
+    }
+
+    let user_agent = parts
+        .headers
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .unwrap_or_default();
+    tracing::warn!(
+        target: "axum_connect::codec",
+        path = parts.uri.path(),
+        user_agent,
+        "unary RPC called with a streaming Content-Type, adapting it"
+    );
+
+    let checked = decode_check_headers(parts, true)?;
+    Ok(ReqResInto {
+        framing: Framing::AdaptedUnary,
+        ..checked
+    })
+}
+
+/// Checks the metadata against `max_metadata_bytes` and `max_metadata_value_bytes`, skipping
+/// reserved headers.
+fn check_metadata(parts: &request::Parts) -> Result<(), RpcError> {
+    let config = RpcConfig::from_parts(parts);
+    if config.max_metadata_bytes.is_none() && config.max_metadata_value_bytes.is_none() {
+        return Ok(());
+    }
+
+    let mut total = 0;
+    for (key, value) in &parts.headers {
+        if RpcMetadata::is_reserved(key.as_str()) {
+            continue;
+        }
+        if let Some(max) = config.max_metadata_value_bytes {
+            if value.len() > max {
+                return Err(RpcError::new(
+                    RpcErrorCode::ResourceExhausted,
+                    format!(
+                        "The value of the metadata {} is {} bytes, over the limit of {}",
+                        key,
+                        value.len(),
+                        max
+                    ),
+                ));
+            }
+        }
+        total += key.as_str().len() + value.len();
+    }
+
+    match config.max_metadata_bytes {
+        Some(max) if total > max => Err(RpcError::new(
+            RpcErrorCode::ResourceExhausted,
+            format!("The metadata is {} bytes, over the limit of {}", total, max),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Picks the codec for a unary response. An `Accept` header naming a supported codec wins,
+/// anything else (eg. a browser's `*/*`) mirrors the request codec.
+pub(crate) fn response_binary(parts: &request::Parts, request_binary: bool) -> bool {
+    let Some(accept) = parts
+        .headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+    else {
+        return request_binary;
+    };
+
+    for media_type in accept.split(',') {
+        let media_type = media_type.split(';').next().unwrap_or_default().trim();
+        if media_type.eq_ignore_ascii_case("application/proto") {
+            return true;
+        }
+        if media_type.eq_ignore_ascii_case("application/json") {
+            return false;
+        }
+    }
+
+    request_binary
 }
 
 pub(crate) fn decode_request_payload_from_query<M, S>(
@@ -335,11 +1141,28 @@ pub(crate) fn decode_request_payload_from_query<M, S>(
     as_binary: bool,
 ) -> Result<M, Response>
 where
-    M: Message + DeserializeOwned + Default,
+    M: Message + RpcJsonDecode + Default + 'static,
     S: Send + Sync + 'static,
 {
     let for_streaming = false;
+    let message = query_message_bytes(parts)?;
+    verify_body(parts, &message).map_err(|error| {
+        ResponseEncoder::error(error, for_streaming, as_binary).encode_response()
+    })?;
 
+    let message = if as_binary {
+        decode_binary_message(&message)
+    } else {
+        M::rpc_json_decode(&message)
+    };
+
+    message
+        .and_then(|message| defaults::apply(parts, message))
+        .map_err(|error| ResponseEncoder::error(error, for_streaming, as_binary).encode_response())
+}
+
+/// The still-encoded request message of a unary GET request.
+pub(crate) fn query_message_bytes(parts: &request::Parts) -> Result<Vec<u8>, Response> {
     let query_str = match parts.uri.query() {
         Some(x) => x,
         None => {
@@ -360,98 +1183,1618 @@ where
         }
     };
 
-    let message = if query.base64 == Some(1) {
-        use base64::{engine::general_purpose, Engine as _};
-
-        match general_purpose::URL_SAFE.decode(&query.message) {
-            Ok(x) => x,
-            Err(err) => {
-                let error = RpcError::new(
-                    RpcErrorCode::InvalidArgument,
-                    format!("Wrong query.message, {}", err),
-                );
-
-                return Err(ResponseEncoder::error(error, false, false).encode_response());
-            }
-        }
-    } else {
-        query.message.as_bytes().to_vec()
-    };
-
-    if as_binary {
-        let message: M = M::decode(&message[..]).map_err(|e| {
-            let error = RpcError::new(
-                RpcErrorCode::InvalidArgument,
-                format!("Failed to decode binary protobuf. {}", e),
-            );
-
-            ResponseEncoder::error(error, for_streaming, as_binary).encode_response()
-        })?;
-
-        Ok(message)
-    } else {
-        let message: M = serde_json::from_slice(&message).map_err(|e| {
-            let error = RpcError::new(
-                RpcErrorCode::InvalidArgument,
-                format!("Failed to decode json. {}", e),
-            );
-
-            ResponseEncoder::error(error, for_streaming, as_binary).encode_response()
-        })?;
-
-        Ok(message)
-    }
+    codec::decode_query_message(&query.message, query.base64 == Some(1))
+        .map_err(|error| ResponseEncoder::error(error, false, false).encode_response())
 }
 
 pub(crate) async fn decode_request_payload<M, S>(
-    req: Request<Body>,
+    parts: &request::Parts,
+    body: Body,
     _state: &S,
     as_binary: bool,
-    for_streaming: bool,
+    framing: Framing,
 ) -> Result<M, Response>
 where
-    M: Message + DeserializeOwned + Default,
+    M: Message + RpcJsonDecode + Default + 'static,
     S: Send + Sync + 'static,
 {
-    let bytes = body::to_bytes(req.into_body(), usize::MAX)
-        .await
-        .map_err(|e| {
-            let error = RpcError::new(
-                RpcErrorCode::InvalidArgument,
-                format!("Failed to read request body. {}", e),
-            );
-
-            ResponseEncoder::error(error, for_streaming, as_binary).encode_response()
-        })?;
+    let config = RpcConfig::from_parts(parts);
+    let for_streaming = framing.is_streaming();
+
+    // A per-route timeout overrides the router config.
+    let idle_timeout = parts
+        .extensions
+        .get::<StreamIdleTimeout>()
+        .map(|timeout| timeout.0)
+        .or(config.stream_idle_timeout)
+        .filter(|_| framing == Framing::Stream);
+
+    let bytes = read_body(
+        body,
+        idle_timeout,
+        config.max_request_bytes,
+        preallocates(parts),
+    )
+    .await
+    .map_err(|error| ResponseEncoder::error(error, for_streaming, as_binary).encode_response())?;
+    verify_body(parts, &bytes).map_err(|error| {
+        ResponseEncoder::error(error, for_streaming, as_binary).encode_response()
+    })?;
 
     // All streaming messages are wrapped in an envelope,
     // even if they are just requests for server-streaming.
     // https://connectrpc.com/docs/protocol/#streaming-request
     // https://github.com/connectrpc/connectrpc.com/issues/141
-    // TODO: Parse the envelope (containing flags u8 and length u32)
-    let bytes = bytes.slice(if for_streaming { 5.. } else { 0.. });
+    let message = match framing {
+        Framing::Unary => codec::decode_unary_request(&bytes, as_binary, &config),
+        Framing::Stream => codec::decode_stream_request(&bytes, as_binary, &config),
+        Framing::AdaptedUnary => codec::decode_single_frame_request(&bytes, as_binary, &config),
+    };
 
-    if as_binary {
-        let message: M = M::decode(bytes).map_err(|e| {
-            let error = RpcError::new(
-                RpcErrorCode::InvalidArgument,
-                format!("Failed to decode binary protobuf. {}", e),
-            );
+    message
+        .and_then(|message| defaults::apply(parts, message))
+        .map_err(|error| ResponseEncoder::error(error, for_streaming, as_binary).encode_response())
+}
 
-            ResponseEncoder::error(error, for_streaming, as_binary).encode_response()
-        })?;
+/// The response to a gRPC request, which isn't supported (yet). gRPC needs HTTP/2 for its
+/// trailers, so over HTTP/1.1 it's a plain `505 HTTP Version Not Supported` saying so. Over HTTP/2
+/// it's a trailers-only `UNIMPLEMENTED` response, which gRPC clients can read.
+fn grpc_rejection(parts: &request::Parts) -> Option<Response> {
+    let content_type = parts.headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+    let media_type = content_type.split(';').next()?.trim().to_ascii_lowercase();
+    let is_grpc = media_type == "application/grpc" || media_type.starts_with("application/grpc+");
+    if !is_grpc {
+        return None;
+    }
 
-        Ok(message)
-    } else {
-        let message: M = serde_json::from_slice(&bytes).map_err(|e| {
-            let error = RpcError::new(
-                RpcErrorCode::InvalidArgument,
-                format!("Failed to decode JSON protobuf. {}", e),
-            );
+    if parts.version < Version::HTTP_2 {
+        let message = format!(
+            "gRPC requires HTTP/2, this request used {:?}. Use the Connect protocol \
+             (application/json or application/proto) over HTTP/1.1",
+            parts.version
+        );
+        return Some((StatusCode::HTTP_VERSION_NOT_SUPPORTED, message).into_response());
+    }
 
-            ResponseEncoder::error(error, for_streaming, as_binary).encode_response()
-        })?;
+    // `grpc-message` is percent-encoded, this one has nothing to escape.
+    let message = "gRPC isn't supported, use the Connect protocol instead";
+    Some(
+        (
+            [
+                (
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/grpc"),
+                ),
+                (
+                    header::HeaderName::from_static("grpc-status"),
+                    HeaderValue::from_static("12"),
+                ),
+                (
+                    header::HeaderName::from_static("grpc-message"),
+                    HeaderValue::from_static(message),
+                ),
+            ],
+            (),
+        )
+            .into_response(),
+    )
+}
 
-        Ok(message)
+/// Whether the route reads bodies into a buffer of their declared length, see
+/// `RouteOptions::preallocate_body`.
+pub(crate) fn preallocates(parts: &request::Parts) -> bool {
+    parts
+        .extensions
+        .get::<RouteOptions>()
+        .is_some_and(|options| options.preallocate_body)
+}
+
+/// Reads the whole request body, up to `max_bytes`. With an idle timeout, reading fails with
+/// `DeadlineExceeded` if no frame arrives within the window; the window restarts with every frame.
+/// With `preallocate` and a `max_bytes`, the buffer is reserved for the declared length upfront.
+pub(crate) async fn read_body(
+    body: Body,
+    idle_timeout: Option<Duration>,
+    max_bytes: Option<usize>,
+    preallocate: bool,
+) -> RpcResult<Bytes> {
+    // A declared length over the limit is rejected before the body is polled, so hyper never
+    // sends `100 Continue` to clients that asked with `Expect`, and they don't upload it.
+    let declared = HttpBody::size_hint(&body).lower();
+    if let Some(max_bytes) = max_bytes.filter(|max| declared > *max as u64) {
+        return Err(too_large(max_bytes));
+    }
+
+    // Within the limit, so a made up `content-length` can't reserve more than a body may have.
+    let reserved = match (preallocate, max_bytes) {
+        (true, Some(_)) => declared as usize,
+        _ => 0,
+    };
+    let mut stream = body.into_data_stream();
+    let mut bytes = BytesMut::with_capacity(reserved);
+
+    loop {
+        let Some(chunk) = next_chunk(&mut stream, idle_timeout).await? else {
+            return Ok(bytes.freeze());
+        };
+
+        bytes.extend_from_slice(&chunk);
+
+        if let Some(max_bytes) = max_bytes.filter(|max| bytes.len() > *max) {
+            return Err(too_large(max_bytes));
+        }
+    }
+}
+
+/// The next chunk of a request body, `None` at its end. With an idle timeout, fails with
+/// `DeadlineExceeded` if none arrives within it.
+async fn next_chunk(
+    stream: &mut BodyDataStream,
+    idle_timeout: Option<Duration>,
+) -> RpcResult<Option<Bytes>> {
+    let chunk = match idle_timeout {
+        Some(idle_timeout) => tokio::time::timeout(idle_timeout, stream.next())
+            .await
+            .map_err(|_| {
+                RpcError::new(
+                    RpcErrorCode::DeadlineExceeded,
+                    format!("No request frame received for {:?}", idle_timeout),
+                )
+            })?,
+        None => stream.next().await,
+    };
+
+    chunk.transpose().map_err(|e| {
+        RpcError::new(
+            RpcErrorCode::InvalidArgument,
+            format!("Failed to read request body. {}", e),
+        )
+    })
+}
+
+/// The messages of a client-streaming request, decoded as their frames arrive, each at most
+/// `max_request_bytes`, and within the route's `max_stream_frames` and `max_stream_total_bytes`.
+/// The stream idle timeout applies between chunks.
+///
+/// A body verifier needs the whole body, so on routes with one it's read (up to
+/// `max_stream_total_bytes`) and verified before the handler runs, and the messages streamed from
+/// the buffer.
+pub(crate) async fn decode_request_stream<M>(
+    parts: &request::Parts,
+    body: Body,
+    binary: bool,
+) -> Result<RpcRequestStream<M>, Response>
+where
+    M: Message + RpcJsonDecode + Default + Send + 'static,
+{
+    let config = RpcConfig::from_parts(parts);
+    let idle_timeout = parts
+        .extensions
+        .get::<StreamIdleTimeout>()
+        .map(|timeout| timeout.0)
+        .or(config.stream_idle_timeout);
+
+    let options = parts.extensions.get::<RouteOptions>();
+    let (max_frames, max_total_bytes) = options.map_or(
+        (DEFAULT_MAX_STREAM_FRAMES, DEFAULT_MAX_STREAM_TOTAL_BYTES),
+        |options| (options.max_stream_frames, options.max_stream_total_bytes),
+    );
+    let verifies = options.is_some_and(|options| options.body_verifier.is_some());
+    let body = match verifies {
+        true => {
+            let bytes = read_body(
+                body,
+                idle_timeout,
+                Some(max_total_bytes),
+                preallocates(parts),
+            )
+            .await
+            .and_then(|bytes| verify_body(parts, &bytes).map(|_| bytes))
+            .map_err(|error| ResponseEncoder::error(error, true, binary).encode_response())?;
+            Body::from(bytes)
+        }
+        false => body,
+    };
+
+    let mut decoder = FrameDecoder::new()
+        .max_frames(max_frames)
+        .max_total_bytes(max_total_bytes);
+    if let Some(max_request_bytes) = config.max_request_bytes {
+        decoder = decoder.max_frame_bytes(max_request_bytes);
+    }
+    let frames = RequestFrames {
+        chunks: body.into_data_stream(),
+        decoder,
+        idle_timeout,
+        binary,
+        defaults: defaults::for_message::<M>(parts),
+        config,
+    };
+    // The first error ends the stream, dropping the body.
+    Ok(RpcRequestStream::new(stream::unfold(
+        Some(frames),
+        |frames| async move {
+            let mut frames = frames?;
+            let message = frames.next().await?;
+            let frames = message.is_ok().then_some(frames);
+            Some((message, frames))
+        },
+    )))
+}
+
+/// The state of a request stream, see [`decode_request_stream`].
+struct RequestFrames {
+    chunks: BodyDataStream,
+    decoder: FrameDecoder,
+    idle_timeout: Option<Duration>,
+    binary: bool,
+    defaults: Option<RequestDefaults>,
+    config: Arc<RpcConfig>,
+}
+
+impl RequestFrames {
+    async fn next<M>(&mut self) -> Option<RpcResult<M>>
+    where
+        M: Message + RpcJsonDecode + Default,
+    {
+        loop {
+            match self.decoder.next_frame() {
+                Ok(Some(frame)) => {
+                    let message = codec::decode_request_frame(&frame, self.binary, &self.config);
+                    return Some(match &self.defaults {
+                        Some(defaults) => message.and_then(|message| defaults.apply(message)),
+                        None => message,
+                    });
+                }
+                Ok(None) => {}
+                Err(error) => return Some(Err(error)),
+            }
+
+            match next_chunk(&mut self.chunks, self.idle_timeout).await {
+                Ok(Some(chunk)) => self.decoder.push(&chunk),
+                Ok(None) => return std::mem::take(&mut self.decoder).finish().err().map(Err),
+                Err(error) => return Some(Err(error)),
+            }
+        }
+    }
+}
+
+fn too_large(max_bytes: usize) -> RpcError {
+    RpcError::new(
+        RpcErrorCode::ResourceExhausted,
+        format!("Request body is larger than the {} byte limit", max_bytes),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(feature = "json")]
+    use std::collections::HashMap;
+    use std::{
+        sync::atomic::AtomicUsize,
+        task::{Context, Poll},
+    };
+
+    use axum::http::{HeaderValue, Request};
+    use http_body::{Frame, SizeHint};
+
+    use super::*;
+    use crate::{
+        codec::encode_stream_response,
+        config::RpcConfig,
+        logging::RpcCallStats,
+        prelude::*,
+        test_util::{
+            client, hello, message, peak_allocation, proto_request, say_hello, say_hello_stream,
+            server_stream, stream_request, unary, HelloRequest, HelloResponse, SAY_HELLO,
+            SAY_HELLO_STREAM,
+        },
+    };
+    #[cfg(feature = "stream-compression")]
+    use crate::{
+        codec::FrameDecoder,
+        router::{CompressionMode, RouteOptions},
+        test_util::messages,
+    };
+    #[cfg(feature = "json")]
+    use crate::{
+        error::RpcErrorDetail,
+        router::RpcMethod,
+        test_util::{stream_info, unary_info},
+    };
+
+    /// Whether each message of a [`say_hello_stream`] mounted with `options` was compressed, for a
+    /// client accepting gzip, checking that they decode either way.
+    #[cfg(feature = "stream-compression")]
+    async fn compressed_frames(options: RouteOptions) -> Vec<bool> {
+        let router = RpcRouter::new().rpc_with_options(options, |router: RpcRouter| {
+            router.rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+        });
+        let mut request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("connect-accept-encoding", HeaderValue::from_static("gzip"));
+        let response = client(router).send(request).await;
+        assert_eq!(messages::<HelloResponse>(&response).len(), 3);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&response.body);
+        let mut compressed = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            if !frame.is_end_stream() {
+                compressed.push(frame.is_compressed());
+            }
+        }
+        compressed
+    }
+
+    #[cfg(feature = "stream-compression")]
+    #[tokio::test]
+    async fn auto_compresses_over_the_threshold() {
+        let options = RouteOptions::new().stream_compression(1024);
+        assert_eq!(compressed_frames(options).await, [false; 3]);
+        let options = RouteOptions::new().stream_compression(0);
+        assert_eq!(compressed_frames(options).await, [true; 3]);
+    }
+
+    #[cfg(feature = "stream-compression")]
+    #[tokio::test]
+    async fn never_compresses_frames() {
+        let options = RouteOptions::new()
+            .stream_compression(0)
+            .compression(CompressionMode::Never);
+        assert_eq!(compressed_frames(options).await, [false; 3]);
+    }
+
+    #[cfg(feature = "stream-compression")]
+    #[tokio::test]
+    async fn force_compresses_every_frame() {
+        let options = RouteOptions::new().compression(CompressionMode::Force("gzip"));
+        assert_eq!(compressed_frames(options).await, [true; 3]);
+        let options = RouteOptions::new().compression(CompressionMode::Force("zstd"));
+        assert_eq!(compressed_frames(options).await, [false; 3]);
+    }
+
+    /// How content types were parsed before [`ContentType`]: lowercased, parameters dropped.
+    fn parse_as_strings(value: &HeaderValue) -> Option<&'static str> {
+        let media_type = value
+            .to_str()
+            .unwrap_or_default()
+            .to_lowercase()
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        [
+            "application/json",
+            "application/proto",
+            "application/connect+json",
+            "application/connect+proto",
+        ]
+        .into_iter()
+        .find(|content_type| *content_type == media_type)
+    }
+
+    #[test]
+    fn parses_content_types_like_the_string_parsing() {
+        let values = [
+            &b"application/json"[..],
+            b"application/proto",
+            b"application/connect+json",
+            b"application/connect+proto",
+            b"Application/JSON",
+            b"APPLICATION/CONNECT+PROTO",
+            b"application/json; charset=utf-8",
+            b"application/proto ;foo=bar",
+            b" application/json ",
+            b"application/connect+json;",
+            b"application/grpc",
+            b"application/jsonx",
+            b"application/json+connect",
+            b"text/plain; application/json",
+            b"",
+            b";",
+            b"application/\xc3\xa9json",
+            b"application/json\xff",
+        ];
+        for value in values {
+            let value = HeaderValue::from_bytes(value).unwrap();
+            assert_eq!(
+                ContentType::parse(value.as_bytes()).map(ContentType::as_str),
+                parse_as_strings(&value),
+                "{value:?}"
+            );
+        }
+    }
+
+    /// The chunks of `message` as a binary stream frame, and the frame encoded into one buffer.
+    fn chunks_and_frame(message: &HelloResponse) -> (Vec<Vec<u8>>, Vec<u8>) {
+        let mut encoder = EnvelopeEncoder::new(true, false, None);
+        let chunks = encoder.encode(message, &RpcCallStats::default()).unwrap();
+        let mut frame = vec![];
+        encode_stream_response(message, true, &mut frame).unwrap();
+        (chunks.map(|chunk| chunk.to_vec()).collect(), frame)
+    }
+
+    #[test]
+    fn splits_large_frames_into_their_prefix_and_payload() {
+        let small = HelloResponse {
+            message: "Hello!".to_string(),
+        };
+        let (chunks, frame) = chunks_and_frame(&small);
+        assert_eq!(chunks, [frame]);
+
+        let large = HelloResponse {
+            message: "a".repeat(VECTORED_FRAME_BYTES),
+        };
+        let (chunks, frame) = chunks_and_frame(&large);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].len(), 5);
+        assert_eq!(chunks.concat(), frame);
+    }
+
+    #[tokio::test]
+    async fn streams_large_frames_byte_for_byte() {
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream));
+        let name = "a".repeat(VECTORED_FRAME_BYTES);
+        let response = client(router)
+            .send(stream_request(SAY_HELLO_STREAM, &hello(&name)))
+            .await;
+
+        let mut expected = vec![];
+        for n in 1..=3 {
+            let message = HelloResponse {
+                message: format!("Hello {name} #{n}!"),
+            };
+            encode_stream_response(&message, true, &mut expected).unwrap();
+        }
+        assert!(response.body.starts_with(&expected));
+        let (frames, error) = response.frames();
+        assert_eq!(frames.len(), 3);
+        assert!(error.is_none(), "{error:?}");
+    }
+
+    /// A body sent in `chunks`, declaring its length like one with a `content-length` unless it's
+    /// `None`. Counts the chunks read from it in `read`.
+    fn body(chunks: Vec<Bytes>, declared: Option<u64>, read: Arc<AtomicUsize>) -> Body {
+        struct Chunks {
+            chunks: std::vec::IntoIter<Bytes>,
+            declared: Option<u64>,
+            read: Arc<AtomicUsize>,
+        }
+
+        impl http_body::Body for Chunks {
+            type Data = Bytes;
+            type Error = Infallible;
+
+            fn poll_frame(
+                mut self: Pin<&mut Self>,
+                _: &mut Context<'_>,
+            ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+                let chunk = self.chunks.next();
+                if chunk.is_some() {
+                    self.read.fetch_add(1, Ordering::SeqCst);
+                }
+                Poll::Ready(chunk.map(|chunk| Ok(Frame::data(chunk))))
+            }
+
+            fn size_hint(&self) -> SizeHint {
+                match self.declared {
+                    Some(declared) => SizeHint::with_exact(declared),
+                    None => SizeHint::new(),
+                }
+            }
+        }
+
+        Body::new(Chunks {
+            chunks: chunks.into_iter(),
+            declared,
+            read,
+        })
+    }
+
+    /// `bytes` as 64 KiB chunks sharing its allocation.
+    fn chunked(bytes: Vec<u8>) -> Vec<Bytes> {
+        let size = bytes.len();
+        let bytes = Bytes::from(bytes);
+        (0..size)
+            .step_by(64 * 1024)
+            .map(|at| bytes.slice(at..size.min(at + 64 * 1024)))
+            .collect()
+    }
+
+    const MIB: usize = 1024 * 1024;
+
+    #[tokio::test]
+    async fn reads_preallocated_bodies_into_one_buffer_of_their_length() {
+        let size = 8 * MIB;
+        let body = body(
+            chunked(vec![b'a'; size]),
+            Some(size as u64),
+            Default::default(),
+        );
+        let (bytes, peak) = peak_allocation(read_body(body, None, Some(16 * MIB), true)).await;
+        assert_eq!(bytes.unwrap().len(), size);
+        assert!(peak < size + 64 * 1024, "{peak} bytes at the peak");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn decodes_preallocated_json_without_an_intermediate_value() {
+        let name = "a".repeat(8 * MIB);
+        let json = serde_json::to_vec(&hello(&name)).unwrap();
+        let size = json.len();
+        let body = body(chunked(json), Some(size as u64), Default::default());
+        let ((), peak) = peak_allocation(async {
+            let bytes = read_body(body, None, Some(16 * MIB), true).await.unwrap();
+            let request: HelloRequest =
+                crate::codec::decode_unary_request(&bytes, false, &Default::default()).unwrap();
+            assert_eq!(request.name.len(), name.len());
+        })
+        .await;
+        // The body once, and the decoded message.
+        assert!(peak < 2 * size + 64 * 1024, "{peak} bytes at the peak");
+    }
+
+    #[tokio::test]
+    async fn rejects_bodies_declared_over_the_limit_before_reading_them() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let body = body(
+            chunked(vec![b'a'; 8 * MIB]),
+            Some(8 * MIB as u64),
+            read.clone(),
+        );
+        let error = read_body(body, None, Some(MIB), true).await.unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+        assert_eq!(read.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn stops_reading_undeclared_bodies_at_the_limit() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let body = body(chunked(vec![b'a'; 64 * MIB]), None, read.clone());
+        let (error, peak) = peak_allocation(read_body(body, None, Some(MIB), true)).await;
+        assert_eq!(error.unwrap_err().code, RpcErrorCode::ResourceExhausted);
+        // Up to the first chunk past the limit, not the 64 MiB sent. At the peak, the buffer is
+        // copied into one twice its size.
+        assert_eq!(read.load(Ordering::SeqCst), 17);
+        assert!(peak <= 3 * MIB, "{peak} bytes at the peak");
+    }
+
+    #[tokio::test]
+    async fn sends_unary_error_metadata_as_headers() {
+        async fn unavailable(_: HelloRequest) -> RpcResult<HelloResponse> {
+            let mut metadata = HeaderMap::new();
+            metadata.append("retry-after", HeaderValue::from_static("1"));
+            metadata.append("x-tried", HeaderValue::from_static("a"));
+            metadata.append("x-tried", HeaderValue::from_static("b"));
+            Err(RpcError::new(RpcErrorCode::Unavailable, "Busy".to_string())
+                .with_metadata(metadata))
+        }
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, unavailable)));
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(response.error().unwrap().code, RpcErrorCode::Unavailable);
+        assert_eq!(response.headers["retry-after"], "1");
+        let tried = response
+            .headers
+            .get_all("x-tried")
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(tried, ["a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_unary_calls_of_streaming_methods() {
+        let client = client(crate::test_util::hello_router());
+
+        let response = client
+            .send(proto_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(response.content_type(), Some("application/json"));
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                "This method is streaming, its Content-Type must be application/connect+json or \
+                 application/connect+proto, not application/proto"
+                    .to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_streaming_calls_of_unary_methods() {
+        let client = client(crate::test_util::hello_router());
+
+        let response = client.send(stream_request(SAY_HELLO, &hello("Ada"))).await;
+
+        // In the framing the client expects, an end-of-stream error.
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type(), Some("application/connect+proto"));
+        assert_eq!(
+            response.frames(),
+            (
+                vec![],
+                Some(RpcError::new(
+                    RpcErrorCode::InvalidArgument,
+                    "This method is unary, its Content-Type must be application/json or \
+                     application/proto, not application/connect+proto"
+                        .to_string()
+                ))
+            )
+        );
+    }
+
+    fn hello_with_config(config: RpcConfig) -> crate::testing::TestClient {
+        client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, say_hello))
+                .with_config(config),
+        )
+    }
+
+    fn json_as_proto() -> Request<Body> {
+        Request::post(SAY_HELLO)
+            .header("content-type", "application/proto")
+            .body(Body::from(r#" {"name": "Ada"}"#))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_json_sent_as_binary_protobuf() {
+        let client = hello_with_config(Default::default());
+
+        let error = client.send(json_as_proto()).await.error().unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(
+            error.message,
+            "The body is JSON, but the Content-Type is application/proto. Send JSON as application/json"
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_json_sent_as_binary_protobuf_when_not_detecting_mismatches() {
+        let client = hello_with_config(RpcConfig::default().detect_codec_mismatch(false));
+
+        let response = client.send(json_as_proto()).await;
+
+        if let Some(error) = response.error() {
+            assert!(!error.message.contains("Content-Type"), "{}", error.message);
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_binary_messages_that_look_like_json() {
+        // A 123 byte name encodes as `\n{` and then the name, whitespace and a `{`.
+        let name = "A".repeat(b'{' as usize);
+        let request = proto_request(SAY_HELLO, &hello(&name));
+        let client = hello_with_config(Default::default());
+
+        let response = client.send(request).await;
+
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            format!("Hello {name}!")
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn reports_json_bodies_that_arent_utf_8() {
+        let client = hello_with_config(Default::default());
+        let request = Request::post(SAY_HELLO)
+            .header("content-type", "application/json")
+            .body(Body::from(vec![0x0a, 0x03, 0xff, 0xfe, 0xfd]))
+            .unwrap();
+
+        let error = client.send(request).await.error().unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(
+            error.message,
+            "The body isn't valid UTF-8, so it can't be JSON. Send binary protobuf as application/proto"
+        );
+    }
+
+    /// Serves `router` over HTTP `version` only, like a server without ALPN or h2c upgrades would.
+    async fn serve_only(version: Version, router: RpcRouter) -> std::net::SocketAddr {
+        use hyper_util::{
+            rt::{TokioExecutor, TokioIo},
+            server::conn::auto,
+        };
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router.into_service();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let builder = auto::Builder::new(TokioExecutor::new());
+                let builder = match version {
+                    Version::HTTP_2 => builder.http2_only(),
+                    _ => builder.http1_only(),
+                };
+                let _ = builder
+                    .serve_connection(TokioIo::new(stream), service.clone())
+                    .await;
+            }
+        });
+        address
+    }
+
+    /// Sends `request` over a new HTTP `version` connection to `address`.
+    async fn send_over(
+        version: Version,
+        address: std::net::SocketAddr,
+        mut request: Request<Body>,
+    ) -> crate::testing::TestResponse {
+        use hyper::client::conn::{http1, http2};
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        let stream = TokioIo::new(tokio::net::TcpStream::connect(address).await.unwrap());
+        *request.version_mut() = version;
+        let response = match version {
+            Version::HTTP_2 => {
+                // HTTP/2 has the authority in the `:authority` pseudo-header, from the URI.
+                *request.uri_mut() = format!("http://{address}{}", request.uri())
+                    .parse()
+                    .unwrap();
+                let (mut sender, connection) = http2::handshake(TokioExecutor::new(), stream)
+                    .await
+                    .unwrap();
+                tokio::spawn(connection);
+                sender.send_request(request).await.unwrap()
+            }
+            _ => {
+                request
+                    .headers_mut()
+                    .insert(header::HOST, HeaderValue::from_static("localhost"));
+                let (mut sender, connection) = http1::handshake(stream).await.unwrap();
+                tokio::spawn(connection);
+                sender.send_request(request).await.unwrap()
+            }
+        };
+        assert_eq!(response.version(), version);
+
+        let (parts, body) = response.into_parts();
+        crate::testing::TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: axum::body::to_bytes(Body::new(body), usize::MAX)
+                .await
+                .unwrap(),
+        }
+    }
+
+    fn hello_rpcs() -> RpcRouter {
+        RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, say_hello))
+            .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+    }
+
+    #[tokio::test]
+    async fn serves_connect_over_http_1_and_2() {
+        for version in [Version::HTTP_11, Version::HTTP_2] {
+            let address = serve_only(version, hello_rpcs()).await;
+
+            let response =
+                send_over(version, address, proto_request(SAY_HELLO, &hello("Ada"))).await;
+            assert_eq!(
+                message::<HelloResponse>(&response).message,
+                "Hello Ada!",
+                "{version:?}"
+            );
+
+            let request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+            let response = send_over(version, address, request).await;
+            assert_eq!(
+                crate::test_util::messages::<HelloResponse>(&response).len(),
+                3,
+                "{version:?}"
+            );
+            // Streams have no length up front, so HTTP/1.1 chunks them.
+            if version == Version::HTTP_11 {
+                assert_eq!(response.headers[header::TRANSFER_ENCODING], "chunked");
+            }
+        }
+    }
+
+    fn grpc_request() -> Request<Body> {
+        Request::post(SAY_HELLO)
+            .header(header::CONTENT_TYPE, "application/grpc+proto")
+            .header("te", "trailers")
+            .body(Body::from(vec![0, 0, 0, 0, 0]))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_grpc_over_http_1_with_505() {
+        let address = serve_only(Version::HTTP_11, hello_rpcs()).await;
+
+        let response = send_over(Version::HTTP_11, address, grpc_request()).await;
+
+        assert_eq!(response.status, StatusCode::HTTP_VERSION_NOT_SUPPORTED);
+        assert_eq!(
+            response.body,
+            "gRPC requires HTTP/2, this request used HTTP/1.1. Use the Connect protocol \
+             (application/json or application/proto) over HTTP/1.1"
+                .as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn answers_grpc_over_http_2_with_unimplemented() {
+        let address = serve_only(Version::HTTP_2, hello_rpcs()).await;
+
+        let response = send_over(Version::HTTP_2, address, grpc_request()).await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers[header::CONTENT_TYPE], "application/grpc");
+        assert_eq!(response.headers["grpc-status"], "12");
+        assert_eq!(
+            response.headers["grpc-message"],
+            "gRPC isn't supported, use the Connect protocol instead"
+        );
+    }
+
+    /// Which of the cells of the response codec matrix a call is.
+    #[cfg(feature = "json")]
+    #[derive(Clone, Copy, Debug)]
+    struct Cell {
+        binary: bool,
+        ok: bool,
+        streaming: bool,
+    }
+
+    /// Every combination of request codec, success or error, and unary or streaming.
+    #[cfg(feature = "json")]
+    fn cells() -> Vec<Cell> {
+        let mut cells = vec![];
+        for binary in [true, false] {
+            for ok in [true, false] {
+                for streaming in [false, true] {
+                    cells.push(Cell {
+                        binary,
+                        ok,
+                        streaming,
+                    });
+                }
+            }
+        }
+        cells
+    }
+
+    #[cfg(feature = "json")]
+    async fn call_cell(cell: Cell) -> crate::testing::TestResponse {
+        async fn not_found(_: HelloRequest) -> RpcResult<HelloResponse> {
+            Err(RpcError::new(RpcErrorCode::NotFound, "Nobody".to_string()))
+        }
+        async fn not_found_stream(
+            _: HelloRequest,
+        ) -> impl futures::Stream<Item = RpcResult<HelloResponse>> {
+            futures::stream::iter([Err(RpcError::new(
+                RpcErrorCode::NotFound,
+                "Nobody".to_string(),
+            ))])
+        }
+
+        let router = match (cell.ok, cell.streaming) {
+            (true, false) => RpcRouter::new().rpc_method(unary(SAY_HELLO, say_hello)),
+            (false, false) => RpcRouter::new().rpc_method(unary(SAY_HELLO, not_found)),
+            (true, true) => RpcRouter::new().rpc_method(server_stream(SAY_HELLO, say_hello_stream)),
+            (false, true) => {
+                RpcRouter::new().rpc_method(server_stream(SAY_HELLO, not_found_stream))
+            }
+        };
+        let request = hello("Ada");
+        let body = match cell.binary {
+            true => request.encode_to_vec(),
+            false => serde_json::to_vec(&request).unwrap(),
+        };
+        let content_type = match (cell.streaming, cell.binary) {
+            (false, true) => ContentType::Proto,
+            (false, false) => ContentType::Json,
+            (true, true) => ContentType::ConnectProto,
+            (true, false) => ContentType::ConnectJson,
+        };
+        let body = match cell.streaming {
+            true => {
+                let mut frame = vec![];
+                crate::codec::encode_envelope(0, &body, &mut frame);
+                frame
+            }
+            false => body,
+        };
+        client(router)
+            .post(SAY_HELLO, content_type.as_str(), body)
+            .await
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn responses_mirror_the_request_codec() {
+        for cell in cells() {
+            let response = call_cell(cell).await;
+            let context = format!("{cell:?}");
+            let first = HelloResponse {
+                message: match cell.streaming {
+                    true => "Hello Ada #1!".to_string(),
+                    false => "Hello Ada!".to_string(),
+                },
+            };
+
+            if !cell.streaming {
+                // Errors are always JSON, with their own status.
+                let (status, content_type) = match (cell.ok, cell.binary) {
+                    (true, true) => (StatusCode::OK, "application/proto"),
+                    (true, false) => (StatusCode::OK, "application/json"),
+                    (false, _) => (StatusCode::NOT_FOUND, "application/json"),
+                };
+                assert_eq!(response.status, status, "{context}");
+                assert_eq!(response.content_type(), Some(content_type), "{context}");
+                match (cell.ok, cell.binary) {
+                    (true, true) => assert_eq!(
+                        HelloResponse::decode(response.body.as_ref()).unwrap(),
+                        first,
+                        "{context}"
+                    ),
+                    (true, false) => assert_eq!(
+                        serde_json::from_slice::<HelloResponse>(&response.body).unwrap(),
+                        first,
+                        "{context}"
+                    ),
+                    (false, _) => assert_eq!(
+                        response.error().unwrap().code,
+                        RpcErrorCode::NotFound,
+                        "{context}"
+                    ),
+                }
+                continue;
+            }
+
+            // Streams are always 200 in the request's codec, their end frame is always JSON.
+            let content_type = match cell.binary {
+                true => "application/connect+proto",
+                false => "application/connect+json",
+            };
+            assert_eq!(response.status, StatusCode::OK, "{context}");
+            assert_eq!(response.content_type(), Some(content_type), "{context}");
+            let (frames, error) = response.frames();
+            if cell.ok {
+                assert_eq!(error, None, "{context}");
+                assert_eq!(frames.len(), 3, "{context}");
+                let decoded = match cell.binary {
+                    true => HelloResponse::decode(frames[0].as_slice()).unwrap(),
+                    false => serde_json::from_slice(&frames[0]).unwrap(),
+                };
+                assert_eq!(decoded, first, "{context}");
+            } else {
+                assert!(frames.is_empty(), "{context}");
+                assert_eq!(error.unwrap().code, RpcErrorCode::NotFound, "{context}");
+            }
+        }
+    }
+
+    /// A message whose JSON encoding always fails.
+    #[cfg(feature = "json")]
+    #[derive(Clone, PartialEq, Message)]
+    struct Unencodable {
+        #[prost(string, tag = "1")]
+        message: String,
+    }
+
+    #[cfg(feature = "json")]
+    impl serde::Serialize for Unencodable {
+        fn serialize<S: serde::Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("no JSON form"))
+        }
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn fails_responses_that_dont_encode_instead_of_switching_codec() {
+        async fn unencodable(request: HelloRequest) -> RpcResult<Unencodable> {
+            Ok(Unencodable {
+                message: request.name,
+            })
+        }
+        let router = RpcRouter::new().rpc_method(crate::router::RpcMethod::unary(
+            crate::test_util::unary_info(SAY_HELLO),
+            unencodable,
+        ));
+        let client = client(router);
+
+        let response = client
+            .post(SAY_HELLO, "application/json", br#"{"name":"Ada"}"#.to_vec())
+            .await;
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.content_type(), Some("application/json"));
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::Internal,
+                "Failed to serialize response: no JSON form".to_string()
+            ))
+        );
+
+        // Binary protobuf has nothing to fail on.
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(response.content_type(), Some("application/proto"));
+        assert_eq!(message::<Unencodable>(&response).message, "Ada");
+    }
+
+    /// A GET of [`SAY_HELLO`] with `message` in the query, as base64 binary protobuf.
+    fn get_uri(message: &[u8]) -> String {
+        use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+
+        format!(
+            "{SAY_HELLO}?connect=v1&encoding=proto&base64=1&message={}",
+            URL_SAFE.encode(message)
+        )
+    }
+
+    /// Calls `uri` on a [`say_hello`] served over GET, with URLs of up to `max` bytes.
+    async fn get_with_max(uri: &str, max: usize) -> crate::testing::TestResponse {
+        use crate::router::{RpcIdempotencyLevel, RpcMethod, RpcMethodInfo, RpcMethodKind};
+        use axum::http::Method;
+
+        let info = RpcMethodInfo::new(
+            "hello.HelloWorldService",
+            "SayHello",
+            RpcMethodKind::Unary,
+            RpcIdempotencyLevel::NoSideEffects,
+        )
+        .with_http_methods(vec![Method::POST, Method::GET]);
+        let router = RpcRouter::new()
+            .rpc_method(RpcMethod::unary(info, say_hello))
+            .with_config(RpcConfig::default().max_get_url_bytes(max));
+        let request = Request::get(uri).body(Body::empty()).unwrap();
+        client(router).send(request).await
+    }
+
+    #[tokio::test]
+    async fn serves_get_urls_right_at_the_limit() {
+        let uri = get_uri(&hello("Ada").encode_to_vec());
+        let response = get_with_max(&uri, uri.len()).await;
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+    }
+
+    #[tokio::test]
+    async fn rejects_get_urls_over_the_limit() {
+        let uri = get_uri(&hello("Ada").encode_to_vec());
+        let response = get_with_max(&uri, uri.len() - 1).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!(
+                    "The URL is {} bytes, over the limit of {} for GET requests, send the \
+                     request with POST instead",
+                    uri.len(),
+                    uri.len() - 1
+                )
+            ))
+        );
+
+        // The limit is checked first, a message that isn't even base64 fails the same way.
+        let uri = format!("{SAY_HELLO}?connect=v1&encoding=proto&base64=1&message=!!!");
+        let response = get_with_max(&uri, uri.len() - 1).await;
+        assert!(
+            response.error().unwrap().message.starts_with("The URL is "),
+            "{:?}",
+            response.error()
+        );
+        let response = get_with_max(&uri, uri.len()).await;
+        assert!(
+            response
+                .error()
+                .unwrap()
+                .message
+                .starts_with("Wrong query.message"),
+            "{:?}",
+            response.error()
+        );
+    }
+
+    /// Reads an HTTP/1.1 response head off `stream`, up to and including the blank line.
+    async fn read_head(stream: &mut tokio::net::TcpStream) -> String {
+        use tokio::io::AsyncReadExt;
+
+        let mut head = vec![];
+        while !head.ends_with(b"\r\n\r\n") {
+            head.push(stream.read_u8().await.unwrap());
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    /// Starts a POST of [`SAY_HELLO`] with a body of `length` bytes over raw HTTP/1.1, sending
+    /// `Expect: 100-continue` and then only the headers, to a server taking up to 64 bytes.
+    async fn expect_continue(length: usize) -> tokio::net::TcpStream {
+        use tokio::io::AsyncWriteExt;
+
+        let router = hello_rpcs().with_config(RpcConfig::default().max_request_bytes(64));
+        let address = serve_only(Version::HTTP_11, router).await;
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let head = format!(
+            "POST {SAY_HELLO} HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/proto\r\n\
+             content-length: {length}\r\nexpect: 100-continue\r\n\r\n"
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn continues_uploads_within_the_limit() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let body = hello("Ada").encode_to_vec();
+        let mut stream = expect_continue(body.len()).await;
+        assert_eq!(
+            read_head(&mut stream).await,
+            "HTTP/1.1 100 Continue\r\n\r\n"
+        );
+
+        stream.write_all(&body).await.unwrap();
+        let head = read_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut response = vec![0; length];
+        stream.read_exact(&mut response).await.unwrap();
+        assert_eq!(
+            HelloResponse::decode(response.as_slice()).unwrap().message,
+            "Hello Ada!"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_uploads_before_they_are_sent() {
+        use tokio::io::AsyncReadExt;
+
+        let mut stream = expect_continue(1024).await;
+        // Without a `100 Continue`, the final response, before a byte of the body was sent.
+        let head = read_head(&mut stream).await;
+        assert!(
+            head.starts_with("HTTP/1.1 429 Too Many Requests\r\n"),
+            "{head}"
+        );
+        assert!(
+            head.contains("content-type: application/json\r\n"),
+            "{head}"
+        );
+
+        let mut body = vec![];
+        stream.read_to_end(&mut body).await.unwrap();
+        let error = codec::parse_unary_error(&body).unwrap();
+        assert_eq!(
+            error,
+            RpcError::new(
+                RpcErrorCode::ResourceExhausted,
+                "Request body is larger than the 64 byte limit".to_string()
+            )
+        );
+    }
+
+    /// A [`say_hello`] call with `x-tenant: acme`, 12 bytes of metadata, and reserved headers
+    /// longer than any of the limits.
+    fn tenant_request() -> Request<Body> {
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        let headers = request.headers_mut();
+        headers.insert("X-Tenant", HeaderValue::from_static("acme"));
+        headers.insert("connect-timeout-ms", HeaderValue::from_static("100000"));
+        headers.insert("user-agent", HeaderValue::from_static("connect-es/1.4.0"));
+        request
+    }
+
+    #[tokio::test]
+    async fn serves_metadata_right_at_the_limits() {
+        let config = RpcConfig::default()
+            .max_metadata_bytes(12)
+            .max_metadata_value_bytes(4);
+
+        let response = hello_with_config(config).send(tenant_request()).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+    }
+
+    #[tokio::test]
+    async fn rejects_metadata_over_the_limits() {
+        let response = hello_with_config(RpcConfig::default().max_metadata_bytes(11))
+            .send(tenant_request())
+            .await;
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::ResourceExhausted,
+                "The metadata is 12 bytes, over the limit of 11".to_string()
+            ))
+        );
+
+        let response = hello_with_config(RpcConfig::default().max_metadata_value_bytes(3))
+            .send(tenant_request())
+            .await;
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::ResourceExhausted,
+                "The value of the metadata x-tenant is 4 bytes, over the limit of 3".to_string()
+            ))
+        );
+
+        // Streaming calls are checked the same way.
+        let router = RpcRouter::new()
+            .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+            .with_config(RpcConfig::default().max_metadata_bytes(11));
+        let mut request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("x-tenant", HeaderValue::from_static("acme"));
+        let (frames, error) = client(router).send(request).await.frames();
+        assert!(frames.is_empty());
+        assert_eq!(error.unwrap().code, RpcErrorCode::ResourceExhausted);
+    }
+
+    #[tokio::test]
+    async fn hands_handlers_lowercase_metadata_keys() {
+        async fn keys(metadata: RpcMetadata, _: HelloRequest) -> RpcResult<HelloResponse> {
+            let mut keys = metadata
+                .0
+                .keys()
+                .map(|key| key.as_str())
+                .filter(|key| !RpcMetadata::is_reserved(key))
+                .collect::<Vec<_>>();
+            keys.sort();
+            Ok(HelloResponse {
+                message: keys.join(" "),
+            })
+        }
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, keys))
+            .with_config(RpcConfig::default().max_metadata_bytes(12));
+
+        let response = client(router).send(tenant_request()).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "x-tenant");
+    }
+
+    fn adapting() -> crate::testing::TestClient {
+        hello_with_config(RpcConfig::default().adapt_streaming_unary(true))
+    }
+
+    #[tokio::test]
+    async fn adapts_unary_calls_of_a_single_frame() {
+        let (recorder, _guard) = crate::test_util::traced();
+        let mut request = stream_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert(header::USER_AGENT, HeaderValue::from_static("legacy/1.0"));
+
+        let response = adapting().send(request).await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type(), Some("application/connect+proto"));
+        assert_eq!(
+            crate::test_util::messages::<HelloResponse>(&response),
+            [HelloResponse {
+                message: "Hello Ada!".to_string()
+            }]
+        );
+        let events = recorder.events("axum_connect::codec");
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].field("user_agent"), Some("legacy/1.0"));
+        assert_eq!(events[0].field("path"), Some(SAY_HELLO));
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn adapts_json_unary_calls_into_json_frames() {
+        let mut body = vec![];
+        codec::encode_envelope(0, br#"{"name":"Ada"}"#, &mut body);
+        let request = Request::post(SAY_HELLO)
+            .header(header::CONTENT_TYPE, "application/connect+json")
+            .body(Body::from(body))
+            .unwrap();
+        let response = adapting().send(request).await;
+        assert_eq!(response.content_type(), Some("application/connect+json"));
+        assert_eq!(
+            response.frames(),
+            (vec![br#"{"message":"Hello Ada!"}"#.to_vec()], None)
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_adapted_unary_calls_of_more_or_less_than_a_frame() {
+        for (messages, count) in [(vec![hello("Ada"), hello("Grace")], 2), (vec![], 0)] {
+            let request = Request::post(SAY_HELLO)
+                .header(header::CONTENT_TYPE, "application/connect+proto")
+                .body(Body::from(crate::test_util::frames(&messages)))
+                .unwrap();
+
+            let response = adapting().send(request).await;
+
+            assert_eq!(response.content_type(), Some("application/connect+proto"));
+            assert_eq!(
+                response.frames(),
+                (
+                    vec![],
+                    Some(RpcError::new(
+                        RpcErrorCode::InvalidArgument,
+                        format!(
+                            "This method is unary, it takes a single request message, not {count}"
+                        )
+                    ))
+                )
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn doesnt_adapt_unary_calls_by_default() {
+        let (recorder, _guard) = crate::test_util::traced();
+
+        let response = hello_with_config(RpcConfig::default())
+            .send(stream_request(SAY_HELLO, &hello("Ada")))
+            .await;
+
+        let (frames, error) = response.frames();
+        assert!(frames.is_empty());
+        assert!(error
+            .unwrap()
+            .message
+            .starts_with("This method is unary, its Content-Type must be"),);
+        assert!(recorder.events("axum_connect::codec").is_empty());
+    }
+
+    const FORGET: &str = "/hello.HelloWorldService/Forget";
+
+    async fn forget(_: HelloRequest) -> RpcResult<pbjson_types::Empty> {
+        Ok(pbjson_types::Empty {})
+    }
+
+    /// [`forget`] and [`say_hello`], with or without `empty_response_body`.
+    fn forgetful(empty_response_body: bool) -> crate::testing::TestClient {
+        use crate::{router::RpcMethod, test_util::unary_info};
+
+        client(
+            RpcRouter::new()
+                .rpc_method(RpcMethod::unary(unary_info(FORGET), forget))
+                .rpc_method(unary(SAY_HELLO, say_hello))
+                .with_config(RpcConfig::default().empty_response_body(empty_response_body)),
+        )
+    }
+
+    fn assert_body(response: &crate::testing::TestResponse, content_type: &str, body: &[u8]) {
+        assert_eq!(response.status, StatusCode::OK, "{:?}", response.error());
+        assert_eq!(response.content_type(), Some(content_type));
+        assert_eq!(
+            response.headers[header::CONTENT_LENGTH],
+            body.len().to_string()
+        );
+        assert_eq!(response.body, body);
+    }
+
+    #[tokio::test]
+    async fn sends_empty_messages_as_empty_binary_bodies_either_way() {
+        for empty_response_body in [false, true] {
+            let response = forgetful(empty_response_body)
+                .send(proto_request(FORGET, &hello("Ada")))
+                .await;
+            assert_body(&response, "application/proto", b"");
+        }
+    }
+
+    #[cfg(feature = "json")]
+    fn json_request(path: &str) -> Request<Body> {
+        Request::post(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"Ada"}"#))
+            .unwrap()
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn sends_empty_json_bodies_for_empty_messages_when_enabled() {
+        let response = forgetful(true).send(json_request(FORGET)).await;
+        assert_body(&response, "application/json", b"");
+
+        // Other messages are sent as they are.
+        let response = forgetful(true).send(json_request(SAY_HELLO)).await;
+        assert_body(
+            &response,
+            "application/json",
+            br#"{"message":"Hello Ada!"}"#,
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn sends_empty_messages_as_json_objects_by_default() {
+        let response = forgetful(false).send(json_request(FORGET)).await;
+        assert_body(&response, "application/json", b"{}");
+    }
+
+    #[cfg(feature = "json")]
+    #[derive(Clone, PartialEq, prost::Message, serde::Serialize)]
+    struct Inventory {
+        #[prost(string, tag = "1")]
+        zone: String,
+        #[prost(map = "string, int64", tag = "2")]
+        counts: HashMap<String, i64>,
+        #[prost(message, optional, tag = "3")]
+        owner: Option<Owner>,
+    }
+
+    #[cfg(feature = "json")]
+    #[derive(Clone, PartialEq, prost::Message, serde::Serialize)]
+    struct Owner {
+        #[prost(string, tag = "1")]
+        name: String,
+        #[prost(map = "string, string", tag = "2")]
+        labels: HashMap<String, String>,
+    }
+
+    /// An inventory with maps at two depths, each built afresh so its iteration order varies
+    /// from one call to the next.
+    #[cfg(feature = "json")]
+    fn inventory(name: &str) -> Inventory {
+        Inventory {
+            zone: "eu".to_string(),
+            counts: (0..16).map(|n| (format!("item-{n:02}"), n)).collect(),
+            owner: Some(Owner {
+                name: name.to_string(),
+                labels: (0..16)
+                    .map(|n| (format!("k{n:02}"), n.to_string()))
+                    .collect(),
+            }),
+        }
+    }
+
+    /// [`inventory`] fails for `boom`, with a detail.
+    #[cfg(feature = "json")]
+    async fn get_inventory(request: HelloRequest) -> RpcResult<Inventory> {
+        match request.name.as_str() {
+            "boom" => {
+                let mut error = RpcError::new(RpcErrorCode::Aborted, "Out of stock".to_string());
+                error
+                    .details
+                    .push(RpcErrorDetail::new("hello.HelloRequest", &request));
+                Err(error)
+            }
+            name => Ok(inventory(name)),
+        }
+    }
+
+    #[cfg(feature = "json")]
+    async fn watch_inventory(request: HelloRequest) -> impl Stream<Item = RpcResult<Inventory>> {
+        stream::iter([Ok(inventory(&request.name)), Ok(inventory(&request.name))])
+    }
+
+    #[cfg(feature = "json")]
+    fn inventory_router(stable: bool) -> RpcRouter {
+        RpcRouter::new()
+            .rpc_method(RpcMethod::unary(unary_info(SAY_HELLO), get_inventory))
+            .rpc_method(RpcMethod::server_streaming(
+                stream_info(SAY_HELLO_STREAM),
+                watch_inventory,
+            ))
+            .with_config(RpcConfig::default().stable_json_field_order(stable))
+    }
+
+    #[cfg(feature = "json")]
+    fn named_json_request(path: &str, name: &str) -> Request<Body> {
+        Request::post(path)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(format!(r#"{{"name":"{name}"}}"#)))
+            .unwrap()
+    }
+
+    #[cfg(feature = "json")]
+    fn json_stream_request(path: &str, name: &str) -> Request<Body> {
+        let json = format!(r#"{{"name":"{name}"}}"#);
+        let mut body = vec![0];
+        body.extend((json.len() as u32).to_be_bytes());
+        body.extend(json.as_bytes());
+        Request::post(path)
+            .header(header::CONTENT_TYPE, "application/connect+json")
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn serializes_byte_identical_unary_responses_when_stable() {
+        let client = client(inventory_router(true));
+
+        let first = client.send(named_json_request(SAY_HELLO, "Ada")).await;
+        assert_eq!(first.error(), None);
+        for _ in 0..8 {
+            let response = client.send(named_json_request(SAY_HELLO, "Ada")).await;
+            assert_eq!(response.body, first.body);
+        }
+        let text = std::str::from_utf8(&first.body).unwrap();
+        assert!(
+            text.starts_with(r#"{"counts":{"item-00":0,"item-01":1,"#),
+            "{text}"
+        );
+        assert!(
+            text.ends_with(r#""k15":"15"},"name":"Ada"},"zone":"eu"}"#),
+            "{text}"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn keeps_the_serializer_order_by_default() {
+        let client = client(inventory_router(false));
+
+        let response = client.send(named_json_request(SAY_HELLO, "Ada")).await;
+        let text = std::str::from_utf8(&response.body).unwrap();
+        // Fields in declaration order, not sorted.
+        assert!(text.starts_with(r#"{"zone":"eu","counts":{"#), "{text}");
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn serializes_byte_identical_frames_when_stable() {
+        let client = client(inventory_router(true));
+
+        let (first, error) = client
+            .send(json_stream_request(SAY_HELLO_STREAM, "Ada"))
+            .await
+            .frames();
+        assert_eq!(error, None);
+        assert_eq!(first.len(), 2);
+        assert_eq!(first[0], first[1]);
+        for _ in 0..8 {
+            let (frames, _) = client
+                .send(json_stream_request(SAY_HELLO_STREAM, "Ada"))
+                .await
+                .frames();
+            assert_eq!(frames, first);
+        }
+        let text = std::str::from_utf8(&first[0]).unwrap();
+        assert!(
+            text.starts_with(r#"{"counts":{"item-00":0,"item-01":1,"#),
+            "{text}"
+        );
+        assert!(
+            text.contains(r#""owner":{"labels":{"k00":"0","k01":"1","#),
+            "{text}"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn sorts_the_keys_of_error_bodies_when_stable() {
+        let detail =
+            serde_json::to_string(&RpcErrorDetail::new("hello.HelloRequest", &hello("boom")))
+                .unwrap();
+
+        let stable = client(inventory_router(true))
+            .send(named_json_request(SAY_HELLO, "boom"))
+            .await;
+        assert_eq!(
+            std::str::from_utf8(&stable.body).unwrap(),
+            format!(r#"{{"code":"aborted","details":[{detail}],"message":"Out of stock"}}"#)
+        );
+
+        let default = client(inventory_router(false))
+            .send(named_json_request(SAY_HELLO, "boom"))
+            .await;
+        assert_eq!(
+            std::str::from_utf8(&default.body).unwrap(),
+            format!(r#"{{"code":"aborted","message":"Out of stock","details":[{detail}]}}"#)
+        );
     }
 }
@@ -0,0 +1,434 @@
+use std::future::ready;
+use std::pin::Pin;
+
+use axum::body::Body;
+use axum::http::Request;
+use axum::response::Response;
+use futures::{stream, Future};
+use prost::Message;
+
+use crate::auth::authorize;
+use crate::concurrency;
+use crate::config::RpcConfig;
+use crate::deadline;
+use crate::hooks::ResponseHooks;
+use crate::parts::{RpcFromRequestParts, RpcResponseHeaders, RpcTrailers};
+use crate::request::RpcRequestStream;
+use crate::response::RpcIntoResponse;
+use crate::router::check_enabled;
+
+use super::codec::{
+    decode_check_headers, decode_request_stream, ReqResInto, ResponseCompression, ResponseEncoder,
+    RpcJsonDecode, RpcJsonEncode,
+};
+
+/// A client-streaming handler, taking its extractors and then an [`RpcRequestStream`] of the
+/// request messages, and answering with a single message.
+///
+/// The handler runs as soon as the headers are in, and reads the messages as they arrive. Its
+/// response is sent once it returns, whether or not the client is done sending: a handler can fail
+/// on a bad first message (or succeed after the few it needed) without reading the rest, which is
+/// dropped with the stream, see [`RpcRequestStream`].
+pub trait RpcHandlerClientStream<TMReq, TMRes, TUid, TState>:
+    Clone + Send + Sync + Sized + 'static
+{
+    type Future: Future<Output = Response> + Send + 'static;
+
+    fn call(self, req: Request<Body>, state: TState) -> Self::Future;
+}
+
+macro_rules! impl_handler {
+    (
+        [$($ty:ident),*]
+    ) => {
+        #[allow(unused_parens, non_snake_case, unused_mut, unused_variables)]
+        impl<TMReq, TMRes, TInto, TFnFut, TFn, TState, $($ty,)*>
+            RpcHandlerClientStream<TMReq, TMRes, ($($ty,)* TMReq), TState> for TFn
+        where
+            TMReq: Message + RpcJsonDecode + Default + Send + 'static,
+            TMRes: Message + RpcJsonEncode + Send + 'static,
+            TInto: RpcIntoResponse<TMRes>,
+            TFnFut: Future<Output = TInto> + Send,
+            TFn: FnOnce($($ty,)* RpcRequestStream<TMReq>) -> TFnFut + Clone + Send + Sync + 'static,
+            TState: Send + Sync + 'static,
+            $( $ty: RpcFromRequestParts<TMRes, TState> + Send, )*
+        {
+            type Future = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+            fn call(self, req: Request<Body>, state: TState) -> Self::Future {
+                Box::pin(async move {
+                    let (mut parts, body) = req.into_parts();
+
+                    let ReqResInto { binary, .. } = match decode_check_headers(&mut parts, true) {
+                        Ok(binary) => binary,
+                        Err(e) => return e,
+                    };
+
+                    let state = &state;
+                    let config = RpcConfig::from_parts(&parts);
+                    let hooks = ResponseHooks::from_parts(&parts);
+
+                    if let Err(error) = check_enabled(&parts) {
+                        return ResponseEncoder::error(error, true, binary).encode_response();
+                    }
+
+                    let deadline = match deadline::start(&mut parts, &config) {
+                        Ok(deadline) => deadline,
+                        Err(error) => return ResponseEncoder::error(error, true, binary).encode_response(),
+                    };
+
+                    if let Err(error) = authorize(&mut parts).await {
+                        return ResponseEncoder::error(error, true, binary).encode_response();
+                    }
+
+                    let _permit = match concurrency::acquire(&parts).await {
+                        Ok(permit) => permit,
+                        Err(error) => return ResponseEncoder::error(error, true, binary).encode_response(),
+                    };
+
+                    let trailers = RpcTrailers::default();
+                    parts.extensions.insert(trailers.clone());
+                    let headers = RpcResponseHeaders::default();
+                    parts.extensions.insert(headers.clone());
+                    let compression = ResponseCompression::new(&parts, true, None);
+                    parts.extensions.insert(compression.clone());
+                    let strict_reserved_headers = config.strict_reserved_headers;
+                    let stable_json_field_order = config.stable_json_field_order;
+
+                    run_extractors!(parts, state, config, true, binary; $($ty),*);
+
+                    let requests = match decode_request_stream(&parts, body, binary).await {
+                        Ok(requests) => requests,
+                        Err(e) => return e,
+                    };
+
+                    // The request stream is dropped with the handler's future, so whatever the
+                    // client has yet to send isn't waited for.
+                    let response = deadline::run(deadline, trailers.clone().scope(self($($ty,)* requests)))
+                        .await
+                        .and_then(RpcIntoResponse::rpc_into_response)
+                        .map(|mut message| {
+                            hooks.apply(&mut message);
+                            message
+                        })
+                        .map_err(|e| config.redact(e));
+                    let mut response = ResponseEncoder::<TMRes>::stream(Box::pin(stream::once(ready(response))), binary)
+                        .headers(headers)
+                        .trailers(trailers)
+                        .strict_reserved_headers(strict_reserved_headers)
+                        .stable_json_field_order(stable_json_field_order)
+                        .stream_compression(compression.stream_threshold())
+                        .encode_response();
+                    compression.mark(&mut response);
+                    response
+                })
+            }
+        }
+    };
+}
+
+impl_handler!([]);
+impl_handler!([T1]);
+impl_handler!([T1, T2]);
+impl_handler!([T1, T2, T3]);
+impl_handler!([T1, T2, T3, T4]);
+impl_handler!([T1, T2, T3, T4, T5]);
+impl_handler!([T1, T2, T3, T4, T5, T6]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14]);
+impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15]);
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::{header, Request};
+    use futures::{channel::mpsc, stream, SinkExt, StreamExt, TryStreamExt};
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::{
+        config::RpcConfig,
+        error::{RpcError, RpcErrorCode},
+        prelude::*,
+        router::{RouteOptions, DEFAULT_MAX_STREAM_FRAMES, DEFAULT_MAX_STREAM_TOTAL_BYTES},
+        test_util::{
+            client, client_stream, frames, hello, messages, HelloRequest, HelloResponse,
+            SAY_HELLO_UPLOAD,
+        },
+    };
+
+    /// Greets everyone in the stream, failing on the first empty name.
+    async fn greet_all(mut requests: RpcRequestStream<HelloRequest>) -> RpcResult<HelloResponse> {
+        let mut names = vec![];
+        while let Some(request) = requests.try_next().await? {
+            if request.name.is_empty() {
+                return Err(RpcError::new(
+                    RpcErrorCode::InvalidArgument,
+                    "A name is missing".to_string(),
+                ));
+            }
+            names.push(request.name);
+        }
+        Ok(HelloResponse {
+            message: format!("Hello {}!", names.join(", ")),
+        })
+    }
+
+    fn upload_router() -> RpcRouter {
+        RpcRouter::new().rpc_method(client_stream(SAY_HELLO_UPLOAD, greet_all))
+    }
+
+    #[tokio::test]
+    async fn reads_every_message_of_the_stream() {
+        let body = frames(&[hello("Ada"), hello("Grace"), hello("Edsger")]);
+        let response = client(upload_router())
+            .post(SAY_HELLO_UPLOAD, "application/connect+proto", body)
+            .await;
+
+        assert_eq!(
+            response.headers["content-type"],
+            "application/connect+proto"
+        );
+        assert_eq!(
+            messages::<HelloResponse>(&response)
+                .into_iter()
+                .map(|response| response.message)
+                .collect::<Vec<_>>(),
+            ["Hello Ada, Grace, Edsger!"]
+        );
+    }
+
+    #[tokio::test]
+    async fn responds_before_the_client_is_done_sending() {
+        let (mut sender, chunks) = mpsc::channel::<Result<Vec<u8>, std::io::Error>>(1);
+        let request = Request::post(SAY_HELLO_UPLOAD)
+            .header(header::CONTENT_TYPE, "application/connect+proto")
+            .body(Body::from_stream(chunks))
+            .unwrap();
+        let upload = tokio::spawn(async move {
+            sender.send(Ok(frames(&[hello("")]))).await.unwrap();
+            // The upload goes on until the server stops reading it.
+            let mut sent = 1;
+            while sender.send(Ok(frames(&[hello("Ada")]))).await.is_ok() {
+                sent += 1;
+            }
+            sent
+        });
+
+        let response = timeout(
+            Duration::from_secs(5),
+            client(upload_router()).send(request),
+        )
+        .await
+        .expect("the response waited for the upload");
+        assert_eq!(
+            response.error().map(|error| error.code),
+            Some(RpcErrorCode::InvalidArgument)
+        );
+
+        // The rest of the body was dropped unread, closing the client's upload.
+        let sent = timeout(Duration::from_secs(5), upload)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(sent < 4, "the server read {sent} frames");
+    }
+
+    #[tokio::test]
+    async fn hyper_sends_the_response_before_the_upload_is_done() {
+        use hyper_util::{
+            rt::{TokioExecutor, TokioIo},
+            server::conn::auto,
+        };
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = upload_router().into_service();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+        });
+
+        // A 50 MB upload, of which only the bad first frame is sent.
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let head = format!(
+            "POST {SAY_HELLO_UPLOAD} HTTP/1.1\r\nhost: localhost\r\n\
+             content-type: application/connect+proto\r\ncontent-length: {}\r\n\r\n",
+            50 << 20
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(&frames(&[hello("")])).await.unwrap();
+
+        // The head and the body may come in separate reads.
+        let mut response = vec![];
+        let read = async {
+            while !String::from_utf8_lossy(&response).contains("invalid_argument") {
+                let mut chunk = [0; 1024];
+                let read = stream.read(&mut chunk).await.unwrap();
+                assert!(read > 0, "the connection closed without a response");
+                response.extend_from_slice(&chunk[..read]);
+            }
+        };
+        timeout(Duration::from_secs(5), read)
+            .await
+            .expect("the response waited for the upload");
+        let response = String::from_utf8_lossy(&response);
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    }
+
+    /// How many messages the handler got before the error ending its stream.
+    async fn count(requests: RpcRequestStream<HelloRequest>) -> RpcResult<HelloResponse> {
+        let results = requests.collect::<Vec<_>>().await;
+        let error = results.last().unwrap().clone().unwrap_err();
+        Ok(HelloResponse {
+            message: format!("{} then {}", results.len() - 1, error.message),
+        })
+    }
+
+    /// What [`count`] says of `chunks`, sent one at a time to a route with `options`.
+    async fn count_with(options: RouteOptions, chunks: Vec<Vec<u8>>) -> String {
+        let router = RpcRouter::new().rpc_with_options(options, |router: RpcRouter| {
+            router.rpc_method(client_stream(SAY_HELLO_UPLOAD, count))
+        });
+        let chunks = stream::iter(chunks.into_iter().map(Ok::<_, std::io::Error>));
+        let request = Request::post(SAY_HELLO_UPLOAD)
+            .header(header::CONTENT_TYPE, "application/connect+proto")
+            .body(Body::from_stream(chunks))
+            .unwrap();
+        let response = client(router).send(request).await;
+        messages::<HelloResponse>(&response).remove(0).message
+    }
+
+    #[tokio::test]
+    async fn ends_the_stream_after_max_stream_frames() {
+        let chunks = vec![frames(&vec![hello("Ada"); 5])];
+        let options = RouteOptions::new().max_stream_frames(3);
+        assert_eq!(
+            count_with(options, chunks).await,
+            "3 then Stream has more than the 3 frame limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_the_stream_past_max_stream_total_bytes() {
+        // 10 bytes each, so the third goes over.
+        let chunks = vec![frames(&[hello("Ada")]); 3];
+        let options = RouteOptions::new().max_stream_total_bytes(25);
+        assert_eq!(
+            count_with(options, chunks).await,
+            "2 then Stream is larger than the 25 byte limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_streams_by_default() {
+        let options = RouteOptions::new();
+        assert_eq!(options.max_stream_frames, DEFAULT_MAX_STREAM_FRAMES);
+        assert_eq!(
+            options.max_stream_total_bytes,
+            DEFAULT_MAX_STREAM_TOTAL_BYTES
+        );
+
+        let chunks = vec![frames(&vec![hello(""); DEFAULT_MAX_STREAM_FRAMES + 1])];
+        assert_eq!(
+            count_with(options, chunks).await,
+            format!("{DEFAULT_MAX_STREAM_FRAMES} then Stream has more than the {DEFAULT_MAX_STREAM_FRAMES} frame limit")
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_the_stream_at_a_truncated_frame() {
+        let mut body = frames(&[hello("Ada")]);
+        body.extend_from_slice(&[0, 0, 0, 0, 9, 1]);
+        let router = RpcRouter::new().rpc_method(client_stream(SAY_HELLO_UPLOAD, count));
+        let response = client(router)
+            .post(SAY_HELLO_UPLOAD, "application/connect+proto", body)
+            .await;
+
+        assert_eq!(
+            messages::<HelloResponse>(&response)[0].message,
+            "1 then Truncated envelope, expected 9 payload bytes but got 1"
+        );
+    }
+
+    /// How many messages [`SAY_HELLO_UPLOAD`] got, and how the stream ended.
+    async fn outcome(requests: RpcRequestStream<HelloRequest>) -> RpcResult<HelloResponse> {
+        let results = requests.collect::<Vec<_>>().await;
+        let received = results.iter().filter(|result| result.is_ok()).count();
+        let end = match results.last() {
+            Some(Err(error)) => format!("{:?}: {}", error.code, error.message),
+            _ => "the end".to_string(),
+        };
+        Ok(HelloResponse {
+            message: format!("{received} then {end}"),
+        })
+    }
+
+    /// What [`outcome`] says of an upload sending a frame after each of `gaps` (in ms).
+    async fn outcome_of(router: RpcRouter, gaps: &[u64]) -> String {
+        let chunks = stream::iter(gaps.to_vec()).then(|gap| async move {
+            tokio::time::sleep(Duration::from_millis(gap)).await;
+            Ok::<_, std::io::Error>(frames(&[hello("Ada")]))
+        });
+        let request = Request::post(SAY_HELLO_UPLOAD)
+            .header(header::CONTENT_TYPE, "application/connect+proto")
+            .body(Body::from_stream(chunks))
+            .unwrap();
+        let response = client(router).send(request).await;
+        messages::<HelloResponse>(&response).remove(0).message
+    }
+
+    fn outcome_router() -> RpcRouter {
+        RpcRouter::new().rpc_method(client_stream(SAY_HELLO_UPLOAD, outcome))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn frames_inside_the_idle_timeout_keep_the_stream_alive() {
+        let router = outcome_router().stream_idle_timeout(Duration::from_secs(1));
+        // 3.6s in all, but never a second without a frame.
+        assert_eq!(
+            outcome_of(router, &[0, 900, 900, 900, 900]).await,
+            "5 then the end"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_gap_past_the_idle_timeout_ends_the_stream() {
+        let router = outcome_router().stream_idle_timeout(Duration::from_secs(1));
+        assert_eq!(
+            outcome_of(router, &[0, 900, 1100, 0]).await,
+            "2 then DeadlineExceeded: No request frame received for 1s"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn routes_override_the_idle_timeout_of_the_router() {
+        let router = RpcRouter::new()
+            .merge(outcome_router().stream_idle_timeout(Duration::from_secs(5)))
+            .stream_idle_timeout(Duration::from_secs(1));
+        assert_eq!(outcome_of(router, &[0, 3000, 0]).await, "3 then the end");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_config_sets_the_default_idle_timeout() {
+        let config = RpcConfig::default().stream_idle_timeout(Duration::from_secs(1));
+        let router = outcome_router().with_config(config);
+        assert_eq!(
+            outcome_of(router, &[0, 1500]).await,
+            "1 then DeadlineExceeded: No request frame received for 1s"
+        );
+    }
+}
@@ -0,0 +1,235 @@
+use std::pin::Pin;
+
+use axum::body::{Body, Bytes};
+use axum::http::{Method, Request};
+use axum::response::Response;
+use futures::Future;
+
+use crate::auth::authorize;
+use crate::concurrency;
+use crate::config::RpcConfig;
+use crate::deadline;
+use crate::error::RpcError;
+use crate::parts::RpcMetadata;
+use crate::router::check_enabled;
+use crate::verify::verify_body;
+
+use super::codec::{
+    decode_check_headers, decode_check_query, preallocates, query_message_bytes, read_body,
+    response_binary, ReqResInto, ResponseEncoder,
+};
+
+/// What a dynamic handler knows about the request, besides the message itself.
+#[derive(Clone, Debug)]
+pub struct RpcProtocolInfo {
+    /// The request path, eg. `/hello.HelloWorldService/SayHello`.
+    pub path: String,
+    /// True if the request message is binary protobuf, false if it's JSON.
+    pub request_binary: bool,
+    /// True if the response must be binary protobuf, false if it must be JSON. Usually the same as
+    /// `request_binary`, unless the client asked otherwise with an `Accept` header.
+    pub response_binary: bool,
+    /// True for Connect GET requests.
+    pub get: bool,
+    pub metadata: RpcMetadata,
+}
+
+/// A unary handler working on encoded messages, for schemas only known at runtime. See
+/// [`RpcRouter::rpc_dynamic`](crate::router::RpcRouter::rpc_dynamic).
+///
+/// It gets the request message bytes (unwrapped from the GET query if need be) and returns the
+/// response message, encoded as [`RpcProtocolInfo::response_binary`] says. Headers, content-type
+/// negotiation and error rendering are handled for it.
+pub trait RpcHandlerDynamic: Clone + Send + Sync + Sized + 'static {
+    type Future: Future<Output = Response> + Send + 'static;
+
+    fn call(self, req: Request<Body>) -> Self::Future;
+}
+
+impl<TFn, TFnFut> RpcHandlerDynamic for TFn
+where
+    TFn: FnOnce(RpcProtocolInfo, Bytes) -> TFnFut + Clone + Send + Sync + 'static,
+    TFnFut: Future<Output = Result<Vec<u8>, RpcError>> + Send,
+{
+    type Future = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+    fn call(self, req: Request<Body>) -> Self::Future {
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            // axum serves HEAD requests with the GET route, minus the body.
+            let get = matches!(parts.method, Method::GET | Method::HEAD);
+            let config = RpcConfig::from_parts(&parts);
+
+            let ReqResInto { binary, .. } = if get {
+                match decode_check_query(&parts) {
+                    Ok(binary) => binary,
+                    Err(e) => return e,
+                }
+            } else {
+                match decode_check_headers(&mut parts, false) {
+                    Ok(binary) => binary,
+                    Err(e) => return e,
+                }
+            };
+
+            if let Err(error) = check_enabled(&parts) {
+                return ResponseEncoder::error(error, false, binary).encode_response();
+            }
+
+            let deadline = match deadline::start(&mut parts, &config) {
+                Ok(deadline) => deadline,
+                Err(error) => {
+                    return ResponseEncoder::error(error, false, binary).encode_response()
+                }
+            };
+
+            if let Err(error) = authorize(&mut parts).await {
+                return ResponseEncoder::error(error, false, binary).encode_response();
+            }
+
+            let _permit = match concurrency::acquire(&parts).await {
+                Ok(permit) => permit,
+                Err(error) => {
+                    return ResponseEncoder::error(error, false, binary).encode_response()
+                }
+            };
+
+            let message = if get {
+                match query_message_bytes(&parts) {
+                    Ok(message) => Bytes::from(message),
+                    Err(e) => return e,
+                }
+            } else {
+                match read_body(body, None, config.max_request_bytes, preallocates(&parts)).await {
+                    Ok(message) => message,
+                    Err(error) => {
+                        return ResponseEncoder::error(error, false, binary).encode_response()
+                    }
+                }
+            };
+
+            if let Err(error) = verify_body(&parts, &message) {
+                return ResponseEncoder::error(error, false, binary).encode_response();
+            }
+
+            let info = RpcProtocolInfo {
+                path: parts.uri.path().to_string(),
+                request_binary: binary,
+                response_binary: response_binary(&parts, binary),
+                get,
+                metadata: RpcMetadata(parts.headers),
+            };
+
+            let response_binary = info.response_binary;
+            let response = deadline::run(deadline, self(info, message))
+                .await
+                .and_then(std::convert::identity)
+                .map_err(|e| config.redact(e));
+            ResponseEncoder::unary_raw(response, response_binary)
+                .stable_json_field_order(config.stable_json_field_order)
+                .encode_response()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::header;
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        error::RpcErrorCode,
+        router::RpcRouter,
+        test_util::{client, hello, HelloRequest},
+    };
+
+    const ECHO: &str = "/echo.EchoService/Echo";
+
+    /// Sends the request message back. When the client asked for the other codec, it answers
+    /// with a `HelloRequest` named after the codecs instead, eg. `true>false` for proto in and
+    /// JSON out.
+    async fn echo(info: RpcProtocolInfo, message: Bytes) -> Result<Vec<u8>, RpcError> {
+        assert_eq!(info.path, ECHO);
+        if message.is_empty() && !info.request_binary {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                "Nothing to echo".to_string(),
+            ));
+        }
+        if info.request_binary != info.response_binary {
+            let codecs = format!("{}>{}", info.request_binary, info.response_binary);
+            return Ok(match info.response_binary {
+                true => hello(&codecs).encode_to_vec(),
+                false => format!(r#"{{"name":"{}"}}"#, codecs).into_bytes(),
+            });
+        }
+        Ok(message.to_vec())
+    }
+
+    fn echo_router() -> RpcRouter {
+        RpcRouter::new().rpc_dynamic(ECHO, echo)
+    }
+
+    #[tokio::test]
+    async fn echoes_binary_messages() {
+        let body = hello("Ada").encode_to_vec();
+        let response = client(echo_router())
+            .post(ECHO, "application/proto", body.clone())
+            .await;
+        assert_eq!(response.content_type(), Some("application/proto"));
+        assert_eq!(response.body, body);
+    }
+
+    #[tokio::test]
+    async fn echoes_json_messages() {
+        let body = br#"{"name":"Ada"}"#.to_vec();
+        let response = client(echo_router())
+            .post(ECHO, "application/json", body.clone())
+            .await;
+        assert_eq!(response.content_type(), Some("application/json"));
+        assert_eq!(response.body, body);
+    }
+
+    #[tokio::test]
+    async fn tells_the_handler_the_codec_to_answer_in() {
+        let request = Request::post(ECHO)
+            .header(header::CONTENT_TYPE, "application/proto")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::from(hello("Ada").encode_to_vec()))
+            .unwrap();
+        let response = client(echo_router()).send(request).await;
+        assert_eq!(response.content_type(), Some("application/json"));
+        assert_eq!(response.body, br#"{"name":"true>false"}"#.as_slice());
+    }
+
+    #[tokio::test]
+    async fn renders_errors_in_the_request_codec() {
+        let response = client(echo_router())
+            .post(ECHO, "application/json", vec![])
+            .await;
+        assert_eq!(response.status, 400);
+        assert_eq!(response.content_type(), Some("application/json"));
+        let error = response.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(error.message, "Nothing to echo");
+    }
+
+    #[tokio::test]
+    async fn echoes_get_requests() {
+        let message = hello("Ada").encode_to_vec();
+        let query = message
+            .iter()
+            .map(|byte| format!("%{:02X}", byte))
+            .collect::<String>();
+        let request = Request::get(format!("{ECHO}?connect=v1&encoding=proto&message={query}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = client(echo_router()).send(request).await;
+        assert_eq!(response.content_type(), Some("application/proto"));
+        assert_eq!(
+            HelloRequest::decode(response.body.as_ref()).unwrap().name,
+            "Ada"
+        );
+    }
+}
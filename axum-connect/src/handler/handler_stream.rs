@@ -1,18 +1,36 @@
+use std::marker::PhantomData;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use axum::body::Body;
 use axum::http::Request;
 use axum::response::Response;
 use futures::{Future, Stream, StreamExt};
 use prost::Message;
-use serde::de::DeserializeOwned;
-use serde::Serialize;
 
-use crate::parts::RpcFromRequestParts;
+use crate::auth::authorize;
+use crate::concurrency;
+use crate::config::RpcConfig;
+use crate::deadline;
+use crate::hooks::ResponseHooks;
+use crate::parts::{PeekedMessage, RpcFromRequestParts, RpcResponseHeaders, RpcTrailers};
 use crate::response::RpcIntoResponse;
+use crate::router::check_enabled;
+use crate::shutdown::{self, ShutdownSignal};
 
-use super::codec::{decode_check_headers, decode_request_payload, ReqResInto, ResponseEncoder};
+use super::codec::{
+    decode_check_headers, decode_request_payload, stream_compression, Framing, ReqResInto,
+    ResponseCompression, ResponseEncoder, RpcJsonDecode, RpcJsonEncode,
+};
 
+/// A server-streaming handler.
+///
+/// The response headers are sent as soon as the handler returns its stream, before the first item
+/// is polled, so clients (and proxies with header timeouts) see the RPC start even if the first
+/// message takes a while.
+///
+/// Handlers that don't need the request message (eg. of watch RPCs taking `Empty`) can leave it
+/// out and take only extractors. The request body is then never decoded.
 pub trait RpcHandlerStream<TMReq, TMRes, TUid, TState>:
     Clone + Send + Sync + Sized + 'static
 {
@@ -21,11 +39,9 @@ pub trait RpcHandlerStream<TMReq, TMRes, TUid, TState>:
     fn call(self, req: Request<Body>, state: TState) -> Self::Future;
 }
 
-// TODO: Get "connect-timeout-ms" (number as string) and apply timeout.
 // TODO: Parse request metadata from:
 //      - [0-9a-z]*!"-bin" ASCII value
 //      - [0-9a-z]*-bin" (base64 encoded binary)
-// TODO: Allow response to send back both leading and trailing metadata.
 
 macro_rules! impl_handler {
     (
@@ -35,11 +51,11 @@ macro_rules! impl_handler {
         impl<TMReq, TMRes, TInto, TFnItem, TFnFut, TFn, TState, $($ty,)*>
             RpcHandlerStream<TMReq, TMRes, ($($ty,)* TMReq), TState> for TFn
         where
-            TMReq: Message + DeserializeOwned + Default + Send + 'static,
-            TMRes: Message + Serialize + Send + 'static,
+            TMReq: Message + RpcJsonDecode + Clone + Default + Send + Sync + 'static,
+            TMRes: Message + RpcJsonEncode + Send + 'static,
             TInto: RpcIntoResponse<TMRes>,
-            TFnItem: Stream<Item = TInto> + Send + Sized + 'static,
-            TFnFut: Future<Output = TFnItem> + Send + Sync,
+            TFnItem: Stream<Item = TInto> + Send + 'static,
+            TFnFut: Future<Output = TFnItem> + Send,
             TFn: FnOnce($($ty,)* TMReq) -> TFnFut + Clone + Send + Sync + 'static,
             TState: Send + Sync + 'static,
             $( $ty: RpcFromRequestParts<TMRes, TState> + Send, )*
@@ -51,32 +67,205 @@ macro_rules! impl_handler {
                 Box::pin(async move {
                     let (mut parts, body) = req.into_parts();
 
-                    let ReqResInto { binary } = match decode_check_headers(&mut parts, true) {
+                    let ReqResInto { binary, .. } = match decode_check_headers(&mut parts, true) {
                         Ok(binary) => binary,
                         Err(e) => return e,
                     };
 
                     let state = &state;
+                    let config = RpcConfig::from_parts(&parts);
+                    let hooks = ResponseHooks::from_parts(&parts);
+                    let shutdown = ShutdownSignal::from_parts(&parts);
 
-                    $(
-                    let $ty = match $ty::rpc_from_request_parts(&mut parts, state).await {
-                        Ok(value) => value,
+                    if let Err(error) = check_enabled(&parts) {
+                        return ResponseEncoder::error(error, true, binary).encode_response();
+                    }
+
+                    let deadline = match deadline::start(&mut parts, &config) {
+                        Ok(deadline) => deadline,
+                        Err(error) => return ResponseEncoder::error(error, true, binary).encode_response(),
+                    };
+
+                    if let Err(error) = authorize(&mut parts).await {
+                        return ResponseEncoder::error(error, true, binary).encode_response();
+                    }
+
+                    let permit = match concurrency::acquire(&parts).await {
+                        Ok(permit) => permit,
+                        Err(error) => return ResponseEncoder::error(error, true, binary).encode_response(),
+                    };
+
+                    let trailers = RpcTrailers::default();
+                    parts.extensions.insert(trailers.clone());
+                    let headers = RpcResponseHeaders::default();
+                    parts.extensions.insert(headers.clone());
+                    let strict_reserved_headers = config.strict_reserved_headers;
+                    let stable_json_field_order = config.stable_json_field_order;
+                    let threshold = stream_compression(&parts);
+                    let compression = ResponseCompression::new(&parts, true, threshold);
+                    parts.extensions.insert(compression.clone());
+
+                    // With a `Peek` among the extractors, the message is decoded before all of them.
+                    let mut body = Some(body);
+                    let mut peeked = None;
+                    if false $(|| $ty::PEEKS_MESSAGE)* {
+                        let message: TMReq = match decode_request_payload(&parts, body.take().unwrap_or_default(), state, binary, Framing::Stream).await {
+                            Ok(value) => value,
+                            Err(e) => return e,
+                        };
+                        parts.extensions.insert(PeekedMessage(Arc::new(message.clone())));
+                        peeked = Some(message);
+                    }
+
+                    run_extractors!(parts, state, config, true, binary; $($ty),*);
+
+                    let proto_req: TMReq = match peeked {
+                        Some(message) => message,
+                        None => match decode_request_payload(&parts, body.take().unwrap_or_default(), state, binary, Framing::Stream).await {
+                            Ok(value) => value,
+                            Err(e) => return e,
+                        },
+                    };
+
+                    // The stream is only polled by the response body, after the headers are out.
+                    let stream = match deadline::run(deadline, trailers.clone().scope(self($($ty,)* proto_req))).await {
+                        Ok(stream) => stream,
                         Err(error) => {
-                            return ResponseEncoder::error(error, true, binary).encode_response();
+                            return ResponseEncoder::error(error, true, binary)
+                                .headers(headers)
+                                .trailers(trailers)
+                                .strict_reserved_headers(strict_reserved_headers)
+                                .stable_json_field_order(stable_json_field_order)
+                                .encode_response()
                         }
                     };
-                    )*
+                    let stream = stream
+                        .map(move |response| {
+                            // The slot is held until the response stream is dropped.
+                            let _permit = &permit;
+                            response
+                                .rpc_into_response()
+                                .map(|mut message| {
+                                    hooks.apply(&mut message);
+                                    message
+                                })
+                                .map_err(|e| config.redact(e))
+                        });
+                    let stream = deadline::limit_stream(deadline, stream);
+                    let stream = shutdown::limit_stream(shutdown, stream);
+                    ResponseEncoder::<TMRes>::stream(stream.boxed(), binary)
+                        .headers(headers)
+                        .trailers(trailers)
+                        .strict_reserved_headers(strict_reserved_headers)
+                        .stable_json_field_order(stable_json_field_order)
+                        .stream_compression(compression.stream_threshold())
+                        .encode_response()
+                })
+            }
+        }
+    };
+}
+
+/// The `TUid` marker of [`RpcHandlerStream`] impls for handlers without a request message, `T` is
+/// the tuple of extractors.
+#[derive(Clone, Copy, Debug)]
+pub struct WithoutMessage<T>(PhantomData<T>);
+
+macro_rules! impl_handler_without_message {
+    (
+        [$($ty:ident),*]
+    ) => {
+        #[allow(unused_parens, non_snake_case, unused_mut, unused_variables)]
+        impl<TMReq, TMRes, TInto, TFnItem, TFnFut, TFn, TState, $($ty,)*>
+            RpcHandlerStream<TMReq, TMRes, WithoutMessage<($($ty,)*)>, TState> for TFn
+        where
+            TMRes: Message + RpcJsonEncode + Send + 'static,
+            TInto: RpcIntoResponse<TMRes>,
+            TFnItem: Stream<Item = TInto> + Send + 'static,
+            TFnFut: Future<Output = TFnItem> + Send,
+            TFn: FnOnce($($ty),*) -> TFnFut + Clone + Send + Sync + 'static,
+            TState: Send + Sync + 'static,
+            $( $ty: RpcFromRequestParts<TMRes, TState> + Send, )*
+        {
+
+            type Future = Pin<Box<dyn Future<Output = Response> + Send>>;
 
-                    let req = Request::from_parts(parts, body);
+            fn call(self, req: Request<Body>, state: TState) -> Self::Future {
+                Box::pin(async move {
+                    let (mut parts, _body) = req.into_parts();
 
-                    let proto_req: TMReq = match decode_request_payload(req, state, binary, true).await {
-                        Ok(value) => value,
+                    let ReqResInto { binary, .. } = match decode_check_headers(&mut parts, true) {
+                        Ok(binary) => binary,
                         Err(e) => return e,
                     };
 
-                    // TODO: Support returning trailers (they would need to bundle in the error type).
-                    let mut stream = self($($ty,)* proto_req).await.map(RpcIntoResponse::rpc_into_response);
-                    ResponseEncoder::<TMRes>::stream(stream.boxed(), binary).encode_response()
+                    let state = &state;
+                    let config = RpcConfig::from_parts(&parts);
+                    let hooks = ResponseHooks::from_parts(&parts);
+                    let shutdown = ShutdownSignal::from_parts(&parts);
+
+                    if let Err(error) = check_enabled(&parts) {
+                        return ResponseEncoder::error(error, true, binary).encode_response();
+                    }
+
+                    let deadline = match deadline::start(&mut parts, &config) {
+                        Ok(deadline) => deadline,
+                        Err(error) => return ResponseEncoder::error(error, true, binary).encode_response(),
+                    };
+
+                    if let Err(error) = authorize(&mut parts).await {
+                        return ResponseEncoder::error(error, true, binary).encode_response();
+                    }
+
+                    let permit = match concurrency::acquire(&parts).await {
+                        Ok(permit) => permit,
+                        Err(error) => return ResponseEncoder::error(error, true, binary).encode_response(),
+                    };
+
+                    let trailers = RpcTrailers::default();
+                    parts.extensions.insert(trailers.clone());
+                    let headers = RpcResponseHeaders::default();
+                    parts.extensions.insert(headers.clone());
+                    let strict_reserved_headers = config.strict_reserved_headers;
+                    let stable_json_field_order = config.stable_json_field_order;
+                    let threshold = stream_compression(&parts);
+                    let compression = ResponseCompression::new(&parts, true, threshold);
+                    parts.extensions.insert(compression.clone());
+
+                    run_extractors!(parts, state, config, true, binary; $($ty),*);
+
+                    let stream = match deadline::run(deadline, trailers.clone().scope(self($($ty),*))).await {
+                        Ok(stream) => stream,
+                        Err(error) => {
+                            return ResponseEncoder::error(error, true, binary)
+                                .headers(headers)
+                                .trailers(trailers)
+                                .strict_reserved_headers(strict_reserved_headers)
+                                .stable_json_field_order(stable_json_field_order)
+                                .encode_response()
+                        }
+                    };
+                    let stream = stream
+                        .map(move |response| {
+                            // The slot is held until the response stream is dropped.
+                            let _permit = &permit;
+                            response
+                                .rpc_into_response()
+                                .map(|mut message| {
+                                    hooks.apply(&mut message);
+                                    message
+                                })
+                                .map_err(|e| config.redact(e))
+                        });
+                    let stream = deadline::limit_stream(deadline, stream);
+                    let stream = shutdown::limit_stream(shutdown, stream);
+                    ResponseEncoder::<TMRes>::stream(stream.boxed(), binary)
+                        .headers(headers)
+                        .trailers(trailers)
+                        .strict_reserved_headers(strict_reserved_headers)
+                        .stable_json_field_order(stable_json_field_order)
+                        .stream_compression(compression.stream_threshold())
+                        .encode_response()
                 })
             }
         }
@@ -99,3 +288,280 @@ impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12]);
 impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13]);
 impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14]);
 impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15]);
+
+impl_handler_without_message!([]);
+impl_handler_without_message!([T1]);
+impl_handler_without_message!([T1, T2]);
+impl_handler_without_message!([T1, T2, T3]);
+impl_handler_without_message!([T1, T2, T3, T4]);
+impl_handler_without_message!([T1, T2, T3, T4, T5]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8, T9]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14]);
+impl_handler_without_message!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15]);
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{extract::State, http::HeaderValue};
+    use futures::stream;
+    use tokio::time::{sleep, Instant};
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        codec::FrameDecoder,
+        prelude::*,
+        test_util::{
+            client, hello, server_stream, stream_request, HelloRequest, HelloResponse,
+            SAY_HELLO_STREAM,
+        },
+    };
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_headers_before_the_first_item() {
+        async fn export(
+            headers: RpcResponseHeaders,
+            request: HelloRequest,
+        ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            headers.insert("x-export-id", HeaderValue::from_static("42"));
+            stream::once(async move {
+                sleep(Duration::from_millis(500)).await;
+                Ok(HelloResponse {
+                    message: format!("Exported {}", request.name),
+                })
+            })
+        }
+
+        let router = RpcRouter::new()
+            .rpc_method(server_stream(SAY_HELLO_STREAM, export))
+            .into_router();
+        let request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+
+        let start = Instant::now();
+        let response = router.oneshot(request).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers()["content-type"],
+            "application/connect+proto"
+        );
+        assert_eq!(response.headers()["x-export-id"], "42");
+
+        let mut body = response.into_body().into_data_stream();
+        let mut decoder = FrameDecoder::new();
+        let frame = loop {
+            decoder.push(&body.next().await.unwrap().unwrap());
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                break frame;
+            }
+        };
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert_eq!(
+            HelloResponse::decode(frame.payload.as_slice())
+                .unwrap()
+                .message,
+            "Exported Ada"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn quotes_64_bit_ints_in_json_frames() {
+        use pbjson_types::{Empty, Int64Value};
+
+        use crate::{
+            codec::encode_envelope,
+            router::RpcMethod,
+            test_util::{client, stream_info},
+        };
+
+        const ID: i64 = (1 << 53) + 1;
+
+        async fn ids(_: Empty) -> impl Stream<Item = RpcResult<Int64Value>> {
+            stream::iter([Ok(Int64Value { value: ID })])
+        }
+
+        let router = RpcRouter::new().rpc_method(RpcMethod::server_streaming(
+            stream_info(SAY_HELLO_STREAM),
+            ids,
+        ));
+        let mut body = vec![];
+        encode_envelope(0, b"{}", &mut body);
+        let response = client(router)
+            .post(SAY_HELLO_STREAM, "application/connect+json", body)
+            .await;
+
+        let (frames, error) = response.frames();
+        assert_eq!(error, None);
+        assert_eq!(
+            String::from_utf8(frames[0].clone()).unwrap(),
+            format!("\"{ID}\"")
+        );
+    }
+
+    #[derive(Clone)]
+    struct AppState {
+        greeting: &'static str,
+    }
+
+    /// A custom extractor, the caller's `x-user-id`.
+    struct UserId(String);
+
+    impl<M: Message, S: Send + Sync> RpcFromRequestParts<M, S> for UserId {
+        type Rejection = RpcError;
+
+        async fn rpc_from_request_parts(
+            parts: &mut axum::http::request::Parts,
+            _state: &S,
+        ) -> Result<Self, Self::Rejection> {
+            parts
+                .headers
+                .get("x-user-id")
+                .and_then(|id| id.to_str().ok())
+                .map(|id| UserId(id.to_string()))
+                .ok_or_else(|| RpcError::new(RpcErrorCode::Unauthenticated, "No user id".into()))
+        }
+    }
+
+    /// The messages of a call of [`SAY_HELLO_STREAM`] with an `x-user-id` of `7`.
+    async fn tail(router: RpcRouter<AppState>) -> Vec<String> {
+        let router = router.with_state::<()>(AppState { greeting: "Hi" });
+        let mut request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("x-user-id", HeaderValue::from_static("7"));
+        client(router)
+            .send(request)
+            .await
+            .frames()
+            .0
+            .iter()
+            .map(|frame| HelloResponse::decode(frame.as_slice()).unwrap().message)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn calls_handlers_without_extractors() {
+        async fn greet(request: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([Ok(HelloResponse {
+                message: format!("Hello {}!", request.name),
+            })])
+        }
+
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, greet));
+        assert_eq!(tail(router).await, ["Hello Ada!"]);
+    }
+
+    #[tokio::test]
+    async fn calls_handlers_with_one_extractor() {
+        async fn greet(
+            meta: RpcMetadata,
+            request: HelloRequest,
+        ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            let user = meta.get("x-user-id").unwrap_or_default().into_owned();
+            stream::iter([Ok(HelloResponse {
+                message: format!("Hello {} ({user})!", request.name),
+            })])
+        }
+
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, greet));
+        assert_eq!(tail(router).await, ["Hello Ada (7)!"]);
+    }
+
+    #[tokio::test]
+    async fn calls_handlers_with_three_extractors() {
+        async fn greet(
+            State(state): State<AppState>,
+            meta: RpcMetadata,
+            UserId(user): UserId,
+            request: HelloRequest,
+        ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            let agent = meta.get("user-agent").is_some();
+            stream::iter([
+                Ok(HelloResponse {
+                    message: format!("{} {}!", state.greeting, request.name),
+                }),
+                Ok(HelloResponse {
+                    message: format!("user {user}, user agent {agent}"),
+                }),
+            ])
+        }
+
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, greet));
+        assert_eq!(tail(router).await, ["Hi Ada!", "user 7, user agent false"]);
+    }
+
+    #[tokio::test]
+    async fn calls_handlers_without_a_request_message() {
+        async fn watch() -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([Ok(HelloResponse {
+                message: "Watching".into(),
+            })])
+        }
+
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, watch));
+        assert_eq!(tail(router).await, ["Watching"]);
+    }
+
+    #[tokio::test]
+    async fn calls_extractor_only_handlers_like_their_message_variants() {
+        async fn with_message(
+            State(state): State<AppState>,
+            UserId(user): UserId,
+            _request: HelloRequest,
+        ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([Ok(HelloResponse {
+                message: format!("{} {user}!", state.greeting),
+            })])
+        }
+
+        async fn without_message(
+            State(state): State<AppState>,
+            UserId(user): UserId,
+        ) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([Ok(HelloResponse {
+                message: format!("{} {user}!", state.greeting),
+            })])
+        }
+
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, with_message));
+        assert_eq!(tail(router).await, ["Hi 7!"]);
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, without_message));
+        assert_eq!(tail(router).await, ["Hi 7!"]);
+    }
+
+    #[tokio::test]
+    async fn never_decodes_the_body_without_a_request_message() {
+        async fn watch(UserId(user): UserId) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            stream::iter([Ok(HelloResponse {
+                message: format!("Watching for {user}"),
+            })])
+        }
+
+        let router = RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, watch));
+        let response = client(router)
+            .send(
+                Request::post(SAY_HELLO_STREAM)
+                    .header("content-type", "application/connect+proto")
+                    .header("x-user-id", "7")
+                    .body(Body::from("not an envelope"))
+                    .unwrap(),
+            )
+            .await;
+
+        let (frames, error) = response.frames();
+        assert_eq!(error, None);
+        assert_eq!(
+            HelloResponse::decode(frames[0].as_slice()).unwrap().message,
+            "Watching for 7"
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+
+use axum::body::Body;
+use axum::http::Request;
+use axum::response::Response;
+use futures::Future;
+
+use super::RpcHandlerUnary;
+
+type ResponseFuture = Pin<Box<dyn Future<Output = Response> + Send>>;
+
+/// A unary handler of any signature for the same messages, boxed.
+type BoxedHandler<TState> = Arc<dyn Fn(Request<Body>, TState) -> ResponseFuture + Send + Sync>;
+
+fn boxed<TMReq, TMRes, T, H, TState>(handler: H) -> BoxedHandler<TState>
+where
+    H: RpcHandlerUnary<TMReq, TMRes, T, TState>,
+{
+    Arc::new(move |req, state| Box::pin(handler.clone().call(req, state)))
+}
+
+/// The `TUid` marker of the [`RpcHandlerUnary`] impl of [`SwappableHandler`].
+#[derive(Clone, Copy, Debug)]
+pub struct Swappable;
+
+/// A unary handler that can be replaced at runtime, eg. to move an RPC from one implementation to
+/// another without a restart. Register it like any handler, and swap what serves the calls with its
+/// [`HandlerSwitch`]. The handlers can take different extractors, only the messages must match.
+///
+/// Calls are served by the handler that was active when they came in: one in flight during a swap
+/// still finishes on the old handler, and the ones after it get the new one.
+///
+/// ```
+/// # use axum_connect::{handler::SwappableHandler, pbjson_types::Empty, prelude::*};
+/// async fn v1(_: Empty) -> RpcResult<Empty> {
+///     Ok(Empty {})
+/// }
+///
+/// async fn v2(_: Empty) -> RpcResult<Empty> {
+///     Ok(Empty {})
+/// }
+///
+/// // The registration function usually infers the types.
+/// let handler: SwappableHandler<Empty, Empty> = SwappableHandler::new(v1);
+/// let switch = handler.switch();
+/// // RpcRouter::new().rpc(HealthService::check(handler));
+/// switch.swap(v2);
+/// ```
+pub struct SwappableHandler<TMReq, TMRes, TState = ()> {
+    active: Arc<RwLock<BoxedHandler<TState>>>,
+    _messages: PhantomData<fn(TMReq) -> TMRes>,
+}
+
+impl<TMReq, TMRes, TState> SwappableHandler<TMReq, TMRes, TState>
+where
+    TMReq: 'static,
+    TMRes: 'static,
+    TState: Send + Sync + 'static,
+{
+    /// Serves the calls with `handler` until it's swapped.
+    pub fn new<T, H>(handler: H) -> Self
+    where
+        H: RpcHandlerUnary<TMReq, TMRes, T, TState>,
+    {
+        Self {
+            active: Arc::new(RwLock::new(boxed(handler))),
+            _messages: PhantomData,
+        }
+    }
+
+    /// A handle swapping the handler of this and its clones.
+    pub fn switch(&self) -> HandlerSwitch<TMReq, TMRes, TState> {
+        HandlerSwitch {
+            active: self.active.clone(),
+            _messages: PhantomData,
+        }
+    }
+}
+
+impl<TMReq, TMRes, TState> Clone for SwappableHandler<TMReq, TMRes, TState> {
+    fn clone(&self) -> Self {
+        Self {
+            active: self.active.clone(),
+            _messages: PhantomData,
+        }
+    }
+}
+
+impl<TMReq, TMRes, TState> fmt::Debug for SwappableHandler<TMReq, TMRes, TState> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SwappableHandler").finish_non_exhaustive()
+    }
+}
+
+impl<TMReq, TMRes, TState> RpcHandlerUnary<TMReq, TMRes, Swappable, TState>
+    for SwappableHandler<TMReq, TMRes, TState>
+where
+    TMReq: 'static,
+    TMRes: 'static,
+    TState: Send + Sync + 'static,
+{
+    type Future = ResponseFuture;
+
+    fn call(self, req: Request<Body>, state: TState) -> Self::Future {
+        // Cloned out, so a swap doesn't wait for the calls in flight or change their handler.
+        let handler = self.active.read().unwrap().clone();
+        handler(req, state)
+    }
+}
+
+/// Swaps the handler of a [`SwappableHandler`]. Clones swap the same one.
+pub struct HandlerSwitch<TMReq, TMRes, TState = ()> {
+    active: Arc<RwLock<BoxedHandler<TState>>>,
+    _messages: PhantomData<fn(TMReq) -> TMRes>,
+}
+
+impl<TMReq, TMRes, TState> HandlerSwitch<TMReq, TMRes, TState>
+where
+    TMReq: 'static,
+    TMRes: 'static,
+    TState: Send + Sync + 'static,
+{
+    /// Serves the calls from now on with `handler`.
+    pub fn swap<T, H>(&self, handler: H)
+    where
+        H: RpcHandlerUnary<TMReq, TMRes, T, TState>,
+    {
+        *self.active.write().unwrap() = boxed(handler);
+    }
+}
+
+impl<TMReq, TMRes, TState> Clone for HandlerSwitch<TMReq, TMRes, TState> {
+    fn clone(&self) -> Self {
+        Self {
+            active: self.active.clone(),
+            _messages: PhantomData,
+        }
+    }
+}
+
+impl<TMReq, TMRes, TState> fmt::Debug for HandlerSwitch<TMReq, TMRes, TState> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandlerSwitch").finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::{mpsc, Semaphore};
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        router::RpcRouter,
+        test_util::{
+            client, hello, message, proto_request, say_hello, unary, HelloRequest, HelloResponse,
+            SAY_HELLO,
+        },
+    };
+
+    #[tokio::test]
+    async fn serves_new_calls_with_the_swapped_handler() {
+        let handler: SwappableHandler<HelloRequest, HelloResponse> =
+            SwappableHandler::new(say_hello);
+        let switch = handler.switch();
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, handler)));
+
+        let before = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        switch.swap(|request: HelloRequest| async move {
+            RpcResult::Ok(HelloResponse {
+                message: format!("Hi {}!", request.name),
+            })
+        });
+        let after = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(message::<HelloResponse>(&before).message, "Hello Ada!");
+        assert_eq!(message::<HelloResponse>(&after).message, "Hi Ada!");
+    }
+
+    #[tokio::test]
+    async fn finishes_calls_in_flight_on_the_old_handler() {
+        let (entered, mut entered_rx) = mpsc::unbounded_channel();
+        let open = Arc::new(Semaphore::new(0));
+        let held = {
+            let open = open.clone();
+            move |request: HelloRequest| {
+                let (entered, open) = (entered.clone(), open.clone());
+                async move {
+                    entered.send(()).unwrap();
+                    let _permit = open.acquire().await.unwrap();
+                    say_hello(request).await
+                }
+            }
+        };
+        let handler: SwappableHandler<HelloRequest, HelloResponse> = SwappableHandler::new(held);
+        let switch = handler.switch();
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, handler)));
+
+        let in_flight = tokio::spawn({
+            let client = client.clone();
+            async move { client.send(proto_request(SAY_HELLO, &hello("Ada"))).await }
+        });
+        entered_rx.recv().await.unwrap();
+        switch.swap(|_: HelloRequest| async {
+            RpcResult::<HelloResponse>::Err(RpcError::new(
+                RpcErrorCode::Unavailable,
+                "Swapped".to_string(),
+            ))
+        });
+        let after = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        open.add_permits(1);
+        let in_flight = in_flight.await.unwrap();
+
+        assert_eq!(after.error().unwrap().code, RpcErrorCode::Unavailable);
+        assert_eq!(message::<HelloResponse>(&in_flight).message, "Hello Ada!");
+    }
+}
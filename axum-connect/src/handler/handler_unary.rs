@@ -1,19 +1,29 @@
+use std::future::ready;
 use std::pin::Pin;
+use std::sync::Arc;
 
 use axum::body::Body;
-use axum::http::{Method, Request};
+use axum::http::{request, Method, Request};
 use axum::response::Response;
-use futures::Future;
+use futures::{stream, Future};
 use prost::Message;
-use serde::de::DeserializeOwned;
-use serde::Serialize;
 
-use crate::parts::RpcFromRequestParts;
-use crate::response::RpcIntoResponse;
+use crate::audit::AuditCall;
+use crate::auth::authorize;
+#[cfg(feature = "capture")]
+use crate::capture::CaptureCall;
+use crate::concurrency;
+use crate::config::RpcConfig;
+use crate::deadline;
+use crate::hooks::ResponseHooks;
+use crate::parts::{PeekedMessage, RpcFromRequestParts, RpcResponseHeaders, RpcTrailers};
+use crate::response::{RpcIntoResponse, RpcPayload};
+use crate::router::check_enabled;
 
 use super::codec::{
-    decode_check_headers, decode_check_query, decode_request_payload,
-    decode_request_payload_from_query, ReqResInto, ResponseEncoder,
+    decode_check_query, decode_check_unary_headers, decode_request_payload,
+    decode_request_payload_from_query, response_binary, Framing, ReqResInto, ResponseCompression,
+    ResponseEncoder, RpcJsonDecode, RpcJsonEncode,
 };
 
 pub trait RpcHandlerUnary<TMReq, TMRes, TUid, TState>:
@@ -25,12 +35,9 @@ pub trait RpcHandlerUnary<TMReq, TMRes, TUid, TState>:
 }
 
 // This is for Unary.
-// TODO: Check that the header "connect-protocol-version" == "1"
-// TODO: Get "connect-timeout-ms" (number as string) and apply timeout.
 // TODO: Parse request metadata from:
 //      - [0-9a-z]*!"-bin" ASCII value
 //      - [0-9a-z]*-bin" (base64 encoded binary)
-// TODO: Allow response to send back both leading and trailing metadata.
 
 macro_rules! impl_handler {
     (
@@ -40,8 +47,8 @@ macro_rules! impl_handler {
         impl<TMReq, TMRes, TInto, TFnFut, TFn, TState, $($ty,)*>
             RpcHandlerUnary<TMReq, TMRes, ($($ty,)* TMReq), TState> for TFn
         where
-            TMReq: Message + DeserializeOwned + Default + Send + 'static,
-            TMRes: Message + Serialize + Send + 'static,
+            TMReq: Message + RpcJsonDecode + RpcJsonEncode + Clone + Default + Send + Sync + 'static,
+            TMRes: Message + RpcJsonEncode + Send + 'static,
             TInto: RpcIntoResponse<TMRes>,
             TFnFut: Future<Output = TInto> + Send,
             TFn: FnOnce($($ty,)* TMReq) -> TFnFut + Clone + Send + Sync + 'static,
@@ -54,51 +61,150 @@ macro_rules! impl_handler {
                 Box::pin(async move {
                     let (mut parts, body) = req.into_parts();
 
-                    let ReqResInto { binary } = if parts.method == Method::GET {
+                    // axum serves HEAD requests with the GET route, minus the body.
+                    let get = matches!(parts.method, Method::GET | Method::HEAD);
+                    let ReqResInto { binary, framing } = if get {
                         match decode_check_query(&parts) {
                             Ok(binary) => binary,
                             Err(e) => return e,
                         }
                     } else {
-                        match decode_check_headers(&mut parts, false) {
+                        match decode_check_unary_headers(&mut parts) {
                             Ok(binary) => binary,
                             Err(e) => return e,
                         }
                     };
 
                     let state = &state;
+                    // Clients sending a streaming Content-Type read a stream back, in their codec.
+                    let streaming = framing.is_streaming();
+                    let response_binary = match streaming {
+                        true => binary,
+                        false => response_binary(&parts, binary),
+                    };
+                    let config = RpcConfig::from_parts(&parts);
+                    let hooks = ResponseHooks::from_parts(&parts);
 
-                    $(
-                        let $ty = match $ty::rpc_from_request_parts(&mut parts, state).await {
-                            Ok(value) => value,
-                            Err(error) => {
-                                return ResponseEncoder::error(error, false, binary).encode_response();
-                            }
-                        };
-                    )*
+                    if let Err(error) = check_enabled(&parts) {
+                        return ResponseEncoder::error(error, streaming, binary).encode_response();
+                    }
+
+                    let deadline = match deadline::start(&mut parts, &config) {
+                        Ok(deadline) => deadline,
+                        Err(error) => return ResponseEncoder::error(error, streaming, binary).encode_response(),
+                    };
+
+                    if let Err(error) = authorize(&mut parts).await {
+                        return ResponseEncoder::error(error, streaming, binary).encode_response();
+                    }
 
-                    let proto_req: TMReq = if parts.method == Method::GET {
-                        match decode_request_payload_from_query(&parts, state, binary) {
+                    let _permit = match concurrency::acquire(&parts).await {
+                        Ok(permit) => permit,
+                        Err(error) => return ResponseEncoder::error(error, streaming, binary).encode_response(),
+                    };
+
+                    let trailers = RpcTrailers::default();
+                    parts.extensions.insert(trailers.clone());
+                    let headers = RpcResponseHeaders::default();
+                    parts.extensions.insert(headers.clone());
+                    let compression = ResponseCompression::new(&parts, streaming, None);
+                    parts.extensions.insert(compression.clone());
+                    let strict_reserved_headers = config.strict_reserved_headers;
+                    let stable_json_field_order = config.stable_json_field_order;
+                    let empty_response_body = config.empty_response_body;
+                    let mut audit = AuditCall::start(&parts);
+                    #[cfg(feature = "capture")]
+                    let mut capture = CaptureCall::start(&parts);
+
+                    // With a `Peek` among the extractors, the message is decoded before all of them.
+                    let mut body = Some(body);
+                    let mut peeked = None;
+                    if false $(|| $ty::PEEKS_MESSAGE)* {
+                        let message: TMReq = match decode_message(&parts, body.take().unwrap_or_default(), state, get, binary, framing).await {
                             Ok(value) => value,
                             Err(e) => return e,
-                        }
-                    } else {
-                        let req = Request::from_parts(parts, body);
+                        };
+                        parts.extensions.insert(PeekedMessage(Arc::new(message.clone())));
+                        peeked = Some(message);
+                    }
 
-                        match decode_request_payload(req, state, binary, false).await {
+                    run_extractors!(parts, state, config, streaming, binary; $($ty),*);
+
+                    let proto_req: TMReq = match peeked {
+                        Some(message) => message,
+                        None => match decode_message(&parts, body.take().unwrap_or_default(), state, get, binary, framing).await {
                             Ok(value) => value,
                             Err(e) => return e,
-                        }
+                        },
                     };
 
-                    let response = self($($ty,)* proto_req).await;
-                    ResponseEncoder::<TMRes>::unary(response, binary).encode_response()
+                    if let Some(audit) = &mut audit {
+                        audit.request(&proto_req);
+                    }
+                    #[cfg(feature = "capture")]
+                    if let Some(capture) = &mut capture {
+                        capture.request(&proto_req);
+                    }
+
+                    let response = deadline::run(deadline, self($($ty,)* proto_req))
+                        .await
+                        .and_then(RpcIntoResponse::rpc_into_payload)
+                        .map(|payload| {
+                            payload.map_message(|mut message| {
+                                hooks.apply(&mut message);
+                                message
+                            })
+                        })
+                        .map_err(|e| config.redact(e));
+                    if let Some(audit) = audit {
+                        audit.finish(&response);
+                    }
+                    #[cfg(feature = "capture")]
+                    if let Some(capture) = capture {
+                        capture.finish::<TMReq, TMRes>(&hooks, &response);
+                    }
+                    let encoder = match streaming {
+                        true => ResponseEncoder::<TMRes>::stream(
+                            Box::pin(stream::once(ready(response.and_then(RpcPayload::into_message)))),
+                            response_binary,
+                        ),
+                        false => ResponseEncoder::<TMRes>::unary(response, response_binary)
+                            .empty_response_body(empty_response_body),
+                    };
+                    let mut response = encoder
+                        .headers(headers)
+                        .trailers(trailers)
+                        .strict_reserved_headers(strict_reserved_headers)
+                        .stable_json_field_order(stable_json_field_order)
+                        .stream_compression(compression.stream_threshold())
+                        .encode_response();
+                    compression.mark(&mut response);
+                    response
                 })
             }
         }
     };
 }
 
+/// Decodes the request message, from the query of GET requests and the body of the others.
+async fn decode_message<M, S>(
+    parts: &request::Parts,
+    body: Body,
+    state: &S,
+    get: bool,
+    binary: bool,
+    framing: Framing,
+) -> Result<M, Response>
+where
+    M: Message + RpcJsonDecode + Default + 'static,
+    S: Send + Sync + 'static,
+{
+    match get {
+        true => decode_request_payload_from_query(parts, state, binary),
+        false => decode_request_payload(parts, body, state, binary, framing).await,
+    }
+}
+
 impl_handler!([]);
 impl_handler!([T1]);
 impl_handler!([T1, T2]);
@@ -115,3 +221,166 @@ impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12]);
 impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13]);
 impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14]);
 impl_handler!([T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12, T13, T14, T15]);
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use axum::{
+        body::Body,
+        http::{header, Request},
+    };
+    use std::sync::{Arc, Mutex};
+
+    use axum::http::request::Parts;
+    use prost::Message;
+
+    use super::RpcHandlerUnary;
+    use crate::{
+        prelude::*,
+        test_util::{
+            client, hello, hello_router, proto_request, unary, HelloRequest, HelloResponse,
+            SAY_HELLO,
+        },
+    };
+
+    /// The content type and body of a unary call of [`SAY_HELLO`] sent as `request` with `accept`.
+    async fn respond(mut request: Request<Body>, accept: &str) -> (String, Vec<u8>) {
+        request
+            .headers_mut()
+            .insert(header::ACCEPT, accept.parse().unwrap());
+        let response = client(hello_router()).send(request).await;
+        assert!(response.status.is_success(), "{:?}", response.error());
+        (
+            response.content_type().unwrap().to_string(),
+            response.body.to_vec(),
+        )
+    }
+
+    fn json_request() -> Request<Body> {
+        Request::post(SAY_HELLO)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"name":"Ada"}"#))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_proto_requests_in_json() {
+        let (content_type, body) =
+            respond(proto_request(SAY_HELLO, &hello("Ada")), "application/json").await;
+        assert_eq!(content_type, "application/json");
+        assert_eq!(body, br#"{"message":"Hello Ada!"}"#);
+    }
+
+    #[tokio::test]
+    async fn answers_json_requests_in_proto() {
+        let (content_type, body) = respond(json_request(), "application/proto").await;
+        assert_eq!(content_type, "application/proto");
+        assert_eq!(
+            HelloResponse::decode(body.as_slice()).unwrap().message,
+            "Hello Ada!"
+        );
+    }
+
+    #[tokio::test]
+    async fn mirrors_the_request_codec_for_other_accept_values() {
+        let (content_type, _) = respond(json_request(), "*/*").await;
+        assert_eq!(content_type, "application/json");
+        let (content_type, _) = respond(proto_request(SAY_HELLO, &hello("Ada")), "*/*").await;
+        assert_eq!(content_type, "application/proto");
+        let (content_type, _) = respond(json_request(), "text/html, image/webp").await;
+        assert_eq!(content_type, "application/json");
+    }
+
+    /// The extractors that ran, in order, put in the request's extensions by the test.
+    #[derive(Clone, Default)]
+    struct Runs(Arc<Mutex<Vec<&'static str>>>);
+
+    impl Runs {
+        fn push(parts: &Parts, extractor: &'static str) {
+            parts
+                .extensions
+                .get::<Runs>()
+                .unwrap()
+                .0
+                .lock()
+                .unwrap()
+                .push(extractor);
+        }
+
+        fn get(&self) -> Vec<&'static str> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    /// Inserts a `Tenant` for the extractors after it.
+    struct LookupTenant;
+
+    #[derive(Clone)]
+    struct Tenant(&'static str);
+
+    impl<M: Message, S: Send + Sync> RpcFromRequestParts<M, S> for LookupTenant {
+        type Rejection = RpcError;
+
+        async fn rpc_from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, RpcError> {
+            Runs::push(parts, "LookupTenant");
+            parts.extensions.insert(Tenant("acme"));
+            Ok(Self)
+        }
+    }
+
+    /// The `Tenant` an earlier extractor inserted.
+    struct RequireTenant(Tenant);
+
+    impl<M: Message, S: Send + Sync> RpcFromRequestParts<M, S> for RequireTenant {
+        type Rejection = RpcError;
+
+        async fn rpc_from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, RpcError> {
+            Runs::push(parts, "RequireTenant");
+            match parts.extensions.get::<Tenant>() {
+                Some(tenant) => Ok(Self(tenant.clone())),
+                None => Err(RpcError::new(
+                    RpcErrorCode::PermissionDenied,
+                    "no tenant".to_string(),
+                )),
+            }
+        }
+    }
+
+    /// Runs the handler of [`SAY_HELLO`] with `request`, recording its extractors in `runs`.
+    async fn call<T, H>(handler: H, runs: &Runs, mut request: Request<Body>) -> Option<RpcError>
+    where
+        H: RpcHandlerUnary<HelloRequest, HelloResponse, T, ()>,
+        T: 'static,
+    {
+        request.extensions_mut().insert(runs.clone());
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, handler)));
+        client.send(request).await.error()
+    }
+
+    #[tokio::test]
+    async fn runs_extractors_in_order_on_the_parts_the_ones_before_left() {
+        let runs = Runs::default();
+        let handler = |_: LookupTenant, RequireTenant(tenant): RequireTenant, _: HelloRequest| async move {
+            Ok::<_, RpcError>(HelloResponse {
+                message: format!("Hello {}!", tenant.0),
+            })
+        };
+        let error = call(handler, &runs, proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert!(error.is_none(), "{error:?}");
+        assert_eq!(runs.get(), ["LookupTenant", "RequireTenant"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_first_rejection_before_reading_the_body() {
+        let runs = Runs::default();
+        let handler = |_: RequireTenant, _: LookupTenant, _: HelloRequest| async move {
+            Ok::<_, RpcError>(HelloResponse::default())
+        };
+        let request = Request::post(SAY_HELLO)
+            .header(header::CONTENT_TYPE, "application/proto")
+            .body(Body::from("not a message"))
+            .unwrap();
+        let error = call(handler, &runs, request).await.unwrap();
+        assert_eq!(error.code, RpcErrorCode::PermissionDenied);
+        assert_eq!(runs.get(), ["RequireTenant"]);
+    }
+}
@@ -1,7 +1,36 @@
+/// Runs a handler's extractors on `parts`, in the order [`RpcFromRequestParts`] describes,
+/// returning the rejection of the first to fail as the response.
+///
+/// [`RpcFromRequestParts`]: crate::parts::RpcFromRequestParts
+macro_rules! run_extractors {
+    ($parts:ident, $state:ident, $config:ident, $streaming:expr, $binary:ident; $($ty:ident),*) => {
+        $(
+            let $ty = match $ty::rpc_from_request_parts(&mut $parts, $state).await {
+                Ok(value) => value,
+                Err(error) => {
+                    let error = $config.rejection::<$ty>(error, $parts.uri.path());
+                    return $crate::handler::codec::ResponseEncoder::error(error, $streaming, $binary)
+                        .encode_response();
+                }
+            };
+        )*
+    };
+}
+
+pub mod handler_client_stream;
+pub mod handler_dynamic;
 pub mod handler_stream;
+pub mod handler_swappable;
 pub mod handler_unary;
 
-mod codec;
+// Decoders return a ready-to-send `Response` as their error, which is large but intentional.
+#[allow(clippy::result_large_err)]
+pub(crate) mod codec;
 
+pub use handler_client_stream::*;
+pub use handler_dynamic::*;
 pub use handler_stream::*;
+pub use handler_swappable::*;
 pub use handler_unary::*;
+
+pub use codec::{RpcJsonDecode, RpcJsonEncode};
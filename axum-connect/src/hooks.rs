@@ -0,0 +1,178 @@
+//! Rewriting response messages before they're encoded, see [`ResponseHooks`].
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+};
+
+use axum::http::request;
+
+type Hook = Arc<dyn Fn(&mut dyn Any) + Send + Sync>;
+
+/// Hooks run on every response message of a type after the handler returns it and before it's
+/// encoded, as binary or JSON, eg. to scrub fields in some deployments without touching every
+/// handler. Registered with
+/// [`RpcRouter::response_hooks`](crate::router::RpcRouter::response_hooks), they see unary
+/// responses and every frame of a response stream. Messages of other types are left alone.
+///
+/// ```
+/// # use axum_connect::hooks::ResponseHooks;
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct UserResponse { #[prost(string, tag = "1")] email: String }
+/// let hooks = ResponseHooks::new().on_response::<UserResponse>(|user| {
+///     user.email = "<redacted>".to_string();
+/// });
+/// ```
+///
+/// Dynamic RPCs respond with encoded messages, which hooks don't see.
+#[derive(Clone, Default)]
+pub struct ResponseHooks {
+    hooks: Arc<HashMap<TypeId, Vec<Hook>>>,
+}
+
+impl ResponseHooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `hook` on every `M` response. Hooks of the same type run in the order they were added.
+    pub fn on_response<M: 'static>(
+        mut self,
+        hook: impl Fn(&mut M) + Send + Sync + 'static,
+    ) -> Self {
+        let hook: Hook = Arc::new(move |message: &mut dyn Any| {
+            if let Some(message) = message.downcast_mut::<M>() {
+                hook(message);
+            }
+        });
+        Arc::make_mut(&mut self.hooks)
+            .entry(TypeId::of::<M>())
+            .or_default()
+            .push(hook);
+        self
+    }
+
+    /// Runs the hooks registered for `M` on `message`.
+    pub fn apply<M: 'static>(&self, message: &mut M) {
+        for hook in self.hooks.get(&TypeId::of::<M>()).into_iter().flatten() {
+            hook(message);
+        }
+    }
+
+    /// The hooks of the route, an empty set if it has none.
+    pub(crate) fn from_parts(parts: &request::Parts) -> Self {
+        parts.extensions.get::<Self>().cloned().unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for ResponseHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseHooks")
+            .field("types", &self.hooks.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, Stream};
+    use pbjson_types::StringValue;
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        router::RpcMethod,
+        test_util::{
+            client, hello, message, messages, proto_request, say_hello, server_stream,
+            stream_request, unary, unary_info, HelloRequest, HelloResponse, SAY_HELLO,
+            SAY_HELLO_STREAM,
+        },
+    };
+
+    const ECHO: &str = "/hello.HelloWorldService/Echo";
+
+    async fn echo(request: HelloRequest) -> RpcResult<StringValue> {
+        Ok(StringValue {
+            value: format!("Hello {}!", request.name),
+        })
+    }
+
+    async fn greet_twice(request: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+        let greeting = say_hello(request).await;
+        stream::iter([greeting.clone(), greeting])
+    }
+
+    /// Hooks redacting greetings, and only them.
+    fn redacting() -> crate::testing::TestClient {
+        let hooks = ResponseHooks::new().on_response::<HelloResponse>(|response| {
+            response.message = response.message.replace("Ada", "<redacted>");
+        });
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, say_hello))
+            .rpc_method(server_stream(SAY_HELLO_STREAM, greet_twice))
+            .rpc_method(RpcMethod::unary(unary_info(ECHO), echo))
+            .response_hooks(hooks);
+        client(router)
+    }
+
+    #[tokio::test]
+    async fn rewrites_unary_responses_of_the_hooked_type() {
+        let client = redacting();
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            "Hello <redacted>!"
+        );
+    }
+
+    #[tokio::test]
+    async fn rewrites_every_frame_of_a_stream() {
+        let client = redacting();
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        let greetings = messages::<HelloResponse>(&response)
+            .into_iter()
+            .map(|response| response.message)
+            .collect::<Vec<_>>();
+        assert_eq!(greetings, ["Hello <redacted>!", "Hello <redacted>!"]);
+    }
+
+    #[tokio::test]
+    async fn leaves_other_types_alone() {
+        let client = redacting();
+
+        let response = client.send(proto_request(ECHO, &hello("Ada"))).await;
+
+        assert_eq!(
+            StringValue::decode(response.body).unwrap().value,
+            "Hello Ada!"
+        );
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn rewrites_json_responses_too() {
+        let client = redacting();
+
+        let greeting = client
+            .post(SAY_HELLO, "application/json", br#"{"name":"Ada"}"#.to_vec())
+            .await;
+        let echo = client
+            .post(ECHO, "application/json", br#"{"name":"Ada"}"#.to_vec())
+            .await;
+
+        assert_eq!(
+            greeting.body,
+            r#"{"message":"Hello <redacted>!"}"#.as_bytes()
+        );
+        assert_eq!(echo.body, r#""Hello Ada!""#.as_bytes());
+    }
+}
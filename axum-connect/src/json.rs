@@ -0,0 +1,56 @@
+//! Serde helpers used by generated code, where pbjson's output doesn't match the protobuf JSON
+//! mapping that other Connect clients (eg. connect-es) expect.
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{ser::SerializeSeq, Serialize, Serializer};
+
+/// Serializes a `google.protobuf.Timestamp` as a Z-normalized RFC 3339 string with 0, 3, 6 or 9
+/// fractional digits, eg. `1970-01-01T00:00:01.500Z`. pbjson uses a `+00:00` offset instead, which
+/// protobuf-es rejects when there are fractional seconds.
+pub struct Timestamp<'a>(pub &'a pbjson_types::Timestamp);
+
+impl Serialize for Timestamp<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let t: DateTime<Utc> = (*self.0).try_into().map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&t.to_rfc3339_opts(SecondsFormat::AutoSi, true))
+    }
+}
+
+/// A repeated `google.protobuf.Timestamp`, see [`Timestamp`].
+pub struct Timestamps<'a>(pub &'a [pbjson_types::Timestamp]);
+
+impl Serialize for Timestamps<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for timestamp in self.0 {
+            seq.serialize_element(&Timestamp(timestamp))?;
+        }
+        seq.end()
+    }
+}
+
+/// A `map<_, google.protobuf.Timestamp>`, see [`Timestamp`]. Takes a `HashMap` or a `BTreeMap`.
+pub struct TimestampMap<'a, M>(pub &'a M);
+
+impl<'a, M, K> Serialize for TimestampMap<'a, M>
+where
+    &'a M: IntoIterator<Item = (&'a K, &'a pbjson_types::Timestamp)>,
+    K: Serialize + 'a,
+{
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_map(
+            self.0
+                .into_iter()
+                .map(|(key, timestamp)| (key, Timestamp(timestamp))),
+        )
+    }
+}
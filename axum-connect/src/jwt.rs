@@ -0,0 +1,340 @@
+//! Verified JWT claims for handlers, see [`Claims`].
+
+use std::{collections::HashMap, fmt, time::Duration};
+
+use axum::{
+    extract::FromRef,
+    http::{self, header, HeaderValue},
+};
+use jsonwebtoken::{errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use prost::Message;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::{RpcError, RpcErrorCode},
+    parts::RpcFromRequestParts,
+};
+
+/// How [`Claims`] verifies tokens, taken from the router state with `FromRef`.
+///
+/// Tokens must be signed with one of the configured algorithms and carry an `exp` claim. The
+/// audience and issuer are only checked when set. Expiry and not-before allow for 60 seconds of
+/// clock skew by default.
+#[derive(Clone)]
+pub struct JwtConfig {
+    key: DecodingKey,
+    keys_by_id: HashMap<String, DecodingKey>,
+    validation: Validation,
+}
+
+impl JwtConfig {
+    pub fn new(algorithm: Algorithm, key: DecodingKey) -> Self {
+        let mut validation = Validation::new(algorithm);
+        // Checked once an audience is set, tokens with an `aud` would be rejected otherwise.
+        validation.validate_aud = false;
+        validation.validate_nbf = true;
+
+        Self {
+            key,
+            keys_by_id: HashMap::new(),
+            validation,
+        }
+    }
+
+    /// An HS256 config, the common case for tokens issued by the same service.
+    pub fn hs256(secret: &[u8]) -> Self {
+        Self::new(Algorithm::HS256, DecodingKey::from_secret(secret))
+    }
+
+    /// Verifies tokens whose `kid` header is `id` with `key` instead, eg. during key rotation.
+    pub fn key(mut self, id: &str, key: DecodingKey) -> Self {
+        self.keys_by_id.insert(id.to_string(), key);
+        self
+    }
+
+    /// The algorithms tokens may be signed with, replacing the one given to `new`. They must all be
+    /// of the same family as the keys.
+    pub fn algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.validation.algorithms = algorithms;
+        self
+    }
+
+    /// Tokens must be issued for one of `audience`, or fail with `PermissionDenied`. Tokens without
+    /// an `aud` claim fail with `Unauthenticated`.
+    pub fn audience(mut self, audience: &[&str]) -> Self {
+        self.validation.set_audience(audience);
+        self.validation.validate_aud = true;
+        // Otherwise only checked when the token has one.
+        self.validation.set_required_spec_claims(&["exp", "aud"]);
+        self
+    }
+
+    /// Tokens must be issued by one of `issuer`.
+    pub fn issuer(mut self, issuer: &[&str]) -> Self {
+        self.validation.set_issuer(issuer);
+        self
+    }
+
+    /// The clock skew allowed when checking `exp` and `nbf`, rounded down to whole seconds.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.validation.leeway = leeway.as_secs();
+        self
+    }
+
+    fn decode<T: DeserializeOwned>(&self, token: &str) -> Result<T, RpcError> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| rejection(e.kind()))?;
+        let key = header
+            .kid
+            .and_then(|id| self.keys_by_id.get(&id))
+            .unwrap_or(&self.key);
+
+        jsonwebtoken::decode(token, key, &self.validation)
+            .map(|data| data.claims)
+            .map_err(|e| rejection(e.kind()))
+    }
+}
+
+impl fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("key_ids", &self.keys_by_id.keys().collect::<Vec<_>>())
+            .field("validation", &self.validation)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The claims of the request's verified bearer token, deserialized as `T`.
+///
+/// Missing, malformed, expired or badly signed tokens fail with `Unauthenticated` and a
+/// `www-authenticate` header, tokens for another audience with `PermissionDenied`.
+///
+/// ```
+/// # use axum::extract::FromRef;
+/// # use axum_connect::{jwt::{Claims, JwtConfig}, prelude::*};
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Empty {}
+/// #[derive(serde::Deserialize)]
+/// struct User {
+///     sub: String,
+/// }
+///
+/// #[derive(Clone)]
+/// struct AppState {
+///     jwt: JwtConfig,
+/// }
+///
+/// impl FromRef<AppState> for JwtConfig {
+///     fn from_ref(state: &AppState) -> Self {
+///         state.jwt.clone()
+///     }
+/// }
+///
+/// async fn whoami(Claims(user): Claims<User>, _: Empty) -> RpcResult<Empty> {
+///     println!("called by {}", user.sub);
+///     Ok(Empty {})
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Claims<T>(pub T);
+
+impl<M, S, T> RpcFromRequestParts<M, S> for Claims<T>
+where
+    M: Message,
+    S: Send + Sync,
+    JwtConfig: FromRef<S>,
+    T: DeserializeOwned,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.split_once(' ')
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            })
+            .map(|(_, token)| token.trim());
+
+        let Some(token) = token else {
+            return Err(unauthenticated("Missing bearer token", "Bearer"));
+        };
+
+        JwtConfig::from_ref(state).decode(token).map(Claims)
+    }
+}
+
+fn rejection(kind: &ErrorKind) -> RpcError {
+    match kind {
+        ErrorKind::InvalidAudience => RpcError::new(
+            RpcErrorCode::PermissionDenied,
+            "The token wasn't issued for this service".to_string(),
+        ),
+        ErrorKind::ExpiredSignature => unauthenticated(
+            "The token expired",
+            r#"Bearer error="invalid_token", error_description="The token expired""#,
+        ),
+        ErrorKind::Json(_) => unauthenticated(
+            "The token's claims are invalid",
+            r#"Bearer error="invalid_token""#,
+        ),
+        _ => unauthenticated(
+            &format!("Invalid token: {}", kind_description(kind)),
+            r#"Bearer error="invalid_token""#,
+        ),
+    }
+}
+
+fn kind_description(kind: &ErrorKind) -> String {
+    match kind {
+        ErrorKind::InvalidSignature => "bad signature".to_string(),
+        ErrorKind::InvalidIssuer => "wrong issuer".to_string(),
+        ErrorKind::ImmatureSignature => "not valid yet".to_string(),
+        ErrorKind::InvalidAlgorithm => "algorithm not allowed".to_string(),
+        ErrorKind::MissingRequiredClaim(claim) => format!("missing `{}` claim", claim),
+        _ => "malformed".to_string(),
+    }
+}
+
+fn unauthenticated(message: &str, challenge: &'static str) -> RpcError {
+    let mut error = RpcError::new(RpcErrorCode::Unauthenticated, message.to_string());
+    error.metadata_mut().insert(
+        header::WWW_AUTHENTICATE,
+        HeaderValue::from_static(challenge),
+    );
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use axum::http::{header, HeaderValue};
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        test_util::{client, hello, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO},
+        testing::TestResponse,
+    };
+
+    const SECRET: &[u8] = b"the secret";
+
+    #[derive(Serialize, Deserialize)]
+    struct User {
+        sub: String,
+        exp: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        aud: Option<String>,
+    }
+
+    /// A token for `ada` expiring `expires_in` seconds from now (or ago), signed with `secret`.
+    fn token(secret: &[u8], expires_in: i64) -> String {
+        token_for(secret, expires_in, None)
+    }
+
+    /// Like [`token`], issued for `audience`.
+    fn token_for(secret: &[u8], expires_in: i64, audience: Option<&str>) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let user = User {
+            sub: "ada".to_string(),
+            exp: now.saturating_add_signed(expires_in),
+            aud: audience.map(str::to_string),
+        };
+        jsonwebtoken::encode(&Header::default(), &user, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    /// Calls SAY_HELLO, answering with the subject of the token, with `authorization`.
+    async fn call(config: JwtConfig, authorization: Option<String>) -> TestResponse {
+        let handler = |Claims(user): Claims<User>, _: HelloRequest| async move {
+            Ok::<_, RpcError>(HelloResponse {
+                message: format!("Hello {}!", user.sub),
+            })
+        };
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, handler))
+            .with_state(config);
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        if let Some(authorization) = authorization {
+            request.headers_mut().insert(
+                header::AUTHORIZATION,
+                HeaderValue::from_str(&authorization).unwrap(),
+            );
+        }
+        client(router).send(request).await
+    }
+
+    fn rejection(response: &TestResponse) -> (RpcErrorCode, String, Option<&HeaderValue>) {
+        let error = response.error().unwrap();
+        let challenge = response.headers.get(header::WWW_AUTHENTICATE);
+        (error.code, error.message, challenge)
+    }
+
+    #[tokio::test]
+    async fn extracts_the_claims_of_valid_tokens() {
+        let authorization = format!("Bearer {}", token(SECRET, 3600));
+        let response = call(JwtConfig::hs256(SECRET), Some(authorization)).await;
+        let message: HelloResponse = crate::test_util::message(&response);
+        assert_eq!(message.message, "Hello ada!");
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_tokens() {
+        // Past the 60 seconds of leeway.
+        let authorization = format!("Bearer {}", token(SECRET, -120));
+        let response = call(JwtConfig::hs256(SECRET), Some(authorization)).await;
+        let (code, message, challenge) = rejection(&response);
+        assert_eq!(code, RpcErrorCode::Unauthenticated);
+        assert_eq!(message, "The token expired");
+        assert_eq!(
+            challenge.unwrap(),
+            r#"Bearer error="invalid_token", error_description="The token expired""#
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_tokens_with_a_bad_signature() {
+        let authorization = format!("Bearer {}", token(b"another secret", 3600));
+        let response = call(JwtConfig::hs256(SECRET), Some(authorization)).await;
+        let (code, message, challenge) = rejection(&response);
+        assert_eq!(code, RpcErrorCode::Unauthenticated);
+        assert_eq!(message, "Invalid token: bad signature");
+        assert_eq!(challenge.unwrap(), r#"Bearer error="invalid_token""#);
+    }
+
+    #[tokio::test]
+    async fn rejects_calls_without_a_bearer_token() {
+        for authorization in [None, Some(format!("Basic {}", token(SECRET, 3600)))] {
+            let response = call(JwtConfig::hs256(SECRET), authorization).await;
+            let (code, message, challenge) = rejection(&response);
+            assert_eq!(code, RpcErrorCode::Unauthenticated);
+            assert_eq!(message, "Missing bearer token");
+            assert_eq!(challenge.unwrap(), "Bearer");
+        }
+    }
+
+    #[tokio::test]
+    async fn checks_the_audience_once_set() {
+        let config = JwtConfig::hs256(SECRET).audience(&["billing"]);
+        let call = |audience| {
+            let authorization = format!("Bearer {}", token_for(SECRET, 3600, audience));
+            call(config.clone(), Some(authorization))
+        };
+
+        assert!(call(Some("billing")).await.error().is_none());
+        let (code, _, _) = rejection(&call(Some("search")).await);
+        assert_eq!(code, RpcErrorCode::PermissionDenied);
+        let (code, message, _) = rejection(&call(None).await);
+        assert_eq!(code, RpcErrorCode::Unauthenticated);
+        assert_eq!(message, "Invalid token: missing `aud` claim");
+    }
+}
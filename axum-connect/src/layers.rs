@@ -0,0 +1,691 @@
+//! The usual middleware for RPC routes, composed in an order that works, see [`recommended`].
+
+use std::{convert::Infallible, fmt, time::Duration};
+
+#[cfg(feature = "tower-http")]
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{HeaderName, HeaderValue},
+    BoxError,
+};
+use axum::{extract::Request, http::header, response::Response};
+use tower::{util::BoxCloneSyncService, Layer, Service};
+#[cfg(feature = "tower-http")]
+use tower::{
+    util::{AndThenLayer, MapRequestLayer},
+    ServiceBuilder,
+};
+#[cfg(feature = "tower-http")]
+use tower_http::{
+    compression::{
+        predicate::{DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+};
+
+use crate::{
+    codec::ContentType,
+    error::{RpcError, RpcErrorCode},
+    handler::codec::ResponseEncoder,
+    logging::RpcLogLayer,
+};
+#[cfg(feature = "tower-http")]
+use crate::{handler::codec::ForcedEncoding, logging::RpcCallStats, signing};
+
+/// The default [`RecommendedLayers::timeout`].
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The request id header set and propagated by [`RecommendedLayers`].
+#[cfg(feature = "tower-http")]
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The layers most services end up assembling, as one layer:
+///
+/// ```
+/// # use axum_connect::prelude::*;
+/// # fn routes(app: RpcRouter) -> RpcRouter {
+/// app.layer(axum_connect::layers::recommended())
+/// # }
+/// ```
+///
+/// From the outside in:
+///
+/// 1. With the `tower-http` feature, an `x-request-id` for requests without one (a UUID),
+///    copied to the response.
+/// 2. The [`RpcLogLayer`] access log, outside everything that can fail a call so it logs the
+///    code the client got.
+/// 3. With the `tower-http` feature, gzip of unary responses for clients that accept it. Stream
+///    responses are left alone, compressing them would hold frames back. Compressed responses
+///    are sent with the `content-length` of the compressed body. It's outside the timeout, so
+///    errors are compressed like any other response; the other way around, the timeout's error
+///    would go out uncompressed while a compressed body was half sent. Handlers can force it
+///    either way, see [`RpcCompressionInfo`](crate::parts::RpcCompressionInfo). Routes signing
+///    [`after_compression`](crate::signing::ResponseSigning::after_compression) are signed here.
+/// 4. A timeout for the handler's response (for streams, until the stream starts), failing with
+///    `DeadlineExceeded` as a Connect error. [`DEFAULT_TIMEOUT`] by default. Clients' own
+///    `connect-timeout-ms` deadlines are enforced either way.
+///
+/// Every piece can be turned off or replaced, eg. `recommended().without_compression()`.
+pub fn recommended() -> RecommendedLayers {
+    RecommendedLayers {
+        #[cfg(feature = "tower-http")]
+        request_id: true,
+        log: Some(RpcLogLayer::new()),
+        #[cfg(feature = "tower-http")]
+        compression: true,
+        timeout: Some(DEFAULT_TIMEOUT),
+    }
+}
+
+/// The layer returned by [`recommended`].
+#[derive(Clone)]
+pub struct RecommendedLayers {
+    #[cfg(feature = "tower-http")]
+    request_id: bool,
+    log: Option<RpcLogLayer>,
+    #[cfg(feature = "tower-http")]
+    compression: bool,
+    timeout: Option<Duration>,
+}
+
+impl RecommendedLayers {
+    #[cfg(feature = "tower-http")]
+    pub fn without_request_id(mut self) -> Self {
+        self.request_id = false;
+        self
+    }
+
+    /// Logs with `log` instead of a default [`RpcLogLayer`], eg. one with more redacted keys.
+    pub fn log(mut self, log: RpcLogLayer) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    pub fn without_log(mut self) -> Self {
+        self.log = None;
+        self
+    }
+
+    #[cfg(feature = "tower-http")]
+    pub fn without_compression(mut self) -> Self {
+        self.compression = false;
+        self
+    }
+
+    /// Whether unary responses are gzipped, for
+    /// [`capabilities_json`](crate::router::RpcRouter::capabilities_json).
+    pub(crate) fn compresses(&self) -> bool {
+        #[cfg(feature = "tower-http")]
+        return self.compression;
+        #[cfg(not(feature = "tower-http"))]
+        false
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn without_timeout(mut self) -> Self {
+        self.timeout = None;
+        self
+    }
+}
+
+impl fmt::Debug for RecommendedLayers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("RecommendedLayers");
+        #[cfg(feature = "tower-http")]
+        debug.field("request_id", &self.request_id);
+        debug.field("log", &self.log);
+        #[cfg(feature = "tower-http")]
+        debug.field("compression", &self.compression);
+        debug.field("timeout", &self.timeout).finish()
+    }
+}
+
+impl<S> Layer<S> for RecommendedLayers
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + Sync + 'static,
+    S::Future: Send + 'static,
+{
+    type Service = BoxCloneSyncService<Request, Response, Infallible>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        let mut service = BoxCloneSyncService::new(inner);
+
+        // Innermost first.
+        if let Some(timeout) = self.timeout {
+            service = BoxCloneSyncService::new(Timeout {
+                inner: service,
+                timeout,
+            });
+        }
+        #[cfg(feature = "tower-http")]
+        if self.compression {
+            service = BoxCloneSyncService::new(
+                ServiceBuilder::new()
+                    .layer(AndThenLayer::new(|response| async {
+                        Ok::<_, Infallible>(signing::sign_compressed(response).await)
+                    }))
+                    .layer(AndThenLayer::new(with_content_length))
+                    .layer(
+                        CompressionLayer::new()
+                            .gzip(true)
+                            .compress_when(UnaryPredicate),
+                    )
+                    .layer(MapRequestLayer::new(|mut request: Request| {
+                        request.extensions_mut().insert(UnaryGzip);
+                        request
+                    }))
+                    .service(service),
+            );
+        }
+        if let Some(log) = &self.log {
+            service = BoxCloneSyncService::new(log.layer(service));
+        }
+        #[cfg(feature = "tower-http")]
+        if self.request_id {
+            service = BoxCloneSyncService::new(
+                ServiceBuilder::new()
+                    .layer(SetRequestIdLayer::new(REQUEST_ID, MakeRequestUuid))
+                    .layer(PropagateRequestIdLayer::new(REQUEST_ID))
+                    .service(service),
+            );
+        }
+
+        service
+    }
+}
+
+/// Tells [`RpcCompressionInfo`](crate::parts::RpcCompressionInfo) that unary responses are
+/// gzipped for clients accepting it.
+#[cfg(feature = "tower-http")]
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct UnaryGzip;
+
+/// Compresses what `DefaultPredicate` does, stream responses aside, unless the handler overrode
+/// it: it's left alone if forced to identity, and compressed whatever its size if forced to gzip.
+#[cfg(feature = "tower-http")]
+#[derive(Clone, Copy, Debug)]
+struct UnaryPredicate;
+
+#[cfg(feature = "tower-http")]
+impl Predicate for UnaryPredicate {
+    fn should_compress<B: HttpBody>(&self, response: &Response<B>) -> bool {
+        let unary = NotForContentType::const_new("application/connect+");
+        match response.extensions().get::<ForcedEncoding>() {
+            Some(ForcedEncoding(encoding)) => encoding.is_some() && unary.should_compress(response),
+            None => DefaultPredicate::new().and(unary).should_compress(response),
+        }
+    }
+}
+
+/// Buffers compressed unary responses, which were in memory before compression anyway, to send
+/// their length. It's their [`RpcCallStats::response_bytes`] too, which counted them before.
+#[cfg(feature = "tower-http")]
+async fn with_content_length<B>(response: Response<B>) -> Result<Response, Infallible>
+where
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    let streaming = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+        .is_some_and(|content_type| content_type.is_streaming());
+    if streaming || !response.headers().contains_key(header::CONTENT_ENCODING) {
+        return Ok(response.map(Body::new));
+    }
+
+    let (mut parts, body) = response.into_parts();
+    match axum::body::to_bytes(Body::new(body), usize::MAX).await {
+        Ok(bytes) => {
+            if let Some(stats) = parts.extensions.get::<std::sync::Arc<RpcCallStats>>() {
+                stats
+                    .response_bytes
+                    .store(bytes.len() as u64, std::sync::atomic::Ordering::Relaxed);
+            }
+            parts
+                .headers
+                .insert(header::CONTENT_LENGTH, HeaderValue::from(bytes.len()));
+            Ok(Response::from_parts(parts, Body::from(bytes)))
+        }
+        Err(error) => {
+            let error = RpcError::new(
+                RpcErrorCode::Internal,
+                format!("Failed to compress the response: {}", error),
+            );
+            Ok(ResponseEncoder::error(error, false, false).encode_response())
+        }
+    }
+}
+
+/// Fails calls whose response takes longer than `timeout` with `DeadlineExceeded`.
+#[derive(Clone)]
+struct Timeout {
+    inner: BoxCloneSyncService<Request, Response, Infallible>,
+    timeout: Duration,
+}
+
+impl Service<Request> for Timeout {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = futures::future::BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(
+        &mut self,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let content_type = request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| ContentType::parse(content_type.as_bytes()));
+        let streaming = content_type.is_some_and(|content_type| content_type.is_streaming());
+        let binary = content_type.is_some_and(|content_type| content_type.is_binary());
+        let timeout = self.timeout;
+        // `poll_ready` was called on `inner`, so it's the one that must be called.
+        let clone = self.inner.clone();
+        let future = std::mem::replace(&mut self.inner, clone).call(request);
+
+        Box::pin(async move {
+            match tokio::time::timeout(timeout, future).await {
+                Ok(response) => response,
+                Err(_) => {
+                    let error = RpcError::new(
+                        RpcErrorCode::DeadlineExceeded,
+                        format!("The server's timeout of {:?} elapsed", timeout),
+                    );
+                    Ok(ResponseEncoder::error(error, streaming, binary).encode_response())
+                }
+            }
+        })
+    }
+}
+
+#[cfg(all(test, feature = "tower-http"))]
+mod tests {
+    use axum::http::{HeaderValue, StatusCode};
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        parts::RpcCompressionInfo,
+        prelude::*,
+        request::RpcCallParts,
+        response::RpcResponse,
+        router::{CompressionMode, RouteOptions},
+        test_util::{
+            client, hello, message, proto_request, say_hello, say_hello_stream, server_stream,
+            traced, unary, HelloRequest, HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+        testing::TestResponse,
+    };
+
+    /// A call of [`say_hello`] mounted with `mode`, large enough to be gzipped by default, from a
+    /// client sending `accept_encoding`.
+    async fn call(mode: CompressionMode, name: &str, accept_encoding: &str) -> TestResponse {
+        let router = RpcRouter::new()
+            .rpc_with_options(
+                RouteOptions::new().compression(mode),
+                |router: RpcRouter| router.rpc_method(unary(SAY_HELLO, say_hello)),
+            )
+            .layer(recommended());
+        let mut request = proto_request(SAY_HELLO, &hello(name));
+        request.headers_mut().insert(
+            header::ACCEPT_ENCODING,
+            HeaderValue::from_str(accept_encoding).unwrap(),
+        );
+        client(router).send(request).await
+    }
+
+    fn encoding(response: &TestResponse) -> Option<&str> {
+        assert!(response.status.is_success(), "{:?}", response.error());
+        response
+            .headers
+            .get(header::CONTENT_ENCODING)
+            .map(|encoding| encoding.to_str().unwrap())
+    }
+
+    #[tokio::test]
+    async fn auto_compresses_as_negotiated() {
+        let large = "Ada".repeat(100);
+        let response = call(CompressionMode::Auto, &large, "gzip").await;
+        assert_eq!(encoding(&response), Some("gzip"));
+        let response = call(CompressionMode::Auto, "Ada", "gzip").await;
+        assert_eq!(encoding(&response), None);
+    }
+
+    #[tokio::test]
+    async fn never_leaves_responses_alone() {
+        let large = "Ada".repeat(100);
+        let response = call(CompressionMode::Never, &large, "gzip").await;
+        assert_eq!(encoding(&response), None);
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            format!("Hello {large}!")
+        );
+    }
+
+    #[tokio::test]
+    async fn force_compresses_small_responses() {
+        let response = call(CompressionMode::Force("gzip"), "Ada", "gzip").await;
+        assert_eq!(encoding(&response), Some("gzip"));
+    }
+
+    #[tokio::test]
+    async fn force_falls_back_to_identity() {
+        let response = call(CompressionMode::Force("gzip"), "Ada", "identity").await;
+        assert_eq!(encoding(&response), None);
+        let large = "Ada".repeat(100);
+        let response = call(CompressionMode::Force("br"), &large, "gzip").await;
+        assert_eq!(encoding(&response), None);
+    }
+
+    #[tokio::test]
+    async fn responses_can_turn_it_off() {
+        async fn blob(call: RpcCallParts, request: HelloRequest) -> RpcResult<HelloResponse> {
+            let response = RpcResponse::new(HelloResponse {
+                message: request.name.repeat(100),
+            });
+            Ok(call.respond(response.no_compression()))
+        }
+
+        let router = RpcRouter::new()
+            .rpc_with_options(
+                RouteOptions::new().compression(CompressionMode::Force("gzip")),
+                |router: RpcRouter| router.rpc_method(unary(SAY_HELLO, blob)),
+            )
+            .layer(recommended());
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        let response = client(router).send(request).await;
+        assert_eq!(encoding(&response), None);
+    }
+
+    async fn slow(request: HelloRequest) -> RpcResult<HelloResponse> {
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        say_hello(request).await
+    }
+
+    /// A call of [`slow`] behind `layers`, from a client accepting gzip.
+    async fn call_slow(layers: RecommendedLayers, request_id: Option<&str>) -> TestResponse {
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, slow))
+            .layer(layers.timeout(Duration::from_millis(10)));
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        let headers = request.headers_mut();
+        headers.insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+        if let Some(request_id) = request_id {
+            headers.insert(REQUEST_ID, HeaderValue::from_str(request_id).unwrap());
+        }
+        client(router).send(request).await
+    }
+
+    fn timed_out() -> RpcError {
+        RpcError::new(
+            RpcErrorCode::DeadlineExceeded,
+            "The server's timeout of 10ms elapsed".to_string(),
+        )
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn compresses_the_errors_of_the_timeout() {
+        use std::io::Read;
+
+        let response = call_slow(recommended(), None).await;
+        assert_eq!(response.status, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(response.content_type(), Some("application/json"));
+        assert_eq!(response.headers[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(
+            response.headers[header::CONTENT_LENGTH],
+            response.body.len().to_string()
+        );
+
+        let mut json = vec![];
+        flate2::read::GzDecoder::new(response.body.as_ref())
+            .read_to_end(&mut json)
+            .unwrap();
+        assert_eq!(
+            json,
+            br#"{"code":"deadline_exceeded","message":"The server's timeout of 10ms elapsed"}"#
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_the_errors_of_the_timeout_as_they_are_without_compression() {
+        let response = call_slow(recommended().without_compression(), None).await;
+        assert!(!response.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(response.error(), Some(timed_out()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn puts_request_ids_on_the_errors_of_the_timeout() {
+        let response = call_slow(recommended().without_compression(), Some("req-1")).await;
+        assert_eq!(
+            response.error().map(|error| error.code),
+            Some(timed_out().code)
+        );
+        assert_eq!(response.headers[REQUEST_ID], "req-1");
+
+        let response = call_slow(recommended().without_compression(), None).await;
+        let generated = response.headers[REQUEST_ID].to_str().unwrap();
+        assert_eq!(generated.len(), 36, "{generated}");
+
+        let response = call_slow(recommended().without_request_id(), None).await;
+        assert!(!response.headers.contains_key(REQUEST_ID));
+    }
+
+    /// Sends `body` to `path` over a raw HTTP/1.1 connection to `router` behind the
+    /// [`recommended`] layers, returning the response head and the bytes after it.
+    async fn raw_call(path: &str, headers: &str, body: &[u8]) -> (String, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, say_hello))
+            .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+            .layer(recommended());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, router.into_router()).await });
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let head = format!(
+            "POST {path} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n{headers}\
+             content-length: {}\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = response.split_off(end);
+        (String::from_utf8(response).unwrap().to_lowercase(), body)
+    }
+
+    /// The `content-length` of a response head, checking it's not chunked too.
+    fn content_length(head: &str) -> Option<usize> {
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .map(|length| length.trim().parse().unwrap());
+        if length.is_some() {
+            assert!(!head.contains("transfer-encoding"), "{head}");
+        }
+        length
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn sends_the_exact_length_of_json_responses() {
+        let (head, body) = raw_call(
+            SAY_HELLO,
+            "content-type: application/json\r\n",
+            br#"{"name":"Ada"}"#,
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 200"), "{head}");
+        assert_eq!(content_length(&head), Some(body.len()));
+        assert_eq!(body, br#"{"message":"Hello Ada!"}"#);
+    }
+
+    #[tokio::test]
+    async fn sends_the_exact_length_of_proto_responses_and_errors() {
+        let (head, body) = raw_call(
+            SAY_HELLO,
+            "content-type: application/proto\r\n",
+            &hello("Ada").encode_to_vec(),
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 200"), "{head}");
+        assert_eq!(content_length(&head), Some(body.len()));
+        assert_eq!(
+            HelloResponse::decode(body.as_slice()).unwrap().message,
+            "Hello Ada!"
+        );
+
+        let (head, body) =
+            raw_call(SAY_HELLO, "content-type: application/proto\r\n", b"\xff").await;
+        assert!(head.starts_with("http/1.1 400"), "{head}");
+        assert_eq!(content_length(&head), Some(body.len()));
+        assert!(body.starts_with(br#"{"code":"invalid_argument""#));
+    }
+
+    #[tokio::test]
+    async fn sends_the_compressed_length_of_gzipped_responses() {
+        use std::io::Read;
+
+        let name = "Ada".repeat(100);
+        let (head, body) = raw_call(
+            SAY_HELLO,
+            "content-type: application/proto\r\naccept-encoding: gzip\r\n",
+            &hello(&name).encode_to_vec(),
+        )
+        .await;
+        assert!(head.contains("content-encoding: gzip"), "{head}");
+        assert_eq!(content_length(&head), Some(body.len()));
+
+        let mut decompressed = vec![];
+        flate2::read::GzDecoder::new(body.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(
+            HelloResponse::decode(decompressed.as_slice())
+                .unwrap()
+                .message,
+            format!("Hello {name}!")
+        );
+    }
+
+    #[tokio::test]
+    async fn never_sends_a_length_for_streams() {
+        let message = hello("Ada").encode_to_vec();
+        let mut frame = vec![0];
+        frame.extend((message.len() as u32).to_be_bytes());
+        frame.extend(message);
+
+        for headers in [
+            "content-type: application/connect+proto\r\n",
+            "content-type: application/connect+proto\r\naccept-encoding: gzip\r\n",
+        ] {
+            let (head, _) = raw_call(SAY_HELLO_STREAM, headers, &frame).await;
+            assert!(head.starts_with("http/1.1 200"), "{head}");
+            assert_eq!(content_length(&head), None, "{head}");
+            assert!(head.contains("transfer-encoding: chunked"), "{head}");
+            assert!(!head.contains("content-encoding"), "{head}");
+        }
+
+        // Nor for the errors of streams, which are a frame of the stream too.
+        let (head, _) = raw_call(
+            SAY_HELLO_STREAM,
+            "content-type: application/connect+proto\r\n",
+            b"\x00\x00",
+        )
+        .await;
+        assert!(head.starts_with("http/1.1 200"), "{head}");
+        assert_eq!(content_length(&head), None, "{head}");
+    }
+
+    /// Reports the negotiated response encoding and the one sent, after asking for identity or
+    /// gzip if that's the name, padded to be worth compressing.
+    async fn negotiating(
+        mut compression: RpcCompressionInfo,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        let negotiated = compression.response_encoding.clone();
+        let sent = match request.name.as_str() {
+            "identity" => compression.set_response_encoding(None),
+            "gzip" => compression.set_response_encoding(Some("gzip")),
+            _ => compression.response_encoding.as_deref(),
+        };
+        Ok(HelloResponse {
+            message: format!(
+                "{} {} {}",
+                negotiated.as_deref().unwrap_or("identity"),
+                sent.unwrap_or("identity"),
+                "padding".repeat(20)
+            ),
+        })
+    }
+
+    /// A call of [`negotiating`] from a client sending `accept_encoding`, if any.
+    async fn negotiate(name: &str, accept_encoding: Option<&str>) -> TestResponse {
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, negotiating))
+            .layer(recommended());
+        let mut request = proto_request(SAY_HELLO, &hello(name));
+        if let Some(accept_encoding) = accept_encoding {
+            request.headers_mut().insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_str(accept_encoding).unwrap(),
+            );
+        }
+        client(router).send(request).await
+    }
+
+    fn encodings(response: &TestResponse) -> String {
+        let message = message::<HelloResponse>(response).message;
+        message.rsplit_once(' ').unwrap().0.to_string()
+    }
+
+    #[tokio::test]
+    async fn tells_handlers_the_negotiated_compression() {
+        let response = negotiate("Ada", Some("gzip")).await;
+        assert_eq!(encoding(&response), Some("gzip"));
+
+        let response = negotiate("Ada", None).await;
+        assert_eq!(encoding(&response), None);
+        assert_eq!(encodings(&response), "identity identity");
+    }
+
+    #[tokio::test]
+    async fn lets_handlers_force_identity_despite_gzip() {
+        let response = negotiate("identity", Some("gzip")).await;
+        assert_eq!(encoding(&response), None);
+        // Not compressed twice either: the body is the message as it is.
+        assert_eq!(encodings(&response), "gzip identity");
+    }
+
+    #[tokio::test]
+    async fn falls_back_from_gzip_the_client_doesnt_accept() {
+        let (recorder, _guard) = traced();
+
+        let response = negotiate("gzip", Some("identity")).await;
+        assert_eq!(encoding(&response), None);
+        assert_eq!(encodings(&response), "identity identity");
+
+        let warnings = recorder.events("axum_connect::codec");
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].field("requested"), Some("gzip"));
+        assert_eq!(warnings[0].field("sent"), Some("identity"));
+    }
+}
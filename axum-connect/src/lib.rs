@@ -1,19 +1,88 @@
+pub mod any;
+pub mod audit;
+pub mod auth;
+pub mod batch;
+pub mod breaker;
+#[cfg(feature = "capture")]
+pub mod capture;
+pub mod client;
+pub mod codec;
+mod concurrency;
+pub mod config;
+pub mod context;
+mod deadline;
+#[cfg(feature = "debug-routes")]
+pub mod debug_routes;
+pub mod defaults;
+pub mod deprecation;
+pub mod drain;
 pub mod error;
+pub mod events;
+#[cfg(feature = "field-mask")]
+pub mod field_mask;
 pub mod handler;
+pub mod hooks;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod layers;
+pub mod logging;
+#[cfg(feature = "prometheus")]
+pub mod metrics;
+#[cfg(feature = "pagination")]
+pub mod pagination;
 pub mod parts;
+pub mod ping;
+mod pool;
+pub mod proxy;
+pub mod ratelimit;
+pub mod request;
 pub mod response;
+pub mod rest;
+pub mod resume;
+pub mod retry;
 pub mod router;
+#[cfg(feature = "serve")]
+pub mod serve;
+mod shutdown;
+pub mod signing;
+#[cfg(feature = "sse")]
+mod sse;
+pub mod stream;
+pub mod tenant;
+#[cfg(test)]
+mod test_util;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+#[cfg(feature = "token-cache")]
+pub mod token_cache;
+pub mod trace;
+pub mod verify;
+pub mod well_known;
+
+#[cfg(feature = "macros")]
+pub use axum_connect_macros::rpc_debug_handler;
+#[cfg(feature = "debug-metrics")]
+pub use pool::{buffer_pool_stats, BufferPoolStats};
+#[cfg(feature = "serve")]
+pub use serve::serve;
+#[cfg(all(feature = "serve", unix))]
+pub use serve::serve_unix;
 
 // Re-export several crates
 pub use futures;
 pub use pbjson;
 pub use pbjson_types;
 pub use prost;
+#[cfg(feature = "field-mask")]
+pub use prost_reflect;
 pub use serde;
 
 pub mod prelude {
     pub use crate::error::*;
     pub use crate::parts::*;
+    pub use crate::request::*;
     pub use crate::response::*;
-    pub use crate::router::RpcRouterExt;
+    pub use crate::router::{RpcRouter, RpcRouterExt, RpcServiceCollection};
 }
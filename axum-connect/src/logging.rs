@@ -0,0 +1,759 @@
+//! A Connect-aware access log, one event per RPC.
+//!
+//! Generic HTTP trace layers can't see the Connect error code (it lives in the response body, and
+//! streaming responses are always 200), so every RPC leaves an [`RpcCallStats`] in the response
+//! extensions for [`RpcLogLayer`] (or any outer layer) to pick up.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    http::{request, Request},
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use tower::{Layer, Service};
+
+use crate::{
+    codec::ContentType,
+    error::{RpcError, RpcErrorCode},
+    events::{CallEvents, RpcEventBus},
+    router::RpcMethodInfo,
+};
+
+/// Counters for a single RPC, found in the response extensions as an `Arc<RpcCallStats>`.
+///
+/// Response counts are updated as the response body is sent, so for streams they are only final
+/// once the body is done. Byte counts are the body bytes as sent on the wire, envelope prefixes
+/// included, after any stream compression (`RouteOptions::stream_compression`), and for unary
+/// responses after the gzip of [`recommended`](crate::layers::recommended) layers.
+#[derive(Debug, Default)]
+pub struct RpcCallStats {
+    pub(crate) streaming: bool,
+    pub(crate) request_messages: u64,
+    pub(crate) response_messages: AtomicU64,
+    pub(crate) request_bytes: AtomicU64,
+    pub(crate) response_bytes: AtomicU64,
+    pub(crate) compressed_frames: AtomicU64,
+    pub(crate) uncompressed_frames: AtomicU64,
+    pub(crate) handler_latency: Mutex<Option<Duration>>,
+    pub(crate) code: Mutex<Option<RpcErrorCode>>,
+    pub(crate) internal_message: Mutex<Option<String>>,
+    pub(crate) deprecated: AtomicBool,
+    pub(crate) invalid_transport: AtomicBool,
+}
+
+impl RpcCallStats {
+    pub(crate) fn set_error(&self, error: &RpcError) {
+        *self.code.lock().unwrap() = Some(error.code.clone());
+        *self.internal_message.lock().unwrap() = error.internal_message().map(str::to_string);
+    }
+
+    pub(crate) fn set_deprecated(&self) {
+        self.deprecated.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_invalid_transport(&self) {
+        self.invalid_transport.store(true, Ordering::Relaxed);
+    }
+
+    pub fn streaming(&self) -> bool {
+        self.streaming
+    }
+
+    pub fn request_messages(&self) -> u64 {
+        self.request_messages
+    }
+
+    pub fn response_messages(&self) -> u64 {
+        self.response_messages.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the request body read by the handler.
+    pub fn request_bytes(&self) -> u64 {
+        self.request_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of the response body sent so far.
+    pub fn response_bytes(&self) -> u64 {
+        self.response_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Messages of a response stream sent gzipped, see `RouteOptions::stream_compression`.
+    pub fn compressed_frames(&self) -> u64 {
+        self.compressed_frames.load(Ordering::Relaxed)
+    }
+
+    /// Messages of a response stream sent as they are, under the compression threshold or to a
+    /// client that doesn't accept gzip.
+    pub fn uncompressed_frames(&self) -> u64 {
+        self.uncompressed_frames.load(Ordering::Relaxed)
+    }
+
+    /// From the request reaching the route until the handler returned its response (for streams,
+    /// until it returned the stream). `None` for responses that didn't come from an RPC route.
+    pub fn handler_latency(&self) -> Option<Duration> {
+        *self.handler_latency.lock().unwrap()
+    }
+
+    /// The Connect error code, or `None` if the RPC succeeded (so far).
+    pub fn code(&self) -> Option<RpcErrorCode> {
+        self.code.lock().unwrap().clone()
+    }
+
+    /// The [internal message](RpcError::with_internal_message) of the error, if it has one.
+    pub fn internal_message(&self) -> Option<String> {
+        self.internal_message.lock().unwrap().clone()
+    }
+
+    /// Whether the RPC is [deprecated](crate::deprecation::Deprecation).
+    pub fn deprecated(&self) -> bool {
+        self.deprecated.load(Ordering::Relaxed)
+    }
+
+    /// Whether the call was rejected for its transport, over HTTP/1.0 or (with
+    /// [`RpcConfig::require_host`](crate::config::RpcConfig::require_host)) without a `Host`
+    /// header. Those come from old health checkers and scanners, not Connect clients.
+    pub fn invalid_transport(&self) -> bool {
+        self.invalid_transport.load(Ordering::Relaxed)
+    }
+}
+
+/// Fills in the byte counts and handler latency of the [`RpcCallStats`] of one RPC route, and
+/// publishes to the [`RpcEventBus`] if there is one. Mounted by
+/// [`RpcRouter::rpc_route`](crate::router::RpcRouter::rpc_route).
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RpcCallStatsLayer;
+
+impl<S> Layer<S> for RpcCallStatsLayer {
+    type Service = RpcCallStatsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcCallStatsService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RpcCallStatsService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request<Body>> for RpcCallStatsService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let start = Instant::now();
+        let events = req
+            .extensions()
+            .get::<RpcEventBus>()
+            .map(|bus| CallEvents::start(bus, &req));
+
+        let request_bytes = Arc::new(AtomicU64::new(0));
+        let req = {
+            let request_bytes = request_bytes.clone();
+            req.map(|body| Body::new(CountingBody::new(body, request_bytes)))
+        };
+
+        let mut inner = self.inner.clone();
+        Box::pin(async move {
+            let response = inner.call(req).await?;
+            let stats = response.extensions().get::<Arc<RpcCallStats>>().cloned();
+            if stats.is_none() && events.is_none() {
+                return Ok(response);
+            }
+
+            if let Some(stats) = &stats {
+                *stats.handler_latency.lock().unwrap() = Some(start.elapsed());
+                // By now the handler has read all of the request it's going to.
+                stats
+                    .request_bytes
+                    .store(request_bytes.load(Ordering::Relaxed), Ordering::Relaxed);
+            }
+
+            let counter = ResponseCounter { stats, events };
+            Ok(response.map(|body| Body::new(CountingBody::new(body, counter))))
+        })
+    }
+}
+
+/// Where a [`CountingBody`] adds up its bytes.
+trait ByteCounter: Send + Sync + Unpin + 'static {
+    fn add(&self, bytes: u64);
+}
+
+impl ByteCounter for Arc<AtomicU64> {
+    fn add(&self, bytes: u64) {
+        self.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Counts response bytes into the call's stats, and publishes its response events.
+struct ResponseCounter {
+    stats: Option<Arc<RpcCallStats>>,
+    events: Option<CallEvents>,
+}
+
+impl ByteCounter for ResponseCounter {
+    fn add(&self, bytes: u64) {
+        if let Some(stats) = &self.stats {
+            stats.response_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+        if let Some(events) = &self.events {
+            events.frame();
+        }
+    }
+}
+
+impl Drop for ResponseCounter {
+    fn drop(&mut self) {
+        if let Some(events) = &self.events {
+            events.complete(self.stats.as_ref().and_then(|stats| stats.code()));
+        }
+    }
+}
+
+/// Counts the data bytes of a body as they go by. Unlike going through a `Stream`, this keeps the
+/// size hint, so unary responses still get a `Content-Length`.
+struct CountingBody<C> {
+    inner: Body,
+    counter: C,
+}
+
+impl<C: ByteCounter> CountingBody<C> {
+    fn new(inner: Body, counter: C) -> Self {
+        Self { inner, counter }
+    }
+}
+
+impl<C: ByteCounter> HttpBody for CountingBody<C> {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let poll = pin!(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                self.counter.add(data.len() as u64);
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// A single completed RPC, as seen by [`RpcLogLayer`].
+#[derive(Clone, Debug)]
+pub struct RpcLog {
+    /// The request path, eg. `/hello.HelloWorldService/SayHello`, or the alias path for calls to
+    /// an [`RpcRouter::alias_service`](crate::router::RpcRouter::alias_service) alias.
+    pub procedure: String,
+    /// `json`, `proto` or `unknown` if the request didn't say.
+    pub codec: &'static str,
+    pub streaming: bool,
+    /// Bytes read from the request body, as sent on the wire.
+    pub request_bytes: u64,
+    /// Bytes written to the response body, as sent on the wire.
+    pub response_bytes: u64,
+    pub request_messages: u64,
+    pub response_messages: u64,
+    /// From the start of the request until the last response byte was sent.
+    pub latency: Duration,
+    /// See [`RpcCallStats::handler_latency`].
+    pub handler_latency: Option<Duration>,
+    /// The Connect error code, or `None` if the RPC succeeded.
+    pub code: Option<RpcErrorCode>,
+    /// The [internal message](RpcError::with_internal_message) of the error, never sent to the
+    /// client.
+    pub internal_message: Option<String>,
+    /// Request headers, with the values of credentials and deny-listed keys replaced by
+    /// `[redacted]`, see [`RpcLogLayer::new`].
+    pub metadata: Vec<(String, String)>,
+    /// See [`RpcCallStats::deprecated`], eg. to count the calls left to deprecated RPCs.
+    pub deprecated: bool,
+    /// See [`RpcCallStats::invalid_transport`], eg. to leave those calls out of error rates.
+    pub invalid_transport: bool,
+}
+
+type OnComplete = Arc<dyn Fn(&RpcLog) + Send + Sync>;
+
+/// Headers whose values are credentials, redacted by default.
+const CREDENTIAL_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-amz-security-token",
+];
+
+/// Headers with one of these in their name are taken for credentials too, eg. `x-auth-token`,
+/// `x-session-id` or `x-client-secret`.
+const CREDENTIAL_WORDS: &[&str] = &[
+    "auth",
+    "token",
+    "secret",
+    "password",
+    "passwd",
+    "api-key",
+    "apikey",
+    "session",
+    "credential",
+    "csrf",
+    "xsrf",
+    "signature",
+];
+
+/// Emits one [`RpcLog`] per RPC once its response has been sent, including for streams.
+///
+/// By default it's emitted as an `info` level `tracing` event on the `axum_connect::rpc` target;
+/// use [`on_complete`](RpcLogLayer::on_complete) to handle it yourself. Mount it on an
+/// [`RpcRouter`](crate::router::RpcRouter) with `.layer(...)` so it only sees RPC routes.
+#[derive(Clone)]
+pub struct RpcLogLayer {
+    on_complete: OnComplete,
+    redacted: Arc<Vec<String>>,
+}
+
+impl RpcLogLayer {
+    /// Credentials are redacted by default: `authorization`, `proxy-authorization`, `cookie`,
+    /// `set-cookie`, `x-api-key`, `x-amz-security-token`, and every header with `auth`, `token`,
+    /// `secret`, `password`, `passwd`, `api-key`, `apikey`, `session`, `credential`, `csrf`,
+    /// `xsrf` or `signature` in its name.
+    pub fn new() -> Self {
+        Self {
+            on_complete: Arc::new(emit_event),
+            redacted: Arc::new(vec![]),
+        }
+    }
+
+    /// Calls `f` with every completed RPC instead of emitting a `tracing` event.
+    pub fn on_complete<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RpcLog) + Send + Sync + 'static,
+    {
+        self.on_complete = Arc::new(f);
+        self
+    }
+
+    /// Adds a metadata key (case insensitive) whose value should never be logged, besides the
+    /// credentials redacted by default.
+    pub fn redact(mut self, key: &str) -> Self {
+        Arc::make_mut(&mut self.redacted).push(key.to_lowercase());
+        self
+    }
+
+    fn redacts(&self, key: &str) -> bool {
+        CREDENTIAL_HEADERS.contains(&key)
+            || CREDENTIAL_WORDS.iter().any(|word| key.contains(word))
+            || self.redacted.iter().any(|redacted| redacted == key)
+    }
+}
+
+impl Default for RpcLogLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RpcLogLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcLogLayer")
+            .field("redacted", &self.redacted)
+            .finish()
+    }
+}
+
+impl<S> Layer<S> for RpcLogLayer {
+    type Service = RpcLogService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcLogService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// The service produced by [`RpcLogLayer`].
+#[derive(Clone)]
+pub struct RpcLogService<S> {
+    inner: S,
+    layer: RpcLogLayer,
+}
+
+impl<S> Service<Request<Body>> for RpcLogService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let start = Instant::now();
+        let layer = self.layer.clone();
+
+        let (mut parts, body) = req.into_parts();
+        // Aliases are rewritten to the original paths before routing, but keep their own info.
+        let procedure = match parts.extensions.get::<RpcMethodInfo>() {
+            Some(info) => info.path.clone(),
+            None => parts.uri.path().to_string(),
+        };
+        let codec = request_codec(&mut parts);
+        let metadata = parts
+            .headers
+            .iter()
+            .map(|(key, value)| {
+                let value = if layer.redacts(key.as_str()) {
+                    "[redacted]".to_string()
+                } else {
+                    String::from_utf8_lossy(value.as_bytes()).into_owned()
+                };
+                (key.to_string(), value)
+            })
+            .collect();
+
+        // Count request bytes as the handler reads them.
+        let request_bytes = Arc::new(AtomicU64::new(0));
+        let body = Body::new(CountingBody::new(body, request_bytes.clone()));
+        let req = Request::from_parts(parts, body);
+
+        let mut inner = self.inner.clone();
+        Box::pin(async move {
+            let response = inner.call(req).await?;
+            let stats = response.extensions().get::<Arc<RpcCallStats>>().cloned();
+
+            let guard = CompletionGuard {
+                log: Some(RpcLog {
+                    procedure,
+                    codec,
+                    streaming: false,
+                    request_bytes: 0,
+                    response_bytes: 0,
+                    request_messages: 0,
+                    response_messages: 0,
+                    latency: Duration::ZERO,
+                    handler_latency: None,
+                    code: None,
+                    internal_message: None,
+                    metadata,
+                    deprecated: false,
+                    invalid_transport: false,
+                }),
+                start,
+                request_bytes,
+                response_bytes: AtomicU64::new(0),
+                stats,
+                on_complete: layer.on_complete,
+            };
+
+            // The log is emitted when the body has been sent (or dropped), so stream counts and
+            // latency cover the whole response.
+            Ok(response.map(move |body| Body::new(CountingBody::new(body, guard))))
+        })
+    }
+}
+
+struct CompletionGuard {
+    log: Option<RpcLog>,
+    start: Instant,
+    request_bytes: Arc<AtomicU64>,
+    response_bytes: AtomicU64,
+    stats: Option<Arc<RpcCallStats>>,
+    on_complete: OnComplete,
+}
+
+impl ByteCounter for CompletionGuard {
+    fn add(&self, bytes: u64) {
+        self.response_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+impl Drop for CompletionGuard {
+    fn drop(&mut self) {
+        let Some(mut log) = self.log.take() else {
+            return;
+        };
+
+        log.latency = self.start.elapsed();
+        log.request_bytes = self.request_bytes.load(Ordering::Relaxed);
+        log.response_bytes = self.response_bytes.load(Ordering::Relaxed);
+
+        if let Some(stats) = &self.stats {
+            log.streaming = stats.streaming;
+            log.request_messages = stats.request_messages;
+            log.response_messages = stats.response_messages();
+            log.handler_latency = stats.handler_latency();
+            log.code = stats.code();
+            log.internal_message = stats.internal_message();
+            log.deprecated = stats.deprecated();
+            log.invalid_transport = stats.invalid_transport();
+        }
+
+        (self.on_complete)(&log);
+    }
+}
+
+fn request_codec(parts: &mut request::Parts) -> &'static str {
+    // Unary GET requests carry the codec in the query.
+    if let Some(query) = parts.uri.query() {
+        for pair in query.split('&') {
+            match pair {
+                "encoding=json" => return "json",
+                "encoding=proto" => return "proto",
+                _ => {}
+            }
+        }
+    }
+
+    match ContentType::cached(&mut parts.extensions, &parts.headers) {
+        Some(content_type) if content_type.is_binary() => "proto",
+        Some(_) => "json",
+        None => "unknown",
+    }
+}
+
+fn emit_event(log: &RpcLog) {
+    tracing::info!(
+        target: "axum_connect::rpc",
+        procedure = %log.procedure,
+        codec = log.codec,
+        streaming = log.streaming,
+        request_bytes = log.request_bytes,
+        response_bytes = log.response_bytes,
+        request_messages = log.request_messages,
+        response_messages = log.response_messages,
+        latency_ms = log.latency.as_secs_f64() * 1000.0,
+        handler_latency_ms = log.handler_latency.map(|l| l.as_secs_f64() * 1000.0),
+        code = log.code.as_ref().map(|c| c.to_string()).unwrap_or_else(|| "ok".to_string()),
+        internal_message = log.internal_message.as_deref(),
+        metadata = ?log.metadata,
+        deprecated = log.deprecated,
+        invalid_transport = log.invalid_transport,
+        "rpc completed"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        router::RpcRouter,
+        test_util::{
+            client, hello, hello_router, message, messages, proto_request, stream_request, unary,
+            HelloRequest, HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+    };
+
+    const FAIL: &str = "/hello.HelloWorldService/Fail";
+
+    async fn fail(_: HelloRequest) -> Result<HelloResponse, RpcError> {
+        Err(
+            RpcError::new(RpcErrorCode::NotFound, "no such greeting".into())
+                .with_internal_message("looked everywhere"),
+        )
+    }
+
+    /// The router with a log layer, and the logs it emits.
+    fn logged(router: RpcRouter, layer: RpcLogLayer) -> (RpcRouter, Arc<Mutex<Vec<RpcLog>>>) {
+        let logs = Arc::new(Mutex::new(vec![]));
+        let layer = layer.on_complete({
+            let logs = logs.clone();
+            move |log| logs.lock().unwrap().push(log.clone())
+        });
+        (router.layer(layer), logs)
+    }
+
+    fn only(logs: &Mutex<Vec<RpcLog>>) -> RpcLog {
+        let logs = logs.lock().unwrap();
+        assert_eq!(logs.len(), 1, "{logs:?}");
+        logs[0].clone()
+    }
+
+    fn header<'a>(log: &'a RpcLog, key: &str) -> &'a str {
+        let (_, value) = log.metadata.iter().find(|(k, _)| k == key).unwrap();
+        value
+    }
+
+    #[tokio::test]
+    async fn logs_a_success() {
+        let (router, logs) = logged(hello_router(), RpcLogLayer::new());
+        let request = proto_request(SAY_HELLO, &hello("Ada"));
+        let request_bytes = hello("Ada").encoded_len() as u64;
+        let response = client(router).send(request).await;
+        message::<HelloResponse>(&response);
+
+        let log = only(&logs);
+        assert_eq!(log.procedure, SAY_HELLO);
+        assert_eq!(log.codec, "proto");
+        assert!(!log.streaming);
+        assert_eq!(log.request_bytes, request_bytes);
+        assert_eq!(log.response_bytes, response.body.len() as u64);
+        assert_eq!(log.request_messages, 1);
+        assert_eq!(log.response_messages, 1);
+        assert_eq!(log.code, None);
+        assert!(log
+            .handler_latency
+            .is_some_and(|handler| handler <= log.latency));
+    }
+
+    #[tokio::test]
+    async fn logs_an_error() {
+        let router = RpcRouter::new().rpc_method(unary(FAIL, fail));
+        let (router, logs) = logged(router, RpcLogLayer::new());
+        let response = client(router)
+            .send(proto_request(FAIL, &hello("Ada")))
+            .await;
+        assert_eq!(response.error().unwrap().code, RpcErrorCode::NotFound);
+
+        let log = only(&logs);
+        assert_eq!(log.code, Some(RpcErrorCode::NotFound));
+        assert_eq!(log.internal_message.as_deref(), Some("looked everywhere"));
+        assert_eq!(log.response_messages, 0);
+    }
+
+    #[tokio::test]
+    async fn logs_a_stream_once_it_ends() {
+        let (router, logs) = logged(hello_router(), RpcLogLayer::new());
+        let request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+        let response = client(router).send(request).await;
+        assert_eq!(messages::<HelloResponse>(&response).len(), 3);
+
+        let log = only(&logs);
+        assert!(log.streaming);
+        assert_eq!(log.request_messages, 1);
+        assert_eq!(log.response_messages, 3);
+        assert_eq!(log.response_bytes, response.body.len() as u64);
+        assert_eq!(log.code, None);
+    }
+
+    #[tokio::test]
+    async fn redacts_credentials() {
+        let (router, logs) = logged(hello_router(), RpcLogLayer::new().redact("X-Customer"));
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        for (key, value) in [
+            ("authorization", "Bearer secret"),
+            ("proxy-authorization", "Basic secret"),
+            ("cookie", "session=secret"),
+            ("x-api-key", "secret"),
+            ("x-auth-token", "secret"),
+            ("x-session-id", "secret"),
+            ("x-client-secret", "secret"),
+            ("x-customer", "ada@example.com"),
+            ("x-request-id", "42"),
+        ] {
+            request
+                .headers_mut()
+                .insert(key, HeaderValue::from_static(value));
+        }
+        client(router).send(request).await;
+
+        let log = only(&logs);
+        for key in [
+            "authorization",
+            "proxy-authorization",
+            "cookie",
+            "x-api-key",
+            "x-auth-token",
+            "x-session-id",
+            "x-client-secret",
+            "x-customer",
+        ] {
+            assert_eq!(header(&log, key), "[redacted]", "{key}");
+        }
+        assert_eq!(header(&log, "x-request-id"), "42");
+        assert_eq!(header(&log, "content-type"), "application/proto");
+    }
+
+    /// The router, and the stats of the calls it answers as the layers outside it see them.
+    fn with_stats(router: RpcRouter) -> (RpcRouter, Arc<Mutex<Vec<Arc<RpcCallStats>>>>) {
+        let stats = Arc::new(Mutex::new(vec![]));
+        let router = router.layer(axum::middleware::map_response({
+            let stats = stats.clone();
+            move |response: axum::response::Response| {
+                let call = response.extensions().get::<Arc<RpcCallStats>>().cloned();
+                stats.lock().unwrap().extend(call);
+                std::future::ready(response)
+            }
+        }));
+        (router, stats)
+    }
+
+    #[cfg(feature = "tower-http")]
+    #[tokio::test]
+    async fn counts_the_gzipped_bytes_of_unary_calls() {
+        let (router, stats) = with_stats(hello_router().layer(crate::layers::recommended()));
+        let request = hello(&"Ada".repeat(100));
+        let request_bytes = request.encoded_len() as u64;
+        let mut request = proto_request(SAY_HELLO, &request);
+        request.headers_mut().insert(
+            axum::http::header::ACCEPT_ENCODING,
+            axum::http::HeaderValue::from_static("gzip"),
+        );
+
+        let response = client(router).send(request).await;
+
+        assert_eq!(response.headers["content-encoding"], "gzip");
+        let stats = stats.lock().unwrap()[0].clone();
+        assert!(!stats.streaming());
+        assert_eq!(stats.request_bytes(), request_bytes);
+        assert_eq!(stats.response_bytes(), response.body.len() as u64);
+        assert!(stats.response_bytes() < 300);
+        assert_eq!(stats.response_messages(), 1);
+        assert!(stats.handler_latency().is_some());
+    }
+
+    #[tokio::test]
+    async fn accumulates_the_bytes_of_stream_frames() {
+        let (router, stats) = with_stats(hello_router());
+        let request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+
+        let response = client(router).send(request).await;
+
+        assert_eq!(messages::<HelloResponse>(&response).len(), 3);
+        let stats = stats.lock().unwrap()[0].clone();
+        assert!(stats.streaming());
+        assert_eq!(stats.request_messages(), 1);
+        assert_eq!(stats.response_messages(), 3);
+        assert_eq!(stats.response_bytes(), response.body.len() as u64);
+        assert_eq!(stats.code(), None);
+    }
+}
@@ -0,0 +1,518 @@
+//! Prometheus metrics of the RPCs of a router, see [`PrometheusMetrics`].
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::{self, Write},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::header,
+    response::IntoResponse,
+    routing::{get, MethodRouter},
+};
+
+use crate::{
+    audit::RpcAuditor,
+    breaker::{CircuitBreaker, CircuitState},
+    drain::DrainController,
+    logging::RpcLog,
+    router::RpcMethodInfo,
+};
+
+/// The label value of calls to paths that aren't a mounted RPC, eg. those the fallback answers.
+pub const UNKNOWN: &str = "unknown";
+
+/// The Prometheus defaults, in seconds.
+const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// A series by its labels: service, method and code.
+type SeriesKey = (String, String, String);
+
+/// Counters and latency histograms of completed RPCs, by `service`, `method` and `code` (`ok` or
+/// the Connect error code), served in the Prometheus text format by
+/// [`RpcRouter::mount_metrics`](crate::router::RpcRouter::mount_metrics):
+///
+/// - `rpc_server_calls_total`, a counter of the calls.
+/// - `rpc_server_duration_seconds`, a histogram of their latency, until the last response byte
+///   was sent, so streams count in full.
+///
+/// Calls rejected for their [transport](crate::logging::RpcCallStats::invalid_transport), eg.
+/// HTTP/1.0 from old health checkers, aren't in those, so they don't count against error rates.
+/// `rpc_server_invalid_transport_total` counts them, by `service` and `method`. With
+/// [`in_flight`](PrometheusMetrics::in_flight), there's also `rpc_server_in_flight`, a gauge of
+/// the calls in flight, with [`audit`](PrometheusMetrics::audit),
+/// `rpc_server_audit_dropped_total`, a counter of the audit records dropped, and with
+/// [`circuit_breaker`](PrometheusMetrics::circuit_breaker), `rpc_server_circuit_state`, a gauge of
+/// the state of each route's circuit (0 closed, 1 half-open, 2 open), and
+/// `rpc_server_circuit_rejected_total`, a counter of the calls its breaker failed fast.
+///
+/// Calls to paths that aren't a mounted RPC are all labeled [`UNKNOWN`], so scanners probing
+/// random paths can't blow up the number of series. Clones share the same series.
+#[derive(Clone)]
+pub struct PrometheusMetrics {
+    buckets: Arc<Vec<f64>>,
+    series: Arc<Mutex<BTreeMap<SeriesKey, Series>>>,
+    /// By service and method.
+    invalid_transport: Arc<Mutex<BTreeMap<(String, String), u64>>>,
+    drain: Option<DrainController>,
+    auditor: Option<RpcAuditor>,
+    breakers: Vec<CircuitBreaker>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Series {
+    /// Per bucket, not cumulative.
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl PrometheusMetrics {
+    /// With the default Prometheus buckets, from 5ms to 10s.
+    pub fn new() -> Self {
+        Self {
+            buckets: Arc::new(DEFAULT_BUCKETS.to_vec()),
+            series: Default::default(),
+            invalid_transport: Default::default(),
+            drain: None,
+            auditor: None,
+            breakers: vec![],
+        }
+    }
+
+    /// The upper bounds of the latency histogram buckets, in seconds. `+Inf` is always added.
+    pub fn buckets(mut self, mut buckets: Vec<f64>) -> Self {
+        buckets.retain(|bound| bound.is_finite());
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        self.buckets = Arc::new(buckets);
+        self
+    }
+
+    /// Serves the calls in flight counted by `drain`, the one of the router's
+    /// [`drain`](crate::router::RpcRouter::drain).
+    pub fn in_flight(mut self, drain: DrainController) -> Self {
+        self.drain = Some(drain);
+        self
+    }
+
+    /// Serves the records dropped by `auditor`, the one of the router's
+    /// [`audit`](crate::router::RpcRouter::audit).
+    pub fn audit(mut self, auditor: RpcAuditor) -> Self {
+        self.auditor = Some(auditor);
+        self
+    }
+
+    /// Serves the circuits of `breaker`, one of a
+    /// [`RouteOptions::circuit_breaker`](crate::router::RouteOptions::circuit_breaker). Call it
+    /// for each breaker of the router.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.breakers.push(breaker);
+        self
+    }
+
+    /// Records a completed call, with the service and method labels of `method`, or [`UNKNOWN`].
+    pub fn record(&self, method: Option<&RpcMethodInfo>, log: &RpcLog) {
+        let (service, method) = match method {
+            Some(info) => (info.service.clone(), info.method.clone()),
+            None => (UNKNOWN.to_string(), UNKNOWN.to_string()),
+        };
+        if log.invalid_transport {
+            *self
+                .invalid_transport
+                .lock()
+                .unwrap()
+                .entry((service, method))
+                .or_default() += 1;
+            return;
+        }
+        let code = log
+            .code
+            .as_ref()
+            .map(|code| code.to_string())
+            .unwrap_or_else(|| "ok".to_string());
+        let seconds = log.latency.as_secs_f64();
+
+        let mut series = self.series.lock().unwrap();
+        let series = series.entry((service, method, code)).or_default();
+        if series.buckets.is_empty() {
+            series.buckets = vec![0; self.buckets.len()];
+        }
+        if let Some(bucket) = self.buckets.iter().position(|bound| seconds <= *bound) {
+            series.buckets[bucket] += 1;
+        }
+        series.count += 1;
+        series.sum += seconds;
+    }
+
+    /// Every series in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap().clone();
+        let mut out = String::new();
+
+        out.push_str("# HELP rpc_server_calls_total Completed RPCs.\n");
+        out.push_str("# TYPE rpc_server_calls_total counter\n");
+        for (labels, series) in &series {
+            let _ = writeln!(
+                out,
+                "rpc_server_calls_total{{{}}} {}",
+                Labels(labels, None),
+                series.count
+            );
+        }
+
+        out.push_str("# HELP rpc_server_duration_seconds The latency of completed RPCs.\n");
+        out.push_str("# TYPE rpc_server_duration_seconds histogram\n");
+        for (labels, series) in &series {
+            let mut cumulative = 0;
+            for (bound, count) in self.buckets.iter().zip(&series.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "rpc_server_duration_seconds_bucket{{{}}} {}",
+                    Labels(labels, Some(&bound.to_string())),
+                    cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rpc_server_duration_seconds_bucket{{{}}} {}",
+                Labels(labels, Some("+Inf")),
+                series.count
+            );
+            let _ = writeln!(
+                out,
+                "rpc_server_duration_seconds_sum{{{}}} {}",
+                Labels(labels, None),
+                series.sum
+            );
+            let _ = writeln!(
+                out,
+                "rpc_server_duration_seconds_count{{{}}} {}",
+                Labels(labels, None),
+                series.count
+            );
+        }
+
+        out.push_str(
+            "# HELP rpc_server_invalid_transport_total RPCs rejected for their HTTP version or a \
+             missing Host.\n",
+        );
+        out.push_str("# TYPE rpc_server_invalid_transport_total counter\n");
+        for ((service, method), count) in self.invalid_transport.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "rpc_server_invalid_transport_total{{service=\"{}\",method=\"{}\"}} {}",
+                escape(service),
+                escape(method),
+                count
+            );
+        }
+
+        if let Some(drain) = &self.drain {
+            out.push_str("# HELP rpc_server_in_flight RPCs in flight.\n");
+            out.push_str("# TYPE rpc_server_in_flight gauge\n");
+            let _ = writeln!(out, "rpc_server_in_flight {}", drain.in_flight());
+        }
+
+        if let Some(auditor) = &self.auditor {
+            out.push_str(
+                "# HELP rpc_server_audit_dropped_total Audit records dropped because the queue \
+                 was full.\n",
+            );
+            out.push_str("# TYPE rpc_server_audit_dropped_total counter\n");
+            let _ = writeln!(out, "rpc_server_audit_dropped_total {}", auditor.dropped());
+        }
+
+        if !self.breakers.is_empty() {
+            let circuits = self
+                .breakers
+                .iter()
+                .flat_map(CircuitBreaker::circuits)
+                .collect::<Vec<_>>();
+            out.push_str(
+                "# HELP rpc_server_circuit_state The state of the route's circuit breaker, 0 \
+                 closed, 1 half-open, 2 open.\n",
+            );
+            out.push_str("# TYPE rpc_server_circuit_state gauge\n");
+            for circuit in &circuits {
+                let state = match circuit.state {
+                    CircuitState::Closed => 0,
+                    CircuitState::HalfOpen => 1,
+                    CircuitState::Open => 2,
+                };
+                let _ = writeln!(
+                    out,
+                    "rpc_server_circuit_state{{service=\"{}\",method=\"{}\"}} {}",
+                    escape(&circuit.service),
+                    escape(&circuit.method),
+                    state
+                );
+            }
+            out.push_str(
+                "# HELP rpc_server_circuit_rejected_total RPCs failed fast by an open circuit \
+                 breaker.\n",
+            );
+            out.push_str("# TYPE rpc_server_circuit_rejected_total counter\n");
+            for circuit in &circuits {
+                let _ = writeln!(
+                    out,
+                    "rpc_server_circuit_rejected_total{{service=\"{}\",method=\"{}\"}} {}",
+                    escape(&circuit.service),
+                    escape(&circuit.method),
+                    circuit.rejected
+                );
+            }
+        }
+        out
+    }
+
+    /// Records the calls of the RPCs in `methods` by their labels, other paths as [`UNKNOWN`].
+    pub(crate) fn recorder(
+        &self,
+        methods: &[RpcMethodInfo],
+    ) -> impl Fn(&RpcLog) + Send + Sync + 'static {
+        let methods = methods
+            .iter()
+            .map(|info| (info.path.clone(), info.clone()))
+            .collect::<HashMap<_, _>>();
+        let metrics = self.clone();
+        move |log| metrics.record(methods.get(&log.procedure), log)
+    }
+
+    pub(crate) fn method_router<S>(&self) -> MethodRouter<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let metrics = self.clone();
+        get(move || async move {
+            (
+                [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                metrics.render(),
+            )
+                .into_response()
+        })
+    }
+}
+
+impl Default for PrometheusMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for PrometheusMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrometheusMetrics")
+            .field("buckets", &self.buckets)
+            .field("in_flight", &self.drain.is_some())
+            .field("audit", &self.auditor.is_some())
+            .field("circuit_breakers", &self.breakers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Formats the labels of a series, with `le` for histogram buckets.
+struct Labels<'a>(&'a SeriesKey, Option<&'a str>);
+
+impl fmt::Display for Labels<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (service, method, code) = self.0;
+        write!(
+            f,
+            "service=\"{}\",method=\"{}\",code=\"{}\"",
+            escape(service),
+            escape(method),
+            code
+        )?;
+        if let Some(le) = self.1 {
+            write!(f, ",le=\"{}\"", le)?;
+        }
+        Ok(())
+    }
+}
+
+/// A label value, with `\`, `"` and newlines escaped.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::{body::Body, http::Request};
+
+    use super::*;
+    use crate::{
+        error::{RpcError, RpcErrorCode},
+        prelude::*,
+        test_util::{client, hello, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO},
+    };
+
+    async fn greet(request: HelloRequest) -> RpcResult<HelloResponse> {
+        match request.name.as_str() {
+            "nobody" => Err(RpcError::new(
+                RpcErrorCode::NotFound,
+                "No such user".to_string(),
+            )),
+            name => Ok(HelloResponse {
+                message: format!("Hello {name}!"),
+            }),
+        }
+    }
+
+    /// The samples of `rendered` by series, eg. `rpc_server_calls_total{...}`.
+    fn samples(rendered: &str) -> BTreeMap<&str, &str> {
+        rendered
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.rsplit_once(' ').unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn serves_the_series_of_the_calls_so_far() {
+        let metrics = PrometheusMetrics::new().buckets(vec![60.0]);
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, greet))
+                .mount_metrics("/metrics", metrics),
+        );
+        for name in ["Ada", "Grace", "nobody"] {
+            client.send(proto_request(SAY_HELLO, &hello(name))).await;
+        }
+        for path in ["/hello.HelloWorldService/Nope", "/wp-login.php"] {
+            client.send(proto_request(path, &hello("Ada"))).await;
+        }
+
+        let response = client
+            .send(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await;
+        assert_eq!(response.content_type(), Some("text/plain; version=0.0.4"));
+        let rendered = String::from_utf8(response.body.to_vec()).unwrap();
+        let samples = samples(&rendered);
+
+        let ok = r#"service="hello.HelloWorldService",method="SayHello",code="ok""#;
+        let not_found = r#"service="hello.HelloWorldService",method="SayHello",code="not_found""#;
+        for (series, value) in [
+            (format!("rpc_server_calls_total{{{ok}}}"), "2"),
+            (format!("rpc_server_calls_total{{{not_found}}}"), "1"),
+            (
+                format!(r#"rpc_server_duration_seconds_bucket{{{ok},le="60"}}"#),
+                "2",
+            ),
+            (
+                format!(r#"rpc_server_duration_seconds_bucket{{{ok},le="+Inf"}}"#),
+                "2",
+            ),
+            (format!("rpc_server_duration_seconds_count{{{ok}}}"), "2"),
+            (
+                format!(r#"rpc_server_duration_seconds_bucket{{{not_found},le="60"}}"#),
+                "1",
+            ),
+        ] {
+            assert_eq!(samples.get(series.as_str()), Some(&value), "{rendered}");
+        }
+
+        // Both unknown paths are one series, and the scrape isn't a call.
+        let unknown = samples
+            .iter()
+            .filter(|(series, _)| series.starts_with("rpc_server_calls_total{"))
+            .filter(|(series, _)| series.contains(r#"service="unknown",method="unknown""#))
+            .map(|(_, value)| *value)
+            .collect::<Vec<_>>();
+        assert_eq!(unknown, ["2"], "{rendered}");
+        assert!(!rendered.contains("metrics"), "{rendered}");
+    }
+
+    fn log(latency_ms: u64, code: Option<RpcErrorCode>) -> RpcLog {
+        RpcLog {
+            procedure: SAY_HELLO.to_string(),
+            codec: "proto",
+            streaming: false,
+            request_bytes: 0,
+            response_bytes: 0,
+            request_messages: 1,
+            response_messages: 1,
+            latency: Duration::from_millis(latency_ms),
+            handler_latency: None,
+            code,
+            internal_message: None,
+            metadata: vec![],
+            deprecated: false,
+            invalid_transport: false,
+        }
+    }
+
+    #[test]
+    fn fills_the_configured_buckets_cumulatively() {
+        // Unsorted, duplicated and infinite bounds are cleaned up.
+        let metrics = PrometheusMetrics::new().buckets(vec![1.0, 0.1, f64::INFINITY, 0.1]);
+        let info = crate::test_util::unary_info(SAY_HELLO);
+        for latency_ms in [50, 100, 700, 5000] {
+            metrics.record(Some(&info), &log(latency_ms, None));
+        }
+        metrics.record(Some(&info), &log(20, Some(RpcErrorCode::Unavailable)));
+        metrics.record(None, &log(20, None));
+
+        let rendered = metrics.render();
+        let samples = samples(&rendered);
+        let ok = r#"service="hello.HelloWorldService",method="SayHello",code="ok""#;
+        for (series, value) in [
+            (format!(r#"rpc_server_duration_seconds_bucket{{{ok},le="0.1"}}"#), "2"),
+            (format!(r#"rpc_server_duration_seconds_bucket{{{ok},le="1"}}"#), "3"),
+            (format!(r#"rpc_server_duration_seconds_bucket{{{ok},le="+Inf"}}"#), "4"),
+            (format!("rpc_server_duration_seconds_sum{{{ok}}}"), "5.85"),
+            (format!("rpc_server_duration_seconds_count{{{ok}}}"), "4"),
+            (
+                r#"rpc_server_calls_total{service="hello.HelloWorldService",method="SayHello",code="unavailable"}"#
+                    .to_string(),
+                "1",
+            ),
+            (
+                r#"rpc_server_calls_total{service="unknown",method="unknown",code="ok"}"#
+                    .to_string(),
+                "1",
+            ),
+        ] {
+            assert_eq!(samples.get(series.as_str()), Some(&value), "{rendered}");
+        }
+        assert_eq!(
+            rendered
+                .lines()
+                .filter(
+                    |line| line.starts_with("rpc_server_duration_seconds_bucket{")
+                        && line.contains(ok)
+                )
+                .count(),
+            3,
+            "{rendered}"
+        );
+    }
+
+    #[test]
+    fn counts_invalid_transports_apart() {
+        let metrics = PrometheusMetrics::new();
+        let info = crate::test_util::unary_info(SAY_HELLO);
+        let mut invalid = log(1, Some(RpcErrorCode::Unimplemented));
+        invalid.invalid_transport = true;
+        metrics.record(Some(&info), &invalid);
+
+        let rendered = metrics.render();
+        let samples = samples(&rendered);
+        assert_eq!(
+            samples.get(
+                r#"rpc_server_invalid_transport_total{service="hello.HelloWorldService",method="SayHello"}"#
+            ),
+            Some(&"1")
+        );
+        assert!(!rendered.contains("rpc_server_calls_total{"), "{rendered}");
+    }
+}
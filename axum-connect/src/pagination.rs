@@ -0,0 +1,295 @@
+//! Opaque page tokens for list RPCs, see [`PageToken`].
+//!
+//! A token carries whatever the server needs to continue a listing (eg. the last key returned),
+//! serialized as JSON and authenticated with HMAC-SHA256, so clients can't forge one to read past
+//! what they were given. Tokens are URL-safe base64, ready for a `string page_token` field. They're
+//! signed, not encrypted: a client can read the state, so keep secrets out of it.
+//!
+//! ```
+//! # use axum_connect::pagination::{PageToken, PageTokenKey};
+//! # use serde::{Deserialize, Serialize};
+//! #[derive(Serialize, Deserialize, PartialEq, Debug)]
+//! struct Cursor {
+//!     after: String,
+//! }
+//!
+//! let key = PageTokenKey::new(b"a secret of the service");
+//! let token = PageToken::encode(&Cursor { after: "user-42".to_string() }, &key);
+//! let cursor: Cursor = PageToken::decode(&token, &key).unwrap();
+//! assert_eq!(cursor.after, "user-42");
+//! ```
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::Sha256;
+
+use crate::error::{RpcError, RpcErrorCode};
+
+/// The format of the tokens, the first byte of each.
+const VERSION: u8 = 1;
+
+/// The bytes of the HMAC kept in a token, truncated like HMAC-SHA256-128.
+const TAG_BYTES: usize = 16;
+
+/// The version and the time the token was issued, before the state.
+const HEADER_BYTES: usize = 1 + 8;
+
+/// The secret page tokens are authenticated with, and how long they're valid. Keep it in the
+/// router state and take it with `State<PageTokenKey>`, through `FromRef` for a state of your own.
+///
+/// Tokens only decode with the key they were encoded with, so rotating it invalidates every
+/// token out there: clients get `InvalidArgument` and list again from the first page.
+#[derive(Clone)]
+pub struct PageTokenKey {
+    mac: Hmac<Sha256>,
+    max_age: Option<Duration>,
+}
+
+impl PageTokenKey {
+    /// Tokens don't expire by default.
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            // HMAC takes keys of any length.
+            mac: Hmac::new_from_slice(secret).unwrap(),
+            max_age: None,
+        }
+    }
+
+    /// Rejects tokens issued longer ago, eg. so a listing can't be continued over a snapshot that
+    /// was dropped.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn tag(&self, bytes: &[u8]) -> [u8; TAG_BYTES] {
+        let mut mac = self.mac.clone();
+        mac.update(bytes);
+        let mut tag = [0; TAG_BYTES];
+        tag.copy_from_slice(&mac.finalize().into_bytes()[..TAG_BYTES]);
+        tag
+    }
+}
+
+impl fmt::Debug for PageTokenKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageTokenKey")
+            .field("max_age", &self.max_age)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Encodes and decodes page tokens, see the [module docs](self).
+#[derive(Clone, Copy, Debug)]
+pub struct PageToken;
+
+impl PageToken {
+    /// The token for `state`, to send as the response's `next_page_token`.
+    pub fn encode<T: Serialize>(state: &T, key: &PageTokenKey) -> String {
+        let issued = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        let mut bytes = vec![VERSION];
+        bytes.extend_from_slice(&issued.to_be_bytes());
+        // Serializing to a `Vec` only fails for maps with non-string keys.
+        serde_json::to_writer(&mut bytes, state).expect("page token state serializes to JSON");
+        let tag = key.tag(&bytes);
+        bytes.extend_from_slice(&tag);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    /// The state `token` was encoded with. Tokens that weren't encoded with `key`, were changed,
+    /// or are older than its [`max_age`](PageTokenKey::max_age) fail with `InvalidArgument`.
+    pub fn decode<T: DeserializeOwned>(token: &str, key: &PageTokenKey) -> Result<T, RpcError> {
+        let invalid = || RpcError::new(RpcErrorCode::InvalidArgument, "Invalid page token".into());
+
+        let bytes = URL_SAFE_NO_PAD.decode(token).map_err(|_| invalid())?;
+        if bytes.len() < HEADER_BYTES + TAG_BYTES || bytes[0] != VERSION {
+            return Err(invalid());
+        }
+        let (signed, tag) = bytes.split_at(bytes.len() - TAG_BYTES);
+        let mut mac = key.mac.clone();
+        mac.update(signed);
+        // In constant time.
+        mac.verify_truncated_left(tag).map_err(|_| invalid())?;
+
+        let issued = u64::from_be_bytes(signed[1..HEADER_BYTES].try_into().unwrap());
+        if let Some(max_age) = key.max_age {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            if now.saturating_sub(issued) > max_age.as_secs() {
+                return Err(RpcError::new(
+                    RpcErrorCode::InvalidArgument,
+                    "The page token expired, list again from the first page".into(),
+                ));
+            }
+        }
+
+        serde_json::from_slice(&signed[HEADER_BYTES..]).map_err(|_| invalid())
+    }
+}
+
+/// A request message with a page token, what [`Page`] decodes. Codegen implements it for
+/// messages with a `string page_token` field with `page_tokens: true`.
+pub trait HasPageToken {
+    /// The token, empty for the first page.
+    fn page_token(&self) -> &str;
+}
+
+/// The decoded page token of a list request, `None` for the first page (an empty token).
+///
+/// ```
+/// # use axum::extract::State;
+/// # use axum_connect::{pagination::{HasPageToken, Page, PageToken, PageTokenKey}, prelude::*};
+/// # struct ListUsersRequest { page_token: String }
+/// # impl HasPageToken for ListUsersRequest {
+/// #     fn page_token(&self) -> &str { &self.page_token }
+/// # }
+/// # struct ListUsersResponse { next_page_token: String }
+/// async fn list_users(
+///     State(key): State<PageTokenKey>,
+///     request: ListUsersRequest,
+/// ) -> RpcResult<ListUsersResponse> {
+///     let Page(after) = Page::<u64>::decode(&request, &key)?;
+///     let next = after.unwrap_or(0) + 100;
+///     Ok(ListUsersResponse {
+///         next_page_token: PageToken::encode(&next, &key),
+///     })
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Page<T>(pub Option<T>);
+
+impl<T: DeserializeOwned> Page<T> {
+    pub fn decode(request: &impl HasPageToken, key: &PageTokenKey) -> Result<Self, RpcError> {
+        match request.page_token() {
+            "" => Ok(Self(None)),
+            token => PageToken::decode(token, key).map(|state| Self(Some(state))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Cursor {
+        after: String,
+    }
+
+    struct ListUsers {
+        page_token: String,
+    }
+
+    impl HasPageToken for ListUsers {
+        fn page_token(&self) -> &str {
+            &self.page_token
+        }
+    }
+
+    fn key() -> PageTokenKey {
+        PageTokenKey::new(b"a secret of the service")
+    }
+
+    fn cursor(after: &str) -> Cursor {
+        Cursor {
+            after: after.to_string(),
+        }
+    }
+
+    /// A token for `state` issued at `issued`, seconds since the epoch.
+    fn issued_at(issued: u64, state: &Cursor, key: &PageTokenKey) -> String {
+        let mut bytes = vec![VERSION];
+        bytes.extend_from_slice(&issued.to_be_bytes());
+        serde_json::to_writer(&mut bytes, state).unwrap();
+        let tag = key.tag(&bytes);
+        bytes.extend_from_slice(&tag);
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    #[test]
+    fn decodes_the_state_tokens_were_encoded_with() {
+        let token = PageToken::encode(&cursor("user-42"), &key());
+
+        assert!(token
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_'));
+        assert_eq!(
+            PageToken::decode::<Cursor>(&token, &key()).unwrap(),
+            cursor("user-42")
+        );
+    }
+
+    #[test]
+    fn rejects_tampered_tokens() {
+        let token = PageToken::encode(&cursor("user-42"), &key());
+        let mut bytes = URL_SAFE_NO_PAD.decode(&token).unwrap();
+        // The `2` of `user-42`, just before the tag.
+        let at = bytes.len() - TAG_BYTES - 3;
+        bytes[at] = b'9';
+        let forged = URL_SAFE_NO_PAD.encode(bytes);
+
+        let error = PageToken::decode::<Cursor>(&forged, &key()).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(error.message, "Invalid page token");
+    }
+
+    #[test]
+    fn rejects_tokens_of_other_keys_and_garbage() {
+        let token = PageToken::encode(&cursor("user-42"), &PageTokenKey::new(b"another secret"));
+
+        for token in [token.as_str(), "not a token", "AQ", ""] {
+            let error = PageToken::decode::<Cursor>(token, &key()).unwrap_err();
+            assert_eq!(error.message, "Invalid page token", "{token:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_tokens_past_their_max_age() {
+        let key = key().max_age(Duration::from_secs(3_600));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let fresh = issued_at(now - 60, &cursor("user-42"), &key);
+        let stale = issued_at(now - 7_200, &cursor("user-42"), &key);
+
+        assert!(PageToken::decode::<Cursor>(&fresh, &key).is_ok());
+        let error = PageToken::decode::<Cursor>(&stale, &key).unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(
+            error.message,
+            "The page token expired, list again from the first page"
+        );
+    }
+
+    #[test]
+    fn reads_empty_tokens_as_the_first_page() {
+        let first = ListUsers {
+            page_token: String::new(),
+        };
+        let next = ListUsers {
+            page_token: PageToken::encode(&cursor("user-42"), &key()),
+        };
+
+        assert_eq!(Page::<Cursor>::decode(&first, &key()).unwrap(), Page(None));
+        assert_eq!(
+            Page::<Cursor>::decode(&next, &key()).unwrap(),
+            Page(Some(cursor("user-42")))
+        );
+    }
+}
@@ -1,19 +1,43 @@
-use std::future::Future;
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    fmt,
+    future::{poll_fn, Future},
+    net::SocketAddr,
+    ops::Deref,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+#[cfg(not(target_arch = "wasm32"))]
 use axum::{
-    extract::{
-        connect_info::MockConnectInfo, ConnectInfo, FromRef, FromRequestParts, Query, State,
-    },
-    http::{self},
+    extract::{connect_info::MockConnectInfo, ConnectInfo},
     Extension,
 };
+use axum::{
+    extract::{FromRef, FromRequestParts, Query, State},
+    http::{self, header::IntoHeaderName, HeaderMap, HeaderValue},
+};
 #[cfg(feature = "axum-extra")]
 use axum_extra::extract::Host;
 use prost::Message;
 use serde::de::DeserializeOwned;
 
-use crate::error::{RpcError, RpcErrorCode, RpcIntoError};
+use crate::{
+    auth::AuthContext,
+    error::{RpcError, RpcErrorCode, RpcIntoError},
+    handler::codec::ResponseCompression,
+};
 
+/// Extracts a handler argument from the request parts, like axum's `FromRequestParts`.
+///
+/// A handler's extractors run one at a time, left to right, after authorization and before
+/// anything else. Each gets the parts as the ones before it left them, so an extractor can read
+/// an extension an earlier one inserted (eg. a tenant looked up from the auth context). The first
+/// to fail rejects the call and the ones after it never run. The request body is only read,
+/// verified ([`RouteOptions::verify_body`](crate::router::RouteOptions::verify_body)) and decoded
+/// once every extractor has succeeded, unless one of them is a [`Peek`].
 pub trait RpcFromRequestParts<T, S>: Sized
 where
     T: Message,
@@ -23,6 +47,10 @@ where
     /// a kind of error that can be converted into a response.
     type Rejection: RpcIntoError;
 
+    /// Whether the extractor reads the decoded request message, like [`Peek`]. The handler then
+    /// decodes it before the first extractor runs. Wrappers of other extractors pass theirs on.
+    const PEEKS_MESSAGE: bool = false;
+
     /// Perform the extraction.
     fn rpc_from_request_parts(
         parts: &mut http::request::Parts,
@@ -42,9 +70,44 @@ where
         parts: &mut http::request::Parts,
         state: &S,
     ) -> Result<Self, Self::Rejection> {
-        Ok(Host::from_request_parts(parts, state)
-            .await
-            .map_err(|e| (RpcErrorCode::Internal, e.to_string()).rpc_into_error())?)
+        Ok(Host::from_request_parts(parts, state).await?)
+    }
+}
+
+/// Needs the `SessionManagerLayer` outside of the RPC routes. The session is loaded before the
+/// handler runs, so a failing store fails the call with `Unavailable` instead of the first access.
+#[cfg(feature = "sessions")]
+impl<M, S> RpcFromRequestParts<M, S> for tower_sessions::Session
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let Some(session) = parts.extensions.get::<tower_sessions::Session>().cloned() else {
+            return Err((
+                RpcErrorCode::Internal,
+                "Missing session, is the `SessionManagerLayer` applied to the router?",
+            )
+                .rpc_into_error());
+        };
+
+        // Without a session cookie there's nothing to load.
+        if session.id().is_some() {
+            session.load().await.map_err(|e| {
+                (
+                    RpcErrorCode::Unavailable,
+                    format!("Failed to load the session: {}", e),
+                )
+                    .rpc_into_error()
+            })?;
+        }
+
+        Ok(session)
     }
 }
 
@@ -60,12 +123,29 @@ where
         parts: &mut http::request::Parts,
         state: &S,
     ) -> Result<Self, Self::Rejection> {
-        Ok(Query::from_request_parts(parts, state)
-            .await
-            .map_err(|e| (RpcErrorCode::Internal, e.to_string()).rpc_into_error())?)
+        Ok(Query::from_request_parts(parts, state).await?)
     }
 }
 
+/// The peer's address from `ConnectInfo<SocketAddr>`, or a `MockConnectInfo` layer.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn peer_address(parts: &http::request::Parts) -> Option<SocketAddr> {
+    match parts.extensions.get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(address)) => Some(*address),
+        None => parts
+            .extensions
+            .get::<MockConnectInfo<SocketAddr>>()
+            .map(|MockConnectInfo(address)| *address),
+    }
+}
+
+/// There are no sockets to serve from in the browser.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn peer_address(_parts: &http::request::Parts) -> Option<SocketAddr> {
+    None
+}
+
+#[cfg(not(target_arch = "wasm32"))]
 impl<M, S, T> RpcFromRequestParts<M, S> for ConnectInfo<T>
 where
     M: Message,
@@ -82,7 +162,14 @@ where
             Ok(Extension(connect_info)) => Ok(connect_info),
             Err(err) => match parts.extensions.get::<MockConnectInfo<T>>() {
                 Some(MockConnectInfo(connect_info)) => Ok(Self(connect_info.clone())),
-                None => Err((RpcErrorCode::Internal, err.to_string()).rpc_into_error()),
+                None => Err((
+                    RpcErrorCode::Internal,
+                    format!(
+                        "{}. Serve the router with `into_make_service_with_connect_info`",
+                        err
+                    ),
+                )
+                    .rpc_into_error()),
             },
         }
     }
@@ -104,3 +191,1070 @@ where
         Ok(Self(inner_state))
     }
 }
+
+/// The request metadata, ie. every request header.
+///
+/// Connect sends ASCII metadata as plain headers and binary metadata in headers ending in `-bin`,
+/// base64 encoded. Works in both unary and streaming handlers. Keys are always lowercase, however
+/// the client sent them, and the metadata is within
+/// [`RpcConfig::max_metadata_bytes`](crate::config::RpcConfig::max_metadata_bytes) and
+/// [`max_metadata_value_bytes`](crate::config::RpcConfig::max_metadata_value_bytes).
+#[derive(Clone, Debug, Default)]
+pub struct RpcMetadata(pub HeaderMap);
+
+impl RpcMetadata {
+    /// Whether `key` (lowercase) is a header of the protocol or of HTTP rather than user metadata,
+    /// eg. `content-type`, `connect-timeout-ms` or `host`. Those don't count against the metadata
+    /// limits.
+    pub fn is_reserved(key: &str) -> bool {
+        const PREFIXES: [&str; 5] = ["connect-", "content-", "accept", "grpc-", "sec-"];
+        const NAMES: [&str; 11] = [
+            "host",
+            "user-agent",
+            "te",
+            "transfer-encoding",
+            "connection",
+            "keep-alive",
+            "upgrade",
+            "expect",
+            "origin",
+            "referer",
+            "via",
+        ];
+
+        PREFIXES.iter().any(|prefix| key.starts_with(prefix)) || NAMES.contains(&key)
+    }
+
+    /// An ASCII metadata value, keys are case-insensitive. Repeated keys are joined with `, `, use
+    /// [`get_all`](RpcMetadata::get_all) to see them separately. Values that aren't visible ASCII
+    /// are skipped.
+    pub fn get(&self, key: &str) -> Option<Cow<'_, str>> {
+        let mut values = self.get_all(key);
+        let first = values.next()?;
+
+        match values.next() {
+            None => Some(Cow::Borrowed(first)),
+            Some(second) => {
+                let mut joined = format!("{}, {}", first, second);
+                for value in values {
+                    joined.push_str(", ");
+                    joined.push_str(value);
+                }
+                Some(Cow::Owned(joined))
+            }
+        }
+    }
+
+    /// Every ASCII value for a (case-insensitive) key, in the order they were sent.
+    pub fn get_all(&self, key: &str) -> impl Iterator<Item = &str> {
+        self.0
+            .get_all(key)
+            .into_iter()
+            .filter_map(|v| v.to_str().ok())
+    }
+
+    /// A binary metadata value, `key` should end in `-bin`. Both padded and unpadded base64 are
+    /// accepted, per the spec.
+    pub fn get_bin(&self, key: &str) -> Option<Vec<u8>> {
+        use base64::{engine::general_purpose, Engine as _};
+
+        let value = self.0.get(key)?.as_bytes();
+        let value = value
+            .strip_suffix(b"==")
+            .or(value.strip_suffix(b"="))
+            .unwrap_or(value);
+        general_purpose::STANDARD_NO_PAD.decode(value).ok()
+    }
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for RpcMetadata
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self(parts.headers.clone()))
+    }
+}
+
+/// The call's deadline, from its `connect-timeout-ms` header. `None` without a timeout, or when an
+/// invalid one was ignored (see [`RpcConfig::strict_timeouts`](crate::config::RpcConfig)).
+///
+/// The handler is cancelled with `DeadlineExceeded` once the deadline passes, and response streams
+/// end there, so this is mostly for passing the deadline on to downstream calls, see
+/// [`ClientContext`](crate::client::ClientContext).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RpcDeadline(pub Option<Instant>);
+
+impl RpcDeadline {
+    /// The time left until the deadline, zero once it passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for RpcDeadline
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(parts.extensions.get::<Self>().copied().unwrap_or_default())
+    }
+}
+
+/// Trailing metadata of the response, set while the call runs, eg. a total only known once a
+/// stream is done. Streams send it in their end-of-stream message (with an error's metadata, if
+/// they fail), unary responses as `trailer-` prefixed headers. Reserved headers, eg.
+/// `content-length`, are dropped (see
+/// [`RpcConfig::strict_reserved_headers`](crate::config::RpcConfig::strict_reserved_headers)).
+/// Clones share the trailers, so one can go into the response stream:
+///
+/// ```
+/// # use axum::http::HeaderValue;
+/// # use axum_connect::{futures::{stream, Stream, StreamExt}, parts::RpcTrailers};
+/// # fn download(trailers: RpcTrailers) -> impl Stream<Item = Vec<u8>> {
+/// # let chunks = stream::iter([vec![0u8; 16]]);
+/// let mut total = 0;
+/// chunks
+///     .inspect(move |chunk| {
+///         total += chunk.len();
+///         trailers.insert("x-total-bytes", HeaderValue::from(total));
+///     })
+/// # }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct RpcTrailers(Arc<Mutex<HeaderMap>>);
+
+impl RpcTrailers {
+    /// Sets `key` to `value`, replacing what it was set to.
+    pub fn insert(&self, key: impl IntoHeaderName, value: HeaderValue) {
+        self.0.lock().unwrap().insert(key, value);
+    }
+
+    /// Adds `value` to the values of `key`.
+    pub fn append(&self, key: impl IntoHeaderName, value: HeaderValue) {
+        self.0.lock().unwrap().append(key, value);
+    }
+
+    /// The trailers set so far, for the end of the response.
+    pub(crate) fn take(&self) -> HeaderMap {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    /// The trailers of the call whose handler is running, for what it builds its response with,
+    /// eg. [`rpc_stream_channel`](crate::stream::rpc_stream_channel).
+    pub(crate) fn current() -> Option<Self> {
+        CURRENT_TRAILERS.with(|current| current.borrow().clone())
+    }
+
+    /// Makes these the [`current`](Self::current) trailers while `future` is polled.
+    pub(crate) async fn scope<F: Future>(self, future: F) -> F::Output {
+        /// Restores the trailers of the outer scope, even if the poll panics.
+        struct Restore(Option<RpcTrailers>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT_TRAILERS.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+
+        let mut future = pin!(future);
+        poll_fn(|cx| {
+            let outer = CURRENT_TRAILERS.with(|current| current.replace(Some(self.clone())));
+            let _restore = Restore(outer);
+            future.as_mut().poll(cx)
+        })
+        .await
+    }
+}
+
+thread_local! {
+    static CURRENT_TRAILERS: RefCell<Option<RpcTrailers>> = const { RefCell::new(None) };
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for RpcTrailers
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<Self>() {
+            Some(trailers) => Ok(trailers.clone()),
+            None => Err((
+                RpcErrorCode::Internal,
+                "Trailers are only sent by the handlers of generated RPC routes",
+            )
+                .rpc_into_error()),
+        }
+    }
+}
+
+/// Leading metadata of the response, sent as headers, eg. whether it came from a cache. Only what's
+/// set by the time the handler returns is sent; a stream's headers go out when it returns its
+/// stream, use [`RpcTrailers`] for what's only known later. Reserved headers are dropped like
+/// those among the trailers. Clones share the headers.
+#[derive(Clone, Debug, Default)]
+pub struct RpcResponseHeaders(Arc<Mutex<HeaderMap>>);
+
+impl RpcResponseHeaders {
+    /// Sets `key` to `value`, replacing what it was set to.
+    pub fn insert(&self, key: impl IntoHeaderName, value: HeaderValue) {
+        self.0.lock().unwrap().insert(key, value);
+    }
+
+    /// Adds `value` to the values of `key`.
+    pub fn append(&self, key: impl IntoHeaderName, value: HeaderValue) {
+        self.0.lock().unwrap().append(key, value);
+    }
+
+    /// The headers set so far, for the response.
+    pub(crate) fn take(&self) -> HeaderMap {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for RpcResponseHeaders
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<Self>() {
+            Some(headers) => Ok(headers.clone()),
+            None => Err((
+                RpcErrorCode::Internal,
+                "Response headers are only sent by the handlers of generated RPC routes",
+            )
+                .rpc_into_error()),
+        }
+    }
+}
+
+/// The compression of the call, `None` for identity: the encoding of the request, from its
+/// `content-encoding` (`connect-content-encoding` for streams), and the encoding negotiated for
+/// the response. That's gzip for clients accepting it, of unary responses under the compression
+/// of [`layers::recommended`](crate::layers::recommended) (which leaves those under 32 bytes
+/// alone) and of the messages of streams on routes with
+/// [`RouteOptions::stream_compression`](crate::router::RouteOptions::stream_compression) (those
+/// over its threshold).
+///
+/// [`set_response_encoding`](Self::set_response_encoding) overrides it for this call, eg. for a
+/// client that compresses at a higher layer:
+///
+/// ```
+/// # use axum_connect::parts::RpcCompressionInfo;
+/// # fn render(mut compression: RpcCompressionInfo) {
+/// if compression.response_encoding.is_none() {
+///     // Skip the padding that's only there to compress well.
+/// }
+/// // Already compressed, don't gzip it again.
+/// compression.set_response_encoding(None);
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RpcCompressionInfo {
+    pub request_encoding: Option<String>,
+    pub response_encoding: Option<String>,
+    negotiated: ResponseCompression,
+}
+
+impl RpcCompressionInfo {
+    /// Sends the response of this call with `encoding`, `None` (or `identity`) for uncompressed
+    /// and `gzip` for gzipped, whatever its size or the route's threshold. Gzip needs a client
+    /// accepting it and a server compressing that kind of response, see above; asking for it
+    /// otherwise, or for any other encoding, logs a warning and leaves it as it was. Returns the
+    /// new [`response_encoding`](Self::response_encoding).
+    pub fn set_response_encoding(&mut self, encoding: Option<&str>) -> Option<&str> {
+        self.response_encoding = self.negotiated.set(encoding).map(str::to_string);
+        self.response_encoding.as_deref()
+    }
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for RpcCompressionInfo
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<ResponseCompression>() {
+            Some(negotiated) => {
+                let (request_encoding, response_encoding) = negotiated.encodings();
+                Ok(Self {
+                    request_encoding,
+                    response_encoding: response_encoding.map(str::to_string),
+                    negotiated: negotiated.clone(),
+                })
+            }
+            None => Err((
+                RpcErrorCode::Internal,
+                "Compression is only negotiated by the handlers of generated RPC routes",
+            )
+                .rpc_into_error()),
+        }
+    }
+}
+
+/// The [`AuthContext`] returned by the router's [`RpcAuthorize`](crate::auth::RpcAuthorize).
+/// Fails with `Unauthenticated` on routes that weren't authorized, eg. public ones.
+#[derive(Clone, Debug)]
+pub struct Auth(pub AuthContext);
+
+impl<M, S> RpcFromRequestParts<M, S> for Auth
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<AuthContext>() {
+            Some(context) => Ok(Self(context.clone())),
+            None => Err((
+                RpcErrorCode::Unauthenticated,
+                "This route wasn't authorized, is an RpcAuthorize registered on the router?",
+            )
+                .rpc_into_error()),
+        }
+    }
+}
+
+/// Shares the extracted value, eg. to hand it on to spawned tasks.
+impl<M, S, E> RpcFromRequestParts<M, S> for Arc<E>
+where
+    M: Message,
+    S: Send + Sync,
+    E: RpcFromRequestParts<M, S>,
+{
+    type Rejection = E::Rejection;
+
+    const PEEKS_MESSAGE: bool = E::PEEKS_MESSAGE;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        E::rpc_from_request_parts(parts, state).await.map(Arc::new)
+    }
+}
+
+/// Runs the extractor `E` once per request, however many times it's extracted: the first
+/// extraction stores its result in the request extensions, keyed by `E`'s type, and the ones
+/// after it get a clone. A rejection is stored too, as the [`RpcError`] it converts to, so an
+/// extractor that failed once fails the same way everywhere. Handy for extractors verifying
+/// tokens or looking things up, eg. `Claims` taken by both a handler and a middleware before it.
+///
+/// Every extraction site has to take it as `Cached<E>`, a plain `E` still runs on its own. A
+/// middleware holding the whole request extracts it with [`Cached::extract`]:
+///
+/// ```
+/// # use axum::{extract::{Request, State}, middleware::Next, response::Response};
+/// # use axum_connect::{error::RpcError, parts::Cached, prelude::*};
+/// # #[derive(Clone)]
+/// # struct User(String);
+/// # impl<M: axum_connect::prost::Message, S: Send + Sync> RpcFromRequestParts<M, S> for User {
+/// #     type Rejection = RpcError;
+/// #     async fn rpc_from_request_parts(
+/// #         _parts: &mut axum::http::request::Parts,
+/// #         _state: &S,
+/// #     ) -> Result<Self, RpcError> {
+/// #         Ok(User("ada".to_string()))
+/// #     }
+/// # }
+/// # #[derive(Clone)]
+/// # struct AppState;
+/// async fn audit_user(State(state): State<AppState>, request: Request, next: Next) -> Response {
+///     let (mut parts, body) = request.into_parts();
+///     // Handlers taking `Cached<User>` get the same user, or the same error, without
+///     // extracting it again.
+///     if let Ok(User(name)) = Cached::<User>::extract(&mut parts, &state).await {
+///         println!("{} called {}", name, parts.uri.path());
+///     }
+///     next.run(Request::from_parts(parts, body)).await
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cached<E>(pub E);
+
+/// The result of the first `Cached<E>` of a request.
+struct CachedResult<E>(Result<E, RpcError>);
+
+impl<E: Clone> Clone for CachedResult<E> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<E> Cached<E>
+where
+    E: Clone + Send + Sync + 'static,
+{
+    /// Extracts `E` out of a handler, eg. in a middleware, with the same cache as `Cached<E>`
+    /// handler arguments.
+    pub async fn extract<S>(parts: &mut http::request::Parts, state: &S) -> Result<E, RpcError>
+    where
+        S: Send + Sync,
+        E: RpcFromRequestParts<(), S>,
+    {
+        <Self as RpcFromRequestParts<(), S>>::rpc_from_request_parts(parts, state)
+            .await
+            .map(|Cached(value)| value)
+    }
+}
+
+impl<M, S, E> RpcFromRequestParts<M, S> for Cached<E>
+where
+    M: Message,
+    S: Send + Sync,
+    E: RpcFromRequestParts<M, S> + Clone + Send + Sync + 'static,
+{
+    type Rejection = RpcError;
+
+    const PEEKS_MESSAGE: bool = E::PEEKS_MESSAGE;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(CachedResult(result)) = parts.extensions.get::<CachedResult<E>>() {
+            return result.clone().map(Self);
+        }
+
+        let result = E::rpc_from_request_parts(parts, state)
+            .await
+            .map_err(RpcIntoError::rpc_into_error);
+        parts.extensions.insert(CachedResult(result.clone()));
+        result.map(Self)
+    }
+}
+
+/// The decoded request message, for extractors that decide by its fields, eg. authorizing by
+/// `org_id`. With a `Peek` among a handler's extractors, the request is decoded before the first
+/// of them runs, instead of after the last, and each extractor can take a `Peek` of it from the
+/// parts; the handler still gets the owned message as its last argument. The message is decoded
+/// once, and shared with an `Arc`. Server-streaming requests have a single frame, it's that one.
+///
+/// `M` must be the handler's request type, other types are rejected with `Internal`, and so are
+/// handlers leaving the request message out.
+///
+/// Decoding first means the body is read, checked against the size limit and
+/// [`verify_body`](crate::router::RouteOptions::verify_body), and decoded before any extractor
+/// gets a say, so an extractor can no longer reject a call before its body is read: a malformed
+/// body fails with `InvalidArgument` even if an auth extractor would have failed it with
+/// `Unauthenticated`. Router-level authorization
+/// ([`RpcRouter::authorize`](crate::router::RpcRouter::authorize)) still runs first.
+///
+/// ```
+/// # use axum_connect::{error::{RpcError, RpcErrorCode}, parts::Peek, pbjson_types::StringValue, prelude::*};
+/// struct SameOrg;
+///
+/// impl<M: axum_connect::prost::Message, S: Send + Sync> RpcFromRequestParts<M, S> for SameOrg {
+///     type Rejection = RpcError;
+///
+///     const PEEKS_MESSAGE: bool = true;
+///
+///     async fn rpc_from_request_parts(
+///         parts: &mut axum::http::request::Parts,
+///         _state: &S,
+///     ) -> Result<Self, RpcError> {
+///         let Peek(request) = Peek::<StringValue>::extract(parts)?;
+///         let org = parts.headers.get("x-org").and_then(|org| org.to_str().ok());
+///         match org == Some(request.value.as_str()) {
+///             true => Ok(SameOrg),
+///             false => Err(RpcError::new(RpcErrorCode::PermissionDenied, "Wrong org".to_string())),
+///         }
+///     }
+/// }
+///
+/// async fn delete_org(_: SameOrg, org: StringValue) -> RpcResult<StringValue> {
+///     Ok(org)
+/// }
+/// ```
+pub struct Peek<M>(pub Arc<M>);
+
+/// The request message of a handler with a [`Peek`] among its extractors.
+pub(crate) struct PeekedMessage<M>(pub Arc<M>);
+
+impl<M> Clone for PeekedMessage<M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<M> Peek<M>
+where
+    M: Send + Sync + 'static,
+{
+    /// The peeked message, for extractors implementing [`RpcFromRequestParts`] that set
+    /// `PEEKS_MESSAGE`.
+    pub fn extract(parts: &http::request::Parts) -> Result<Self, RpcError> {
+        match parts.extensions.get::<PeekedMessage<M>>() {
+            Some(PeekedMessage(message)) => Ok(Self(message.clone())),
+            None => Err(RpcError::new(
+                RpcErrorCode::Internal,
+                format!(
+                    "No {} to peek, Peek must be of the handler's request message",
+                    std::any::type_name::<M>()
+                ),
+            )),
+        }
+    }
+}
+
+impl<M> Clone for Peek<M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<M: fmt::Debug> fmt::Debug for Peek<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Peek").field(&self.0).finish()
+    }
+}
+
+impl<M> Deref for Peek<M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.0
+    }
+}
+
+impl<T, S, M> RpcFromRequestParts<T, S> for Peek<M>
+where
+    T: Message,
+    S: Send + Sync,
+    M: Send + Sync + 'static,
+{
+    type Rejection = RpcError;
+
+    const PEEKS_MESSAGE: bool = true;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Self::extract(parts)
+    }
+}
+
+/// Extractors that always go together can be taken as one tuple, eg. `ctx: (Tenant, Locale)`.
+/// They run in order and the first to fail rejects the call, the ones after it don't run.
+macro_rules! impl_from_request_parts_tuple {
+    (
+        [$($ty:ident),*]
+    ) => {
+        #[allow(non_snake_case)]
+        impl<M, S, $($ty,)*> RpcFromRequestParts<M, S> for ($($ty,)*)
+        where
+            M: Message,
+            S: Send + Sync,
+            $( $ty: RpcFromRequestParts<M, S> + Send, )*
+        {
+            type Rejection = RpcError;
+
+            const PEEKS_MESSAGE: bool = false $(|| $ty::PEEKS_MESSAGE)*;
+
+            async fn rpc_from_request_parts(
+                parts: &mut http::request::Parts,
+                state: &S,
+            ) -> Result<Self, Self::Rejection> {
+                $(
+                    let $ty = $ty::rpc_from_request_parts(parts, state)
+                        .await
+                        .map_err(RpcIntoError::rpc_into_error)?;
+                )*
+                Ok(($($ty,)*))
+            }
+        }
+    };
+}
+
+impl_from_request_parts_tuple!([T1, T2]);
+impl_from_request_parts_tuple!([T1, T2, T3]);
+impl_from_request_parts_tuple!([T1, T2, T3, T4]);
+impl_from_request_parts_tuple!([T1, T2, T3, T4, T5]);
+impl_from_request_parts_tuple!([T1, T2, T3, T4, T5, T6]);
+impl_from_request_parts_tuple!([T1, T2, T3, T4, T5, T6, T7]);
+impl_from_request_parts_tuple!([T1, T2, T3, T4, T5, T6, T7, T8]);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use axum::{
+        extract::Request,
+        middleware::{from_fn, Next},
+        response::Response,
+    };
+    use prost::{
+        bytes::{Buf, BufMut},
+        encoding::{self, DecodeContext, WireType},
+        DecodeError,
+    };
+
+    use super::*;
+    use crate::{
+        prelude::*,
+        router::{RpcMethod, RpcRouter},
+        test_util::{
+            client, hello, message, proto_request, unary, unary_info, HelloRequest, HelloResponse,
+            SAY_HELLO,
+        },
+    };
+
+    thread_local! {
+        static DECODED_FIELDS: Cell<usize> = const { Cell::new(0) };
+        static EXTRACTED: Cell<usize> = const { Cell::new(0) };
+        static VERIFIED: Cell<usize> = const { Cell::new(0) };
+        static RAN: RefCell<Vec<&'static str>> = const { RefCell::new(vec![]) };
+    }
+
+    /// A `HelloRequest` counting the fields it decodes.
+    #[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+    struct Counted {
+        name: String,
+    }
+
+    impl Message for Counted {
+        fn encode_raw(&self, buf: &mut impl BufMut) {
+            encoding::string::encode(1, &self.name, buf);
+        }
+
+        fn merge_field(
+            &mut self,
+            tag: u32,
+            wire_type: WireType,
+            buf: &mut impl Buf,
+            ctx: DecodeContext,
+        ) -> Result<(), DecodeError> {
+            DECODED_FIELDS.with(|decoded| decoded.set(decoded.get() + 1));
+            match tag {
+                1 => encoding::string::merge(wire_type, &mut self.name, buf, ctx),
+                _ => encoding::skip_field(wire_type, tag, buf, ctx),
+            }
+        }
+
+        fn encoded_len(&self) -> usize {
+            encoding::string::encoded_len(1, &self.name)
+        }
+
+        fn clear(&mut self) {
+            self.name.clear();
+        }
+    }
+
+    /// Counts the times it's extracted.
+    struct Counter;
+
+    impl<M: Message, S: Send + Sync> RpcFromRequestParts<M, S> for Counter {
+        type Rejection = RpcError;
+
+        async fn rpc_from_request_parts(
+            _parts: &mut http::request::Parts,
+            _state: &S,
+        ) -> Result<Self, RpcError> {
+            EXTRACTED.with(|extracted| extracted.set(extracted.get() + 1));
+            Ok(Counter)
+        }
+    }
+
+    async fn peeking(
+        _: Counter,
+        peeked: Peek<Counted>,
+        request: Counted,
+    ) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse {
+            message: format!("peeked {}, got {}", peeked.name, request.name),
+        })
+    }
+
+    fn peeking_client() -> crate::testing::TestClient {
+        client(RpcRouter::new().rpc_method(RpcMethod::unary(unary_info(SAY_HELLO), peeking)))
+    }
+
+    fn counted(name: &str) -> Counted {
+        Counted {
+            name: name.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn peeks_the_message_the_handler_gets() {
+        let response = peeking_client()
+            .send(proto_request(SAY_HELLO, &counted("Ada")))
+            .await;
+
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            "peeked Ada, got Ada"
+        );
+    }
+
+    #[tokio::test]
+    async fn decodes_peeked_messages_once() {
+        DECODED_FIELDS.with(|decoded| decoded.set(0));
+
+        let response = peeking_client()
+            .send(proto_request(SAY_HELLO, &counted("Ada")))
+            .await;
+
+        assert_eq!(response.error(), None);
+        assert_eq!(DECODED_FIELDS.with(Cell::get), 1);
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_messages_before_running_the_extractors() {
+        EXTRACTED.with(|extracted| extracted.set(0));
+
+        let request = axum::http::Request::post(SAY_HELLO)
+            .header("content-type", "application/proto")
+            .body(axum::body::Body::from(vec![0x0a, 0xff]))
+            .unwrap();
+        let error = peeking_client().send(request).await.error().unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+        assert_eq!(EXTRACTED.with(Cell::get), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_peeks_of_other_messages() {
+        async fn mistyped(_: Peek<String>, request: Counted) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: request.name,
+            })
+        }
+        let client =
+            client(RpcRouter::new().rpc_method(RpcMethod::unary(unary_info(SAY_HELLO), mistyped)));
+
+        let error = client
+            .send(proto_request(SAY_HELLO, &counted("Ada")))
+            .await
+            .error()
+            .unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::Internal);
+    }
+
+    #[tokio::test]
+    async fn reads_metadata_case_insensitively_with_every_value() {
+        async fn tenants(metadata: RpcMetadata, _: HelloRequest) -> RpcResult<HelloResponse> {
+            let all = metadata.get_all("X-Tenant").collect::<Vec<_>>();
+            Ok(HelloResponse {
+                message: format!("{:?} {:?}", metadata.get("x-tenant"), all),
+            })
+        }
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, tenants)));
+
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .append("X-Tenant", HeaderValue::from_static("acme"));
+        request
+            .headers_mut()
+            .append("x-tenant", HeaderValue::from_static("globex"));
+        let response = client.send(request).await;
+
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            r#"Some("acme, globex") ["acme", "globex"]"#
+        );
+    }
+
+    /// The `x-user` of a request, counting the times it's verified.
+    #[derive(Clone, Debug)]
+    struct Claims(String);
+
+    impl<M: Message, S: Send + Sync> RpcFromRequestParts<M, S> for Claims {
+        type Rejection = RpcError;
+
+        async fn rpc_from_request_parts(
+            parts: &mut http::request::Parts,
+            _state: &S,
+        ) -> Result<Self, RpcError> {
+            VERIFIED.with(|verified| verified.set(verified.get() + 1));
+            let user = parts.headers.get("x-user").ok_or_else(|| {
+                RpcError::new(RpcErrorCode::Unauthenticated, "No user".to_string())
+            })?;
+            Ok(Claims(user.to_str().unwrap().to_string()))
+        }
+    }
+
+    async fn twice_claimed(
+        Cached(first): Cached<Claims>,
+        Cached(second): Cached<Claims>,
+        _: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse {
+            message: format!("{} {}", first.0, second.0),
+        })
+    }
+
+    fn claimed_request(user: Option<&'static str>) -> Request {
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        if let Some(user) = user {
+            request
+                .headers_mut()
+                .insert("x-user", HeaderValue::from_static(user));
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn runs_cached_extractors_once_per_request() {
+        VERIFIED.with(|verified| verified.set(0));
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, twice_claimed)));
+
+        let response = client.send(claimed_request(Some("ada"))).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "ada ada");
+        assert_eq!(VERIFIED.with(Cell::get), 1);
+
+        client.send(claimed_request(Some("bob"))).await;
+        assert_eq!(VERIFIED.with(Cell::get), 2);
+    }
+
+    #[tokio::test]
+    async fn shares_cached_extractions_with_middlewares() {
+        async fn log_user(request: Request, next: Next) -> Response {
+            let (mut parts, body) = request.into_parts();
+            let user = Cached::<Claims>::extract(&mut parts, &()).await;
+            assert_eq!(user.unwrap().0, "ada");
+            next.run(Request::from_parts(parts, body)).await
+        }
+        VERIFIED.with(|verified| verified.set(0));
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, twice_claimed))
+                .layer(from_fn(log_user)),
+        );
+
+        let response = client.send(claimed_request(Some("ada"))).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "ada ada");
+        assert_eq!(VERIFIED.with(Cell::get), 1);
+    }
+
+    #[tokio::test]
+    async fn caches_rejections_too() {
+        async fn checked(request: Request, next: Next) -> Response {
+            let (mut parts, body) = request.into_parts();
+            let error = Cached::<Claims>::extract(&mut parts, &())
+                .await
+                .unwrap_err();
+            assert_eq!(error.code, RpcErrorCode::Unauthenticated);
+            next.run(Request::from_parts(parts, body)).await
+        }
+        VERIFIED.with(|verified| verified.set(0));
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, twice_claimed))
+                .layer(from_fn(checked)),
+        );
+
+        let error = client.send(claimed_request(None)).await.error().unwrap();
+
+        assert_eq!(
+            error,
+            RpcError::new(RpcErrorCode::Unauthenticated, "No user".to_string())
+        );
+        assert_eq!(VERIFIED.with(Cell::get), 1);
+    }
+
+    #[cfg(feature = "sessions")]
+    #[tokio::test]
+    async fn keeps_session_values_across_calls() {
+        use tower_sessions::{MemoryStore, Session, SessionManagerLayer};
+
+        async fn visit(session: Session, request: HelloRequest) -> RpcResult<HelloResponse> {
+            let visits = session.get::<u32>("visits").await.unwrap().unwrap_or(0) + 1;
+            session.insert("visits", visits).await.unwrap();
+            Ok(HelloResponse {
+                message: format!("Hello {}, visit {visits}!", request.name),
+            })
+        }
+
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, visit))
+            .into_router()
+            .layer(SessionManagerLayer::new(MemoryStore::default()));
+        let client = crate::test_util::client_of(router);
+
+        let first = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(
+            message::<HelloResponse>(&first).message,
+            "Hello Ada, visit 1!"
+        );
+        let cookie = first.headers["set-cookie"].to_str().unwrap();
+        let cookie = cookie.split(';').next().unwrap().to_string();
+
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("cookie", cookie.parse().unwrap());
+        let second = client.send(request).await;
+        assert_eq!(
+            message::<HelloResponse>(&second).message,
+            "Hello Ada, visit 2!"
+        );
+    }
+
+    #[cfg(feature = "sessions")]
+    #[tokio::test]
+    async fn rejects_sessions_without_the_layer() {
+        async fn visit(_: tower_sessions::Session, _: HelloRequest) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse::default())
+        }
+
+        let router = RpcRouter::new().rpc_method(unary(SAY_HELLO, visit));
+        let response = client(router)
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await;
+        assert_eq!(response.error().unwrap().code, RpcErrorCode::Internal);
+    }
+
+    /// The `name` header, noting that it ran in [`RAN`].
+    fn required_header(parts: &http::request::Parts, name: &'static str) -> RpcResult<String> {
+        RAN.with(|ran| ran.borrow_mut().push(name));
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| {
+                RpcError::new(RpcErrorCode::InvalidArgument, format!("Missing {}", name))
+            })
+    }
+
+    macro_rules! header_extractor {
+        ($name:ident, $header:literal) => {
+            struct $name(String);
+
+            impl<M: Message, S: Send + Sync> RpcFromRequestParts<M, S> for $name {
+                type Rejection = RpcError;
+
+                async fn rpc_from_request_parts(
+                    parts: &mut http::request::Parts,
+                    _state: &S,
+                ) -> Result<Self, RpcError> {
+                    required_header(parts, $header).map($name)
+                }
+            }
+        };
+    }
+
+    header_extractor!(Tenant, "x-tenant");
+    header_extractor!(Locale, "x-locale");
+    header_extractor!(RequestId, "x-request-id");
+
+    async fn with_context(
+        (Tenant(tenant), Locale(locale), RequestId(id)): (Tenant, Locale, RequestId),
+        _: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse {
+            message: format!("{tenant} {locale} {id}"),
+        })
+    }
+
+    /// A call of [`with_context`] with `headers`, and the extractors that ran.
+    async fn call_with_context(
+        headers: &[(&'static str, &'static str)],
+    ) -> (crate::testing::TestResponse, Vec<&'static str>) {
+        RAN.with(|ran| ran.borrow_mut().clear());
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .insert(*name, http::HeaderValue::from_static(value));
+        }
+        let router = RpcRouter::new().rpc_method(unary(SAY_HELLO, with_context));
+        let response = client(router).send(request).await;
+        (response, RAN.with(|ran| ran.take()))
+    }
+
+    #[tokio::test]
+    async fn extracts_tuples_in_order() {
+        let (response, ran) = call_with_context(&[
+            ("x-tenant", "acme"),
+            ("x-locale", "en-GB"),
+            ("x-request-id", "42"),
+        ])
+        .await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "acme en-GB 42");
+        assert_eq!(ran, ["x-tenant", "x-locale", "x-request-id"]);
+    }
+
+    #[tokio::test]
+    async fn stops_tuples_at_the_first_rejection() {
+        let (response, ran) =
+            call_with_context(&[("x-tenant", "acme"), ("x-request-id", "42")]).await;
+
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                "Missing x-locale".to_string()
+            ))
+        );
+        assert_eq!(ran, ["x-tenant", "x-locale"]);
+    }
+
+    #[tokio::test]
+    async fn shares_extracted_values_in_arcs() {
+        async fn shared(
+            tenant: std::sync::Arc<Tenant>,
+            _: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            let task = tokio::spawn({
+                let tenant = tenant.clone();
+                async move { tenant.0.len() }
+            });
+            Ok(HelloResponse {
+                message: format!("{} has {} letters", tenant.0, task.await.unwrap()),
+            })
+        }
+
+        let router = RpcRouter::new().rpc_method(unary(SAY_HELLO, shared));
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("x-tenant", http::HeaderValue::from_static("acme"));
+        let response = client(router).send(request).await;
+
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            "acme has 4 letters"
+        );
+    }
+}
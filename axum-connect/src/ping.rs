@@ -0,0 +1,311 @@
+//! A liveness RPC for load balancers, see [`RpcRouter::add_ping_service`].
+//!
+//! [`RpcRouter::add_ping_service`]: crate::router::RpcRouter::add_ping_service
+
+use std::{
+    convert::Infallible,
+    future::{ready, Ready},
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue, Method},
+    response::Response,
+};
+use futures::future::{BoxFuture, Either};
+use tower::Service;
+
+use crate::{
+    auth::authorize,
+    codec::ContentType,
+    drain::DrainController,
+    error::{RpcError, RpcErrorCode},
+    handler::codec::ResponseEncoder,
+    router::{RpcIdempotencyLevel, RpcMethodInfo, RpcMethodKind},
+};
+
+/// The path of the ping RPC.
+pub const PING_PATH: &str = "/axum.connect.Ping/Ping";
+
+/// `google.protobuf.Empty` as JSON, encoded as binary it's no bytes at all.
+const EMPTY_JSON: &[u8] = b"{}";
+
+/// Answers `/axum.connect.Ping/Ping` with an empty message (`google.protobuf.Empty`), over POST and
+/// GET, in both codecs. The request body isn't read and no handler, state or extractor is
+/// involved, so a call costs no more than the response headers.
+///
+/// It isn't authorized by default, even on a router with
+/// [`authorize`](crate::router::RpcRouter::authorize), so load balancers don't need credentials.
+/// [`authorized`](PingService::authorized) runs the authorizer first. It fails with `Unavailable`
+/// once the router's [`DrainController`] drains. Layers of the router (eg. rate limits) apply to
+/// it like to any route; the per-RPC ones (config, options, hooks, signing, default headers)
+/// don't.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PingService {
+    authorized: bool,
+}
+
+impl PingService {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the router's [`RpcAuthorize`](crate::auth::RpcAuthorize) before answering, failing
+    /// pings it rejects.
+    pub fn authorized(mut self, authorized: bool) -> Self {
+        self.authorized = authorized;
+        self
+    }
+
+    /// The info the ping RPC is recorded with, eg. in the debug routes.
+    pub fn info() -> RpcMethodInfo {
+        RpcMethodInfo::from_rpc_path(
+            PING_PATH,
+            RpcMethodKind::Unary,
+            RpcIdempotencyLevel::NoSideEffects,
+        )
+        .with_http_methods(vec![Method::POST, Method::GET])
+    }
+}
+
+/// Ready right away, unless the ping is authorized.
+type PingFuture =
+    Either<Ready<Result<Response, Infallible>>, BoxFuture<'static, Result<Response, Infallible>>>;
+
+impl Service<Request> for PingService {
+    type Response = Response;
+    type Error = Infallible;
+    type Future = PingFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        if request
+            .extensions()
+            .get::<DrainController>()
+            .is_some_and(DrainController::is_draining)
+        {
+            let error = DrainController::unavailable();
+            return Either::Left(ready(Ok(
+                ResponseEncoder::error(error, false, false).encode_response()
+            )));
+        }
+        if !self.authorized {
+            return Either::Left(ready(Ok(pong(&request))));
+        }
+
+        Either::Right(Box::pin(async move {
+            let (mut parts, body) = request.into_parts();
+            if let Err(error) = authorize(&mut parts).await {
+                return Ok(ResponseEncoder::error(error, false, false).encode_response());
+            }
+            Ok(pong(&Request::from_parts(parts, body)))
+        }))
+    }
+}
+
+/// The empty response in the codec of `request`.
+fn pong(request: &Request) -> Response {
+    let binary = match codec(request) {
+        Ok(binary) => binary,
+        Err(error) => return ResponseEncoder::error(error, false, false).encode_response(),
+    };
+
+    let (content_type, body) = match binary {
+        true => (ContentType::Proto, Body::empty()),
+        false => (ContentType::Json, Body::from(EMPTY_JSON)),
+    };
+    let mut response = Response::new(body);
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(content_type.as_str()),
+    );
+    headers.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(if binary { 0 } else { EMPTY_JSON.len() }),
+    );
+    response
+}
+
+/// Whether `request` asks for binary protobuf, from its `Content-Type` or, over GET, its
+/// `encoding` query parameter.
+fn codec(request: &Request) -> Result<bool, RpcError> {
+    match *request.method() {
+        Method::GET | Method::HEAD => {
+            let query = request.uri().query().unwrap_or_default();
+            match query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("encoding="))
+            {
+                Some("proto") => Ok(true),
+                Some("json") | None => Ok(false),
+                Some(_) => Err(RpcError::new(
+                    RpcErrorCode::InvalidArgument,
+                    "The encoding query parameter must be `proto` or `json`".to_string(),
+                )),
+            }
+        }
+        Method::POST => match request
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+        {
+            Some(content_type) if !content_type.is_streaming() => Ok(content_type.is_binary()),
+            _ => Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                "Ping takes application/json or application/proto".to_string(),
+            )),
+        },
+        ref method => Err(RpcError::new(
+            RpcErrorCode::Unimplemented,
+            format!("HTTP method {} is not supported, use POST or GET", method),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::{request, StatusCode};
+
+    use super::*;
+    use crate::{
+        auth::{AuthContext, RpcAuthorize},
+        prelude::*,
+        test_util::{client, hello, hello_router, peak_allocation, proto_request, SAY_HELLO},
+        testing::TestResponse,
+    };
+
+    fn post(content_type: &str, body: &'static [u8]) -> Request {
+        Request::post(PING_PATH)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn get(query: &str) -> Request {
+        Request::get(format!("{PING_PATH}{query}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn assert_pong(response: &TestResponse, content_type: &str, body: &[u8]) {
+        assert_eq!(response.status, StatusCode::OK, "{:?}", response.error());
+        assert_eq!(response.content_type(), Some(content_type));
+        assert_eq!(
+            response.headers[header::CONTENT_LENGTH],
+            body.len().to_string()
+        );
+        assert_eq!(response.body, body);
+    }
+
+    #[tokio::test]
+    async fn answers_both_codecs_over_post() {
+        let client = client(hello_router().add_ping_service());
+
+        let response = client.send(post("application/json", b"{}")).await;
+        assert_pong(&response, "application/json", b"{}");
+        let response = client.send(post("application/proto", b"")).await;
+        assert_pong(&response, "application/proto", b"");
+
+        // The body isn't read, it needn't even be a message.
+        let response = client.send(post("application/proto", b"\xff\xff")).await;
+        assert_pong(&response, "application/proto", b"");
+
+        let error = client
+            .send(post("application/connect+proto", b""))
+            .await
+            .error();
+        assert_eq!(error.unwrap().code, RpcErrorCode::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn answers_get_in_the_encoding_of_the_query() {
+        let client = client(hello_router().add_ping_service());
+
+        let response = client
+            .send(get("?connect=v1&encoding=proto&message="))
+            .await;
+        assert_pong(&response, "application/proto", b"");
+        let response = client
+            .send(get("?connect=v1&encoding=json&message=%7B%7D"))
+            .await;
+        assert_pong(&response, "application/json", b"{}");
+        let response = client.send(get("")).await;
+        assert_pong(&response, "application/json", b"{}");
+
+        let error = client.send(get("?encoding=xml")).await.error();
+        assert_eq!(error.unwrap().code, RpcErrorCode::InvalidArgument);
+    }
+
+    struct DenyAll;
+
+    impl RpcAuthorize for DenyAll {
+        async fn authorize(
+            &self,
+            _: &RpcMethodInfo,
+            _: &request::Parts,
+        ) -> Result<AuthContext, RpcError> {
+            Err(RpcError::new(
+                RpcErrorCode::PermissionDenied,
+                "Denied".to_string(),
+            ))
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_the_authorizer_unless_authorized() {
+        let open = client(hello_router().add_ping_service().authorize(DenyAll));
+        let error = open
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await
+            .error();
+        assert_eq!(error.unwrap().code, RpcErrorCode::PermissionDenied);
+        let response = open.send(post("application/proto", b"")).await;
+        assert_pong(&response, "application/proto", b"");
+
+        let authorized = client(
+            hello_router()
+                .add_ping_service_with(PingService::new().authorized(true))
+                .authorize(DenyAll),
+        );
+        let response = authorized.send(post("application/proto", b"")).await;
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::PermissionDenied,
+                "Denied".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn allocates_no_more_than_the_response() {
+        let (_, headers) = peak_allocation(async {
+            let mut response = Response::new(Body::empty());
+            let headers = response.headers_mut();
+            headers.insert(
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/proto"),
+            );
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(0));
+            response
+        })
+        .await;
+
+        let mut ping = PingService::new();
+        let request = post("application/proto", b"");
+        let (response, peak) = peak_allocation(async { ping.call(request).await }).await;
+
+        assert_eq!(response.unwrap().status(), StatusCode::OK);
+        // Give or take a few bytes of bookkeeping, nothing is allocated but the response.
+        assert!(
+            peak <= headers + 64,
+            "{peak} bytes, the headers alone are {headers}"
+        );
+    }
+}
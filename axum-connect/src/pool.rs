@@ -0,0 +1,203 @@
+//! A thread-local pool of response buffers.
+//!
+//! Response bodies and envelope frames are encoded into pooled `Vec`s, which are handed to the
+//! body as [`Bytes`] that return the `Vec` to the pool (of whichever thread drops them) once hyper
+//! is done with it.
+
+use std::cell::RefCell;
+
+use axum::body::Bytes;
+
+/// Buffers kept per thread.
+const MAX_POOLED: usize = 64;
+
+/// Larger buffers are freed instead of pooled, so one giant response doesn't pin its memory.
+pub(crate) const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty buffer, from the pool if it has one.
+pub(crate) fn take() -> Vec<u8> {
+    match POOL.with(|pool| pool.borrow_mut().pop()) {
+        Some(buffer) => {
+            stats::HITS.increment();
+            buffer
+        }
+        None => {
+            stats::MISSES.increment();
+            Vec::new()
+        }
+    }
+}
+
+/// Hands `buffer` over to a response body, it's returned to the pool when the body drops it.
+pub(crate) fn into_bytes(buffer: Vec<u8>) -> Bytes {
+    Bytes::from_owner(PooledBuffer(buffer))
+}
+
+fn give_back(mut buffer: Vec<u8>) {
+    if buffer.capacity() > MAX_POOLED_CAPACITY {
+        stats::DISCARDED.increment();
+        return;
+    }
+
+    buffer.clear();
+    let pooled = POOL.with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_POOLED {
+            pool.push(buffer);
+            true
+        } else {
+            false
+        }
+    });
+
+    if pooled {
+        stats::RETURNED.increment();
+    } else {
+        stats::DISCARDED.increment();
+    }
+}
+
+struct PooledBuffer(Vec<u8>);
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        give_back(std::mem::take(&mut self.0));
+    }
+}
+
+/// Counters for tuning the pool, totals across all threads since startup.
+#[cfg(feature = "debug-metrics")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers taken from the pool.
+    pub hits: u64,
+    /// Buffers allocated because the pool was empty.
+    pub misses: u64,
+    /// Buffers returned to the pool.
+    pub returned: u64,
+    /// Buffers freed because they were too large or the pool was full.
+    pub discarded: u64,
+}
+
+/// The current [`BufferPoolStats`].
+#[cfg(feature = "debug-metrics")]
+pub fn buffer_pool_stats() -> BufferPoolStats {
+    BufferPoolStats {
+        hits: stats::HITS.get(),
+        misses: stats::MISSES.get(),
+        returned: stats::RETURNED.get(),
+        discarded: stats::DISCARDED.get(),
+    }
+}
+
+mod stats {
+    #[cfg(feature = "debug-metrics")]
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// A counter that compiles to nothing without the `debug-metrics` feature.
+    pub struct Counter {
+        #[cfg(feature = "debug-metrics")]
+        value: AtomicU64,
+    }
+
+    impl Counter {
+        const fn new() -> Self {
+            Self {
+                #[cfg(feature = "debug-metrics")]
+                value: AtomicU64::new(0),
+            }
+        }
+
+        pub fn increment(&self) {
+            #[cfg(feature = "debug-metrics")]
+            self.value.fetch_add(1, Ordering::Relaxed);
+        }
+
+        #[cfg(feature = "debug-metrics")]
+        pub fn get(&self) -> u64 {
+            self.value.load(Ordering::Relaxed)
+        }
+    }
+
+    pub static HITS: Counter = Counter::new();
+    pub static MISSES: Counter = Counter::new();
+    pub static RETURNED: Counter = Counter::new();
+    pub static DISCARDED: Counter = Counter::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::test_util::allocated_by;
+
+    fn encode(payload: &[u8]) -> Bytes {
+        let mut buffer = take();
+        buffer.extend_from_slice(payload);
+        into_bytes(buffer)
+    }
+
+    #[test]
+    fn reuses_buffers() {
+        let payload = vec![7; 4096];
+        drop(encode(&payload));
+
+        let pooled = allocated_by(|| {
+            for _ in 0..100 {
+                let bytes = encode(&payload);
+                assert_eq!(bytes, payload);
+            }
+        });
+        let unpooled = allocated_by(|| {
+            for _ in 0..100 {
+                let bytes = Bytes::from(payload.clone());
+                assert_eq!(bytes, payload);
+            }
+        });
+        // Only the owners of the `Bytes` are allocated.
+        assert!(
+            pooled * 10 < unpooled,
+            "{pooled} bytes pooled, {unpooled} unpooled"
+        );
+    }
+
+    #[test]
+    fn frees_large_buffers() {
+        thread::spawn(|| {
+            drop(encode(&vec![7; MAX_POOLED_CAPACITY + 1]));
+            assert_eq!(take().capacity(), 0);
+
+            drop(encode(&[7; 16]));
+            assert!(take().capacity() >= 16);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn keeps_at_most_max_pooled() {
+        thread::spawn(|| {
+            let bytes: Vec<_> = (0..MAX_POOLED + 1).map(|_| encode(&[7; 16])).collect();
+            drop(bytes);
+            let buffers: Vec<_> = (0..MAX_POOLED + 1).map(|_| take()).collect();
+            let pooled = buffers
+                .iter()
+                .filter(|buffer| buffer.capacity() > 0)
+                .count();
+            assert_eq!(pooled, MAX_POOLED);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+//! Passing Connect streams on without decoding their messages, eg. in a proxy in front of another
+//! Connect server, see [`ValidatedConnectBody`].
+
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+};
+
+use axum::{body::Body, BoxError};
+use bytes::Bytes;
+use http_body::{Frame, SizeHint};
+
+use crate::{
+    codec::FrameDecoder,
+    error::{RpcError, RpcErrorCode},
+};
+
+/// A streaming request or response body, checked envelope by envelope as it passes through and
+/// otherwise left as it is: the bytes come out unchanged and in the same chunks, except for up to
+/// 4 bytes of an envelope prefix held back until the chunk completing it arrives.
+///
+/// The envelopes must have known flags, nothing may follow the end-of-stream frame, and the stream
+/// must not end halfway through an envelope; [`max_frame_bytes`](Self::max_frame_bytes) and
+/// [`max_bytes`](Self::max_bytes) limit them. On the first violation the body is cut, at the start
+/// of the envelope at fault or at the `max_bytes`th byte, and fails with the error after the bytes
+/// before it, so what's on the other end sees a failed body rather than a shorter stream. The
+/// error is also kept in its [`BodyValidation`], for the caller that handed the body on.
+///
+/// ```
+/// # use axum::{body::Body, http::Request};
+/// # use axum_connect::proxy::ValidatedConnectBody;
+/// fn forward(request: Request<Body>) -> Request<Body> {
+///     let (parts, body) = request.into_parts();
+///     let body = ValidatedConnectBody::new(body).max_frame_bytes(4 << 20);
+///     let validation = body.validation();
+///     // Once the upstream call failed: `validation.error()` says if it's the client's fault.
+///     Request::from_parts(parts, Body::new(body))
+/// }
+/// ```
+pub struct ValidatedConnectBody {
+    inner: Body,
+    decoder: FrameDecoder,
+    max_bytes: Option<u64>,
+    require_end_stream: bool,
+    validation: BodyValidation,
+    /// The violation the body fails with, once the bytes before it have been passed on.
+    violation: Option<RpcError>,
+    done: bool,
+}
+
+impl ValidatedConnectBody {
+    pub fn new<B>(body: B) -> Self
+    where
+        B: http_body::Body<Data = Bytes> + Send + 'static,
+        B::Error: Into<BoxError>,
+    {
+        Self {
+            inner: Body::new(body),
+            decoder: FrameDecoder::new(),
+            max_bytes: None,
+            require_end_stream: false,
+            validation: BodyValidation::default(),
+            violation: None,
+            done: false,
+        }
+    }
+
+    /// Envelopes announcing a larger payload fail with `ResourceExhausted`.
+    pub fn max_frame_bytes(mut self, max_frame_bytes: usize) -> Self {
+        self.decoder = self.decoder.max_frame_bytes(max_frame_bytes);
+        self
+    }
+
+    /// Bodies of more bytes in total fail with `ResourceExhausted`, eg. the
+    /// [`max_request_bytes`](crate::config::RpcConfig::max_request_bytes) of the server behind.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Fails a body ending without the end-of-stream frame with `InvalidArgument`, for response
+    /// streams. Request streams don't have one.
+    pub fn require_end_stream(mut self) -> Self {
+        self.require_end_stream = true;
+        self
+    }
+
+    /// What's been seen of the body, shared with it.
+    pub fn validation(&self) -> BodyValidation {
+        self.validation.clone()
+    }
+
+    /// Cuts the body with `error`, after the bytes already passed on.
+    fn violate(&mut self, error: RpcError) {
+        *self.validation.error.lock().unwrap() = Some(error.clone());
+        self.violation = Some(error);
+    }
+
+    /// The checks once the inner body has ended.
+    fn finish(&mut self) -> Result<(), RpcError> {
+        let decoder = std::mem::take(&mut self.decoder);
+        let ended = decoder.passed_end_stream();
+        decoder.finish()?;
+        if self.require_end_stream && !ended {
+            return Err(RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                "The stream ended without an end-of-stream frame".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl http_body::Body for ValidatedConnectBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(error) = this.violation.take() {
+                this.done = true;
+                return Poll::Ready(Some(Err(axum::Error::new(error.message))));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+
+            let frame = match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => frame,
+                Some(Err(error)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(error)));
+                }
+                None => {
+                    if let Err(error) = this.finish() {
+                        this.violate(error);
+                        continue;
+                    }
+                    this.done = true;
+                    return Poll::Ready(None);
+                }
+            };
+            // HTTP trailers aren't part of Connect, they're passed on as they are.
+            let data = match frame.into_data() {
+                Ok(data) => data,
+                Err(frame) => return Poll::Ready(Some(Ok(frame))),
+            };
+
+            let mut data = match this.decoder.pass(data) {
+                Ok(data) => data,
+                Err((data, error)) => {
+                    this.violate(error);
+                    data
+                }
+            };
+            let passed = this.validation.passed_bytes();
+            if let Some(max) = this
+                .max_bytes
+                .filter(|max| passed + data.len() as u64 > *max)
+            {
+                data.truncate((max - passed) as usize);
+                this.violate(RpcError::new(
+                    RpcErrorCode::ResourceExhausted,
+                    format!("The body is larger than the {} byte limit", max),
+                ));
+            }
+
+            if data.is_empty() {
+                continue;
+            }
+            this.validation
+                .passed
+                .fetch_add(data.len() as u64, Ordering::Relaxed);
+            return Poll::Ready(Some(Ok(Frame::data(data))));
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// The progress of a [`ValidatedConnectBody`], for the caller after it's been handed on. Clones
+/// share it.
+#[derive(Clone, Debug, Default)]
+pub struct BodyValidation {
+    passed: Arc<AtomicU64>,
+    error: Arc<Mutex<Option<RpcError>>>,
+}
+
+impl BodyValidation {
+    /// Bytes of the body passed on so far.
+    pub fn passed_bytes(&self) -> u64 {
+        self.passed.load(Ordering::Relaxed)
+    }
+
+    /// The violation the body was cut at, if it was.
+    pub fn error(&self) -> Option<RpcError> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+
+    use http_body::Body as _;
+
+    use futures::stream;
+
+    use super::*;
+    use crate::{
+        codec::{encode_envelope, EndStreamResponse},
+        test_util::{frames, hello},
+    };
+
+    /// A body sending `chunks` one at a time.
+    fn chunked(chunks: Vec<Vec<u8>>) -> Body {
+        Body::from_stream(stream::iter(
+            chunks.into_iter().map(Ok::<_, std::io::Error>),
+        ))
+    }
+
+    /// The data chunks the body passes on, and the error it ends with.
+    async fn passed(mut body: ValidatedConnectBody) -> (Vec<Bytes>, Option<axum::Error>) {
+        let mut chunks = vec![];
+        while let Some(frame) = poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await {
+            match frame {
+                Ok(frame) => chunks.push(frame.into_data().unwrap()),
+                Err(error) => return (chunks, Some(error)),
+            }
+        }
+        (chunks, None)
+    }
+
+    /// The frames of a response stream of three greetings.
+    fn response_stream() -> Vec<u8> {
+        let mut body = frames(&[hello("Ada"), hello("Grace"), hello("Linus")]);
+        let end = serde_json::to_vec(&EndStreamResponse::default()).unwrap();
+        encode_envelope(EndStreamResponse::FLAG, &end, &mut body);
+        body
+    }
+
+    #[tokio::test]
+    async fn passes_streams_on_byte_for_byte() {
+        let stream = response_stream();
+        // Split inside an envelope prefix and inside a payload.
+        let chunks = vec![
+            stream[..3].to_vec(),
+            stream[3..12].to_vec(),
+            stream[12..].to_vec(),
+        ];
+        let body = ValidatedConnectBody::new(chunked(chunks)).require_end_stream();
+        let validation = body.validation();
+
+        let (chunks, error) = passed(body).await;
+
+        assert!(error.is_none());
+        assert_eq!(chunks.concat(), stream);
+        // Partial prefixes wait for the rest: the first chunk's 3 bytes, and the start of the
+        // second envelope at the end of the second chunk.
+        assert_eq!(chunks[0], stream[..10]);
+        assert_eq!(validation.passed_bytes(), stream.len() as u64);
+        assert!(validation.error().is_none());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&chunks.concat());
+        let mut payloads = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            payloads.push(frame.payload.to_vec());
+        }
+        assert_eq!(payloads.len(), 4);
+        decoder.finish().unwrap();
+    }
+
+    #[tokio::test]
+    async fn fails_streams_ending_inside_an_envelope() {
+        let stream = frames(&[hello("Ada"), hello("Grace")]);
+        let truncated = stream[..stream.len() - 2].to_vec();
+        let body = ValidatedConnectBody::new(chunked(vec![truncated.clone()]));
+        let validation = body.validation();
+
+        let (chunks, error) = passed(body).await;
+
+        assert_eq!(chunks.concat(), truncated);
+        assert_eq!(
+            error.unwrap().to_string(),
+            "Truncated envelope, 2 payload bytes missing"
+        );
+        assert_eq!(
+            validation.error().unwrap().code,
+            RpcErrorCode::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_streams_ending_inside_an_envelope_prefix() {
+        let mut stream = frames(&[hello("Ada")]);
+        stream.extend_from_slice(&[0, 0, 0]);
+        let body = ValidatedConnectBody::new(chunked(vec![stream.clone()]));
+
+        let (chunks, error) = passed(body).await;
+
+        assert_eq!(chunks.concat(), stream[..stream.len() - 3]);
+        assert_eq!(
+            error.unwrap().to_string(),
+            "Truncated envelope, expected at least 5 bytes but got 3"
+        );
+    }
+
+    #[tokio::test]
+    async fn cuts_streams_at_the_envelope_at_fault() {
+        let good = frames(&[hello("Ada")]);
+        let mut stream = good.clone();
+        encode_envelope(0x80, b"bad", &mut stream);
+        let body = ValidatedConnectBody::new(chunked(vec![stream]));
+        let validation = body.validation();
+
+        let (chunks, error) = passed(body).await;
+
+        assert_eq!(chunks.concat(), good);
+        assert_eq!(error.unwrap().to_string(), "Unknown envelope flags 0x80");
+        assert_eq!(validation.passed_bytes(), good.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn limits_frames_and_bodies() {
+        let stream = frames(&[hello("Ada"), hello("Grace")]);
+
+        let body = ValidatedConnectBody::new(chunked(vec![stream.clone()])).max_frame_bytes(4);
+        let (chunks, error) = passed(body).await;
+        assert!(chunks.is_empty());
+        assert_eq!(
+            error.unwrap().to_string(),
+            "Envelope of 5 bytes is larger than the 4 byte limit"
+        );
+
+        let body = ValidatedConnectBody::new(chunked(vec![stream.clone()])).max_bytes(12);
+        let (chunks, error) = passed(body).await;
+        assert_eq!(chunks.concat(), stream[..12]);
+        assert_eq!(
+            error.unwrap().to_string(),
+            "The body is larger than the 12 byte limit"
+        );
+    }
+
+    #[tokio::test]
+    async fn requires_the_end_of_response_streams_if_asked_to() {
+        let stream = frames(&[hello("Ada")]);
+
+        let body = ValidatedConnectBody::new(chunked(vec![stream.clone()]));
+        assert!(passed(body).await.1.is_none());
+
+        let body = ValidatedConnectBody::new(chunked(vec![stream])).require_end_stream();
+        assert_eq!(
+            passed(body).await.1.unwrap().to_string(),
+            "The stream ended without an end-of-stream frame"
+        );
+    }
+}
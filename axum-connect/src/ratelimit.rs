@@ -0,0 +1,361 @@
+//! Per-method, per-caller rate limits that fail with a proper Connect error.
+//!
+//! Limits use GCRA (a token bucket that only stores one timestamp per key). State is kept in an
+//! [`RpcRateLimitStore`], in process by default, which distributed setups can replace with a
+//! shared one.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::Body,
+    http::{header, request, HeaderValue, Request},
+    response::Response,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    codec::ContentType,
+    error::{RpcError, RpcErrorCode, RpcErrorDetail},
+    handler::codec::ResponseEncoder,
+};
+
+/// Allows `requests` calls per `period`, all of which can be made at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RpcRateLimit {
+    pub requests: u32,
+    pub period: Duration,
+}
+
+impl RpcRateLimit {
+    pub fn new(requests: u32, period: Duration) -> Self {
+        Self { requests, period }
+    }
+
+    pub fn per_second(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(1))
+    }
+
+    pub fn per_minute(requests: u32) -> Self {
+        Self::new(requests, Duration::from_secs(60))
+    }
+
+    /// The time one request "costs".
+    fn emission_interval(&self) -> Duration {
+        self.period / self.requests.max(1)
+    }
+}
+
+/// The future returned by [`RpcRateLimitStore::check`].
+pub type RpcRateLimitFuture<'a> = Pin<Box<dyn Future<Output = Result<(), Duration>> + Send + 'a>>;
+
+/// Where rate limit state lives. `key` identifies both the method and the caller.
+pub trait RpcRateLimitStore: Send + Sync + 'static {
+    /// Counts one call against `limit`, or returns how long to wait if it's exceeded. Rejected calls
+    /// must not be counted.
+    fn check<'a>(&'a self, key: &'a str, limit: RpcRateLimit) -> RpcRateLimitFuture<'a>;
+}
+
+/// The default, in process [`RpcRateLimitStore`].
+#[derive(Default)]
+pub struct MemoryRateLimitStore {
+    /// The theoretical arrival time of the next call, per key.
+    arrivals: Mutex<HashMap<String, Instant>>,
+}
+
+impl MemoryRateLimitStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn check_now(&self, key: &str, limit: RpcRateLimit, now: Instant) -> Result<(), Duration> {
+        let interval = limit.emission_interval();
+        let tolerance = limit.period.saturating_sub(interval);
+
+        let mut arrivals = self.arrivals.lock().unwrap();
+
+        // Keys whose bucket has refilled hold no information, drop them now and then.
+        if arrivals.len() >= 10_000 {
+            arrivals.retain(|_, arrival| *arrival > now);
+        }
+
+        let arrival = arrivals.get(key).copied().unwrap_or(now).max(now);
+        let wait = arrival - now;
+        if wait > tolerance {
+            return Err(wait - tolerance);
+        }
+
+        arrivals.insert(key.to_string(), arrival + interval);
+        Ok(())
+    }
+}
+
+impl RpcRateLimitStore for MemoryRateLimitStore {
+    fn check<'a>(&'a self, key: &'a str, limit: RpcRateLimit) -> RpcRateLimitFuture<'a> {
+        let result = self.check_now(key, limit, Instant::now());
+        Box::pin(async move { result })
+    }
+}
+
+impl fmt::Debug for MemoryRateLimitStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryRateLimitStore")
+            .finish_non_exhaustive()
+    }
+}
+
+type KeyExtractor = Arc<dyn Fn(&request::Parts) -> Option<String> + Send + Sync>;
+
+/// Rejects calls over their limit with `ResourceExhausted`, a `Retry-After` header and a
+/// `google.rpc.RetryInfo` error detail.
+///
+/// The key extractor names the caller, eg. from the bearer token or client IP. Calls it returns
+/// `None` for aren't limited. Mount it on an [`RpcRouter`](crate::router::RpcRouter) with
+/// `.layer(...)`.
+///
+/// ```
+/// # use axum_connect::ratelimit::{RpcRateLimit, RpcRateLimitLayer};
+/// let layer = RpcRateLimitLayer::new(RpcRateLimit::per_second(10), |parts| {
+///     parts
+///         .headers
+///         .get("authorization")
+///         .and_then(|v| v.to_str().ok())
+///         .map(|v| v.to_string())
+/// })
+/// .limit("/hello.HelloWorldService/SayHello", RpcRateLimit::per_second(2));
+/// ```
+#[derive(Clone)]
+pub struct RpcRateLimitLayer {
+    default_limit: RpcRateLimit,
+    limits: Arc<HashMap<String, RpcRateLimit>>,
+    key_extractor: KeyExtractor,
+    store: Arc<dyn RpcRateLimitStore>,
+}
+
+impl RpcRateLimitLayer {
+    /// Limits every method to `default_limit` per caller.
+    pub fn new<F>(default_limit: RpcRateLimit, key_extractor: F) -> Self
+    where
+        F: Fn(&request::Parts) -> Option<String> + Send + Sync + 'static,
+    {
+        Self {
+            default_limit,
+            limits: Default::default(),
+            key_extractor: Arc::new(key_extractor),
+            store: Arc::new(MemoryRateLimitStore::new()),
+        }
+    }
+
+    /// Overrides the limit for one method, by path (eg. `/hello.HelloWorldService/SayHello`).
+    pub fn limit(mut self, path: &str, limit: RpcRateLimit) -> Self {
+        Arc::make_mut(&mut self.limits).insert(path.to_string(), limit);
+        self
+    }
+
+    /// Keeps rate limit state in `store` instead of in process.
+    pub fn store(mut self, store: impl RpcRateLimitStore) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+}
+
+impl fmt::Debug for RpcRateLimitLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcRateLimitLayer")
+            .field("default_limit", &self.default_limit)
+            .field("limits", &self.limits)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Layer<S> for RpcRateLimitLayer {
+    type Service = RpcRateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcRateLimitService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// The service produced by [`RpcRateLimitLayer`].
+#[derive(Clone)]
+pub struct RpcRateLimitService<S> {
+    inner: S,
+    layer: RpcRateLimitLayer,
+}
+
+impl<S> Service<Request<Body>> for RpcRateLimitService<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let layer = self.layer.clone();
+        let mut inner = self.inner.clone();
+
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            let Some(caller) = (layer.key_extractor)(&parts) else {
+                return inner.call(Request::from_parts(parts, body)).await;
+            };
+
+            let path = parts.uri.path();
+            let limit = layer
+                .limits
+                .get(path)
+                .copied()
+                .unwrap_or(layer.default_limit);
+            let key = format!("{} {}", path, caller);
+
+            match layer.store.check(&key, limit).await {
+                Ok(()) => inner.call(Request::from_parts(parts, body)).await,
+                Err(retry_after) => Ok(rate_limited(&mut parts, retry_after)),
+            }
+        })
+    }
+}
+
+/// `google.rpc.RetryInfo`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub(crate) struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    pub retry_delay: Option<pbjson_types::Duration>,
+}
+
+fn rate_limited(parts: &mut request::Parts, retry_after: Duration) -> Response {
+    let error = RpcError::new(
+        RpcErrorCode::ResourceExhausted,
+        "Rate limit exceeded".to_string(),
+    );
+    retry_later(parts, error, retry_after)
+}
+
+/// Fails the call with `error`, telling the client to retry after `retry_after` with a
+/// `google.rpc.RetryInfo` detail and a `Retry-After` header.
+pub(crate) fn retry_later(
+    parts: &mut request::Parts,
+    mut error: RpcError,
+    retry_after: Duration,
+) -> Response {
+    let retry_info = RetryInfo {
+        retry_delay: Some(pbjson_types::Duration {
+            seconds: retry_after.as_secs() as i64,
+            nanos: retry_after.subsec_nanos() as i32,
+        }),
+    };
+    error
+        .details
+        .push(RpcErrorDetail::new("google.rpc.RetryInfo", &retry_info));
+
+    let content_type = ContentType::cached(&mut parts.extensions, &parts.headers);
+    let streaming = content_type.is_some_and(ContentType::is_streaming);
+    let binary = content_type.is_some_and(ContentType::is_binary);
+
+    let mut response = ResponseEncoder::error(error, streaming, binary).encode_response();
+
+    // Whole seconds, rounded up so clients don't retry too early.
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{
+        client, hello, hello_router, message, proto_request, stream_request, HelloResponse,
+        SAY_HELLO, SAY_HELLO_STREAM,
+    };
+
+    fn call_as(caller: &str, mut request: Request<Body>) -> Request<Body> {
+        request
+            .headers_mut()
+            .insert("x-caller", caller.parse().unwrap());
+        request
+    }
+
+    #[tokio::test]
+    async fn limits_each_method_and_caller() {
+        let layer = RpcRateLimitLayer::new(RpcRateLimit::per_second(100), |parts| {
+            let caller = parts.headers.get("x-caller")?;
+            Some(caller.to_str().ok()?.to_string())
+        })
+        .limit(SAY_HELLO, RpcRateLimit::per_second(2));
+        let client = client(hello_router().layer(layer));
+
+        for _ in 0..2 {
+            let response = client
+                .send(call_as("alice", proto_request(SAY_HELLO, &hello("Ada"))))
+                .await;
+            message::<HelloResponse>(&response);
+        }
+
+        let response = client
+            .send(call_as("alice", proto_request(SAY_HELLO, &hello("Ada"))))
+            .await;
+        let error = response.error().unwrap();
+        assert_eq!(error.code, RpcErrorCode::ResourceExhausted);
+        assert_eq!(response.headers[header::RETRY_AFTER], "1");
+        let [detail] = error.details.as_slice() else {
+            panic!("expected one detail, got {:?}", error.details);
+        };
+        assert_eq!(detail.proto_type, "google.rpc.RetryInfo");
+        let delay = detail.decode::<RetryInfo>().unwrap().retry_delay.unwrap();
+        assert!(delay.seconds == 0 && delay.nanos > 0, "{delay:?}");
+
+        // Someone else, and another method.
+        let response = client
+            .send(call_as("bob", proto_request(SAY_HELLO, &hello("Ada"))))
+            .await;
+        message::<HelloResponse>(&response);
+        let response = client
+            .send(call_as(
+                "alice",
+                stream_request(SAY_HELLO_STREAM, &hello("Ada")),
+            ))
+            .await;
+        assert_eq!(response.error(), None);
+
+        // Anonymous calls aren't limited.
+        for _ in 0..3 {
+            let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+            message::<HelloResponse>(&response);
+        }
+    }
+
+    #[test]
+    fn refills_over_the_period() {
+        let store = MemoryRateLimitStore::new();
+        let limit = RpcRateLimit::per_second(2);
+        let start = Instant::now();
+        assert_eq!(store.check_now("key", limit, start), Ok(()));
+        assert_eq!(store.check_now("key", limit, start), Ok(()));
+        assert_eq!(
+            store.check_now("key", limit, start),
+            Err(Duration::from_millis(500))
+        );
+        // A rejected call isn't counted.
+        let later = start + Duration::from_millis(500);
+        assert_eq!(store.check_now("key", limit, later), Ok(()));
+        assert!(store.check_now("key", limit, later).is_err());
+    }
+}
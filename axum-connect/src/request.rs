@@ -0,0 +1,338 @@
+//! The request of the handlers of service traits, the message with what came with it, see
+//! [`RpcRequest`].
+
+use std::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use axum::http::{self, Extensions};
+use futures::Stream;
+use prost::Message;
+
+use crate::{
+    error::RpcError,
+    handler::codec::ResponseCompression,
+    parts::{RpcDeadline, RpcFromRequestParts, RpcMetadata, RpcResponseHeaders, RpcTrailers},
+    response::{RpcResponse, RpcResult},
+    router::RpcMethodInfo,
+};
+
+/// A request message with its metadata, deadline, method and extensions, what the methods of the
+/// `<Service>Handlers` traits generated with `service_traits` take. It mirrors tonic's `Request`,
+/// so handlers move over by renaming types.
+///
+/// The extensions are those of the HTTP request, eg. what middleware inserted or the
+/// [`AuthContext`](crate::auth::AuthContext) once the call is authorized.
+///
+/// ```
+/// # use axum_connect::prelude::*;
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct GetUserRequest {}
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct User {}
+/// async fn get_user(request: RpcRequest<GetUserRequest>) -> RpcResult<RpcResponse<User>> {
+///     let tenant = request.metadata().get("x-tenant").map(|tenant| tenant.to_string());
+///     let remaining = request.deadline().remaining();
+///     let message = request.into_inner();
+///     # let _ = (tenant, remaining, message);
+///     Ok(User {}.into())
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct RpcRequest<M> {
+    message: M,
+    metadata: RpcMetadata,
+    deadline: RpcDeadline,
+    method: Option<RpcMethodInfo>,
+    extensions: Extensions,
+}
+
+impl<M> RpcRequest<M> {
+    /// A request without metadata, deadline or method, eg. to call a handler in a test.
+    pub fn new(message: M) -> Self {
+        Self {
+            message,
+            metadata: RpcMetadata::default(),
+            deadline: RpcDeadline::default(),
+            method: None,
+            extensions: Extensions::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &M {
+        &self.message
+    }
+
+    pub fn get_mut(&mut self) -> &mut M {
+        &mut self.message
+    }
+
+    pub fn into_inner(self) -> M {
+        self.message
+    }
+
+    pub fn metadata(&self) -> &RpcMetadata {
+        &self.metadata
+    }
+
+    pub fn metadata_mut(&mut self) -> &mut RpcMetadata {
+        &mut self.metadata
+    }
+
+    /// See [`RpcDeadline`].
+    pub fn deadline(&self) -> RpcDeadline {
+        self.deadline
+    }
+
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = RpcDeadline(Some(deadline));
+    }
+
+    /// The RPC called, `None` for requests built with [`new`](Self::new).
+    pub fn method(&self) -> Option<&RpcMethodInfo> {
+        self.method.as_ref()
+    }
+
+    pub fn extensions(&self) -> &Extensions {
+        &self.extensions
+    }
+
+    pub fn extensions_mut(&mut self) -> &mut Extensions {
+        &mut self.extensions
+    }
+
+    /// The request with its message replaced by `f` of it, eg. converted to a domain type.
+    pub fn map<N>(self, f: impl FnOnce(M) -> N) -> RpcRequest<N> {
+        RpcRequest {
+            message: f(self.message),
+            metadata: self.metadata,
+            deadline: self.deadline,
+            method: self.method,
+            extensions: self.extensions,
+        }
+    }
+}
+
+/// The request messages of a client-streaming call as they arrive, what client-streaming handlers
+/// take after their extractors. It ends when the client closes the request body, or with the first
+/// error, eg. a message that doesn't decode.
+///
+/// ```
+/// # use axum_connect::prelude::*;
+/// # use futures::TryStreamExt;
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Chunk { #[prost(bytes = "vec", tag = "1")] data: Vec<u8> }
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Upload { #[prost(uint64, tag = "1")] size: u64 }
+/// async fn upload(mut chunks: RpcRequestStream<Chunk>) -> RpcResult<Upload> {
+///     let mut size = 0;
+///     while let Some(chunk) = chunks.try_next().await? {
+///         size += chunk.data.len() as u64;
+///     }
+///     Ok(Upload { size })
+/// }
+/// ```
+///
+/// Handlers may answer before the client is done sending, eg. to reject a bad first message of a
+/// large upload: once the handler returns, the stream is dropped and the response goes out, without
+/// waiting for the rest of the body. It's never read, hyper closes the HTTP/1.1 connection after
+/// the response (or resets the HTTP/2 stream) instead of draining it.
+pub struct RpcRequestStream<M> {
+    inner: Pin<Box<dyn Stream<Item = RpcResult<M>> + Send>>,
+}
+
+impl<M> RpcRequestStream<M> {
+    /// The stream of `messages`, eg. to call a handler in a test.
+    pub fn new(messages: impl Stream<Item = RpcResult<M>> + Send + 'static) -> Self {
+        Self {
+            inner: Box::pin(messages),
+        }
+    }
+}
+
+impl<M> Stream for RpcRequestStream<M> {
+    type Item = RpcResult<M>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+}
+
+impl<M> fmt::Debug for RpcRequestStream<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcRequestStream").finish_non_exhaustive()
+    }
+}
+
+/// What an [`RpcRequest`] is built from besides its message, and where the headers and trailers
+/// of its [`RpcResponse`] go, taken by the handlers generated for service traits.
+#[doc(hidden)]
+#[derive(Debug)]
+pub struct RpcCallParts {
+    metadata: RpcMetadata,
+    deadline: RpcDeadline,
+    method: Option<RpcMethodInfo>,
+    extensions: Extensions,
+    headers: RpcResponseHeaders,
+    trailers: RpcTrailers,
+    compression: Option<ResponseCompression>,
+}
+
+impl RpcCallParts {
+    /// The request of `message`, with the metadata, method and extensions moved out of the parts.
+    pub fn request<M>(&mut self, message: M) -> RpcRequest<M> {
+        RpcRequest {
+            message,
+            metadata: std::mem::take(&mut self.metadata),
+            deadline: self.deadline,
+            method: self.method.take(),
+            extensions: std::mem::take(&mut self.extensions),
+        }
+    }
+
+    /// Sends the headers and trailers of `response` with the call's response, uncompressed if it
+    /// asked for it, returning its message.
+    pub fn respond<M>(self, response: RpcResponse<M>) -> M {
+        let (message, headers, trailers, no_compression) = response.into_parts();
+        if let Some(compression) = self.compression.filter(|_| no_compression) {
+            compression.set(None);
+        }
+        for (key, value) in headers {
+            if let Some(key) = key {
+                self.headers.append(key, value);
+            }
+        }
+        for (key, value) in trailers {
+            if let Some(key) = key {
+                self.trailers.append(key, value);
+            }
+        }
+        message
+    }
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for RpcCallParts
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            metadata: <RpcMetadata as RpcFromRequestParts<M, S>>::rpc_from_request_parts(
+                parts, state,
+            )
+            .await?,
+            deadline: <RpcDeadline as RpcFromRequestParts<M, S>>::rpc_from_request_parts(
+                parts, state,
+            )
+            .await?,
+            method: parts.extensions.get::<RpcMethodInfo>().cloned(),
+            extensions: parts.extensions.clone(),
+            headers: <RpcResponseHeaders as RpcFromRequestParts<M, S>>::rpc_from_request_parts(
+                parts, state,
+            )
+            .await?,
+            trailers: <RpcTrailers as RpcFromRequestParts<M, S>>::rpc_from_request_parts(
+                parts, state,
+            )
+            .await?,
+            compression: parts.extensions.get::<ResponseCompression>().cloned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        router::{RpcMethod, RpcRouter},
+        test_util::{
+            hello, message, proto_request, unary_info, HelloRequest, HelloResponse, SAY_HELLO,
+        },
+    };
+
+    /// What a `HelloWorldServiceHandlers` implementation would do.
+    async fn say_hello(request: RpcRequest<HelloRequest>) -> RpcResult<RpcResponse<HelloResponse>> {
+        let tenant = request
+            .metadata()
+            .get("x-tenant")
+            .unwrap_or_default()
+            .to_string();
+        let remaining = request.deadline().remaining().unwrap();
+        let path = request.method().unwrap().path.clone();
+        let mut response = RpcResponse::new(HelloResponse {
+            message: format!("Hello {} of {}!", request.into_inner().name, tenant),
+        });
+        response
+            .headers_mut()
+            .insert("x-called", HeaderValue::from_str(&path).unwrap());
+        let in_time = remaining > Duration::from_secs(4) && remaining <= Duration::from_secs(5);
+        response
+            .trailers_mut()
+            .insert("x-in-time", HeaderValue::from(in_time as u16));
+        Ok(response)
+    }
+
+    /// Mounts `say_hello` the way `register_all` mounts trait methods.
+    fn service_router() -> RpcRouter {
+        RpcRouter::new().rpc_method(RpcMethod::unary(
+            unary_info(SAY_HELLO),
+            |mut call: RpcCallParts, request: HelloRequest| async move {
+                let request = call.request(request);
+                say_hello(request)
+                    .await
+                    .map(|response| call.respond(response))
+            },
+        ))
+    }
+
+    #[tokio::test]
+    async fn carries_metadata_and_deadline_to_service_trait_methods() {
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("x-tenant", HeaderValue::from_static("acme"));
+        request
+            .headers_mut()
+            .insert("connect-timeout-ms", HeaderValue::from_static("5000"));
+
+        let response = crate::test_util::client(service_router())
+            .send(request)
+            .await;
+
+        assert_eq!(
+            message::<HelloResponse>(&response).message,
+            "Hello Ada of acme!"
+        );
+        assert_eq!(response.headers["x-called"], SAY_HELLO);
+        assert_eq!(response.headers["trailer-x-in-time"], "1");
+    }
+
+    #[test]
+    fn keeps_what_came_with_the_message_when_mapped() {
+        let mut request = RpcRequest::new(hello("Ada"));
+        assert!(request.method().is_none());
+        assert_eq!(request.deadline().remaining(), None);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        request.set_deadline(deadline);
+        request.extensions_mut().insert(7_u32);
+        let request = request.map(|message| message.name);
+
+        assert_eq!(request.get_ref(), "Ada");
+        assert_eq!(request.deadline(), RpcDeadline(Some(deadline)));
+        assert_eq!(request.extensions().get::<u32>(), Some(&7));
+    }
+}
@@ -1,6 +1,14 @@
+use std::{collections::BTreeMap, fmt, marker::PhantomData};
+
+use axum::{body::Bytes, http::HeaderMap};
 use prost::Message;
+use serde::{Serialize, Serializer};
 
-use crate::error::{RpcError, RpcIntoError};
+use crate::{
+    audit,
+    codec::{self, RpcJsonDecode, RpcJsonEncode},
+    error::{RpcError, RpcErrorCode, RpcIntoError},
+};
 
 pub type RpcResult<M> = Result<M, RpcError>;
 
@@ -9,6 +17,15 @@ where
     T: Message,
 {
     fn rpc_into_response(self) -> RpcResult<T>;
+
+    /// The response of a unary handler, which [`LazyResponse`] sends as it was encoded.
+    #[doc(hidden)]
+    fn rpc_into_payload(self) -> RpcResult<RpcPayload<T>>
+    where
+        Self: Sized,
+    {
+        self.rpc_into_response().map(RpcPayload::message)
+    }
 }
 
 impl<T> RpcIntoResponse<T> for T
@@ -29,3 +46,481 @@ where
         self.map_err(|e| e.rpc_into_error())
     }
 }
+
+/// A response message with the metadata to send along, what the methods of the
+/// `<Service>Handlers` traits generated with `service_traits` return, like tonic's `Response`.
+/// The headers are sent as [`RpcResponseHeaders`](crate::parts::RpcResponseHeaders), the trailers
+/// as [`RpcTrailers`](crate::parts::RpcTrailers). For server streams the message is the stream,
+/// and both are sent as it starts, after the handler returned it.
+#[derive(Clone, Debug, Default)]
+pub struct RpcResponse<M> {
+    message: M,
+    headers: HeaderMap,
+    trailers: HeaderMap,
+    no_compression: bool,
+}
+
+impl<M> RpcResponse<M> {
+    pub fn new(message: M) -> Self {
+        Self {
+            message,
+            headers: HeaderMap::new(),
+            trailers: HeaderMap::new(),
+            no_compression: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &M {
+        &self.message
+    }
+
+    pub fn get_mut(&mut self) -> &mut M {
+        &mut self.message
+    }
+
+    pub fn into_inner(self) -> M {
+        self.message
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn trailers(&self) -> &HeaderMap {
+        &self.trailers
+    }
+
+    pub fn trailers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.trailers
+    }
+
+    /// Sends the response uncompressed, whatever was negotiated and the route's
+    /// [`CompressionMode`](crate::router::CompressionMode), eg. for a message of an already
+    /// compressed blob. For streams, it applies to every message.
+    pub fn no_compression(mut self) -> Self {
+        self.no_compression = true;
+        self
+    }
+
+    /// The response with its message replaced by `f` of it.
+    pub fn map<N>(self, f: impl FnOnce(M) -> N) -> RpcResponse<N> {
+        RpcResponse {
+            message: f(self.message),
+            headers: self.headers,
+            trailers: self.trailers,
+            no_compression: self.no_compression,
+        }
+    }
+
+    /// The message, headers and trailers, and whether compression is off.
+    pub(crate) fn into_parts(self) -> (M, HeaderMap, HeaderMap, bool) {
+        (
+            self.message,
+            self.headers,
+            self.trailers,
+            self.no_compression,
+        )
+    }
+}
+
+impl<M> From<M> for RpcResponse<M> {
+    fn from(message: M) -> Self {
+        Self::new(message)
+    }
+}
+
+/// A unary response the handler encoded itself, eg. assembled from cached JSON fragments, sent
+/// as it is instead of being encoded from a message. It has an encoding per codec, and the one the
+/// client negotiated is sent; calls whose codec it lacks fail with `Internal`. Layers like gzip
+/// still apply, as they would to any response.
+///
+/// The bytes aren't checked: they must be a valid encoding of `M`, in the protobuf binary format
+/// or the protobuf JSON mapping, or clients fail to decode them. Response hooks don't see them.
+/// Where a message is needed after all, one encoding is decoded into `M`: as an item of a
+/// response stream, for the rare clients calling a unary RPC with a streaming content type, and
+/// for the audit log when there's no JSON encoding.
+///
+/// ```
+/// # use axum_connect::{pbjson_types::Struct, prelude::*};
+/// async fn catalog() -> LazyResponse<Struct> {
+///     LazyResponse::from_json(r#"{"items":[]}"#)
+/// }
+/// ```
+pub struct LazyResponse<M> {
+    json: Option<Bytes>,
+    proto: Option<Bytes>,
+    message: PhantomData<fn() -> M>,
+}
+
+impl<M> LazyResponse<M> {
+    /// With the JSON encoding of the message.
+    pub fn from_json(json: impl Into<Bytes>) -> Self {
+        Self {
+            json: Some(json.into()),
+            proto: None,
+            message: PhantomData,
+        }
+    }
+
+    /// With the binary protobuf encoding of the message.
+    pub fn from_proto(proto: impl Into<Bytes>) -> Self {
+        Self {
+            json: None,
+            proto: Some(proto.into()),
+            message: PhantomData,
+        }
+    }
+
+    pub fn json(mut self, json: impl Into<Bytes>) -> Self {
+        self.json = Some(json.into());
+        self
+    }
+
+    pub fn proto(mut self, proto: impl Into<Bytes>) -> Self {
+        self.proto = Some(proto.into());
+        self
+    }
+
+    /// The encoding for the negotiated codec.
+    fn encoded(self, binary: bool) -> RpcResult<Bytes> {
+        let (encoded, codec) = match binary {
+            true => (self.proto, "proto"),
+            false => (self.json, "json"),
+        };
+        encoded.ok_or_else(|| {
+            RpcError::new(
+                RpcErrorCode::Internal,
+                format!("The response has no {} encoding", codec),
+            )
+        })
+    }
+
+    /// Decodes the message, preferring the binary encoding. Bad bytes are the server's fault.
+    fn decode(&self) -> RpcResult<M>
+    where
+        M: Message + RpcJsonDecode + Default + 'static,
+    {
+        let decoded = match (&self.proto, &self.json) {
+            (Some(proto), _) => codec::decode_unary_response(proto, true),
+            (None, Some(json)) => codec::decode_unary_response(json, false),
+            (None, None) => unreachable!("a LazyResponse has at least one encoding"),
+        };
+        decoded.map_err(|error| {
+            RpcError::new(
+                RpcErrorCode::Internal,
+                format!("The response isn't a valid encoding: {}", error.message),
+            )
+        })
+    }
+}
+
+impl<M> fmt::Debug for LazyResponse<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyResponse")
+            .field("json_bytes", &self.json.as_ref().map(Bytes::len))
+            .field("proto_bytes", &self.proto.as_ref().map(Bytes::len))
+            .finish()
+    }
+}
+
+impl<M> RpcIntoResponse<M> for LazyResponse<M>
+where
+    M: Message + RpcJsonDecode + Default + 'static,
+{
+    fn rpc_into_response(self) -> RpcResult<M> {
+        self.decode()
+    }
+
+    fn rpc_into_payload(self) -> RpcResult<RpcPayload<M>> {
+        Ok(RpcPayload(Payload::Encoded {
+            response: self,
+            decode: LazyResponse::decode,
+        }))
+    }
+}
+
+impl<M, E> RpcIntoResponse<M> for Result<LazyResponse<M>, E>
+where
+    M: Message + RpcJsonDecode + Default + 'static,
+    E: RpcIntoError + Send + Sync + 'static,
+{
+    fn rpc_into_response(self) -> RpcResult<M> {
+        self.map_err(|e| e.rpc_into_error())
+            .and_then(RpcIntoResponse::rpc_into_response)
+    }
+
+    fn rpc_into_payload(self) -> RpcResult<RpcPayload<M>> {
+        self.map_err(|e| e.rpc_into_error())
+            .and_then(RpcIntoResponse::rpc_into_payload)
+    }
+}
+
+/// A message, or the encodings of one, see [`RpcIntoResponse::rpc_into_payload`].
+#[doc(hidden)]
+pub struct RpcPayload<M>(Payload<M>);
+
+enum Payload<M> {
+    Message(M),
+    Encoded {
+        response: LazyResponse<M>,
+        /// Captured where `M` is known to be decodable, so the handlers don't need the bounds.
+        decode: fn(&LazyResponse<M>) -> RpcResult<M>,
+    },
+}
+
+impl<M> RpcPayload<M> {
+    pub(crate) fn message(message: M) -> Self {
+        Self(Payload::Message(message))
+    }
+
+    /// Runs `f` on the message, unless it's already encoded.
+    pub(crate) fn map_message(self, f: impl FnOnce(M) -> M) -> Self {
+        match self.0 {
+            Payload::Message(message) => Self::message(f(message)),
+            encoded => Self(encoded),
+        }
+    }
+
+    pub(crate) fn into_message(self) -> RpcResult<M> {
+        match self.0 {
+            Payload::Message(message) => Ok(message),
+            Payload::Encoded { response, decode } => decode(&response),
+        }
+    }
+
+    /// The body of a successful unary response.
+    pub(crate) fn encode(
+        self,
+        binary: bool,
+        encode: impl FnOnce(M) -> RpcResult<Bytes>,
+    ) -> RpcResult<Bytes> {
+        match self.0 {
+            Payload::Message(message) => encode(message),
+            Payload::Encoded { response, .. } => response.encoded(binary),
+        }
+    }
+
+    /// The message in the JSON mapping, `null` if it has none, eg. without the `json` feature.
+    pub(crate) fn json_value(&self) -> serde_json::Value
+    where
+        M: RpcJsonEncode,
+    {
+        match &self.0 {
+            Payload::Message(message) => audit::to_value(message),
+            Payload::Encoded { response, decode } => match &response.json {
+                Some(json) => serde_json::from_slice(json).unwrap_or_default(),
+                None => decode(response)
+                    .map(|message| audit::to_value(&message))
+                    .unwrap_or_default(),
+            },
+        }
+    }
+}
+
+/// The final message of every streaming response.
+///
+/// It's always JSON (even for `application/connect+proto`), in an envelope with the end-of-stream
+/// flag (`0x02`) set. Both keys are omitted when empty, so a plain success is `{}`.
+///
+/// https://connectrpc.com/docs/protocol/#error-end-stream
+#[derive(Clone, Default, Serialize)]
+pub struct EndStreamResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+    /// Trailing metadata. Keys are case-insensitive and can repeat, repeated values are kept.
+    #[serde(
+        skip_serializing_if = "HeaderMap::is_empty",
+        serialize_with = "serialize_metadata"
+    )]
+    pub metadata: HeaderMap,
+}
+
+impl EndStreamResponse {
+    /// The envelope flag marking the end of the stream.
+    pub const FLAG: u8 = 0x02;
+
+    /// An error end-of-stream, the error's metadata becomes the trailing metadata.
+    pub fn error(error: RpcError) -> Self {
+        Self {
+            metadata: error.metadata().clone(),
+            error: Some(error),
+        }
+    }
+
+    /// Encodes the response as an enveloped frame, ready to be sent.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut result = vec![Self::FLAG, 0, 0, 0, 0];
+        serde_json::to_writer(&mut result, self).unwrap();
+
+        let size = ((result.len() - 5) as u32).to_be_bytes();
+        result[1..5].copy_from_slice(&size);
+        result
+    }
+}
+
+/// Metadata is a JSON object of (lowercase) key to an array of values.
+fn serialize_metadata<S: Serializer>(
+    metadata: &HeaderMap,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let grouped = metadata
+        .keys()
+        .map(|key| {
+            let values = metadata
+                .get_all(key)
+                .iter()
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                .collect::<Vec<_>>();
+            (key.as_str(), values)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    grouped.serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        error::RpcErrorDetail,
+        router::RpcRouter,
+        test_util::{
+            client, hello, message, proto_request, unary, HelloRequest, HelloResponse, SAY_HELLO,
+        },
+    };
+
+    /// `payload` in an end-of-stream envelope.
+    fn end_stream(payload: &str) -> Vec<u8> {
+        let mut frame = vec![EndStreamResponse::FLAG];
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload.as_bytes());
+        frame
+    }
+
+    #[test]
+    fn encodes_a_plain_success_as_an_empty_object() {
+        assert_eq!(
+            EndStreamResponse::default().encode(),
+            b"\x02\x00\x00\x00\x02{}"
+        );
+    }
+
+    #[test]
+    fn encodes_a_success_with_metadata() {
+        let mut metadata = HeaderMap::new();
+        metadata.append("x-cursor", HeaderValue::from_static("42"));
+        metadata.append("x-served-by", HeaderValue::from_static("a"));
+        metadata.append("x-served-by", HeaderValue::from_static("b"));
+        let end = EndStreamResponse {
+            error: None,
+            metadata,
+        };
+
+        assert_eq!(
+            end.encode(),
+            end_stream(r#"{"metadata":{"x-cursor":["42"],"x-served-by":["a","b"]}}"#)
+        );
+    }
+
+    #[test]
+    fn encodes_an_error_without_metadata() {
+        let mut error = RpcError::new(RpcErrorCode::ResourceExhausted, "Slow down".to_string());
+        error
+            .details
+            .push(RpcErrorDetail::new("hello.HelloRequest", &hello("Ada")));
+
+        assert_eq!(
+            EndStreamResponse::error(error).encode(),
+            end_stream(
+                r#"{"error":{"code":"resource_exhausted","message":"Slow down","details":[{"type":"hello.HelloRequest","value":"CgNBZGE"}]}}"#
+            )
+        );
+    }
+
+    #[test]
+    fn moves_the_error_metadata_to_the_trailers() {
+        let mut metadata = HeaderMap::new();
+        metadata.insert("retry-after", HeaderValue::from_static("1"));
+        let error = RpcError::new(RpcErrorCode::Unavailable, String::new()).with_metadata(metadata);
+
+        assert_eq!(
+            EndStreamResponse::error(error).encode(),
+            end_stream(r#"{"error":{"code":"unavailable"},"metadata":{"retry-after":["1"]}}"#)
+        );
+    }
+
+    fn greeting(message: &str) -> HelloResponse {
+        HelloResponse {
+            message: message.to_string(),
+        }
+    }
+
+    async fn lazy(_: HelloRequest) -> LazyResponse<HelloResponse> {
+        LazyResponse::from_json(r#"{"message":"From JSON"}"#)
+            .proto(greeting("From proto").encode_to_vec())
+    }
+
+    async fn json_only(_: HelloRequest) -> LazyResponse<HelloResponse> {
+        LazyResponse::from_json(r#"{"message":"From JSON"}"#)
+    }
+
+    #[tokio::test]
+    async fn sends_the_encoding_of_the_negotiated_codec() {
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, lazy)));
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(message::<HelloResponse>(&response), greeting("From proto"));
+
+        #[cfg(feature = "json")]
+        {
+            let response = client
+                .post(SAY_HELLO, "application/json", br#"{"name":"Ada"}"#.to_vec())
+                .await;
+            assert_eq!(response.body, r#"{"message":"From JSON"}"#.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn fails_calls_whose_codec_it_lacks() {
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, json_only)));
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::Internal,
+                "The response has no proto encoding".to_string()
+            ))
+        );
+    }
+
+    #[cfg(feature = "tower-http")]
+    #[tokio::test]
+    async fn is_gzipped_like_other_responses() {
+        use axum::http::header;
+
+        async fn large(_: HelloRequest) -> LazyResponse<HelloResponse> {
+            LazyResponse::from_proto(greeting(&"Ada".repeat(1000)).encode_to_vec())
+        }
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, large))
+            .layer(crate::layers::recommended());
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+
+        let response = client(router).send(request).await;
+
+        assert!(response.status.is_success(), "{:?}", response.error());
+        assert_eq!(response.headers[header::CONTENT_ENCODING], "gzip");
+        assert!(response.body.len() < 3000);
+    }
+}
@@ -0,0 +1,288 @@
+//! Runtime support for REST aliases generated from `google.api.http` annotations.
+//!
+//! A REST request is rewritten into an equivalent Connect unary JSON request (path variables,
+//! query parameters and the body are folded into a single JSON message) and then handed to the
+//! normal unary handler. The response is the plain JSON response message.
+
+use std::collections::HashMap;
+
+use axum::{
+    body::Body,
+    extract::{FromRequestParts, Path, Query},
+    http::{header, HeaderValue, Method, Request},
+    response::Response,
+};
+use serde_json::{Map, Value};
+
+use crate::{
+    config::RpcConfig,
+    error::{RpcError, RpcErrorCode},
+    handler::codec::{preallocates, read_body, ResponseEncoder},
+};
+
+/// How a single `google.api.http` binding maps onto the request message. Generated by
+/// `axum-connect-build`.
+pub struct RestBinding {
+    /// (axum capture name, request field path) pairs.
+    pub path_fields: &'static [(&'static str, &'static str)],
+    /// `None` if there's no body, `Some("*")` for the whole message or `Some("field.path")`.
+    pub body: Option<&'static str>,
+    /// Request fields that must be sent as a JSON bool rather than a string.
+    pub bool_fields: &'static [&'static str],
+    /// Request fields that are repeated.
+    pub repeated_fields: &'static [&'static str],
+}
+
+/// Rewrites a REST request into a Connect unary JSON request, per `binding`. Errors are returned
+/// as ready-to-send Connect error responses. Bodies over the `max_request_bytes` of the route's
+/// [`RpcConfig`] fail with `ResourceExhausted`, like those of RPCs.
+#[allow(clippy::result_large_err)]
+pub async fn rest_into_connect_request(
+    req: Request<Body>,
+    binding: &RestBinding,
+) -> Result<Request<Body>, Response> {
+    let (mut parts, body) = req.into_parts();
+    let error = |message: String| {
+        let error = RpcError::new(RpcErrorCode::InvalidArgument, message);
+        ResponseEncoder::error(error, false, false).encode_response()
+    };
+
+    let mut message = Map::new();
+
+    if let Some(body_field) = binding.body {
+        let config = RpcConfig::from_parts(&parts);
+        let bytes = read_body(body, None, config.max_request_bytes, preallocates(&parts))
+            .await
+            .map_err(|error| ResponseEncoder::error(error, false, false).encode_response())?;
+
+        let value = if bytes.is_empty() {
+            Value::Object(Map::new())
+        } else {
+            serde_json::from_slice(&bytes)
+                .map_err(|e| error(format!("Failed to decode JSON body. {}", e)))?
+        };
+
+        if body_field == "*" {
+            match value {
+                Value::Object(map) => message = map,
+                _ => return Err(error("Request body must be a JSON object".to_string())),
+            }
+        } else {
+            set_field(&mut message, body_field, value);
+        }
+    }
+
+    if !binding.path_fields.is_empty() {
+        let Path(captures) = Path::<HashMap<String, String>>::from_request_parts(&mut parts, &())
+            .await
+            .map_err(|e| error(e.to_string()))?;
+
+        for (capture, field) in binding.path_fields {
+            if let Some(value) = captures.get(*capture) {
+                set_field(&mut message, field, scalar(binding, field, value));
+            }
+        }
+    }
+
+    // When the body is the whole message, there's nothing left for query parameters to bind to.
+    if binding.body != Some("*") {
+        let Query(query) = Query::<Vec<(String, String)>>::from_request_parts(&mut parts, &())
+            .await
+            .map_err(|e| error(e.to_string()))?;
+
+        let mut grouped = HashMap::<String, Vec<String>>::new();
+        for (key, value) in query {
+            grouped.entry(key).or_default().push(value);
+        }
+
+        for (field, values) in grouped {
+            if binding.path_fields.iter().any(|(_, f)| *f == field) {
+                continue;
+            }
+
+            let value = if binding.repeated_fields.contains(&field.as_str()) {
+                Value::Array(values.iter().map(|v| scalar(binding, &field, v)).collect())
+            } else {
+                scalar(binding, &field, values.last().unwrap())
+            };
+
+            set_field(&mut message, &field, value);
+        }
+    }
+
+    let body = serde_json::to_vec(&message).map_err(|e| error(e.to_string()))?;
+
+    parts.method = Method::POST;
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+/// Path and query values are all strings, which the protobuf JSON mapping accepts for every
+/// scalar type except bools.
+fn scalar(binding: &RestBinding, field: &str, value: &str) -> Value {
+    match value {
+        "true" | "false" if binding.bool_fields.contains(&field) => Value::Bool(value == "true"),
+        _ => Value::String(value.to_string()),
+    }
+}
+
+/// Sets a (possibly nested, dot separated) field in a JSON object, creating objects as needed.
+fn set_field(message: &mut Map<String, Value>, path: &str, value: Value) {
+    let (head, rest) = match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    };
+
+    match rest {
+        None => {
+            message.insert(head.to_string(), value);
+        }
+        Some(rest) => {
+            let entry = message
+                .entry(head.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+
+            if !entry.is_object() {
+                *entry = Value::Object(Map::new());
+            }
+
+            if let Value::Object(nested) = entry {
+                set_field(nested, rest, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::to_bytes, http::StatusCode, response::IntoResponse, routing::any, Extension, Router,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::{test_util::client_of, testing::TestResponse};
+
+    const CREATE_USER: RestBinding = RestBinding {
+        path_fields: &[("org", "org")],
+        body: Some("user"),
+        bool_fields: &["notify"],
+        repeated_fields: &["tags"],
+    };
+
+    const GET_USER: RestBinding = RestBinding {
+        path_fields: &[("user_id", "user.id")],
+        body: None,
+        bool_fields: &[],
+        repeated_fields: &[],
+    };
+
+    const UPDATE_USER: RestBinding = RestBinding {
+        path_fields: &[],
+        body: Some("*"),
+        bool_fields: &[],
+        repeated_fields: &[],
+    };
+
+    /// Answers with the JSON message of the rewritten request.
+    fn router(path: &str, binding: &'static RestBinding) -> Router {
+        Router::new().route(
+            path,
+            any(move |request: Request<Body>| async move {
+                match rest_into_connect_request(request, binding).await {
+                    Ok(request) => {
+                        assert_eq!(request.method(), Method::POST);
+                        assert_eq!(request.headers()[header::CONTENT_TYPE], "application/json");
+                        let body = to_bytes(request.into_body(), usize::MAX).await.unwrap();
+                        body.into_response()
+                    }
+                    Err(response) => response,
+                }
+            }),
+        )
+    }
+
+    async fn send(router: Router, request: Request<Body>) -> TestResponse {
+        client_of(router).send(request).await
+    }
+
+    fn rewritten(response: &TestResponse) -> Value {
+        assert_eq!(response.status, StatusCode::OK, "{:?}", response.error());
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn folds_path_query_and_body() {
+        let request = Request::post("/v1/orgs/acme/users?notify=true&tags=a&tags=b&name=ignored")
+            .body(Body::from(r#"{"name":"Ada"}"#))
+            .unwrap();
+        let response = send(router("/v1/orgs/{org}/users", &CREATE_USER), request).await;
+        assert_eq!(
+            rewritten(&response),
+            json!({
+                "org": "acme",
+                "user": {"name": "Ada"},
+                "notify": true,
+                "tags": ["a", "b"],
+                "name": "ignored",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn nests_path_fields() {
+        let request = Request::get("/v1/users/42?view=full")
+            .body(Body::empty())
+            .unwrap();
+        let response = send(router("/v1/users/{user_id}", &GET_USER), request).await;
+        assert_eq!(
+            rewritten(&response),
+            json!({"user": {"id": "42"}, "view": "full"})
+        );
+    }
+
+    #[tokio::test]
+    async fn whole_body_ignores_the_query() {
+        let request = Request::patch("/v1/users?name=query")
+            .body(Body::from(r#"{"name":"body"}"#))
+            .unwrap();
+        let response = send(router("/v1/users", &UPDATE_USER), request).await;
+        assert_eq!(rewritten(&response), json!({"name": "body"}));
+
+        let request = Request::patch("/v1/users")
+            .body(Body::from("[1, 2]"))
+            .unwrap();
+        let response = send(router("/v1/users", &UPDATE_USER), request).await;
+        assert_eq!(
+            response.error().unwrap().code,
+            RpcErrorCode::InvalidArgument
+        );
+    }
+
+    #[tokio::test]
+    async fn limits_the_body() {
+        let config = Arc::new(RpcConfig::new().max_request_bytes(16));
+        let router = router("/v1/orgs/{org}/users", &CREATE_USER).layer(Extension(config));
+
+        let request = Request::post("/v1/orgs/acme/users")
+            .body(Body::from(r#"{"name":"Ada"}"#))
+            .unwrap();
+        rewritten(&send(router.clone(), request).await);
+
+        let request = Request::post("/v1/orgs/acme/users")
+            .body(Body::from(r#"{"name":"Ada Lovelace"}"#))
+            .unwrap();
+        let response = send(router, request).await;
+        assert_eq!(
+            response.error().unwrap().code,
+            RpcErrorCode::ResourceExhausted
+        );
+    }
+}
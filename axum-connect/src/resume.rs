@@ -0,0 +1,405 @@
+//! Streams clients can pick up where they left off after a disconnect, see [`ResumableStream`].
+
+use std::{
+    fmt,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use axum::http::{self, HeaderValue};
+use futures::{Stream, StreamExt};
+use prost::Message;
+
+use crate::{
+    error::{RpcError, RpcErrorCode, RpcIntoError},
+    parts::{RpcFromRequestParts, RpcTrailers},
+    response::RpcResult,
+};
+
+/// The request header with the offset to resume from, the number of bytes already received.
+pub const RESUME_FROM: &str = "x-resume-from";
+/// The request header with the [`checksum`] of the bytes already received, 8 hex digits.
+pub const RESUME_CHECKSUM: &str = "x-resume-checksum";
+/// The end-of-stream metadata with the offset the stream ended at.
+pub const FINAL_OFFSET: &str = "x-final-offset";
+/// The end-of-stream metadata with the [`checksum`] of every byte up to the final offset,
+/// including those sent before the resume.
+pub const CHECKSUM: &str = "x-checksum";
+
+/// Where a resumed call starts, from the `x-resume-from` and `x-resume-checksum` headers. Both
+/// are 0 for calls starting from the beginning. Services taking the offset in a field of the
+/// request message instead build it with [`ResumeFrom::new`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ResumeFrom {
+    pub offset: u64,
+    /// The [`checksum`] of the bytes before `offset`.
+    pub checksum: u32,
+}
+
+impl ResumeFrom {
+    pub fn new(offset: u64, checksum: u32) -> Self {
+        Self { offset, checksum }
+    }
+}
+
+impl<M, S> RpcFromRequestParts<M, S> for ResumeFrom
+where
+    M: Message,
+    S: Send + Sync,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let header = |name: &str, radix: u32| {
+            let Some(value) = parts.headers.get(name) else {
+                return Ok(0);
+            };
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| u64::from_str_radix(value, radix).ok())
+                .ok_or_else(|| {
+                    RpcError::new(
+                        RpcErrorCode::InvalidArgument,
+                        format!(
+                            "Invalid {} header `{}`",
+                            name,
+                            String::from_utf8_lossy(value.as_bytes())
+                        ),
+                    )
+                })
+        };
+
+        let offset = header(RESUME_FROM, 10)?;
+        let checksum = u32::try_from(header(RESUME_CHECKSUM, 16)?).map_err(|_| {
+            RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                format!("Invalid {} header, it's 8 hex digits", RESUME_CHECKSUM),
+            )
+        })?;
+        Ok(Self { offset, checksum })
+    }
+}
+
+/// A stream of chunks of bytes, eg. of a file download, tracking the offset each chunk starts at
+/// and a checksum of what was sent so far.
+///
+/// The handler starts its chunks at the [`ResumeFrom`] offset, the stream counts from there. The
+/// offset and checksum so far are kept in the trailers, so the end-of-stream metadata has
+/// `x-final-offset` and `x-checksum`, even when the stream fails. A client that was disconnected
+/// never gets those, so [`set_offset`](ResumableStream::set_offset) also writes the offset into
+/// each chunk, and the client resumes from the end of the last chunk it got:
+///
+/// ```
+/// # use axum_connect::{error::RpcError, futures::{stream, Stream}, parts::RpcTrailers, response::RpcResult};
+/// use axum_connect::resume::{ResumableStream, ResumeFrom};
+///
+/// struct Chunk {
+///     offset: u64,
+///     data: Vec<u8>,
+/// }
+///
+/// async fn download(
+///     resume: ResumeFrom,
+///     trailers: RpcTrailers,
+///     request: DownloadRequest,
+/// ) -> impl Stream<Item = RpcResult<Chunk>> {
+///     let file = std::fs::read(&request.path).unwrap_or_default();
+///     let rest = file.get(resume.offset as usize..).unwrap_or_default().to_vec();
+///     let chunks = rest
+///         .chunks(1 << 16)
+///         .map(|data| Ok::<_, RpcError>(Chunk { offset: 0, data: data.to_vec() }))
+///         .collect::<Vec<_>>();
+///
+///     ResumableStream::new(stream::iter(chunks), resume, trailers, |chunk| chunk.data.as_slice())
+///         .set_offset(|chunk, offset| chunk.offset = offset)
+/// }
+/// # struct DownloadRequest { path: String }
+/// ```
+///
+/// The client checks the final `x-checksum` against the [`checksum`] of everything it received.
+pub struct ResumableStream<M> {
+    inner: Pin<Box<dyn Stream<Item = RpcResult<M>> + Send>>,
+    data: ChunkData<M>,
+    set_offset: Option<SetOffset<M>>,
+    offset: u64,
+    checksum: u32,
+    trailers: RpcTrailers,
+}
+
+type ChunkData<M> = Box<dyn Fn(&M) -> &[u8] + Send + Sync>;
+type SetOffset<M> = Box<dyn Fn(&mut M, u64) + Send + Sync>;
+
+impl<M: 'static> ResumableStream<M> {
+    /// `data` is the bytes of a chunk. The errors of `chunks` are converted with
+    /// [`RpcIntoError`], the first one ends the response.
+    pub fn new<S, E>(
+        chunks: S,
+        resume: ResumeFrom,
+        trailers: RpcTrailers,
+        data: impl Fn(&M) -> &[u8] + Send + Sync + 'static,
+    ) -> Self
+    where
+        S: Stream<Item = Result<M, E>> + Send + 'static,
+        E: RpcIntoError,
+    {
+        let stream = Self {
+            inner: Box::pin(chunks.map(|item| item.map_err(RpcIntoError::rpc_into_error))),
+            data: Box::new(data),
+            set_offset: None,
+            offset: resume.offset,
+            checksum: resume.checksum,
+            trailers,
+        };
+        stream.set_trailers();
+        stream
+    }
+
+    /// Sets each chunk's offset, where its bytes start, before it's sent.
+    pub fn set_offset(mut self, set_offset: impl Fn(&mut M, u64) + Send + Sync + 'static) -> Self {
+        self.set_offset = Some(Box::new(set_offset));
+        self
+    }
+}
+
+impl<M> ResumableStream<M> {
+    /// The offset the next chunk starts at.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// The [`checksum`] of the bytes before [`offset`](ResumableStream::offset).
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    fn set_trailers(&self) {
+        self.trailers
+            .insert(FINAL_OFFSET, HeaderValue::from(self.offset));
+        if let Ok(checksum) = HeaderValue::try_from(format!("{:08x}", self.checksum)) {
+            self.trailers.insert(CHECKSUM, checksum);
+        }
+    }
+}
+
+impl<M> Stream for ResumableStream<M> {
+    type Item = RpcResult<M>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let mut chunk = match ready!(this.inner.as_mut().poll_next(cx)) {
+            Some(Ok(chunk)) => chunk,
+            end => return Poll::Ready(end),
+        };
+
+        if let Some(set_offset) = &this.set_offset {
+            set_offset(&mut chunk, this.offset);
+        }
+        let data = (this.data)(&chunk);
+        this.checksum = checksum(this.checksum, data);
+        this.offset += data.len() as u64;
+        this.set_trailers();
+        Poll::Ready(Some(Ok(chunk)))
+    }
+}
+
+impl<M> fmt::Debug for ResumableStream<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResumableStream")
+            .field("offset", &self.offset)
+            .field("checksum", &self.checksum)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The CRC-32 (IEEE, as in gzip) of `previous`'s bytes followed by `bytes`. Start from 0.
+///
+/// ```
+/// # use axum_connect::resume::checksum;
+/// assert_eq!(checksum(0, b"123456789"), 0xcbf43926);
+/// assert_eq!(checksum(checksum(0, b"1234"), b"56789"), 0xcbf43926);
+/// ```
+pub fn checksum(previous: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !previous;
+    for byte in bytes {
+        crc = CRC_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                1 => 0xedb8_8320 ^ (crc >> 1),
+                _ => crc >> 1,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::HeaderMap};
+    use futures::stream;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::{
+        codec::{parse_end_stream, FrameDecoder},
+        prelude::*,
+        router::RpcMethod,
+        test_util::{client, hello, stream_info, stream_request, HelloRequest, SAY_HELLO_STREAM},
+    };
+
+    #[derive(Clone, PartialEq, Message, serde::Serialize, serde::Deserialize)]
+    #[serde(default)]
+    struct Chunk {
+        #[prost(uint64, tag = "1")]
+        offset: u64,
+        #[prost(bytes = "vec", tag = "2")]
+        data: Vec<u8>,
+    }
+
+    /// The file [`download`] serves, in chunks of 10 bytes.
+    fn file() -> Vec<u8> {
+        (0..60).map(|byte| byte * 3).collect()
+    }
+
+    async fn download(
+        resume: ResumeFrom,
+        trailers: RpcTrailers,
+        _: HelloRequest,
+    ) -> ResumableStream<Chunk> {
+        let chunks = file()[resume.offset as usize..]
+            .chunks(10)
+            .map(|data| {
+                Ok::<_, RpcError>(Chunk {
+                    offset: 0,
+                    data: data.to_vec(),
+                })
+            })
+            .collect::<Vec<_>>();
+        ResumableStream::new(stream::iter(chunks), resume, trailers, |chunk: &Chunk| {
+            chunk.data.as_slice()
+        })
+        .set_offset(|chunk, offset| chunk.offset = offset)
+    }
+
+    fn router() -> RpcRouter {
+        RpcRouter::new().rpc_method(RpcMethod::server_streaming(
+            stream_info(SAY_HELLO_STREAM),
+            download,
+        ))
+    }
+
+    fn request(resume: Option<ResumeFrom>) -> http::Request<Body> {
+        let mut request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+        if let Some(resume) = resume {
+            let headers = request.headers_mut();
+            headers.insert(RESUME_FROM, HeaderValue::from(resume.offset));
+            let checksum = format!("{:08x}", resume.checksum);
+            headers.insert(RESUME_CHECKSUM, HeaderValue::try_from(checksum).unwrap());
+        }
+        request
+    }
+
+    /// Reads the chunks of a call, and its end-of-stream metadata if it gets there. Disconnects
+    /// after `max` chunks.
+    async fn read(request: http::Request<Body>, max: usize) -> (Vec<Chunk>, Option<HeaderMap>) {
+        let response = router().into_router().oneshot(request).await.unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let mut decoder = FrameDecoder::new();
+        let mut chunks = vec![];
+        while chunks.len() < max {
+            while let Some(frame) = decoder.next_frame().unwrap() {
+                if frame.is_end_stream() {
+                    let end = parse_end_stream(&frame.payload).unwrap();
+                    assert_eq!(end.error, None);
+                    return (chunks, Some(end.metadata));
+                }
+                chunks.push(Chunk::decode(frame.payload.as_slice()).unwrap());
+                if chunks.len() == max {
+                    break;
+                }
+            }
+            if chunks.len() < max {
+                decoder.push(&body.next().await.unwrap().unwrap());
+            }
+        }
+        (chunks, None)
+    }
+
+    #[tokio::test]
+    async fn resumes_after_a_disconnect_with_consistent_offsets() {
+        let (first, end) = read(request(None), 3).await;
+        assert_eq!(end, None);
+        assert_eq!(
+            first.iter().map(|chunk| chunk.offset).collect::<Vec<_>>(),
+            [0, 10, 20]
+        );
+        let received = first
+            .iter()
+            .flat_map(|chunk| chunk.data.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(received, file()[..30]);
+
+        let last = first.last().unwrap();
+        let resume = ResumeFrom::new(last.offset + last.data.len() as u64, checksum(0, &received));
+        let (rest, end) = read(request(Some(resume)), usize::MAX).await;
+        assert_eq!(
+            rest.iter().map(|chunk| chunk.offset).collect::<Vec<_>>(),
+            [30, 40, 50]
+        );
+        let received = received
+            .into_iter()
+            .chain(rest.iter().flat_map(|chunk| chunk.data.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(received, file());
+
+        let end = end.unwrap();
+        assert_eq!(end[FINAL_OFFSET], "60");
+        assert_eq!(end[CHECKSUM], format!("{:08x}", checksum(0, &file())));
+    }
+
+    #[tokio::test]
+    async fn sends_the_same_end_as_a_call_that_was_never_interrupted() {
+        let (chunks, end) = read(request(None), usize::MAX).await;
+        assert_eq!(chunks.len(), 6);
+        let end = end.unwrap();
+        assert_eq!(end[FINAL_OFFSET], "60");
+        assert_eq!(end[CHECKSUM], format!("{:08x}", checksum(0, &file())));
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_resume_headers() {
+        for (name, value) in [
+            (RESUME_FROM, "-1"),
+            (RESUME_FROM, "ten"),
+            (RESUME_CHECKSUM, "xyz"),
+            (RESUME_CHECKSUM, "123456789"),
+        ] {
+            let mut request = request(None);
+            request
+                .headers_mut()
+                .insert(name, HeaderValue::from_static(value));
+            let error = client(router()).send(request).await.error();
+            assert_eq!(
+                error.map(|error| error.code),
+                Some(RpcErrorCode::InvalidArgument),
+                "{name}: {value}"
+            );
+        }
+    }
+}
@@ -0,0 +1,732 @@
+//! Retrying calls to other Connect services on transient errors, see [`retry`], and hedging them
+//! against tail latency, see [`hedge`].
+
+use std::{
+    fmt,
+    future::Future,
+    hash::{BuildHasher, RandomState},
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue};
+use futures::{
+    future::{select, Either},
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use tokio::time::Instant;
+
+use crate::{
+    error::{RpcError, RpcErrorCode},
+    ratelimit::RetryInfo,
+    router::{RpcIdempotencyLevel, RpcMethodInfo},
+    well_known::DurationExt,
+};
+
+/// A failed attempt that's about to be retried, passed to [`RetryPolicy::on_retry`].
+#[derive(Clone, Copy)]
+pub struct RetryAttempt<'a> {
+    /// The attempt that failed, starting at 1.
+    pub attempt: u32,
+    pub error: &'a RpcError,
+    /// How long until the next attempt.
+    pub delay: Duration,
+}
+
+type OnRetry = Arc<dyn Fn(&RetryAttempt<'_>) + Send + Sync>;
+
+/// When and how often [`retry`] tries again.
+///
+/// By default a call is tried up to 3 times, and only retried on `Unavailable` and
+/// `DeadlineExceeded`. Backoff starts at 100ms and doubles up to 5s, minus up to 20% of jitter.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    retryable: Vec<RpcErrorCode>,
+    on_retry: Option<OnRetry>,
+    budget: Option<RetryBudget>,
+}
+
+impl RetryPolicy {
+    pub fn new() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            jitter: 0.2,
+            retryable: vec![RpcErrorCode::Unavailable, RpcErrorCode::DeadlineExceeded],
+            on_retry: None,
+            budget: None,
+        }
+    }
+
+    /// Attempts in total, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The delay before the first retry, doubled for every one after it up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// The fraction (`0.0` to `1.0`) of each backoff delay that's randomly taken off, so clients
+    /// failing together don't all retry together.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The codes worth retrying, replacing the default ones.
+    pub fn retryable(mut self, codes: impl IntoIterator<Item = RpcErrorCode>) -> Self {
+        self.retryable = codes.into_iter().collect();
+        self
+    }
+
+    /// Calls `f` before every retry, eg. to count them.
+    pub fn on_retry<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&RetryAttempt<'_>) + Send + Sync + 'static,
+    {
+        self.on_retry = Some(Arc::new(f));
+        self
+    }
+
+    /// Spends `budget` on every retry, and stops retrying once it's used up. The calls of every
+    /// policy with (a clone of) the same budget share it.
+    pub fn budget(mut self, budget: RetryBudget) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// The backoff after `attempt` failed, jitter included.
+    fn backoff_after(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        backoff.mul_f64(1.0 - self.jitter * random_fraction())
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("retryable", &self.retryable)
+            .field("budget", &self.budget)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Retries shared by the calls to an upstream, so when it's down, callers don't multiply its load
+/// with their retries.
+///
+/// Every retry spends one, and every call that succeeds puts back a fraction of one (a tenth by
+/// default), up to the maximum the budget starts with. Errors that aren't retried don't spend
+/// any. Once it's spent, calls fail with their first error until enough of them succeed again.
+///
+/// ```
+/// # use axum_connect::retry::{RetryBudget, RetryPolicy};
+/// // At most 20 retries in a row, then one for every 10 calls that succeed.
+/// let policy = RetryPolicy::new().budget(RetryBudget::new(20));
+/// ```
+#[derive(Clone)]
+pub struct RetryBudget {
+    max: f64,
+    deposit: f64,
+    balance: Arc<Mutex<f64>>,
+}
+
+impl RetryBudget {
+    /// A budget of `max_retries`, full to begin with.
+    pub fn new(max_retries: u32) -> Self {
+        Self {
+            max: max_retries as f64,
+            deposit: 0.1,
+            balance: Arc::new(Mutex::new(max_retries as f64)),
+        }
+    }
+
+    /// The fraction of a retry each successful call puts back, `0.1` by default.
+    pub fn deposit(mut self, deposit: f64) -> Self {
+        self.deposit = deposit.max(0.0);
+        self
+    }
+
+    /// The retries left, a fraction while they're being put back.
+    pub fn remaining(&self) -> f64 {
+        *self.balance.lock().unwrap()
+    }
+
+    /// Takes a retry out of the budget, `false` if there's none left.
+    fn withdraw(&self) -> bool {
+        let mut balance = self.balance.lock().unwrap();
+        if *balance < 1.0 {
+            return false;
+        }
+        *balance -= 1.0;
+        true
+    }
+
+    fn succeeded(&self) {
+        let mut balance = self.balance.lock().unwrap();
+        *balance = (*balance + self.deposit).min(self.max);
+    }
+}
+
+impl fmt::Debug for RetryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryBudget")
+            .field("max", &self.max)
+            .field("deposit", &self.deposit)
+            .field("remaining", &self.remaining())
+            .finish()
+    }
+}
+
+/// Calls `call` until it succeeds, fails with a code `policy` doesn't retry, or runs out of
+/// attempts or [budget](RetryPolicy::budget), and returns the last result.
+///
+/// When the error says how long to wait, through a `google.rpc.RetryInfo` detail or a
+/// `Retry-After` (in seconds) metadata entry, that's waited instead of the backoff.
+///
+/// ```
+/// # use axum_connect::{prelude::*, retry::{retry, RetryPolicy}};
+/// # async fn get_quote(symbol: &str) -> Result<f64, RpcError> { Ok(1.0) }
+/// async fn quote() -> RpcResult<f64> {
+///     let policy = RetryPolicy::new().max_attempts(5);
+///     retry(&policy, || get_quote("ACME")).await
+/// }
+/// ```
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, mut call: F) -> Result<T, RpcError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, RpcError>>,
+{
+    let mut attempt = 1;
+    loop {
+        let error = match call().await {
+            Ok(value) => {
+                if let Some(budget) = &policy.budget {
+                    budget.succeeded();
+                }
+                return Ok(value);
+            }
+            Err(error) => error,
+        };
+
+        if attempt >= policy.max_attempts || !policy.retryable.contains(&error.code) {
+            return Err(error);
+        }
+        if policy
+            .budget
+            .as_ref()
+            .is_some_and(|budget| !budget.withdraw())
+        {
+            return Err(error);
+        }
+
+        let delay = requested_delay(&error).unwrap_or_else(|| policy.backoff_after(attempt));
+        if let Some(on_retry) = &policy.on_retry {
+            on_retry(&RetryAttempt {
+                attempt,
+                error: &error,
+                delay,
+            });
+        }
+
+        tokio::time::sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+/// The header [`hedge`] tags each attempt with, its number starting at 1, so servers can tell
+/// hedged calls apart.
+pub const HEDGE_ATTEMPT_HEADER: HeaderName = HeaderName::from_static("x-hedge-attempt");
+
+/// When [`hedge`] sends another attempt, like gRPC's hedging policy.
+///
+/// By default there's one hedge, and only `Unavailable` sends the next attempt right away, any
+/// other error ends the call.
+#[derive(Clone, Debug)]
+pub struct HedgingPolicy {
+    delay: Duration,
+    max_attempts: u32,
+    retryable: Vec<RpcErrorCode>,
+}
+
+impl HedgingPolicy {
+    /// Sends another attempt each time `delay` passes without a response, eg. the p95 latency of
+    /// the RPC.
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            max_attempts: 2,
+            retryable: vec![RpcErrorCode::Unavailable],
+        }
+    }
+
+    /// Attempts in total, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// The codes that send the next attempt without waiting out the delay, replacing the default
+    /// ones. Other errors are the call's result.
+    pub fn retryable(mut self, codes: impl IntoIterator<Item = RpcErrorCode>) -> Self {
+        self.retryable = codes.into_iter().collect();
+        self
+    }
+}
+
+/// An attempt of a [`hedge`]d call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HedgeAttempt {
+    /// Starting at 1.
+    pub attempt: u32,
+}
+
+impl HedgeAttempt {
+    /// The [`HEDGE_ATTEMPT_HEADER`] to send with the attempt.
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEDGE_ATTEMPT_HEADER, HeaderValue::from(self.attempt));
+        headers
+    }
+}
+
+/// Calls `call`, and calls it again each time the policy's delay passes without a response, up to
+/// its `max_attempts` in flight, returning the first success. The attempts still in flight are
+/// dropped then, which cancels them for clients that cancel calls on drop. A retryable error sends
+/// the next attempt right away, any other is returned as it comes; if all attempts fail, the last
+/// error is returned.
+///
+/// Hedged attempts run at the same time, so only `NO_SIDE_EFFECTS` RPCs can be hedged: `method`
+/// is the RPC's info, eg. from its generated `<method>_info()`, and any other idempotency level
+/// fails with `Internal` before `call` is called. Send each attempt's
+/// [`headers`](HedgeAttempt::headers) with it.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use axum::http::HeaderMap;
+/// # use axum_connect::{prelude::*, retry::{hedge, HedgingPolicy}, router::*};
+/// # async fn get_quote(symbol: &str, headers: HeaderMap) -> RpcResult<f64> { Ok(1.0) }
+/// # let get_quote_info = RpcMethodInfo::from_rpc_path(
+/// #     "/pricing.Pricing/GetQuote",
+/// #     RpcMethodKind::Unary,
+/// #     RpcIdempotencyLevel::NoSideEffects,
+/// # );
+/// async fn quote(info: &RpcMethodInfo) -> RpcResult<f64> {
+///     let policy = HedgingPolicy::new(Duration::from_millis(40)).max_attempts(3);
+///     // `info` is eg. `PricingService::get_quote_info()`.
+///     hedge(&policy, info, |attempt| get_quote("ACME", attempt.headers())).await
+/// }
+/// ```
+pub async fn hedge<T, F, Fut>(
+    policy: &HedgingPolicy,
+    method: &RpcMethodInfo,
+    mut call: F,
+) -> Result<T, RpcError>
+where
+    F: FnMut(HedgeAttempt) -> Fut,
+    Fut: Future<Output = Result<T, RpcError>>,
+{
+    if method.idempotency_level != RpcIdempotencyLevel::NoSideEffects {
+        return Err(RpcError::new(
+            RpcErrorCode::Internal,
+            format!(
+                "{} is {}, only no_side_effects RPCs can be hedged",
+                method.path, method.idempotency_level
+            ),
+        ));
+    }
+
+    let mut attempts = FuturesUnordered::new();
+    attempts.push(call(HedgeAttempt { attempt: 1 }));
+    let mut started = 1;
+    let mut last_error = None;
+    let mut delay = pin!(tokio::time::sleep(policy.delay));
+    loop {
+        // `None` once the delay passed.
+        let finished = match started < policy.max_attempts {
+            true => match select(attempts.next(), delay.as_mut()).await {
+                Either::Left((finished, _)) => finished,
+                Either::Right(_) => None,
+            },
+            false => attempts.next().await,
+        };
+        match finished {
+            Some(Ok(value)) => return Ok(value),
+            Some(Err(error)) if !policy.retryable.contains(&error.code) => return Err(error),
+            Some(Err(error)) => last_error = Some(error),
+            None => {}
+        }
+
+        if started < policy.max_attempts {
+            started += 1;
+            attempts.push(call(HedgeAttempt { attempt: started }));
+            delay.as_mut().reset(Instant::now() + policy.delay);
+        } else if attempts.is_empty() {
+            // Only failed attempts leave it empty.
+            return Err(last_error.unwrap());
+        }
+    }
+}
+
+/// The delay the server asked for, if any.
+fn requested_delay(error: &RpcError) -> Option<Duration> {
+    let retry_info = error
+        .details
+        .iter()
+        .filter(|detail| detail.proto_type == "google.rpc.RetryInfo")
+        .find_map(|detail| detail.decode::<RetryInfo>())
+        .and_then(|info| info.retry_delay)
+        .map(|delay| delay.to_std_saturating());
+
+    retry_info.or_else(|| {
+        let seconds = error.metadata().get(header::RETRY_AFTER)?.to_str().ok()?;
+        seconds.trim().parse().ok().map(Duration::from_secs)
+    })
+}
+
+/// A random number in `0.0..1.0`, good enough for jitter.
+fn random_fraction() -> f64 {
+    // Every `RandomState` gets new keys, so hashing the same value gives a new result each time.
+    let random = RandomState::new().hash_one(0u8);
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use crate::{error::RpcErrorDetail, ratelimit::RetryInfo, router::RpcMethodKind};
+
+    /// Fails with `code` the first `failures` calls, then succeeds, counting the calls.
+    fn flaky(
+        calls: &AtomicU32,
+        failures: u32,
+        code: RpcErrorCode,
+    ) -> impl Future<Output = Result<u32, RpcError>> + '_ {
+        let call = calls.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            match call <= failures {
+                true => Err(RpcError::new(code, "upstream failed".to_string())),
+                false => Ok(call),
+            }
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new()
+            .backoff(Duration::from_millis(100), Duration::from_secs(1))
+            .jitter(0.0)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_the_call_succeeds() {
+        let calls = AtomicU32::new(0);
+        let delays = Arc::new(Mutex::new(vec![]));
+        let policy = policy().on_retry({
+            let delays = delays.clone();
+            move |attempt| delays.lock().unwrap().push(attempt.delay)
+        });
+
+        let result = retry(&policy, || flaky(&calls, 2, RpcErrorCode::Unavailable)).await;
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(
+            *delays.lock().unwrap(),
+            [Duration::from_millis(100), Duration::from_millis(200)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn doesnt_retry_other_codes_or_past_the_attempts() {
+        let calls = AtomicU32::new(0);
+        let error = retry(&policy(), || flaky(&calls, 1, RpcErrorCode::NotFound))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let calls = AtomicU32::new(0);
+        let error = retry(&policy(), || flaky(&calls, 5, RpcErrorCode::Unavailable))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn waits_for_the_delay_the_server_asks_for() {
+        let calls = AtomicU32::new(0);
+        let start = Instant::now();
+        let result = retry(&policy(), || {
+            let call = calls.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if call > 0 {
+                    return Ok(Instant::now());
+                }
+                let retry_info = RetryInfo {
+                    retry_delay: Some(crate::well_known::Duration {
+                        seconds: 7,
+                        nanos: 0,
+                    }),
+                };
+                let mut error = RpcError::new(RpcErrorCode::Unavailable, "busy".to_string());
+                error
+                    .details
+                    .push(RpcErrorDetail::new("google.rpc.RetryInfo", &retry_info));
+                Err(error)
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap() - start, Duration::from_secs(7));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn spends_the_budget_on_retries_only() {
+        let budget = RetryBudget::new(2).deposit(0.5);
+        let policy = policy().max_attempts(10).budget(budget.clone());
+
+        // Errors that aren't retried don't spend any.
+        let calls = AtomicU32::new(0);
+        let _ = retry(&policy, || flaky(&calls, 1, RpcErrorCode::InvalidArgument)).await;
+        assert_eq!(budget.remaining(), 2.0);
+
+        let calls = AtomicU32::new(0);
+        let result = retry(&policy, || flaky(&calls, 1, RpcErrorCode::Unavailable)).await;
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(budget.remaining(), 1.5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn passes_errors_through_once_the_budget_is_spent() {
+        let budget = RetryBudget::new(2).deposit(0.5);
+        let policy = policy().max_attempts(10).budget(budget.clone());
+
+        let calls = AtomicU32::new(0);
+        let error = retry(&policy, || flaky(&calls, 10, RpcErrorCode::Unavailable))
+            .await
+            .unwrap_err();
+        assert_eq!(error.code, RpcErrorCode::Unavailable);
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(budget.remaining(), 0.0);
+
+        // Spent, so the upstream's error comes back without a retry.
+        let calls = AtomicU32::new(0);
+        let error = retry(&policy, || flaky(&calls, 1, RpcErrorCode::Unavailable))
+            .await
+            .unwrap_err();
+        assert_eq!(error.message, "upstream failed");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Successful calls put some back.
+        for _ in 0..2 {
+            let calls = AtomicU32::new(0);
+            retry(&policy, || flaky(&calls, 0, RpcErrorCode::Unavailable))
+                .await
+                .unwrap();
+        }
+        assert_eq!(budget.remaining(), 1.0);
+        let calls = AtomicU32::new(0);
+        let result = retry(&policy, || flaky(&calls, 1, RpcErrorCode::Unavailable)).await;
+        assert_eq!(result.unwrap(), 2);
+    }
+
+    fn quote_info(idempotency_level: RpcIdempotencyLevel) -> RpcMethodInfo {
+        RpcMethodInfo::from_rpc_path(
+            "/pricing.Pricing/GetQuote",
+            RpcMethodKind::Unary,
+            idempotency_level,
+        )
+    }
+
+    /// Marks an attempt cancelled when it's dropped before finishing.
+    struct Attempt {
+        number: u32,
+        cancelled: Arc<Mutex<Vec<u32>>>,
+        finished: bool,
+    }
+
+    impl Attempt {
+        fn finish(&mut self) {
+            self.finished = true;
+        }
+    }
+
+    impl Drop for Attempt {
+        fn drop(&mut self) {
+            if !self.finished {
+                self.cancelled.lock().unwrap().push(self.number);
+            }
+        }
+    }
+
+    /// Answers attempts with their number after the latency at their index, recording when they
+    /// started and which were cancelled.
+    struct Upstream {
+        latencies: Vec<(u64, Result<(), RpcErrorCode>)>,
+        start: Instant,
+        started: Mutex<Vec<Duration>>,
+        cancelled: Arc<Mutex<Vec<u32>>>,
+    }
+
+    impl Upstream {
+        fn new(latencies: &[(u64, Result<(), RpcErrorCode>)]) -> Self {
+            Self {
+                latencies: latencies.to_vec(),
+                start: Instant::now(),
+                started: Mutex::new(vec![]),
+                cancelled: Arc::new(Mutex::new(vec![])),
+            }
+        }
+
+        fn call(&self, attempt: HedgeAttempt) -> impl Future<Output = Result<u32, RpcError>> {
+            self.started.lock().unwrap().push(self.start.elapsed());
+            let (latency, result) = self.latencies[attempt.attempt as usize - 1].clone();
+            let mut guard = Attempt {
+                number: attempt.attempt,
+                cancelled: self.cancelled.clone(),
+                finished: false,
+            };
+            async move {
+                tokio::time::sleep(Duration::from_millis(latency)).await;
+                guard.finish();
+                result
+                    .map(|_| attempt.attempt)
+                    .map_err(|code| RpcError::new(code, "upstream failed".to_string()))
+            }
+        }
+
+        fn started(&self) -> Vec<Duration> {
+            self.started.lock().unwrap().clone()
+        }
+
+        fn cancelled(&self) -> Vec<u32> {
+            self.cancelled.lock().unwrap().clone()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hedges_after_the_delay_and_takes_the_first_success() {
+        let policy = HedgingPolicy::new(Duration::from_millis(40));
+        let upstream = Upstream::new(&[(100, Ok(())), (20, Ok(()))]);
+
+        let start = Instant::now();
+        let result = hedge(
+            &policy,
+            &quote_info(RpcIdempotencyLevel::NoSideEffects),
+            |attempt| upstream.call(attempt),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), 2);
+        assert_eq!(start.elapsed(), Duration::from_millis(60));
+        assert_eq!(
+            upstream.started(),
+            [Duration::ZERO, Duration::from_millis(40)]
+        );
+        // The slow first attempt is dropped, not waited for.
+        assert_eq!(upstream.cancelled(), [1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn doesnt_hedge_calls_answered_within_the_delay() {
+        let policy = HedgingPolicy::new(Duration::from_millis(40)).max_attempts(3);
+        let upstream = Upstream::new(&[(30, Ok(())), (10, Ok(())), (10, Ok(()))]);
+
+        let result = hedge(
+            &policy,
+            &quote_info(RpcIdempotencyLevel::NoSideEffects),
+            |attempt| upstream.call(attempt),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(upstream.started().len(), 1);
+        assert!(upstream.cancelled().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_the_next_attempt_right_away_on_retryable_errors() {
+        let policy = HedgingPolicy::new(Duration::from_millis(40)).max_attempts(3);
+        let upstream = Upstream::new(&[
+            (5, Err(RpcErrorCode::Unavailable)),
+            (100, Ok(())),
+            (10, Ok(())),
+        ]);
+
+        let result = hedge(
+            &policy,
+            &quote_info(RpcIdempotencyLevel::NoSideEffects),
+            |attempt| upstream.call(attempt),
+        )
+        .await;
+
+        assert_eq!(result.unwrap(), 3);
+        assert_eq!(
+            upstream.started(),
+            [
+                Duration::ZERO,
+                Duration::from_millis(5),
+                Duration::from_millis(45)
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn returns_other_errors_as_they_come() {
+        let policy = HedgingPolicy::new(Duration::from_millis(40));
+        let upstream = Upstream::new(&[(100, Ok(())), (10, Err(RpcErrorCode::NotFound))]);
+
+        let error = hedge(
+            &policy,
+            &quote_info(RpcIdempotencyLevel::NoSideEffects),
+            |attempt| upstream.call(attempt),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+        assert_eq!(upstream.cancelled(), [1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_hedges_rpcs_without_side_effects() {
+        let policy = HedgingPolicy::new(Duration::from_millis(40));
+        let upstream = Upstream::new(&[(10, Ok(()))]);
+
+        let error = hedge(
+            &policy,
+            &quote_info(RpcIdempotencyLevel::Idempotent),
+            |attempt| upstream.call(attempt),
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(error.code, RpcErrorCode::Internal);
+        assert!(upstream.started().is_empty());
+    }
+}
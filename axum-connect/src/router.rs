@@ -1,18 +1,3525 @@
-use axum::Router;
+use std::{
+    any::{type_name, Any},
+    collections::{BTreeSet, HashMap},
+    convert::Infallible,
+    fmt,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
+use axum::{
+    body::Bytes,
+    extract::{Request, State},
+    http::{self, header, request, Method, StatusCode, Uri, Version},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{self, future::RouteFuture, IntoMakeService, MethodRouter, Route},
+    BoxError, Extension, Router,
+};
+use pbjson_types::FileDescriptorSet;
+use prost::Message;
+use serde_json::json;
+use tower::{Layer, Service, ServiceExt};
+
+use crate::{
+    audit::RpcAuditor,
+    auth::{Authorizer, RpcAuthorize},
+    breaker::{self, CircuitBreaker},
+    codec::ContentType,
+    concurrency::{ConcurrencyLimits, ConnectionCalls},
+    config::{RpcConfig, DEFAULT_MAX_GET_URL_BYTES},
+    defaults::RequestDefaults,
+    deprecation::{self, Deprecation},
+    drain::{self, DrainController},
+    error::{RpcError, RpcErrorCode},
+    events::RpcEventBus,
+    handler::{
+        codec::ResponseEncoder, RpcHandlerClientStream, RpcHandlerDynamic, RpcHandlerStream,
+        RpcHandlerUnary,
+    },
+    hooks::ResponseHooks,
+    layers::RecommendedLayers,
+    logging::{RpcCallStats, RpcCallStatsLayer},
+    ping::PingService,
+    signing::{self, ResponseSigning},
+    trace,
+    verify::{BodyVerifier, RouteVerifier},
+};
 
 pub trait RpcRouterExt<S>: Sized {
+    /// Mounts the RPCs `register` adds, eg. `HelloWorldService::say_hello(handler)`.
+    ///
+    /// On a plain axum `Router` they're served but not recorded: it has nowhere to keep the
+    /// method table, so [`paths`](RpcRouter::paths), [`manifest_json`](RpcRouter::manifest_json),
+    /// [`validate_against`](RpcRouter::validate_against), the capabilities and the debug routes
+    /// miss them. Mount them on an [`RpcRouter`] (`RpcRouter::from(router)` for one you have
+    /// already) to have them recorded.
+    fn rpc<F>(self, register: F) -> Self
+    where
+        F: FnOnce(RpcRouter<S>) -> RpcRouter<S>;
+
+    /// Like [`rpc`](RpcRouterExt::rpc), but the service gets its own state type `T` instead of
+    /// sharing the router's. The service is mounted on its own router, which is given `state` and
+    /// then merged in.
+    fn rpc_with_state<T, F>(self, state: T, register: F) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce(RpcRouter<T>) -> RpcRouter<T>;
+}
+
+impl<S> RpcRouterExt<S> for Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
     fn rpc<F>(self, register: F) -> Self
     where
-        F: FnOnce(Self) -> RpcRouter<S>;
+        F: FnOnce(RpcRouter<S>) -> RpcRouter<S>,
+    {
+        // The method table is dropped with the `RpcRouter`, see the trait method.
+        register(RpcRouter::from(self)).into_router()
+    }
+
+    fn rpc_with_state<T, F>(self, state: T, register: F) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce(RpcRouter<T>) -> RpcRouter<T>,
+    {
+        self.merge(
+            register(RpcRouter::new())
+                .with_state::<S>(state)
+                .into_router(),
+        )
+    }
 }
 
-impl<S> RpcRouterExt<S> for Router<S> {
+impl<S> RpcRouterExt<S> for RpcRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
     fn rpc<F>(self, register: F) -> Self
     where
-        F: FnOnce(Self) -> RpcRouter<S>,
+        F: FnOnce(RpcRouter<S>) -> RpcRouter<S>,
     {
         register(self)
     }
+
+    fn rpc_with_state<T, F>(self, state: T, register: F) -> Self
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce(RpcRouter<T>) -> RpcRouter<T>,
+    {
+        self.merge(register(RpcRouter::new()).with_state(state))
+    }
+}
+
+/// The streaming shape of an RPC, as declared in the proto service definition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RpcMethodKind {
+    Unary,
+    ClientStreaming,
+    ServerStreaming,
+    BidiStreaming,
+}
+
+impl fmt::Display for RpcMethodKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RpcMethodKind::Unary => "unary",
+            RpcMethodKind::ClientStreaming => "client_streaming",
+            RpcMethodKind::ServerStreaming => "server_streaming",
+            RpcMethodKind::BidiStreaming => "bidi_streaming",
+        })
+    }
+}
+
+/// The `idempotency_level` method option from the proto service definition.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum RpcIdempotencyLevel {
+    #[default]
+    IdempotencyUnknown,
+    /// Safe to retry and to serve over GET (and so from HTTP caches).
+    NoSideEffects,
+    /// Safe to retry, but may have side effects.
+    Idempotent,
+}
+
+impl RpcIdempotencyLevel {
+    /// Maps the raw `MethodOptions.idempotency_level` value, unknown values are treated as
+    /// `IdempotencyUnknown`.
+    pub fn from_proto(level: i32) -> Self {
+        match level {
+            1 => RpcIdempotencyLevel::NoSideEffects,
+            2 => RpcIdempotencyLevel::Idempotent,
+            _ => RpcIdempotencyLevel::IdempotencyUnknown,
+        }
+    }
+}
+
+impl fmt::Display for RpcIdempotencyLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RpcIdempotencyLevel::IdempotencyUnknown => "idempotency_unknown",
+            RpcIdempotencyLevel::NoSideEffects => "no_side_effects",
+            RpcIdempotencyLevel::Idempotent => "idempotent",
+        })
+    }
+}
+
+/// Describes a single mounted RPC.
+///
+/// Generated registration functions fill this in from the proto method descriptor. Routes added by
+/// hand through [`RpcRouter::route`] only have their path to go on, so `kind` is `None`, the
+/// idempotency level is unknown and the HTTP methods are empty.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct RpcMethodInfo {
+    /// The full HTTP path, eg. `/hello.HelloWorldService/SayHello`.
+    pub path: String,
+    /// The fully-qualified proto service name, eg. `hello.HelloWorldService`.
+    pub service: String,
+    /// The proto method name, eg. `SayHello`.
+    pub method: String,
+    pub kind: Option<RpcMethodKind>,
+    pub idempotency_level: RpcIdempotencyLevel,
+    /// The HTTP methods the RPC is mounted for. Generated code mounts `POST`, plus `GET` for
+    /// `NO_SIDE_EFFECTS` methods.
+    pub http_methods: Vec<Method>,
+    /// The Rust types of the request and response messages, as codegen maps them from the method
+    /// descriptor (eg. `GetUserRequest`, relative to the package module), for
+    /// [`RpcMethod::unary`] and [`RpcMethod::server_streaming`] to check handlers against.
+    pub message_types: Option<(&'static str, &'static str)>,
+}
+
+impl RpcMethodInfo {
+    pub fn new(
+        service: &str,
+        method: &str,
+        kind: RpcMethodKind,
+        idempotency_level: RpcIdempotencyLevel,
+    ) -> Self {
+        Self {
+            path: format!("/{}/{}", service, method),
+            service: service.to_string(),
+            method: method.to_string(),
+            kind: Some(kind),
+            idempotency_level,
+            http_methods: vec![Method::POST],
+            message_types: None,
+        }
+    }
+
+    /// Like [`new`](RpcMethodInfo::new), from the full path (eg. `/hello.HelloWorldService/SayHello`)
+    /// instead of its parts. Generated code passes its path constants here.
+    pub fn from_rpc_path(
+        path: &str,
+        kind: RpcMethodKind,
+        idempotency_level: RpcIdempotencyLevel,
+    ) -> Self {
+        Self {
+            kind: Some(kind),
+            idempotency_level,
+            http_methods: vec![Method::POST],
+            ..Self::from_path(path)
+        }
+    }
+
+    /// Best-effort info for an RPC we only know the path of. Connect paths are always
+    /// `/{service}/{method}`, anything else is kept as-is in `method`.
+    pub fn from_path(path: &str) -> Self {
+        let (service, method) = path
+            .trim_start_matches('/')
+            .rsplit_once('/')
+            .unwrap_or(("", path));
+
+        Self {
+            path: path.to_string(),
+            service: service.to_string(),
+            method: method.to_string(),
+            kind: None,
+            idempotency_level: RpcIdempotencyLevel::IdempotencyUnknown,
+            http_methods: vec![],
+            message_types: None,
+        }
+    }
+
+    pub fn with_http_methods(mut self, http_methods: Vec<Method>) -> Self {
+        self.http_methods = http_methods;
+        self
+    }
+
+    /// See [`message_types`](RpcMethodInfo::message_types). Generated code sets them.
+    pub fn with_message_types(mut self, request: &'static str, response: &'static str) -> Self {
+        self.message_types = Some((request, response));
+        self
+    }
+
+    /// The same RPC at `path`, its service and method names taken from it.
+    fn rebased(&self, path: &str) -> Self {
+        let names = Self::from_path(path);
+        Self {
+            path: names.path,
+            service: names.service,
+            method: names.method,
+            ..self.clone()
+        }
+    }
+
+    /// True if the method is marked `NO_SIDE_EFFECTS` or `IDEMPOTENT`, ie. safe to retry.
+    pub fn idempotent(&self) -> bool {
+        self.idempotency_level != RpcIdempotencyLevel::IdempotencyUnknown
+    }
+}
+
+/// A single RPC, not mounted yet: its info and the method router of its handler. Generated code
+/// has a `<method>_method` function building one, which the registration functions mount with
+/// [`RpcRouter::rpc_method`].
+///
+/// [`into_service`](RpcMethod::into_service) turns it into a tower `Service` of its own, eg. to
+/// wrap a single RPC in a circuit breaker or `tower::balance` before mounting it with
+/// [`RpcRouter::rpc_service`]:
+///
+/// ```
+/// # use axum_connect::router::{RpcMethod, RpcMethodInfo, RpcRouter};
+/// # use tower::ServiceBuilder;
+/// # struct HelloWorldService;
+/// # impl HelloWorldService {
+/// #     fn say_hello_method(_handler: ()) -> RpcMethod {
+/// #         let info = RpcMethodInfo::from_path("/hello.HelloWorldService/SayHello");
+/// #         RpcMethod::new(info, axum::routing::post(|| async {}))
+/// #     }
+/// # }
+/// # let (say_hello, state) = ((), ());
+/// let say_hello = HelloWorldService::say_hello_method(say_hello).into_service(state);
+/// let info = say_hello.info().clone();
+/// let say_hello = ServiceBuilder::new().concurrency_limit(16).service(say_hello);
+/// let app: RpcRouter = RpcRouter::new().rpc_service(info, say_hello);
+/// ```
+pub struct RpcMethod<S = ()> {
+    info: RpcMethodInfo,
+    method_router: MethodRouter<S>,
+    /// The `type_name`s of the handler's request and response messages, if it's known.
+    handler_types: Option<(&'static str, &'static str)>,
+}
+
+impl<S> RpcMethod<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    pub fn new(info: RpcMethodInfo, method_router: MethodRouter<S>) -> Self {
+        Self {
+            info,
+            method_router,
+            handler_types: None,
+        }
+    }
+
+    /// The unary RPC `info` describes, served by `handler`, over POST and, if `info` lists it, GET.
+    ///
+    /// In debug builds (or with the `paranoid` feature), mounting it panics if `handler` doesn't
+    /// take and return the [`message_types`](RpcMethodInfo::message_types) of `info`, eg. a handler
+    /// wired to the wrong method. Release builds don't check.
+    pub fn unary<TMReq, TMRes, T, H>(info: RpcMethodInfo, handler: H) -> Self
+    where
+        H: RpcHandlerUnary<TMReq, TMRes, T, S>,
+        T: 'static,
+    {
+        let get = info.http_methods.contains(&Method::GET);
+        let mut method_router = routing::post({
+            let handler = handler.clone();
+            |State(state): State<S>, request: Request| async move { handler.call(request, state).await }
+        });
+        if get {
+            method_router =
+                method_router.get(|State(state): State<S>, request: Request| async move {
+                    handler.call(request, state).await
+                });
+        }
+        Self {
+            handler_types: Some((type_name::<TMReq>(), type_name::<TMRes>())),
+            ..Self::new(info, method_router)
+        }
+    }
+
+    /// Like [`unary`](RpcMethod::unary), for a server-streaming RPC, over POST.
+    pub fn server_streaming<TMReq, TMRes, T, H>(info: RpcMethodInfo, handler: H) -> Self
+    where
+        H: RpcHandlerStream<TMReq, TMRes, T, S>,
+        T: 'static,
+    {
+        let method_router = routing::post(|State(state): State<S>, request: Request| async move {
+            handler.call(request, state).await
+        });
+        Self {
+            handler_types: Some((type_name::<TMReq>(), type_name::<TMRes>())),
+            ..Self::new(info, method_router)
+        }
+    }
+
+    /// Like [`unary`](RpcMethod::unary), for a client-streaming RPC, over POST. Codegen skips
+    /// these methods for now, mount them with this.
+    pub fn client_streaming<TMReq, TMRes, T, H>(info: RpcMethodInfo, handler: H) -> Self
+    where
+        H: RpcHandlerClientStream<TMReq, TMRes, T, S>,
+        T: 'static,
+    {
+        let method_router = routing::post(|State(state): State<S>, request: Request| async move {
+            handler.call(request, state).await
+        });
+        Self {
+            handler_types: Some((type_name::<TMReq>(), type_name::<TMRes>())),
+            ..Self::new(info, method_router)
+        }
+    }
+
+    /// Panics if the handler's message types are known and aren't those of the info, in debug
+    /// builds or with the `paranoid` feature.
+    fn check_message_types(&self) {
+        if !cfg!(any(debug_assertions, feature = "paranoid")) {
+            return;
+        }
+        let (Some((request, response)), Some((expected_request, expected_response))) =
+            (self.handler_types, self.info.message_types)
+        else {
+            return;
+        };
+        if !same_type(expected_request, request) || !same_type(expected_response, response) {
+            panic!(
+                "{} takes {} and returns {}, but its handler takes {} and returns {}",
+                self.info.path, expected_request, expected_response, request, response
+            );
+        }
+    }
+
+    pub fn info(&self) -> &RpcMethodInfo {
+        &self.info
+    }
+
+    /// The RPC as a `Service`, with the layers [`RpcRouter::rpc_route`] would give it. Layers of
+    /// the router it's mounted on (eg. [`RpcRouter::with_config`]) still apply.
+    pub fn into_service(self, state: S) -> RpcMethodService {
+        self.check_message_types();
+        let method_router =
+            route_layers(&self.info, self.method_router.fallback(method_not_allowed));
+        RpcMethodService {
+            info: self.info,
+            method_router: method_router.with_state(state),
+        }
+    }
+}
+
+impl<S> fmt::Debug for RpcMethod<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcMethod")
+            .field("info", &self.info)
+            .finish()
+    }
+}
+
+/// Whether `actual`, a `type_name`, is the type `expected` names: a path relative to a package
+/// module, the way codegen maps descriptor types, so only its last segments need to match.
+fn same_type(expected: &str, actual: &str) -> bool {
+    let mut expected = expected.trim_start_matches("::");
+    while let Some(rest) = ["super::", "self::", "crate::"]
+        .iter()
+        .find_map(|prefix| expected.strip_prefix(prefix))
+    {
+        expected = rest;
+    }
+    actual == expected
+        || actual
+            .strip_suffix(expected)
+            .is_some_and(|module| module.ends_with("::"))
+}
+
+/// A single RPC as a tower `Service`, see [`RpcMethod::into_service`].
+#[derive(Clone, Debug)]
+pub struct RpcMethodService {
+    info: RpcMethodInfo,
+    method_router: MethodRouter,
+}
+
+impl RpcMethodService {
+    pub fn info(&self) -> &RpcMethodInfo {
+        &self.info
+    }
+}
+
+impl<B> Service<http::Request<B>> for RpcMethodService
+where
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = RouteFuture<Infallible>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<http::Request<B>>::poll_ready(&mut self.method_router, cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        self.method_router.call(request)
+    }
+}
+
+/// One RPC of a generated service, mounted with a value handling all of them, for mounting
+/// services from tables. Codegen with `service_traits` emits a `registrars()` of each service,
+/// one per RPC in the order of its `PATHS`, with `H` implementing the service's handlers trait:
+///
+/// ```ignore
+/// // Everything but the admin RPC, in the public binary.
+/// let app = Users::registrars::<Handlers, ()>()
+///     .iter()
+///     .filter(|registrar| registrar.method != "DeleteUser")
+///     .fold(RpcRouter::new(), |app, registrar| (registrar.register)(app, handlers.clone()));
+/// ```
+pub struct RpcRegistrar<S, H> {
+    /// The proto name of the method, eg. `SayHello`.
+    pub method: &'static str,
+    pub path: &'static str,
+    /// Mounts the RPC, handled by the trait method of `H`.
+    pub register: fn(RpcRouter<S>, H) -> RpcRouter<S>,
+}
+
+impl<S, H> Clone for RpcRegistrar<S, H> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S, H> Copy for RpcRegistrar<S, H> {}
+
+impl<S, H> fmt::Debug for RpcRegistrar<S, H> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcRegistrar")
+            .field("method", &self.method)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// A difference between the mounted RPCs and the descriptors, see
+/// [`RpcRouter::validate_against`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RouteMismatch {
+    /// A proto method no RPC is mounted for.
+    NotMounted { path: String },
+    /// A mounted RPC none of the descriptors define.
+    UnknownMethod { path: String },
+    /// The descriptor set at `index` couldn't be decoded.
+    InvalidDescriptorSet { index: usize, error: String },
+}
+
+impl fmt::Display for RouteMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteMismatch::NotMounted { path } => write!(f, "{} is defined but not mounted", path),
+            RouteMismatch::UnknownMethod { path } => {
+                write!(f, "{} is mounted but not defined in any descriptor", path)
+            }
+            RouteMismatch::InvalidDescriptorSet { index, error } => {
+                write!(f, "descriptor set {} is invalid: {}", index, error)
+            }
+        }
+    }
+}
+
+/// The default of [`RouteOptions::max_stream_frames`].
+pub const DEFAULT_MAX_STREAM_FRAMES: usize = 100_000;
+
+/// The default of [`RouteOptions::max_stream_total_bytes`], 256 MiB.
+pub const DEFAULT_MAX_STREAM_TOTAL_BYTES: usize = 256 << 20;
+
+/// Options for a group of routes, see [`RpcRouter::rpc_with_options`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RouteOptions {
+    /// Skip the [`RpcAuthorize`] check, eg. for health checks and login RPCs.
+    pub public: bool,
+    /// The most calls each route may have in flight (streams count until they end), across
+    /// codecs and the POST and GET routes of a method. Unlimited by default.
+    pub concurrency_limit: Option<usize>,
+    /// How many calls over the concurrency limit wait for a slot, per route. The rest fail with
+    /// `ResourceExhausted`. None by default.
+    pub concurrency_queue: usize,
+    /// Decides per call whether the routes are enabled, see [`enabled`](RouteOptions::enabled).
+    pub enabled: Option<RouteGate>,
+    /// The code calls to disabled routes fail with. `Unimplemented` by default, as if the route
+    /// didn't exist.
+    pub disabled_code: RpcErrorCode,
+    /// Checks request bodies before they're decoded, see [`BodyVerifier`].
+    pub body_verifier: Option<RouteVerifier>,
+    /// Templates for unset request fields, see
+    /// [`request_defaults`](RouteOptions::request_defaults).
+    pub request_defaults: Vec<RequestDefaults>,
+    /// Marks the routes deprecated, see [`deprecated`](RouteOptions::deprecated).
+    pub deprecation: Option<Deprecation>,
+    /// Records the calls with the router's [`RpcAuditor`], see [`audited`](RouteOptions::audited).
+    pub audited: bool,
+    /// Reads request bodies into a buffer of their `content-length`, see
+    /// [`preallocate_body`](RouteOptions::preallocate_body).
+    pub preallocate_body: bool,
+    /// Fails calls fast while too many recent ones failed, see
+    /// [`circuit_breaker`](RouteOptions::circuit_breaker).
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Serves server streams as Server-Sent Events to clients that ask for them, with a heartbeat
+    /// comment after this long without an event, see [`sse`](RouteOptions::sse).
+    #[cfg(feature = "sse")]
+    pub sse: Option<Duration>,
+    /// Gzips the messages of response streams of at least this many bytes, for clients that
+    /// accept it, see [`stream_compression`](RouteOptions::stream_compression).
+    #[cfg(feature = "stream-compression")]
+    pub stream_compression: Option<usize>,
+    /// Whether responses are compressed, see [`compression`](RouteOptions::compression).
+    pub compression: CompressionMode,
+    /// The most messages a request stream may have, see
+    /// [`max_stream_frames`](RouteOptions::max_stream_frames).
+    pub max_stream_frames: usize,
+    /// The most bytes a request stream may have, see
+    /// [`max_stream_total_bytes`](RouteOptions::max_stream_total_bytes).
+    pub max_stream_total_bytes: usize,
+}
+
+impl Default for RouteOptions {
+    fn default() -> Self {
+        Self {
+            public: false,
+            concurrency_limit: None,
+            concurrency_queue: 0,
+            enabled: None,
+            disabled_code: RpcErrorCode::Unimplemented,
+            body_verifier: None,
+            request_defaults: vec![],
+            deprecation: None,
+            audited: false,
+            preallocate_body: false,
+            circuit_breaker: None,
+            #[cfg(feature = "sse")]
+            sse: None,
+            #[cfg(feature = "stream-compression")]
+            stream_compression: None,
+            compression: CompressionMode::Auto,
+            max_stream_frames: DEFAULT_MAX_STREAM_FRAMES,
+            max_stream_total_bytes: DEFAULT_MAX_STREAM_TOTAL_BYTES,
+        }
+    }
+}
+
+impl RouteOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn public() -> Self {
+        Self {
+            public: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn concurrency_limit(mut self, concurrency_limit: usize) -> Self {
+        self.concurrency_limit = Some(concurrency_limit);
+        self
+    }
+
+    pub fn concurrency_queue(mut self, concurrency_queue: usize) -> Self {
+        self.concurrency_queue = concurrency_queue;
+        self
+    }
+
+    /// Only serve the routes while `predicate` returns true, eg. to dark-launch RPCs behind a
+    /// runtime feature flag. It runs on every call before authorization and the extractors, which
+    /// are skipped along with the handler when it returns false. It sees the request headers, eg.
+    /// of allowlisted testers; capture whatever else it needs, like a flag store shared with the
+    /// router state.
+    pub fn enabled(
+        mut self,
+        predicate: impl Fn(&request::Parts) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.enabled = Some(RouteGate(Arc::new(predicate)));
+        self
+    }
+
+    pub fn disabled_code(mut self, disabled_code: RpcErrorCode) -> Self {
+        self.disabled_code = disabled_code;
+        self
+    }
+
+    /// Checks the raw bytes of every request body with `verifier` before decoding it, eg. for
+    /// signed webhooks. Calls it rejects fail with `Unauthenticated`.
+    pub fn verify_body(mut self, verifier: impl BodyVerifier) -> Self {
+        self.body_verifier = Some(RouteVerifier::new(verifier));
+        self
+    }
+
+    /// Fills the fields a request leaves unset from `template`, eg. a `page_size` of 50, before the
+    /// handler sees it. Fields that are set are never overwritten, even to their default value if
+    /// they have presence (`optional` and message fields); set message fields aren't merged with
+    /// the template's either. Repeated and map fields count as unset when empty.
+    ///
+    /// Only routes whose request message is `M` get the defaults, so a group of routes can have a
+    /// template for each of its request types. Another template of the same type replaces it.
+    pub fn request_defaults<M: Message + 'static>(mut self, template: M) -> Self {
+        self.request_defaults
+            .retain(|defaults| !defaults.is_for::<M>());
+        self.request_defaults.push(RequestDefaults::new(&template));
+        self
+    }
+
+    /// Keeps serving the routes until `sunset` and after, but tells callers they're deprecated,
+    /// with `Deprecation` and `Sunset` response headers, and logs the calls. `message` should say
+    /// what to use instead. See [`Deprecation`] for the details, and to fail calls after the
+    /// sunset.
+    pub fn deprecated(self, message: &str, sunset: SystemTime) -> Self {
+        self.deprecation(Deprecation::new(message, sunset))
+    }
+
+    pub fn deprecation(mut self, deprecation: Deprecation) -> Self {
+        self.deprecation = Some(deprecation);
+        self
+    }
+
+    /// Records every call to the routes, request and response, with the [`RpcAuditor`] mounted
+    /// by [`RpcRouter::audit`]. Only unary RPCs are recorded; without an auditor, nothing is.
+    pub fn audited(mut self) -> Self {
+        self.audited = true;
+        self
+    }
+
+    /// Reads each request body into one buffer, reserved upfront for its `content-length`,
+    /// instead of one grown as chunks arrive, for routes taking large messages, eg. an import
+    /// taking tens of MB of JSON. A grown buffer is copied on each doubling and ends up with up
+    /// to twice the capacity it needs; a reserved one holds the body once, and the message is
+    /// decoded straight from it, so a call peaks at the body plus the decoded message.
+    ///
+    /// Only with a [`max_request_bytes`](crate::config::RpcConfig::max_request_bytes) limit,
+    /// without one nothing is reserved and the buffer grows as usual. Bodies declaring more than
+    /// the limit are already rejected before they're read, so a client can't make the server
+    /// reserve more than the limit; one sending less than it declared leaves the rest unused.
+    pub fn preallocate_body(mut self) -> Self {
+        self.preallocate_body = true;
+        self
+    }
+
+    /// Fails calls fast with `Unavailable` while too many recent ones failed, eg. because a
+    /// dependency is down and every call would wait for its timeout, see [`CircuitBreaker`]. Each
+    /// route gets a circuit of its own; keep a clone of `breaker` to watch and reset them.
+    pub fn circuit_breaker(mut self, breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Some(breaker);
+        self
+    }
+
+    /// Answers calls to the server-streaming routes with `Accept: text/event-stream` with
+    /// Server-Sent Events instead of Connect frames, for browsers behind proxies that break
+    /// streamed `fetch` bodies. Each message is a `data:` line of its JSON, so requests must be
+    /// `application/connect+json` (others get Connect frames); the stream ends with an
+    /// `event: error` of the error's JSON, or an `event: end` of the end-of-stream JSON with the
+    /// trailers. A `: heartbeat` comment goes out after `heartbeat` without an event, so proxies
+    /// don't time the stream out. It isn't part of the Connect protocol, Connect clients can't
+    /// read it.
+    #[cfg(feature = "sse")]
+    pub fn sse(mut self, heartbeat: Duration) -> Self {
+        self.sse = Some(heartbeat);
+        self
+    }
+
+    /// Gzips the messages of the response streams of clients sending `connect-accept-encoding:
+    /// gzip`, those of at least `threshold` bytes. Each frame is compressed or not on its own,
+    /// with the compressed flag set or clear, so a stream of small updates and the odd large
+    /// snapshot only spends CPU on the snapshots. The end-of-stream frame isn't compressed. A
+    /// `threshold` of 0 compresses every message. How many were is in the
+    /// [`RpcCallStats`](crate::logging::RpcCallStats).
+    #[cfg(feature = "stream-compression")]
+    pub fn stream_compression(mut self, threshold: usize) -> Self {
+        self.stream_compression = Some(threshold);
+        self
+    }
+
+    /// Overrides the negotiated compression of the responses, see [`CompressionMode`]. Handlers
+    /// can still turn it off for a call, with
+    /// [`RpcCompressionInfo`](crate::parts::RpcCompressionInfo) or
+    /// [`RpcResponse::no_compression`](crate::response::RpcResponse::no_compression).
+    pub fn compression(mut self, compression: CompressionMode) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Ends the request streams of client-streaming calls with `ResourceExhausted` at their
+    /// message after `max_stream_frames`, [`DEFAULT_MAX_STREAM_FRAMES`] by default.
+    /// [`max_request_bytes`](crate::config::RpcConfig::max_request_bytes) caps each message, this
+    /// caps how many a client can make the handler work through, eg. a million tiny ones.
+    pub fn max_stream_frames(mut self, max_stream_frames: usize) -> Self {
+        self.max_stream_frames = max_stream_frames;
+        self
+    }
+
+    /// Ends the request streams of client-streaming calls with `ResourceExhausted` once the client
+    /// has sent more than `max_stream_total_bytes` in all, envelope prefixes included,
+    /// [`DEFAULT_MAX_STREAM_TOTAL_BYTES`] by default. It also caps the bodies read upfront for a
+    /// [`verify_body`](RouteOptions::verify_body) check.
+    pub fn max_stream_total_bytes(mut self, max_stream_total_bytes: usize) -> Self {
+        self.max_stream_total_bytes = max_stream_total_bytes;
+        self
+    }
+}
+
+/// How the responses of a route are compressed, see [`RouteOptions::compression`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CompressionMode {
+    /// Whatever the client accepts and the server compresses, as negotiated: unary responses
+    /// under [`layers::recommended`](crate::layers::recommended), the messages of streams over
+    /// the threshold of [`stream_compression`](RouteOptions::stream_compression).
+    #[default]
+    Auto,
+    /// Never compressed, eg. for health checks and tiny responses, where compressing costs more
+    /// CPU than it saves, or for messages of already compressed blobs.
+    Never,
+    /// Compressed with this encoding (`gzip`) whatever their size, as if the handler asked for it
+    /// with [`RpcCompressionInfo`](crate::parts::RpcCompressionInfo). Calls of clients that don't
+    /// accept it, or that the server can't compress, are sent uncompressed instead of failing.
+    Force(&'static str),
+}
+
+impl fmt::Display for CompressionMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompressionMode::Auto => f.write_str("auto"),
+            CompressionMode::Never => f.write_str("never"),
+            CompressionMode::Force(encoding) => write!(f, "force:{}", encoding),
+        }
+    }
+}
+
+/// The predicate of [`RouteOptions::enabled`]. Gates are only equal to their own clones.
+#[derive(Clone)]
+pub struct RouteGate(Arc<dyn Fn(&request::Parts) -> bool + Send + Sync>);
+
+impl fmt::Debug for RouteGate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RouteGate")
+    }
+}
+
+impl PartialEq for RouteGate {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
 }
 
-pub type RpcRouter<S> = Router<S>;
+impl Eq for RouteGate {}
+
+/// Fails the call if its route is disabled by [`RouteOptions::enabled`].
+pub(crate) fn check_enabled(parts: &request::Parts) -> Result<(), RpcError> {
+    let Some(options) = parts.extensions.get::<RouteOptions>() else {
+        return Ok(());
+    };
+    match &options.enabled {
+        Some(RouteGate(predicate)) if !predicate(parts) => {
+            let path = parts
+                .extensions
+                .get::<RpcMethodInfo>()
+                .map(|info| info.path.as_str())
+                .unwrap_or_else(|| parts.uri.path());
+            Err(RpcError::new(
+                options.disabled_code.clone(),
+                format!("{} is not available", path),
+            ))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// How long a streaming RPC may go without receiving a request frame, see
+/// [`RpcRouter::stream_idle_timeout`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamIdleTimeout(pub Duration);
+
+type Registration<S> = Box<dyn FnOnce(RpcRouter<S>) -> RpcRouter<S>>;
+
+/// A set of services for an [`RpcRouter`], built by a library crate and mounted with
+/// [`RpcRouter::add_collection`]. Collections nest, so a crate can include another crate's.
+///
+/// ```
+/// # use axum_connect::prelude::*;
+/// # #[derive(Clone)]
+/// # struct Db;
+/// # fn register_users(r: RpcRouter<Db>) -> RpcRouter<Db> { r }
+/// # fn register_billing(r: RpcRouter<Db>) -> RpcRouter<Db> { r }
+/// pub fn users() -> RpcServiceCollection<Db> {
+///     RpcServiceCollection::new().service("users.Users", register_users)
+/// }
+///
+/// pub fn services() -> RpcServiceCollection<Db> {
+///     users().service("billing.Billing", register_billing)
+/// }
+/// ```
+pub struct RpcServiceCollection<S = ()> {
+    services: Vec<String>,
+    registrations: Vec<Registration<S>>,
+}
+
+impl<S> RpcServiceCollection<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            services: vec![],
+            registrations: vec![],
+        }
+    }
+
+    /// Adds the service `name` (eg. `hello.HelloWorldService`), whose RPCs `register` mounts like
+    /// [`rpc`](RpcRouterExt::rpc) does.
+    pub fn service<F>(mut self, name: &str, register: F) -> Self
+    where
+        F: FnOnce(RpcRouter<S>) -> RpcRouter<S> + 'static,
+    {
+        self.services.push(name.to_string());
+        self.registrations.push(Box::new(register));
+        self
+    }
+
+    /// Adds every service of `other`.
+    pub fn collection(mut self, other: RpcServiceCollection<S>) -> Self {
+        self.services.extend(other.services);
+        self.registrations.extend(other.registrations);
+        self
+    }
+
+    /// The names of the services in the collection, in the order they were added.
+    pub fn services(&self) -> &[String] {
+        &self.services
+    }
+}
+
+impl<S> Default for RpcServiceCollection<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> fmt::Debug for RpcServiceCollection<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcServiceCollection")
+            .field("services", &self.services)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An axum `Router` that also keeps track of which RPCs have been mounted on it.
+pub struct RpcRouter<S = ()> {
+    router: Router<S>,
+    methods: Vec<RpcMethodInfo>,
+    normalize_rpc_paths: bool,
+    /// By the path of each [`alias_service`](RpcRouter::alias_service) alias, the path it's
+    /// rewritten to and its info.
+    aliases: HashMap<String, (String, RpcMethodInfo)>,
+    /// Puts the path rewrite around the routes, set by `normalize_rpc_paths` and `alias_service`,
+    /// which only routers without state have, as the routes have to be a `Service` for it.
+    rewrite: Option<PutAround<S>>,
+    /// A router with nothing but the fallback, kept apart so `merge` can tell two fallbacks apart.
+    fallback: Option<Router<S>>,
+    /// The last config and idle timeout applied, for the summary `into_router` logs.
+    config: Option<Arc<RpcConfig>>,
+    stream_idle_timeout: Option<Duration>,
+    /// By path, what was applied to each RPC through the methods that work like `layer`.
+    settings: HashMap<String, RouteSettings>,
+    /// Applied to every route by `into_router`, so routes mounted after `authorize` aren't left
+    /// open.
+    authorizer: Option<Authorizer>,
+    /// The tables of the debug routes, filled in by `into_router`.
+    #[cfg(feature = "debug-routes")]
+    debug_routes: Vec<Arc<OnceLock<String>>>,
+    /// Whether `layers::recommended()` gzips the responses of every route, seen by `layer`.
+    gzip: bool,
+    /// The documents of the capabilities routes, filled in by `into_router`.
+    capabilities: Vec<Arc<OnceLock<String>>>,
+}
+
+/// What applies to a route, for the debug routes. The first of each applied to a route wins, the
+/// same way its extension is the one the handler sees.
+#[derive(Clone, Debug, Default)]
+struct RouteSettings {
+    options: Option<RouteOptions>,
+    config: Option<Arc<RpcConfig>>,
+    stream_idle_timeout: Option<Duration>,
+    authorized: bool,
+}
+
+impl<S> RpcRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self {
+            router: Router::new(),
+            methods: vec![],
+            normalize_rpc_paths: false,
+            aliases: HashMap::new(),
+            rewrite: None,
+            fallback: None,
+            config: None,
+            stream_idle_timeout: None,
+            settings: HashMap::new(),
+            authorizer: None,
+            #[cfg(feature = "debug-routes")]
+            debug_routes: vec![],
+            gzip: false,
+            capabilities: vec![],
+        }
+    }
+
+    /// Mounts an RPC described by `info`. Generated code calls this; the same path may be
+    /// registered more than once (eg. for POST and GET) and is only recorded once, with the HTTP
+    /// methods combined.
+    ///
+    /// Handlers can find `info` in the request extensions.
+    pub fn rpc_route(mut self, info: RpcMethodInfo, method_router: MethodRouter<S>) -> Self {
+        // Requests with an HTTP method the RPC isn't mounted for get a Connect error instead of an
+        // empty 405. Only the first registration of a path gets it, axum won't merge two fallbacks.
+        let method_router = if self.methods.iter().any(|m| m.path == info.path) {
+            method_router
+        } else {
+            method_router.fallback(method_not_allowed)
+        };
+
+        self.router = self
+            .router
+            .route(&info.path, route_layers(&info, method_router));
+        self.record(info);
+        self
+    }
+
+    /// Mounts an [`RpcMethod`], what generated registration functions do.
+    pub fn rpc_method(self, method: RpcMethod<S>) -> Self {
+        method.check_message_types();
+        self.rpc_route(method.info, method.method_router)
+    }
+
+    /// Mounts a single RPC's `Service`, eg. an [`RpcMethodService`] wrapped in tower middleware,
+    /// recording it like [`rpc_route`](Self::rpc_route) does.
+    pub fn rpc_service<T>(mut self, info: RpcMethodInfo, service: T) -> Self
+    where
+        T: Service<Request, Error = Infallible> + Clone + Send + Sync + 'static,
+        T::Response: IntoResponse,
+        T::Future: Send + 'static,
+    {
+        self.router = self.router.route_service(&info.path, service);
+        self.record(info);
+        self
+    }
+
+    /// Mounts `/axum.connect.Ping/Ping`, answered inside the crate with an empty message for load
+    /// balancers' health checks, see [`PingService`]. It isn't authorized.
+    pub fn add_ping_service(self) -> Self {
+        self.add_ping_service_with(PingService::new())
+    }
+
+    /// Like [`add_ping_service`](Self::add_ping_service), eg. with
+    /// [`PingService::authorized`].
+    pub fn add_ping_service_with(self, ping: PingService) -> Self {
+        self.rpc_service(PingService::info(), ping)
+    }
+
+    fn record(&mut self, info: RpcMethodInfo) {
+        match self.methods.iter_mut().find(|m| m.path == info.path) {
+            Some(existing) => {
+                for http_method in info.http_methods {
+                    if !existing.http_methods.contains(&http_method) {
+                        existing.http_methods.push(http_method);
+                    }
+                }
+            }
+            None => self.methods.push(info),
+        }
+    }
+
+    /// Records `apply` for every RPC mounted so far.
+    fn settle(&mut self, apply: impl Fn(&mut RouteSettings)) {
+        for info in &self.methods {
+            apply(self.settings.entry(info.path.clone()).or_default());
+        }
+    }
+
+    /// Like [`rpc`](RpcRouterExt::rpc), with `options` applied to every route `register` mounts.
+    pub fn rpc_with_options<F>(self, options: RouteOptions, register: F) -> Self
+    where
+        F: FnOnce(RpcRouter<S>) -> RpcRouter<S>,
+    {
+        let mut router = register(RpcRouter::new());
+        if let Some(limits) = ConcurrencyLimits::new(&options) {
+            router = router.layer(Extension(limits));
+        }
+        router.settle(|settings| {
+            settings.options.get_or_insert_with(|| options.clone());
+        });
+        self.merge(router.layer(Extension(options)))
+    }
+
+    /// Mounts every service of `collection`.
+    ///
+    /// # Panics
+    ///
+    /// If a service is in the collection twice, or already has RPCs mounted on this router. Two
+    /// crates both providing a service is a wiring mistake, better caught at startup than by
+    /// axum's overlapping route panic (or not at all, when they mount different methods).
+    pub fn add_collection(mut self, collection: RpcServiceCollection<S>) -> Self {
+        let mut mounted = self
+            .methods
+            .iter()
+            .map(|m| m.service.clone())
+            .collect::<BTreeSet<_>>();
+        let duplicates = collection
+            .services
+            .iter()
+            .filter(|service| !mounted.insert(service.to_string()))
+            .map(String::as_str)
+            .collect::<BTreeSet<_>>();
+        if !duplicates.is_empty() {
+            panic!(
+                "Services mounted more than once: {}",
+                duplicates.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+
+        for register in collection.registrations {
+            self = register(self);
+        }
+        self
+    }
+
+    /// Mounts a hand-written RPC route, recording what can be inferred from the path alone.
+    pub fn route(self, path: &str, method_router: MethodRouter<S>) -> Self {
+        self.rpc_route(RpcMethodInfo::from_path(path), method_router)
+    }
+
+    /// Mounts a unary RPC whose schema is only known at runtime, for both POST and GET. The
+    /// handler works on encoded messages, see [`RpcHandlerDynamic`].
+    pub fn rpc_dynamic<H>(self, path: &str, handler: H) -> Self
+    where
+        H: RpcHandlerDynamic,
+    {
+        let mut info =
+            RpcMethodInfo::from_path(path).with_http_methods(vec![Method::POST, Method::GET]);
+        info.kind = Some(RpcMethodKind::Unary);
+
+        let method_router = axum::routing::post({
+            let handler = handler.clone();
+            |request: Request| async move { handler.call(request).await }
+        })
+        .get(|request: Request| async move { handler.call(request).await });
+
+        self.rpc_route(info, method_router)
+    }
+
+    /// Mounts a route that isn't an RPC, eg. a REST alias (from a `google.api.http` annotation) of
+    /// one, or a health check. It isn't listed in `paths()`.
+    pub fn rest_route(mut self, path: &str, method_router: MethodRouter<S>) -> Self {
+        self.router = self.router.route(path, method_router);
+        self
+    }
+
+    /// Every RPC mounted so far, in registration order.
+    pub fn paths(&self) -> Vec<RpcMethodInfo> {
+        self.methods.clone()
+    }
+
+    /// Checks the mounted RPCs against encoded `FileDescriptorSet`s, eg. the `FILE_DESCRIPTOR_SET`
+    /// constants codegen embeds with `field_masks`, so a renamed or forgotten method is caught at
+    /// startup. Reports every proto method that isn't mounted and every RPC mounted for a method
+    /// the descriptors don't have; whether to warn or abort is up to the caller.
+    ///
+    /// Client-streaming methods are skipped, codegen doesn't mount them. Services of imported
+    /// files count too, filter the mismatches if they're served elsewhere.
+    pub fn validate_against(&self, descriptor_sets: &[&[u8]]) -> Result<(), Vec<RouteMismatch>> {
+        let mut mismatches = vec![];
+        let mut defined = BTreeSet::new();
+        for (index, bytes) in descriptor_sets.iter().enumerate() {
+            let set = match FileDescriptorSet::decode(*bytes) {
+                Ok(set) => set,
+                Err(error) => {
+                    mismatches.push(RouteMismatch::InvalidDescriptorSet {
+                        index,
+                        error: error.to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            for file in set.file {
+                let package = file.package.unwrap_or_default();
+                for service in file.service {
+                    let service_name = match package.as_str() {
+                        "" => service.name().to_string(),
+                        package => format!("{}.{}", package, service.name()),
+                    };
+                    for method in service.method {
+                        if !method.client_streaming() {
+                            defined.insert(format!("/{}/{}", service_name, method.name()));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mounted = self
+            .methods
+            .iter()
+            .map(|m| m.path.as_str())
+            .collect::<BTreeSet<_>>();
+        mismatches.extend(
+            defined
+                .iter()
+                .filter(|path| !mounted.contains(path.as_str()))
+                .map(|path| RouteMismatch::NotMounted { path: path.clone() }),
+        );
+        mismatches.extend(
+            mounted
+                .into_iter()
+                .filter(|path| !defined.contains(*path))
+                .map(|path| RouteMismatch::UnknownMethod {
+                    path: path.to_string(),
+                }),
+        );
+
+        match mismatches.is_empty() {
+            true => Ok(()),
+            false => Err(mismatches),
+        }
+    }
+
+    /// A versioned JSON manifest of every mounted RPC, eg. to serve on an admin endpoint for an API
+    /// gateway. Same schema as the `routes_manifest` emitted by `axum-connect-build`; routes added
+    /// by hand have a `null` kind. `auth_required` is whether [`authorize`](RpcRouter::authorize)
+    /// covers the RPC and it isn't mounted with [`RouteOptions::public`].
+    pub fn manifest_json(&self) -> String {
+        let mut methods = self.methods.iter().collect::<Vec<_>>();
+        methods.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let routes = methods
+            .into_iter()
+            .map(|m| {
+                let settings = self.settings.get(&m.path).cloned().unwrap_or_default();
+                let authorized = settings.authorized || self.authorizer.is_some();
+                let public = settings.options.is_some_and(|options| options.public);
+
+                json!({
+                    "path": m.path,
+                    "service": m.service,
+                    "method": m.method,
+                    "kind": m.kind.map(|k| k.to_string()),
+                    "idempotent": m.idempotent(),
+                    "idempotency_level": m.idempotency_level.to_string(),
+                    "http_methods": m.http_methods.iter().map(Method::as_str).collect::<Vec<_>>(),
+                    "auth_required": authorized && !public,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let manifest = json!({ "version": 1, "routes": routes });
+        serde_json::to_string_pretty(&manifest).unwrap() + "\n"
+    }
+
+    /// Serves [`debug_routes_json`](RpcRouter::debug_routes_json) as JSON at `path`, to requests
+    /// `allow` returns true for; everyone else gets a 404, as if the route didn't exist. The table
+    /// is built by [`into_router`](RpcRouter::into_router), so it lists RPCs mounted after this too.
+    /// For only local requests, pass [`debug_routes::localhost`](crate::debug_routes::localhost)
+    /// and serve with `into_make_service_with_connect_info`.
+    ///
+    /// It's never mounted unless this is called. Layers added afterwards apply to it like to any
+    /// other route, but the RPC ones don't: it isn't authorized, and has no options or config.
+    #[cfg(feature = "debug-routes")]
+    pub fn mount_debug_routes(
+        mut self,
+        path: &str,
+        allow: impl Fn(&request::Parts) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        let table = Arc::new(OnceLock::new());
+        self.debug_routes.push(table.clone());
+        self.router = self
+            .router
+            .route(path, crate::debug_routes::method_router(table, allow));
+        self
+    }
+
+    /// Records the RPCs mounted so far in `metrics`, like `layer`, and serves them in the
+    /// Prometheus text format over GET at `path`. Call it after mounting the services. Paths that
+    /// aren't one of those RPCs, eg. those the fallback answers, are labeled
+    /// [`metrics::UNKNOWN`](crate::metrics::UNKNOWN).
+    ///
+    /// Like [`mount_debug_routes`](RpcRouter::mount_debug_routes), the route isn't an RPC: it
+    /// isn't authorized and isn't recorded itself. Restrict it with a layer if it mustn't be
+    /// public.
+    #[cfg(feature = "prometheus")]
+    pub fn mount_metrics(self, path: &str, metrics: crate::metrics::PrometheusMetrics) -> Self {
+        let recorder = metrics.recorder(&self.methods);
+        let mut router = self.layer(crate::logging::RpcLogLayer::new().on_complete(recorder));
+        router.router = router.router.route(path, metrics.method_router());
+        router
+    }
+
+    /// Every mounted RPC with what applies to it, the document served by
+    /// [`mount_debug_routes`](RpcRouter::mount_debug_routes). Besides the
+    /// [manifest](RpcRouter::manifest_json) fields, each route has:
+    ///
+    /// - `authorization`, `"required"` if an [`authorize`](RpcRouter::authorize) authorizer runs
+    ///   for it, `"public"` if one would but the route is [`RouteOptions::public`], or `"none"`.
+    /// - `options`, its [`RouteOptions`] (the defaults if it has none). The predicate, body
+    ///   verifier and request defaults are shown as `gated`, `verify_body` and the message type
+    ///   names, a [`deprecation`](RouteOptions::deprecated) as its message and sunset.
+    /// - `config`, the settings of its [`RpcConfig`] that change how calls are handled, with
+    ///   `stream_idle_timeout_ms` from [`stream_idle_timeout`](RpcRouter::stream_idle_timeout) if
+    ///   set.
+    /// - `features`, `get` if it's served over GET, `compression`, `"gzip"` if it has
+    ///   [`stream_compression`](RouteOptions::stream_compression) and `"none"` otherwise (unary
+    ///   compression is up to middleware), `stream_compression_threshold`, and
+    ///   `sse_heartbeat_ms`, the heartbeat interval if it serves Server-Sent Events.
+    #[cfg(feature = "debug-routes")]
+    pub fn debug_routes_json(&self) -> String {
+        let mut methods = self.methods.iter().collect::<Vec<_>>();
+        methods.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let routes = methods
+            .into_iter()
+            .map(|m| {
+                let settings = self.settings.get(&m.path).cloned().unwrap_or_default();
+                let options = settings.options.unwrap_or_default();
+                let config = settings.config.unwrap_or_default();
+                let stream_idle_timeout =
+                    settings.stream_idle_timeout.or(config.stream_idle_timeout);
+                let authorized = settings.authorized || self.authorizer.is_some();
+                let authorization = match (authorized, options.public) {
+                    (false, _) => "none",
+                    (true, true) => "public",
+                    (true, false) => "required",
+                };
+                #[cfg(feature = "sse")]
+                let sse = options.sse.map(|heartbeat| heartbeat.as_millis() as u64);
+                #[cfg(not(feature = "sse"))]
+                let sse = None::<u64>;
+                #[cfg(feature = "stream-compression")]
+                let stream_compression = options.stream_compression;
+                #[cfg(not(feature = "stream-compression"))]
+                let stream_compression = None::<usize>;
+
+                json!({
+                    "path": m.path,
+                    "service": m.service,
+                    "method": m.method,
+                    "kind": m.kind.map(|k| k.to_string()),
+                    "idempotent": m.idempotent(),
+                    "idempotency_level": m.idempotency_level.to_string(),
+                    "http_methods": m.http_methods.iter().map(Method::as_str).collect::<Vec<_>>(),
+                    "authorization": authorization,
+                    "options": {
+                        "public": options.public,
+                        "concurrency_limit": options.concurrency_limit,
+                        "concurrency_queue": options.concurrency_queue,
+                        "gated": options.enabled.is_some(),
+                        "disabled_code": options.disabled_code,
+                        "verify_body": options.body_verifier.is_some(),
+                        "audited": options.audited,
+                        "preallocate_body": options.preallocate_body,
+                        "circuit_breaker": options.circuit_breaker.is_some(),
+                        "compression": options.compression.to_string(),
+                        "deprecation": options.deprecation.as_ref().map(|deprecation| json!({
+                            "message": deprecation.message(),
+                            "sunset": deprecation::http_date(deprecation.sunset()),
+                        })),
+                        "request_defaults": options
+                            .request_defaults
+                            .iter()
+                            .map(RequestDefaults::type_name)
+                            .collect::<Vec<_>>(),
+                    },
+                    "config": {
+                        "max_request_bytes": config.max_request_bytes,
+                        "max_get_url_bytes": config.max_get_url_bytes,
+                        "stream_idle_timeout_ms": stream_idle_timeout.map(|t| t.as_millis() as u64),
+                        "require_protocol_version": config.require_protocol_version,
+                        "strict_timeouts": config.strict_timeouts,
+                        "redact_internal_errors": config.redact_internal_errors,
+                        "max_metadata_bytes": config.max_metadata_bytes,
+                        "max_metadata_value_bytes": config.max_metadata_value_bytes,
+                        "max_calls_per_connection": config.max_calls_per_connection,
+                        "strict_reserved_headers": config.strict_reserved_headers,
+                        "adapt_streaming_unary": config.adapt_streaming_unary,
+                        "empty_response_body": config.empty_response_body,
+                        "require_host": config.require_host,
+                        "stable_json_field_order": config.stable_json_field_order,
+                    },
+                    "features": {
+                        "get": m.http_methods.contains(&Method::GET),
+                        "compression": match stream_compression {
+                            Some(_) => "gzip",
+                            None => "none",
+                        },
+                        "stream_compression_threshold": stream_compression,
+                        "sse_heartbeat_ms": sse,
+                    },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_string_pretty(&json!({ "routes": routes })).unwrap() + "\n"
+    }
+
+    /// Serves [`capabilities_json`](RpcRouter::capabilities_json) as JSON over GET at `path`, eg.
+    /// `/.well-known/connect-capabilities`, for client teams to check what the server supports.
+    /// The document is built by [`into_router`](RpcRouter::into_router), so it covers RPCs mounted
+    /// after this too.
+    ///
+    /// It's never mounted unless this is called. Like
+    /// [`mount_debug_routes`](RpcRouter::mount_debug_routes), the route isn't an RPC: it isn't
+    /// authorized and isn't recorded itself.
+    pub fn mount_capabilities(mut self, path: &str) -> Self {
+        let document = Arc::new(OnceLock::<String>::new());
+        self.capabilities.push(document.clone());
+        self.router = self.router.route(
+            path,
+            routing::get(move || async move {
+                match document.get() {
+                    Some(document) => (
+                        [(header::CONTENT_TYPE, "application/json")],
+                        document.clone(),
+                    )
+                        .into_response(),
+                    None => StatusCode::NOT_FOUND.into_response(),
+                }
+            }),
+        );
+        self
+    }
+
+    /// What the server supports, the document served by
+    /// [`mount_capabilities`](RpcRouter::mount_capabilities). It's derived from the router as
+    /// built, like the summary [`into_router`](RpcRouter::into_router) logs: the limits and
+    /// protocol version strictness are those of the last [`with_config`](RpcRouter::with_config)
+    /// (routes with a config of their own may differ, see the debug routes).
+    ///
+    /// - `protocols`, always `["connect"]`: gRPC and gRPC-Web requests are rejected.
+    /// - `codecs`, `proto` and, with the `json` feature, `json`.
+    /// - `compression`, by `requests`, `unary_responses` and `stream_responses`, `gzip` for the
+    ///   latter if any route has [`RouteOptions::stream_compression`]. Only the gzip of
+    ///   [`layers::recommended`](crate::layers::recommended) applied with
+    ///   [`layer`](RpcRouter::layer) is seen; compression layers of your own aren't, nor are
+    ///   layers applied to the `Router` after `into_router`.
+    /// - `get`, whether any RPC is served over GET, how many, and the longest URL taken.
+    /// - `method_kinds`, the kinds of the mounted RPCs.
+    /// - `protocol_version`, `1`, and `require_protocol_version`.
+    /// - `limits`, `max_request_bytes`, `max_get_url_bytes`, `max_metadata_bytes` and
+    ///   `stream_idle_timeout_ms`, `null` if unlimited.
+    pub fn capabilities_json(&self) -> String {
+        let config = self.config.as_deref().cloned().unwrap_or_default();
+        let stream_idle_timeout = self.stream_idle_timeout.or(config.stream_idle_timeout);
+        let get_methods = self
+            .methods
+            .iter()
+            .filter(|m| m.http_methods.contains(&Method::GET))
+            .count();
+        let method_kinds = self
+            .methods
+            .iter()
+            .filter_map(|m| m.kind)
+            .collect::<BTreeSet<_>>();
+        let mut codecs = vec!["proto"];
+        if cfg!(feature = "json") {
+            codecs.push("json");
+        }
+        let (unary_compression, stream_compression) = self.response_compression();
+
+        let document = json!({
+            "protocols": ["connect"],
+            "protocol_version": 1,
+            "require_protocol_version": config.require_protocol_version,
+            "codecs": codecs,
+            // Compressed request frames are rejected, streams are compressed by the routes with
+            // `RouteOptions::stream_compression`.
+            "compression": {
+                "requests": [],
+                "unary_responses": unary_compression.as_slice(),
+                "stream_responses": stream_compression.as_slice(),
+            },
+            "get": {
+                "enabled": get_methods > 0,
+                "methods": get_methods,
+                "max_url_bytes": config.max_get_url_bytes,
+            },
+            "method_kinds": method_kinds.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            "limits": {
+                "max_request_bytes": config.max_request_bytes,
+                "max_get_url_bytes": config.max_get_url_bytes,
+                "max_metadata_bytes": config.max_metadata_bytes,
+                "stream_idle_timeout_ms": stream_idle_timeout.map(|t| t.as_millis() as u64),
+            },
+        });
+        serde_json::to_string_pretty(&document).unwrap() + "\n"
+    }
+
+    /// Sends requests no route matched to `service`, eg. a `tower_http::services::ServeDir` for a
+    /// single-page app served alongside the API. With
+    /// [`normalize_rpc_paths`](RpcRouter::normalize_rpc_paths) it only gets the requests that
+    /// don't normalize to an RPC path.
+    pub fn fallback_service<T>(mut self, service: T) -> Self
+    where
+        T: Service<Request, Error = Infallible> + Clone + Send + Sync + 'static,
+        T::Response: IntoResponse,
+        T::Future: Send + 'static,
+    {
+        self.fallback = Some(Router::new().fallback_service(service));
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If both routers have a fallback service.
+    pub fn merge(mut self, mut other: RpcRouter<S>) -> Self {
+        // Its authorizer covers its own RPCs, this router's covers the rest.
+        if let Some(authorizer) = other.authorizer.take() {
+            other.settle(|settings| settings.authorized = true);
+            other = other.layer(Extension(authorizer));
+        }
+        self.router = self.router.merge(other.router);
+        self.fallback = match (self.fallback, other.fallback) {
+            (Some(_), Some(_)) => panic!("Cannot merge two `RpcRouter`s that both have a fallback"),
+            (fallback, None) | (None, fallback) => fallback,
+        };
+        self.aliases.extend(other.aliases);
+        self.rewrite = self.rewrite.or(other.rewrite);
+        self.config = self.config.or(other.config);
+        self.stream_idle_timeout = self.stream_idle_timeout.or(other.stream_idle_timeout);
+        for (path, settings) in other.settings {
+            self.settings.entry(path).or_insert(settings);
+        }
+        #[cfg(feature = "debug-routes")]
+        self.debug_routes.extend(other.debug_routes);
+        // Gzipped if every route is, routers without any don't count.
+        self.gzip = match (self.methods.is_empty(), other.methods.is_empty()) {
+            (true, _) => other.gzip,
+            (false, true) => self.gzip,
+            (false, false) => self.gzip && other.gzip,
+        };
+        self.capabilities.extend(other.capabilities);
+        for info in other.methods {
+            self.record(info);
+        }
+        self
+    }
+
+    /// Mounts the routes of `other` under `prefix`, eg. `/canary`, for a second copy of services
+    /// that are also mounted at their own paths. Its RPCs are recorded at their new paths, with
+    /// the service names to match (eg. `canary/hello.HelloWorldService`), and that's the
+    /// [`RpcMethodInfo`] their handlers, logs and metrics get too. Layers applied to `other` see
+    /// the path without the prefix.
+    ///
+    /// # Panics
+    ///
+    /// If `prefix` isn't a path other than `/`, `other` has a fallback service, or an RPC is
+    /// already mounted at one of the new paths.
+    pub fn nest(self, prefix: &str, other: RpcRouter<S>) -> Self {
+        if other.fallback.is_some() {
+            panic!("Cannot nest an `RpcRouter` that has a fallback");
+        }
+
+        let nested = other
+            .methods
+            .iter()
+            .map(|info| {
+                (
+                    info.path.clone(),
+                    info.rebased(&format!("{}{}", prefix, info.path)),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        self.check_unmounted(nested.values());
+
+        let infos = Arc::new(nested);
+        // Unless the call is to an alias, which already has its own.
+        let router = other.router.layer(middleware::from_fn(
+            move |mut request: Request, next: Next| {
+                if let Some(info) = infos.get(request.uri().path()) {
+                    if request.extensions().get::<RpcMethodInfo>().is_none() {
+                        request.extensions_mut().insert(info.clone());
+                    }
+                }
+                next.run(request)
+            },
+        ));
+        let methods = other
+            .methods
+            .iter()
+            .map(|info| info.rebased(&format!("{}{}", prefix, info.path)))
+            .collect();
+        let settings = other
+            .settings
+            .into_iter()
+            .map(|(path, settings)| (format!("{}{}", prefix, path), settings))
+            .collect();
+        let aliases = other
+            .aliases
+            .into_iter()
+            .map(|(alias, (original, info))| {
+                let alias = format!("{}{}", prefix, alias);
+                let info = info.rebased(&alias);
+                (alias, (format!("{}{}", prefix, original), info))
+            })
+            .collect();
+        self.merge(RpcRouter {
+            router: Router::new().nest(prefix, router),
+            methods,
+            settings,
+            aliases,
+            ..other
+        })
+    }
+
+    /// Panics if an RPC is mounted at the path of one of `infos` already.
+    fn check_unmounted<'a>(&self, infos: impl Iterator<Item = &'a RpcMethodInfo>) {
+        let mounted = infos
+            .filter(|info| self.methods.iter().any(|m| m.path == info.path))
+            .map(|info| info.path.as_str())
+            .collect::<BTreeSet<_>>();
+        if !mounted.is_empty() {
+            panic!(
+                "RPCs mounted more than once: {}",
+                mounted.into_iter().collect::<Vec<_>>().join(", ")
+            );
+        }
+    }
+
+    pub fn layer<L>(mut self, layer: L) -> Self
+    where
+        L: Layer<Route> + Clone + Send + Sync + 'static,
+        L::Service: Service<Request> + Clone + Send + Sync + 'static,
+        <L::Service as Service<Request>>::Response: IntoResponse + 'static,
+        <L::Service as Service<Request>>::Error: Into<Infallible> + 'static,
+        <L::Service as Service<Request>>::Future: Send + 'static,
+    {
+        if let Some(recommended) = (&layer as &dyn Any).downcast_ref::<RecommendedLayers>() {
+            self.gzip |= recommended.compresses();
+        }
+        self.fallback = self.fallback.map(|fallback| fallback.layer(layer.clone()));
+        self.router = self.router.layer(layer);
+        self
+    }
+
+    /// Applies `config` to every route mounted so far, like `layer`.
+    pub fn with_config(mut self, config: RpcConfig) -> Self {
+        if let Some(name) = config.reserved_default_header() {
+            if !config.strict_reserved_headers {
+                tracing::warn!(
+                    target: "axum_connect::headers",
+                    header = %name,
+                    "the reserved header `{}` in default_response_headers is never sent",
+                    name
+                );
+            }
+        }
+        let limits_connections = config.max_calls_per_connection.is_some();
+        let config = Arc::new(config);
+        self.config = Some(config.clone());
+        self.settle(|settings| {
+            settings.config.get_or_insert_with(|| config.clone());
+        });
+        let router = self.layer(Extension(config));
+        match limits_connections {
+            true => router.layer(Extension(ConnectionCalls::default())),
+            false => router,
+        }
+    }
+
+    /// Runs `authorizer` before the extractors of every RPC of the router, except those mounted as
+    /// [`RouteOptions::public`]. Unlike `layer`, that's RPCs mounted after this too; it's applied
+    /// by [`into_router`](RpcRouter::into_router). Calling it again replaces the authorizer, and
+    /// routers [merged](RpcRouter::merge) in with one of their own keep theirs for their RPCs.
+    pub fn authorize(mut self, authorizer: impl RpcAuthorize) -> Self {
+        self.authorizer = Some(Authorizer::new(authorizer));
+        self
+    }
+
+    /// Runs `hooks` on the response messages of every route mounted so far, like `layer`.
+    pub fn response_hooks(self, hooks: ResponseHooks) -> Self {
+        self.layer(Extension(hooks))
+    }
+
+    /// Counts the calls of every route mounted so far in `drain`, like `layer`, and drains them
+    /// once it [starts](DrainController::start_drain), the ping service included.
+    pub fn drain(self, drain: DrainController) -> Self {
+        self.layer(Extension(drain))
+    }
+
+    /// Records the calls of every [audited](RouteOptions::audited) route mounted so far with
+    /// `auditor`, like `layer`.
+    pub fn audit(self, auditor: RpcAuditor) -> Self {
+        self.layer(Extension(auditor))
+    }
+
+    /// Captures example calls of every route mounted so far in `capture`, like `layer`.
+    #[cfg(feature = "capture")]
+    pub fn capture(self, capture: crate::capture::RpcCapture) -> Self {
+        self.layer(Extension(capture))
+    }
+
+    /// Signs the responses of every route mounted so far with `signing`'s signer, like `layer`.
+    pub fn sign_responses(self, signing: ResponseSigning) -> Self {
+        self.layer(Extension(signing))
+    }
+
+    /// Publishes the lifecycle events of every route mounted so far to `bus`, like `layer`.
+    pub fn event_bus(self, bus: RpcEventBus) -> Self {
+        self.layer(Extension(bus))
+    }
+
+    /// Fails streaming RPCs with `DeadlineExceeded` if no request frame arrives within `timeout`,
+    /// the window restarts with every frame. Applies to every route mounted so far, like `layer`.
+    ///
+    /// Calling this on a router before merging it into another overrides the outer router's
+    /// timeout for those routes.
+    pub fn stream_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.stream_idle_timeout = Some(timeout);
+        self.settle(|settings| {
+            settings.stream_idle_timeout.get_or_insert(timeout);
+        });
+        self.layer(Extension(StreamIdleTimeout(timeout)))
+    }
+
+    /// Gives the router its state. With [`normalize_rpc_paths`](RpcRouter::normalize_rpc_paths)
+    /// or [`alias_service`](RpcRouter::alias_service), the path rewrite goes around the routes
+    /// and the fallback here, so RPCs mounted after aren't normalized, and the router can't get
+    /// another fallback.
+    pub fn with_state<S2>(mut self, state: S) -> RpcRouter<S2> {
+        let (router, fallback) = match self.rewrites_paths() {
+            true => (self.routes().with_state(state), None),
+            false => (
+                self.router.with_state(state.clone()),
+                self.fallback.map(|fallback| fallback.with_state(state)),
+            ),
+        };
+        RpcRouter {
+            fallback,
+            router,
+            methods: self.methods,
+            normalize_rpc_paths: self.normalize_rpc_paths,
+            aliases: self.aliases,
+            rewrite: None,
+            config: self.config,
+            stream_idle_timeout: self.stream_idle_timeout,
+            settings: self.settings,
+            authorizer: self.authorizer,
+            #[cfg(feature = "debug-routes")]
+            debug_routes: self.debug_routes,
+            gzip: self.gzip,
+            capabilities: self.capabilities,
+        }
+    }
+
+    /// Logs a summary of the router at `info` level, with the target `axum_connect::router`.
+    /// The fields are `services`, `methods`, `get_methods` and `streaming_methods` (counts),
+    /// `compression` and `stream_compression` (the codec of unary and streaming responses,
+    /// `"gzip"` or `"none"`), `grpc` (`false`), `normalize_rpc_paths`,
+    /// `max_request_bytes`, `max_get_url_bytes` and `stream_idle_timeout_ms` (of the last
+    /// [`with_config`](RpcRouter::with_config) and
+    /// [`stream_idle_timeout`](RpcRouter::stream_idle_timeout), unset if unlimited).
+    pub fn into_router(mut self) -> Router<S> {
+        if let Some(authorizer) = self.authorizer.take() {
+            self.settle(|settings| settings.authorized = true);
+            self = self.layer(Extension(authorizer));
+        }
+        self.log_summary();
+        #[cfg(feature = "debug-routes")]
+        if !self.debug_routes.is_empty() {
+            let table = self.debug_routes_json();
+            for debug_routes in &self.debug_routes {
+                let _ = debug_routes.set(table.clone());
+            }
+        }
+        if !self.capabilities.is_empty() {
+            let document = self.capabilities_json();
+            for capabilities in &self.capabilities {
+                let _ = capabilities.set(document.clone());
+            }
+        }
+
+        self.routes()
+    }
+
+    fn rewrites_paths(&self) -> bool {
+        self.rewrite.is_some() && (self.normalize_rpc_paths || !self.aliases.is_empty())
+    }
+
+    /// The routes and the fallback, with the path rewrite around them if there's one.
+    fn routes(&mut self) -> Router<S> {
+        let router = std::mem::take(&mut self.router);
+        let router = match self.fallback.take() {
+            Some(fallback) => router.merge(fallback),
+            None => router,
+        };
+        match self.rewrite.take() {
+            Some(around) if self.normalize_rpc_paths || !self.aliases.is_empty() => around(
+                PathRewrite {
+                    normalize: self.normalize_rpc_paths,
+                    paths: Arc::new(self.methods.iter().map(|m| m.path.clone()).collect()),
+                    aliases: Arc::new(std::mem::take(&mut self.aliases)),
+                },
+                router,
+            ),
+            _ => router,
+        }
+    }
+}
+
+/// Rewrites the paths of requests before they're routed: with
+/// [`RpcRouter::normalize_rpc_paths`], those that normalize to the path of an RPC, and the paths
+/// of [`RpcRouter::alias_service`] aliases to the originals, putting in the alias's info.
+/// Handlers still see the path as sent in `OriginalUri`.
+#[derive(Clone)]
+struct PathRewrite {
+    normalize: bool,
+    paths: Arc<BTreeSet<String>>,
+    aliases: Arc<HashMap<String, (String, RpcMethodInfo)>>,
+}
+
+type PutAround<S> = fn(PathRewrite, Router<S>) -> Router<S>;
+
+impl PathRewrite {
+    /// Rewrites paths before routing, around the whole router, which takes a router without
+    /// state to be a `Service`.
+    fn around(self, router: Router) -> Router {
+        Router::new()
+            .fallback_service(router.map_request(move |request: Request| self.rewrite(request)))
+    }
+
+    fn rewrite(&self, mut request: Request) -> Request {
+        let path = request.uri().path();
+        let normalized = normalize_rpc_path(path)
+            .filter(|_| self.normalize)
+            .filter(|normalized| normalized != path && self.paths.contains(normalized));
+        let path = normalized.as_deref().unwrap_or(path);
+        let path = match self.aliases.get(path) {
+            Some((original, info)) => {
+                let original = original.clone();
+                request.extensions_mut().insert(info.clone());
+                original
+            }
+            None => match normalized {
+                Some(normalized) => normalized,
+                None => return request,
+            },
+        };
+
+        let path_and_query = match request.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        };
+        let mut parts = request.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = Uri::from_parts(parts) {
+            *request.uri_mut() = uri;
+        }
+        request
+    }
+}
+
+impl<S> RpcRouter<S> {
+    /// The codec unary and streaming responses are compressed with, if any: gzip by
+    /// `layers::recommended()` on every route, and by the routes with
+    /// [`RouteOptions::stream_compression`] for streams.
+    fn response_compression(&self) -> (Option<&'static str>, Option<&'static str>) {
+        let unary = self.gzip.then_some("gzip");
+        #[cfg(feature = "stream-compression")]
+        let stream = self.settings.values().any(|settings| {
+            settings
+                .options
+                .as_ref()
+                .is_some_and(|options| options.stream_compression.is_some())
+        });
+        #[cfg(not(feature = "stream-compression"))]
+        let stream = false;
+        (unary, stream.then_some("gzip"))
+    }
+
+    fn log_summary(&self) {
+        let services = self
+            .methods
+            .iter()
+            .map(|m| m.service.as_str())
+            .collect::<BTreeSet<_>>();
+        let get_methods = self
+            .methods
+            .iter()
+            .filter(|m| m.http_methods.contains(&Method::GET))
+            .count();
+        let streaming_methods = self
+            .methods
+            .iter()
+            .filter(|m| m.kind.is_some_and(|kind| kind != RpcMethodKind::Unary))
+            .count();
+        let stream_idle_timeout = self
+            .stream_idle_timeout
+            .or(self.config.as_ref().and_then(|c| c.stream_idle_timeout));
+        let (compression, stream_compression) = self.response_compression();
+
+        tracing::info!(
+            target: "axum_connect::router",
+            services = services.len(),
+            methods = self.methods.len(),
+            get_methods,
+            streaming_methods,
+            compression = compression.unwrap_or("none"),
+            stream_compression = stream_compression.unwrap_or("none"),
+            grpc = false,
+            normalize_rpc_paths = self.normalize_rpc_paths,
+            max_request_bytes = self.config.as_ref().and_then(|c| c.max_request_bytes),
+            max_get_url_bytes = self
+                .config
+                .as_ref()
+                .map_or(Some(DEFAULT_MAX_GET_URL_BYTES), |c| c.max_get_url_bytes),
+            stream_idle_timeout_ms = stream_idle_timeout.map(|t| t.as_millis() as u64),
+            "rpc router built"
+        );
+    }
+}
+
+/// Strips a single trailing slash and collapses duplicate slashes. `None` if the path has
+/// percent-encoded characters, those are never valid in an RPC path.
+fn normalize_rpc_path(path: &str) -> Option<String> {
+    if path.contains('%') {
+        return None;
+    }
+
+    let path = path.strip_suffix('/').unwrap_or(path);
+    let mut normalized = String::with_capacity(path.len());
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    Some(normalized)
+}
+
+/// The layers of every RPC route. The stats layer wraps everything but the info, so its latency
+/// covers the whole route. Server-Sent Events come next, transcoding the stream as signed. Then
+/// signing, to sign the body as sent. Deprecation and the circuit breaker are innermost, so calls
+/// they fail get the default headers, and calls failed after the sunset don't open the circuit.
+fn route_layers<S>(info: &RpcMethodInfo, method_router: MethodRouter<S>) -> MethodRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let method_router = method_router.layer((
+        middleware::from_fn(signing::sign_responses),
+        middleware::from_fn(default_response_headers),
+        middleware::from_fn(deprecation::deprecation),
+        middleware::from_fn(breaker::circuit_breaker),
+    ));
+    #[cfg(feature = "sse")]
+    let method_router = method_router.layer(middleware::from_fn(crate::sse::server_sent_events));
+    method_router.layer((
+        middleware::from_fn_with_state(info.clone(), method_info),
+        middleware::from_fn(trace::trace),
+        middleware::from_fn(drain::track),
+        RpcCallStatsLayer,
+        middleware::from_fn(check_transport),
+    ))
+}
+
+/// Puts the route's `info` in the request extensions, unless a [`RpcRouter::nest`] or an
+/// [`RpcRouter::alias_service`] of it already put in its own.
+async fn method_info(
+    State(info): State<RpcMethodInfo>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    if request.extensions().get::<RpcMethodInfo>().is_none() {
+        request.extensions_mut().insert(info);
+    }
+    next.run(request).await
+}
+
+/// Fails RPC calls over HTTP/1.0, and over HTTP/1.1 without a `Host` header with
+/// [`RpcConfig::require_host`], with `InvalidArgument`. They're old health checkers and scanners
+/// rather than Connect clients, so their [`RpcLog::invalid_transport`] is set, eg. to leave them
+/// out of error rates.
+///
+/// [`RpcLog::invalid_transport`]: crate::logging::RpcLog::invalid_transport
+async fn check_transport(request: Request, next: Next) -> Response {
+    let message = match request.version() {
+        Version::HTTP_09 | Version::HTTP_10 => {
+            "HTTP/1.0 is not supported, Connect takes HTTP/1.1 or HTTP/2"
+        }
+        Version::HTTP_11
+            if !request.headers().contains_key(header::HOST)
+                && request
+                    .extensions()
+                    .get::<Arc<RpcConfig>>()
+                    .is_some_and(|config| config.require_host) =>
+        {
+            "HTTP/1.1 requests need a Host header"
+        }
+        _ => return next.run(request).await,
+    };
+
+    let content_type = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+        .filter(|_| request.method() != Method::GET);
+    let streaming = content_type.is_some_and(|content_type| content_type.is_streaming());
+    let binary = content_type.is_some_and(|content_type| content_type.is_binary());
+    let error = RpcError::new(RpcErrorCode::InvalidArgument, message.to_string());
+    let response = ResponseEncoder::error(error, streaming, binary).encode_response();
+    if let Some(stats) = response.extensions().get::<Arc<RpcCallStats>>() {
+        stats.set_invalid_transport();
+    }
+    response
+}
+
+/// Applies [`RpcConfig::default_response_headers`] to every response of an RPC route.
+async fn default_response_headers(request: Request, next: Next) -> Response {
+    let config = request.extensions().get::<Arc<RpcConfig>>().cloned();
+    let mut response = next.run(request).await;
+    let Some(config) = config else {
+        return response;
+    };
+
+    if let Err(error) = config.apply_default_response_headers(response.headers_mut()) {
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| ContentType::parse(content_type.as_bytes()));
+        let streaming = content_type.is_some_and(|content_type| content_type.is_streaming());
+        let binary = content_type.is_some_and(|content_type| content_type.is_binary());
+        return ResponseEncoder::error(error, streaming, binary).encode_response();
+    }
+    response
+}
+
+async fn method_not_allowed(method: Method) -> Response {
+    let message = if method == Method::GET {
+        "GET is not enabled for this method, it must be marked `idempotency_level = NO_SIDE_EFFECTS`"
+            .to_string()
+    } else {
+        format!("HTTP method {} is not supported, use POST", method)
+    };
+
+    let error = RpcError::new(RpcErrorCode::Unimplemented, message);
+    ResponseEncoder::error(error, false, false).encode_response()
+}
+
+impl RpcRouter {
+    /// Accept RPC paths with a trailing slash or duplicate slashes, eg.
+    /// `/hello.HelloWorldService//SayHello/` after a proxy rewrite. Off by default. Other routes
+    /// aren't affected, and paths with percent-encoded characters are never normalized.
+    ///
+    /// The paths are rewritten before routing, by a service [`into_router`](RpcRouter::into_router)
+    /// puts around the routes, so layers applied to the `Router` it returns see the paths as sent,
+    /// and those applied before see them normalized. Requests that don't normalize to an RPC path
+    /// go to the [`fallback_service`](RpcRouter::fallback_service) as they are.
+    ///
+    /// Only a router without state normalizes paths, give it its state with
+    /// [`with_state`](RpcRouter::with_state) after.
+    ///
+    /// ```compile_fail
+    /// # use axum_connect::prelude::*;
+    /// # #[derive(Clone)]
+    /// # struct AppState;
+    /// let router: RpcRouter<AppState> = RpcRouter::new().normalize_rpc_paths(true);
+    /// ```
+    pub fn normalize_rpc_paths(mut self, normalize: bool) -> Self {
+        self.normalize_rpc_paths = normalize;
+        self.rewrite = Some(PathRewrite::around);
+        self
+    }
+
+    /// Also mounts the RPCs under `from_prefix`, eg. `/hello.v1.HelloWorldService`, at the same
+    /// paths under `to_prefix`, eg. `/hello.v2.HelloWorldService` for a v2 service the v1
+    /// handlers can serve, without mounting their handlers again. The aliases are recorded like
+    /// [`nest`](RpcRouter::nest)ed RPCs, and their calls are rewritten to the original paths
+    /// before routing, like [normalized](RpcRouter::normalize_rpc_paths) ones, so they go through
+    /// every layer of the original routes. Those layers see the original path in the URI, and the
+    /// alias in the [`RpcMethodInfo`] extension, which is what handlers, logs, metrics and traces
+    /// go by.
+    ///
+    /// Like `normalize_rpc_paths`, only a router without state has aliases, give it its state
+    /// with [`with_state`](RpcRouter::with_state) after.
+    ///
+    /// ```compile_fail
+    /// # use axum_connect::prelude::*;
+    /// # #[derive(Clone)]
+    /// # struct AppState;
+    /// let router: RpcRouter<AppState> = RpcRouter::new()
+    ///     .alias_service("/hello.v1.HelloWorldService", "/hello.v2.HelloWorldService");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// If no RPCs are mounted under `from_prefix`, or one is already mounted at an alias path.
+    pub fn alias_service(mut self, from_prefix: &str, to_prefix: &str) -> Self {
+        let aliases = self
+            .methods
+            .iter()
+            .filter_map(|info| {
+                let rest = info.path.strip_prefix(from_prefix)?;
+                rest.starts_with('/').then(|| {
+                    (
+                        info.clone(),
+                        info.rebased(&format!("{}{}", to_prefix, rest)),
+                    )
+                })
+            })
+            .collect::<Vec<_>>();
+        if aliases.is_empty() {
+            panic!("No RPCs are mounted under {}", from_prefix);
+        }
+        self.check_unmounted(aliases.iter().map(|(_, alias)| alias));
+
+        // Calls are rewritten to the original paths before routing, see `into_router`.
+        for (info, alias) in aliases {
+            if let Some(settings) = self.settings.get(&info.path).cloned() {
+                self.settings.insert(alias.path.clone(), settings);
+            }
+            self.aliases
+                .insert(alias.path.clone(), (info.path, alias.clone()));
+            self.record(alias);
+        }
+        self.rewrite = Some(PathRewrite::around);
+        self
+    }
+
+    /// Forwards to [`Router::into_make_service`].
+    pub fn into_make_service(self) -> IntoMakeService<Router> {
+        self.into_router().into_make_service()
+    }
+
+    /// Forwards to [`Router::into_make_service_with_connect_info`], which `ConnectInfo` extraction
+    /// needs.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn into_make_service_with_connect_info<C>(
+        self,
+    ) -> IntoMakeServiceWithConnectInfo<Router, C> {
+        self.into_router().into_make_service_with_connect_info()
+    }
+
+    /// The router as a service, to serve it without `axum::serve`. It's both a tower and a hyper
+    /// `Service`, so it can be handed straight to hyper's connection builders. A router with state
+    /// needs [`with_state`](RpcRouter::with_state) first.
+    ///
+    /// ```no_run
+    /// # use axum_connect::prelude::*;
+    /// # use hyper_util::{rt::{TokioExecutor, TokioIo}, server::conn::auto};
+    /// # async fn serve(router: RpcRouter) {
+    /// let service = router.into_service();
+    /// let listener = tokio::net::TcpListener::bind("127.0.0.1:3030").await.unwrap();
+    /// loop {
+    ///     let (stream, _) = listener.accept().await.unwrap();
+    ///     let service = service.clone();
+    ///     tokio::spawn(async move {
+    ///         auto::Builder::new(TokioExecutor::new())
+    ///             .serve_connection(TokioIo::new(stream), service)
+    ///             .await
+    ///     });
+    /// }
+    /// # }
+    /// ```
+    pub fn into_service(self) -> RpcService {
+        RpcService {
+            router: self.into_router(),
+        }
+    }
+}
+
+/// A finished [`RpcRouter`], see [`RpcRouter::into_service`].
+#[derive(Clone, Debug)]
+pub struct RpcService {
+    router: Router,
+}
+
+impl<B> Service<http::Request<B>> for RpcService
+where
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = RouteFuture<Infallible>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Service::<http::Request<B>>::poll_ready(&mut self.router, cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        self.router.call(request)
+    }
+}
+
+impl<B> hyper::service::Service<http::Request<B>> for RpcService
+where
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = RouteFuture<Infallible>;
+
+    fn call(&self, request: http::Request<B>) -> Self::Future {
+        // Routers are always ready.
+        self.router.clone().call(request)
+    }
+}
+
+impl<S> Default for RpcRouter<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S> From<Router<S>> for RpcRouter<S> {
+    fn from(router: Router<S>) -> Self {
+        Self {
+            router,
+            methods: vec![],
+            normalize_rpc_paths: false,
+            aliases: HashMap::new(),
+            rewrite: None,
+            fallback: None,
+            config: None,
+            stream_idle_timeout: None,
+            settings: HashMap::new(),
+            authorizer: None,
+            #[cfg(feature = "debug-routes")]
+            debug_routes: vec![],
+            gzip: false,
+            capabilities: vec![],
+        }
+    }
+}
+
+impl<S> From<RpcRouter<S>> for Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    fn from(router: RpcRouter<S>) -> Self {
+        router.into_router()
+    }
+}
+
+impl<S> fmt::Debug for RpcRouter<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcRouter")
+            .field("methods", &self.methods)
+            .finish()
+    }
+}
+
+/// Prints a summary line followed by a table of every mounted RPC.
+impl<S> fmt::Display for RpcRouter<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let services = self
+            .methods
+            .iter()
+            .map(|m| m.service.as_str())
+            .collect::<BTreeSet<_>>();
+
+        writeln!(
+            f,
+            "mounted {} RPCs across {} services",
+            self.methods.len(),
+            services.len()
+        )?;
+
+        let kinds = self
+            .methods
+            .iter()
+            .map(|m| m.kind.map(|k| k.to_string()).unwrap_or_else(|| "-".into()))
+            .collect::<Vec<_>>();
+        let service_width = self
+            .methods
+            .iter()
+            .map(|m| m.service.len())
+            .fold(7, usize::max);
+        let method_width = self
+            .methods
+            .iter()
+            .map(|m| m.method.len())
+            .fold(6, usize::max);
+        let kind_width = kinds.iter().map(|k| k.len()).fold(4, usize::max);
+
+        write!(
+            f,
+            "{:service_width$}  {:method_width$}  {:kind_width$}  IDEMPOTENT",
+            "SERVICE", "METHOD", "KIND"
+        )?;
+
+        for (method, kind) in self.methods.iter().zip(kinds) {
+            write!(
+                f,
+                "\n{:service_width$}  {:method_width$}  {:kind_width$}  {}",
+                method.service,
+                method.method,
+                kind,
+                if method.idempotent() { "yes" } else { "no" }
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use axum::{
+        body::Body,
+        extract::{ConnectInfo, State},
+        routing::post,
+    };
+
+    use super::*;
+    use crate::{
+        error::{RpcError, RpcErrorCode},
+        response::RpcResult,
+        test_util::{
+            client, client_of, hello, hello_router, message, proto_request, say_hello,
+            say_hello_stream, server_stream, stream_request, unary, HelloRequest, HelloResponse,
+            SAY_HELLO, SAY_HELLO_STREAM,
+        },
+        testing::TestClient,
+    };
+
+    /// [`hello_router`], plus a `NO_SIDE_EFFECTS` RPC of a second service and a route only known
+    /// by its path.
+    fn two_services() -> RpcRouter {
+        let info = RpcMethodInfo::new(
+            "admin.AdminService",
+            "Status",
+            RpcMethodKind::Unary,
+            RpcIdempotencyLevel::NoSideEffects,
+        );
+        hello_router()
+            .rpc_method(RpcMethod::unary(info, say_hello))
+            .route("/admin.AdminService/Reload", post(|| async {}))
+    }
+
+    #[test]
+    fn collects_the_rpcs_of_every_service() {
+        let paths = two_services()
+            .paths()
+            .into_iter()
+            .map(|info| {
+                (
+                    info.idempotent(),
+                    info.path,
+                    info.service,
+                    info.method,
+                    info.kind,
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            [
+                (
+                    false,
+                    SAY_HELLO.to_string(),
+                    "hello.HelloWorldService".to_string(),
+                    "SayHello".to_string(),
+                    Some(RpcMethodKind::Unary),
+                ),
+                (
+                    false,
+                    SAY_HELLO_STREAM.to_string(),
+                    "hello.HelloWorldService".to_string(),
+                    "SayHelloStream".to_string(),
+                    Some(RpcMethodKind::ServerStreaming),
+                ),
+                (
+                    true,
+                    "/admin.AdminService/Status".to_string(),
+                    "admin.AdminService".to_string(),
+                    "Status".to_string(),
+                    Some(RpcMethodKind::Unary),
+                ),
+                (
+                    false,
+                    "/admin.AdminService/Reload".to_string(),
+                    "admin.AdminService".to_string(),
+                    "Reload".to_string(),
+                    None,
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn prints_the_route_table() {
+        assert_eq!(
+            two_services().to_string(),
+            "mounted 4 RPCs across 2 services\n\
+             SERVICE                  METHOD          KIND              IDEMPOTENT\n\
+             hello.HelloWorldService  SayHello        unary             no\n\
+             hello.HelloWorldService  SayHelloStream  server_streaming  no\n\
+             admin.AdminService       Status          unary             yes\n\
+             admin.AdminService       Reload          -                 no"
+        );
+    }
+
+    #[derive(Clone)]
+    struct KeyStore(&'static str);
+
+    #[derive(Clone)]
+    struct StripeClient(u32);
+
+    async fn login(
+        State(keys): State<KeyStore>,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse {
+            message: format!("{} signed with {}", request.name, keys.0),
+        })
+    }
+
+    async fn charge(
+        State(stripe): State<StripeClient>,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse {
+            message: format!("{} charged on account {}", request.name, stripe.0),
+        })
+    }
+
+    async fn call(client: &crate::testing::TestClient, path: &str) -> String {
+        let response = client.send(proto_request(path, &hello("Ada"))).await;
+        message::<HelloResponse>(&response).message
+    }
+
+    #[tokio::test]
+    async fn mounts_services_with_their_own_state() {
+        let router: RpcRouter<()> = RpcRouter::new()
+            .rpc_with_state(KeyStore("hunter2"), |router| {
+                router.rpc_method(unary("/auth.AuthService/Login", login))
+            })
+            .rpc_with_state(StripeClient(42), |router| {
+                router.rpc_method(unary("/billing.BillingService/Charge", charge))
+            });
+        assert_eq!(
+            router
+                .paths()
+                .into_iter()
+                .map(|info| info.path)
+                .collect::<Vec<_>>(),
+            ["/auth.AuthService/Login", "/billing.BillingService/Charge"]
+        );
+
+        let client = client(router);
+        assert_eq!(
+            call(&client, "/auth.AuthService/Login").await,
+            "Ada signed with hunter2"
+        );
+        assert_eq!(
+            call(&client, "/billing.BillingService/Charge").await,
+            "Ada charged on account 42"
+        );
+    }
+
+    #[tokio::test]
+    async fn mounts_services_with_their_own_state_on_an_axum_router() {
+        let router: Router<()> = Router::new()
+            .rpc_with_state(KeyStore("hunter2"), |router| {
+                router.rpc_method(unary("/auth.AuthService/Login", login))
+            })
+            .rpc_with_state(StripeClient(42), |router| {
+                router.rpc_method(unary("/billing.BillingService/Charge", charge))
+            });
+
+        let client = client_of(router);
+        assert_eq!(
+            call(&client, "/auth.AuthService/Login").await,
+            "Ada signed with hunter2"
+        );
+        assert_eq!(
+            call(&client, "/billing.BillingService/Charge").await,
+            "Ada charged on account 42"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_static_files_and_plain_routes_alongside_rpcs() {
+        // Like `ServeDir`, one file and a 404 for the rest.
+        let static_files = routing::get(|uri: Uri| async move {
+            match uri.path() {
+                "/index.html" => (StatusCode::OK, "<h1>Hello</h1>"),
+                _ => (StatusCode::NOT_FOUND, "Not found"),
+            }
+        });
+        let router = hello_router()
+            .rest_route("/healthz", routing::get(|| async { "ok" }))
+            .fallback_service(static_files)
+            .into_router();
+        let client = client_of(router);
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+
+        let get = |path: &str| Request::get(path).body(Body::empty()).unwrap();
+        let response = client.send(get("/index.html")).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.body, "<h1>Hello</h1>".as_bytes());
+        let response = client.send(get("/healthz")).await;
+        assert_eq!(response.body, "ok".as_bytes());
+        let response = client.send(get("/missing.js")).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    /// [`hello_router`] with normalized paths and a fallback answering with the path it got.
+    fn normalizing() -> Router {
+        hello_router()
+            .normalize_rpc_paths(true)
+            .fallback_service(routing::any(|uri: Uri| async move {
+                (
+                    StatusCode::NOT_FOUND,
+                    format!("fallback got {}", uri.path()),
+                )
+            }))
+            .into_router()
+    }
+
+    #[tokio::test]
+    async fn normalizes_trailing_and_duplicate_slashes() {
+        let client = client_of(normalizing());
+        for path in [
+            "/hello.HelloWorldService/SayHello/",
+            "//hello.HelloWorldService/SayHello",
+            "/hello.HelloWorldService//SayHello/",
+        ] {
+            let response = client.send(proto_request(path, &hello("Ada"))).await;
+            assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_paths_it_cant_normalize_to_the_fallback() {
+        let client = client_of(normalizing());
+        for path in [
+            "/hello.HelloWorldService/Say%48ello",
+            "/hello.HelloWorldService/SayHello%2F",
+            "/hello.HelloWorldService/SayGoodbye/",
+            "/index.html",
+        ] {
+            let response = client.send(proto_request(path, &hello("Ada"))).await;
+            assert_eq!(response.status, StatusCode::NOT_FOUND);
+            assert_eq!(response.body, format!("fallback got {}", path));
+        }
+    }
+
+    #[tokio::test]
+    async fn normalizes_before_the_layers_of_the_returned_router() {
+        let seen = Arc::new(std::sync::Mutex::new(vec![]));
+        let router = normalizing().layer(middleware::from_fn({
+            let seen = seen.clone();
+            move |request: Request, next: Next| {
+                seen.lock().unwrap().push(request.uri().path().to_string());
+                next.run(request)
+            }
+        }));
+
+        let client = client_of(router);
+        let response = client
+            .send(proto_request(
+                "/hello.HelloWorldService/SayHello/",
+                &hello("Ada"),
+            ))
+            .await;
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        let response = client
+            .send(proto_request("/index.html", &hello("Ada")))
+            .await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(
+            *seen.lock().unwrap(),
+            ["/hello.HelloWorldService/SayHello/", "/index.html"]
+        );
+    }
+
+    #[tokio::test]
+    async fn normalizes_the_paths_of_a_router_given_state_after() {
+        let router = hello_router()
+            .normalize_rpc_paths(true)
+            .with_state(())
+            .merge(RpcRouter::new().rpc_method(unary("/auth.AuthService/Login", login)))
+            .with_state(KeyStore("hunter2"));
+
+        let client = client(router);
+        assert_eq!(
+            call(&client, "/hello.HelloWorldService/SayHello/").await,
+            "Hello Ada!"
+        );
+        assert_eq!(
+            call(&client, "/auth.AuthService/Login").await,
+            "Ada signed with hunter2"
+        );
+        // Mounted after the state, so it isn't normalized.
+        let response = client
+            .send(proto_request("/auth.AuthService/Login/", &hello("Ada")))
+            .await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+    }
+
+    const SAY_HELLO_V2: &str = "/hello.v2.HelloWorldService/SayHello";
+
+    /// Answers with the path of the [`RpcMethodInfo`] it got.
+    async fn method_path(
+        mut call: crate::request::RpcCallParts,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        let request = call.request(request);
+        Ok(HelloResponse {
+            message: request.method().unwrap().path.clone(),
+        })
+    }
+
+    /// [`method_path`] at [`SAY_HELLO`], aliased at [`SAY_HELLO_V2`], behind a layer applied
+    /// before the alias that records the paths it sees.
+    fn aliased(seen: &Arc<std::sync::Mutex<Vec<String>>>) -> RpcRouter {
+        let seen = seen.clone();
+        RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, method_path))
+            .layer(middleware::from_fn(move |request: Request, next: Next| {
+                seen.lock().unwrap().push(request.uri().path().to_string());
+                next.run(request)
+            }))
+            .alias_service("/hello.HelloWorldService", "/hello.v2.HelloWorldService")
+    }
+
+    #[tokio::test]
+    async fn serves_aliases_through_the_original_route() {
+        let seen = Arc::default();
+        let router = aliased(&seen);
+        assert_eq!(
+            router
+                .paths()
+                .into_iter()
+                .map(|info| (info.path, info.service))
+                .collect::<Vec<_>>(),
+            [
+                (SAY_HELLO.to_string(), "hello.HelloWorldService".to_string()),
+                (
+                    SAY_HELLO_V2.to_string(),
+                    "hello.v2.HelloWorldService".to_string()
+                ),
+            ]
+        );
+
+        let client = client(router);
+        assert_eq!(call(&client, SAY_HELLO).await, SAY_HELLO);
+        assert_eq!(call(&client, SAY_HELLO_V2).await, SAY_HELLO_V2);
+        assert_eq!(*seen.lock().unwrap(), [SAY_HELLO, SAY_HELLO]);
+    }
+
+    #[tokio::test]
+    async fn logs_calls_to_aliases_under_the_alias() {
+        let procedures = Arc::new(std::sync::Mutex::new(vec![]));
+        let log = crate::logging::RpcLogLayer::new().on_complete({
+            let procedures = procedures.clone();
+            move |log| procedures.lock().unwrap().push(log.procedure.clone())
+        });
+        let client = client(aliased(&Arc::default()).layer(log));
+        call(&client, SAY_HELLO_V2).await;
+        call(&client, SAY_HELLO).await;
+        assert_eq!(*procedures.lock().unwrap(), [SAY_HELLO_V2, SAY_HELLO]);
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[tokio::test]
+    async fn labels_the_metrics_of_aliases_with_the_alias() {
+        let metrics = crate::metrics::PrometheusMetrics::new();
+        let client = client(aliased(&Arc::default()).mount_metrics("/metrics", metrics.clone()));
+        call(&client, SAY_HELLO_V2).await;
+        call(&client, SAY_HELLO_V2).await;
+        call(&client, SAY_HELLO).await;
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            "rpc_server_calls_total{service=\"hello.v2.HelloWorldService\",method=\"SayHello\",code=\"ok\"} 2\n"
+        ), "{rendered}");
+        assert!(rendered.contains(
+            "rpc_server_calls_total{service=\"hello.HelloWorldService\",method=\"SayHello\",code=\"ok\"} 1\n"
+        ), "{rendered}");
+    }
+
+    #[tokio::test]
+    async fn nests_aliases() {
+        let client = client(RpcRouter::new().nest("/canary", aliased(&Arc::default())));
+        assert_eq!(
+            call(&client, "/canary/hello.v2.HelloWorldService/SayHello").await,
+            "/canary/hello.v2.HelloWorldService/SayHello"
+        );
+        assert_eq!(
+            call(&client, "/canary/hello.HelloWorldService/SayHello").await,
+            "/canary/hello.HelloWorldService/SayHello"
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_aliases_of_a_router_given_state_after() {
+        let router = aliased(&Arc::default())
+            .with_state(())
+            .merge(RpcRouter::new().rpc_method(unary("/auth.AuthService/Login", login)))
+            .with_state(KeyStore("hunter2"));
+
+        let client = client(router);
+        assert_eq!(call(&client, SAY_HELLO_V2).await, SAY_HELLO_V2);
+        assert_eq!(
+            call(&client, "/auth.AuthService/Login").await,
+            "Ada signed with hunter2"
+        );
+    }
+
+    async fn peer(
+        ConnectInfo(peer): ConnectInfo<std::net::SocketAddr>,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        Ok(HelloResponse {
+            message: format!("{} at {}", request.name, peer),
+        })
+    }
+
+    #[cfg(feature = "serve")]
+    #[tokio::test]
+    async fn serves_the_peer_address_with_connect_info() {
+        use prost::Message;
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::{TcpListener, TcpStream},
+        };
+
+        let router = RpcRouter::new().rpc_method(unary(SAY_HELLO, peer));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let service = router.into_make_service_with_connect_info::<std::net::SocketAddr>();
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let body = hello("Ada").encode_to_vec();
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let local = stream.local_addr().unwrap();
+        let head = format!(
+            "POST {SAY_HELLO} HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/proto\r\n\
+             content-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(&body).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        let end_of_head = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        assert_eq!(
+            HelloResponse::decode(&response[end_of_head + 4..])
+                .unwrap()
+                .message,
+            format!("Ada at {local}")
+        );
+    }
+
+    #[tokio::test]
+    async fn tells_how_to_get_connect_info_without_it() {
+        let client = client(RpcRouter::new().rpc_method(unary(SAY_HELLO, peer)));
+
+        let error = client
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await
+            .error()
+            .unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::Internal);
+        assert!(
+            error
+                .message
+                .ends_with("Serve the router with `into_make_service_with_connect_info`"),
+            "{}",
+            error.message
+        );
+    }
+
+    fn bodyless(method: Method, uri: &str) -> Request {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(axum::body::Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejects_other_http_methods_with_a_connect_error() {
+        let client = client(two_services());
+
+        let response = client.send(bodyless(Method::GET, SAY_HELLO)).await;
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::Unimplemented,
+                "GET is not enabled for this method, it must be marked \
+                 `idempotency_level = NO_SIDE_EFFECTS`"
+                    .to_string()
+            ))
+        );
+
+        let response = client.send(bodyless(Method::PUT, SAY_HELLO)).await;
+        assert_eq!(
+            response.error(),
+            Some(RpcError::new(
+                RpcErrorCode::Unimplemented,
+                "HTTP method PUT is not supported, use POST".to_string()
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn serves_get_where_it_is_enabled() {
+        use base64::{engine::general_purpose::URL_SAFE, Engine as _};
+        use prost::Message;
+
+        let uri = format!(
+            "/admin.AdminService/Status?connect=v1&encoding=proto&base64=1&message={}",
+            URL_SAFE.encode(hello("Ada").encode_to_vec())
+        );
+        let info = RpcMethodInfo::new(
+            "admin.AdminService",
+            "Status",
+            RpcMethodKind::Unary,
+            RpcIdempotencyLevel::NoSideEffects,
+        )
+        .with_http_methods(vec![Method::POST, Method::GET]);
+        let router = RpcRouter::new().rpc_method(RpcMethod::unary(info, say_hello));
+
+        let response = client(router).send(bodyless(Method::GET, &uri)).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+    }
+
+    /// A library crate providing `users.Users`.
+    mod users {
+        use super::*;
+
+        pub const WHO_AM_I: &str = "/users.Users/WhoAmI";
+
+        pub fn services() -> RpcServiceCollection {
+            RpcServiceCollection::new().service("users.Users", |router: RpcRouter| {
+                router.rpc_method(unary(WHO_AM_I, say_hello))
+            })
+        }
+    }
+
+    /// A library crate providing `billing.Billing`, and the users it bills.
+    mod billing {
+        use super::*;
+
+        pub const CHARGE: &str = "/billing.Billing/Charge";
+
+        pub fn services() -> RpcServiceCollection {
+            RpcServiceCollection::new()
+                .service("billing.Billing", |router: RpcRouter| {
+                    router.rpc_method(unary(CHARGE, say_hello))
+                })
+                .collection(users::services())
+        }
+    }
+
+    #[tokio::test]
+    async fn mounts_the_services_of_nested_collections() {
+        let collection = billing::services();
+        assert_eq!(collection.services(), ["billing.Billing", "users.Users"]);
+
+        let client = client(hello_router().add_collection(collection));
+
+        for path in [SAY_HELLO, users::WHO_AM_I, billing::CHARGE] {
+            let response = client.send(proto_request(path, &hello("Ada"))).await;
+            assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Services mounted more than once: users.Users")]
+    fn rejects_services_mounted_twice() {
+        let _ = RpcRouter::new()
+            .add_collection(users::services())
+            .add_collection(billing::services());
+    }
+
+    #[test]
+    #[should_panic(expected = "Services mounted more than once: hello.HelloWorldService")]
+    fn rejects_collections_of_services_already_mounted() {
+        let collection = RpcServiceCollection::new()
+            .service("hello.HelloWorldService", |router: RpcRouter| router);
+        let _ = hello_router().add_collection(collection);
+    }
+
+    /// Compiles only if `service` can be handed to hyper's connection builders as it is.
+    fn assert_hyper_service<T>(service: T) -> T
+    where
+        T: hyper::service::Service<
+                http::Request<hyper::body::Incoming>,
+                Response = Response,
+                Error = Infallible,
+            > + Clone
+            + Send
+            + 'static,
+        T::Future: Send + 'static,
+    {
+        service
+    }
+
+    #[tokio::test]
+    async fn serves_stateful_routers_with_hyper_directly() {
+        use hyper::client::conn::http1;
+        use hyper_util::{
+            rt::{TokioExecutor, TokioIo},
+            server::conn::auto,
+        };
+        use tokio::net::{TcpListener, TcpStream};
+
+        async fn greet(
+            State(greeting): State<&'static str>,
+            request: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: format!("{greeting} {}!", request.name),
+            })
+        }
+
+        let router: RpcRouter<&'static str> = RpcRouter::new().rpc_method(unary(SAY_HELLO, greet));
+        let service = assert_hyper_service(router.with_state::<()>("Hi").into_service());
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            auto::Builder::new(TokioExecutor::new())
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+        });
+
+        let stream = TcpStream::connect(address).await.unwrap();
+        let (mut sender, connection) = http1::handshake(TokioIo::new(stream)).await.unwrap();
+        tokio::spawn(connection);
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert(http::header::HOST, "localhost".parse().unwrap());
+        let response = sender.send_request(request).await.unwrap();
+
+        let body = axum::body::to_bytes(Body::new(response.into_body()), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(HelloResponse::decode(body).unwrap().message, "Hi Ada!");
+    }
+
+    /// An encoded `FileDescriptorSet` of `package`, with a service per `(name, methods)` and
+    /// methods as `(name, client_streaming)`.
+    fn descriptor_set(package: &str, services: &[(&str, &[(&str, bool)])]) -> Vec<u8> {
+        use pbjson_types::{FileDescriptorProto, MethodDescriptorProto, ServiceDescriptorProto};
+
+        let service = services
+            .iter()
+            .map(|(name, methods)| ServiceDescriptorProto {
+                name: Some(name.to_string()),
+                method: methods
+                    .iter()
+                    .map(|(name, client_streaming)| MethodDescriptorProto {
+                        name: Some(name.to_string()),
+                        client_streaming: Some(*client_streaming),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
+        FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some(format!("{package}.proto")),
+                package: Some(package.to_string()),
+                service,
+                ..Default::default()
+            }],
+        }
+        .encode_to_vec()
+    }
+
+    #[test]
+    fn validates_routes_matching_the_descriptors() {
+        let hello = descriptor_set(
+            "hello",
+            &[(
+                "HelloWorldService",
+                &[("SayHello", false), ("SayHelloStream", false)],
+            )],
+        );
+        assert_eq!(hello_router().validate_against(&[&hello]), Ok(()));
+    }
+
+    #[test]
+    fn reports_unmounted_and_unknown_methods() {
+        let hello = descriptor_set(
+            "hello",
+            &[(
+                "HelloWorldService",
+                &[
+                    ("SayHello", false),
+                    // Renamed from `SayHelloStream`.
+                    ("SayHelloStreaming", false),
+                    // Codegen doesn't mount these.
+                    ("Upload", true),
+                ],
+            )],
+        );
+        let billing = descriptor_set("billing", &[("BillingService", &[("Charge", false)])]);
+
+        let mismatches = hello_router()
+            .validate_against(&[&hello, &billing, &[0xff]])
+            .unwrap_err();
+
+        assert_eq!(
+            mismatches
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            [
+                "descriptor set 2 is invalid: failed to decode Protobuf message: invalid varint",
+                "/billing.BillingService/Charge is defined but not mounted",
+                "/hello.HelloWorldService/SayHelloStreaming is defined but not mounted",
+                "/hello.HelloWorldService/SayHelloStream is mounted but not defined in any descriptor",
+            ]
+        );
+    }
+
+    /// Counts the calls it gets.
+    static GREETED: AtomicUsize = AtomicUsize::new(0);
+
+    async fn counted(request: HelloRequest) -> RpcResult<HelloResponse> {
+        GREETED.fetch_add(1, Ordering::SeqCst);
+        say_hello(request).await
+    }
+
+    /// [`counted`] and a stream, served while `flag` is set or for testers.
+    fn dark_launched(flag: Arc<AtomicBool>, code: RpcErrorCode) -> TestClient {
+        let options = RouteOptions::new()
+            .enabled(move |parts| {
+                flag.load(Ordering::SeqCst) || parts.headers.contains_key("x-tester")
+            })
+            .disabled_code(code);
+        client(
+            RpcRouter::new().rpc_with_options(options, |router: RpcRouter| {
+                router
+                    .rpc_method(unary(SAY_HELLO, counted))
+                    .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn serves_gated_routes_while_their_flag_is_set() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let client = dark_launched(flag.clone(), RpcErrorCode::Unimplemented);
+        let call = || client.send(proto_request(SAY_HELLO, &hello("Ada")));
+        let greeted = GREETED.load(Ordering::SeqCst);
+
+        assert_eq!(
+            call().await.error(),
+            Some(RpcError::new(
+                RpcErrorCode::Unimplemented,
+                format!("{SAY_HELLO} is not available")
+            ))
+        );
+        let (_, error) = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await
+            .frames();
+        assert_eq!(error.unwrap().code, RpcErrorCode::Unimplemented);
+        assert_eq!(GREETED.load(Ordering::SeqCst), greeted);
+
+        flag.store(true, Ordering::SeqCst);
+        assert_eq!(
+            message::<HelloResponse>(&call().await).message,
+            "Hello Ada!"
+        );
+        assert_eq!(GREETED.load(Ordering::SeqCst), greeted + 1);
+
+        flag.store(false, Ordering::SeqCst);
+        assert!(call().await.error().is_some());
+        assert_eq!(GREETED.load(Ordering::SeqCst), greeted + 1);
+    }
+
+    #[tokio::test]
+    async fn gates_can_read_headers_and_fail_with_another_code() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let client = dark_launched(flag, RpcErrorCode::FailedPrecondition);
+
+        let response = client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        assert_eq!(
+            response.error().unwrap().code,
+            RpcErrorCode::FailedPrecondition
+        );
+
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        request
+            .headers_mut()
+            .insert("x-tester", http::HeaderValue::from_static("1"));
+        let response = client.send(request).await;
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+    }
+
+    /// Calls in flight, and the most there have been at once.
+    #[derive(Default)]
+    struct Gauge {
+        in_flight: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    async fn gauged(
+        State(gauge): State<Arc<Gauge>>,
+        request: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        let in_flight = gauge.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+        gauge.peak.fetch_max(in_flight, Ordering::SeqCst);
+        // Lets the other calls run, up to their own handler if nothing holds them back.
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        gauge.in_flight.fetch_sub(1, Ordering::SeqCst);
+        say_hello(request).await
+    }
+
+    /// Sends 3 calls at once, returning the most that were in the handler together.
+    async fn peak_of_3_calls(client: &TestClient, gauge: &Gauge) -> usize {
+        let call = || client.send(proto_request(SAY_HELLO, &hello("Ada")));
+        let (a, b, c) = futures::join!(call(), call(), call());
+        for response in [a, b, c] {
+            assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        }
+        gauge.peak.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn serves_a_single_rpc_service_in_tower_middleware() {
+        use tower::limit::ConcurrencyLimit;
+
+        let gauge = Arc::new(Gauge::default());
+        let unlimited = unary(SAY_HELLO, gauged).into_service(gauge.clone());
+        let client = client(RpcRouter::new().rpc_service(unlimited.info().clone(), unlimited));
+        assert_eq!(peak_of_3_calls(&client, &gauge).await, 3);
+
+        let gauge = Arc::new(Gauge::default());
+        let service = unary(SAY_HELLO, gauged).into_service(gauge.clone());
+        let info = service.info().clone();
+        let router = RpcRouter::new().rpc_service(info, ConcurrencyLimit::new(service, 1));
+        assert_eq!(
+            router
+                .paths()
+                .into_iter()
+                .map(|info| info.path)
+                .collect::<Vec<_>>(),
+            [SAY_HELLO]
+        );
+        assert_eq!(
+            peak_of_3_calls(&client_of(router.into_router()), &gauge).await,
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn mounts_a_single_rpc_service_on_an_axum_router() {
+        use tower::limit::ConcurrencyLimit;
+
+        let gauge = Arc::new(Gauge::default());
+        let service = unary(SAY_HELLO, gauged).into_service(gauge.clone());
+        let router = Router::new().route_service(SAY_HELLO, ConcurrencyLimit::new(service, 2));
+        let client = client_of(router);
+        assert_eq!(peak_of_3_calls(&client, &gauge).await, 2);
+
+        // Still a Connect route, with Connect errors.
+        let response = client
+            .post(SAY_HELLO, "application/proto", vec![0x0a, 0xff, 0xff])
+            .await;
+        assert_eq!(
+            response.error().unwrap().code,
+            RpcErrorCode::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn logs_a_summary_of_the_built_router() {
+        use crate::test_util::traced;
+
+        let (recorder, _guard) = traced();
+        let info = RpcMethodInfo::new(
+            "admin.AdminService",
+            "Status",
+            RpcMethodKind::Unary,
+            RpcIdempotencyLevel::NoSideEffects,
+        )
+        .with_http_methods(vec![Method::POST, Method::GET]);
+        let _ = hello_router()
+            .rpc_method(RpcMethod::unary(info, say_hello))
+            .with_config(RpcConfig::default().max_request_bytes(1024))
+            .stream_idle_timeout(Duration::from_secs(30))
+            .into_router();
+        let _ = RpcRouter::<()>::new().into_router();
+
+        let events = recorder.events("axum_connect::router");
+        let fields = events
+            .iter()
+            .map(|event| {
+                event
+                    .fields
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                "compression=none get_methods=1 grpc=false max_get_url_bytes=8192 \
+                 max_request_bytes=1024 message=rpc router built methods=3 \
+                 normalize_rpc_paths=false services=2 stream_compression=none \
+                 stream_idle_timeout_ms=30000 streaming_methods=1",
+                // Unlimited leaves the limit out, the GET URL limit has a default.
+                "compression=none get_methods=0 grpc=false max_get_url_bytes=8192 \
+                 message=rpc router built methods=0 normalize_rpc_paths=false services=0 \
+                 stream_compression=none streaming_methods=0",
+            ]
+        );
+    }
+
+    #[cfg(feature = "tower-http")]
+    #[test]
+    fn logs_the_gzip_of_the_recommended_layers_in_the_summary() {
+        use crate::test_util::traced;
+
+        let (recorder, _guard) = traced();
+        let _ = hello_router()
+            .layer(crate::layers::recommended())
+            .into_router();
+
+        let events = recorder.events("axum_connect::router");
+        assert_eq!(events[0].field("compression"), Some("gzip"));
+        assert_eq!(events[0].field("stream_compression"), Some("none"));
+    }
+
+    /// The panic message of `mount`, which must panic.
+    fn panic_message(mount: impl FnOnce() + std::panic::UnwindSafe) -> String {
+        let payload = std::panic::catch_unwind(mount).unwrap_err();
+        match payload.downcast::<String>() {
+            Ok(message) => *message,
+            Err(payload) => payload.downcast_ref::<&str>().unwrap().to_string(),
+        }
+    }
+
+    #[cfg(any(debug_assertions, feature = "paranoid"))]
+    #[test]
+    fn panics_on_handlers_with_transposed_message_types() {
+        let transposed = || {
+            crate::test_util::unary_info(SAY_HELLO)
+                .with_message_types("HelloResponse", "HelloRequest")
+        };
+        let expected = "/hello.HelloWorldService/SayHello takes HelloResponse and returns \
+                        HelloRequest, but its handler takes \
+                        axum_connect::test_util::HelloRequest and returns \
+                        axum_connect::test_util::HelloResponse";
+
+        let message = panic_message(|| {
+            let _ = RpcRouter::<()>::new().rpc_method(RpcMethod::unary(transposed(), say_hello));
+        });
+        assert_eq!(message, expected);
+
+        // As a service too.
+        let message = panic_message(|| {
+            let _ = RpcMethod::unary(transposed(), say_hello).into_service(());
+        });
+        assert_eq!(message, expected);
+
+        let info = crate::test_util::stream_info(SAY_HELLO_STREAM)
+            .with_message_types("HelloRequest", "HelloRequest");
+        let message = panic_message(|| {
+            let _ = RpcRouter::<()>::new()
+                .rpc_method(RpcMethod::server_streaming(info, say_hello_stream));
+        });
+        assert!(
+            message.ends_with("returns axum_connect::test_util::HelloResponse"),
+            "{message}"
+        );
+    }
+
+    #[test]
+    fn mounts_handlers_of_the_descriptor_message_types() {
+        for (request, response) in [
+            ("HelloRequest", "HelloResponse"),
+            ("super::HelloRequest", "self::HelloResponse"),
+            (
+                "test_util::HelloRequest",
+                "::axum_connect::test_util::HelloResponse",
+            ),
+        ] {
+            let info =
+                crate::test_util::unary_info(SAY_HELLO).with_message_types(request, response);
+            let _ = RpcRouter::<()>::new().rpc_method(RpcMethod::unary(info, say_hello));
+        }
+
+        // Methods mounted without the types, eg. by hand, aren't checked.
+        let _ = hello_router();
+    }
+
+    #[test]
+    fn matches_type_names_by_their_last_segments() {
+        assert!(same_type("HelloRequest", "hello::HelloRequest"));
+        assert!(same_type(
+            "super::v1::HelloRequest",
+            "api::hello::v1::HelloRequest"
+        ));
+        assert!(same_type("HelloRequest", "HelloRequest"));
+        assert!(!same_type("HelloRequest", "hello::OtherHelloRequest"));
+        assert!(!same_type("v2::HelloRequest", "hello::v1::HelloRequest"));
+        assert!(!same_type("HelloResponse", "hello::HelloRequest"));
+    }
+
+    type TransportLogs = Arc<std::sync::Mutex<Vec<(String, Option<RpcErrorCode>, bool)>>>;
+
+    /// Serves [`hello_router`] with `config` and a plain `/healthz` route over TCP, logging each
+    /// call's path, code and `invalid_transport`.
+    async fn serve_hello(config: RpcConfig) -> (std::net::SocketAddr, TransportLogs) {
+        let logs = TransportLogs::default();
+        let log = crate::logging::RpcLogLayer::new().on_complete({
+            let logs = logs.clone();
+            move |log| {
+                logs.lock().unwrap().push((
+                    log.procedure.clone(),
+                    log.code.clone(),
+                    log.invalid_transport,
+                ))
+            }
+        });
+        let router = hello_router()
+            .with_config(config)
+            .layer(log)
+            .into_router()
+            .route("/healthz", axum::routing::get(|| async { "ok" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            use hyper_util::{
+                rt::{TokioExecutor, TokioIo},
+                server::conn::auto,
+            };
+
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = hyper_util::service::TowerToHyperService::new(router.clone());
+                tokio::spawn(
+                    auto::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service)
+                        .into_owned(),
+                );
+            }
+        });
+        (address, logs)
+    }
+
+    /// Sends `head`, the request line and headers, and `body` over a new connection, returning
+    /// the response's status line and body.
+    async fn send_raw(address: std::net::SocketAddr, head: &str, body: &[u8]) -> (String, Vec<u8>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let head = format!("{head}content-length: {}\r\n\r\n", body.len());
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(body).await.unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let status = String::from_utf8_lossy(&response[..end]);
+        let status = status.lines().next().unwrap().to_string();
+        (status, response[end + 4..].to_vec())
+    }
+
+    #[tokio::test]
+    async fn rejects_rpc_calls_over_http_1_0_as_invalid_transport() {
+        let (address, logs) = serve_hello(RpcConfig::default()).await;
+        let body = hello("Ada").encode_to_vec();
+
+        let (status, response) = send_raw(
+            address,
+            &format!("POST {SAY_HELLO} HTTP/1.0\r\ncontent-type: application/proto\r\n"),
+            &body,
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.0 400 Bad Request");
+        assert_eq!(
+            crate::codec::parse_unary_error(&response).unwrap(),
+            RpcError::new(
+                RpcErrorCode::InvalidArgument,
+                "HTTP/1.0 is not supported, Connect takes HTTP/1.1 or HTTP/2".to_string()
+            )
+        );
+
+        // Routes that aren't RPCs are left alone.
+        let (status, response) = send_raw(address, "GET /healthz HTTP/1.0\r\n", b"").await;
+        assert_eq!(status, "HTTP/1.0 200 OK");
+        assert_eq!(response, b"ok");
+
+        // A proper call for comparison, only the first is tagged.
+        let (status, _) = send_raw(
+            address,
+            &format!(
+                "POST {SAY_HELLO} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\
+                 content-type: application/proto\r\n"
+            ),
+            &body,
+        )
+        .await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(
+            *logs.lock().unwrap(),
+            [
+                (
+                    SAY_HELLO.to_string(),
+                    Some(RpcErrorCode::InvalidArgument),
+                    true
+                ),
+                (SAY_HELLO.to_string(), None, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_calls_without_a_host_when_required() {
+        let body = hello("Ada").encode_to_vec();
+        let head = format!(
+            "POST {SAY_HELLO} HTTP/1.1\r\nconnection: close\r\ncontent-type: application/proto\r\n"
+        );
+
+        let (address, logs) = serve_hello(RpcConfig::default().require_host(true)).await;
+        let (status, response) = send_raw(address, &head, &body).await;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(
+            crate::codec::parse_unary_error(&response).unwrap().message,
+            "HTTP/1.1 requests need a Host header"
+        );
+        assert!(logs.lock().unwrap()[0].2);
+
+        let (address, logs) = serve_hello(RpcConfig::default()).await;
+        let (status, _) = send_raw(address, &head, &body).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(!logs.lock().unwrap()[0].2);
+    }
+
+    const CAPABILITIES: &str = "/.well-known/connect-capabilities";
+
+    /// The capabilities document `router` serves, parsed.
+    async fn capabilities(router: RpcRouter) -> serde_json::Value {
+        let request = http::Request::get(CAPABILITIES)
+            .body(Body::empty())
+            .unwrap();
+        let response = client(router).send(request).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type(), Some("application/json"));
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_capabilities_derived_from_the_router() {
+        let default = capabilities(hello_router().mount_capabilities(CAPABILITIES)).await;
+        assert_eq!(default["require_protocol_version"], false);
+        assert_eq!(
+            default["codecs"],
+            json!(if cfg!(feature = "json") {
+                vec!["proto", "json"]
+            } else {
+                vec!["proto"]
+            })
+        );
+        assert_eq!(
+            default["method_kinds"],
+            json!(["unary", "server_streaming"])
+        );
+        assert_eq!(
+            default["get"],
+            json!({ "enabled": false, "methods": 0, "max_url_bytes": 8192 })
+        );
+        assert_eq!(
+            default["limits"],
+            json!({
+                "max_request_bytes": null,
+                "max_get_url_bytes": 8192,
+                "max_metadata_bytes": null,
+                "stream_idle_timeout_ms": null,
+            })
+        );
+        assert_eq!(default["compression"]["unary_responses"], json!([]));
+
+        let configured = hello_router()
+            .add_ping_service()
+            .with_config(
+                RpcConfig::default()
+                    .require_protocol_version(true)
+                    .max_request_bytes(1024)
+                    .max_get_url_bytes(2048)
+                    .max_metadata_bytes(512),
+            )
+            .stream_idle_timeout(Duration::from_secs(30))
+            .mount_capabilities(CAPABILITIES);
+        #[cfg(feature = "tower-http")]
+        let configured = configured.layer(crate::layers::recommended());
+        let configured = capabilities(configured).await;
+        assert_eq!(configured["require_protocol_version"], true);
+        assert_eq!(
+            configured["get"],
+            json!({ "enabled": true, "methods": 1, "max_url_bytes": 2048 })
+        );
+        assert_eq!(
+            configured["limits"],
+            json!({
+                "max_request_bytes": 1024,
+                "max_get_url_bytes": 2048,
+                "max_metadata_bytes": 512,
+                "stream_idle_timeout_ms": 30_000,
+            })
+        );
+        #[cfg(feature = "tower-http")]
+        assert_eq!(
+            configured["compression"]["unary_responses"],
+            json!(["gzip"])
+        );
+        assert_eq!(configured["codecs"], default["codecs"]);
+    }
+
+    #[tokio::test]
+    async fn serves_no_capabilities_unless_mounted() {
+        let request = http::Request::get(CAPABILITIES)
+            .body(Body::empty())
+            .unwrap();
+        let response = client(hello_router()).send(request).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert!(response.body.is_empty());
+
+        // Mounted elsewhere, only that path serves it.
+        let router = || hello_router().mount_capabilities("/capabilities");
+        let request = http::Request::get(CAPABILITIES)
+            .body(Body::empty())
+            .unwrap();
+        let response = client(router()).send(request).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        let request = http::Request::get("/capabilities")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(client(router()).send(request).await.status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,541 @@
+//! Serving a router over HTTP/1.1 and cleartext HTTP/2, see [`serve`], or over a Unix domain
+//! socket, see [`serve_unix`].
+
+use std::{
+    fmt,
+    future::{Future, IntoFuture, Pending},
+    io,
+    net::SocketAddr,
+    pin::Pin,
+};
+
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+use axum::extract::connect_info::Connected;
+#[cfg(unix)]
+use axum::serve::IncomingStream;
+use axum::{Extension, Router};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio::{net::TcpListener, sync::watch};
+
+use crate::shutdown::ServerShutdown;
+
+/// Serves `routes` on `addr`, for browsers and HTTP/2 clients alike.
+///
+/// Each connection speaks HTTP/1.1, or HTTP/2 with prior knowledge (h2c, what gRPC-style clients
+/// do without TLS), whichever its first bytes are. The routes are served with their
+/// [`ConnectInfo`](axum::extract::ConnectInfo), the peer's `SocketAddr`.
+///
+/// After the [graceful shutdown](Serve::with_graceful_shutdown) signal, no connections are
+/// accepted, open response streams end with `Unavailable`, and it returns once the in-flight
+/// calls are done.
+///
+/// ```no_run
+/// # use axum_connect::prelude::*;
+/// # async fn run(app: RpcRouter) -> std::io::Result<()> {
+/// # async fn ctrl_c() {}
+/// axum_connect::serve(([0, 0, 0, 0], 3030), app)
+///     .with_graceful_shutdown(ctrl_c())
+///     .await
+/// # }
+/// ```
+///
+/// Bind first to find out the address, eg. of port 0 in tests:
+///
+/// ```no_run
+/// # use axum_connect::prelude::*;
+/// # async fn run(app: RpcRouter) -> std::io::Result<()> {
+/// let server = axum_connect::serve(([127, 0, 0, 1], 0), app).bind().await?;
+/// let addr = server.local_addr();
+/// tokio::spawn(async move { server.await });
+/// # Ok(())
+/// # }
+/// ```
+pub fn serve(addr: impl Into<SocketAddr>, routes: impl Into<Router>) -> Serve {
+    Serve {
+        addr: addr.into(),
+        router: routes.into(),
+        signal: std::future::pending(),
+    }
+}
+
+/// A server about to bind its address, see [`serve`].
+#[must_use = "servers do nothing unless `.await`ed"]
+pub struct Serve<F = Pending<()>> {
+    addr: SocketAddr,
+    router: Router,
+    signal: F,
+}
+
+impl<F> Serve<F> {
+    /// Shuts down gracefully once `signal` completes.
+    pub fn with_graceful_shutdown<G>(self, signal: G) -> Serve<G>
+    where
+        G: Future<Output = ()> + Send + 'static,
+    {
+        Serve {
+            addr: self.addr,
+            router: self.router,
+            signal,
+        }
+    }
+
+    /// Binds the address, the returned [`Server`] serves on it once awaited.
+    pub async fn bind(self) -> io::Result<Server<F>> {
+        Ok(Server {
+            listener: TcpListener::bind(self.addr).await?,
+            router: self.router,
+            signal: self.signal,
+        })
+    }
+}
+
+impl<F> fmt::Debug for Serve<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Serve").field("addr", &self.addr).finish()
+    }
+}
+
+impl<F> IntoFuture for Serve<F>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    type Output = io::Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move { self.bind().await?.await })
+    }
+}
+
+/// A server bound to its address, see [`Serve::bind`].
+#[must_use = "servers do nothing unless `.await`ed"]
+pub struct Server<F = Pending<()>> {
+    listener: TcpListener,
+    router: Router,
+    signal: F,
+}
+
+impl<F> Server<F> {
+    pub fn local_addr(&self) -> SocketAddr {
+        // A bound TCP listener always has one.
+        self.listener.local_addr().unwrap()
+    }
+}
+
+impl<F> fmt::Debug for Server<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("local_addr", &self.listener.local_addr().ok())
+            .finish()
+    }
+}
+
+impl<F> IntoFuture for Server<F>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    type Output = io::Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let (shutdown, receiver) = watch::channel(false);
+        let router = self.router.layer(Extension(ServerShutdown(receiver)));
+        let service = router.into_make_service_with_connect_info::<SocketAddr>();
+        let signal = self.signal;
+
+        // axum's server detects HTTP/2 prior knowledge itself, with its `http2` feature.
+        Box::pin(async move {
+            axum::serve(self.listener, service)
+                .with_graceful_shutdown(async move {
+                    signal.await;
+                    shutdown.send_replace(true);
+                })
+                .await
+        })
+    }
+}
+
+/// Serves `routes` on the Unix domain socket at `path`, eg. for a sidecar on the same host. Same
+/// protocols and graceful shutdown as [`serve`]; the routes are served with a
+/// [`ConnectInfo`](axum::extract::ConnectInfo) of [`UdsConnectInfo`], the peer's credentials.
+///
+/// A socket left at `path` by an earlier run is removed before binding, any other file there
+/// fails it. The socket isn't removed on shutdown.
+///
+/// ```no_run
+/// # use axum_connect::prelude::*;
+/// # async fn run(app: RpcRouter) -> std::io::Result<()> {
+/// # async fn ctrl_c() {}
+/// axum_connect::serve_unix("/run/app/rpc.sock", app)
+///     .with_graceful_shutdown(ctrl_c())
+///     .await
+/// # }
+/// ```
+///
+/// There's no client in this crate to call it with; with hyper's, connect a
+/// `tokio::net::UnixStream` and hand it to `hyper::client::conn::http1::handshake`.
+#[cfg(unix)]
+pub fn serve_unix(path: impl AsRef<Path>, routes: impl Into<Router>) -> ServeUnix {
+    ServeUnix {
+        path: path.as_ref().to_path_buf(),
+        router: routes.into(),
+        signal: std::future::pending(),
+    }
+}
+
+/// The peer of a Unix domain socket connection, from the credentials the kernel reports for it,
+/// see [`serve_unix`]. Handlers take it with `ConnectInfo<UdsConnectInfo>`. Fields are `None`
+/// where the platform doesn't report them, or the lookup failed.
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UdsConnectInfo {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub pid: Option<i32>,
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for UdsConnectInfo {
+    fn connect_info(stream: IncomingStream<'_, UnixListener>) -> Self {
+        match stream.io().peer_cred() {
+            Ok(cred) => Self {
+                uid: Some(cred.uid()),
+                gid: Some(cred.gid()),
+                pid: cred.pid(),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+/// A server about to bind its socket, see [`serve_unix`].
+#[cfg(unix)]
+#[must_use = "servers do nothing unless `.await`ed"]
+pub struct ServeUnix<F = Pending<()>> {
+    path: PathBuf,
+    router: Router,
+    signal: F,
+}
+
+#[cfg(unix)]
+impl<F> ServeUnix<F> {
+    /// Shuts down gracefully once `signal` completes.
+    pub fn with_graceful_shutdown<G>(self, signal: G) -> ServeUnix<G>
+    where
+        G: Future<Output = ()> + Send + 'static,
+    {
+        ServeUnix {
+            path: self.path,
+            router: self.router,
+            signal,
+        }
+    }
+
+    /// Binds the socket, the returned [`UnixServer`] serves on it once awaited.
+    pub async fn bind(self) -> io::Result<UnixServer<F>> {
+        use std::os::unix::fs::FileTypeExt;
+
+        match std::fs::symlink_metadata(&self.path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&self.path)?,
+            _ => {}
+        }
+        Ok(UnixServer {
+            listener: UnixListener::bind(&self.path)?,
+            path: self.path,
+            router: self.router,
+            signal: self.signal,
+        })
+    }
+}
+
+#[cfg(unix)]
+impl<F> fmt::Debug for ServeUnix<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServeUnix")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(unix)]
+impl<F> IntoFuture for ServeUnix<F>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    type Output = io::Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        Box::pin(async move { self.bind().await?.await })
+    }
+}
+
+/// A server bound to its socket, see [`ServeUnix::bind`].
+#[cfg(unix)]
+#[must_use = "servers do nothing unless `.await`ed"]
+pub struct UnixServer<F = Pending<()>> {
+    listener: UnixListener,
+    path: PathBuf,
+    router: Router,
+    signal: F,
+}
+
+#[cfg(unix)]
+impl<F> UnixServer<F> {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(unix)]
+impl<F> fmt::Debug for UnixServer<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UnixServer")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(unix)]
+impl<F> IntoFuture for UnixServer<F>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    type Output = io::Result<()>;
+    type IntoFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+
+    fn into_future(self) -> Self::IntoFuture {
+        let (shutdown, receiver) = watch::channel(false);
+        let router = self.router.layer(Extension(ServerShutdown(receiver)));
+        let service = router.into_make_service_with_connect_info::<UdsConnectInfo>();
+        let signal = self.signal;
+
+        Box::pin(async move {
+            axum::serve(self.listener, service)
+                .with_graceful_shutdown(async move {
+                    signal.await;
+                    shutdown.send_replace(true);
+                })
+                .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::Body,
+        extract::ConnectInfo,
+        http::{header, Request, Response, Version},
+    };
+    use futures::{stream, Stream, StreamExt};
+    use hyper::body::Incoming;
+    use prost::Message;
+    use tokio::sync::oneshot;
+    #[cfg(unix)]
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UnixStream,
+    };
+
+    use super::*;
+    use crate::{
+        codec::FrameDecoder,
+        prelude::*,
+        router::{RpcMethod, RpcRouter},
+        test_util::{
+            hello, proto_request, stream_info, stream_request, unary_info, HelloRequest,
+            HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+    };
+
+    /// Sends `request` over a new HTTP `version` connection to `addr`, HTTP/2 with prior knowledge.
+    async fn send_over(
+        version: Version,
+        addr: SocketAddr,
+        mut request: Request<Body>,
+    ) -> Response<Incoming> {
+        use hyper::client::conn::{http1, http2};
+        use hyper_util::rt::{TokioExecutor, TokioIo};
+
+        let stream = TokioIo::new(tokio::net::TcpStream::connect(addr).await.unwrap());
+        *request.version_mut() = version;
+        let response = if version == Version::HTTP_2 {
+            *request.uri_mut() = format!("http://{addr}{}", request.uri()).parse().unwrap();
+            let (mut sender, connection) = http2::handshake(TokioExecutor::new(), stream)
+                .await
+                .unwrap();
+            tokio::spawn(connection);
+            sender.send_request(request).await.unwrap()
+        } else {
+            request
+                .headers_mut()
+                .insert(header::HOST, "localhost".parse().unwrap());
+            let (mut sender, connection) = http1::handshake(stream).await.unwrap();
+            tokio::spawn(connection);
+            sender.send_request(request).await.unwrap()
+        };
+        assert_eq!(response.version(), version);
+        response
+    }
+
+    async fn body(response: Response<Incoming>) -> axum::body::Bytes {
+        axum::body::to_bytes(Body::new(response.into_body()), usize::MAX)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_http_1_and_h2_prior_knowledge_on_one_port() {
+        async fn peer(
+            ConnectInfo(peer): ConnectInfo<SocketAddr>,
+            request: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: format!("{} from {}", request.name, peer.ip()),
+            })
+        }
+        let router = RpcRouter::new().rpc_method(RpcMethod::unary(unary_info(SAY_HELLO), peer));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = serve(([127, 0, 0, 1], 0), router)
+            .with_graceful_shutdown(async move {
+                stopped.await.ok();
+            })
+            .bind()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        assert_ne!(addr.port(), 0);
+        let server = tokio::spawn(server.into_future());
+
+        for version in [Version::HTTP_11, Version::HTTP_2] {
+            let request = proto_request(SAY_HELLO, &hello("Ada"));
+            let response = send_over(version, addr, request).await;
+            assert_eq!(response.status(), 200, "{version:?}");
+            assert_eq!(
+                HelloResponse::decode(body(response).await).unwrap().message,
+                "Ada from 127.0.0.1",
+                "{version:?}"
+            );
+        }
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn ends_open_streams_on_graceful_shutdown() {
+        async fn endless(request: HelloRequest) -> impl Stream<Item = RpcResult<HelloResponse>> {
+            let first = HelloResponse {
+                message: format!("Hello {}!", request.name),
+            };
+            stream::iter([Ok(first)]).chain(stream::pending())
+        }
+        let router = RpcRouter::new().rpc_method(RpcMethod::server_streaming(
+            stream_info(SAY_HELLO_STREAM),
+            endless,
+        ));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = serve(([127, 0, 0, 1], 0), router)
+            .with_graceful_shutdown(async move {
+                stopped.await.ok();
+            })
+            .bind()
+            .await
+            .unwrap();
+        let addr = server.local_addr();
+        let server = tokio::spawn(server.into_future());
+
+        let request = stream_request(SAY_HELLO_STREAM, &hello("Ada"));
+        let response = send_over(Version::HTTP_11, addr, request).await;
+        let mut chunks = Body::new(response.into_body()).into_data_stream();
+        let mut decoder = FrameDecoder::new();
+        let first = loop {
+            decoder.push(&chunks.next().await.unwrap().unwrap());
+            if let Some(frame) = decoder.next_frame().unwrap() {
+                break frame;
+            }
+        };
+        assert_eq!(
+            HelloResponse::decode(first.payload.as_slice())
+                .unwrap()
+                .message,
+            "Hello Ada!"
+        );
+
+        stop.send(()).unwrap();
+        while let Some(chunk) = chunks.next().await {
+            decoder.push(&chunk.unwrap());
+        }
+        let end = decoder.next_frame().unwrap().unwrap();
+        assert!(end.is_end_stream());
+        let end = crate::codec::parse_end_stream(&end.payload).unwrap();
+        assert_eq!(end.error.unwrap().code, RpcErrorCode::Unavailable);
+        decoder.finish().unwrap();
+
+        // It returns once the stream it waited for ended.
+        server.await.unwrap().unwrap();
+    }
+
+    /// POSTs `message` to `path` over the socket at `socket`, and returns the response's body.
+    #[cfg(unix)]
+    async fn post_unix(socket: &Path, path: &str, message: &impl Message) -> Vec<u8> {
+        let body = message.encode_to_vec();
+        let mut stream = UnixStream::connect(socket).await.unwrap();
+        let head = format!(
+            "POST {path} HTTP/1.1\r\nhost: localhost\r\ncontent-type: application/proto\r\n\
+             content-length: {}\r\nconnection: close\r\n\r\n",
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await.unwrap();
+        stream.write_all(&body).await.unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).await.unwrap();
+        let end_of_head = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+        response.split_off(end_of_head + 4)
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_unix_sockets_with_the_peer_credentials() {
+        use std::os::unix::fs::MetadataExt;
+
+        async fn whoami(
+            ConnectInfo(peer): ConnectInfo<UdsConnectInfo>,
+            request: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: format!("{} is {:?}", request.name, peer.uid),
+            })
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("rpc.sock");
+        let router = RpcRouter::new().rpc_method(RpcMethod::unary(unary_info(SAY_HELLO), whoami));
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = serve_unix(&socket, router)
+            .with_graceful_shutdown(async move {
+                stopped.await.ok();
+            })
+            .bind()
+            .await
+            .unwrap();
+        let server = tokio::spawn(server.into_future());
+
+        let body = post_unix(&socket, SAY_HELLO, &hello("Ada")).await;
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+
+        // The socket is ours, so is the process connecting to it.
+        let uid = std::fs::metadata(&socket).unwrap().uid();
+        assert_eq!(
+            HelloResponse::decode(body.as_slice()).unwrap().message,
+            format!("Ada is {:?}", Some(uid))
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Ending response streams when the server shuts down or drains, so they don't hold up the drain.
+
+use std::pin::pin;
+
+use axum::http::request;
+use futures::{future::Either, Stream, StreamExt};
+use tokio::sync::watch;
+
+use crate::{
+    drain::DrainController,
+    error::{RpcError, RpcErrorCode},
+    response::RpcResult,
+};
+
+/// Turns `true` once the server starts shutting down, put in the request extensions by
+/// [`serve`](crate::serve::serve).
+#[derive(Clone)]
+pub(crate) struct ServerShutdown(pub watch::Receiver<bool>);
+
+/// What ends the response streams of a call: the server shutting down, or the grace period of
+/// its [`DrainController`] running out.
+pub(crate) struct ShutdownSignal {
+    server: Option<watch::Receiver<bool>>,
+    drain: Option<DrainController>,
+}
+
+impl ShutdownSignal {
+    pub fn from_parts(parts: &request::Parts) -> Option<Self> {
+        let server = parts
+            .extensions
+            .get::<ServerShutdown>()
+            .map(|ServerShutdown(receiver)| receiver.clone());
+        let drain = parts.extensions.get::<DrainController>().cloned();
+        (server.is_some() || drain.is_some()).then_some(Self { server, drain })
+    }
+
+    async fn wait(self) {
+        let server = async move {
+            // The sender lives as long as the server, there's no shutdown without it.
+            let shut_down = match self.server {
+                Some(mut receiver) => receiver.wait_for(|shut_down| *shut_down).await.is_ok(),
+                None => false,
+            };
+            if !shut_down {
+                futures::future::pending::<()>().await;
+            }
+        };
+        let drain = async move {
+            match self.drain {
+                Some(drain) => drain.streams_ended().await,
+                None => futures::future::pending().await,
+            }
+        };
+        futures::future::select(pin!(server), pin!(drain)).await;
+    }
+}
+
+/// Ends a response stream with `Unavailable` once the server starts shutting down, or its drain's
+/// grace period ran out, dropping the rest. Clients retry those, eg. on another instance, while
+/// the connection drains.
+pub(crate) fn limit_stream<S, T>(
+    shutdown: Option<ShutdownSignal>,
+    stream: S,
+) -> impl Stream<Item = RpcResult<T>> + Send
+where
+    S: Stream<Item = RpcResult<T>> + Send + 'static,
+    T: Send + 'static,
+{
+    let Some(shutdown) = shutdown else {
+        return Either::Left(stream);
+    };
+
+    let shut_down = Box::pin(shutdown.wait());
+    let state = Some((stream.boxed(), shut_down));
+    Either::Right(futures::stream::unfold(state, |state| async move {
+        let (mut stream, shut_down) = state?;
+        match futures::future::select(stream.next(), shut_down).await {
+            Either::Left((Some(item), shut_down)) => Some((item, Some((stream, shut_down)))),
+            Either::Left((None, _)) => None,
+            Either::Right(_) => Some((
+                Err(RpcError::new(
+                    RpcErrorCode::Unavailable,
+                    "The server is shutting down".to_string(),
+                )),
+                None,
+            )),
+        }
+    }))
+}
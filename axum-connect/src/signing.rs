@@ -0,0 +1,369 @@
+//! Signing response bodies, see [`ResponseSigner`].
+
+use std::{fmt, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use futures::StreamExt;
+
+use crate::{
+    codec::{encode_envelope, ContentType, FrameDecoder},
+    error::{RpcError, RpcErrorCode},
+    handler::codec::ResponseEncoder,
+    router::RpcMethodInfo,
+};
+
+/// Signs the bytes of responses, eg. with an HMAC a partner verifies, returning the header to send
+/// the signature in. Registered with
+/// [`RpcRouter::sign_responses`](crate::router::RpcRouter::sign_responses).
+///
+/// Unary responses, errors included, are signed over their whole body and get the header.
+/// Streams are signed frame by frame, over each message's payload without its envelope; headers
+/// are sent before the first frame, so the end-of-stream metadata gets the header instead, with a
+/// value per message frame in order. `None` leaves the response (or frame) unsigned.
+///
+/// ```
+/// # use axum::http::{HeaderName, HeaderValue};
+/// # use axum_connect::{router::RpcMethodInfo, signing::ResponseSigner};
+/// struct Checksum;
+///
+/// impl ResponseSigner for Checksum {
+///     fn sign(&self, _method: &RpcMethodInfo, body: &[u8]) -> Option<(HeaderName, HeaderValue)> {
+///         let sum = body.iter().map(|byte| *byte as u64).sum::<u64>();
+///         Some((HeaderName::from_static("x-checksum"), HeaderValue::from(sum)))
+///     }
+/// }
+/// ```
+pub trait ResponseSigner: Send + Sync + 'static {
+    fn sign(&self, method: &RpcMethodInfo, body: &[u8]) -> Option<(HeaderName, HeaderValue)>;
+}
+
+/// A [`ResponseSigner`] and which bytes of unary responses it signs.
+#[derive(Clone)]
+pub struct ResponseSigning {
+    signer: Arc<dyn ResponseSigner>,
+    after_compression: bool,
+}
+
+impl ResponseSigning {
+    /// Signs the bytes as encoded, before any compression outside the router.
+    pub fn new(signer: impl ResponseSigner) -> Self {
+        Self {
+            signer: Arc::new(signer),
+            after_compression: false,
+        }
+    }
+
+    /// Signs unary responses as sent, after compression, which is done outside the router. The
+    /// compression of [`layers::recommended`](crate::layers::recommended) signs them; with a
+    /// compression layer of your own, add [`sign_compressed`] outside it:
+    ///
+    /// ```
+    /// # use axum::{middleware, Router};
+    /// # use axum_connect::signing::sign_compressed;
+    /// # fn app(router: Router) -> Router {
+    /// router
+    ///     // .layer(my_compression_layer)
+    ///     .layer(middleware::map_response(sign_compressed))
+    /// # }
+    /// ```
+    ///
+    /// Responses that aren't compressed are signed there too. Streams are never compressed, they're
+    /// signed by the router either way.
+    pub fn after_compression(mut self) -> Self {
+        self.after_compression = true;
+        self
+    }
+}
+
+impl fmt::Debug for ResponseSigning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseSigning")
+            .field("after_compression", &self.after_compression)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A unary response left for [`sign_compressed`] to sign.
+#[derive(Clone)]
+struct PendingSignature {
+    signer: Arc<dyn ResponseSigner>,
+    info: RpcMethodInfo,
+}
+
+/// The `route_layers` middleware signing the responses of routes under
+/// [`RpcRouter::sign_responses`](crate::router::RpcRouter::sign_responses).
+pub(crate) async fn sign_responses(request: Request, next: Next) -> Response {
+    let signing = request.extensions().get::<ResponseSigning>().cloned();
+    let info = request.extensions().get::<RpcMethodInfo>().cloned();
+    let response = next.run(request).await;
+    let (Some(signing), Some(info)) = (signing, info) else {
+        return response;
+    };
+
+    if is_streaming(&response) {
+        return response.map(|body| sign_frames(body, signing.signer, info));
+    }
+    if signing.after_compression {
+        let mut response = response;
+        response.extensions_mut().insert(PendingSignature {
+            signer: signing.signer,
+            info,
+        });
+        return response;
+    }
+    sign_unary(response, &*signing.signer, &info).await
+}
+
+/// Signs the unary responses of routes signing [`after_compression`], as they are by now. A
+/// no-op for other responses.
+///
+/// [`after_compression`]: ResponseSigning::after_compression
+pub async fn sign_compressed(mut response: Response) -> Response {
+    match response.extensions_mut().remove::<PendingSignature>() {
+        Some(pending) => sign_unary(response, &*pending.signer, &pending.info).await,
+        None => response,
+    }
+}
+
+async fn sign_unary(
+    response: Response,
+    signer: &dyn ResponseSigner,
+    info: &RpcMethodInfo,
+) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            let error = RpcError::new(
+                RpcErrorCode::Internal,
+                format!("Failed to sign the response: {}", error),
+            );
+            return ResponseEncoder::error(error, false, false).encode_response();
+        }
+    };
+
+    if let Some((name, value)) = signer.sign(info, &bytes) {
+        parts.headers.insert(name, value);
+    }
+    Response::from_parts(parts, Body::from(bytes))
+}
+
+fn is_streaming(response: &Response) -> bool {
+    response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+        .is_some_and(|content_type| content_type.is_streaming())
+}
+
+/// Signs every message frame of a stream body as it passes, and adds the signatures to its
+/// end-of-stream metadata.
+fn sign_frames(body: Body, signer: Arc<dyn ResponseSigner>, info: RpcMethodInfo) -> Body {
+    let mut decoder = FrameDecoder::new();
+    let mut signatures: Option<(HeaderName, Vec<String>)> = None;
+
+    let frames = body.into_data_stream().map(move |chunk| {
+        decoder.push(&chunk?);
+        let mut bytes = vec![];
+        while let Some(frame) = decoder
+            .next_frame()
+            .map_err(|error| axum::Error::new(error.message))?
+        {
+            if !frame.is_end_stream() {
+                if let Some((name, value)) = signer.sign(&info, &frame.payload) {
+                    let (_, values) = signatures.get_or_insert_with(|| (name, vec![]));
+                    values.push(value.to_str().unwrap_or_default().to_string());
+                }
+                encode_envelope(frame.flags, &frame.payload, &mut bytes);
+                continue;
+            }
+
+            let payload = match (&signatures, frame.is_compressed()) {
+                (Some((name, values)), false) => with_metadata(&frame.payload, name, values),
+                _ => frame.payload,
+            };
+            encode_envelope(frame.flags, &payload, &mut bytes);
+        }
+        Ok::<_, axum::Error>(Bytes::from(bytes))
+    });
+
+    // A chunk ending halfway through a frame leaves nothing to send yet.
+    Body::from_stream(
+        frames.filter(|bytes| {
+            futures::future::ready(!matches!(bytes, Ok(bytes) if bytes.is_empty()))
+        }),
+    )
+}
+
+/// `payload`, an end-of-stream message, with `values` as the metadata `name`.
+fn with_metadata(payload: &[u8], name: &HeaderName, values: &[String]) -> Vec<u8> {
+    let Ok(serde_json::Value::Object(mut end)) = serde_json::from_slice(payload) else {
+        return payload.to_vec();
+    };
+
+    let metadata = end
+        .entry("metadata")
+        .or_insert_with(|| serde_json::Value::Object(Default::default()));
+    if let serde_json::Value::Object(metadata) = metadata {
+        metadata.insert(name.to_string(), values.into());
+    }
+    serde_json::to_vec(&end).unwrap_or_else(|_| payload.to_vec())
+}
+
+/// Signs with HMAC-SHA256, sending the signature base64 encoded in a header.
+///
+/// ```
+/// # use axum::http::HeaderName;
+/// # use axum_connect::signing::HmacSigner;
+/// let signer = HmacSigner::new(HeaderName::from_static("x-signature"), b"partner secret");
+/// let signature = signer.signature(b"{}");
+/// assert!(signer.verify(b"{}", &signature));
+/// ```
+#[cfg(feature = "signing")]
+#[derive(Clone)]
+pub struct HmacSigner {
+    header: HeaderName,
+    key: hmac::Hmac<sha2::Sha256>,
+}
+
+#[cfg(feature = "signing")]
+impl HmacSigner {
+    pub fn new(header: HeaderName, key: &[u8]) -> Self {
+        use hmac::Mac;
+
+        Self {
+            header,
+            // HMAC takes keys of any length.
+            key: hmac::Hmac::new_from_slice(key).unwrap(),
+        }
+    }
+
+    /// The base64 signature of `body`, the value of the header.
+    pub fn signature(&self, body: &[u8]) -> String {
+        use base64::Engine;
+        use hmac::Mac;
+
+        let mut mac = self.key.clone();
+        mac.update(body);
+        base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Checks `signature` against `body` in constant time, for clients.
+    pub fn verify(&self, body: &[u8], signature: &str) -> bool {
+        use base64::Engine;
+        use hmac::Mac;
+
+        let Ok(signature) = base64::engine::general_purpose::STANDARD.decode(signature) else {
+            return false;
+        };
+        let mut mac = self.key.clone();
+        mac.update(body);
+        mac.verify_slice(&signature).is_ok()
+    }
+}
+
+#[cfg(feature = "signing")]
+impl fmt::Debug for HmacSigner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HmacSigner")
+            .field("header", &self.header)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "signing")]
+impl ResponseSigner for HmacSigner {
+    fn sign(&self, _method: &RpcMethodInfo, body: &[u8]) -> Option<(HeaderName, HeaderValue)> {
+        let signature = HeaderValue::try_from(self.signature(body)).ok()?;
+        Some((self.header.clone(), signature))
+    }
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod tests {
+    use super::*;
+    use crate::{
+        prelude::*,
+        test_util::{
+            client, hello, hello_router, proto_request, say_hello_stream, server_stream,
+            stream_request, unary, HelloRequest, HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+        testing::TestResponse,
+    };
+
+    fn signer() -> HmacSigner {
+        HmacSigner::new(HeaderName::from_static("x-signature"), b"partner secret")
+    }
+
+    fn signature(response: &TestResponse) -> &str {
+        response.headers["x-signature"].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn signs_the_bytes_of_unary_responses() {
+        let router = hello_router().sign_responses(ResponseSigning::new(signer()));
+        let response = client(router)
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await;
+        assert!(signer().verify(&response.body, signature(&response)));
+
+        let mut tampered = response.body.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(!signer().verify(&tampered, signature(&response)));
+    }
+
+    #[tokio::test]
+    async fn signs_unary_errors() {
+        let handler = |_: HelloRequest| async move {
+            Err::<HelloResponse, _>(RpcError::new(
+                RpcErrorCode::NotFound,
+                "No such greeting".to_string(),
+            ))
+        };
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, handler))
+            .sign_responses(ResponseSigning::new(signer()));
+        let response = client(router)
+            .send(proto_request(SAY_HELLO, &hello("Ada")))
+            .await;
+        assert_eq!(response.error().unwrap().code, RpcErrorCode::NotFound);
+        assert!(signer().verify(&response.body, signature(&response)));
+    }
+
+    #[tokio::test]
+    async fn signs_each_message_of_streams_in_the_end_of_stream_metadata() {
+        let router = RpcRouter::new()
+            .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+            .sign_responses(ResponseSigning::new(signer()));
+        let response = client(router)
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+        assert!(response.headers.get("x-signature").is_none());
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&response.body);
+        let mut payloads = vec![];
+        let mut signatures: Vec<String> = vec![];
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            if !frame.is_end_stream() {
+                payloads.push(frame.payload);
+                continue;
+            }
+            let end: serde_json::Value = serde_json::from_slice(&frame.payload).unwrap();
+            signatures = serde_json::from_value(end["metadata"]["x-signature"].clone()).unwrap();
+        }
+
+        assert_eq!(payloads.len(), 3);
+        assert_eq!(signatures.len(), 3);
+        for (payload, signature) in payloads.iter().zip(&signatures) {
+            assert!(signer().verify(payload, signature));
+        }
+        assert!(!signer().verify(&payloads[0], &signatures[1]));
+    }
+}
@@ -0,0 +1,280 @@
+//! Server-Sent Events responses for server streams, for clients behind proxies that break
+//! streamed `fetch` bodies but pass `text/event-stream` through, see [`RouteOptions::sse`].
+
+use std::{pin::pin, time::Duration};
+
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use bytes::Bytes;
+use futures::{future::Either, StreamExt};
+
+use crate::{
+    codec::{self, ContentType, Frame, FrameDecoder},
+    error::{RpcError, RpcErrorCode},
+    router::{RouteOptions, RpcMethodInfo, RpcMethodKind},
+};
+
+const EVENT_STREAM: HeaderValue = HeaderValue::from_static("text/event-stream");
+
+const HEARTBEAT: &[u8] = b": heartbeat\n\n";
+
+/// The `route_layers` middleware serving the response stream as Server-Sent Events, when the
+/// route has [`RouteOptions::sse`] and the client accepts them. Requests must be
+/// `application/connect+json`, the events carry the JSON messages as they are; others get the
+/// Connect framing.
+pub(crate) async fn server_sent_events(request: Request, next: Next) -> Response {
+    let Some(heartbeat) = negotiate(&request) else {
+        return next.run(request).await;
+    };
+    // The events carry the JSON as text, not gzipped frames.
+    #[cfg(feature = "stream-compression")]
+    let request = {
+        let mut request = request;
+        request.headers_mut().remove("connect-accept-encoding");
+        request
+    };
+
+    // Errors before the handler may be `application/connect+proto`, they're only an end-of-stream
+    // frame, which is JSON.
+    let response = next.run(request).await;
+    let streaming = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+        .is_some_and(|content_type| content_type.is_streaming());
+    if !streaming {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.insert(header::CONTENT_TYPE, EVENT_STREAM);
+    parts
+        .headers
+        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, events(body, heartbeat))
+}
+
+/// The heartbeat interval, if the call is to be answered with Server-Sent Events.
+fn negotiate(request: &Request) -> Option<Duration> {
+    let heartbeat = request.extensions().get::<RouteOptions>()?.sse?;
+    let server_streaming = request
+        .extensions()
+        .get::<RpcMethodInfo>()
+        .is_some_and(|info| info.kind == Some(RpcMethodKind::ServerStreaming));
+    let json = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+        == Some(ContentType::ConnectJson);
+    let accepted = request
+        .headers()
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|accept| accept.to_str().ok())
+        .flat_map(|accept| accept.split(','))
+        .any(|media_type| {
+            let media_type = media_type.split(';').next().unwrap_or_default().trim();
+            media_type.eq_ignore_ascii_case("text/event-stream")
+        });
+    (server_streaming && json && accepted).then_some(heartbeat)
+}
+
+/// The events of a Connect stream `body`, with a heartbeat comment whenever it's been quiet for
+/// `heartbeat`. The end-of-stream frame is the last event.
+fn events(body: Body, heartbeat: Duration) -> Body {
+    let chunks = body.into_data_stream();
+    let events = futures::stream::unfold(
+        Some((chunks, FrameDecoder::new())),
+        move |state| async move {
+            let (mut chunks, mut decoder) = state?;
+            let sleep = pin!(tokio::time::sleep(heartbeat));
+            let chunk = match futures::future::select(chunks.next(), sleep).await {
+                Either::Left((Some(chunk), _)) => chunk,
+                Either::Left((None, _)) => return None,
+                Either::Right(_) => {
+                    return Some((Ok(Bytes::from_static(HEARTBEAT)), Some((chunks, decoder))));
+                }
+            };
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(error) => return Some((Err(error), None)),
+            };
+
+            decoder.push(&chunk);
+            let mut out = vec![];
+            loop {
+                match decoder.next_frame() {
+                    Ok(Some(frame)) if frame.is_end_stream() => {
+                        end_event(&frame, &mut out);
+                        return Some((Ok(out.into()), None));
+                    }
+                    Ok(Some(frame)) => event(None, &frame.payload, &mut out),
+                    Ok(None) => return Some((Ok(out.into()), Some((chunks, decoder)))),
+                    Err(error) => return Some((Err(axum::Error::new(error.message)), None)),
+                }
+            }
+        },
+    );
+
+    // A chunk ending halfway through a frame leaves nothing to send yet.
+    Body::from_stream(
+        events.filter(|bytes| {
+            futures::future::ready(!matches!(bytes, Ok(bytes) if bytes.is_empty()))
+        }),
+    )
+}
+
+/// `event: error` with the error as unary error JSON, or `event: end` with the end-of-stream JSON,
+/// its trailing metadata included.
+fn end_event(frame: &Frame, out: &mut Vec<u8>) {
+    match codec::parse_end_stream(&frame.payload) {
+        Ok(end) => match end.error {
+            Some(error) => event(Some("error"), &codec::encode_unary_error(&error), out),
+            None => event(Some("end"), &frame.payload, out),
+        },
+        Err(error) => {
+            let error = RpcError::new(
+                RpcErrorCode::Internal,
+                format!("Invalid end-of-stream frame: {}", error),
+            );
+            event(Some("error"), &codec::encode_unary_error(&error), out);
+        }
+    }
+}
+
+fn event(name: Option<&str>, data: &[u8], out: &mut Vec<u8>) {
+    if let Some(name) = name {
+        out.extend_from_slice(format!("event: {}\n", name).as_bytes());
+    }
+    // JSON only has line breaks between tokens, each line of it is a `data` line of its own.
+    for line in data.split(|byte| *byte == b'\n') {
+        out.extend_from_slice(b"data: ");
+        out.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+        out.push(b'\n');
+    }
+    out.push(b'\n');
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use axum::http::{Request, StatusCode};
+    use futures::{stream, Stream};
+
+    use super::*;
+    use crate::{
+        codec::encode_envelope,
+        response::RpcResult,
+        router::RpcRouter,
+        test_util::{
+            client, say_hello_stream, server_stream, HelloRequest, HelloResponse, SAY_HELLO_STREAM,
+        },
+        testing::{TestClient, TestResponse},
+    };
+
+    fn sse_client<F, S>(handler: F) -> TestClient
+    where
+        F: Fn(HelloRequest) -> S + Clone + Send + Sync + 'static,
+        S: std::future::Future + Send + 'static,
+        S::Output: Stream<Item = RpcResult<HelloResponse>> + Send + 'static,
+    {
+        let options = RouteOptions::new().sse(Duration::from_secs(15));
+        client(RpcRouter::new().rpc_with_options(options, |router| {
+            router.rpc_method(server_stream(SAY_HELLO_STREAM, handler))
+        }))
+    }
+
+    async fn call(client: &TestClient, accept: Option<&str>) -> TestResponse {
+        let mut body = vec![];
+        encode_envelope(0, br#"{"name":"Ada"}"#, &mut body);
+        let mut request = Request::post(SAY_HELLO_STREAM)
+            .header(header::CONTENT_TYPE, "application/connect+json");
+        if let Some(accept) = accept {
+            request = request.header(header::ACCEPT, accept);
+        }
+        client.send(request.body(Body::from(body)).unwrap()).await
+    }
+
+    #[tokio::test]
+    async fn serves_the_stream_as_events() {
+        let client = sse_client(say_hello_stream);
+
+        let response = call(&client, Some("text/event-stream")).await;
+
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.content_type(), Some("text/event-stream"));
+        assert_eq!(response.headers[header::CACHE_CONTROL], "no-cache");
+        assert_eq!(
+            std::str::from_utf8(&response.body).unwrap(),
+            "data: {\"message\":\"Hello Ada #1!\"}\n\n\
+             data: {\"message\":\"Hello Ada #2!\"}\n\n\
+             data: {\"message\":\"Hello Ada #3!\"}\n\n\
+             event: end\ndata: {}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_failed_streams_with_an_error_event() {
+        let client = sse_client(|_: HelloRequest| async {
+            stream::iter([
+                Ok(HelloResponse {
+                    message: "Hello!".to_string(),
+                }),
+                Err(RpcError::new(
+                    RpcErrorCode::Unavailable,
+                    "Upstream went away".to_string(),
+                )),
+            ])
+        });
+
+        let response = call(&client, Some("text/event-stream")).await;
+
+        assert_eq!(
+            std::str::from_utf8(&response.body).unwrap(),
+            "data: {\"message\":\"Hello!\"}\n\n\
+             event: error\ndata: {\"code\":\"unavailable\",\"message\":\"Upstream went away\"}\n\n"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_heartbeats_while_the_stream_is_quiet() {
+        let client = sse_client(|_: HelloRequest| async {
+            stream::iter(["first", "second"]).then(|message| async move {
+                if message == "second" {
+                    tokio::time::sleep(Duration::from_secs(40)).await;
+                }
+                Ok(HelloResponse {
+                    message: message.to_string(),
+                })
+            })
+        });
+
+        let response = call(&client, Some("text/event-stream")).await;
+
+        assert_eq!(
+            std::str::from_utf8(&response.body).unwrap(),
+            "data: {\"message\":\"first\"}\n\n\
+             : heartbeat\n\n\
+             : heartbeat\n\n\
+             data: {\"message\":\"second\"}\n\n\
+             event: end\ndata: {}\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn frames_the_stream_for_other_clients() {
+        let client = sse_client(say_hello_stream);
+
+        let response = call(&client, None).await;
+
+        assert_eq!(response.content_type(), Some("application/connect+json"));
+        let (frames, error) = response.frames();
+        assert_eq!(frames.len(), 3);
+        assert!(error.is_none());
+    }
+}
@@ -0,0 +1,788 @@
+//! Response streams for the common sources, to return from server-streaming handlers.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use axum::http::HeaderMap;
+use futures::{stream, Stream, StreamExt};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    error::{RpcError, RpcErrorCode, RpcIntoError},
+    parts::RpcTrailers,
+    response::RpcResult,
+};
+
+/// What [`RpcStream::from_broadcast`] and [`SharedStream`] do when the receiver falls behind and
+/// misses messages. Connect streams end at their first error, so the client can't be told and
+/// carry on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LagPolicy {
+    /// End the stream with a `DataLoss` error.
+    #[default]
+    Fail,
+    /// Carry on from the oldest message still available.
+    Skip,
+    /// End the stream with a `ResourceExhausted` error, for consumers too slow to be served.
+    Disconnect,
+}
+
+/// A boxed stream of response messages (or an error ending the stream), which can be returned as-is
+/// from a server-streaming handler.
+///
+/// ```
+/// # use axum_connect::{prelude::*, stream::{LagPolicy, RpcStream}};
+/// # use tokio::sync::broadcast;
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Event {}
+/// async fn watch(events: broadcast::Receiver<Event>) -> RpcStream<Event> {
+///     RpcStream::from_broadcast(events, LagPolicy::Skip)
+/// }
+/// ```
+pub struct RpcStream<M> {
+    inner: Pin<Box<dyn Stream<Item = RpcResult<M>> + Send>>,
+}
+
+impl<M: Send + 'static> RpcStream<M> {
+    /// Every message sent on the channel from now on, until it's closed.
+    pub fn from_broadcast(receiver: broadcast::Receiver<M>, lag: LagPolicy) -> Self
+    where
+        M: Clone,
+    {
+        Self::new(stream::unfold(Some(receiver), move |receiver| async move {
+            let mut receiver = receiver?;
+            loop {
+                match receiver.recv().await {
+                    Ok(message) => return Some((Ok(message), Some(receiver))),
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                    Err(broadcast::error::RecvError::Lagged(_)) if lag == LagPolicy::Skip => {}
+                    Err(broadcast::error::RecvError::Lagged(missed)) if lag == LagPolicy::Fail => {
+                        let error = RpcError::new(
+                            RpcErrorCode::DataLoss,
+                            format!("The stream fell behind and missed {} messages", missed),
+                        );
+                        return Some((Err(error), None));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        let error = RpcError::new(
+                            RpcErrorCode::ResourceExhausted,
+                            format!(
+                                "The stream was disconnected for falling behind by {} messages",
+                                missed
+                            ),
+                        );
+                        return Some((Err(error), None));
+                    }
+                }
+            }
+        }))
+    }
+
+    /// Every message sent on the channel, until all senders are dropped.
+    pub fn from_mpsc(receiver: mpsc::Receiver<M>) -> Self {
+        Self::new(stream::unfold(receiver, |mut receiver| async move {
+            let message = receiver.recv().await?;
+            Some((Ok(message), receiver))
+        }))
+    }
+
+    /// Converts the errors of `stream` with [`RpcIntoError`]. The first error ends the response.
+    pub fn from_try_stream<S, E>(stream: S) -> Self
+    where
+        S: Stream<Item = Result<M, E>> + Send + 'static,
+        E: RpcIntoError,
+    {
+        Self::new(stream.map(|item| item.map_err(RpcIntoError::rpc_into_error)))
+    }
+
+    /// A stream of a single message.
+    pub fn once(message: M) -> Self {
+        Self::new(stream::once(async move { Ok(message) }))
+    }
+
+    /// A stream without messages, the response only has its end-of-stream.
+    pub fn empty() -> Self {
+        Self::new(stream::empty())
+    }
+
+    fn new(stream: impl Stream<Item = RpcResult<M>> + Send + 'static) -> Self {
+        Self {
+            inner: Box::pin(stream),
+        }
+    }
+}
+
+impl<M> Stream for RpcStream<M> {
+    type Item = RpcResult<M>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.as_mut().poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// One upstream stream fanned out to many response streams, eg. for a `Watch` RPC where every
+/// client follows the same events. The upstream is driven by a task of its own, so it's only
+/// subscribed to once; subscribers get the messages from when they subscribe, each with a queue
+/// of `capacity` messages, and what a full queue does is up to the [`LagPolicy`]. Every response
+/// stream ends when the upstream does, after its queue is drained.
+///
+/// ```
+/// # use axum::extract::State;
+/// # use axum_connect::{prelude::*, stream::{LagPolicy, RpcStream, SharedStream}};
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Event {}
+/// # fn events() -> impl futures::Stream<Item = Event> + Send + 'static { futures::stream::empty() }
+/// # async fn setup() {
+/// let shared = SharedStream::new(events(), 64, LagPolicy::Disconnect);
+///
+/// async fn watch(State(shared): State<SharedStream<Event>>) -> RpcStream<Event> {
+///     shared.subscribe()
+/// }
+/// # }
+/// ```
+pub struct SharedStream<T> {
+    /// Kept to subscribe from, it never reads.
+    receiver: broadcast::Receiver<T>,
+    lag: LagPolicy,
+}
+
+impl<T: Clone + Send + 'static> SharedStream<T> {
+    /// Starts driving `upstream`, which must be done within a Tokio runtime. It's dropped when it
+    /// ends, or once the `SharedStream` and every subscriber are gone.
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is 0.
+    pub fn new<S>(upstream: S, capacity: usize, lag: LagPolicy) -> Self
+    where
+        S: Stream<Item = T> + Send + 'static,
+    {
+        let (sender, receiver) = broadcast::channel(capacity);
+        tokio::spawn(async move {
+            let mut upstream = std::pin::pin!(upstream);
+            while let Some(message) = upstream.next().await {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self { receiver, lag }
+    }
+
+    /// A response stream of the upstream's messages from now on.
+    pub fn subscribe(&self) -> RpcStream<T> {
+        RpcStream::from_broadcast(self.receiver.resubscribe(), self.lag)
+    }
+}
+
+impl<T: Clone> Clone for SharedStream<T> {
+    fn clone(&self) -> Self {
+        Self {
+            receiver: self.receiver.resubscribe(),
+            lag: self.lag,
+        }
+    }
+}
+
+/// A response stream of progress updates ending with the result of an operation, for long-running
+/// calls whose response message is a oneof of the two. What the receiver gets is sent as it comes,
+/// then when `result` resolves, the updates already in the channel and the result last, or the
+/// error ending the stream if it failed. Later updates are dropped.
+///
+/// ```
+/// # use axum_connect::{prelude::*, stream::ProgressStream};
+/// # use tokio::sync::mpsc;
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct TranscodeRequest {}
+/// #[derive(Clone, PartialEq, prost::Message)]
+/// struct TranscodeEvent {
+///     #[prost(oneof = "Event", tags = "1, 2")]
+///     event: Option<Event>,
+/// }
+///
+/// #[derive(Clone, PartialEq, prost::Oneof)]
+/// enum Event {
+///     #[prost(uint32, tag = "1")]
+///     Percent(u32),
+///     #[prost(string, tag = "2")]
+///     Url(String),
+/// }
+///
+/// impl From<Event> for TranscodeEvent {
+///     fn from(event: Event) -> Self {
+///         Self { event: Some(event) }
+///     }
+/// }
+///
+/// async fn transcode(request: TranscodeRequest) -> ProgressStream<TranscodeEvent, Event> {
+///     let (progress, updates) = mpsc::channel(16);
+///     ProgressStream::new(run(request, progress)).with_progress(updates)
+/// }
+///
+/// async fn run(request: TranscodeRequest, progress: mpsc::Sender<Event>) -> RpcResult<Event> {
+///     for percent in [25, 50, 75] {
+///         let _ = progress.send(Event::Percent(percent)).await;
+///     }
+///     Ok(Event::Url("https://example.com/video.mp4".to_string()))
+/// }
+/// ```
+pub struct ProgressStream<M, P = M> {
+    result: Option<Pin<Box<dyn Future<Output = RpcResult<M>> + Send>>>,
+    progress: Option<mpsc::Receiver<P>>,
+    /// The result, once it's resolved, held back until the updates before it are sent.
+    done: Option<RpcResult<M>>,
+}
+
+impl<M: Send + 'static> ProgressStream<M> {
+    /// A stream of only `result`, until [`with_progress`](ProgressStream::with_progress). Errors
+    /// convert through [`RpcIntoError`].
+    pub fn new<F, T, E>(result: F) -> Self
+    where
+        F: Future<Output = Result<T, E>> + Send + 'static,
+        T: Into<M>,
+        E: RpcIntoError,
+    {
+        Self {
+            result: Some(Box::pin(async move {
+                result
+                    .await
+                    .map(Into::into)
+                    .map_err(RpcIntoError::rpc_into_error)
+            })),
+            progress: None,
+            done: None,
+        }
+    }
+}
+
+impl<M, P> ProgressStream<M, P> {
+    /// Sends the updates from `progress` until the result.
+    pub fn with_progress<Q: Into<M>>(self, progress: mpsc::Receiver<Q>) -> ProgressStream<M, Q> {
+        ProgressStream {
+            result: self.result,
+            progress: Some(progress),
+            done: self.done,
+        }
+    }
+}
+
+// Nothing is pinned in place, the future is boxed.
+impl<M, P> Unpin for ProgressStream<M, P> {}
+
+impl<M, P: Into<M>> Stream for ProgressStream<M, P> {
+    type Item = RpcResult<M>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(result) = &mut this.result {
+            if let Poll::Ready(result) = result.as_mut().poll(cx) {
+                this.result = None;
+                this.done = Some(result);
+                // What's buffered is still received, the senders can't add to it.
+                if let Some(progress) = &mut this.progress {
+                    progress.close();
+                }
+            }
+        }
+
+        if let Some(progress) = &mut this.progress {
+            match progress.poll_recv(cx) {
+                Poll::Ready(Some(update)) => return Poll::Ready(Some(Ok(update.into()))),
+                Poll::Ready(None) => this.progress = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        match this.done.take() {
+            Some(result) => Poll::Ready(Some(result)),
+            // The future was polled above, it wakes the task.
+            None if this.result.is_some() => Poll::Pending,
+            None => Poll::Ready(None),
+        }
+    }
+}
+
+/// A channel for pushing the messages of a response stream, for producers that call back rather
+/// than return a stream, eg. an SDK invoking a closure per event. Return the [`RpcStream`] from
+/// the handler and move the [`RpcStreamSender`] wherever messages come from, eg. a spawned task.
+///
+/// The stream ends when every sender is dropped or closed, or after the first error. It buffers
+/// `buffer` messages: once it's full, [`send`](RpcStreamSender::send) waits for the client to
+/// read, so a slow one slows the producer down instead of piling messages up.
+///
+/// ```
+/// # use axum_connect::{prelude::*, stream::{rpc_stream_channel, RpcStream}};
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct WatchRequest {}
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Event {}
+/// async fn watch(_request: WatchRequest) -> RpcStream<Event> {
+///     let (sender, stream) = rpc_stream_channel(16);
+///     tokio::spawn(async move {
+///         for _ in 0..3 {
+///             if sender.send(Event {}).await.is_err() {
+///                 // The client went away.
+///                 return;
+///             }
+///         }
+///     });
+///     stream
+/// }
+/// ```
+///
+/// # Panics
+///
+/// If `buffer` is 0.
+pub fn rpc_stream_channel<M: Send + 'static>(buffer: usize) -> (RpcStreamSender<M>, RpcStream<M>) {
+    let (sender, receiver) = mpsc::channel(buffer);
+    let stream = RpcStream::new(stream::unfold(
+        Some(receiver),
+        |receiver: Option<mpsc::Receiver<RpcResult<M>>>| async move {
+            let mut receiver = receiver?;
+            match receiver.recv().await? {
+                Ok(message) => Some((Ok(message), Some(receiver))),
+                Err(error) => Some((Err(error), None)),
+            }
+        },
+    ));
+
+    let sender = RpcStreamSender {
+        sender,
+        trailers: RpcTrailers::current(),
+    };
+    (sender, stream)
+}
+
+/// The sending half of [`rpc_stream_channel`]. Clones send to the same stream.
+pub struct RpcStreamSender<M> {
+    sender: mpsc::Sender<RpcResult<M>>,
+    /// The trailers of the call the channel was made in, `None` outside of a handler.
+    trailers: Option<RpcTrailers>,
+}
+
+impl<M> RpcStreamSender<M> {
+    /// Sends `message`, waiting for room in the buffer. Fails if the response stream was dropped,
+    /// eg. because the client went away, so the producer can stop.
+    pub async fn send(&self, message: M) -> Result<(), RpcStreamClosed> {
+        self.sender
+            .send(Ok(message))
+            .await
+            .map_err(|_| RpcStreamClosed)
+    }
+
+    /// Ends the stream with `error`, after the messages already sent. Connect streams end at their
+    /// first error, so what other senders send afterwards is dropped.
+    pub async fn send_error(self, error: impl RpcIntoError) -> Result<(), RpcStreamClosed> {
+        self.sender
+            .send(Err(error.rpc_into_error()))
+            .await
+            .map_err(|_| RpcStreamClosed)
+    }
+
+    /// Adds `metadata` to the trailers of the call, sent in its end-of-stream message, and closes
+    /// this sender. The metadata is dropped if the channel wasn't made in a server-streaming
+    /// handler (eg. in a spawned task), take [`RpcTrailers`] there instead.
+    pub fn close_with_metadata(self, metadata: HeaderMap) {
+        if let Some(trailers) = &self.trailers {
+            let mut key = None;
+            for (name, value) in metadata {
+                key = name.or(key);
+                if let Some(key) = &key {
+                    trailers.append(key.clone(), value);
+                }
+            }
+        }
+    }
+
+    /// Whether the response stream was dropped, eg. because the client went away.
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+impl<M> Clone for RpcStreamSender<M> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            trailers: self.trailers.clone(),
+        }
+    }
+}
+
+impl<M> fmt::Debug for RpcStreamSender<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcStreamSender")
+            .field("closed", &self.is_closed())
+            .finish_non_exhaustive()
+    }
+}
+
+/// The response stream of an [`RpcStreamSender`] was dropped, nothing more will be sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RpcStreamClosed;
+
+impl fmt::Display for RpcStreamClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the response stream was dropped")
+    }
+}
+
+impl std::error::Error for RpcStreamClosed {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{
+        channel::mpsc::{unbounded, UnboundedSender},
+        FutureExt,
+    };
+
+    use super::*;
+    use crate::{
+        router::RpcRouter,
+        test_util::{
+            client, hello, messages, server_stream, stream_request, HelloRequest, HelloResponse,
+            SAY_HELLO_STREAM,
+        },
+    };
+
+    /// Lets the upstream's task forward what's been sent.
+    async fn settle() {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+
+    fn shared(capacity: usize, lag: LagPolicy) -> (UnboundedSender<u32>, SharedStream<u32>) {
+        let (upstream, receiver) = unbounded();
+        (upstream, SharedStream::new(receiver, capacity, lag))
+    }
+
+    async fn send(upstream: &UnboundedSender<u32>, messages: impl IntoIterator<Item = u32>) {
+        for message in messages {
+            upstream.unbounded_send(message).unwrap();
+        }
+        settle().await;
+    }
+
+    async fn received(stream: RpcStream<u32>) -> (Vec<u32>, Option<RpcError>) {
+        let mut messages = vec![];
+        let mut stream = std::pin::pin!(stream);
+        while let Some(item) = stream.next().await {
+            match item {
+                Ok(message) => messages.push(message),
+                Err(error) => return (messages, Some(error)),
+            }
+        }
+        (messages, None)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_every_message_to_every_subscriber() {
+        let (upstream, shared) = shared(8, LagPolicy::Fail);
+        let (first, second) = (shared.subscribe(), shared.clone().subscribe());
+
+        send(&upstream, [1, 2, 3]).await;
+        drop(upstream);
+
+        assert_eq!(received(first).await, (vec![1, 2, 3], None));
+        assert_eq!(received(second).await, (vec![1, 2, 3], None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_subscribers_the_messages_from_when_they_subscribe() {
+        let (upstream, shared) = shared(8, LagPolicy::Fail);
+        let early = shared.subscribe();
+
+        send(&upstream, [1, 2]).await;
+        let late = shared.subscribe();
+        send(&upstream, [3]).await;
+        drop(upstream);
+
+        assert_eq!(received(early).await.0, [1, 2, 3]);
+        assert_eq!(received(late).await.0, [3]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn leaves_subscribers_keeping_up_unaffected_by_slow_ones() {
+        let (upstream, shared) = shared(2, LagPolicy::Fail);
+        let (mut fast, slow) = (shared.subscribe(), shared.subscribe());
+
+        let mut read = vec![];
+        for message in 1..=5 {
+            send(&upstream, [message]).await;
+            read.push(fast.next().await.unwrap().unwrap());
+        }
+        drop(upstream);
+
+        assert_eq!(read, [1, 2, 3, 4, 5]);
+        assert!(fast.next().await.is_none());
+        let (messages, error) = received(slow).await;
+        assert!(messages.is_empty());
+        let error = error.unwrap();
+        assert_eq!(error.code, RpcErrorCode::DataLoss);
+        assert_eq!(
+            error.message,
+            "The stream fell behind and missed 3 messages"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_to_the_oldest_message_left_for_slow_subscribers() {
+        let (upstream, shared) = shared(2, LagPolicy::Skip);
+        let slow = shared.subscribe();
+
+        send(&upstream, 1..=5).await;
+        drop(upstream);
+
+        assert_eq!(received(slow).await, (vec![4, 5], None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn disconnects_slow_subscribers() {
+        let (upstream, shared) = shared(2, LagPolicy::Disconnect);
+        let slow = shared.subscribe();
+
+        send(&upstream, 1..=5).await;
+
+        let (messages, error) = received(slow).await;
+        assert!(messages.is_empty());
+        assert_eq!(error.unwrap().code, RpcErrorCode::ResourceExhausted);
+        // The others carry on.
+        let next = shared.subscribe();
+        send(&upstream, [6]).await;
+        drop(upstream);
+        assert_eq!(received(next).await.0, [6]);
+    }
+
+    fn greeting(message: &str) -> HelloResponse {
+        HelloResponse {
+            message: message.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_a_channel_until_its_senders_are_gone() {
+        async fn greetings(request: HelloRequest) -> RpcStream<HelloResponse> {
+            let (sender, receiver) = mpsc::channel(1);
+            tokio::spawn(async move {
+                for greeting in ["Hello", "Hi"] {
+                    let message = format!("{greeting} {}!", request.name);
+                    sender.send(HelloResponse { message }).await.unwrap();
+                }
+            });
+            RpcStream::from_mpsc(receiver)
+        }
+        let client =
+            client(RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, greetings)));
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        assert_eq!(
+            messages::<HelloResponse>(&response),
+            [greeting("Hello Ada!"), greeting("Hi Ada!")]
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_fallible_streams_at_their_first_error() {
+        async fn lookups(_: HelloRequest) -> RpcStream<HelloResponse> {
+            RpcStream::from_try_stream(stream::iter([
+                Ok(greeting("found")),
+                Err((RpcErrorCode::NotFound, "missing")),
+                Ok(greeting("never sent")),
+            ]))
+        }
+        let client = client(RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, lookups)));
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        let (frames, error) = response.frames();
+        assert_eq!(frames.len(), 1);
+        let error = error.unwrap();
+        assert_eq!(error.code, RpcErrorCode::NotFound);
+        assert_eq!(error.message, "missing");
+    }
+
+    async fn received_messages(stream: RpcStream<HelloResponse>) -> Vec<HelloResponse> {
+        stream.map(Result::unwrap).collect().await
+    }
+
+    #[tokio::test]
+    async fn streams_one_message_or_none() {
+        assert_eq!(
+            received_messages(RpcStream::once(greeting("only"))).await,
+            [greeting("only")]
+        );
+        assert!(received_messages(RpcStream::<HelloResponse>::empty())
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn streams_what_senders_push_until_an_error() {
+        async fn pushed(request: HelloRequest) -> RpcStream<HelloResponse> {
+            let (sender, stream) = rpc_stream_channel(1);
+            tokio::spawn(async move {
+                for n in 1..=2 {
+                    let message = format!("Hello {} #{n}!", request.name);
+                    sender.send(HelloResponse { message }).await.unwrap();
+                }
+                let _ = sender
+                    .send_error((RpcErrorCode::Aborted, "Producer gave up"))
+                    .await;
+            });
+            stream
+        }
+        let client = client(RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, pushed)));
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        let (frames, error) = response.frames();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(error.unwrap().message, "Producer gave up");
+    }
+
+    #[tokio::test]
+    async fn sends_the_metadata_senders_close_with_as_trailers() {
+        async fn counted(_: HelloRequest) -> RpcStream<HelloResponse> {
+            let (sender, stream) = rpc_stream_channel(4);
+            sender.send(greeting("one")).await.unwrap();
+            let mut metadata = HeaderMap::new();
+            metadata.insert("x-count", "1".parse().unwrap());
+            sender.close_with_metadata(metadata);
+            stream
+        }
+        let client = client(RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, counted)));
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        assert_eq!(messages::<HelloResponse>(&response), [greeting("one")]);
+        let mut decoder = crate::codec::FrameDecoder::new();
+        decoder.push(&response.body);
+        let mut end = None;
+        while let Some(frame) = decoder.next_frame().unwrap() {
+            end = Some(frame.payload);
+        }
+        assert_eq!(end.unwrap(), br#"{"metadata":{"x-count":["1"]}}"#);
+    }
+
+    #[tokio::test]
+    async fn waits_for_room_and_stops_once_the_stream_is_dropped() {
+        let (sender, mut stream) = rpc_stream_channel(1);
+
+        sender.send(1).await.unwrap();
+        // The buffer is full until the stream is read.
+        assert!(sender.send(2).now_or_never().is_none());
+        assert_eq!(stream.next().await.unwrap().unwrap(), 1);
+        sender.send(2).await.unwrap();
+
+        drop(stream);
+        assert!(sender.is_closed());
+        assert_eq!(sender.send(3).await, Err(RpcStreamClosed));
+    }
+
+    #[tokio::test]
+    async fn sends_progress_as_it_comes_and_the_result_last() {
+        let (finish, finished) = tokio::sync::oneshot::channel::<u32>();
+        let (progress, updates) = mpsc::channel::<u32>(4);
+        let result = finished
+            .map(|result| result.map_err(|_| RpcError::new(RpcErrorCode::Aborted, String::new())));
+        let mut stream = ProgressStream::<u32>::new(result).with_progress(updates);
+
+        assert!(stream.next().now_or_never().is_none());
+        progress.send(25).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), 25);
+        progress.send(50).await.unwrap();
+        progress.send(75).await.unwrap();
+        finish.send(100).unwrap();
+
+        // Updates sent before the result still come first, later ones are dropped.
+        assert_eq!(stream.next().await.unwrap().unwrap(), 50);
+        assert!(progress.send(99).await.is_err());
+        assert_eq!(stream.next().await.unwrap().unwrap(), 75);
+        assert_eq!(stream.next().await.unwrap().unwrap(), 100);
+        assert!(stream.next().await.is_none());
+    }
+
+    async fn transcode(request: HelloRequest, fail: bool) -> ProgressStream<HelloResponse> {
+        let (progress, updates) = mpsc::channel(16);
+        ProgressStream::new(async move {
+            for percent in [25, 50, 75] {
+                let _ = progress.send(greeting(&format!("{percent}%"))).await;
+            }
+            match fail {
+                true => Err(RpcError::new(
+                    RpcErrorCode::DataLoss,
+                    "The source is corrupt".to_string(),
+                )),
+                false => Ok(greeting(&format!("{} done", request.name))),
+            }
+        })
+        .with_progress(updates)
+    }
+
+    #[tokio::test]
+    async fn streams_progress_then_the_result_to_the_client() {
+        async fn succeed(request: HelloRequest) -> ProgressStream<HelloResponse> {
+            transcode(request, false).await
+        }
+        let client = client(RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, succeed)));
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        assert_eq!(
+            messages::<HelloResponse>(&response),
+            [
+                greeting("25%"),
+                greeting("50%"),
+                greeting("75%"),
+                greeting("Ada done")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn ends_the_stream_with_the_error_of_a_failed_result() {
+        async fn fail(request: HelloRequest) -> ProgressStream<HelloResponse> {
+            transcode(request, true).await
+        }
+        let client = client(RpcRouter::new().rpc_method(server_stream(SAY_HELLO_STREAM, fail)));
+
+        let response = client
+            .send(stream_request(SAY_HELLO_STREAM, &hello("Ada")))
+            .await;
+
+        let (frames, error) = response.frames();
+        let updates: Vec<HelloResponse> = frames
+            .iter()
+            .map(|frame| prost::Message::decode(frame.as_slice()).unwrap())
+            .collect();
+        assert_eq!(updates, [greeting("25%"), greeting("50%"), greeting("75%")]);
+        assert_eq!(
+            error,
+            Some(RpcError::new(
+                RpcErrorCode::DataLoss,
+                "The source is corrupt".to_string()
+            ))
+        );
+    }
+}
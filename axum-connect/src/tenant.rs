@@ -0,0 +1,501 @@
+//! Resolving the tenant of a request in multi-tenant deployments, see [`Tenant`].
+
+use std::{fmt, future::Future};
+
+use axum::{
+    extract::FromRef,
+    http::{self, header, HeaderName},
+};
+use prost::Message;
+
+use crate::{
+    error::{RpcError, RpcErrorCode},
+    parts::RpcFromRequestParts,
+};
+
+/// The longest tenant id, that of a DNS label.
+const MAX_ID_LEN: usize = 63;
+
+/// Where a request's tenant id comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TenantStrategy {
+    /// A header, eg. `x-tenant-id`.
+    Header(HeaderName),
+    /// The subdomain of `base_domain` in the `Host`, eg. `acme` for `acme.example.com` with a base
+    /// domain of `example.com`. Only a single label is allowed, and `Forwarded` headers aren't
+    /// trusted, a proxy in front has to set the `Host` itself.
+    Subdomain { base_domain: String },
+    /// The first of these strategies that finds an id in the request.
+    FirstOf(Vec<TenantStrategy>),
+}
+
+impl TenantStrategy {
+    /// The `x-tenant-id` header.
+    pub fn header() -> Self {
+        Self::Header(HeaderName::from_static("x-tenant-id"))
+    }
+
+    pub fn subdomain(base_domain: &str) -> Self {
+        Self::Subdomain {
+            base_domain: base_domain.trim_matches('.').to_ascii_lowercase(),
+        }
+    }
+
+    /// The tenant id of the request, `Ok(None)` if it doesn't name one.
+    fn id(&self, parts: &http::request::Parts) -> Result<Option<String>, RpcError> {
+        match self {
+            Self::Header(name) => match parts.headers.get(name) {
+                Some(value) => match value.to_str() {
+                    Ok(id) => Ok(Some(id.trim().to_string())),
+                    Err(_) => Err(malformed(&format!("The {} header isn't ASCII", name))),
+                },
+                None => Ok(None),
+            },
+            Self::Subdomain { base_domain } => {
+                let host = parts
+                    .headers
+                    .get(header::HOST)
+                    .and_then(|v| v.to_str().ok())
+                    .or_else(|| parts.uri.host());
+                let Some(host) = host else {
+                    return Ok(None);
+                };
+
+                let host = host_without_port(host)
+                    .trim_end_matches('.')
+                    .to_ascii_lowercase();
+                let Some(subdomain) = host
+                    .strip_suffix(base_domain.as_str())
+                    .and_then(|rest| rest.strip_suffix('.'))
+                else {
+                    return Ok(None);
+                };
+                if subdomain.contains('.') {
+                    return Err(malformed(&format!(
+                        "{} isn't a single subdomain of {}",
+                        host, base_domain
+                    )));
+                }
+                Ok(Some(subdomain.to_string()))
+            }
+            Self::FirstOf(strategies) => {
+                for strategy in strategies {
+                    if let Some(id) = strategy.id(parts)? {
+                        return Ok(Some(id));
+                    }
+                }
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl fmt::Display for TenantStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Header(name) => write!(f, "the {} header", name),
+            Self::Subdomain { base_domain } => write!(f, "a subdomain of {}", base_domain),
+            Self::FirstOf(strategies) => {
+                let strategies = strategies.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+                f.write_str(&strategies.join(" or "))
+            }
+        }
+    }
+}
+
+/// Looks up tenants for [`Tenant`], taken from the router state with `FromRef`.
+pub trait TenantResolver: Send + Sync + 'static {
+    type Tenant: Clone + Send + Sync + 'static;
+
+    /// Where tenant ids are taken from.
+    fn strategy(&self) -> TenantStrategy;
+
+    /// The tenant with the id, `None` if there's no such tenant.
+    fn resolve(&self, id: &str) -> impl Future<Output = Option<Self::Tenant>> + Send;
+}
+
+/// The id of the request's tenant, inserted into the request extensions by [`Tenant`] for the
+/// extractors after it, eg. to label metrics.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TenantId(pub String);
+
+/// The request's tenant, as resolved by the `R` in the router state.
+///
+/// Ids are 1 to 63 ASCII letters, digits, `-` or `_`; anything else (or no id at all) fails with
+/// `InvalidArgument`, and ids the resolver doesn't know with `NotFound`. The tenant and its
+/// [`TenantId`] are inserted into the request extensions, a second `Tenant` in the same handler
+/// reuses them.
+///
+/// ```
+/// # use std::collections::HashMap;
+/// # use axum::extract::FromRef;
+/// # use axum_connect::{prelude::*, tenant::{Tenant, TenantResolver, TenantStrategy}};
+/// # #[derive(Clone, PartialEq, prost::Message)]
+/// # struct Empty {}
+/// #[derive(Clone)]
+/// struct Tenants(HashMap<String, String>);
+///
+/// impl TenantResolver for Tenants {
+///     type Tenant = String;
+///
+///     fn strategy(&self) -> TenantStrategy {
+///         TenantStrategy::FirstOf(vec![
+///             TenantStrategy::header(),
+///             TenantStrategy::subdomain("example.com"),
+///         ])
+///     }
+///
+///     async fn resolve(&self, id: &str) -> Option<String> {
+///         self.0.get(id).cloned()
+///     }
+/// }
+///
+/// #[derive(Clone)]
+/// struct AppState {
+///     tenants: Tenants,
+/// }
+///
+/// impl FromRef<AppState> for Tenants {
+///     fn from_ref(state: &AppState) -> Self {
+///         state.tenants.clone()
+///     }
+/// }
+///
+/// async fn whoami(Tenant(database): Tenant<Tenants>, _: Empty) -> RpcResult<Empty> {
+///     println!("using {}", database);
+///     Ok(Empty {})
+/// }
+/// ```
+pub struct Tenant<R: TenantResolver>(pub R::Tenant);
+
+impl<R: TenantResolver> Clone for Tenant<R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<R> fmt::Debug for Tenant<R>
+where
+    R: TenantResolver,
+    R::Tenant: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Tenant").field(&self.0).finish()
+    }
+}
+
+/// The tenant resolved by an earlier `Tenant<R>` of the same request.
+struct ResolvedTenant<R: TenantResolver>(R::Tenant);
+
+impl<R: TenantResolver> Clone for ResolvedTenant<R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<M, S, R> RpcFromRequestParts<M, S> for Tenant<R>
+where
+    M: Message,
+    S: Send + Sync,
+    R: TenantResolver + FromRef<S>,
+{
+    type Rejection = RpcError;
+
+    async fn rpc_from_request_parts(
+        parts: &mut http::request::Parts,
+        state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(ResolvedTenant(tenant)) = parts.extensions.get::<ResolvedTenant<R>>() {
+            return Ok(Self(tenant.clone()));
+        }
+
+        let resolver = R::from_ref(state);
+        let strategy = resolver.strategy();
+        let Some(id) = strategy.id(parts)? else {
+            return Err(malformed(&format!(
+                "No tenant in the request, expected {}",
+                strategy
+            )));
+        };
+        if !valid_id(&id) {
+            return Err(malformed(&format!("Invalid tenant id `{}`", id)));
+        }
+
+        let Some(tenant) = resolver.resolve(&id).await else {
+            return Err(RpcError::new(
+                RpcErrorCode::NotFound,
+                format!("Unknown tenant `{}`", id),
+            ));
+        };
+        parts.extensions.insert(TenantId(id));
+        parts.extensions.insert(ResolvedTenant::<R>(tenant.clone()));
+        Ok(Self(tenant))
+    }
+}
+
+fn valid_id(id: &str) -> bool {
+    (1..=MAX_ID_LEN).contains(&id.len())
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Strips the port of a `Host`, IPv6 literals (`[::1]:8080`) included.
+fn host_without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        return host
+            .split_once(']')
+            .map_or(host, |(addr, _)| &host[..addr.len() + 1]);
+    }
+    host.split_once(':').map_or(host, |(host, _)| host)
+}
+
+fn malformed(message: &str) -> RpcError {
+    RpcError::new(RpcErrorCode::InvalidArgument, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use axum::{body::Body, http::Request};
+
+    use super::*;
+    use crate::{
+        response::RpcResult,
+        router::RpcRouter,
+        test_util::{client, hello, unary, HelloRequest, HelloResponse, SAY_HELLO},
+        testing::TestClient,
+    };
+
+    /// Tenants by id, counting the lookups.
+    #[derive(Clone)]
+    struct Tenants {
+        strategy: TenantStrategy,
+        databases: Arc<HashMap<&'static str, &'static str>>,
+        lookups: Arc<AtomicUsize>,
+    }
+
+    impl TenantResolver for Tenants {
+        type Tenant = &'static str;
+
+        fn strategy(&self) -> TenantStrategy {
+            self.strategy.clone()
+        }
+
+        async fn resolve(&self, id: &str) -> Option<&'static str> {
+            self.lookups.fetch_add(1, Ordering::SeqCst);
+            self.databases.get(id).copied()
+        }
+    }
+
+    #[derive(Clone)]
+    struct AppState {
+        tenants: Tenants,
+    }
+
+    impl FromRef<AppState> for Tenants {
+        fn from_ref(state: &AppState) -> Self {
+            state.tenants.clone()
+        }
+    }
+
+    /// The [`TenantId`] an earlier extractor left in the extensions, like a metrics label.
+    struct Label(String);
+
+    impl<M: Message, S: Send + Sync> RpcFromRequestParts<M, S> for Label {
+        type Rejection = RpcError;
+
+        async fn rpc_from_request_parts(
+            parts: &mut http::request::Parts,
+            _state: &S,
+        ) -> Result<Self, Self::Rejection> {
+            let id = parts.extensions.get::<TenantId>().map(|id| id.0.clone());
+            Ok(Self(id.unwrap_or_default()))
+        }
+    }
+
+    async fn whoami(
+        Tenant(database): Tenant<Tenants>,
+        Label(label): Label,
+        Tenant(again): Tenant<Tenants>,
+        _: HelloRequest,
+    ) -> RpcResult<HelloResponse> {
+        assert_eq!(database, again);
+        Ok(HelloResponse {
+            message: format!("{database} {label}"),
+        })
+    }
+
+    fn tenants(strategy: TenantStrategy) -> (TestClient, Arc<AtomicUsize>) {
+        let lookups = Arc::new(AtomicUsize::new(0));
+        let tenants = Tenants {
+            strategy,
+            databases: Arc::new(HashMap::from([
+                ("acme", "acme-db"),
+                ("globex", "globex-db"),
+            ])),
+            lookups: lookups.clone(),
+        };
+        let router = RpcRouter::new()
+            .rpc_method(unary(SAY_HELLO, whoami))
+            .with_state(AppState { tenants });
+        (client(router), lookups)
+    }
+
+    fn request(headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request =
+            Request::post(SAY_HELLO).header(header::CONTENT_TYPE, "application/proto");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request
+            .body(Body::from(hello("Ada").encode_to_vec()))
+            .unwrap()
+    }
+
+    async fn call(client: &TestClient, headers: &[(&str, &str)]) -> RpcResult<String> {
+        let response = client.send(request(headers)).await;
+        match response.error() {
+            Some(error) => Err(error),
+            None => Ok(HelloResponse::decode(response.body).unwrap().message),
+        }
+    }
+
+    #[tokio::test]
+    async fn resolves_tenants_from_a_header() {
+        let (client, lookups) = tenants(TenantStrategy::header());
+        assert_eq!(
+            call(&client, &[("x-tenant-id", " acme ")]).await,
+            Ok("acme-db acme".to_string())
+        );
+        // The second `Tenant` reused the first one's.
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        assert_eq!(
+            call(&client, &[("host", "globex.example.com")]).await,
+            Err(malformed(
+                "No tenant in the request, expected the x-tenant-id header"
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn resolves_tenants_from_the_subdomain() {
+        let (client, _) = tenants(TenantStrategy::subdomain("Example.com."));
+        for host in ["globex.example.com", "GLOBEX.example.com.:8443"] {
+            assert_eq!(
+                call(&client, &[("host", host)]).await,
+                Ok("globex-db globex".to_string()),
+                "{host}"
+            );
+        }
+
+        assert_eq!(
+            call(&client, &[("host", "a.acme.example.com")]).await,
+            Err(malformed(
+                "a.acme.example.com isn't a single subdomain of example.com"
+            ))
+        );
+        assert_eq!(
+            call(&client, &[("host", "acme.example.org")]).await,
+            Err(malformed(
+                "No tenant in the request, expected a subdomain of example.com"
+            ))
+        );
+    }
+
+    #[tokio::test]
+    async fn takes_the_first_strategy_finding_an_id() {
+        let (client, _) = tenants(TenantStrategy::FirstOf(vec![
+            TenantStrategy::header(),
+            TenantStrategy::subdomain("example.com"),
+        ]));
+        assert_eq!(
+            call(
+                &client,
+                &[("x-tenant-id", "acme"), ("host", "globex.example.com")]
+            )
+            .await,
+            Ok("acme-db acme".to_string())
+        );
+        assert_eq!(
+            call(&client, &[("host", "globex.example.com")]).await,
+            Ok("globex-db globex".to_string())
+        );
+        assert_eq!(
+            call(&client, &[]).await.unwrap_err().message,
+            "No tenant in the request, expected the x-tenant-id header or a subdomain of example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_and_malformed_tenants() {
+        let (client, lookups) = tenants(TenantStrategy::header());
+        assert_eq!(
+            call(&client, &[("x-tenant-id", "initech")]).await,
+            Err(RpcError::new(
+                RpcErrorCode::NotFound,
+                "Unknown tenant `initech`".to_string()
+            ))
+        );
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        let long = "a".repeat(64);
+        for id in ["acme!", "../acme", "", long.as_str()] {
+            assert_eq!(
+                call(&client, &[("x-tenant-id", id)]).await,
+                Err(malformed(&format!("Invalid tenant id `{id}`")))
+            );
+        }
+        // Malformed ids aren't looked up.
+        assert_eq!(lookups.load(Ordering::SeqCst), 1);
+
+        let mut request = request(&[]);
+        request.headers_mut().insert(
+            "x-tenant-id",
+            http::HeaderValue::from_bytes(&[0xe4, 0xb8, 0xad]).unwrap(),
+        );
+        assert_eq!(
+            client.send(request).await.error(),
+            Some(malformed("The x-tenant-id header isn't ASCII"))
+        );
+    }
+
+    #[cfg(feature = "axum-extra")]
+    #[tokio::test]
+    async fn reads_the_same_host_as_the_host_extractor() {
+        use axum_extra::extract::Host;
+
+        async fn host_and_tenant(
+            Host(host): Host,
+            Tenant(database): Tenant<Tenants>,
+            _: HelloRequest,
+        ) -> RpcResult<HelloResponse> {
+            Ok(HelloResponse {
+                message: format!("{host} {database}"),
+            })
+        }
+
+        let tenants = Tenants {
+            strategy: TenantStrategy::subdomain("example.com"),
+            databases: Arc::new(HashMap::from([("acme", "acme-db")])),
+            lookups: Arc::default(),
+        };
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, host_and_tenant))
+                .with_state(AppState { tenants }),
+        );
+        assert_eq!(
+            call(&client, &[("host", "acme.example.com:8080")]).await,
+            Ok("acme.example.com:8080 acme-db".to_string())
+        );
+    }
+}
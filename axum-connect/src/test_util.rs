@@ -0,0 +1,366 @@
+//! Messages and RPCs for the unit tests, mounted the way generated code mounts them.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+    collections::BTreeMap,
+    fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    http::{header, Request},
+    Router,
+};
+use futures::{stream, Stream};
+use prost::Message;
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    subscriber::DefaultGuard,
+    Event, Metadata, Subscriber,
+};
+
+use crate::{
+    codec::encode_envelope,
+    handler::{RpcHandlerClientStream, RpcHandlerStream, RpcHandlerUnary},
+    response::RpcResult,
+    router::{RpcIdempotencyLevel, RpcMethod, RpcMethodInfo, RpcMethodKind, RpcRouter},
+    testing::{TestClient, TestResponse},
+};
+
+pub(crate) const SAY_HELLO: &str = "/hello.HelloWorldService/SayHello";
+pub(crate) const SAY_HELLO_STREAM: &str = "/hello.HelloWorldService/SayHelloStream";
+pub(crate) const SAY_HELLO_UPLOAD: &str = "/hello.HelloWorldService/SayHelloUpload";
+
+#[derive(Clone, PartialEq, Message, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct HelloRequest {
+    #[prost(string, tag = "1")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, Message, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub(crate) struct HelloResponse {
+    #[prost(string, tag = "1")]
+    pub message: String,
+}
+
+/// Named like generated messages are, with the `type.googleapis.com` domain.
+macro_rules! impl_name {
+    ($message:ident) => {
+        impl prost::Name for $message {
+            const NAME: &'static str = stringify!($message);
+            const PACKAGE: &'static str = "hello";
+
+            fn type_url() -> String {
+                format!("type.googleapis.com/{}", Self::full_name())
+            }
+        }
+    };
+}
+
+impl_name!(HelloRequest);
+impl_name!(HelloResponse);
+
+pub(crate) fn hello(name: &str) -> HelloRequest {
+    HelloRequest {
+        name: name.to_string(),
+    }
+}
+
+pub(crate) fn unary_info(path: &str) -> RpcMethodInfo {
+    RpcMethodInfo::from_rpc_path(
+        path,
+        RpcMethodKind::Unary,
+        RpcIdempotencyLevel::IdempotencyUnknown,
+    )
+}
+
+pub(crate) fn stream_info(path: &str) -> RpcMethodInfo {
+    RpcMethodInfo::from_rpc_path(
+        path,
+        RpcMethodKind::ServerStreaming,
+        RpcIdempotencyLevel::IdempotencyUnknown,
+    )
+}
+
+pub(crate) fn client_stream_info(path: &str) -> RpcMethodInfo {
+    RpcMethodInfo::from_rpc_path(
+        path,
+        RpcMethodKind::ClientStreaming,
+        RpcIdempotencyLevel::IdempotencyUnknown,
+    )
+}
+
+pub(crate) fn unary<T, H, S>(path: &str, handler: H) -> RpcMethod<S>
+where
+    H: RpcHandlerUnary<HelloRequest, HelloResponse, T, S>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+{
+    RpcMethod::unary(unary_info(path), handler)
+}
+
+pub(crate) fn server_stream<T, H, S>(path: &str, handler: H) -> RpcMethod<S>
+where
+    H: RpcHandlerStream<HelloRequest, HelloResponse, T, S>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+{
+    RpcMethod::server_streaming(stream_info(path), handler)
+}
+
+pub(crate) fn client_stream<T, H, S>(path: &str, handler: H) -> RpcMethod<S>
+where
+    H: RpcHandlerClientStream<HelloRequest, HelloResponse, T, S>,
+    T: 'static,
+    S: Clone + Send + Sync + 'static,
+{
+    RpcMethod::client_streaming(client_stream_info(path), handler)
+}
+
+pub(crate) async fn say_hello(request: HelloRequest) -> RpcResult<HelloResponse> {
+    Ok(HelloResponse {
+        message: format!("Hello {}!", request.name),
+    })
+}
+
+/// Three greetings.
+pub(crate) async fn say_hello_stream(
+    request: HelloRequest,
+) -> impl Stream<Item = RpcResult<HelloResponse>> {
+    stream::iter((1..=3).map(move |n| {
+        Ok(HelloResponse {
+            message: format!("Hello {} #{n}!", request.name),
+        })
+    }))
+}
+
+/// A router with [`say_hello`] at [`SAY_HELLO`] and [`say_hello_stream`] at
+/// [`SAY_HELLO_STREAM`].
+pub(crate) fn hello_router() -> RpcRouter {
+    RpcRouter::new()
+        .rpc_method(unary(SAY_HELLO, say_hello))
+        .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+}
+
+pub(crate) fn client(router: RpcRouter) -> TestClient {
+    TestClient::new(router.into_router())
+}
+
+pub(crate) fn client_of(router: Router) -> TestClient {
+    TestClient::new(router)
+}
+
+/// A unary call of `path` with `message` as binary protobuf.
+pub(crate) fn proto_request<M: Message>(path: &str, message: &M) -> Request<Body> {
+    Request::post(path)
+        .header(header::CONTENT_TYPE, "application/proto")
+        .body(Body::from(message.encode_to_vec()))
+        .unwrap()
+}
+
+/// A streaming call of `path`, with `message` as its one binary frame.
+pub(crate) fn stream_request<M: Message>(path: &str, message: &M) -> Request<Body> {
+    let mut body = vec![];
+    encode_envelope(0, &message.encode_to_vec(), &mut body);
+    Request::post(path)
+        .header(header::CONTENT_TYPE, "application/connect+proto")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// The frames of a binary request stream of `messages`.
+pub(crate) fn frames<M: Message>(messages: &[M]) -> Vec<u8> {
+    let mut body = vec![];
+    for message in messages {
+        encode_envelope(0, &message.encode_to_vec(), &mut body);
+    }
+    body
+}
+
+/// The message of a successful unary binary response, panics if it failed.
+pub(crate) fn message<M: Message + Default>(response: &TestResponse) -> M {
+    if let Some(error) = response.error() {
+        panic!("the call failed: {error:?}");
+    }
+    M::decode(response.body.as_ref()).unwrap()
+}
+
+/// The messages of a successful binary stream, panics if it failed.
+pub(crate) fn messages<M: Message + Default>(response: &TestResponse) -> Vec<M> {
+    let (frames, error) = response.frames();
+    if let Some(error) = error {
+        panic!("the stream failed: {error:?}");
+    }
+    frames
+        .iter()
+        .map(|frame| M::decode(frame.as_slice()).unwrap())
+        .collect()
+}
+
+/// Counts the bytes each thread allocates, and the peak of those it has in use while
+/// [`peak_allocation`] measures, so tests running in parallel don't count each other's.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    /// The bytes in use and their peak, while measuring.
+    static IN_USE: Cell<Option<(isize, isize)>> = const { Cell::new(None) };
+}
+
+fn count_in_use(bytes: isize) {
+    let _ = IN_USE.try_with(|in_use| {
+        if let Some((current, peak)) = in_use.get() {
+            in_use.set(Some((current + bytes, peak.max(current + bytes))));
+        }
+    });
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATED.try_with(|allocated| allocated.set(allocated.get() + layout.size()));
+        count_in_use(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_in_use(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The bytes `f` allocates on this thread, freed or not.
+pub(crate) fn allocated_by(f: impl FnOnce()) -> usize {
+    let before = ALLOCATED.with(Cell::get);
+    f();
+    ALLOCATED.with(Cell::get) - before
+}
+
+/// The most bytes `future` has allocated at once on this thread, on top of what was in use
+/// before. Reallocations count as copies, with the old and the new buffer in use together.
+pub(crate) async fn peak_allocation<T>(future: impl Future<Output = T>) -> (T, usize) {
+    IN_USE.with(|in_use| in_use.set(Some((0, 0))));
+    let output = future.await;
+    let (_, peak) = IN_USE.with(Cell::take).unwrap();
+    (output, peak as usize)
+}
+
+/// A span or event `tracing` recorded, with its fields as they'd print.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Traced {
+    pub target: String,
+    pub name: String,
+    pub fields: BTreeMap<String, String>,
+    /// The span it's in as it was then, for events.
+    pub span: Option<Box<Traced>>,
+}
+
+impl Traced {
+    pub(crate) fn field(&self, name: &str) -> Option<&str> {
+        self.fields.get(name).map(String::as_str)
+    }
+}
+
+impl Visit for Traced {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.fields
+            .insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.fields
+            .insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+/// A `tracing` subscriber keeping every span and event, for the tests on this thread once it's
+/// [`traced`].
+#[derive(Clone, Default)]
+pub(crate) struct TraceRecorder {
+    spans: Arc<Mutex<Vec<Traced>>>,
+    events: Arc<Mutex<Vec<Traced>>>,
+    entered: Arc<Mutex<Vec<usize>>>,
+}
+
+impl TraceRecorder {
+    pub(crate) fn spans(&self, target: &str) -> Vec<Traced> {
+        let spans = self.spans.lock().unwrap();
+        spans
+            .iter()
+            .filter(|span| span.target == target)
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn events(&self, target: &str) -> Vec<Traced> {
+        let events = self.events.lock().unwrap();
+        events
+            .iter()
+            .filter(|event| event.target == target)
+            .cloned()
+            .collect()
+    }
+
+    fn traced(metadata: &Metadata<'_>) -> Traced {
+        Traced {
+            target: metadata.target().to_string(),
+            name: metadata.name().to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+impl Subscriber for TraceRecorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, attributes: &Attributes<'_>) -> Id {
+        let mut span = Self::traced(attributes.metadata());
+        attributes.record(&mut span);
+        let mut spans = self.spans.lock().unwrap();
+        spans.push(span);
+        Id::from_u64(spans.len() as u64)
+    }
+
+    fn record(&self, id: &Id, values: &Record<'_>) {
+        values.record(&mut self.spans.lock().unwrap()[id.into_u64() as usize - 1]);
+    }
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut traced = Self::traced(event.metadata());
+        event.record(&mut traced);
+        if let Some(entered) = self.entered.lock().unwrap().last() {
+            traced.span = Some(Box::new(self.spans.lock().unwrap()[*entered].clone()));
+        }
+        self.events.lock().unwrap().push(traced);
+    }
+
+    fn enter(&self, id: &Id) {
+        self.entered
+            .lock()
+            .unwrap()
+            .push(id.into_u64() as usize - 1);
+    }
+
+    fn exit(&self, _: &Id) {
+        self.entered.lock().unwrap().pop();
+    }
+}
+
+/// Records what's traced on this thread until the guard is dropped.
+pub(crate) fn traced() -> (TraceRecorder, DefaultGuard) {
+    let recorder = TraceRecorder::default();
+    let guard = tracing::subscriber::set_default(recorder.clone());
+    (recorder, guard)
+}
@@ -0,0 +1,454 @@
+//! Protocol checks for mounted RPCs, written once instead of for every service, see
+//! [`rpc_conformance_tests!`](crate::rpc_conformance_tests).
+//!
+//! [`TestClient`] calls a router in-process, and [`Conformance`] runs each check against one RPC;
+//! the macro turns every check into a `#[test]`.
+
+use std::{future::Future, marker::PhantomData};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode},
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use prost::Message;
+use tower::ServiceExt;
+
+use crate::{
+    codec::{
+        decode_unary_response, encode_envelope, encode_unary_response, parse_end_stream,
+        parse_unary_error, ContentType, FrameDecoder, RpcJsonDecode, RpcJsonEncode,
+    },
+    config::RpcConfig,
+    error::{RpcError, RpcErrorCode},
+    router::{RpcMethodInfo, RpcMethodKind, RpcRouter},
+};
+
+/// The request size limit of the oversized body check.
+const MAX_REQUEST_BYTES: usize = 64;
+
+/// Generates `#[test]`s checking the protocol level behavior of a service's RPCs, mounted with
+/// fixture handlers: both codecs, GET for `NO_SIDE_EFFECTS` methods, the error shape of bad
+/// requests (unsupported content type, malformed and oversized bodies), and metadata. Requests are
+/// the messages' defaults.
+///
+/// Each method becomes a module of tests, so the macro goes where those can see the service, the
+/// messages and the fixtures, eg. a `#[cfg(test)]` module next to them:
+///
+/// ```ignore
+/// #[cfg(test)]
+/// mod conformance {
+///     use super::*;
+///
+///     async fn say_hello(_request: HelloRequest) -> RpcResult<HelloResponse> {
+///         Ok(HelloResponse::default())
+///     }
+///
+///     axum_connect::rpc_conformance_tests!(
+///         service = HelloWorldService,
+///         methods = [say_hello: HelloRequest => HelloResponse = say_hello],
+///     );
+/// }
+/// ```
+///
+/// Fixtures must succeed for default requests. The checks only use the router, so they find the
+/// same bugs however the real handlers are written.
+#[macro_export]
+macro_rules! rpc_conformance_tests {
+    (
+        service = $service:ident,
+        methods = [$($method:ident: $request:ty => $response:ty = $fixture:expr),* $(,)?] $(,)?
+    ) => {
+        $(
+            #[allow(non_snake_case)]
+            mod $method {
+                use super::*;
+
+                fn conformance() -> $crate::testing::Conformance<$request, $response> {
+                    $crate::testing::Conformance::new(|router| {
+                        $crate::router::RpcRouterExt::rpc(router, $service::$method($fixture))
+                    })
+                }
+
+                #[test]
+                fn json() {
+                    $crate::testing::block_on(conformance().json());
+                }
+
+                #[test]
+                fn proto() {
+                    $crate::testing::block_on(conformance().proto());
+                }
+
+                #[test]
+                fn get() {
+                    $crate::testing::block_on(conformance().get());
+                }
+
+                #[test]
+                fn metadata() {
+                    $crate::testing::block_on(conformance().metadata());
+                }
+
+                #[test]
+                fn unsupported_content_type() {
+                    $crate::testing::block_on(conformance().unsupported_content_type());
+                }
+
+                #[test]
+                fn malformed_body() {
+                    $crate::testing::block_on(conformance().malformed_body());
+                }
+
+                #[test]
+                fn oversized_body() {
+                    $crate::testing::block_on(conformance().oversized_body());
+                }
+            }
+        )*
+    };
+}
+
+/// Runs a check on a runtime of its own, for the tests of
+/// [`rpc_conformance_tests!`](crate::rpc_conformance_tests).
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Calls a router in-process, without a server.
+#[derive(Clone, Debug)]
+pub struct TestClient {
+    router: Router,
+}
+
+impl TestClient {
+    pub fn new(router: Router) -> Self {
+        Self { router }
+    }
+
+    pub async fn send(&self, request: Request<Body>) -> TestResponse {
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: axum::body::to_bytes(body, usize::MAX).await.unwrap(),
+        }
+    }
+
+    /// POSTs `body` to `path` as `content_type`.
+    pub async fn post(&self, path: &str, content_type: &str, body: Vec<u8>) -> TestResponse {
+        let request = Request::post(path)
+            .header(header::CONTENT_TYPE, content_type)
+            .body(Body::from(body))
+            .unwrap();
+        self.send(request).await
+    }
+}
+
+/// A response of a [`TestClient`], with its body read.
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn content_type(&self) -> Option<&str> {
+        self.headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+    }
+
+    /// The payloads of a stream's message envelopes, and its end-of-stream error if it has one.
+    /// Payloads gzipped with the stream's `connect-content-encoding` are gunzipped. Panics if the
+    /// body isn't a well-formed stream ending with an end-of-stream frame.
+    pub fn frames(&self) -> (Vec<Vec<u8>>, Option<RpcError>) {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&self.body);
+        let mut messages = vec![];
+        while let Some(frame) = decoder.next_frame().expect("malformed stream envelope") {
+            if frame.is_end_stream() {
+                decoder
+                    .finish()
+                    .expect("bytes after the end-of-stream frame");
+                let end = parse_end_stream(&frame.payload).expect("malformed end-of-stream frame");
+                return (messages, end.error);
+            }
+            #[cfg(feature = "stream-compression")]
+            if frame.is_compressed()
+                && self
+                    .headers
+                    .get("connect-content-encoding")
+                    .is_some_and(|encoding| encoding == "gzip")
+            {
+                messages
+                    .push(crate::codec::gunzip(&frame.payload).expect("malformed gzip payload"));
+                continue;
+            }
+            messages.push(frame.payload);
+        }
+        panic!("the stream has no end-of-stream frame");
+    }
+
+    /// The Connect error of the response, unary or streamed, `None` if it succeeded. Panics if
+    /// the response isn't a well-formed Connect response.
+    pub fn error(&self) -> Option<RpcError> {
+        if self.is_streaming() {
+            assert_eq!(self.status, StatusCode::OK, "streams always respond 200");
+            self.frames().1
+        } else if self.status == StatusCode::OK {
+            None
+        } else {
+            assert_eq!(
+                self.content_type(),
+                Some("application/json"),
+                "unary errors are JSON"
+            );
+            let error = parse_unary_error(&self.body).expect("malformed unary error");
+            assert_eq!(
+                self.status,
+                StatusCode::from(error.code.clone()),
+                "the HTTP status doesn't match the error code"
+            );
+            Some(error)
+        }
+    }
+
+    fn is_streaming(&self) -> bool {
+        self.content_type()
+            .and_then(|content_type| ContentType::parse(content_type.as_bytes()))
+            .is_some_and(|content_type| content_type.is_streaming())
+    }
+}
+
+/// The checks of [`rpc_conformance_tests!`](crate::rpc_conformance_tests) for one RPC, sending
+/// `Req::default()`. Each panics if the RPC gets the protocol wrong.
+///
+/// ```ignore
+/// let conformance = Conformance::<HelloRequest, HelloResponse>::new(|router| {
+///     router.rpc(HelloWorldService::say_hello(say_hello))
+/// });
+/// conformance.malformed_body().await;
+/// ```
+pub struct Conformance<Req, Res> {
+    client: TestClient,
+    limited: TestClient,
+    info: RpcMethodInfo,
+    _messages: PhantomData<fn(Req) -> Res>,
+}
+
+impl<Req, Res> Conformance<Req, Res>
+where
+    Req: Message + RpcJsonEncode + Default,
+    Res: Message + RpcJsonDecode + Default + 'static,
+{
+    /// `mount` mounts the RPC on the router it's given, it's called once more for the router of
+    /// the oversized body check. Panics if it mounts anything but one RPC.
+    pub fn new(mount: impl Fn(RpcRouter) -> RpcRouter) -> Self {
+        let router = mount(RpcRouter::new());
+        let paths = router.paths();
+        assert_eq!(paths.len(), 1, "conformance checks take one RPC at a time");
+        let info = paths.into_iter().next().unwrap();
+        let limited = mount(RpcRouter::new())
+            .with_config(RpcConfig::new().max_request_bytes(MAX_REQUEST_BYTES));
+
+        Self {
+            client: TestClient::new(router.into_router()),
+            limited: TestClient::new(limited.into_router()),
+            info,
+            _messages: PhantomData,
+        }
+    }
+
+    pub fn info(&self) -> &RpcMethodInfo {
+        &self.info
+    }
+
+    /// A JSON call succeeds with a JSON response.
+    pub async fn json(&self) {
+        let response = self.call(false, &Req::default(), &[]).await;
+        self.expect_success(&response, false);
+    }
+
+    /// A binary protobuf call succeeds with a binary response.
+    pub async fn proto(&self) {
+        let response = self.call(true, &Req::default(), &[]).await;
+        self.expect_success(&response, true);
+    }
+
+    /// `NO_SIDE_EFFECTS` methods succeed over GET, with the message in the query. Other methods
+    /// aren't mounted for GET, so it's a no-op for them.
+    pub async fn get(&self) {
+        if !self.info.http_methods.contains(&Method::GET) {
+            return;
+        }
+
+        let mut message = vec![];
+        encode_unary_response(&Req::default(), true, &mut message).unwrap();
+        let uri = format!(
+            "{}?connect=v1&encoding=proto&base64=1&message={}",
+            self.info.path,
+            URL_SAFE_NO_PAD.encode(message)
+        );
+        let response = self
+            .client
+            .send(Request::get(uri).body(Body::empty()).unwrap())
+            .await;
+        self.expect_success(&response, true);
+    }
+
+    /// Metadata the RPC doesn't read, the protocol version and a deadline don't get in the way.
+    pub async fn metadata(&self) {
+        let headers = [
+            ("connect-protocol-version", "1"),
+            ("connect-timeout-ms", "30000"),
+            ("x-conformance-test", "1"),
+            ("x-conformance-test-bin", "AQID"),
+        ];
+        let response = self.call(false, &Req::default(), &headers).await;
+        self.expect_success(&response, false);
+    }
+
+    /// A body that isn't a Connect content type fails with `InvalidArgument`. There's no way to
+    /// tell what the client expects, so the error is in an end-of-stream frame.
+    pub async fn unsupported_content_type(&self) {
+        let response = self
+            .client
+            .post(&self.info.path, "text/plain", b"hello".to_vec())
+            .await;
+        match response.error() {
+            Some(error) => assert_eq!(
+                error.code,
+                RpcErrorCode::InvalidArgument,
+                "{}: {}",
+                self.info.path,
+                error.message
+            ),
+            None => panic!("{}: a text/plain body was accepted", self.info.path),
+        }
+    }
+
+    /// A body that doesn't decode fails with `InvalidArgument`, in the shape of the call.
+    pub async fn malformed_body(&self) {
+        let response = self.send(false, b"{\"not json".to_vec(), &[]).await;
+        self.expect_error(&response, RpcErrorCode::InvalidArgument);
+    }
+
+    /// A body over `max_request_bytes` fails with `ResourceExhausted`, in the shape of the call.
+    pub async fn oversized_body(&self) {
+        let mut body = b"{}".to_vec();
+        body.resize(MAX_REQUEST_BYTES * 2, b' ');
+        let content_type = self.content_type(false).as_str();
+        let response = self
+            .limited
+            .post(&self.info.path, content_type, self.wrap(body))
+            .await;
+        self.expect_error(&response, RpcErrorCode::ResourceExhausted);
+    }
+
+    fn streaming(&self) -> bool {
+        matches!(
+            self.info.kind,
+            Some(
+                RpcMethodKind::ClientStreaming
+                    | RpcMethodKind::ServerStreaming
+                    | RpcMethodKind::BidiStreaming
+            )
+        )
+    }
+
+    fn content_type(&self, binary: bool) -> ContentType {
+        match (self.streaming(), binary) {
+            (false, false) => ContentType::Json,
+            (false, true) => ContentType::Proto,
+            (true, false) => ContentType::ConnectJson,
+            (true, true) => ContentType::ConnectProto,
+        }
+    }
+
+    /// `payload` as the body of a call, in an envelope for streams.
+    fn wrap(&self, payload: Vec<u8>) -> Vec<u8> {
+        if !self.streaming() {
+            return payload;
+        }
+        let mut body = vec![];
+        encode_envelope(0, &payload, &mut body);
+        body
+    }
+
+    async fn call(
+        &self,
+        binary: bool,
+        request: &Req,
+        headers: &[(&'static str, &'static str)],
+    ) -> TestResponse {
+        let mut payload = vec![];
+        encode_unary_response(request, binary, &mut payload).unwrap();
+        self.send(binary, payload, headers).await
+    }
+
+    async fn send(
+        &self,
+        binary: bool,
+        payload: Vec<u8>,
+        headers: &[(&'static str, &'static str)],
+    ) -> TestResponse {
+        let mut request = Request::post(&self.info.path)
+            .header(header::CONTENT_TYPE, self.content_type(binary).as_str())
+            .body(Body::from(self.wrap(payload)))
+            .unwrap();
+        for (name, value) in headers {
+            request
+                .headers_mut()
+                .insert(*name, HeaderValue::from_static(value));
+        }
+        self.client.send(request).await
+    }
+
+    fn expect_success(&self, response: &TestResponse, binary: bool) {
+        let path = &self.info.path;
+        if let Some(error) = response.error() {
+            panic!("{path}: the fixture failed: {error:?}");
+        }
+        assert_eq!(
+            response.content_type(),
+            Some(self.content_type(binary).as_str()),
+            "{path}: the response should have the request's content type"
+        );
+        assert_eq!(response.status, StatusCode::OK, "{path}");
+
+        let messages = if self.streaming() {
+            response.frames().0
+        } else {
+            vec![response.body.to_vec()]
+        };
+        for message in messages {
+            if let Err(error) = decode_unary_response::<Res>(&message, binary) {
+                panic!("{path}: the response doesn't decode: {}", error.message);
+            }
+        }
+    }
+
+    fn expect_error(&self, response: &TestResponse, code: RpcErrorCode) {
+        let path = &self.info.path;
+        let content_type = self.content_type(false).as_str();
+        if self.streaming() {
+            assert_eq!(
+                response.content_type(),
+                Some(content_type),
+                "{path}: stream errors should have the request's content type"
+            );
+        }
+        match response.error() {
+            Some(error) => assert_eq!(error.code, code, "{path}: {}", error.message),
+            None => panic!("{path}: expected {code:?}, the call succeeded"),
+        }
+    }
+}
@@ -0,0 +1,551 @@
+//! Bearer token verification that doesn't call the auth service on every RPC, see
+//! [`BearerAuth`].
+//!
+//! Verdicts are kept in a [`TokenCache`], in process by default ([`MemoryTokenCache`]), keyed by
+//! the SHA-256 of the token so the cache never holds a usable credential. Valid tokens are cached
+//! until they expire, invalid ones for a short while, so a client retrying a bad token doesn't
+//! reach the auth service either.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+use axum::http::{header, request, HeaderMap, HeaderValue};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    auth::{AuthContext, RpcAuthorize},
+    error::{RpcError, RpcErrorCode},
+    router::RpcMethodInfo,
+};
+
+/// The default [`BearerAuth::negative_ttl`].
+pub const DEFAULT_NEGATIVE_TTL: Duration = Duration::from_secs(10);
+
+/// The default [`BearerAuth::max_ttl`].
+pub const DEFAULT_MAX_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The SHA-256 of a token, what [`TokenCache`]s are keyed by.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TokenKey([u8; 32]);
+
+impl TokenKey {
+    pub fn new(token: &str) -> Self {
+        Self(Sha256::digest(token.as_bytes()).into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for TokenKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // A prefix is plenty to tell keys apart in logs.
+        write!(f, "TokenKey(")?;
+        for byte in &self.0[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "..)")
+    }
+}
+
+/// What verifying a token returned: who it's for, or why it was rejected.
+pub type TokenVerdict = Result<AuthContext, RpcError>;
+
+/// The future returned by [`TokenCache::get`].
+pub type TokenCacheGetFuture<'a> = Pin<Box<dyn Future<Output = Option<TokenVerdict>> + Send + 'a>>;
+
+/// The future returned by [`TokenCache::insert`].
+pub type TokenCacheInsertFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Where token verdicts live.
+pub trait TokenCache: Send + Sync + 'static {
+    /// The verdict for `key`, `None` if there's none or it expired.
+    fn get<'a>(&'a self, key: &'a TokenKey) -> TokenCacheGetFuture<'a>;
+
+    /// Keeps `verdict` for `ttl`.
+    fn insert(
+        &self,
+        key: TokenKey,
+        verdict: TokenVerdict,
+        ttl: Duration,
+    ) -> TokenCacheInsertFuture<'_>;
+}
+
+/// The default, in process [`TokenCache`]: an LRU of `capacity` verdicts, split into shards that
+/// are locked separately. When a shard is full, its expired verdicts go first, then the least
+/// recently used one.
+pub struct MemoryTokenCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<TokenKey, Entry>,
+    /// Keys by when they were last used, oldest first.
+    recency: BTreeMap<u64, TokenKey>,
+    clock: u64,
+}
+
+struct Entry {
+    verdict: TokenVerdict,
+    expires: Instant,
+    used: u64,
+}
+
+impl MemoryTokenCache {
+    /// Holds up to `capacity` verdicts, in 16 shards.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_shards(capacity, 16)
+    }
+
+    /// Holds up to `capacity` verdicts, in `shards` shards (at least one).
+    pub fn with_shards(capacity: usize, shards: usize) -> Self {
+        let shards = shards.clamp(1, capacity.max(1));
+        Self {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            shard_capacity: capacity.div_ceil(shards).max(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn shard(&self, key: &TokenKey) -> &Mutex<Shard> {
+        // The key is a hash already.
+        let index = u64::from_le_bytes(key.0[..8].try_into().unwrap());
+        &self.shards[(index % self.shards.len() as u64) as usize]
+    }
+
+    fn get_now(&self, key: &TokenKey, now: Instant) -> Option<TokenVerdict> {
+        let mut shard = self.shard(key).lock().unwrap();
+        let Shard {
+            entries,
+            recency,
+            clock,
+        } = &mut *shard;
+
+        let entry = entries.get_mut(key)?;
+        if entry.expires <= now {
+            recency.remove(&entry.used);
+            entries.remove(key);
+            return None;
+        }
+
+        *clock += 1;
+        recency.remove(&entry.used);
+        recency.insert(*clock, *key);
+        entry.used = *clock;
+        Some(entry.verdict.clone())
+    }
+
+    fn insert_now(&self, key: TokenKey, verdict: TokenVerdict, ttl: Duration, now: Instant) {
+        let mut shard = self.shard(&key).lock().unwrap();
+        let Shard {
+            entries,
+            recency,
+            clock,
+        } = &mut *shard;
+
+        if let Some(entry) = entries.remove(&key) {
+            recency.remove(&entry.used);
+        }
+        if entries.len() >= self.shard_capacity {
+            entries.retain(|_, entry| entry.expires > now);
+            recency.retain(|_, key| entries.contains_key(key));
+        }
+        while entries.len() >= self.shard_capacity {
+            let Some((_, oldest)) = recency.pop_first() else {
+                break;
+            };
+            entries.remove(&oldest);
+        }
+
+        *clock += 1;
+        recency.insert(*clock, key);
+        entries.insert(
+            key,
+            Entry {
+                verdict,
+                expires: now + ttl,
+                used: *clock,
+            },
+        );
+    }
+}
+
+impl TokenCache for MemoryTokenCache {
+    fn get<'a>(&'a self, key: &'a TokenKey) -> TokenCacheGetFuture<'a> {
+        let verdict = self.get_now(key, Instant::now());
+        Box::pin(async move { verdict })
+    }
+
+    fn insert(
+        &self,
+        key: TokenKey,
+        verdict: TokenVerdict,
+        ttl: Duration,
+    ) -> TokenCacheInsertFuture<'_> {
+        self.insert_now(key, verdict, ttl, Instant::now());
+        Box::pin(async {})
+    }
+}
+
+impl fmt::Debug for MemoryTokenCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryTokenCache")
+            .field("shards", &self.shards.len())
+            .field("shard_capacity", &self.shard_capacity)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A token the [`TokenVerifier`] accepted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VerifiedToken {
+    pub context: AuthContext,
+    /// When the token expires, eg. its `exp` claim. It's never cached past this.
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Checks a bearer token, eg. by calling the auth service's introspection endpoint.
+///
+/// Return `Unauthenticated` or `PermissionDenied` for tokens that are invalid, those verdicts
+/// are cached for the [`negative_ttl`](BearerAuth::negative_ttl). Other errors, like
+/// `Unavailable` when the auth service is down, are returned to the caller without caching.
+pub trait TokenVerifier: Send + Sync + 'static {
+    fn verify(&self, token: &str) -> impl Future<Output = Result<VerifiedToken, RpcError>> + Send;
+}
+
+/// How [`BearerAuth`] resolved a token, passed to [`BearerAuth::on_lookup`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenLookup {
+    /// A cached valid token.
+    Hit,
+    /// A cached invalid token.
+    NegativeHit,
+    /// Not cached, the verifier was called.
+    Miss,
+}
+
+type OnLookup = Arc<dyn Fn(TokenLookup) + Send + Sync>;
+
+/// An [`RpcAuthorize`] for `authorization: Bearer <token>` headers, verifying tokens with a
+/// [`TokenVerifier`] and caching the verdicts.
+///
+/// Valid tokens are cached until their `expires_at`, at most for the [`max_ttl`](Self::max_ttl),
+/// so revoked tokens stop working within that. The verifier only sees the token, not the method,
+/// so per-method checks belong in the handlers, eg. with [`AuthContext::has_scope`].
+///
+/// ```
+/// # use axum_connect::{auth::AuthContext, prelude::*, token_cache::*};
+/// struct Introspect;
+///
+/// impl TokenVerifier for Introspect {
+///     async fn verify(&self, token: &str) -> Result<VerifiedToken, RpcError> {
+///         // Call the auth service here.
+///         # let _ = token;
+///         Ok(VerifiedToken {
+///             context: AuthContext::default(),
+///             expires_at: None,
+///         })
+///     }
+/// }
+///
+/// let auth = BearerAuth::new(Introspect)
+///     .cache(MemoryTokenCache::new(100_000))
+///     .on_lookup(|lookup| println!("token cache: {:?}", lookup));
+/// let app = RpcRouter::<()>::new().authorize(auth);
+/// ```
+pub struct BearerAuth<V> {
+    verifier: V,
+    cache: Arc<dyn TokenCache>,
+    negative_ttl: Duration,
+    max_ttl: Duration,
+    on_lookup: Option<OnLookup>,
+}
+
+impl<V: TokenVerifier> BearerAuth<V> {
+    /// Caches in a [`MemoryTokenCache`] of 10,000 verdicts.
+    pub fn new(verifier: V) -> Self {
+        Self {
+            verifier,
+            cache: Arc::new(MemoryTokenCache::new(10_000)),
+            negative_ttl: DEFAULT_NEGATIVE_TTL,
+            max_ttl: DEFAULT_MAX_TTL,
+            on_lookup: None,
+        }
+    }
+
+    pub fn cache(mut self, cache: impl TokenCache) -> Self {
+        self.cache = Arc::new(cache);
+        self
+    }
+
+    /// How long invalid tokens are cached. [`DEFAULT_NEGATIVE_TTL`] by default, zero turns it off.
+    pub fn negative_ttl(mut self, negative_ttl: Duration) -> Self {
+        self.negative_ttl = negative_ttl;
+        self
+    }
+
+    /// The longest a valid token is cached, and how long one without an expiry is.
+    /// [`DEFAULT_MAX_TTL`] by default.
+    pub fn max_ttl(mut self, max_ttl: Duration) -> Self {
+        self.max_ttl = max_ttl;
+        self
+    }
+
+    /// Calls `f` on every lookup, eg. to count cache hits and misses.
+    pub fn on_lookup<F>(mut self, f: F) -> Self
+    where
+        F: Fn(TokenLookup) + Send + Sync + 'static,
+    {
+        self.on_lookup = Some(Arc::new(f));
+        self
+    }
+
+    fn observe(&self, lookup: TokenLookup) {
+        if let Some(on_lookup) = &self.on_lookup {
+            on_lookup(lookup);
+        }
+    }
+
+    async fn resolve(&self, token: &str) -> TokenVerdict {
+        let key = TokenKey::new(token);
+        if let Some(verdict) = self.cache.get(&key).await {
+            self.observe(match verdict {
+                Ok(_) => TokenLookup::Hit,
+                Err(_) => TokenLookup::NegativeHit,
+            });
+            return verdict;
+        }
+        self.observe(TokenLookup::Miss);
+
+        match self.verifier.verify(token).await {
+            Ok(verified) => {
+                let ttl = match verified.expires_at {
+                    Some(expires_at) => expires_at
+                        .duration_since(SystemTime::now())
+                        .unwrap_or_default()
+                        .min(self.max_ttl),
+                    None => self.max_ttl,
+                };
+                if !ttl.is_zero() {
+                    let verdict = Ok(verified.context.clone());
+                    self.cache.insert(key, verdict, ttl).await;
+                }
+                Ok(verified.context)
+            }
+            Err(error) => {
+                let invalid = matches!(
+                    error.code,
+                    RpcErrorCode::Unauthenticated | RpcErrorCode::PermissionDenied
+                );
+                if invalid && !self.negative_ttl.is_zero() {
+                    let verdict = Err(error.clone());
+                    self.cache.insert(key, verdict, self.negative_ttl).await;
+                }
+                Err(error)
+            }
+        }
+    }
+}
+
+impl<V: TokenVerifier> RpcAuthorize for BearerAuth<V> {
+    async fn authorize(
+        &self,
+        _method: &RpcMethodInfo,
+        parts: &request::Parts,
+    ) -> Result<AuthContext, RpcError> {
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| {
+                v.split_once(' ')
+                    .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            })
+            .map(|(_, token)| token.trim());
+
+        let Some(token) = token else {
+            let mut metadata = HeaderMap::new();
+            metadata.insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Err(RpcError::new(
+                RpcErrorCode::Unauthenticated,
+                "Missing bearer token".to_string(),
+            )
+            .with_metadata(metadata));
+        };
+
+        self.resolve(token).await
+    }
+}
+
+impl<V> fmt::Debug for BearerAuth<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BearerAuth")
+            .field("negative_ttl", &self.negative_ttl)
+            .field("max_ttl", &self.max_ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// Accepts tokens starting with `valid`, for a minute, and rejects `invalid`. Counts its calls.
+    #[derive(Default)]
+    struct Verifier {
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl TokenVerifier for Verifier {
+        async fn verify(&self, token: &str) -> Result<VerifiedToken, RpcError> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            match token {
+                "invalid" => Err(RpcError::new(
+                    RpcErrorCode::Unauthenticated,
+                    "Invalid token".to_string(),
+                )),
+                "unreachable" => Err(RpcError::new(
+                    RpcErrorCode::Unavailable,
+                    "The auth service is down".to_string(),
+                )),
+                _ => Ok(VerifiedToken {
+                    context: AuthContext {
+                        user_id: token.to_string(),
+                        scopes: vec![],
+                    },
+                    expires_at: Some(SystemTime::now() + Duration::from_secs(60)),
+                }),
+            }
+        }
+    }
+
+    /// A `BearerAuth` with a [`Verifier`], and the lookups it made.
+    fn auth(
+        configure: impl FnOnce(BearerAuth<Verifier>) -> BearerAuth<Verifier>,
+    ) -> (
+        BearerAuth<Verifier>,
+        Arc<AtomicUsize>,
+        Arc<Mutex<Vec<TokenLookup>>>,
+    ) {
+        let verifier = Verifier::default();
+        let calls = verifier.calls.clone();
+        let lookups = Arc::new(Mutex::new(vec![]));
+        let auth = configure(BearerAuth::new(verifier)).on_lookup({
+            let lookups = lookups.clone();
+            move |lookup| lookups.lock().unwrap().push(lookup)
+        });
+        (auth, calls, lookups)
+    }
+
+    #[tokio::test]
+    async fn verifies_each_token_once_while_its_cached() {
+        let (auth, calls, lookups) = auth(|auth| auth);
+        for _ in 0..3 {
+            assert_eq!(auth.resolve("valid").await.unwrap().user_id, "valid");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            *lookups.lock().unwrap(),
+            [TokenLookup::Miss, TokenLookup::Hit, TokenLookup::Hit]
+        );
+    }
+
+    #[tokio::test]
+    async fn caches_invalid_tokens_but_not_failures_to_verify() {
+        let (auth, calls, lookups) = auth(|auth| auth);
+        for _ in 0..2 {
+            let error = auth.resolve("invalid").await.unwrap_err();
+            assert_eq!(error.code, RpcErrorCode::Unauthenticated);
+        }
+        for _ in 0..2 {
+            let error = auth.resolve("unreachable").await.unwrap_err();
+            assert_eq!(error.code, RpcErrorCode::Unavailable);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(
+            *lookups.lock().unwrap(),
+            [
+                TokenLookup::Miss,
+                TokenLookup::NegativeHit,
+                TokenLookup::Miss,
+                TokenLookup::Miss
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn verifies_tokens_again_once_they_expire() {
+        let (auth, calls, _) = auth(|auth| auth.max_ttl(Duration::from_millis(20)));
+        auth.resolve("valid").await.unwrap();
+        auth.resolve("valid").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        auth.resolve("valid").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn verifies_evicted_tokens_again() {
+        let (auth, calls, _) = auth(|auth| auth.cache(MemoryTokenCache::new(1)));
+        auth.resolve("valid-1").await.unwrap();
+        auth.resolve("valid-2").await.unwrap();
+        auth.resolve("valid-1").await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    fn verdict(user_id: &str) -> TokenVerdict {
+        Ok(AuthContext {
+            user_id: user_id.to_string(),
+            scopes: vec![],
+        })
+    }
+
+    #[test]
+    fn evicts_expired_verdicts_then_the_least_recently_used() {
+        let cache = MemoryTokenCache::with_shards(2, 1);
+        let (a, b, c) = (TokenKey::new("a"), TokenKey::new("b"), TokenKey::new("c"));
+        let now = Instant::now();
+        let minute = Duration::from_secs(60);
+
+        cache.insert_now(a, verdict("a"), minute, now);
+        cache.insert_now(b, verdict("b"), minute, now);
+        // `a` was used last, so `b` goes.
+        assert!(cache.get_now(&a, now).is_some());
+        cache.insert_now(c, verdict("c"), minute, now);
+        assert!(cache.get_now(&b, now).is_none());
+        assert!(cache.get_now(&a, now).is_some());
+
+        // Expired ones go before the least recently used.
+        let cache = MemoryTokenCache::with_shards(2, 1);
+        cache.insert_now(a, verdict("a"), Duration::from_secs(10), now);
+        cache.insert_now(c, verdict("c"), minute, now);
+        assert!(cache.get_now(&a, now).is_some());
+        let later = now + Duration::from_secs(30);
+        cache.insert_now(b, verdict("b"), minute, later);
+        assert!(cache.get_now(&c, later).is_some());
+        assert!(cache.get_now(&b, later).is_some());
+        assert_eq!(cache.len(), 2);
+    }
+}
@@ -0,0 +1,469 @@
+//! A `tracing` span per RPC, with per-method sampling, see [`RpcTraceLayer`].
+
+use std::{
+    fmt,
+    pin::{pin, Pin},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::request,
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use tower::{Layer, Service};
+use tracing::{field, Instrument, Span};
+
+use crate::{logging::RpcCallStats, router::RpcMethodInfo};
+
+/// Whether an RPC gets a span, from [`RpcTraceLayer::sample`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleDecision {
+    Always,
+    Never,
+    /// Samples this share of the calls, from 0.0 to 1.0, picked at random.
+    Ratio(f64),
+    /// Follows the sampled flag of the inbound `traceparent` header, so a trace is kept or dropped
+    /// as a whole. Calls without a valid `traceparent` start a trace, and are sampled.
+    Parent,
+}
+
+type Sampler = Arc<dyn Fn(&RpcMethodInfo, &request::Parts) -> SampleDecision + Send + Sync>;
+
+/// Opens an `info` level span on the `axum_connect::trace` target for every RPC, named `rpc`,
+/// with the `procedure`, and the `code` (`ok` or the Connect error code), `latency_ms` and the
+/// error's [`internal_message`](crate::error::RpcError::with_internal_message) once the response
+/// has been sent. The handler runs in it, and so does every poll of a response stream.
+///
+/// Which calls get a span is up to [`sample`](RpcTraceLayer::sample), eg. to keep a sliver of a
+/// hot method's traffic. Calls that aren't sampled don't create a span at all, so they cost no
+/// more than the decision. Mount it on an [`RpcRouter`](crate::router::RpcRouter) with
+/// `.layer(...)`, the spans are opened by its RPC routes.
+#[derive(Clone)]
+pub struct RpcTraceLayer {
+    sampler: Sampler,
+    sample_errors: bool,
+    /// The state of the generator picking the calls of [`SampleDecision::Ratio`].
+    rng: Arc<AtomicU64>,
+}
+
+impl RpcTraceLayer {
+    /// Samples every call.
+    pub fn new() -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+
+        Self {
+            sampler: Arc::new(|_, _| SampleDecision::Always),
+            sample_errors: false,
+            rng: Arc::new(AtomicU64::new(seed)),
+        }
+    }
+
+    /// Decides for each call whether it gets a span, before the handler runs.
+    ///
+    /// ```
+    /// # use axum_connect::trace::{RpcTraceLayer, SampleDecision};
+    /// let layer = RpcTraceLayer::new().sample(|info, _parts| match info.method.as_str() {
+    ///     "GetUser" => SampleDecision::Ratio(0.01),
+    ///     _ => SampleDecision::Parent,
+    /// });
+    /// ```
+    pub fn sample<F>(mut self, sampler: F) -> Self
+    where
+        F: Fn(&RpcMethodInfo, &request::Parts) -> SampleDecision + Send + Sync + 'static,
+    {
+        self.sampler = Arc::new(sampler);
+        self
+    }
+
+    /// Also gives failed calls that weren't sampled a span, opened when the response has been
+    /// sent, with `code`, `latency_ms` and `forced = true`. The handler didn't run in it, so it
+    /// has none of the handler's events or child spans.
+    pub fn sample_errors(mut self, sample_errors: bool) -> Self {
+        self.sample_errors = sample_errors;
+        self
+    }
+
+    /// Seeds the generator picking the calls of [`SampleDecision::Ratio`], so which ones are
+    /// sampled is the same from run to run.
+    pub fn seed(self, seed: u64) -> Self {
+        self.rng.store(seed, Ordering::Relaxed);
+        self
+    }
+
+    fn sampled(&self, info: &RpcMethodInfo, parts: &request::Parts) -> bool {
+        match (self.sampler)(info, parts) {
+            SampleDecision::Always => true,
+            SampleDecision::Never => false,
+            SampleDecision::Ratio(ratio) => self.random() < ratio,
+            SampleDecision::Parent => parent_sampled(parts).unwrap_or(true),
+        }
+    }
+
+    /// A random number in `0.0..1.0`.
+    fn random(&self) -> f64 {
+        // splitmix64
+        let mut z = self
+            .rng
+            .fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed)
+            .wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+impl Default for RpcTraceLayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RpcTraceLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RpcTraceLayer")
+            .field("sample_errors", &self.sample_errors)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> Layer<S> for RpcTraceLayer {
+    type Service = RpcTraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RpcTraceService {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+/// The service produced by [`RpcTraceLayer`]. It passes the layer on to the RPC routes, which
+/// know the [`RpcMethodInfo`] of the call.
+#[derive(Clone)]
+pub struct RpcTraceService<S> {
+    inner: S,
+    layer: RpcTraceLayer,
+}
+
+impl<S, B> Service<axum::http::Request<B>> for RpcTraceService<S>
+where
+    S: Service<axum::http::Request<B>>,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: axum::http::Request<B>) -> Self::Future {
+        req.extensions_mut().insert(self.layer.clone());
+        self.inner.call(req)
+    }
+}
+
+/// The `route_layers` middleware opening the spans of [`RpcTraceLayer`].
+pub(crate) async fn trace(request: Request, next: Next) -> Response {
+    let (parts, body) = request.into_parts();
+    let (Some(layer), Some(info)) = (
+        parts.extensions.get::<RpcTraceLayer>(),
+        parts.extensions.get::<RpcMethodInfo>(),
+    ) else {
+        return next.run(Request::from_parts(parts, body)).await;
+    };
+    let layer = layer.clone();
+    let procedure = info.path.clone();
+    let sampled = layer.sampled(info, &parts);
+    let request = Request::from_parts(parts, body);
+    if !sampled && !layer.sample_errors {
+        return next.run(request).await;
+    }
+
+    let start = Instant::now();
+    let span = sampled.then(|| {
+        tracing::info_span!(
+            target: "axum_connect::trace",
+            "rpc",
+            procedure = %procedure,
+            code = field::Empty,
+            latency_ms = field::Empty,
+            internal_message = field::Empty,
+        )
+    });
+    let response = match &span {
+        Some(span) => next.run(request).instrument(span.clone()).await,
+        None => next.run(request).await,
+    };
+
+    let stats = response.extensions().get::<Arc<RpcCallStats>>().cloned();
+    let guard = SpanGuard {
+        span,
+        procedure,
+        start,
+        stats,
+    };
+    response.map(|body| Body::new(TracedBody { inner: body, guard }))
+}
+
+/// The span of a call until its response has been sent (or dropped).
+struct SpanGuard {
+    /// `None` if the call wasn't sampled.
+    span: Option<Span>,
+    procedure: String,
+    start: Instant,
+    stats: Option<Arc<RpcCallStats>>,
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        let code = self.stats.as_ref().and_then(|stats| stats.code());
+        let internal_message = self
+            .stats
+            .as_ref()
+            .and_then(|stats| stats.internal_message());
+        let latency_ms = self.start.elapsed().as_secs_f64() * 1000.0;
+        let code_field = code
+            .as_ref()
+            .map(|code| code.to_string())
+            .unwrap_or_else(|| "ok".to_string());
+
+        match &self.span {
+            Some(span) => {
+                span.record("code", code_field.as_str());
+                span.record("latency_ms", latency_ms);
+                if let Some(internal_message) = &internal_message {
+                    span.record("internal_message", internal_message.as_str());
+                }
+            }
+            // Only errors get here without a span, see `sample_errors`.
+            None if code.is_some() => {
+                let _span = tracing::info_span!(
+                    target: "axum_connect::trace",
+                    "rpc",
+                    procedure = %self.procedure,
+                    code = code_field.as_str(),
+                    latency_ms,
+                    internal_message = internal_message.as_deref(),
+                    forced = true,
+                );
+            }
+            None => {}
+        }
+    }
+}
+
+/// Polls a response body in the call's span, so stream items are produced in it.
+struct TracedBody {
+    inner: Body,
+    guard: SpanGuard,
+}
+
+impl HttpBody for TracedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let _entered = this.guard.span.as_ref().map(Span::enter);
+        pin!(&mut this.inner).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// The sampled flag of the `traceparent` header, `None` if there's no valid one.
+fn parent_sampled(parts: &request::Parts) -> Option<bool> {
+    let value = parts.headers.get("traceparent")?.to_str().ok()?;
+    // version-trace_id-parent_id-flags, eg. `00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`
+    let mut fields = value.trim().split('-');
+    let (version, trace_id, parent_id, flags) = (
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+        fields.next()?,
+    );
+    let hex = |field: &str, len: usize| {
+        field.len() == len && field.bytes().all(|byte| byte.is_ascii_hexdigit())
+    };
+    if !hex(version, 2) || version == "ff" || !hex(trace_id, 32) || !hex(parent_id, 16) {
+        return None;
+    }
+    // Later versions may append fields, version 00 doesn't.
+    if version == "00" && fields.next().is_some() {
+        return None;
+    }
+    if trace_id.bytes().all(|byte| byte == b'0') || parent_id.bytes().all(|byte| byte == b'0') {
+        return None;
+    }
+    if !hex(flags, 2) {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some(flags & 1 == 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        error::{RpcError, RpcErrorCode},
+        response::RpcResult,
+        router::RpcRouter,
+        test_util::{
+            client, hello, proto_request, say_hello, traced, unary, HelloRequest, HelloResponse,
+            SAY_HELLO,
+        },
+        testing::TestClient,
+    };
+
+    const TARGET: &str = "axum_connect::trace";
+
+    fn traced_client(layer: RpcTraceLayer) -> TestClient {
+        client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, say_hello))
+                .layer(layer),
+        )
+    }
+
+    async fn call(client: &TestClient, traceparent: Option<&'static str>) {
+        let mut request = proto_request(SAY_HELLO, &hello("Ada"));
+        if let Some(traceparent) = traceparent {
+            request
+                .headers_mut()
+                .insert("traceparent", HeaderValue::from_static(traceparent));
+        }
+        client.send(request).await;
+    }
+
+    #[tokio::test]
+    async fn runs_the_call_in_its_span() {
+        async fn logged(request: HelloRequest) -> RpcResult<HelloResponse> {
+            tracing::info!(target: "handler", "greeting {}", request.name);
+            say_hello(request).await
+        }
+        let (recorder, _guard) = traced();
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, logged))
+                .layer(RpcTraceLayer::new()),
+        );
+
+        call(&client, None).await;
+
+        let spans = recorder.spans(TARGET);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].name, "rpc");
+        assert_eq!(spans[0].field("procedure"), Some(SAY_HELLO));
+        assert_eq!(spans[0].field("code"), Some("ok"));
+        assert!(spans[0].field("latency_ms").is_some());
+        let events = recorder.events("handler");
+        let span = events[0].span.as_ref().unwrap();
+        assert_eq!(span.field("procedure"), Some(SAY_HELLO));
+    }
+
+    #[tokio::test]
+    async fn creates_no_span_for_calls_it_doesnt_sample() {
+        let (recorder, _guard) = traced();
+        let client = traced_client(RpcTraceLayer::new().sample(|_, _| SampleDecision::Never));
+
+        call(&client, None).await;
+
+        assert!(recorder.spans(TARGET).is_empty());
+    }
+
+    #[tokio::test]
+    async fn samples_a_ratio_of_the_calls() {
+        let (recorder, _guard) = traced();
+        let layer = RpcTraceLayer::new()
+            .sample(|_, _| SampleDecision::Ratio(0.25))
+            .seed(42);
+        let client = traced_client(layer);
+
+        for _ in 0..400 {
+            call(&client, None).await;
+        }
+
+        let sampled = recorder.spans(TARGET).len();
+        assert!((70..=130).contains(&sampled), "sampled {sampled} of 400");
+    }
+
+    #[tokio::test]
+    async fn follows_the_sampled_flag_of_the_parent() {
+        let (recorder, _guard) = traced();
+        let client = traced_client(RpcTraceLayer::new().sample(|_, _| SampleDecision::Parent));
+
+        call(
+            &client,
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        )
+        .await;
+        assert_eq!(recorder.spans(TARGET).len(), 1);
+        call(
+            &client,
+            Some("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00"),
+        )
+        .await;
+        assert_eq!(recorder.spans(TARGET).len(), 1);
+        // Without a valid parent, the call starts a trace.
+        call(
+            &client,
+            Some("00-00000000000000000000000000000000-00f067aa0ba902b7-00"),
+        )
+        .await;
+        call(&client, None).await;
+        assert_eq!(recorder.spans(TARGET).len(), 3);
+    }
+
+    #[tokio::test]
+    async fn forces_spans_for_errors_if_asked_to() {
+        async fn flaky(request: HelloRequest) -> RpcResult<HelloResponse> {
+            match request.name.as_str() {
+                "fail" => Err(RpcError::new(RpcErrorCode::Unavailable, "Down".to_string())),
+                _ => say_hello(request).await,
+            }
+        }
+        let (recorder, _guard) = traced();
+        let layer = RpcTraceLayer::new()
+            .sample(|_, _| SampleDecision::Never)
+            .sample_errors(true);
+        let client = client(
+            RpcRouter::new()
+                .rpc_method(unary(SAY_HELLO, flaky))
+                .layer(layer),
+        );
+
+        client.send(proto_request(SAY_HELLO, &hello("Ada"))).await;
+        client.send(proto_request(SAY_HELLO, &hello("fail"))).await;
+
+        let spans = recorder.spans(TARGET);
+        assert_eq!(spans.len(), 1);
+        assert_eq!(spans[0].field("code"), Some("unavailable"));
+        assert_eq!(spans[0].field("forced"), Some("true"));
+    }
+}
@@ -0,0 +1,220 @@
+//! Verifying request bodies before they're decoded, see [`BodyVerifier`].
+
+use std::{fmt, sync::Arc};
+
+use axum::http::request;
+
+use crate::{
+    error::{RpcError, RpcErrorCode},
+    router::RouteOptions,
+};
+
+/// Checks the exact bytes of a request body, eg. an HMAC signature of a webhook in an
+/// `x-signature` header. Mounted with
+/// [`RouteOptions::verify_body`](crate::router::RouteOptions::verify_body), it runs after the
+/// extractors and before the message is decoded; the call fails with `Unauthenticated` and the
+/// returned reason if it doesn't pass.
+///
+/// `body` is the body as it came over the wire, stream envelopes included. For GET requests it's
+/// the message from the query.
+///
+/// ```
+/// # use axum::http::request;
+/// # use axum_connect::verify::BodyVerifier;
+/// struct SharedSecret(String);
+///
+/// impl BodyVerifier for SharedSecret {
+///     fn verify(&self, parts: &request::Parts, body: &[u8]) -> Result<(), String> {
+///         let Some(signature) = parts.headers.get("x-signature") else {
+///             return Err("Missing x-signature".to_string());
+///         };
+///         match signature.as_bytes() == sign(&self.0, body).as_bytes() {
+///             true => Ok(()),
+///             false => Err("Invalid x-signature".to_string()),
+///         }
+///     }
+/// }
+/// # fn sign(_secret: &str, _body: &[u8]) -> String { String::new() }
+/// ```
+pub trait BodyVerifier: Send + Sync + 'static {
+    fn verify(&self, parts: &request::Parts, body: &[u8]) -> Result<(), String>;
+}
+
+/// The verifier of [`RouteOptions::verify_body`]. Verifiers are only equal to their own clones.
+#[derive(Clone)]
+pub struct RouteVerifier(Arc<dyn BodyVerifier>);
+
+impl RouteVerifier {
+    pub fn new(verifier: impl BodyVerifier) -> Self {
+        Self(Arc::new(verifier))
+    }
+}
+
+impl fmt::Debug for RouteVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RouteVerifier")
+    }
+}
+
+impl PartialEq for RouteVerifier {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for RouteVerifier {}
+
+/// Runs the route's body verifier, if it has one.
+pub(crate) fn verify_body(parts: &request::Parts, body: &[u8]) -> Result<(), RpcError> {
+    let Some(RouteVerifier(verifier)) = parts
+        .extensions
+        .get::<RouteOptions>()
+        .and_then(|options| options.body_verifier.as_ref())
+    else {
+        return Ok(());
+    };
+
+    verifier
+        .verify(parts, body)
+        .map_err(|reason| RpcError::new(RpcErrorCode::Unauthenticated, reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        hash::{DefaultHasher, Hash, Hasher},
+        sync::Mutex,
+    };
+
+    use axum::{body::Body, http::Request};
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        router::RpcRouter,
+        test_util::{
+            client, frames, hello, message, messages, say_hello, say_hello_stream, server_stream,
+            unary, HelloResponse, SAY_HELLO, SAY_HELLO_STREAM,
+        },
+        testing::TestClient,
+    };
+
+    /// Accepts bodies whose `x-signature` is their [`sign`]ature, and keeps every body it's given.
+    #[derive(Clone, Default)]
+    struct Signed(Arc<Mutex<Vec<Vec<u8>>>>);
+
+    impl BodyVerifier for Signed {
+        fn verify(&self, parts: &request::Parts, body: &[u8]) -> Result<(), String> {
+            self.0.lock().unwrap().push(body.to_vec());
+            let Some(signature) = parts.headers.get("x-signature") else {
+                return Err("Missing x-signature".to_string());
+            };
+            match signature.as_bytes() == sign(body).as_bytes() {
+                true => Ok(()),
+                false => Err("Invalid x-signature".to_string()),
+            }
+        }
+    }
+
+    fn sign(body: &[u8]) -> String {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+
+    fn signed() -> (TestClient, Signed) {
+        let verifier = Signed::default();
+        let options = RouteOptions::new().verify_body(verifier.clone());
+        let router = RpcRouter::new().rpc_with_options(options, |router| {
+            router
+                .rpc_method(unary(SAY_HELLO, say_hello))
+                .rpc_method(server_stream(SAY_HELLO_STREAM, say_hello_stream))
+        });
+        (client(router), verifier)
+    }
+
+    fn request(
+        path: &str,
+        content_type: &str,
+        body: &[u8],
+        signature: Option<&str>,
+    ) -> Request<Body> {
+        let mut request = Request::post(path).header("content-type", content_type);
+        if let Some(signature) = signature {
+            request = request.header("x-signature", signature);
+        }
+        request.body(Body::from(body.to_vec())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn verifies_the_wire_bytes_of_proto_bodies() {
+        let (client, verifier) = signed();
+        let body = hello("Ada").encode_to_vec();
+
+        let signature = sign(&body);
+        let request = request(SAY_HELLO, "application/proto", &body, Some(&signature));
+        let response = client.send(request).await;
+
+        assert_eq!(message::<HelloResponse>(&response).message, "Hello Ada!");
+        assert_eq!(*verifier.0.lock().unwrap(), [body]);
+    }
+
+    #[cfg(feature = "json")]
+    #[tokio::test]
+    async fn verifies_the_wire_bytes_of_json_bodies() {
+        let (client, verifier) = signed();
+        // Spaced out, so it's not what re-encoding the message would give.
+        let body = br#"{ "name" :  "Ada" }"#;
+
+        let signature = sign(body);
+        let request = request(SAY_HELLO, "application/json", body, Some(&signature));
+        let response = client.send(request).await;
+
+        assert_eq!(response.error(), None);
+        assert_eq!(&response.body[..], br#"{"message":"Hello Ada!"}"#);
+        assert_eq!(*verifier.0.lock().unwrap(), [body.to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn verifies_stream_bodies_with_their_envelopes() {
+        let (client, verifier) = signed();
+        let body = frames(&[hello("Ada")]);
+
+        let signature = sign(&body);
+        let request = request(
+            SAY_HELLO_STREAM,
+            "application/connect+proto",
+            &body,
+            Some(&signature),
+        );
+        let response = client.send(request).await;
+
+        assert_eq!(messages::<HelloResponse>(&response).len(), 3);
+        assert_eq!(*verifier.0.lock().unwrap(), [body]);
+    }
+
+    #[tokio::test]
+    async fn rejects_tampered_bodies() {
+        let (client, _) = signed();
+        let signature = sign(&hello("Ada").encode_to_vec());
+        let body = hello("Eve").encode_to_vec();
+
+        let request = request(SAY_HELLO, "application/proto", &body, Some(&signature));
+        let error = client.send(request).await.error().unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::Unauthenticated);
+        assert_eq!(error.message, "Invalid x-signature");
+    }
+
+    #[tokio::test]
+    async fn rejects_unsigned_bodies() {
+        let (client, _) = signed();
+        let body = hello("Ada").encode_to_vec();
+
+        let request = request(SAY_HELLO, "application/proto", &body, None);
+        let error = client.send(request).await.error().unwrap();
+
+        assert_eq!(error.code, RpcErrorCode::Unauthenticated);
+        assert_eq!(error.message, "Missing x-signature");
+    }
+}
@@ -0,0 +1,455 @@
+//! Conversions between the `google.protobuf.Timestamp`/`Duration` well-known types and the Rust
+//! time types.
+//!
+//! The protobuf types can't implement `From` for `std` types outside of pbjson-types, so the
+//! conversions are methods of [`TimestampExt`] and [`DurationExt`]. Each comes in an erroring and a
+//! saturating form. Inputs don't have to be normalized: nanos outside of `0..1e9`, or with a sign
+//! different from the seconds, are carried over into the seconds first.
+//!
+//! Conversions to and from `chrono::DateTime<Utc>` are implemented by pbjson-types itself. The
+//! `chrono` feature adds the ones for `chrono::TimeDelta`, the `time` feature the ones for
+//! `time::OffsetDateTime` and `time::Duration`.
+//!
+//! ```
+//! # use std::time::{Duration, SystemTime};
+//! # use axum_connect::{prelude::*, well_known::{Timestamp, TimestampExt}};
+//! fn expires_at(created: &Timestamp) -> RpcResult<SystemTime> {
+//!     Ok(created.to_system_time()? + Duration::from_secs(3600))
+//! }
+//! ```
+
+use std::{
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+pub use pbjson_types::{Duration, Timestamp};
+
+use crate::error::{RpcError, RpcErrorCode, RpcIntoError};
+
+const NANOS_PER_SECOND: i128 = 1_000_000_000;
+
+/// `0001-01-01T00:00:00Z`, the earliest valid `Timestamp`.
+const MIN_TIMESTAMP_SECONDS: i64 = -62_135_596_800;
+
+/// `9999-12-31T23:59:59Z`, the latest valid `Timestamp` (with 999,999,999 nanos).
+const MAX_TIMESTAMP_SECONDS: i64 = 253_402_300_799;
+
+/// About 10,000 years, the largest valid `Duration` in either direction.
+const MAX_DURATION_SECONDS: i64 = 315_576_000_000;
+
+/// A value that can't be represented on the other side of a conversion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConversionError {
+    /// A negative duration was converted to an unsigned one.
+    Negative,
+    /// The value is past the range of the target type.
+    OutOfRange,
+}
+
+impl fmt::Display for ConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConversionError::Negative => write!(f, "Duration is negative"),
+            ConversionError::OutOfRange => write!(f, "Time is out of the supported range"),
+        }
+    }
+}
+
+impl std::error::Error for ConversionError {}
+
+/// Bad times almost always come from the request, so they're `InvalidArgument`.
+impl RpcIntoError for ConversionError {
+    fn rpc_into_error(self) -> RpcError {
+        RpcError::new(RpcErrorCode::InvalidArgument, self.to_string())
+    }
+}
+
+impl From<ConversionError> for RpcError {
+    fn from(error: ConversionError) -> Self {
+        error.rpc_into_error()
+    }
+}
+
+/// Conversions for [`Timestamp`].
+pub trait TimestampExt: Sized {
+    /// The current time.
+    fn now() -> Self;
+
+    /// Fails if `time` is before year 1 or after year 9999.
+    fn from_system_time(time: SystemTime) -> Result<Self, ConversionError>;
+
+    /// Clamps `time` to the range of `Timestamp`.
+    fn from_system_time_saturating(time: SystemTime) -> Self;
+
+    /// Fails if the timestamp is outside of years 1 to 9999, or if this platform's `SystemTime`
+    /// can't represent it.
+    fn to_system_time(&self) -> Result<SystemTime, ConversionError>;
+
+    /// Clamps the timestamp to years 1 to 9999. Falls back to the Unix epoch on platforms whose
+    /// `SystemTime` can't represent the clamped time, eg. years before 1601 on Windows.
+    fn to_system_time_saturating(&self) -> SystemTime;
+
+    /// The same instant with nanos in `0..1e9`, clamped to the range of `Timestamp`.
+    fn normalized(&self) -> Self;
+
+    #[cfg(feature = "time")]
+    fn from_offset_date_time(time: time::OffsetDateTime) -> Result<Self, ConversionError>;
+
+    #[cfg(feature = "time")]
+    fn to_offset_date_time(&self) -> Result<time::OffsetDateTime, ConversionError>;
+}
+
+impl TimestampExt for Timestamp {
+    fn now() -> Self {
+        Self::from_system_time_saturating(SystemTime::now())
+    }
+
+    fn from_system_time(time: SystemTime) -> Result<Self, ConversionError> {
+        let timestamp = timestamp_from_nanos(system_time_nanos(time));
+        if timestamp_in_range(&timestamp) {
+            Ok(timestamp)
+        } else {
+            Err(ConversionError::OutOfRange)
+        }
+    }
+
+    fn from_system_time_saturating(time: SystemTime) -> Self {
+        clamp_timestamp(timestamp_from_nanos(system_time_nanos(time)))
+    }
+
+    fn to_system_time(&self) -> Result<SystemTime, ConversionError> {
+        let timestamp = timestamp_from_nanos(timestamp_nanos(self));
+        if !timestamp_in_range(&timestamp) {
+            return Err(ConversionError::OutOfRange);
+        }
+        system_time_from_timestamp(&timestamp).ok_or(ConversionError::OutOfRange)
+    }
+
+    fn to_system_time_saturating(&self) -> SystemTime {
+        system_time_from_timestamp(&self.normalized()).unwrap_or(UNIX_EPOCH)
+    }
+
+    fn normalized(&self) -> Self {
+        clamp_timestamp(timestamp_from_nanos(timestamp_nanos(self)))
+    }
+
+    #[cfg(feature = "time")]
+    fn from_offset_date_time(time: time::OffsetDateTime) -> Result<Self, ConversionError> {
+        let timestamp = timestamp_from_nanos(time.unix_timestamp_nanos());
+        if timestamp_in_range(&timestamp) {
+            Ok(timestamp)
+        } else {
+            Err(ConversionError::OutOfRange)
+        }
+    }
+
+    #[cfg(feature = "time")]
+    fn to_offset_date_time(&self) -> Result<time::OffsetDateTime, ConversionError> {
+        let nanos = timestamp_nanos(self);
+        if !timestamp_in_range(&timestamp_from_nanos(nanos)) {
+            return Err(ConversionError::OutOfRange);
+        }
+        time::OffsetDateTime::from_unix_timestamp_nanos(nanos)
+            .map_err(|_| ConversionError::OutOfRange)
+    }
+}
+
+/// Conversions for [`Duration`].
+pub trait DurationExt: Sized {
+    /// Fails if `duration` is over 10,000 years.
+    fn from_std(duration: std::time::Duration) -> Result<Self, ConversionError>;
+
+    /// Clamps `duration` to 10,000 years.
+    fn from_std_saturating(duration: std::time::Duration) -> Self;
+
+    /// Fails if the duration is negative or over 10,000 years.
+    fn to_std(&self) -> Result<std::time::Duration, ConversionError>;
+
+    /// Negative durations become zero, longer ones than 10,000 years are clamped.
+    fn to_std_saturating(&self) -> std::time::Duration;
+
+    /// The same duration with nanos of the same sign as the seconds, clamped to 10,000 years.
+    fn normalized(&self) -> Self;
+
+    /// Fails if `duration` is over 10,000 years in either direction.
+    #[cfg(feature = "chrono")]
+    fn from_chrono(duration: chrono::TimeDelta) -> Result<Self, ConversionError>;
+
+    /// Fails if the duration is over 10,000 years in either direction.
+    #[cfg(feature = "chrono")]
+    fn to_chrono(&self) -> Result<chrono::TimeDelta, ConversionError>;
+
+    #[cfg(feature = "time")]
+    fn from_time(duration: time::Duration) -> Result<Self, ConversionError>;
+
+    #[cfg(feature = "time")]
+    fn to_time(&self) -> Result<time::Duration, ConversionError>;
+}
+
+impl DurationExt for Duration {
+    fn from_std(duration: std::time::Duration) -> Result<Self, ConversionError> {
+        checked_duration(duration.as_nanos() as i128)
+    }
+
+    fn from_std_saturating(duration: std::time::Duration) -> Self {
+        clamp_duration(duration_from_nanos(duration.as_nanos() as i128))
+    }
+
+    fn to_std(&self) -> Result<std::time::Duration, ConversionError> {
+        let duration = checked_duration(duration_nanos(self))?;
+        if duration.seconds < 0 || duration.nanos < 0 {
+            return Err(ConversionError::Negative);
+        }
+        Ok(std::time::Duration::new(
+            duration.seconds as u64,
+            duration.nanos as u32,
+        ))
+    }
+
+    fn to_std_saturating(&self) -> std::time::Duration {
+        let duration = self.normalized();
+        if duration.seconds < 0 || duration.nanos < 0 {
+            return std::time::Duration::ZERO;
+        }
+        std::time::Duration::new(duration.seconds as u64, duration.nanos as u32)
+    }
+
+    fn normalized(&self) -> Self {
+        clamp_duration(duration_from_nanos(duration_nanos(self)))
+    }
+
+    #[cfg(feature = "chrono")]
+    fn from_chrono(duration: chrono::TimeDelta) -> Result<Self, ConversionError> {
+        let nanos =
+            duration.num_seconds() as i128 * NANOS_PER_SECOND + duration.subsec_nanos() as i128;
+        checked_duration(nanos)
+    }
+
+    #[cfg(feature = "chrono")]
+    fn to_chrono(&self) -> Result<chrono::TimeDelta, ConversionError> {
+        let duration = checked_duration(duration_nanos(self))?;
+        // `TimeDelta` keeps its nanos positive.
+        let nanos = duration_nanos(&duration);
+        let seconds = nanos.div_euclid(NANOS_PER_SECOND) as i64;
+        let nanos = nanos.rem_euclid(NANOS_PER_SECOND) as u32;
+        chrono::TimeDelta::new(seconds, nanos).ok_or(ConversionError::OutOfRange)
+    }
+
+    #[cfg(feature = "time")]
+    fn from_time(duration: time::Duration) -> Result<Self, ConversionError> {
+        checked_duration(duration.whole_nanoseconds())
+    }
+
+    #[cfg(feature = "time")]
+    fn to_time(&self) -> Result<time::Duration, ConversionError> {
+        let duration = checked_duration(duration_nanos(self))?;
+        Ok(time::Duration::new(duration.seconds, duration.nanos))
+    }
+}
+
+fn system_time_nanos(time: SystemTime) -> i128 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_nanos() as i128,
+        Err(before) => -(before.duration().as_nanos() as i128),
+    }
+}
+
+fn system_time_from_timestamp(timestamp: &Timestamp) -> Option<SystemTime> {
+    // Normalized, so nanos always count forward from `seconds`.
+    let seconds = std::time::Duration::from_secs(timestamp.seconds.unsigned_abs());
+    let nanos = std::time::Duration::from_nanos(timestamp.nanos as u64);
+    if timestamp.seconds < 0 {
+        UNIX_EPOCH.checked_sub(seconds)?.checked_add(nanos)
+    } else {
+        UNIX_EPOCH.checked_add(seconds)?.checked_add(nanos)
+    }
+}
+
+fn timestamp_nanos(timestamp: &Timestamp) -> i128 {
+    timestamp.seconds as i128 * NANOS_PER_SECOND + timestamp.nanos as i128
+}
+
+/// Nanos are always positive in a normalized `Timestamp`.
+fn timestamp_from_nanos(nanos: i128) -> Timestamp {
+    Timestamp {
+        seconds: saturate_i64(nanos.div_euclid(NANOS_PER_SECOND)),
+        nanos: nanos.rem_euclid(NANOS_PER_SECOND) as i32,
+    }
+}
+
+fn timestamp_in_range(timestamp: &Timestamp) -> bool {
+    (MIN_TIMESTAMP_SECONDS..=MAX_TIMESTAMP_SECONDS).contains(&timestamp.seconds)
+}
+
+fn clamp_timestamp(timestamp: Timestamp) -> Timestamp {
+    if timestamp.seconds < MIN_TIMESTAMP_SECONDS {
+        Timestamp {
+            seconds: MIN_TIMESTAMP_SECONDS,
+            nanos: 0,
+        }
+    } else if timestamp.seconds > MAX_TIMESTAMP_SECONDS {
+        Timestamp {
+            seconds: MAX_TIMESTAMP_SECONDS,
+            nanos: 999_999_999,
+        }
+    } else {
+        timestamp
+    }
+}
+
+fn duration_nanos(duration: &Duration) -> i128 {
+    duration.seconds as i128 * NANOS_PER_SECOND + duration.nanos as i128
+}
+
+/// Nanos have the sign of the seconds in a normalized `Duration`.
+fn duration_from_nanos(nanos: i128) -> Duration {
+    Duration {
+        seconds: saturate_i64(nanos / NANOS_PER_SECOND),
+        nanos: (nanos % NANOS_PER_SECOND) as i32,
+    }
+}
+
+fn checked_duration(nanos: i128) -> Result<Duration, ConversionError> {
+    let duration = duration_from_nanos(nanos);
+    if duration.seconds.unsigned_abs() > MAX_DURATION_SECONDS as u64 {
+        return Err(ConversionError::OutOfRange);
+    }
+    Ok(duration)
+}
+
+fn clamp_duration(duration: Duration) -> Duration {
+    if duration.seconds > MAX_DURATION_SECONDS {
+        Duration {
+            seconds: MAX_DURATION_SECONDS,
+            nanos: 0,
+        }
+    } else if duration.seconds < -MAX_DURATION_SECONDS {
+        Duration {
+            seconds: -MAX_DURATION_SECONDS,
+            nanos: 0,
+        }
+    } else {
+        duration
+    }
+}
+
+fn saturate_i64(value: i128) -> i64 {
+    value.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration as StdDuration;
+
+    use super::*;
+
+    fn timestamp(seconds: i64, nanos: i32) -> Timestamp {
+        Timestamp { seconds, nanos }
+    }
+
+    fn duration(seconds: i64, nanos: i32) -> Duration {
+        Duration { seconds, nanos }
+    }
+
+    #[test]
+    fn converts_timestamps_before_the_epoch() {
+        let time = UNIX_EPOCH - StdDuration::from_millis(1_500);
+        let converted = Timestamp::from_system_time(time).unwrap();
+        // Nanos count forward from the seconds, even before the epoch.
+        assert_eq!(converted, timestamp(-2, 500_000_000));
+        assert_eq!(converted.to_system_time().unwrap(), time);
+    }
+
+    #[test]
+    fn normalizes_the_nanos_of_timestamps() {
+        assert_eq!(
+            timestamp(10, 1_500_000_000).normalized(),
+            timestamp(11, 500_000_000)
+        );
+        assert_eq!(timestamp(10, -1).normalized(), timestamp(9, 999_999_999));
+        assert_eq!(
+            timestamp(0, -1).to_system_time().unwrap(),
+            UNIX_EPOCH - StdDuration::from_nanos(1)
+        );
+    }
+
+    #[test]
+    fn fails_or_clamps_timestamps_out_of_range() {
+        let past_the_end = timestamp(MAX_TIMESTAMP_SECONDS + 1, 0);
+        assert_eq!(
+            past_the_end.to_system_time(),
+            Err(ConversionError::OutOfRange)
+        );
+        assert_eq!(
+            past_the_end.normalized(),
+            timestamp(MAX_TIMESTAMP_SECONDS, 999_999_999)
+        );
+        assert_eq!(
+            timestamp(MIN_TIMESTAMP_SECONDS, -1).normalized(),
+            timestamp(MIN_TIMESTAMP_SECONDS, 0)
+        );
+    }
+
+    #[test]
+    fn converts_negative_durations() {
+        let negative = duration(-1, -500_000_000);
+        assert_eq!(negative.to_std(), Err(ConversionError::Negative));
+        assert_eq!(negative.to_std_saturating(), StdDuration::ZERO);
+        // Nanos of the other sign than the seconds are carried over.
+        assert_eq!(
+            duration(1, -500_000_000).normalized(),
+            duration(0, 500_000_000)
+        );
+        assert_eq!(
+            duration(-1, 500_000_000).normalized(),
+            duration(0, -500_000_000)
+        );
+        assert_eq!(
+            duration(0, -1_500_000_000).normalized(),
+            duration(-1, -500_000_000)
+        );
+    }
+
+    #[test]
+    fn fails_or_clamps_durations_out_of_range() {
+        let too_long = StdDuration::from_secs(MAX_DURATION_SECONDS as u64 + 1);
+        assert_eq!(
+            Duration::from_std(too_long),
+            Err(ConversionError::OutOfRange)
+        );
+        assert_eq!(
+            Duration::from_std_saturating(too_long),
+            duration(MAX_DURATION_SECONDS, 0)
+        );
+        assert_eq!(
+            duration(-MAX_DURATION_SECONDS - 1, 0).normalized(),
+            duration(-MAX_DURATION_SECONDS, 0)
+        );
+
+        let error = RpcError::from(ConversionError::OutOfRange);
+        assert_eq!(error.code, RpcErrorCode::InvalidArgument);
+    }
+
+    #[cfg(feature = "chrono")]
+    #[test]
+    fn converts_chrono_durations() {
+        let negative = chrono::TimeDelta::milliseconds(-1_500);
+        let converted = Duration::from_chrono(negative).unwrap();
+        assert_eq!(converted, duration(-1, -500_000_000));
+        assert_eq!(converted.to_chrono().unwrap(), negative);
+
+        assert_eq!(
+            duration(1, -1).to_chrono().unwrap(),
+            chrono::TimeDelta::nanoseconds(999_999_999)
+        );
+        assert_eq!(
+            duration(MAX_DURATION_SECONDS + 1, 0).to_chrono(),
+            Err(ConversionError::OutOfRange)
+        );
+        assert_eq!(
+            Duration::from_chrono(chrono::TimeDelta::seconds(-MAX_DURATION_SECONDS - 1)),
+            Err(ConversionError::OutOfRange)
+        );
+    }
+}
@@ -0,0 +1,227 @@
+//! A `protoc` plugin wrapping `axum-connect-build`, for use with `buf generate` or
+//! `protoc --axum-connect_out=...` instead of a `build.rs` script.
+//!
+//! Options are passed as comma separated `key=value` pairs in the plugin parameter, eg.
+//! `--axum-connect_opt=include_file=mod.rs`. Supported options:
+//!
+//! - `include_file=<name>`: also emit a single file that includes every generated file, nested in
+//!   `pub mod`s matching the proto packages.
+//! - `json=false`: skip the pbjson Serde impls, for proto-only services. Requires disabling the
+//!   `json` feature of `axum-connect`.
+//! - `openapi=<name>`: also emit an OpenAPI v3 document describing every service.
+//! - `routes_manifest=<name>`: also emit a JSON manifest of every RPC, for API gateways.
+//! - `public_rpcs=<rpc>+<rpc>`: the RPCs the manifest lists as not requiring authorization, each
+//!   a `package.Service/Method` or a whole `package.Service`.
+//! - `gen_mod_name=<name>`: put the package files in a `<name>/` directory, next to a `<name>.rs`
+//!   file that includes them.
+//! - `field_masks=true`: embed the descriptors `axum_connect::field_mask` needs. Requires the
+//!   `field-mask` feature of `axum-connect`.
+//! - `open_enums=true`: decode unknown enum values in JSON requests as the zero value instead of
+//!   rejecting them.
+//! - `page_tokens=true`: implement `axum_connect::pagination::HasPageToken` for messages with a
+//!   `page_token` field. Requires the `pagination` feature of `axum-connect`.
+//! - `oneof_helpers=true`: add `<oneof>_as_<field>()` and `<oneof>_kind_name()` accessors to
+//!   messages with a oneof.
+//! - `service_traits=true`: also generate a `<Service>Handlers` trait per service, with
+//!   `register_all` and `registrars` on the service struct mounting an implementation of it.
+//! - `clients=true`: also generate a `<Service>Client` per service, calling its RPCs through an
+//!   `axum_connect::client::RpcClient`.
+//! - `format=true`: run the generated Rust files through `prettyplease`.
+//! - `deny_unsupported=true`: fail on any diagnostic, eg. a skipped client-streaming method,
+//!   instead of only printing it.
+
+use std::io::{self, Read, Write};
+
+use axum_connect_build::{generate_files, AxumConnectGenSettings};
+use prost::Message;
+use prost_types::compiler::{
+    code_generator_response::{Feature, File},
+    CodeGeneratorRequest, CodeGeneratorResponse,
+};
+
+fn main() -> anyhow::Result<()> {
+    let mut buf = vec![];
+    io::stdin().read_to_end(&mut buf)?;
+    let request = CodeGeneratorRequest::decode(&buf[..])?;
+
+    // Errors in the generator itself are reported back to protoc in the response, per the plugin
+    // protocol. Only IO errors talking to protoc are returned from `main`.
+    let response = match generate(request, &buf) {
+        Ok(file) => CodeGeneratorResponse {
+            file,
+            supported_features: Some(Feature::Proto3Optional as u64),
+            ..Default::default()
+        },
+        Err(error) => CodeGeneratorResponse {
+            error: Some(format!("{:#}", error)),
+            ..Default::default()
+        },
+    };
+
+    io::stdout().write_all(&response.encode_to_vec())?;
+    Ok(())
+}
+
+fn generate(request: CodeGeneratorRequest, raw_request: &[u8]) -> anyhow::Result<Vec<File>> {
+    let settings = parse_options(request.parameter())?;
+    let generated = generate_files(
+        &raw_descriptor_set(raw_request)?,
+        &request.file_to_generate,
+        &settings,
+    )?;
+
+    // stdout belongs to protoc, so warnings go to stderr.
+    for diagnostic in &generated.diagnostics {
+        eprintln!("warning: {}", diagnostic);
+    }
+    generated.check(&settings)?;
+
+    Ok(generated
+        .files
+        .into_iter()
+        .map(|(name, content)| File {
+            name: Some(name),
+            content: Some(content),
+            ..Default::default()
+        })
+        .collect())
+}
+
+/// Re-wraps the raw `proto_file` entries of a `CodeGeneratorRequest` as an encoded
+/// `FileDescriptorSet`. Going through the decoded `FileDescriptorProto`s would drop custom options
+/// like `google.api.http`.
+fn raw_descriptor_set(mut request: &[u8]) -> anyhow::Result<Vec<u8>> {
+    use prost::encoding::{
+        decode_key, decode_varint, encode_key, encode_varint, skip_field, DecodeContext, WireType,
+    };
+
+    const PROTO_FILE_TAG: u32 = 15;
+    const FILE_DESCRIPTOR_SET_FILE_TAG: u32 = 1;
+
+    let mut descriptor_set = vec![];
+    while !request.is_empty() {
+        let (tag, wire_type) = decode_key(&mut request)?;
+
+        if tag == PROTO_FILE_TAG && wire_type == WireType::LengthDelimited {
+            let len = decode_varint(&mut request)? as usize;
+            anyhow::ensure!(len <= request.len(), "Truncated CodeGeneratorRequest");
+            let (file, rest) = request.split_at(len);
+
+            encode_key(
+                FILE_DESCRIPTOR_SET_FILE_TAG,
+                WireType::LengthDelimited,
+                &mut descriptor_set,
+            );
+            encode_varint(len as u64, &mut descriptor_set);
+            descriptor_set.extend_from_slice(file);
+            request = rest;
+        } else {
+            skip_field(wire_type, tag, &mut request, DecodeContext::default())?;
+        }
+    }
+
+    Ok(descriptor_set)
+}
+
+fn parse_options(parameter: &str) -> anyhow::Result<AxumConnectGenSettings> {
+    let mut settings = AxumConnectGenSettings {
+        protoc_version: None,
+        ..Default::default()
+    };
+
+    for option in parameter
+        .split(',')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+    {
+        let (key, value) = option.split_once('=').unwrap_or((option, ""));
+
+        match key {
+            "include_file" if !value.is_empty() => settings.include_file = Some(value.to_string()),
+            "openapi" if !value.is_empty() => settings.openapi = Some(value.to_string()),
+            "routes_manifest" if !value.is_empty() => {
+                settings.routes_manifest = Some(value.to_string())
+            }
+            "public_rpcs" if !value.is_empty() => settings
+                .public_rpcs
+                .extend(value.split('+').map(str::to_string)),
+            "gen_mod_name" if !value.is_empty() => settings.gen_mod_name = Some(value.to_string()),
+            "json" if value == "true" || value == "false" => settings.json = value == "true",
+            "field_masks" if value == "true" || value == "false" => {
+                settings.field_masks = value == "true"
+            }
+            "open_enums" if value == "true" || value == "false" => {
+                settings.open_enums = value == "true"
+            }
+            "page_tokens" if value == "true" || value == "false" => {
+                settings.page_tokens = value == "true"
+            }
+            "oneof_helpers" if value == "true" || value == "false" => {
+                settings.oneof_helpers = value == "true"
+            }
+            "service_traits" if value == "true" || value == "false" => {
+                settings.service_traits = value == "true"
+            }
+            "clients" if value == "true" || value == "false" => settings.clients = value == "true",
+            "format" if value == "true" || value == "false" => settings.format = value == "true",
+            "deny_unsupported" if value == "true" || value == "false" => {
+                settings.deny_unsupported = value == "true"
+            }
+            _ => anyhow::bail!("Unknown or malformed plugin option: {}", option),
+        }
+    }
+
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_options;
+
+    #[test]
+    fn parses_every_option() {
+        let settings = parse_options(
+            "include_file=mod.rs, json=false,openapi=openapi.json,routes_manifest=routes.json,\
+             public_rpcs=auth.Auth+users.Users/Get,gen_mod_name=api,field_masks=true,\
+             open_enums=true,page_tokens=true,oneof_helpers=true,service_traits=true,clients=true,\
+             format=true,deny_unsupported=true",
+        )
+        .unwrap();
+
+        assert_eq!(settings.include_file.as_deref(), Some("mod.rs"));
+        assert!(!settings.json);
+        assert_eq!(settings.openapi.as_deref(), Some("openapi.json"));
+        assert_eq!(settings.routes_manifest.as_deref(), Some("routes.json"));
+        assert_eq!(settings.public_rpcs, ["auth.Auth", "users.Users/Get"]);
+        assert_eq!(settings.gen_mod_name.as_deref(), Some("api"));
+        assert!(settings.field_masks);
+        assert!(settings.open_enums);
+        assert!(settings.page_tokens);
+        assert!(settings.oneof_helpers);
+        assert!(settings.service_traits);
+        assert!(settings.clients);
+        assert!(settings.format);
+        assert!(settings.deny_unsupported);
+        assert_eq!(settings.protoc_version, None);
+    }
+
+    #[test]
+    fn defaults_without_options() {
+        let settings = parse_options("").unwrap();
+        assert!(settings.json);
+        assert_eq!(settings.include_file, None);
+        assert!(!settings.service_traits);
+        assert!(!settings.clients);
+    }
+
+    #[test]
+    fn rejects_malformed_options() {
+        for option in ["include_file", "json=maybe", "clients=yes", "grpc=true"] {
+            let error = parse_options(option).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Unknown or malformed plugin option: {}", option)
+            );
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Runs the plugin on a `CodeGeneratorRequest` recorded from `protoc`, and compares what it
+//! generates with the snapshots in `tests/snapshots`, a `<name>.snap` per generated file (so
+//! rustfmt leaves them alone).
+//!
+//! $ cargo test -p protoc-gen-axum-connect
+//! $ UPDATE_SNAPSHOTS=1 cargo test -p protoc-gen-axum-connect
+//!
+//! `tests/fixtures/hello.request.bin` is the request `protoc` sends for the example's
+//! `hello.proto` with
+//! `--axum-connect_opt=include_file=mod.rs,routes_manifest=routes.json,service_traits=true,public_rpcs=hello.HelloWorldService/SayHello`.
+//! The second command writes the snapshots again, after a deliberate change to the generated
+//! code; review their diff before committing it.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use prost::Message;
+use prost_types::compiler::{code_generator_response::Feature, CodeGeneratorResponse};
+
+const FIXTURE: &[u8] = include_bytes!("fixtures/hello.request.bin");
+
+const SNAPSHOTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/snapshots");
+
+/// Set to write the snapshots instead of comparing with them.
+const UPDATE: &str = "UPDATE_SNAPSHOTS";
+
+/// The response of the plugin to `request`.
+fn run(request: &[u8]) -> CodeGeneratorResponse {
+    let mut plugin = Command::new(env!("CARGO_BIN_EXE_protoc-gen-axum-connect"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    plugin.stdin.take().unwrap().write_all(request).unwrap();
+    let output = plugin.wait_with_output().unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    CodeGeneratorResponse::decode(output.stdout.as_slice()).unwrap()
+}
+
+/// The fixture with its parameter replaced by `parameter`. Protobuf decoders keep the last value
+/// of a field, so it's appended rather than re-encoding the request, which would drop the custom
+/// options of its files.
+fn with_parameter(parameter: &str) -> Vec<u8> {
+    const PARAMETER_TAG: u32 = 2;
+
+    let mut request = FIXTURE.to_vec();
+    prost::encoding::string::encode(PARAMETER_TAG, &parameter.to_string(), &mut request);
+    request
+}
+
+#[test]
+fn matches_the_snapshots() {
+    let response = run(FIXTURE);
+    assert_eq!(response.error, None);
+    assert_eq!(
+        response.supported_features,
+        Some(Feature::Proto3Optional as u64)
+    );
+
+    let generated = response
+        .file
+        .into_iter()
+        .map(|file| (file.name().to_string(), file.content().to_string()))
+        .collect::<BTreeMap<_, _>>();
+    if std::env::var_os(UPDATE).is_some_and(|update| !update.is_empty()) {
+        std::fs::create_dir_all(SNAPSHOTS).unwrap();
+        for (name, content) in &generated {
+            std::fs::write(Path::new(SNAPSHOTS).join(format!("{}.snap", name)), content).unwrap();
+        }
+        return;
+    }
+
+    let mut snapshots = BTreeMap::new();
+    for entry in std::fs::read_dir(SNAPSHOTS).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        if let Some(name) = name.strip_suffix(".snap") {
+            snapshots.insert(name.to_string(), std::fs::read_to_string(&path).unwrap());
+        }
+    }
+    assert_eq!(
+        generated.keys().collect::<Vec<_>>(),
+        snapshots.keys().collect::<Vec<_>>(),
+        "the generated files changed, write them with {}=1 if that's deliberate",
+        UPDATE
+    );
+    for (name, content) in &generated {
+        if let Some((line, snapshot, generated)) = first_difference(&snapshots[name], content) {
+            panic!(
+                "{} changed at line {}, write it with {}=1 if that's deliberate\n- {}\n+ {}",
+                name,
+                line + 1,
+                UPDATE,
+                snapshot,
+                generated
+            );
+        }
+    }
+}
+
+/// The first line that differs, and what it is on either side, `None` if none does.
+fn first_difference<'a>(
+    snapshot: &'a str,
+    generated: &'a str,
+) -> Option<(usize, &'a str, &'a str)> {
+    let mut snapshot = snapshot.lines();
+    let mut generated = generated.lines();
+    for line in 0.. {
+        match (snapshot.next(), generated.next()) {
+            (None, None) => return None,
+            (a, b) if a == b => continue,
+            (a, b) => return Some((line, a.unwrap_or("<end>"), b.unwrap_or("<end>"))),
+        }
+    }
+    None
+}
+
+#[test]
+fn options_change_the_output() {
+    let response = run(&with_parameter("gen_mod_name=api,json=false"));
+    assert_eq!(response.error, None);
+    let names = response
+        .file
+        .iter()
+        .map(|file| file.name())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["api.rs", "api/hello.rs"]);
+}
+
+#[test]
+fn clients_add_a_client_per_service() {
+    let hello = |parameter| {
+        let response = run(&with_parameter(parameter));
+        assert_eq!(response.error, None);
+        let file = response
+            .file
+            .into_iter()
+            .find(|file| file.name() == "hello.rs");
+        file.unwrap().content().to_string()
+    };
+
+    assert!(hello("clients=true").contains("pub struct HelloWorldServiceClient {"));
+    assert!(!hello("").contains("HelloWorldServiceClient"));
+}
+
+#[test]
+fn reports_unknown_options_to_protoc() {
+    let response = run(&with_parameter("include_file=mod.rs,grpc=true"));
+    assert_eq!(
+        response.error.as_deref(),
+        Some("Unknown or malformed plugin option: grpc=true")
+    );
+    assert!(response.file.is_empty());
+}